@@ -0,0 +1,242 @@
+use mini_cluster_worker::cluster::{
+    Aggregation, Aggregation_Aggregate, Aggregation_Function, PartitionedWorkload,
+};
+
+use crate::err::{Result, SchedulerError, ErrKind};
+use crate::merge::PARTIALS_TABLE;
+use crate::partition::PARTITION_PLACEHOLDER;
+
+// Aggregation templates. Aggregating over a partitioned workload takes two statements: one
+// working out partial aggregates over each partition, and one merging the partials together.
+// The two have to agree on what the partials are, and some aggregates don't merge as they are:
+// the average of the partitions' averages isn't the average of the rows, unless every partition
+// has as many rows as every other. Writing both by hand is a chore, and easy to get subtly
+// wrong, so an `Aggregation` can be given instead, and the scheduler writes them.
+//
+// Each aggregate is split into partials that do merge:
+//
+//     COUNT(x) -> COUNT(x) AS partial_1,               merged with SUM(partial_1)
+//     SUM(x)   -> SUM(x) AS partial_1,                 merged with SUM(partial_1)
+//     MIN(x)   -> MIN(x) AS partial_1,                 merged with MIN(partial_1)
+//     MAX(x)   -> MAX(x) AS partial_1,                 merged with MAX(partial_1)
+//     AVG(x)   -> SUM(x) AS partial_1, COUNT(x) AS partial_2,
+//                                  merged with CAST(SUM(partial_1) AS REAL) / SUM(partial_2)
+//
+// and the groups are carried through the partials as `group_1`, `group_2`, and so on, to be
+// grouped by again in the merge.
+
+/// Writes the partial aggregation statement, and the merge statement, an aggregation stands
+/// for.
+pub fn aggregation_statements(aggregation: &Aggregation) -> Result<(String, String)> {
+    if aggregation.get_aggregates().is_empty() {
+        Err(SchedulerError::new(ErrKind::InvalidRequest, "Aggregation has no aggregates."))?
+    }
+    let mut partials = vec![];
+    let mut merged = vec![];
+    let mut groups = vec![];
+    let mut n_partials = 0;
+    for (i, expression) in aggregation.get_group_by().iter().enumerate() {
+        let group = format!("group_{}", i + 1);
+        partials.push(format!("{} AS {}", expression, group));
+        merged.push(format!("{} AS {}", group, quote_identifier(expression)));
+        groups.push(group);
+    }
+    for aggregate in aggregation.get_aggregates() {
+        let function = function_name(aggregate.get_function());
+        let expression = match aggregate.get_expression() {
+            "" if aggregate.get_function() == Aggregation_Function::COUNT => "*",
+            "" => Err(SchedulerError::new(
+                ErrKind::InvalidRequest, &format!("{} aggregate has no expression.", function)
+            ))?,
+            expression => expression,
+        };
+        let mut partial = |sql: String| {
+            n_partials += 1;
+            partials.push(format!("{} AS partial_{}", sql, n_partials));
+            format!("partial_{}", n_partials)
+        };
+        let merge = match aggregate.get_function() {
+            Aggregation_Function::COUNT | Aggregation_Function::SUM => {
+                format!("SUM({})", partial(format!("{}({})", function, expression)))
+            },
+            Aggregation_Function::MIN | Aggregation_Function::MAX => {
+                format!("{0}({1})", function, partial(format!("{}({})", function, expression)))
+            },
+            Aggregation_Function::AVG => {
+                let sum = partial(format!("SUM({})", expression));
+                let count = partial(format!("COUNT({})", expression));
+                format!("CAST(SUM({}) AS REAL) / SUM({})", sum, count)
+            },
+        };
+        merged.push(format!("{} AS {}", merge, quote_identifier(&alias(aggregate, expression))));
+    }
+
+    let filter = match aggregation.get_filter() {
+        "" => "".to_owned(),
+        filter => format!(" WHERE {}", filter),
+    };
+    let (group_by, merge_group_by) = match groups.is_empty() {
+        true => ("".to_owned(), "".to_owned()),
+        false => (
+            format!(" GROUP BY {}", aggregation.get_group_by().join(", ")),
+            format!(" GROUP BY {}", groups.join(", ")),
+        ),
+    };
+    let statement = format!(
+        "SELECT {} FROM {}{}{}", partials.join(", "), PARTITION_PLACEHOLDER, filter, group_by
+    );
+    let merge_statement = format!(
+        "SELECT {} FROM {}{}", merged.join(", "), PARTIALS_TABLE, merge_group_by
+    );
+    Ok((statement, merge_statement))
+}
+
+/// Fills in the statement and merge statement of a partitioned workload with an aggregation
+/// (see `aggregation_statements`). Workloads without one are returned as they are.
+pub fn expand_aggregation(partitioned: &PartitionedWorkload) -> Result<PartitionedWorkload> {
+    let mut expanded = partitioned.clone();
+    if !partitioned.has_aggregation() {
+        return Ok(expanded);
+    }
+    if !partitioned.get_statement().is_empty() || !partitioned.get_merge_statement().is_empty() {
+        Err(SchedulerError::new(
+            ErrKind::InvalidRequest,
+            "Partitioned workload has an aggregation, and statements of its own."
+        ))?
+    }
+    let (statement, merge_statement) = aggregation_statements(partitioned.get_aggregation())?;
+    expanded.set_statement(statement);
+    expanded.set_merge_statement(merge_statement);
+    Ok(expanded)
+}
+
+fn function_name(function: Aggregation_Function) -> &'static str {
+    match function {
+        Aggregation_Function::COUNT => "COUNT",
+        Aggregation_Function::SUM => "SUM",
+        Aggregation_Function::MIN => "MIN",
+        Aggregation_Function::MAX => "MAX",
+        Aggregation_Function::AVG => "AVG",
+    }
+}
+
+/// The name of an aggregate's column in the job's result set.
+fn alias(aggregate: &Aggregation_Aggregate, expression: &str) -> String {
+    match aggregate.get_alias() {
+        "" => format!("{}({})", function_name(aggregate.get_function()), expression),
+        alias => alias.to_owned(),
+    }
+}
+
+/// Quotes a column name, so that it can be anything at all (e.g. `SUM(fare)`).
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::RepeatedField;
+
+    use super::*;
+
+    fn craft_aggregate(function: Aggregation_Function, expression: &str) -> Aggregation_Aggregate {
+        let mut aggregate = Aggregation_Aggregate::new();
+        aggregate.set_function(function);
+        aggregate.set_expression(expression.to_owned());
+        aggregate
+    }
+
+    fn craft_aggregation(
+        group_by: &[&str], aggregates: Vec<Aggregation_Aggregate>, filter: &str
+    ) -> Aggregation {
+        let mut aggregation = Aggregation::new();
+        let group_by = group_by.iter().map(|&expression| expression.to_owned()).collect();
+        aggregation.set_group_by(RepeatedField::from_vec(group_by));
+        aggregation.set_aggregates(RepeatedField::from_vec(aggregates));
+        aggregation.set_filter(filter.to_owned());
+        aggregation
+    }
+
+    fn is_invalid_request(result: Result<(String, String)>) -> bool {
+        match result {
+            Err(err) => match err.downcast_ref::<SchedulerError>() {
+                Some(SchedulerError::InvalidRequest(_)) => true,
+                _ => false,
+            },
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_aggregation_statements() {
+        let mut average = craft_aggregate(Aggregation_Function::AVG, "fare");
+        average.set_alias("fare".to_owned());
+        let aggregation = craft_aggregation(
+            &["vendor", "date(pickup)"],
+            vec![craft_aggregate(Aggregation_Function::COUNT, ""), average],
+            "fare > 0",
+        );
+        let (statement, merge_statement) = aggregation_statements(&aggregation).unwrap();
+        assert_eq!(
+            statement,
+            "SELECT vendor AS group_1, date(pickup) AS group_2, COUNT(*) AS partial_1, \
+            SUM(fare) AS partial_2, COUNT(fare) AS partial_3 FROM {partition} WHERE fare > 0 \
+            GROUP BY vendor, date(pickup)"
+        );
+        assert_eq!(
+            merge_statement,
+            "SELECT group_1 AS \"vendor\", group_2 AS \"date(pickup)\", \
+            SUM(partial_1) AS \"COUNT(*)\", \
+            CAST(SUM(partial_2) AS REAL) / SUM(partial_3) AS \"fare\" \
+            FROM partials GROUP BY group_1, group_2"
+        );
+
+        // Without groups, there is one group overall.
+        let aggregation = craft_aggregation(&[], vec![
+            craft_aggregate(Aggregation_Function::SUM, "fare + tip"),
+            craft_aggregate(Aggregation_Function::MIN, "fare"),
+            craft_aggregate(Aggregation_Function::MAX, "fare"),
+        ], "");
+        let (statement, merge_statement) = aggregation_statements(&aggregation).unwrap();
+        assert_eq!(
+            statement,
+            "SELECT SUM(fare + tip) AS partial_1, MIN(fare) AS partial_2, MAX(fare) AS partial_3 \
+            FROM {partition}"
+        );
+        assert_eq!(
+            merge_statement,
+            "SELECT SUM(partial_1) AS \"SUM(fare + tip)\", MIN(partial_2) AS \"MIN(fare)\", \
+            MAX(partial_3) AS \"MAX(fare)\" FROM partials"
+        );
+    }
+
+    #[test]
+    fn test_invalid_aggregations() {
+        assert!(is_invalid_request(aggregation_statements(&craft_aggregation(
+            &["vendor"], vec![], ""
+        ))));
+        // Only COUNT goes without an expression.
+        assert!(is_invalid_request(aggregation_statements(&craft_aggregation(
+            &[], vec![craft_aggregate(Aggregation_Function::SUM, "")], ""
+        ))));
+    }
+
+    #[test]
+    fn test_expand_aggregation() {
+        let mut partitioned = PartitionedWorkload::new();
+        partitioned.set_statement("SELECT COUNT(*) FROM {partition}".to_owned());
+        assert_eq!(expand_aggregation(&partitioned).unwrap(), partitioned);
+
+        let aggregation = craft_aggregation(
+            &[], vec![craft_aggregate(Aggregation_Function::COUNT, "")], ""
+        );
+        partitioned.set_aggregation(aggregation.clone());
+        assert!(expand_aggregation(&partitioned).is_err());
+
+        partitioned.clear_statement();
+        let expanded = expand_aggregation(&partitioned).unwrap();
+        let (statement, merge_statement) = aggregation_statements(&aggregation).unwrap();
+        assert_eq!(expanded.get_statement(), statement);
+        assert_eq!(expanded.get_merge_statement(), merge_statement);
+    }
+}
@@ -0,0 +1,194 @@
+use hyper::{Body, Client, Method, Request, StatusCode};
+use hyper::client::HttpConnector;
+use hyper::header::AUTHORIZATION;
+use serde_json::Value as Json;
+
+use mini_cluster_worker::grpc::SECRET_METADATA_KEY;
+
+use crate::err::{Result, SchedulerError, ErrKind};
+use crate::http::PRINCIPAL_HEADER;
+use crate::keys::Role;
+use crate::scheduler::Drain;
+
+/// A client of a running scheduler's HTTP API (see `http`), as used by the scheduler's command
+/// line. Every call returns the JSON body of the scheduler's answer; answers with an error status
+/// come back as a `RemoteError` carrying the scheduler's error message instead.
+pub struct ApiClient {
+    /// The scheduler's HTTP API, e.g. `http://localhost:8080`.
+    pub url: String,
+    /// The scheduler's shared secret, if it has one.
+    pub secret: Option<String>,
+    /// Who the jobs submitted through the client are put down to in the job history.
+    pub principal: Option<String>,
+    /// The API key to authenticate with (see `keys`), if any. A key goes in place of the secret
+    /// and the principal, which the scheduler ignores if it's given one.
+    pub api_key: Option<String>,
+    client: Client<HttpConnector>,
+}
+
+impl ApiClient {
+    pub fn new(
+        url: &str, secret: Option<String>, principal: Option<String>, api_key: Option<String>
+    ) -> ApiClient {
+        ApiClient {
+            url: url.trim_end_matches('/').to_owned(),
+            secret,
+            principal,
+            api_key,
+            client: Client::new(),
+        }
+    }
+
+    async fn request(&self, method: Method, path: &str, body: Body) -> Result<Json> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.url, path));
+        if let Some(secret) = &self.secret {
+            request = request.header(SECRET_METADATA_KEY, secret.as_str());
+        }
+        if let Some(principal) = &self.principal {
+            request = request.header(PRINCIPAL_HEADER, principal.as_str());
+        }
+        if let Some(api_key) = &self.api_key {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", api_key));
+        }
+        let response = self.client.request(request.body(body)?).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let json: Json = serde_json::from_slice(&body)?;
+        if status != StatusCode::OK && status != StatusCode::CREATED
+            && status != StatusCode::ACCEPTED {
+            let message = json["error"].as_str().unwrap_or("(no error message)");
+            Err(SchedulerError::new(
+                ErrKind::RemoteError, &format!("{} ({})", message, status)
+            ))?
+        }
+        Ok(json)
+    }
+
+    /// Submits a workload spec (see `spec`), in JSON or YAML.
+    pub async fn submit(&self, spec: String) -> Result<Json> {
+        self.request(Method::POST, "/jobs", Body::from(spec)).await
+    }
+
+    pub async fn status(&self, job_id: u64) -> Result<Json> {
+        self.request(Method::GET, &format!("/jobs/{}", job_id), Body::empty()).await
+    }
+
+    pub async fn results(&self, job_id: u64) -> Result<Json> {
+        self.request(Method::GET, &format!("/jobs/{}/results", job_id), Body::empty()).await
+    }
+
+    pub async fn cancel(&self, job_id: u64) -> Result<Json> {
+        self.request(Method::DELETE, &format!("/jobs/{}", job_id), Body::empty()).await
+    }
+
+    /// Lists the job history, from the given Unix timestamp (in seconds) on.
+    pub async fn history(&self, since: f64) -> Result<Json> {
+        self.request(Method::GET, &format!("/jobs?since={}", since), Body::empty()).await
+    }
+
+    pub async fn workers(&self) -> Result<Json> {
+        self.request(Method::GET, "/workers", Body::empty()).await
+    }
+
+    /// Has a worker drain: finish the jobs it has, and take no new ones.
+    pub async fn drain_worker(&self, worker_id: u64) -> Result<Json> {
+        let path = format!("/workers/{}/drain", worker_id);
+        self.request(Method::POST, &path, Body::empty()).await
+    }
+
+    /// Has a worker reload its configuration file.
+    pub async fn reload_worker(&self, worker_id: u64) -> Result<Json> {
+        let path = format!("/workers/{}/reload", worker_id);
+        self.request(Method::POST, &path, Body::empty()).await
+    }
+
+    /// Lists what a worker has cached.
+    pub async fn cache_list(&self, worker_id: u64) -> Result<Json> {
+        let path = format!("/workers/{}/cache", worker_id);
+        self.request(Method::GET, &path, Body::empty()).await
+    }
+
+    /// Has a worker evict a path (or every path matching a pattern) from its cache.
+    pub async fn cache_evict(&self, worker_id: u64, evicted: &str) -> Result<Json> {
+        let path = format!("/workers/{}/cache/evict", worker_id);
+        let body = serde_json::json!({ "path": evicted }).to_string();
+        self.request(Method::POST, &path, Body::from(body)).await
+    }
+
+    /// Has a worker throw away everything it has cached.
+    pub async fn cache_clear(&self, worker_id: u64) -> Result<Json> {
+        let path = format!("/workers/{}/cache/clear", worker_id);
+        self.request(Method::POST, &path, Body::empty()).await
+    }
+
+    /// Lists the API keys, without their secrets.
+    pub async fn keys(&self) -> Result<Json> {
+        self.request(Method::GET, "/keys", Body::empty()).await
+    }
+
+    /// Creates an API key made out to `principal`. The key itself is in the answer, and can't
+    /// be had again afterwards.
+    pub async fn create_key(&self, principal: &str, role: Role) -> Result<Json> {
+        let body = serde_json::json!({ "principal": principal, "role": role.name() }).to_string();
+        self.request(Method::POST, "/keys", Body::from(body)).await
+    }
+
+    pub async fn revoke_key(&self, id: &str) -> Result<Json> {
+        self.request(Method::DELETE, &format!("/keys/{}", id), Body::empty()).await
+    }
+
+    /// Shuts the scheduler down, along with all of the workers if `all` is set, doing with the
+    /// unfinished jobs as `drain` says.
+    pub async fn shutdown(&self, all: bool, drain: Drain) -> Result<Json> {
+        let path = format!("/shutdown?all={}&drain={}", all, drain.name());
+        self.request(Method::POST, &path, Body::empty()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use serial_test::serial;
+    use tokio::time::timeout;
+
+    use mini_cluster_worker::config::WorkerConfig;
+    use mini_cluster_worker::fixtures::spawn_in_process_worker;
+    use mini_cluster_worker::membership::craft_registration;
+
+    use crate::config::SchedulerConfig;
+    use crate::http;
+    use crate::scheduler::Scheduler;
+
+    use super::*;
+
+    /// How long the scheduler and the worker have to stop.
+    const WAIT: Duration = Duration::from_secs(30);
+
+    // What `mini-cluster-scheduler shutdown --all` does.
+    #[tokio::test]
+    #[serial]
+    async fn test_shutdown_all() {
+        let (worker, _) = spawn_in_process_worker(WorkerConfig::default()).await;
+        let config = SchedulerConfig::for_test("shutdown");
+        let scheduler = Arc::new(Scheduler::new(config).await.unwrap());
+        let registration = craft_registration(&worker.address, &worker.advertised, 0);
+        scheduler.roster.register(&registration).unwrap();
+        // The OS picks a free port, which is given back for the HTTP API to bind.
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        tokio::spawn(http::serve(Arc::clone(&scheduler), address));
+        tokio::task::yield_now().await;
+
+        let client = ApiClient::new(&format!("http://{}", address), None, None, None);
+        let answer = client.shutdown(true, Drain::Leave).await.unwrap();
+        assert_eq!(answer["stopping"], true);
+        assert!(answer["failures"].as_array().unwrap().is_empty());
+        assert!(scheduler.roster.workers().is_empty());
+        assert!(timeout(WAIT, scheduler.stopped()).await.is_ok());
+        assert!(timeout(WAIT, worker.stopped()).await.is_ok());
+    }
+}
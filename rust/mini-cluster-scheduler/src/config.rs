@@ -0,0 +1,248 @@
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use mini_cluster_worker::ratelimit::RateLimit;
+use mini_cluster_worker::tls::{ClientTls, Identity};
+use mini_cluster_worker::transport::Address;
+
+use crate::dispatch::DispatchPolicyKind;
+use crate::err::{Result, SchedulerError, ErrKind};
+use crate::quota::TenantQuota;
+
+/// Scheduler configuration. Like the worker's, values are read out of environment variables by
+/// `from_env`.
+#[derive(Debug, Clone)]
+pub struct SchedulerConfig {
+    /// Where the scheduler listens: port 5000 by default, or the Unix domain socket at
+    /// `SCHEDULER_SOCKET`, if that is set. Port 5000 is on the loopback interface only, unless
+    /// `SCHEDULER_HOST` names another one (e.g. `0.0.0.0`) for workers on other machines to
+    /// reach the scheduler at. Workers find the scheduler through their own `WORKER_SCHEDULER`
+    /// setting.
+    pub address: Address,
+    /// Where the scheduler serves its HTTP API (`SCHEDULER_HTTP_ADDRESS`), `0.0.0.0:8080` by
+    /// default. Set to `off` to go without it.
+    pub http_address: Option<SocketAddr>,
+    /// Shared secret workers authenticate with (`WORKER_SECRET`). The scheduler uses the same
+    /// secret to authenticate with the workers in turn.
+    pub secret: Option<String>,
+    /// Whether or not to talk to the workers over TLS, and if so, the CA their certificates are
+    /// signed by (`SCHEDULER_TLS_CA`), and the name to check them against, if not the workers'
+    /// hostnames (`SCHEDULER_TLS_SERVER_NAME`). Workers that only serve clients with a
+    /// certificate are shown the scheduler's (`SCHEDULER_TLS_CERT` and `SCHEDULER_TLS_KEY`).
+    /// See `mini_cluster_worker::tls`.
+    pub tls: Option<ClientTls>,
+    /// Whether or not clients of the HTTP API, and of the framed protocol, have to show an API
+    /// key to submit, fetch or cancel jobs, or to manage the cluster, even if the scheduler has
+    /// no secret (`SCHEDULER_REQUIRE_API_KEYS`). See `keys`.
+    pub require_api_keys: bool,
+    /// How often the scheduler pings each worker it knows about
+    /// (`SCHEDULER_HEARTBEAT_INTERVAL_SECS`).
+    pub heartbeat_interval: Duration,
+    /// How long a worker has to answer a heartbeat before it counts as missed
+    /// (`SCHEDULER_HEARTBEAT_TIMEOUT_SECS`).
+    pub heartbeat_timeout: Duration,
+    /// How many heartbeats in a row a worker may miss before it is considered dead
+    /// (`SCHEDULER_MAX_MISSED_BEATS`).
+    pub max_missed_beats: u32,
+    /// How the scheduler picks the worker each job is sent to (`SCHEDULER_DISPATCH_POLICY`):
+    /// `cost_based` (the default), `least_loaded`, or `round_robin`. See `dispatch`.
+    pub dispatch_policy: DispatchPolicyKind,
+    /// Whether or not to send stragglers among the partitions of a partitioned job to a second
+    /// worker, and if so, how many times longer than the median of its finished siblings a
+    /// partition has to run for to count as one (`SCHEDULER_SPECULATION_FACTOR`, e.g. `2`). Off
+    /// by default, as a duplicate doubles the work done for the partition.
+    pub speculation_factor: Option<f64>,
+    /// How many times a job may be sent out to workers that then die on it before it is failed
+    /// (`SCHEDULER_MAX_ATTEMPTS`), so that a job that brings down every worker it runs on
+    /// doesn't go round the cluster forever.
+    pub max_attempts: u32,
+    /// Where the scheduler keeps its state database (`SCHEDULER_STATE_PATH`), so that its jobs
+    /// and its roster outlive it: `scheduler.sqlite`, in the working directory, by default. Set
+    /// to `off` to keep them in memory only. See `store`.
+    pub state_path: Option<PathBuf>,
+    /// How many worker processes the scheduler launches and looks after itself, on the machine
+    /// it runs on (`SCHEDULER_LOCAL_WORKERS`). None by default. See `local`.
+    pub local_workers: usize,
+    /// The worker binary local workers are launched from (`SCHEDULER_WORKER_BINARY`). By
+    /// default, the `mini-cluster-worker` next to the scheduler's own binary, as Cargo builds
+    /// them, or failing that, the one on the `PATH`.
+    pub worker_binary: Option<PathBuf>,
+    /// The cluster file listing the workers the scheduler knows about from the start
+    /// (`SCHEDULER_CLUSTER_FILE`). By default, `cluster.toml` in the working directory, if there
+    /// is one. See `topology`.
+    pub cluster_file: Option<PathBuf>,
+    /// The limits each tenant is held to. None by default. See `quota`.
+    pub tenant_quota: TenantQuota,
+    /// How long the results of finished jobs are held for, fetched or not
+    /// (`SCHEDULER_RESULTS_TTL_SECS`): a day by default.
+    pub results_ttl: Duration,
+    /// How long shutting down waits for unfinished jobs to finish, when it waits for them at
+    /// all (`SCHEDULER_DRAIN_TIMEOUT_SECS`): ten minutes by default. See `Scheduler::shutdown`.
+    pub drain_timeout: Duration,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> SchedulerConfig {
+        SchedulerConfig {
+            address: Address::from(5000),
+            http_address: Some(SocketAddr::from(([0, 0, 0, 0], 8080))),
+            secret: None,
+            tls: None,
+            require_api_keys: false,
+            heartbeat_interval: Duration::from_secs(5),
+            heartbeat_timeout: Duration::from_secs(5),
+            max_missed_beats: 3,
+            dispatch_policy: DispatchPolicyKind::CostBased,
+            speculation_factor: None,
+            max_attempts: 3,
+            state_path: Some(PathBuf::from("scheduler.sqlite")),
+            local_workers: 0,
+            worker_binary: None,
+            cluster_file: None,
+            tenant_quota: TenantQuota::default(),
+            results_ttl: Duration::from_secs(24 * 60 * 60),
+            drain_timeout: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// Reads an environment variable and parses it, falling back to `default` if it is not set.
+fn parse_env_var<T: std::str::FromStr>(key: &str, default: T) -> Result<T>
+where T::Err: std::error::Error + 'static {
+    match env::var(key) {
+        Ok(v) if !v.is_empty() => Ok(v.parse::<T>()?),
+        _ => Ok(default),
+    }
+}
+
+#[cfg(test)]
+impl SchedulerConfig {
+    /// A configuration for tests: the scheduler listens on a Unix socket named after the test,
+    /// keeps its state in memory only, and serves no HTTP API.
+    pub fn for_test(name: &str) -> SchedulerConfig {
+        let socket = env::temp_dir().join(format!("mini-cluster-{}-scheduler.sock", name));
+        SchedulerConfig {
+            address: Address::from(socket),
+            http_address: None,
+            state_path: None,
+            ..SchedulerConfig::default()
+        }
+    }
+}
+
+impl SchedulerConfig {
+    pub fn from_env() -> Result<SchedulerConfig> {
+        let defaults = SchedulerConfig::default();
+        let address = match (env::var("SCHEDULER_SOCKET"), env::var("SCHEDULER_HOST")) {
+            (Ok(path), _) if !path.is_empty() => Address::from(PathBuf::from(path)),
+            (_, Ok(host)) if !host.is_empty() => Address::Host(host, 5000),
+            _ => defaults.address,
+        };
+        let http_address = match env::var("SCHEDULER_HTTP_ADDRESS") {
+            Ok(v) if v == "off" => None,
+            Ok(v) if !v.is_empty() => Some(v.parse::<SocketAddr>()?),
+            _ => defaults.http_address,
+        };
+        let secret = env::var("WORKER_SECRET").ok().filter(|v| !v.is_empty());
+        let path = |key: &str| env::var(key).ok().filter(|v| !v.is_empty()).map(PathBuf::from);
+        let identity = match (path("SCHEDULER_TLS_CERT"), path("SCHEDULER_TLS_KEY")) {
+            (Some(cert), Some(key)) => Some(Identity { cert, key }),
+            (None, None) => None,
+            _ => Err(SchedulerError::new(
+                ErrKind::ConfigError,
+                "A client certificate takes both SCHEDULER_TLS_CERT and SCHEDULER_TLS_KEY."
+            ))?,
+        };
+        let tls = match path("SCHEDULER_TLS_CA") {
+            Some(ca) => Some(ClientTls {
+                ca,
+                identity,
+                server_name: env::var("SCHEDULER_TLS_SERVER_NAME").ok().filter(|v| !v.is_empty()),
+            }),
+            None if identity.is_some() => Err(SchedulerError::new(
+                ErrKind::ConfigError,
+                "Talking to workers over TLS takes the CA their certificates are signed by \
+                (SCHEDULER_TLS_CA)."
+            ))?,
+            None => defaults.tls,
+        };
+        let require_api_keys =
+            parse_env_var("SCHEDULER_REQUIRE_API_KEYS", defaults.require_api_keys)?;
+        let heartbeat_interval = Duration::from_secs(parse_env_var(
+            "SCHEDULER_HEARTBEAT_INTERVAL_SECS", defaults.heartbeat_interval.as_secs()
+        )?.max(1));
+        let heartbeat_timeout = Duration::from_secs(parse_env_var(
+            "SCHEDULER_HEARTBEAT_TIMEOUT_SECS", defaults.heartbeat_timeout.as_secs()
+        )?.max(1));
+        // A worker that may miss zero heartbeats would be dead on arrival.
+        let max_missed_beats =
+            parse_env_var("SCHEDULER_MAX_MISSED_BEATS", defaults.max_missed_beats)?.max(1);
+        let dispatch_policy = match env::var("SCHEDULER_DISPATCH_POLICY") {
+            Ok(v) if !v.is_empty() => DispatchPolicyKind::from_name(&v)?,
+            _ => defaults.dispatch_policy,
+        };
+        let speculation_factor = Some(parse_env_var("SCHEDULER_SPECULATION_FACTOR", 0.0)?)
+            .filter(|factor: &f64| *factor > 0.0);
+        let max_attempts = parse_env_var("SCHEDULER_MAX_ATTEMPTS", defaults.max_attempts)?.max(1);
+        let state_path = match env::var("SCHEDULER_STATE_PATH") {
+            Ok(v) if v == "off" => None,
+            Ok(v) if !v.is_empty() => Some(PathBuf::from(v)),
+            _ => defaults.state_path,
+        };
+        let local_workers = parse_env_var("SCHEDULER_LOCAL_WORKERS", defaults.local_workers)?;
+        let worker_binary = match env::var("SCHEDULER_WORKER_BINARY") {
+            Ok(v) if !v.is_empty() => Some(PathBuf::from(v)),
+            _ => defaults.worker_binary,
+        };
+        let cluster_file = match env::var("SCHEDULER_CLUSTER_FILE") {
+            Ok(v) if !v.is_empty() => Some(PathBuf::from(v)),
+            _ => Some(PathBuf::from("cluster.toml")).filter(|path| path.exists()),
+        };
+        // Zero means no limit, same as not setting the variable at all.
+        let tenant_quota = TenantQuota {
+            max_running: Some(parse_env_var("SCHEDULER_TENANT_MAX_RUNNING", 0)?)
+                .filter(|max: &usize| *max > 0),
+            max_queued: Some(parse_env_var("SCHEDULER_TENANT_MAX_QUEUED", 0)?)
+                .filter(|max: &usize| *max > 0),
+            max_result_bytes: Some(parse_env_var("SCHEDULER_TENANT_MAX_RESULT_BYTES", 0)?)
+                .filter(|max: &u64| *max > 0),
+            submit_rate: match parse_env_var("SCHEDULER_TENANT_SUBMIT_RATE", 0.0)? {
+                rate if rate > 0.0 => Some(RateLimit::new(
+                    rate,
+                    Some(parse_env_var("SCHEDULER_TENANT_SUBMIT_BURST", 0.0)?)
+                        .filter(|burst: &f64| *burst > 0.0)
+                )?),
+                _ => None,
+            },
+        };
+        let results_ttl = Duration::from_secs(parse_env_var(
+            "SCHEDULER_RESULTS_TTL_SECS", defaults.results_ttl.as_secs()
+        )?);
+        let drain_timeout = Duration::from_secs(parse_env_var(
+            "SCHEDULER_DRAIN_TIMEOUT_SECS", defaults.drain_timeout.as_secs()
+        )?);
+
+        Ok(SchedulerConfig {
+            address,
+            http_address,
+            secret,
+            tls,
+            require_api_keys,
+            heartbeat_interval,
+            heartbeat_timeout,
+            max_missed_beats,
+            dispatch_policy,
+            speculation_factor,
+            max_attempts,
+            state_path,
+            local_workers,
+            worker_binary,
+            cluster_file,
+            tenant_quota,
+            results_ttl,
+            drain_timeout,
+        })
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use mini_cluster_worker::file::get_workload_files;
+use mini_cluster_worker::response::TableStats;
+use mini_cluster_worker::workload::Workload;
+
+use crate::err::{Result, SchedulerError, ErrKind};
+use crate::scheduler::RegisteredWorker;
+
+/// Decides which worker each job is sent to.
+pub trait DispatchPolicy: Send + Sync {
+    /// Picks the worker to send the next job to, out of the live workers on the roster (ordered
+    /// by worker ID). Returns `None` only if there are no workers to pick from.
+    fn pick<'a>(&self, workers: &'a [RegisteredWorker]) -> Option<&'a RegisteredWorker>;
+
+    /// Like `pick`, but for a particular workload, e.g. the one up next in the job queue.
+    /// Policies that don't care what the job is just `pick`.
+    fn pick_for<'a>(
+        &self, workers: &'a [RegisteredWorker], _workload: &Workload
+    ) -> Option<&'a RegisteredWorker> {
+        self.pick(workers)
+    }
+}
+
+/// Sends jobs to each of the workers in turn.
+pub struct RoundRobin {
+    // The ID of the worker that got the last job. Workers come and go, so remembering a worker
+    // ID (and not a position on the roster) is what keeps the turns fair.
+    last: AtomicU64,
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin { last: AtomicU64::new(0) }
+    }
+}
+
+impl DispatchPolicy for RoundRobin {
+    fn pick<'a>(&self, workers: &'a [RegisteredWorker]) -> Option<&'a RegisteredWorker> {
+        let last = self.last.load(Ordering::SeqCst);
+        let worker = workers.iter().find(|worker| worker.id > last).or_else(|| workers.first())?;
+        self.last.store(worker.id, Ordering::SeqCst);
+        Some(worker)
+    }
+}
+
+/// Sends each job to the worker with the least work on its plate.
+pub struct LeastLoaded;
+
+impl LeastLoaded {
+    /// How much work a worker has on its plate. The queue depth and running job count the
+    /// worker reported are only as fresh as its last heartbeat, so the jobs sent to it since
+    /// (which are among its in-flight jobs) count too. This double-counts the scheduler's own
+    /// jobs until the next heartbeat comes in, which errs on the side of spreading jobs out.
+    pub fn load(worker: &RegisteredWorker) -> usize {
+        worker.queue_depth as usize + worker.running_jobs as usize + worker.in_flight.len()
+    }
+}
+
+impl DispatchPolicy for LeastLoaded {
+    fn pick<'a>(&self, workers: &'a [RegisteredWorker]) -> Option<&'a RegisteredWorker> {
+        // `min_by_key` keeps the first of several equally loaded workers, e.g. the one with the
+        // lowest worker ID.
+        workers.iter().min_by_key(|worker| LeastLoaded::load(worker))
+    }
+}
+
+/// How many bytes of downloading cost as much as one more job on a worker's plate.
+pub const BYTES_PER_JOB: f64 = 256.0 * 1024.0 * 1024.0;
+/// How many rows of loading into a table cost as much as one more job on a worker's plate.
+pub const LOADED_ROWS_PER_JOB: f64 = 1_000_000.0;
+/// How many rows of scanning a table that's already loaded cost as much as one more job on a
+/// worker's plate. Scanning is a good deal cheaper than parsing and inserting.
+pub const SCANNED_ROWS_PER_JOB: f64 = 20_000_000.0;
+
+/// What running a workload on a particular worker would take, going by the table statistics
+/// the workers report (see `mini_cluster_worker::stats`).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cost {
+    /// The bytes of the files the worker hasn't loaded yet.
+    pub download_bytes: u64,
+    /// The rows of the files the worker hasn't loaded yet, which it has to load.
+    pub load_rows: u64,
+    /// The rows of all of the workload's files, which its ops scan.
+    pub scan_rows: u64,
+}
+
+impl Cost {
+    /// Estimates what running a workload on `worker` would take. A file the worker has loaded
+    /// costs nothing to download, and its rows are only scanned. A file it hasn't has to be
+    /// downloaded and loaded, and is taken to be as big as it is wherever else it was loaded.
+    /// Files no worker has loaded yet cost the same everywhere, so they're left out.
+    pub fn estimate(
+        workload: &Workload, worker: &RegisteredWorker, known: &KnownFiles<'_>
+    ) -> Cost {
+        let mut cost = Cost::default();
+        for file in get_workload_files(workload) {
+            let path = file.get_path();
+            if let Some(stats) = worker.tables.iter().find(|stats| stats.get_path() == path) {
+                cost.scan_rows += stats.get_rows();
+            } else if let Some(stats) = known.get(path) {
+                cost.download_bytes += stats.get_bytes();
+                cost.load_rows += stats.get_rows();
+                cost.scan_rows += stats.get_rows();
+            }
+        }
+        cost
+    }
+
+    /// The cost, in jobs: i.e. how many more jobs on the worker's plate it's worth.
+    pub fn in_jobs(&self) -> f64 {
+        self.download_bytes as f64 / BYTES_PER_JOB
+            + self.load_rows as f64 / LOADED_ROWS_PER_JOB
+            + self.scan_rows as f64 / SCANNED_ROWS_PER_JOB
+    }
+}
+
+/// The statistics of every file any of the workers has loaded, by path. Where several workers
+/// have, the first one's statistics win.
+pub type KnownFiles<'a> = HashMap<&'a str, &'a TableStats>;
+
+/// Collects the statistics of the files the given workers have loaded.
+pub fn known_files(workers: &[RegisteredWorker]) -> KnownFiles<'_> {
+    let mut known = HashMap::new();
+    for stats in workers.iter().flat_map(|worker| worker.tables.iter()) {
+        if !stats.get_path().is_empty() {
+            known.entry(stats.get_path()).or_insert(stats);
+        }
+    }
+    known
+}
+
+/// Sends each job to the worker where it would be done soonest: the one with the least work on
+/// its plate (see `LeastLoaded::load`), once what the job itself would cost there (see `Cost`)
+/// is added on. This tends to send jobs to the workers that have their files loaded already,
+/// unless those are much busier than the rest.
+///
+/// A worker's tables may be out of date, which the scheduler can't tell without asking S3. At
+/// worst, the worker just has to load the file again, like any other would have.
+pub struct CostBased;
+
+impl DispatchPolicy for CostBased {
+    fn pick<'a>(&self, workers: &'a [RegisteredWorker]) -> Option<&'a RegisteredWorker> {
+        LeastLoaded.pick(workers)
+    }
+
+    fn pick_for<'a>(
+        &self, workers: &'a [RegisteredWorker], workload: &Workload
+    ) -> Option<&'a RegisteredWorker> {
+        let known = known_files(workers);
+        let score = |worker: &RegisteredWorker| {
+            LeastLoaded::load(worker) as f64 + Cost::estimate(workload, worker, &known).in_jobs()
+        };
+        // As with `LeastLoaded`, the first of several equally good workers is kept.
+        workers.iter().fold(None, |best: Option<(&RegisteredWorker, f64)>, worker| {
+            let worker_score = score(worker);
+            match best {
+                Some((_, best_score)) if best_score <= worker_score => best,
+                _ => Some((worker, worker_score)),
+            }
+        }).map(|(worker, _)| worker)
+    }
+}
+
+/// The dispatch policies that ship with the scheduler.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DispatchPolicyKind {
+    RoundRobin,
+    LeastLoaded,
+    CostBased,
+}
+
+impl DispatchPolicyKind {
+    pub fn from_name(name: &str) -> Result<DispatchPolicyKind> {
+        match name.trim().to_lowercase().replace("-", "_").as_str() {
+            "round_robin" => Ok(DispatchPolicyKind::RoundRobin),
+            "least_loaded" => Ok(DispatchPolicyKind::LeastLoaded),
+            "cost_based" => Ok(DispatchPolicyKind::CostBased),
+            _ => Err(SchedulerError::new(
+                ErrKind::ConfigError, &format!("Unknown dispatch policy {:?}.", name)
+            ))?,
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn DispatchPolicy> {
+        match self {
+            DispatchPolicyKind::RoundRobin => Box::new(RoundRobin::new()),
+            DispatchPolicyKind::LeastLoaded => Box::new(LeastLoaded),
+            DispatchPolicyKind::CostBased => Box::new(CostBased),
+        }
+    }
+}
@@ -5,9 +5,17 @@ use std::fmt;
 use std::result;
 use std::io;
 
+use mini_cluster_worker::response::ErrorResponse_Kind;
+
 #[derive(Debug)]
 pub enum SchedulerError {
     NetworkError(io::Error),
+    /// Carries the kind of error the worker reported, so callers needn't parse the message.
+    RemoteError(ErrorResponse_Kind, io::Error),
+    ConfigError(io::Error),
+    InvalidRequest(io::Error),
+    QuotaExceeded(io::Error),
+    Unacknowledged(io::Error),
 }
 
 impl fmt::Display for SchedulerError {
@@ -16,6 +24,21 @@ impl fmt::Display for SchedulerError {
             SchedulerError::NetworkError(err) => {
                 write!(f, "NetworkError when trying to connect to the worker: {}", err)
             },
+            SchedulerError::RemoteError(_, err) => {
+                write!(f, "RemoteError reported by the worker: {}", err)
+            },
+            SchedulerError::ConfigError(err) => {
+                write!(f, "ConfigError when reading the scheduler configuration: {}", err)
+            },
+            SchedulerError::InvalidRequest(err) => {
+                write!(f, "InvalidRequest sent by the client: {}", err)
+            },
+            SchedulerError::QuotaExceeded(err) => {
+                write!(f, "QuotaExceeded by the tenant: {}", err)
+            },
+            SchedulerError::Unacknowledged(err) => {
+                write!(f, "Unacknowledged workload, the worker may or may not have it: {}", err)
+            }
         }
     }
 }
@@ -27,6 +50,11 @@ pub type Result<T> = result::Result<T, Box<dyn Error>>;
 #[derive(Debug)]
 pub enum ErrKind {
     NetworkError,
+    RemoteError,
+    ConfigError,
+    InvalidRequest,
+    QuotaExceeded,
+    Unacknowledged,
 }
 
 impl SchedulerError {
@@ -35,6 +63,24 @@ impl SchedulerError {
             ErrKind::NetworkError => {
                 SchedulerError::NetworkError(io::Error::new(io::ErrorKind::Other, msg))
             },
+            ErrKind::RemoteError => SchedulerError::remote(ErrorResponse_Kind::INTERNAL, msg),
+            ErrKind::ConfigError => {
+                SchedulerError::ConfigError(io::Error::new(io::ErrorKind::Other, msg))
+            },
+            ErrKind::InvalidRequest => {
+                SchedulerError::InvalidRequest(io::Error::new(io::ErrorKind::Other, msg))
+            },
+            ErrKind::QuotaExceeded => {
+                SchedulerError::QuotaExceeded(io::Error::new(io::ErrorKind::Other, msg))
+            },
+            ErrKind::Unacknowledged => {
+                SchedulerError::Unacknowledged(io::Error::new(io::ErrorKind::Other, msg))
+            },
         }
     }
+
+    /// A `RemoteError` of the given kind, for errors the worker reported itself.
+    pub fn remote(kind: ErrorResponse_Kind, msg: &str) -> SchedulerError {
+        SchedulerError::RemoteError(kind, io::Error::new(io::ErrorKind::Other, msg))
+    }
 }
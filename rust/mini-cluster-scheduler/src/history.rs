@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use mini_cluster_worker::workload::Workload;
+
+/// Who jobs submitted without saying who submitted them are recorded as, e.g. jobs submitted
+/// over the framed protocol, which has no notion of users.
+pub const ANONYMOUS: &str = "anonymous";
+
+// The job history is an audit log of everything that happened to every job: who submitted it
+// and which data it reads, which worker it was sent to (and sent to again, if it had to be
+// re-queued), and how it ended. It answers "who ran what against which data", long after the
+// fact, as the history is kept in the state database along with the jobs (see `store`).
+//
+// Principals are whatever the client says they are (see `http`), so the history is a record of
+// who is doing what, not a way to keep anyone out; that's what the scheduler's secret is for.
+
+/// Something that happened to a job.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Submitted,
+    Dispatched,
+    /// The job was re-queued, e.g. because its worker died.
+    Retried,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Submitted => "submitted",
+            EventKind::Dispatched => "dispatched",
+            EventKind::Retried => "retried",
+            EventKind::Completed => "completed",
+            EventKind::Failed => "failed",
+            EventKind::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<EventKind> {
+        match name {
+            "submitted" => Some(EventKind::Submitted),
+            "dispatched" => Some(EventKind::Dispatched),
+            "retried" => Some(EventKind::Retried),
+            "completed" => Some(EventKind::Completed),
+            "failed" => Some(EventKind::Failed),
+            "cancelled" => Some(EventKind::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+/// An entry in the job history.
+#[derive(Debug, Clone)]
+pub struct JobEvent {
+    pub job_id: u64,
+    pub kind: EventKind,
+    /// When it happened, in milliseconds since the Unix epoch.
+    pub at: u64,
+    /// Who submitted the job. The partitions of a partitioned job count as submitted by
+    /// whoever submitted the job.
+    pub principal: String,
+    /// The worker it happened on, if any.
+    pub worker_id: Option<u64>,
+    /// What else there is to say, e.g. the files a submitted job reads, or why a job failed.
+    pub detail: String,
+}
+
+/// The current time, in milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64)
+}
+
+/// Describes the data a workload reads, for the history: the paths of the files its ops target.
+pub fn describe_reads(workload: &Workload) -> String {
+    let mut paths = vec![];
+    for file in workload.get_ops().iter().flat_map(|op| op.get_targets().iter()) {
+        if !paths.contains(&file.get_path()) {
+            paths.push(file.get_path());
+        }
+    }
+    if paths.is_empty() {
+        "reads no files".to_owned()
+    } else {
+        format!("reads {}", paths.join(", "))
+    }
+}
+
+/// The job history.
+pub struct History {
+    // As with the job queue, these locks are never held across an `.await`, nor at the same
+    // time.
+    events: Mutex<Vec<JobEvent>>,
+    principals: Mutex<HashMap<u64, String>>,
+    // The events that haven't been written to the state database yet (see `take_unsaved`).
+    unsaved: Mutex<Vec<JobEvent>>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            events: Mutex::new(vec![]),
+            principals: Mutex::new(HashMap::new()),
+            unsaved: Mutex::new(vec![]),
+        }
+    }
+
+    /// Puts back the events read out of the state database when the scheduler starts.
+    pub fn restore(&self, restored: Vec<JobEvent>) {
+        let mut principals = self.principals.lock().unwrap();
+        for event in restored.iter().filter(|event| event.kind == EventKind::Submitted) {
+            principals.insert(event.job_id, event.principal.clone());
+        }
+        drop(principals);
+        self.events.lock().unwrap().extend(restored);
+    }
+
+    fn push(&self, event: JobEvent) {
+        self.unsaved.lock().unwrap().push(event.clone());
+        self.events.lock().unwrap().push(event);
+    }
+
+    /// Records that a job was submitted, and by whom.
+    pub fn submitted(&self, job_id: u64, principal: &str, detail: &str) {
+        self.principals.lock().unwrap().insert(job_id, principal.to_owned());
+        self.push(JobEvent {
+            job_id,
+            kind: EventKind::Submitted,
+            at: now_millis(),
+            principal: principal.to_owned(),
+            worker_id: None,
+            detail: detail.to_owned(),
+        });
+    }
+
+    /// Records something that happened to a job after it was submitted. `owner` is the job
+    /// whose submitter the event is put down to: the job itself, or for a partition, its
+    /// partitioned job.
+    pub fn record(
+        &self, job_id: u64, owner: u64, kind: EventKind, worker_id: Option<u64>, detail: &str
+    ) {
+        let principal = self.principal(owner);
+        self.push(JobEvent {
+            job_id, kind, at: now_millis(), principal, worker_id, detail: detail.to_owned()
+        });
+    }
+
+    /// Who submitted a job (or, for a partition, the partitioned job given as `owner`).
+    pub fn principal(&self, owner: u64) -> String {
+        self.principals.lock().unwrap().get(&owner)
+            .cloned()
+            .unwrap_or_else(|| ANONYMOUS.to_owned())
+    }
+
+    /// Returns the events that happened at or after `since` (in milliseconds since the Unix
+    /// epoch), oldest first.
+    pub fn since(&self, since: u64) -> Vec<JobEvent> {
+        self.events.lock().unwrap().iter()
+            .filter(|event| event.at >= since)
+            .cloned()
+            .collect()
+    }
+
+    /// When a job was submitted, in milliseconds since the Unix epoch.
+    pub fn submitted_at(&self, job_id: u64) -> Option<u64> {
+        self.events.lock().unwrap().iter()
+            .find(|event| event.job_id == job_id && event.kind == EventKind::Submitted)
+            .map(|event| event.at)
+    }
+
+    /// Takes the events recorded since the last call, to be written to the state database.
+    pub fn take_unsaved(&self) -> Vec<JobEvent> {
+        std::mem::take(&mut *self.unsaved.lock().unwrap())
+    }
+
+    /// Puts events back to be written again next time, e.g. because writing them failed.
+    pub fn mark_unsaved(&self, events: Vec<JobEvent>) {
+        let mut unsaved = self.unsaved.lock().unwrap();
+        let newer = std::mem::replace(&mut *unsaved, events);
+        unsaved.extend(newer);
+    }
+}
@@ -0,0 +1,674 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, LOCATION, RETRY_AFTER};
+use hyper::service::{make_service_fn, service_fn};
+use serde_json::{json, Value as Json};
+
+use mini_cluster_worker::auth::secrets_match;
+use mini_cluster_worker::cluster::{ClusterJob, ClusterJob_State};
+use mini_cluster_worker::grpc::SECRET_METADATA_KEY;
+use mini_cluster_worker::ratelimit::RateLimited;
+use mini_cluster_worker::response::{CacheReport, ResultBatch, Value, WorkerCapabilities};
+use mini_cluster_worker::result::{collect_result_sets, format_value};
+use mini_cluster_worker::workload::{CacheCommand, CacheCommand_Action};
+
+use crate::err::{Result, SchedulerError};
+use crate::history::{JobEvent, ANONYMOUS};
+use crate::keys::{ApiKey, Role};
+use crate::queue::JobState;
+use crate::scheduler::{Drain, RegisteredWorker, Scheduler};
+use crate::spec::WorkloadSpec;
+
+// The HTTP API is a JSON front to the scheduler, for clients that would rather not speak the
+// framed protocol (or protobuf). It covers the basics:
+//
+//     POST   /jobs               submits a workload, answering with the job's ID
+//     GET    /jobs?since={time}  lists the job history from a Unix timestamp on (see `history`)
+//     GET    /jobs/{id}          describes a job, like a JOB_QUERY frame does
+//     GET    /jobs/{id}/results  returns the result sets of a job that is done
+//     DELETE /jobs/{id}          cancels a job
+//     GET    /workers            lists the workers on the roster
+//     POST   /workers/{id}/drain has a worker finish its jobs and take no new ones, e.g. ahead
+//                                 of restarting it
+//     POST   /workers/{id}/reload
+//                                 has a worker reload its configuration file
+//     GET    /workers/{id}/cache lists what a worker has cached: paths, sizes, ages, and tables
+//     POST   /workers/{id}/cache/evict
+//                                 has a worker evict the path (or pattern) in the JSON body's
+//                                 `path` from its cache, along with the tables loaded from it
+//     POST   /workers/{id}/cache/clear
+//                                 has a worker throw away everything it has cached
+//     GET    /keys               lists the API keys (see `keys`), without their secrets
+//     POST   /keys               creates an API key for the JSON body's `principal`, with its
+//                                 `role` (`submit` or `admin`), answering with the key
+//     DELETE /keys/{id}          revokes an API key
+//     POST   /shutdown           shuts the scheduler down (and with `?all=true`, the workers),
+//                                 waiting for unfinished jobs first, or with `?drain=leave` or
+//                                 `?drain=cancel`, leaving them be or cancelling them
+//
+// Workloads are sent as workload specs (see `spec`), in JSON or YAML. Clients show an API key
+// in the `Authorization` header, and are taken to be whoever the key was made out to, and
+// allowed to do what its role allows (see `keys`). Otherwise, if the scheduler has a secret,
+// every request has to carry it in the same header gRPC clients of the workers use, and clients
+// say who they are in the `PRINCIPAL_HEADER` header. Either way, that's who goes into the job
+// history. A submission can carry an idempotency key (see `Workload.idempotency_key`) in its
+// spec, or in the customary `IDEMPOTENCY_KEY_HEADER` header, which wins if it has both.
+
+/// The header clients put their name (e.g. their user name) in.
+pub const PRINCIPAL_HEADER: &str = "x-mini-cluster-principal";
+
+/// The header clients put a submission's idempotency key in.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Serves the HTTP API on the given address, for as long as the scheduler runs. Once the
+/// scheduler is asked to shut down, requests that are already in progress are seen through
+/// (e.g. the one asking it to), and this returns.
+pub async fn serve(scheduler: Arc<Scheduler>, address: SocketAddr) -> Result<()> {
+    let stopping = Arc::clone(&scheduler);
+    let make_service = make_service_fn(move |_| {
+        let scheduler = Arc::clone(&scheduler);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(Arc::clone(&scheduler), request)
+            }))
+        }
+    });
+    Server::try_bind(&address)?
+        .serve(make_service)
+        .with_graceful_shutdown(async move { stopping.stopped().await })
+        .await?;
+    Ok(())
+}
+
+fn json_response(status: StatusCode, body: Json) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    response
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, json!({ "error": message }))
+}
+
+/// Who a request comes from: who it's put down to, and what it may do (see `keys`). Callers
+/// without a role may only look at jobs and workers.
+struct Caller {
+    principal: String,
+    role: Option<Role>,
+}
+
+/// Works out who a request comes from, from the API key or the secret it carries, if any.
+/// Requests carrying the wrong one are answered with UNAUTHORIZED.
+fn authenticate(
+    scheduler: &Scheduler, request: &Request<Body>
+) -> std::result::Result<Caller, Response<Body>> {
+    let headers = request.headers();
+    if let Some(authorization) = headers.get(AUTHORIZATION) {
+        let key = authorization.to_str().ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or("");
+        return match scheduler.api_keys.authenticate(key.trim()) {
+            Some(key) => Ok(Caller { principal: key.principal, role: Some(key.role) }),
+            None => Err(error_response(StatusCode::UNAUTHORIZED, "Unknown or malformed API key.")),
+        };
+    }
+    let principal = headers.get(PRINCIPAL_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .unwrap_or(ANONYMOUS)
+        .to_owned();
+    if let Some(secret) = &scheduler.config.secret {
+        let provided = headers.get(SECRET_METADATA_KEY)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        if !secrets_match(secret, provided) {
+            return Err(error_response(
+                StatusCode::UNAUTHORIZED, "Missing or incorrect secret or API key."
+            ));
+        }
+        return Ok(Caller { principal, role: Some(Role::Admin) });
+    }
+    let role = match scheduler.config.require_api_keys {
+        true => None,
+        false => Some(Role::Admin),
+    };
+    Ok(Caller { principal, role })
+}
+
+/// The role an endpoint takes, if it takes one at all. Anything that isn't looking at jobs or
+/// workers, or submitting or cancelling jobs, is for admins.
+fn required_role(method: &Method, segments: &[&str]) -> Option<Role> {
+    match (method, segments) {
+        (&Method::GET, ["jobs"]) | (&Method::GET, ["jobs", _])
+        | (&Method::GET, ["jobs", _, "results"]) | (&Method::GET, ["workers"]) => None,
+        (&Method::POST, ["jobs"]) | (&Method::DELETE, ["jobs", _]) => Some(Role::Submit),
+        _ => Some(Role::Admin),
+    }
+}
+
+async fn handle(
+    scheduler: Arc<Scheduler>, request: Request<Body>
+) -> std::result::Result<Response<Body>, Infallible> {
+    let caller = match authenticate(&scheduler, &request) {
+        Ok(caller) => caller,
+        Err(response) => return Ok(response),
+    };
+
+    let method = request.method().clone();
+    let path = request.uri().path().trim_matches('/').to_owned();
+    let segments = path.split('/').collect::<Vec<_>>();
+    if let Some(required) = required_role(&method, segments.as_slice()) {
+        match caller.role {
+            Some(role) if role.allows(required) => {},
+            Some(_) => return Ok(error_response(
+                StatusCode::FORBIDDEN,
+                &format!("{} /{} takes an API key with the {} role.", method, path, required.name())
+            )),
+            None => return Ok(error_response(
+                StatusCode::UNAUTHORIZED,
+                &format!("{} /{} takes an API key with the {} role.", method, path, required.name())
+            )),
+        }
+    }
+    let response = match (&method, segments.as_slice()) {
+        (&Method::POST, ["jobs"]) => {
+            let key = request.headers().get(IDEMPOTENCY_KEY_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .filter(|v| !v.is_empty())
+                .map(|v| v.to_owned());
+            submit(&scheduler, request.into_body(), &caller.principal, key).await
+        },
+        (&Method::GET, ["jobs"]) => history(&scheduler, request.uri().query()),
+        (&Method::GET, ["jobs", id]) => with_job_id(id, |id| describe(&scheduler, id)),
+        (&Method::GET, ["jobs", id, "results"]) => with_job_id(id, |id| results(&scheduler, id)),
+        (&Method::DELETE, ["jobs", id]) => match id.parse::<u64>() {
+            Ok(id) => cancel(&scheduler, &caller, id).await,
+            Err(_) => error_response(StatusCode::BAD_REQUEST, "Job IDs are numbers."),
+        },
+        (&Method::GET, ["workers"]) => workers(&scheduler),
+        (&Method::POST, ["workers", id, "drain"]) => match id.parse::<u64>() {
+            Ok(id) => drain_worker(&scheduler, id).await,
+            Err(_) => error_response(StatusCode::BAD_REQUEST, "Worker IDs are numbers."),
+        },
+        (&Method::POST, ["workers", id, "reload"]) => match id.parse::<u64>() {
+            Ok(id) => reload_worker(&scheduler, id).await,
+            Err(_) => error_response(StatusCode::BAD_REQUEST, "Worker IDs are numbers."),
+        },
+        (&Method::GET, ["workers", id, "cache"]) => match id.parse::<u64>() {
+            Ok(id) => {
+                let mut command = CacheCommand::new();
+                command.set_action(CacheCommand_Action::LIST);
+                worker_cache(&scheduler, id, &command).await
+            },
+            Err(_) => error_response(StatusCode::BAD_REQUEST, "Worker IDs are numbers."),
+        },
+        (&Method::POST, ["workers", id, "cache", "evict"]) => match id.parse::<u64>() {
+            Ok(id) => match eviction(request.into_body()).await {
+                Ok(command) => worker_cache(&scheduler, id, &command).await,
+                Err(message) => error_response(StatusCode::BAD_REQUEST, &message),
+            },
+            Err(_) => error_response(StatusCode::BAD_REQUEST, "Worker IDs are numbers."),
+        },
+        (&Method::POST, ["workers", id, "cache", "clear"]) => match id.parse::<u64>() {
+            Ok(id) => {
+                let mut command = CacheCommand::new();
+                command.set_action(CacheCommand_Action::CLEAR);
+                worker_cache(&scheduler, id, &command).await
+            },
+            Err(_) => error_response(StatusCode::BAD_REQUEST, "Worker IDs are numbers."),
+        },
+        (&Method::GET, ["keys"]) => keys(&scheduler),
+        (&Method::POST, ["keys"]) => create_key(&scheduler, request.into_body()).await,
+        (&Method::DELETE, ["keys", id]) => revoke_key(&scheduler, id).await,
+        (&Method::POST, ["shutdown"]) => {
+            let query = request.uri().query().unwrap_or("");
+            let all = query.split('&').any(|pair| pair == "all=true" || pair == "all");
+            let drain = query.split('&')
+                .find_map(|pair| pair.strip_prefix("drain="))
+                .unwrap_or("wait")
+                .parse::<Drain>();
+            match drain {
+                Ok(drain) => shutdown(&scheduler, all, drain).await,
+                Err(err) => error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+            }
+        },
+        _ => error_response(
+            StatusCode::NOT_FOUND, &format!("No such endpoint: {} /{}.", method, path)
+        ),
+    };
+    Ok(response)
+}
+
+fn with_job_id<F: FnOnce(u64) -> Response<Body>>(id: &str, f: F) -> Response<Body> {
+    match id.parse::<u64>() {
+        Ok(id) => f(id),
+        Err(_) => error_response(StatusCode::BAD_REQUEST, "Job IDs are numbers."),
+    }
+}
+
+fn not_found(id: u64) -> Response<Body> {
+    error_response(StatusCode::NOT_FOUND, &format!("No job with ID {}.", id))
+}
+
+async fn submit(
+    scheduler: &Scheduler, body: Body, principal: &str, key: Option<String>
+) -> Response<Body> {
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(v) => v,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let workload = match std::str::from_utf8(&body) {
+        Ok(text) => WorkloadSpec::parse(text).and_then(|spec| spec.to_workload())
+            .map_err(|err| error_response(StatusCode::BAD_REQUEST, &err.to_string())),
+        Err(_) => {
+            Err(error_response(StatusCode::BAD_REQUEST, "The workload spec is not valid UTF-8."))
+        },
+    };
+    let submitted = workload.and_then(|mut workload| {
+        if let Some(key) = key {
+            workload.set_idempotency_key(key);
+        }
+        scheduler.submit(workload, principal).map_err(|err| rejected(&*err))
+    });
+    match submitted {
+        Ok(job_id) => {
+            let mut response = json_response(
+                StatusCode::CREATED,
+                json!({ "job_id": job_id, "queue_depth": scheduler.jobs.depth() })
+            );
+            response.headers_mut().insert(
+                LOCATION, format!("/jobs/{}", job_id).parse().unwrap()
+            );
+            response
+        },
+        Err(response) => response,
+    }
+}
+
+/// The response to a submission the scheduler turned away. A tenant over its rate limit is
+/// told when it may try again, in whole seconds, as `Retry-After` has it.
+fn rejected(err: &(dyn Error + 'static)) -> Response<Body> {
+    if let Some(limited) = err.downcast_ref::<RateLimited>() {
+        let mut response = error_response(StatusCode::TOO_MANY_REQUESTS, &err.to_string());
+        let retry_after = (limited.retry_after_millis() + 999) / 1000;
+        response.headers_mut().insert(RETRY_AFTER, retry_after.to_string().parse().unwrap());
+        return response;
+    }
+    let status = match err.downcast_ref::<SchedulerError>() {
+        Some(SchedulerError::QuotaExceeded(_)) => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    error_response(status, &err.to_string())
+}
+
+fn describe_key(key: &ApiKey) -> Json {
+    json!({
+        "id": key.id,
+        "principal": key.principal,
+        "role": key.role.name(),
+        "created_at": key.created_at as f64 / 1000.0,
+    })
+}
+
+fn keys(scheduler: &Scheduler) -> Response<Body> {
+    let keys = scheduler.api_keys.list().iter().map(describe_key).collect::<Vec<_>>();
+    json_response(StatusCode::OK, json!({ "keys": keys }))
+}
+
+/// Creates an API key for the principal and role in a JSON body, e.g.
+/// `{"principal": "etl", "role": "submit"}`. The role is `submit` unless it says otherwise.
+async fn create_key(scheduler: &Scheduler, body: Body) -> Response<Body> {
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(v) => v,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let json: Json = match serde_json::from_slice(&body) {
+        Ok(json) => json,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let principal = json["principal"].as_str().unwrap_or("");
+    let role = match json["role"].as_str().unwrap_or("submit").parse::<Role>() {
+        Ok(role) => role,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    match scheduler.create_api_key(principal, role).await {
+        Ok((key, token)) => {
+            let mut described = describe_key(&key);
+            described["key"] = json!(token);
+            json_response(StatusCode::CREATED, described)
+        },
+        Err(err) => error_response(StatusCode::BAD_REQUEST, &err.to_string()),
+    }
+}
+
+async fn revoke_key(scheduler: &Scheduler, id: &str) -> Response<Body> {
+    match scheduler.revoke_api_key(id).await {
+        Ok(Some(key)) => json_response(
+            StatusCode::OK, json!({ "id": key.id, "principal": key.principal, "revoked": true })
+        ),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, &format!("No API key with ID {}.", id)),
+        Err(err) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+fn describe_event(event: &JobEvent) -> Json {
+    json!({
+        "job_id": event.job_id,
+        "event": event.kind.name(),
+        "at": event.at as f64 / 1000.0,
+        "principal": event.principal,
+        "worker_id": event.worker_id,
+        "detail": event.detail,
+    })
+}
+
+/// Lists the job history, from the Unix timestamp (in seconds) in the `since` parameter on, or
+/// all of it if there is none.
+fn history(scheduler: &Scheduler, query: Option<&str>) -> Response<Body> {
+    let since = query.unwrap_or("").split('&')
+        .find_map(|pair| pair.strip_prefix("since="))
+        .map(|since| since.parse::<f64>());
+    let since = match since {
+        Some(Ok(since)) if since >= 0.0 => (since * 1000.0) as u64,
+        Some(_) => return error_response(
+            StatusCode::BAD_REQUEST, "`since` is a Unix timestamp, in seconds."
+        ),
+        None => 0,
+    };
+    let events = scheduler.history.since(since).iter().map(describe_event).collect::<Vec<_>>();
+    json_response(StatusCode::OK, json!({ "events": events }))
+}
+
+fn describe(scheduler: &Scheduler, id: u64) -> Response<Body> {
+    let job = match scheduler.jobs.describe(id) {
+        Some(job) => job,
+        None => return not_found(id),
+    };
+    json_response(StatusCode::OK, describe_job(&job))
+}
+
+/// Describes a job the way the API shows it. Jobs that are done link to their results.
+pub fn describe_job(job: &ClusterJob) -> Json {
+    let mut description = json!({
+        "job_id": job.get_job_id(),
+        "state": format!("{:?}", job.get_state()),
+        "worker_id": job.get_worker_id(),
+        "worker_job_id": job.get_worker_job_id(),
+        "n_rows": job.get_n_rows(),
+        "error": job.get_error(),
+        "attempts": job.get_attempts(),
+        "partitions": job.get_partitions(),
+    });
+    if job.has_progress() {
+        let progress = job.get_progress();
+        description["progress"] = json!({
+            "files_loaded": progress.get_files_loaded(),
+            "files_total": progress.get_files_total(),
+            "rows_loaded": progress.get_rows_loaded(),
+            "bytes_downloaded": progress.get_bytes_downloaded(),
+            "bytes_loaded": progress.get_bytes_loaded(),
+            "current_op": progress.get_current_op(),
+            "ops_done": progress.get_ops_done(),
+            "ops_total": progress.get_ops_total(),
+        });
+    }
+    if job.get_state() == ClusterJob_State::DONE {
+        description["results"] = json!(format!("/jobs/{}/results", job.get_job_id()));
+    }
+    description
+}
+
+fn results(scheduler: &Scheduler, id: u64) -> Response<Body> {
+    match scheduler.jobs.state(id) {
+        None => not_found(id),
+        Some(JobState::Done { .. }) => {
+            let batches = scheduler.jobs.results(id).unwrap_or_default();
+            scheduler.jobs.mark_fetched(id);
+            let result_sets = collect_result_sets(batches).into_iter()
+                .map(|(_, result_set)| describe_result_set(&result_set))
+                .collect::<Vec<_>>();
+            json_response(StatusCode::OK, json!({ "job_id": id, "result_sets": result_sets }))
+        },
+        // Unlike FETCH, this doesn't wait for the job to finish: HTTP clients are expected to
+        // poll `GET /jobs/{id}` until it's done.
+        Some(_) => error_response(
+            StatusCode::CONFLICT, &format!("Job {} is not done (yet).", id)
+        ),
+    }
+}
+
+fn describe_result_set(result_set: &ResultBatch) -> Json {
+    let rows = result_set.get_rows().iter()
+        .map(|row| row.get_values().iter().map(describe_value).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    json!({
+        "op_sequence_num": result_set.get_op_sequence_num(),
+        "columns": result_set.get_columns(),
+        "rows": rows,
+        "truncated": result_set.get_truncated(),
+    })
+}
+
+/// Turns a result value into JSON. Numbers stay numbers, and NULLs become `null`; everything
+/// else is shown as text, the same way the worker prints it.
+fn describe_value(value: &Value) -> Json {
+    if value.has_integer() {
+        json!(value.get_integer())
+    } else if value.has_real() {
+        json!(value.get_real())
+    } else if value.has_null() {
+        Json::Null
+    } else {
+        json!(format_value(value))
+    }
+}
+
+async fn cancel(scheduler: &Scheduler, caller: &Caller, id: u64) -> Response<Body> {
+    // Only admins may cancel other tenants' jobs.
+    let owner = scheduler.history.principal(scheduler.jobs.parent(id).unwrap_or(id));
+    let permitted = caller.role == Some(Role::Admin) || owner == caller.principal;
+    match scheduler.jobs.state(id) {
+        None => not_found(id),
+        Some(_) if !permitted => error_response(
+            StatusCode::FORBIDDEN, &format!("Job {} wasn't submitted by {}.", id, caller.principal)
+        ),
+        Some(state) if state.is_finished() => error_response(
+            StatusCode::CONFLICT, &format!("Job {} already finished.", id)
+        ),
+        // The job can still finish in between the check above and here, hence the second check.
+        Some(_) => {
+            if scheduler.cancel(id).await {
+                json_response(StatusCode::OK, json!({ "job_id": id, "cancelled": true }))
+            } else {
+                error_response(StatusCode::CONFLICT, &format!("Job {} already finished.", id))
+            }
+        },
+    }
+}
+
+fn describe_capabilities(advertised: &WorkerCapabilities) -> Json {
+    json!({
+        "formats": advertised.get_formats(),
+        "engines": advertised.get_engines(),
+        "protocol_versions": advertised.get_protocol_versions(),
+        "extensions": advertised.get_extensions(),
+        "allowed_statements": advertised.get_allowed_statements(),
+        "max_payload_size": advertised.get_max_payload_size(),
+        "executors": advertised.get_executors(),
+        "memory_ceiling": advertised.get_memory_ceiling(),
+        "disk_quota": advertised.get_disk_quota(),
+    })
+}
+
+fn describe_worker(worker: &RegisteredWorker) -> Json {
+    json!({
+        "worker_id": worker.id,
+        "address": worker.address.to_string(),
+        "capabilities": worker.capabilities,
+        "advertised": worker.advertised.as_ref().map(describe_capabilities),
+        "cache_size": worker.cache_size,
+        "labels": worker.labels,
+        "alive": worker.alive,
+        "draining": worker.draining,
+        "missed_beats": worker.missed_beats,
+        "seconds_since_last_beat": worker.last_beat.elapsed().as_secs(),
+        "queue_depth": worker.queue_depth,
+        "running_jobs": worker.running_jobs,
+        "in_flight": worker.in_flight,
+    })
+}
+
+fn workers(scheduler: &Scheduler) -> Response<Body> {
+    let workers = scheduler.roster.workers().iter().map(describe_worker).collect::<Vec<_>>();
+    json_response(StatusCode::OK, json!({ "workers": workers }))
+}
+
+async fn drain_worker(scheduler: &Scheduler, id: u64) -> Response<Body> {
+    match scheduler.drain_worker(id).await {
+        Ok(true) => json_response(StatusCode::OK, json!({ "worker_id": id, "draining": true })),
+        Ok(false) => error_response(StatusCode::NOT_FOUND, &format!("No worker with ID {}.", id)),
+        Err(err) => error_response(StatusCode::BAD_GATEWAY, &err.to_string()),
+    }
+}
+
+async fn reload_worker(scheduler: &Scheduler, id: u64) -> Response<Body> {
+    match scheduler.reload_worker(id).await {
+        Ok(Some(changed)) => {
+            json_response(StatusCode::OK, json!({ "worker_id": id, "changed": changed }))
+        },
+        Ok(None) => error_response(StatusCode::NOT_FOUND, &format!("No worker with ID {}.", id)),
+        Err(err) => error_response(StatusCode::BAD_GATEWAY, &err.to_string()),
+    }
+}
+
+/// Reads the path to evict out of a JSON body, e.g. `{"path": "s3://bucket/trips/"}`.
+async fn eviction(body: Body) -> std::result::Result<CacheCommand, String> {
+    let body = hyper::body::to_bytes(body).await.map_err(|err| err.to_string())?;
+    let json: Json = serde_json::from_slice(&body).map_err(|err| err.to_string())?;
+    let path = match json["path"].as_str() {
+        Some(path) if !path.is_empty() => path,
+        _ => return Err("The body has to say which path to evict, under \"path\".".to_owned()),
+    };
+    let mut command = CacheCommand::new();
+    command.set_action(CacheCommand_Action::EVICT);
+    command.set_path(path.to_owned());
+    Ok(command)
+}
+
+fn describe_cache_report(report: &CacheReport) -> Json {
+    let entries = report.get_entries().iter().map(|entry| json!({
+        "path": entry.get_path(),
+        "bytes": entry.get_bytes(),
+        "age_secs": entry.get_age_secs(),
+        "tables": entry.get_tables(),
+    })).collect::<Vec<_>>();
+    json!({
+        "entries": entries,
+        "bytes_freed": report.get_bytes_freed(),
+        "tables_dropped": report.get_tables_dropped(),
+    })
+}
+
+async fn worker_cache(scheduler: &Scheduler, id: u64, command: &CacheCommand) -> Response<Body> {
+    match scheduler.worker_cache(id, command).await {
+        Ok(Some(report)) => {
+            let mut described = describe_cache_report(&report);
+            described["worker_id"] = json!(id);
+            json_response(StatusCode::OK, described)
+        },
+        Ok(None) => error_response(StatusCode::NOT_FOUND, &format!("No worker with ID {}.", id)),
+        Err(err) => error_response(StatusCode::BAD_GATEWAY, &err.to_string()),
+    }
+}
+
+async fn shutdown(scheduler: &Scheduler, all: bool, drain: Drain) -> Response<Body> {
+    let failures = scheduler.shutdown(all, drain).await.into_iter()
+        .map(|(worker_id, message)| json!({ "worker_id": worker_id, "error": message }))
+        .collect::<Vec<_>>();
+    json_response(StatusCode::ACCEPTED, json!({ "stopping": true, "failures": failures }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+
+    use crate::config::SchedulerConfig;
+
+    use super::*;
+
+    /// A workload spec for a job that reads no files.
+    const SPEC: &str = r#"{"ops": [{"name": "one", "statement": "SELECT 1 AS x"}]}"#;
+
+    async fn start_scheduler(name: &str, require_api_keys: bool) -> Arc<Scheduler> {
+        let config = SchedulerConfig { require_api_keys, ..SchedulerConfig::for_test(name) };
+        Arc::new(Scheduler::new(config).await.unwrap())
+    }
+
+    /// Sends the scheduler a request, with an API key if given one, and reads the status and
+    /// JSON body it answers with.
+    async fn send(
+        scheduler: &Arc<Scheduler>, method: Method, path: &str, key: Option<&str>, body: &str
+    ) -> (StatusCode, Json) {
+        let mut request = Request::builder().method(method).uri(path);
+        if let Some(key) = key {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", key));
+        }
+        let request = request.body(Body::from(body.to_owned())).unwrap();
+        let response = handle(Arc::clone(scheduler), request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_required_api_keys() {
+        let scheduler = start_scheduler("http-keys", true).await;
+        let (_, submit_key) = scheduler.api_keys.create("alice", Role::Submit).unwrap();
+        let (_, admin_key) = scheduler.api_keys.create("root", Role::Admin).unwrap();
+
+        // Without a key, callers may look, but not touch.
+        let (status, _) = send(&scheduler, Method::POST, "/jobs", None, SPEC).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = send(&scheduler, Method::GET, "/workers", None, "").await;
+        assert_eq!(status, StatusCode::OK);
+
+        // A submit key may submit jobs, as its holder, but not manage the keys.
+        let (status, body) = send(&scheduler, Method::POST, "/jobs", Some(&submit_key), SPEC).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let job_id = body["job_id"].as_u64().unwrap();
+        assert_eq!(scheduler.history.principal(job_id), "alice");
+        let (status, _) = send(&scheduler, Method::GET, "/keys", Some(&submit_key), "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // An admin key may.
+        let (status, body) = send(&scheduler, Method::GET, "/keys", Some(&admin_key), "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["keys"].as_array().unwrap().len(), 2);
+
+        // A key the scheduler doesn't know is turned away, whatever the endpoint.
+        let (status, _) = send(&scheduler, Method::GET, "/workers", Some("mck_0_0"), "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_without_required_api_keys() {
+        let scheduler = start_scheduler("http", false).await;
+        let (status, body) = send(&scheduler, Method::POST, "/jobs", None, SPEC).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let job_id = body["job_id"].as_u64().unwrap();
+        assert_eq!(scheduler.history.principal(job_id), ANONYMOUS);
+        let path = format!("/jobs/{}", job_id);
+        let (status, body) = send(&scheduler, Method::GET, &path, None, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["job_id"].as_u64(), Some(job_id));
+        let (status, _) = send(&scheduler, Method::GET, "/keys", None, "").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,244 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use protobuf::RepeatedField;
+
+use mini_cluster_worker::cluster::DistributedJoin;
+use mini_cluster_worker::exchange::EXCHANGE_NULL;
+use mini_cluster_worker::file::create_new_s3_client;
+use mini_cluster_worker::workload::{Exchange, File, Op, Workload};
+
+use crate::err::{Result, SchedulerError, ErrKind};
+
+/// The placeholders a join statement uses for the rows of either side of the partition it runs
+/// over.
+pub const LEFT_PLACEHOLDER: &str = "{left}";
+pub const RIGHT_PLACEHOLDER: &str = "{right}";
+
+// Distributed joins. A `PartitionedWorkload` can only split up one set of files; anything the
+// partitions join against has to go to every one of them whole (see its `broadcast`). That's
+// fine for a small dimension table, but not for joining two big ones.
+//
+// A `DistributedJoin` is run in two stages. First, every file on either side is exchanged on its
+// join keys (see the worker's `exchange`), by a job of its own, which stages it in S3 as one CSV
+// file per partition. Then, once all of those are done, each partition is joined by a job of its
+// own, which loads every file staged for the partition on the left side as `dataset_1`, and on
+// the right side as `dataset_2`. The join statement refers to them as `{left}` and `{right}`.
+//
+// Both stages are queued as partitioned jobs, the second waiting on the first (see
+// `Workload.after_jobs`), so the second fails if the first does.
+
+/// The two stages of a join: the workloads exchanging each file, and the workloads joining each
+/// partition.
+pub struct JoinStages {
+    pub exchanges: Vec<Workload>,
+    pub partitions: Vec<Workload>,
+}
+
+/// Works out the S3 paths of the files on one side of a join.
+pub async fn resolve_side(paths: &[String]) -> Result<Vec<String>> {
+    let client = create_new_s3_client();
+    let mut resolved = vec![];
+    for path in paths {
+        resolved.extend(client.expand(path).await?);
+    }
+    if resolved.is_empty() {
+        Err(SchedulerError::new(
+            ErrKind::InvalidRequest, "One side of the join does not cover any files."
+        ))?
+    }
+    Ok(resolved)
+}
+
+/// Picks the prefix a join stages its partitions under, within its `staging` prefix.
+pub fn staging_prefix(join: &DistributedJoin) -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    format!("{}join-{}/", join.get_staging(), nanos)
+}
+
+/// Checks a join over, before anything is resolved.
+pub fn check_join(join: &DistributedJoin) -> Result<()> {
+    let problem = if join.get_left_keys().is_empty() {
+        Some("Join has no keys to join on.".to_owned())
+    } else if join.get_left_keys().len() != join.get_right_keys().len() {
+        Some("Join has a different number of keys on either side.".to_owned())
+    } else if !join.get_staging().starts_with("s3://") || !join.get_staging().ends_with('/') {
+        Some("Join staging area has to be an S3 prefix, ending in a slash.".to_owned())
+    } else if !join.get_statement().contains(LEFT_PLACEHOLDER)
+        || !join.get_statement().contains(RIGHT_PLACEHOLDER) {
+        Some(format!(
+            "Join statement does not mention both {} and {}.", LEFT_PLACEHOLDER, RIGHT_PLACEHOLDER
+        ))
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        Err(SchedulerError::new(ErrKind::InvalidRequest, &problem))?
+    }
+    Ok(())
+}
+
+/// Builds the workloads of both stages of a join, which stages its partitions under `prefix`.
+pub fn plan_join(
+    join: &DistributedJoin,
+    left: &[String],
+    right: &[String],
+    n_partitions: usize,
+    prefix: &str,
+) -> Result<JoinStages> {
+    check_join(join)?;
+    let n_partitions = n_partitions.max(1) as u32;
+    let sides = [
+        ("left", left, join.get_left_keys()),
+        ("right", right, join.get_right_keys()),
+    ];
+
+    let mut exchanges = vec![];
+    for (side, paths, keys) in sides.iter() {
+        for (i, path) in paths.iter().enumerate() {
+            let mut file = File::new();
+            file.set_path(path.clone());
+            file.set_id(1);
+            let mut exchange = Exchange::new();
+            exchange.set_keys(RepeatedField::from_slice(keys));
+            exchange.set_n_partitions(n_partitions);
+            exchange.set_destination(format!("{}{}/", prefix, side));
+            exchange.set_sender(format!("{}", i + 1));
+            let mut op = Op::new();
+            op.set_statement("SELECT * FROM dataset_1".to_owned());
+            op.set_targets(RepeatedField::from_vec(vec![file]));
+            op.set_op_sequence_num(1);
+            op.set_exchange(exchange);
+            // Serving an exchange out of the result cache would skip staging its partitions.
+            let mut workload = stage_workload(join, op);
+            workload.set_use_result_cache(false);
+            exchanges.push(workload);
+        }
+    }
+
+    let partitions = (0..n_partitions).map(|partition| {
+        let targets = sides.iter().enumerate().map(|(i, (side, _, _))| {
+            let mut file = File::new();
+            file.set_path(format!("{}{}/part-{:05}/*.csv", prefix, side, partition));
+            file.set_id(i as i32 + 1);
+            file.set_null_tokens(RepeatedField::from_vec(vec![EXCHANGE_NULL.to_owned()]));
+            file
+        }).collect::<Vec<_>>();
+        let mut op = Op::new();
+        op.set_statement(
+            join.get_statement()
+                .replace(LEFT_PLACEHOLDER, "dataset_1")
+                .replace(RIGHT_PLACEHOLDER, "dataset_2")
+        );
+        op.set_targets(RepeatedField::from_vec(targets));
+        op.set_op_sequence_num(1);
+        stage_workload(join, op)
+    }).collect();
+    Ok(JoinStages { exchanges, partitions })
+}
+
+/// Builds the workload of one job of a join, with the join's settings and the given op.
+fn stage_workload(join: &DistributedJoin, op: Op) -> Workload {
+    let mut workload = join.get_options().clone();
+    workload.set_ops(RepeatedField::from_vec(vec![op]));
+    workload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn craft_join() -> DistributedJoin {
+        let mut join = DistributedJoin::new();
+        join.set_left_keys(RepeatedField::from_vec(vec!["zone".to_owned()]));
+        join.set_right_keys(RepeatedField::from_vec(vec!["id".to_owned()]));
+        join.set_statement(
+            "SELECT COUNT(*) FROM {left} AS t JOIN {right} AS z ON t.zone = z.id".to_owned()
+        );
+        join.set_staging("s3://bucket/staging/".to_owned());
+        join
+    }
+
+    #[test]
+    fn test_check_join() {
+        assert!(check_join(&craft_join()).is_ok());
+
+        let mut join = craft_join();
+        join.clear_left_keys();
+        join.clear_right_keys();
+        assert!(check_join(&join).is_err());
+
+        let mut join = craft_join();
+        join.mut_right_keys().push("borough".to_owned());
+        assert!(check_join(&join).is_err());
+
+        for staging in &["bucket/staging/", "s3://bucket/staging"] {
+            let mut join = craft_join();
+            join.set_staging(staging.to_string());
+            assert!(check_join(&join).is_err());
+        }
+
+        let mut join = craft_join();
+        join.set_statement("SELECT COUNT(*) FROM {left}".to_owned());
+        assert!(check_join(&join).is_err());
+    }
+
+    #[test]
+    fn test_staging_prefix() {
+        let prefix = staging_prefix(&craft_join());
+        assert!(prefix.starts_with("s3://bucket/staging/join-"));
+        assert!(prefix.ends_with('/'));
+    }
+
+    #[test]
+    fn test_plan_join() {
+        let left = vec!["s3://bucket/trips/1.csv".to_owned(), "s3://bucket/trips/2.csv".to_owned()];
+        let right = vec!["s3://bucket/zones.csv".to_owned()];
+        let prefix = "s3://bucket/staging/join-1/";
+        let stages = plan_join(&craft_join(), &left, &right, 2, prefix).unwrap();
+
+        // One exchange per file, on either side's keys.
+        assert_eq!(stages.exchanges.len(), 3);
+        let exchanges = stages.exchanges.iter().map(|workload| {
+            let op = &workload.get_ops()[0];
+            let exchange = op.get_exchange();
+            assert!(!workload.get_use_result_cache());
+            assert_eq!(exchange.get_n_partitions(), 2);
+            (
+                op.get_targets()[0].get_path(),
+                exchange.get_keys().to_vec(),
+                exchange.get_destination(),
+                exchange.get_sender(),
+            )
+        }).collect::<Vec<_>>();
+        let (zone, id) = (vec!["zone".to_owned()], vec!["id".to_owned()]);
+        let to_left = "s3://bucket/staging/join-1/left/";
+        let to_right = "s3://bucket/staging/join-1/right/";
+        assert_eq!(exchanges, vec![
+            (left[0].as_str(), zone.clone(), to_left, "1"),
+            (left[1].as_str(), zone, to_left, "2"),
+            (right[0].as_str(), id, to_right, "1"),
+        ]);
+
+        // One job per partition, reading whatever was staged for it on either side.
+        assert_eq!(stages.partitions.len(), 2);
+        let op = &stages.partitions[1].get_ops()[0];
+        assert_eq!(
+            op.get_statement(),
+            "SELECT COUNT(*) FROM dataset_1 AS t JOIN dataset_2 AS z ON t.zone = z.id"
+        );
+        let targets = op.get_targets();
+        assert_eq!(targets[0].get_path(), "s3://bucket/staging/join-1/left/part-00001/*.csv");
+        assert_eq!(targets[1].get_path(), "s3://bucket/staging/join-1/right/part-00001/*.csv");
+        assert_eq!((targets[0].get_id(), targets[1].get_id()), (1, 2));
+        assert_eq!(targets[0].get_null_tokens(), &[EXCHANGE_NULL.to_owned()]);
+
+        // A join runs over at least one partition.
+        let stages = plan_join(&craft_join(), &left, &right, 0, prefix).unwrap();
+        assert_eq!(stages.partitions.len(), 1);
+
+        // Joins that don't check out aren't planned at all.
+        let mut join = craft_join();
+        join.clear_left_keys();
+        assert!(plan_join(&join, &left, &right, 2, prefix).is_err());
+    }
+}
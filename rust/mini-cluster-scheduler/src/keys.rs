@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use mini_cluster_worker::auth::{generate_nonce, sign_nonce, verify_nonce};
+
+use crate::err::{Result, SchedulerError, ErrKind};
+use crate::history::now_millis;
+
+// API keys. The shared secret (`SchedulerConfig.secret`) lets in anyone who has it, as whoever
+// they say they are (see `PRINCIPAL_HEADER`), to do anything at all, up to and including
+// shutting the cluster down. That's fine for the operators, but tenants who only ever submit
+// jobs have no business draining workers, nor cancelling each other's jobs, and there's no
+// taking the secret back from one of them without handing a new one out to all the others.
+//
+// So clients can instead be handed API keys, each of them made out to a principal, with a role:
+//
+// * `submit` keys may submit jobs, and cancel the jobs submitted under their own principal;
+// * `admin` keys may do anything, including managing the cluster and the keys themselves.
+//
+// A client shows its key in the `Authorization` header, as `Bearer <key>`, or over the framed
+// protocol in an AUTH frame (see `Scheduler::handle_connection`), and is taken to be the key's
+// principal, whatever else it says. Keys look like `mck_<id>_<secret>`: the ID names
+// the key (e.g. for revoking it), and only a hash of the secret is kept, in the state database
+// along with the rest of the scheduler's state (see `store`), so a key can't be shown again once
+// it has been created.
+//
+// Requests carrying the shared secret instead are let in as admins, so that there's someone to
+// create the first keys. And unless the scheduler has a secret, or is told to require keys
+// (`SchedulerConfig.require_api_keys`), requests carrying neither are let in as admins too, as
+// they always were.
+
+/// What every key starts with.
+pub const API_KEY_PREFIX: &str = "mck";
+
+/// What the secrets of keys are hashed with.
+const HASH_CONTEXT: &[u8] = b"mini-cluster-api-key";
+
+/// What a key lets its holder do. Each role may do everything the roles before it may.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Submit,
+    Admin,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Submit => "submit",
+            Role::Admin => "admin",
+        }
+    }
+
+    /// Whether or not a holder of this role may do what `required` is needed for.
+    pub fn allows(&self, required: Role) -> bool {
+        *self >= required
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = SchedulerError;
+
+    fn from_str(name: &str) -> std::result::Result<Role, SchedulerError> {
+        match name {
+            "submit" => Ok(Role::Submit),
+            "admin" => Ok(Role::Admin),
+            _ => Err(SchedulerError::new(
+                ErrKind::InvalidRequest,
+                &format!("Unknown role {:?}; expected submit or admin.", name)
+            )),
+        }
+    }
+}
+
+/// An API key, as the scheduler keeps it: everything but the key's secret.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    pub id: String,
+    /// Who the key's holder is taken to be.
+    pub principal: String,
+    pub role: Role,
+    /// When the key was created, in milliseconds since the Unix epoch.
+    pub created_at: u64,
+    /// The hash of the key's secret, in hex (see `hash_secret`).
+    pub hash: String,
+}
+
+/// The scheduler's API keys, by ID.
+pub struct ApiKeys {
+    keys: Mutex<HashMap<String, ApiKey>>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// Hashes a key's secret, keyed on the secret itself, the way the workers sign their nonces.
+fn hash_secret(secret: &str) -> String {
+    to_hex(&sign_nonce(secret, HASH_CONTEXT))
+}
+
+/// Splits a key into its ID and its secret, if it looks like a key at all.
+fn split_key(key: &str) -> Option<(&str, &str)> {
+    let mut parts = key.splitn(3, '_');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(API_KEY_PREFIX), Some(id), Some(secret)) if !id.is_empty() && !secret.is_empty() => {
+            Some((id, secret))
+        },
+        _ => None,
+    }
+}
+
+impl ApiKeys {
+    pub fn new() -> ApiKeys {
+        ApiKeys { keys: Mutex::new(HashMap::new()) }
+    }
+
+    /// Puts back the keys read out of the state database.
+    pub fn restore(&self, keys: Vec<ApiKey>) {
+        let mut known = self.keys.lock().unwrap();
+        for key in keys {
+            known.insert(key.id.clone(), key);
+        }
+    }
+
+    /// Creates a key made out to `principal`, returning it along with the key itself, which is
+    /// the only time it's ever seen.
+    pub fn create(&self, principal: &str, role: Role) -> Result<(ApiKey, String)> {
+        if principal.is_empty() {
+            Err(SchedulerError::new(ErrKind::InvalidRequest, "API keys need a principal."))?
+        }
+        let id = to_hex(&generate_nonce()[..6]);
+        let secret = to_hex(&generate_nonce());
+        let key = ApiKey {
+            id: id.clone(),
+            principal: principal.to_owned(),
+            role,
+            created_at: now_millis(),
+            hash: hash_secret(&secret),
+        };
+        self.keys.lock().unwrap().insert(id.clone(), key.clone());
+        Ok((key, format!("{}_{}_{}", API_KEY_PREFIX, id, secret)))
+    }
+
+    /// Revokes the key with the given ID, returning it, if there is one.
+    pub fn revoke(&self, id: &str) -> Option<ApiKey> {
+        self.keys.lock().unwrap().remove(id)
+    }
+
+    /// Lists the keys, oldest first.
+    pub fn list(&self) -> Vec<ApiKey> {
+        let mut keys = self.keys.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        keys.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        keys
+    }
+
+    /// The key a client showed, if it's one of ours. The secret is checked in constant time.
+    pub fn authenticate(&self, key: &str) -> Option<ApiKey> {
+        let (id, secret) = split_key(key)?;
+        let known = self.keys.lock().unwrap().get(id).cloned()?;
+        let hash = from_hex(&known.hash)?;
+        match verify_nonce(secret, HASH_CONTEXT, &hash) {
+            true => Some(known),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authenticate() {
+        let keys = ApiKeys::new();
+        let (created, key) = keys.create("alice", Role::Submit).unwrap();
+        assert!(key.starts_with("mck_"));
+        assert_eq!(keys.authenticate(&key), Some(created.clone()));
+        assert_eq!(keys.list(), vec![created.clone()]);
+
+        // The secret has to be the key's own, for all that the ID is right.
+        let (_, other) = keys.create("bob", Role::Admin).unwrap();
+        let (_, secret) = split_key(&other).unwrap();
+        let forged = format!("{}_{}_{}", API_KEY_PREFIX, created.id, secret);
+        assert_eq!(keys.authenticate(&forged), None);
+
+        // Revoked keys let no one in.
+        assert_eq!(keys.revoke(&created.id), Some(created));
+        assert_eq!(keys.authenticate(&key), None);
+        assert_eq!(keys.list().len(), 1);
+    }
+
+    #[test]
+    fn test_authenticate_malformed_keys() {
+        let keys = ApiKeys::new();
+        keys.create("alice", Role::Submit).unwrap();
+        let malformed = [
+            "", "mck", "mck_", "mck__secret", "mck_id_", "key_id_secret", "mck_id_secret",
+        ];
+        for key in &malformed {
+            assert_eq!(keys.authenticate(key), None, "{:?}", key);
+        }
+        assert!(keys.create("", Role::Submit).is_err());
+    }
+
+    #[test]
+    fn test_restore() {
+        let keys = ApiKeys::new();
+        let (created, key) = keys.create("alice", Role::Admin).unwrap();
+        let restored = ApiKeys::new();
+        restored.restore(keys.list());
+        assert_eq!(restored.authenticate(&key), Some(created));
+    }
+
+    #[test]
+    fn test_roles() {
+        assert!(Role::Admin.allows(Role::Submit));
+        assert!(Role::Admin.allows(Role::Admin));
+        assert!(Role::Submit.allows(Role::Submit));
+        assert!(!Role::Submit.allows(Role::Admin));
+        for role in &[Role::Submit, Role::Admin] {
+            assert_eq!(role.name().parse::<Role>().unwrap(), *role);
+        }
+        assert!("root".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        let bytes = vec![0, 1, 0x7f, 0xff];
+        assert_eq!(to_hex(&bytes), "00017fff");
+        assert_eq!(from_hex("00017fff"), Some(bytes));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
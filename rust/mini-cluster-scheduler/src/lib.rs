@@ -1,3 +1,23 @@
 pub mod scheduler;
 pub mod worker_proxy;
-pub mod err;
\ No newline at end of file
+pub mod err;
+pub mod config;
+pub mod queue;
+pub mod dispatch;
+pub mod partition;
+pub mod join;
+pub mod planner;
+pub mod merge;
+pub mod aggregate;
+pub mod http;
+pub mod spec;
+pub mod client;
+pub mod repl;
+pub mod store;
+pub mod history;
+pub mod notify;
+pub mod local;
+pub mod pool;
+pub mod topology;
+pub mod quota;
+pub mod keys;
@@ -0,0 +1,222 @@
+use std::env;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use mini_cluster_worker::file::get_worker_dir;
+use mini_cluster_worker::transport::Address;
+
+use crate::config::SchedulerConfig;
+use crate::err::Result;
+use crate::scheduler::Scheduler;
+use crate::store::encode_address;
+
+/// How long the scheduler waits before restarting a worker that crashed the first time. The
+/// wait doubles with every crash after that, up to `MAX_RESTART_BACKOFF`.
+pub const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+
+pub const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long a worker has to stay up for its next crash to count as a first one again.
+pub const HEALTHY_RUNTIME: Duration = Duration::from_secs(60);
+
+// For development, the scheduler can run a cluster of its own: it launches a number of worker
+// processes on the machine it runs on (`SchedulerConfig.local_workers`), each on a TCP port of
+// its own, and has them register with it like any other worker would. A worker that crashes is
+// started again, on the same port, so that it takes its own place on the roster; one that exits
+// cleanly (e.g. because it was sent a STOP) is left be. Either way, the scheduler hears about it
+// right away (see `Scheduler::worker_lost`), rather than once the worker has missed enough
+// heartbeats, and the jobs that were out on the worker are re-queued. Each worker keeps what it
+// has on disk in a directory of its own (see `worker_dir`), which it gets back when it's
+// restarted, so the workers don't share a cache or a database.
+//
+// Free ports are found by binding to port 0 and letting go of whatever port the OS handed out,
+// so in principle something else can grab the port before the worker does. The worker then
+// fails to start, and is restarted like a crashed one, but on a new port.
+//
+// Local workers inherit the scheduler's environment, `WORKER_SECRET` and the rest of the
+// `WORKER_*` settings included.
+
+/// Launches and looks after worker processes on the local machine.
+pub struct LocalWorkerManager {
+    binary: PathBuf,
+    scheduler: Arc<Scheduler>,
+    supervisors: Mutex<Vec<JoinHandle<()>>>,
+    // Flipped to `true` when the workers are to be stopped (see `stop`).
+    stop_tx: watch::Sender<bool>,
+    stop_rx: watch::Receiver<bool>,
+}
+
+/// Finds the worker binary: the one named in the config, or else the one next to our own, or
+/// else whichever one is on the `PATH`.
+fn worker_binary(config: &SchedulerConfig) -> PathBuf {
+    if let Some(binary) = &config.worker_binary {
+        return binary.clone();
+    }
+    env::current_exe().ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join("mini-cluster-worker")))
+        .filter(|binary| binary.exists())
+        .unwrap_or_else(|| PathBuf::from("mini-cluster-worker"))
+}
+
+/// The directory the `index`th local worker keeps everything it has on disk in (`WORKER_DIR`):
+/// one of its own, in the directory the scheduler's `WORKER_DIR` (or the default) names.
+fn worker_dir(index: usize) -> PathBuf {
+    get_worker_dir().join(format!("local-{}", index))
+}
+
+/// Asks the OS for a TCP port nobody is listening on.
+fn free_port() -> Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+impl LocalWorkerManager {
+    pub fn new(scheduler: Arc<Scheduler>) -> LocalWorkerManager {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        LocalWorkerManager {
+            binary: worker_binary(&scheduler.config),
+            scheduler,
+            supervisors: Mutex::new(vec![]),
+            stop_tx,
+            stop_rx,
+        }
+    }
+
+    /// Launches `n` workers, each looked after by a task of its own, and returns the ports they
+    /// listen on. The workers register with the scheduler themselves once they are up.
+    pub fn launch(self: Arc<Self>, n: usize) -> Result<Vec<u16>> {
+        println!("Launching {} local worker(s) from {}.", n, self.binary.display());
+        let mut ports = vec![];
+        for index in 0..n {
+            let port = free_port()?;
+            let supervisor = tokio::spawn(Arc::clone(&self).supervise(index, port));
+            self.supervisors.lock().unwrap().push(supervisor);
+            ports.push(port);
+        }
+        Ok(ports)
+    }
+
+    /// Runs the `index`th worker on the given port, and runs it again whenever it crashes, until
+    /// the workers are stopped.
+    async fn supervise(self: Arc<Self>, index: usize, mut port: u16) {
+        let mut stop_rx = self.stop_rx.clone();
+        let mut backoff = RESTART_BACKOFF;
+        loop {
+            let started_at = Instant::now();
+            let child = Command::new(&self.binary)
+                .env_remove("WORKER_SOCKET")
+                .env("WORKER_PORT", port.to_string())
+                .env("WORKER_DIR", worker_dir(index))
+                .env("WORKER_SCHEDULER", encode_address(&self.scheduler.config.address))
+                .kill_on_drop(true)
+                .spawn();
+            let mut child = match child {
+                Ok(child) => child,
+                Err(err) => {
+                    println!("Could not launch a local worker on port {}: {}", port, err);
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {},
+                        _ = stop_rx.changed() => return,
+                    }
+                    backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+                    continue;
+                },
+            };
+            let status = tokio::select! {
+                status = child.wait() => status,
+                _ = stop_rx.changed() => {
+                    let _ = child.kill().await;
+                    return;
+                },
+            };
+            let reason = match &status {
+                Ok(status) => format!("its process exited with {}", status),
+                Err(err) => format!("its process could not be waited on: {}", err),
+            };
+            self.scheduler.worker_lost(&Address::Tcp(port), &reason);
+            if started_at.elapsed() >= HEALTHY_RUNTIME {
+                backoff = RESTART_BACKOFF;
+            }
+            match status {
+                Ok(status) if status.success() => {
+                    println!("Local worker on port {} exited.", port);
+                    return;
+                },
+                Ok(status) => println!(
+                    "Local worker on port {} crashed ({}), restarting it in {:?}.",
+                    port, status, backoff
+                ),
+                Err(err) => println!(
+                    "Lost track of the local worker on port {} ({}), restarting it in {:?}.",
+                    port, err, backoff
+                ),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {},
+                _ = stop_rx.changed() => return,
+            }
+            backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+            // The port is ours again now that the worker is gone, unless something else took
+            // it in the meantime, in which case the worker moves.
+            if TcpListener::bind(("127.0.0.1", port)).is_err() {
+                port = free_port().unwrap_or(port);
+            }
+        }
+    }
+
+    /// Kills every local worker that is still running, and waits until they are gone.
+    pub async fn stop(&self) {
+        let _ = self.stop_tx.send(true);
+        let supervisors = std::mem::take(&mut *self.supervisors.lock().unwrap());
+        for supervisor in supervisors {
+            let _ = supervisor.await;
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use serial_test::serial;
+
+    use super::*;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_worker_dirs() {
+        // A stand-in for the worker binary, which leaves its port in its directory, and exits.
+        let binary = env::temp_dir().join("mini-cluster-test-worker.sh");
+        let script = "#!/bin/sh\n\
+            mkdir -p \"$WORKER_DIR\"\n\
+            echo $WORKER_PORT > \"$WORKER_DIR/port\"\n";
+        fs::write(&binary, script).unwrap();
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755)).unwrap();
+        for index in 0..2 {
+            let _ = fs::remove_dir_all(worker_dir(index));
+        }
+        let config = SchedulerConfig {
+            worker_binary: Some(binary),
+            ..SchedulerConfig::for_test("local")
+        };
+        let scheduler = Arc::new(Scheduler::new(config).await.unwrap());
+        let local = Arc::new(LocalWorkerManager::new(scheduler));
+        let ports = Arc::clone(&local).launch(2).unwrap();
+
+        // The stand-ins exit cleanly, so they aren't restarted.
+        let supervisors = std::mem::take(&mut *local.supervisors.lock().unwrap());
+        for supervisor in supervisors {
+            supervisor.await.unwrap();
+        }
+        for (index, port) in ports.iter().enumerate() {
+            let written = fs::read_to_string(worker_dir(index).join("port")).unwrap();
+            assert_eq!(written.trim(), port.to_string());
+        }
+    }
+}
@@ -1,9 +1,224 @@
-// use mini_cluster_scheduler::scheduler::Scheduler;
-use mini_cluster_scheduler::worker_proxy::WorkerProxy;
-
-fn main() {
-    // let sched = Scheduler::new(8080);
-    let worker_proxy = WorkerProxy::new(8081);
-    // println!("{}", sched);
-    println!("{}", worker_proxy);
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+
+use structopt::StructOpt;
+
+use mini_cluster_scheduler::client::ApiClient;
+use mini_cluster_scheduler::config::SchedulerConfig;
+use mini_cluster_scheduler::err::Result;
+use mini_cluster_scheduler::http;
+use mini_cluster_scheduler::keys::Role;
+use mini_cluster_scheduler::local::LocalWorkerManager;
+use mini_cluster_scheduler::repl::Repl;
+use mini_cluster_scheduler::scheduler::{Drain, Scheduler};
+
+/// Runs a mini-cluster scheduler, or talks to one that is already running.
+#[derive(StructOpt)]
+#[structopt(name = "mini-cluster-scheduler")]
+struct Cli {
+    /// The HTTP API of the scheduler to talk to.
+    #[structopt(long, env = "SCHEDULER_URL", default_value = "http://localhost:8080")]
+    scheduler: String,
+    /// Who to submit jobs as, in the scheduler's job history.
+    #[structopt(long, env = "USER")]
+    principal: Option<String>,
+    /// The API key to authenticate with. Jobs are submitted as whoever the key was made out
+    /// to, whatever `--principal` says.
+    #[structopt(long, env = "SCHEDULER_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Runs the scheduler daemon, configured through environment variables.
+    Serve,
+    /// Submits the workload described in a workload spec (JSON or YAML).
+    Submit {
+        #[structopt(parse(from_os_str))]
+        spec: PathBuf,
+    },
+    /// Shows how a job is doing.
+    Status { job_id: u64 },
+    /// Shows the result sets of a job that is done.
+    Results { job_id: u64 },
+    /// Cancels a job.
+    Cancel { job_id: u64 },
+    /// Shows what happened to which jobs, when, and who submitted them.
+    History {
+        /// Only show what happened from this Unix timestamp (in seconds) on.
+        #[structopt(long, default_value = "0")]
+        since: f64,
+    },
+    /// Runs SQL statements against the cluster interactively.
+    Repl,
+    /// Inspects the workers.
+    Workers(WorkersCommand),
+    /// Inspects, or throws away, what a worker has cached, without restarting it.
+    Cache(CacheCommand),
+    /// Manages the API keys clients authenticate with.
+    Keys(KeysCommand),
+    /// Shuts the scheduler down.
+    Shutdown {
+        /// Shut every worker down too.
+        #[structopt(long)]
+        all: bool,
+        /// What to do about unfinished jobs first: `wait` for them, `leave` them be, or
+        /// `cancel` them.
+        #[structopt(long, default_value = "wait")]
+        drain: Drain,
+    },
+}
+
+#[derive(StructOpt)]
+enum WorkersCommand {
+    /// Lists the workers on the scheduler's roster.
+    List,
+    /// Has a worker finish the jobs it has, without taking new ones, e.g. ahead of restarting
+    /// it.
+    Drain {
+        worker_id: u64,
+    },
+    /// Has a worker reload its configuration file, applying changes to its concurrency limits,
+    /// memory and disk limits, and log level without restarting it.
+    Reload {
+        worker_id: u64,
+    },
+}
+
+#[derive(StructOpt)]
+enum CacheCommand {
+    /// Lists what a worker has cached: the paths it downloaded, how big and how old its copies
+    /// are, and which tables were loaded from them.
+    List {
+        worker_id: u64,
+    },
+    /// Has a worker throw away its copies of a path (or of every path matching a prefix or a
+    /// glob), and the tables loaded from them. Waits for the worker's running jobs to finish.
+    Evict {
+        worker_id: u64,
+        path: String,
+    },
+    /// Has a worker throw away everything it has cached: downloaded files, tables, and cached
+    /// results. Waits for the worker's running jobs to finish.
+    Clear {
+        worker_id: u64,
+    },
+}
+
+#[derive(StructOpt)]
+enum KeysCommand {
+    /// Lists the API keys: their IDs, who they were made out to, and their roles.
+    List,
+    /// Creates an API key, and prints it. It can't be shown again, so keep it somewhere safe.
+    Create {
+        /// Who the key's holder is taken to be.
+        principal: String,
+        /// What the key's holder may do: `submit` (submit jobs, and cancel their own) or
+        /// `admin` (anything at all).
+        #[structopt(long, default_value = "submit")]
+        role: Role,
+    },
+    /// Revokes an API key, by ID.
+    Revoke {
+        id: String,
+    },
+}
+
+async fn serve() {
+    let config = SchedulerConfig::from_env().unwrap();
+    let scheduler = Arc::new(Scheduler::new(config).await.unwrap());
+    println!("Starting {}.", scheduler);
+    Arc::clone(&scheduler).recover();
+    tokio::spawn(Arc::clone(&scheduler).persist());
+    tokio::spawn(Arc::clone(&scheduler).notify());
+    tokio::spawn(Arc::clone(&scheduler).heartbeat());
+    tokio::spawn(Arc::clone(&scheduler).dispatch());
+    // The scheduler is already listening, so local workers can register as soon as they are up.
+    let local = match scheduler.config.local_workers {
+        0 => None,
+        n => {
+            let local = Arc::new(LocalWorkerManager::new(Arc::clone(&scheduler)));
+            Arc::clone(&local).launch(n).unwrap();
+            Some(local)
+        },
+    };
+    let api = scheduler.config.http_address.map(|address| {
+        println!("Serving the HTTP API on {}.", address);
+        let scheduler = Arc::clone(&scheduler);
+        tokio::spawn(async move {
+            if let Err(err) = http::serve(scheduler, address).await.map_err(|err| err.to_string()) {
+                println!("HTTP API went down: {}", err);
+            }
+        })
+    });
+    tokio::select! {
+        outcome = Arc::clone(&scheduler).listen() => outcome.unwrap(),
+        _ = scheduler.stopped() => {},
+    }
+    scheduler.flush().await;
+    if let Some(local) = local {
+        local.stop().await;
+    }
+    // Let the HTTP API answer the request that shut the scheduler down before exiting.
+    if let Some(api) = api {
+        let _ = api.await;
+    }
+}
+
+async fn run(client: &ApiClient, command: Command) -> Result<serde_json::Value> {
+    match command {
+        Command::Serve | Command::Repl => unreachable!(),
+        Command::Submit { spec } => client.submit(fs::read_to_string(spec)?).await,
+        Command::Status { job_id } => client.status(job_id).await,
+        Command::Results { job_id } => client.results(job_id).await,
+        Command::Cancel { job_id } => client.cancel(job_id).await,
+        Command::History { since } => client.history(since).await,
+        Command::Workers(WorkersCommand::List) => client.workers().await,
+        Command::Workers(WorkersCommand::Drain { worker_id }) => {
+            client.drain_worker(worker_id).await
+        },
+        Command::Workers(WorkersCommand::Reload { worker_id }) => {
+            client.reload_worker(worker_id).await
+        },
+        Command::Cache(CacheCommand::List { worker_id }) => client.cache_list(worker_id).await,
+        Command::Cache(CacheCommand::Evict { worker_id, path }) => {
+            client.cache_evict(worker_id, &path).await
+        },
+        Command::Cache(CacheCommand::Clear { worker_id }) => client.cache_clear(worker_id).await,
+        Command::Keys(KeysCommand::List) => client.keys().await,
+        Command::Keys(KeysCommand::Create { principal, role }) => {
+            client.create_key(&principal, role).await
+        },
+        Command::Keys(KeysCommand::Revoke { id }) => client.revoke_key(&id).await,
+        Command::Shutdown { all, drain } => client.shutdown(all, drain).await,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::from_args();
+    if let Command::Serve = cli.command {
+        return serve().await;
+    }
+    let secret = env::var("WORKER_SECRET").ok().filter(|v| !v.is_empty());
+    let client = ApiClient::new(&cli.scheduler, secret, cli.principal.clone(), cli.api_key.clone());
+    if let Command::Repl = cli.command {
+        if let Err(err) = Repl::new(client).run().await {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+    match run(&client, cli.command).await {
+        Ok(answer) => println!("{}", serde_json::to_string_pretty(&answer).unwrap()),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        },
+    }
 }
@@ -0,0 +1,25 @@
+use mini_cluster_worker::db::Database;
+use mini_cluster_worker::response::ResultBatch;
+use mini_cluster_worker::result::{load_result_set, query_result_set};
+
+use crate::err::Result;
+
+/// The table a merge statement finds the partitions' rows in.
+pub const PARTIALS_TABLE: &str = "partials";
+
+/// Merges the result sets of a partitioned job's partitions: their rows are loaded into the
+/// `partials` table of a scratch in-memory database, and the merge statement is run over it.
+///
+/// The scratch database goes away as soon as the merge is done. If none of the partitions
+/// returned any rows there is nothing to tell the columns of `partials` from, so the table
+/// doesn't exist, and a merge statement reading from it fails.
+pub async fn merge(statement: &str, partials: &[ResultBatch]) -> Result<ResultBatch> {
+    let database = Database::new_in_memory().await?;
+    let mut conn = database.connection().await?;
+    for partial in partials {
+        load_result_set(&mut *conn, PARTIALS_TABLE, partial).await?;
+    }
+    let mut merged = query_result_set(&mut *conn, statement).await?;
+    merged.set_op_last(true);
+    Ok(merged)
+}
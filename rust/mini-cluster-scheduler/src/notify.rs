@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use hyper::{Body, Client, Method, Request, Uri};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use serde_json::Value as Json;
+use tokio::time::{sleep, timeout};
+
+use crate::err::{Result, SchedulerError, ErrKind};
+
+/// How many times the scheduler tries to deliver a notification before giving up on it.
+pub const NOTIFY_ATTEMPTS: u32 = 3;
+
+/// How long a notify URL has to answer.
+pub const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A job can name a URL to be notified at once it finishes (`Workload.notify_url`), for systems
+// embedding long batch jobs that would rather not poll `GET /jobs/{id}` for hours. The scheduler
+// POSTs the job's description there, as `GET /jobs/{id}` has it, plus when the job was submitted
+// and finished. Notifications that don't go through are tried again a couple of times, waiting
+// longer each time, and then given up on; the job's state can still be polled for.
+//
+// The scheduler has no TLS client, so only plain `http://` URLs are accepted.
+
+/// Checks that a notify URL is one the scheduler can POST to.
+pub fn check_notify_url(url: &str) -> Result<()> {
+    let valid = match url.parse::<Uri>() {
+        Ok(uri) => uri.scheme_str() == Some("http") && uri.host().is_some(),
+        Err(_) => false,
+    };
+    if !valid {
+        Err(SchedulerError::new(
+            ErrKind::InvalidRequest,
+            &format!("Notify URL {:?} is not an http:// URL.", url)
+        ))?
+    }
+    Ok(())
+}
+
+async fn post(client: &Client<HttpConnector>, url: &str, payload: &Json) -> Result<()> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(payload.to_string()))?;
+    let response = match timeout(NOTIFY_TIMEOUT, client.request(request)).await {
+        Ok(response) => response?,
+        Err(_) => Err(SchedulerError::new(
+            ErrKind::NetworkError, "The notify URL did not answer in time."
+        ))?,
+    };
+    if !response.status().is_success() {
+        Err(SchedulerError::new(
+            ErrKind::RemoteError, &format!("The notify URL answered {}.", response.status())
+        ))?
+    }
+    Ok(())
+}
+
+/// POSTs a notification to a notify URL, trying again if it doesn't go through. Any 2xx
+/// answer counts as delivered.
+pub async fn notify(url: &str, payload: &Json) -> Result<()> {
+    let client = Client::new();
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+        // Our errors aren't `Send`, so only the message is kept past this point.
+        let message = match post(&client, url, payload).await.map_err(|err| err.to_string()) {
+            Ok(()) => return Ok(()),
+            Err(message) => message,
+        };
+        if attempt == NOTIFY_ATTEMPTS {
+            Err(SchedulerError::new(
+                ErrKind::NetworkError,
+                &format!("Gave up after {} attempts: {}", NOTIFY_ATTEMPTS, message)
+            ))?
+        }
+        sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
@@ -0,0 +1,102 @@
+use protobuf::RepeatedField;
+
+use mini_cluster_worker::cluster::PartitionedWorkload;
+use mini_cluster_worker::file::create_new_s3_client;
+use mini_cluster_worker::workload::{File, Op, Workload};
+
+use crate::err::{Result, SchedulerError, ErrKind};
+
+/// The placeholder a partitioned statement uses for the rows of the partition it runs over.
+pub const PARTITION_PLACEHOLDER: &str = "{partition}";
+
+// Partitioning spreads one statement over many files, e.g. a day's worth of hourly log dumps,
+// by splitting the files into groups (partitions) and sending each group to a different worker,
+// as a workload of its own. The statement refers to the partition as `{partition}`, which is
+// swapped for a subquery stitching together the tables of all of the partition's files:
+//
+//     SELECT status, COUNT(*) FROM {partition} GROUP BY status
+//
+// becomes
+//
+//     SELECT status, COUNT(*) FROM (
+//         SELECT * FROM dataset_1 UNION ALL SELECT * FROM dataset_2
+//     ) GROUP BY status
+//
+// on the worker handling files 1 and 2. A subquery (as opposed to e.g. a view) is a plain
+// SELECT, so partitioned statements get past a sandboxed worker's statement checks.
+
+/// Works out the S3 paths of the files a partitioned workload covers.
+pub async fn resolve_paths(partitioned: &PartitionedWorkload) -> Result<Vec<String>> {
+    let client = create_new_s3_client();
+    let paths = if !partitioned.get_prefix().is_empty() {
+        client.list(partitioned.get_prefix()).await?
+    } else {
+        // Paths that are patterns are spread over the partitions object by object, rather than
+        // going to one partition whole.
+        let mut paths = vec![];
+        for path in partitioned.get_paths() {
+            paths.extend(client.expand(path).await?);
+        }
+        paths
+    };
+    if paths.is_empty() {
+        Err(SchedulerError::new(
+            ErrKind::InvalidRequest, "Partitioned workload does not cover any files."
+        ))?
+    }
+    Ok(paths)
+}
+
+/// Splits the files into (at most) `n_partitions` partitions, dealing them out in turn, and
+/// returns the workload for each partition.
+pub fn partition(
+    partitioned: &PartitionedWorkload, paths: &[String], n_partitions: usize
+) -> Result<Vec<Workload>> {
+    if !partitioned.get_statement().contains(PARTITION_PLACEHOLDER) {
+        Err(SchedulerError::new(
+            ErrKind::InvalidRequest,
+            &format!("Partitioned statement does not mention {}.", PARTITION_PLACEHOLDER)
+        ))?
+    }
+    let broadcast = partitioned.get_broadcast();
+    if let Some(file) = broadcast.iter().find(|file| {
+        file.get_id() >= 1 && file.get_id() as usize <= paths.len()
+    }) {
+        Err(SchedulerError::new(
+            ErrKind::InvalidRequest,
+            &format!(
+                "Broadcast file {} has ID {}, which is taken by one of the partitioned files.",
+                file.get_path(), file.get_id()
+            )
+        ))?
+    }
+    let n_partitions = n_partitions.max(1).min(paths.len());
+    let mut partitions: Vec<Vec<File>> = vec![vec![]; n_partitions];
+    for (i, path) in paths.iter().enumerate() {
+        let mut file = File::new();
+        file.set_path(path.clone());
+        // File IDs are what the tables are named after, so they have to be unique within a
+        // partition; making them unique across partitions costs nothing, and makes the worker
+        // logs easier to follow.
+        file.set_id(i as i32 + 1);
+        partitions[i % n_partitions].push(file);
+    }
+
+    Ok(partitions.into_iter().map(|files| {
+        let rows = files.iter()
+            .map(|file| format!("SELECT * FROM dataset_{}", file.get_id()))
+            .collect::<Vec<_>>()
+            .join(" UNION ALL ");
+        let mut op = Op::new();
+        op.set_statement(partitioned.get_statement().replace(
+            PARTITION_PLACEHOLDER, &format!("({})", rows)
+        ));
+        let mut targets = files;
+        targets.extend(broadcast.iter().cloned());
+        op.set_targets(RepeatedField::from_vec(targets));
+        op.set_op_sequence_num(1);
+        let mut workload = partitioned.get_options().clone();
+        workload.set_ops(RepeatedField::from_vec(vec![op]));
+        workload
+    }).collect())
+}
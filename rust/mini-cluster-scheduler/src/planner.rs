@@ -0,0 +1,520 @@
+use std::error::Error;
+
+use protobuf::RepeatedField;
+use sqlparser::ast::{
+    BinaryOperator, Expr, Ident, JoinConstraint, JoinOperator, ObjectName, OrderByExpr,
+    SelectItem, SetExpr, Statement, TableAlias, TableFactor,
+};
+use sqlparser::dialect::SQLiteDialect;
+use sqlparser::parser::Parser;
+
+use mini_cluster_worker::cluster::{
+    Aggregation, Aggregation_Aggregate, Aggregation_Function, ClusterQuery, Dataset,
+    DistributedJoin, PartitionedWorkload,
+};
+
+use crate::aggregate::aggregation_statements;
+use crate::err::{Result, SchedulerError, ErrKind};
+use crate::join::{LEFT_PLACEHOLDER, RIGHT_PLACEHOLDER};
+use crate::merge::PARTIALS_TABLE;
+use crate::partition::PARTITION_PLACEHOLDER;
+
+// Query planning. Partitioned workloads, aggregations, and distributed joins spread work across
+// the cluster, but the client still has to pick the right one, and write its statements in
+// pieces. The planner does that for it, from one SQL query over named datasets (a
+// `ClusterQuery`):
+//
+//     SELECT t.vendor, AVG(t.fare) AS fare
+//     FROM trips AS t JOIN zones AS z ON t.zone = z.id
+//     WHERE z.borough = 'Queens'
+//     GROUP BY t.vendor
+//     ORDER BY fare DESC
+//
+// The datasets are swapped for the placeholders of the plan they end up in: a query over one
+// dataset becomes a `PartitionedWorkload`, its dataset read as `{partition}`, and a query
+// joining two becomes a `DistributedJoin`, exchanged on the columns of the join condition, its
+// datasets read as `{left}` and `{right}`. Filters run where the rows are. Aggregates are split
+// into partials, which run over each partition, and a merge, which runs on the scheduler (see
+// `aggregate`). Sorting and limits run over each partition, and again in the merge.
+//
+// Anything the planner can't split up correctly, e.g. `HAVING`, or `COUNT(DISTINCT ...)`, is
+// turned away, rather than run in a way that would give the wrong answer.
+
+/// What a query is run as.
+pub enum Plan {
+    Partitioned(PartitionedWorkload),
+    Join(DistributedJoin),
+}
+
+/// A dataset, as a query reads from it.
+struct Bound {
+    /// What the query calls the dataset's rows: its alias, or else its name.
+    reference: String,
+    dataset: Dataset,
+}
+
+fn invalid(message: &str) -> Box<dyn Error> {
+    SchedulerError::new(ErrKind::InvalidRequest, message).into()
+}
+
+/// Plans a query (see `ClusterQuery`).
+pub fn plan_query(query: &ClusterQuery) -> Result<Plan> {
+    let mut statements = Parser::parse_sql(&SQLiteDialect {}, query.get_sql())
+        .map_err(|err| invalid(&format!("Could not parse query: {}", err)))?;
+    if statements.len() != 1 {
+        return Err(invalid("Query has to be exactly one statement."));
+    }
+    let mut parsed = match statements.pop().unwrap() {
+        Statement::Query(parsed) => *parsed,
+        _ => return Err(invalid("Query has to be a SELECT.")),
+    };
+    if parsed.with.is_some() || parsed.offset.is_some() {
+        return Err(invalid("Query can't have a WITH or an OFFSET clause."));
+    }
+    let select = match &mut parsed.body {
+        SetExpr::Select(select) => select,
+        _ => return Err(invalid("Query has to be a plain SELECT, e.g. without a UNION.")),
+    };
+    if select.from.len() != 1 {
+        return Err(invalid("Query has to read from one dataset, or from two joined together."));
+    }
+
+    // The datasets are swapped for placeholders in the query itself, which is then rendered
+    // back into SQL.
+    let from = &mut select.from[0];
+    let datasets = query.get_datasets();
+    let (left, right) = match from.joins.as_mut_slice() {
+        [] => (bind_dataset(&mut from.relation, datasets, PARTITION_PLACEHOLDER)?, None),
+        [join] => {
+            let on = match &join.join_operator {
+                JoinOperator::Inner(JoinConstraint::On(on)) => on.clone(),
+                _ => return Err(invalid("Query can only join with JOIN ... ON.")),
+            };
+            let left = bind_dataset(&mut from.relation, datasets, LEFT_PLACEHOLDER)?;
+            let right = bind_dataset(&mut join.relation, datasets, RIGHT_PLACEHOLDER)?;
+            let mut keys = (vec![], vec![]);
+            join_keys(&on, &left.reference, &right.reference, &mut keys)?;
+            (left, Some((right, keys)))
+        },
+        _ => return Err(invalid("Query can only join two datasets.")),
+    };
+    let from_sql = select.from[0].to_string();
+
+    let is_aggregate = !select.group_by.is_empty() || select.projection.iter().any(|item| {
+        match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                aggregate_function(expr).is_some()
+            },
+            _ => false,
+        }
+    });
+    let (statement, merge_statement) = if is_aggregate {
+        if select.having.is_some() || select.distinct {
+            return Err(invalid("Aggregating queries can't have a HAVING clause, or DISTINCT."));
+        }
+        let group_by = select.group_by.iter().map(|expr| expr.to_string()).collect::<Vec<_>>();
+        let mut aggregation = Aggregation::new();
+        aggregation.set_group_by(RepeatedField::from_vec(group_by.clone()));
+        if let Some(selection) = &select.selection {
+            aggregation.set_filter(selection.to_string());
+        }
+        // The merge statement gets wrapped in one putting the columns back in the query's
+        // order, under the query's names for them.
+        let mut outputs = vec![];
+        let mut columns = vec![];
+        for item in select.projection.iter() {
+            let (expr, alias) = match item {
+                SelectItem::UnnamedExpr(expr) => (expr, None),
+                SelectItem::ExprWithAlias { expr, alias } => (expr, Some(alias.value.clone())),
+                _ => return Err(invalid("Aggregating queries can't select *.")),
+            };
+            let text = expr.to_string();
+            if let Some(function) = aggregate_function(expr) {
+                let name = alias.unwrap_or_else(|| text.clone());
+                let mut aggregate = Aggregation_Aggregate::new();
+                aggregate.set_function(function);
+                aggregate.set_expression(aggregate_argument(expr)?);
+                aggregate.set_alias(name.clone());
+                aggregation.mut_aggregates().push(aggregate);
+                columns.push(quote_identifier(&name));
+                outputs.push(name);
+            } else if group_by.contains(&text) {
+                let name = alias.unwrap_or_else(|| text.clone());
+                columns.push(format!("{} AS {}", quote_identifier(&text), quote_identifier(&name)));
+                outputs.push(name);
+            } else {
+                return Err(invalid(&format!(
+                    "Column {} is neither grouped by nor aggregated.", text
+                )));
+            }
+        }
+        let (partial, merge) = aggregation_statements(&aggregation)?;
+        let merge = format!(
+            "SELECT {} FROM ({}){}{}",
+            columns.join(", "),
+            merge,
+            merged_order(&parsed.order_by, Some(&outputs))?,
+            limit(&parsed.limit),
+        );
+        (partial.replace(PARTITION_PLACEHOLDER, &from_sql), merge)
+    } else {
+        let outputs = output_names(&select.projection);
+        let merge = match parsed.order_by.is_empty() && parsed.limit.is_none() && !select.distinct {
+            true => "".to_owned(),
+            false => format!(
+                "SELECT {}* FROM {}{}{}",
+                if select.distinct { "DISTINCT " } else { "" },
+                PARTIALS_TABLE,
+                merged_order(&parsed.order_by, outputs.as_deref())?,
+                limit(&parsed.limit),
+            ),
+        };
+        (parsed.to_string(), merge)
+    };
+
+    let plan = match right {
+        None => {
+            let mut partitioned = PartitionedWorkload::new();
+            partitioned.set_paths(RepeatedField::from_slice(left.dataset.get_paths()));
+            partitioned.set_statement(statement);
+            partitioned.set_n_partitions(query.get_n_partitions());
+            partitioned.set_options(query.get_options().clone());
+            partitioned.set_merge_statement(merge_statement);
+            Plan::Partitioned(partitioned)
+        },
+        Some((right, (left_keys, right_keys))) => {
+            let mut join = DistributedJoin::new();
+            join.set_left(RepeatedField::from_slice(left.dataset.get_paths()));
+            join.set_right(RepeatedField::from_slice(right.dataset.get_paths()));
+            join.set_left_keys(RepeatedField::from_vec(left_keys));
+            join.set_right_keys(RepeatedField::from_vec(right_keys));
+            join.set_statement(statement);
+            join.set_n_partitions(query.get_n_partitions());
+            join.set_staging(query.get_staging().to_owned());
+            join.set_options(query.get_options().clone());
+            join.set_merge_statement(merge_statement);
+            Plan::Join(join)
+        },
+    };
+    Ok(plan)
+}
+
+/// Swaps the dataset a query reads from for the given placeholder, keeping the name the query
+/// knows it by as its alias.
+fn bind_dataset(
+    relation: &mut TableFactor, datasets: &[Dataset], placeholder: &str
+) -> Result<Bound> {
+    let (name, alias) = match relation {
+        TableFactor::Table { name, alias, .. } => (name, alias),
+        _ => return Err(invalid("Query can only read from datasets, not e.g. subqueries.")),
+    };
+    let dataset_name = name.0.last().map(|ident| ident.value.clone()).unwrap_or_default();
+    let dataset = datasets.iter()
+        .find(|dataset| dataset.get_name() == dataset_name)
+        .ok_or_else(|| invalid(&format!(
+            "Query reads from {}, which is not one of its datasets.", dataset_name
+        )))?;
+    let reference = match alias {
+        Some(alias) => alias.name.value.clone(),
+        None => {
+            *alias = Some(TableAlias { name: Ident::new(&dataset_name), columns: vec![] });
+            dataset_name
+        },
+    };
+    *name = ObjectName(vec![Ident::new(placeholder)]);
+    Ok(Bound { reference, dataset: dataset.clone() })
+}
+
+/// Works out the columns a join condition joins on, on either side, e.g. `a.x` and `b.y` in
+/// `a.x = b.y`. Only equalities between columns of either side, and conjunctions of those, can
+/// be exchanged on.
+fn join_keys(
+    on: &Expr, left: &str, right: &str, keys: &mut (Vec<String>, Vec<String>)
+) -> Result<()> {
+    match on {
+        Expr::Nested(on) => join_keys(on, left, right, keys),
+        Expr::BinaryOp { left: a, op: BinaryOperator::And, right: b } => {
+            join_keys(a, left, right, keys)?;
+            join_keys(b, left, right, keys)
+        },
+        Expr::BinaryOp { left: a, op: BinaryOperator::Eq, right: b } => {
+            match (qualified_column(a), qualified_column(b)) {
+                (Some((ta, ca)), Some((tb, cb))) if ta == left && tb == right => {
+                    keys.0.push(ca);
+                    keys.1.push(cb);
+                },
+                (Some((ta, ca)), Some((tb, cb))) if ta == right && tb == left => {
+                    keys.0.push(cb);
+                    keys.1.push(ca);
+                },
+                _ => return Err(invalid(&format!(
+                    "Join condition {} doesn't compare a column of either side.", on
+                ))),
+            }
+            Ok(())
+        },
+        _ => Err(invalid(&format!(
+            "Join condition {} isn't made of equalities, e.g. a.x = b.y AND a.z = b.z.", on
+        ))),
+    }
+}
+
+/// Splits a qualified column reference, e.g. `a.x`, into its table and its column.
+fn qualified_column(expr: &Expr) -> Option<(String, String)> {
+    match expr {
+        Expr::CompoundIdentifier(idents) if idents.len() == 2 => {
+            Some((idents[0].value.clone(), idents[1].value.clone()))
+        },
+        _ => None,
+    }
+}
+
+/// Which aggregate function an expression calls, if it is a call to one the planner can split.
+fn aggregate_function(expr: &Expr) -> Option<Aggregation_Function> {
+    let function = match expr {
+        Expr::Function(function) if function.over.is_none() => function,
+        _ => return None,
+    };
+    match function.name.to_string().to_uppercase().as_str() {
+        "COUNT" => Some(Aggregation_Function::COUNT),
+        "SUM" => Some(Aggregation_Function::SUM),
+        "MIN" => Some(Aggregation_Function::MIN),
+        "MAX" => Some(Aggregation_Function::MAX),
+        "AVG" => Some(Aggregation_Function::AVG),
+        _ => None,
+    }
+}
+
+/// Returns what an aggregate function call aggregates, e.g. `fare` in `SUM(fare)`.
+fn aggregate_argument(expr: &Expr) -> Result<String> {
+    if let Expr::Function(function) = expr {
+        if function.distinct {
+            return Err(invalid(&format!("Aggregate {} can't be split up.", expr)));
+        }
+    }
+    let text = expr.to_string();
+    match (text.find('('), text.rfind(')')) {
+        (Some(start), Some(end)) if start < end => Ok(text[start + 1..end].trim().to_owned()),
+        _ => Err(invalid(&format!("Could not make out what {} aggregates.", expr))),
+    }
+}
+
+/// The names of the columns a query's result set has, if they can be told without knowing the
+/// columns of the datasets, i.e. if it doesn't select `*`.
+fn output_names(projection: &[SelectItem]) -> Option<Vec<String>> {
+    projection.iter().map(|item| match item {
+        SelectItem::ExprWithAlias { alias, .. } => Some(alias.value.clone()),
+        SelectItem::UnnamedExpr(Expr::Identifier(ident)) => Some(ident.value.clone()),
+        SelectItem::UnnamedExpr(Expr::CompoundIdentifier(idents)) => {
+            idents.last().map(|ident| ident.value.clone())
+        },
+        SelectItem::UnnamedExpr(expr) => Some(expr.to_string()),
+        _ => None,
+    }).collect()
+}
+
+/// Renders a query's `ORDER BY` clause for its merge statement, in which its sort keys have to
+/// be columns of its result set (or their positions).
+fn merged_order(order_by: &[OrderByExpr], outputs: Option<&[String]>) -> Result<String> {
+    if order_by.is_empty() {
+        return Ok("".to_owned());
+    }
+    let mut keys = vec![];
+    for key in order_by {
+        let text = key.expr.to_string();
+        if text.parse::<u64>().is_ok() {
+            keys.push(key.to_string());
+            continue;
+        }
+        // Columns are named after what they select, unless they're plain column references,
+        // which are named after the column.
+        let name = match &key.expr {
+            _ if outputs.map_or(false, |outputs| outputs.contains(&text)) => text,
+            Expr::Identifier(ident) => ident.value.clone(),
+            Expr::CompoundIdentifier(idents) => {
+                idents.last().map(|ident| ident.value.clone()).unwrap_or_default()
+            },
+            _ => text,
+        };
+        if outputs.map_or(false, |outputs| !outputs.contains(&name)) {
+            return Err(invalid(&format!(
+                "Query can only be sorted by columns of its result, which {} is not.", key.expr
+            )));
+        }
+        let mut merged = key.clone();
+        merged.expr = Expr::Identifier(Ident::with_quote('"', name));
+        keys.push(merged.to_string());
+    }
+    Ok(format!(" ORDER BY {}", keys.join(", ")))
+}
+
+/// Renders a query's `LIMIT` clause, if it has one.
+fn limit(limit: &Option<Expr>) -> String {
+    limit.as_ref().map_or_else(String::new, |limit| format!(" LIMIT {}", limit))
+}
+
+/// Quotes a column name, so that it can be anything at all (e.g. `SUM(fare)`).
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A query over `trips`, with its files under one prefix, and `zones`, a single file.
+    fn craft_query(sql: &str) -> ClusterQuery {
+        let datasets = [("trips", "s3://bucket/trips/"), ("zones", "s3://bucket/zones.csv")];
+        let mut query = ClusterQuery::new();
+        query.set_sql(sql.to_owned());
+        for (name, path) in datasets.iter() {
+            let mut dataset = Dataset::new();
+            dataset.set_name(name.to_string());
+            dataset.set_paths(RepeatedField::from_vec(vec![path.to_string()]));
+            query.mut_datasets().push(dataset);
+        }
+        query.set_n_partitions(4);
+        query.set_staging("s3://bucket/staging/".to_owned());
+        query
+    }
+
+    fn plan_partitioned(sql: &str) -> PartitionedWorkload {
+        match plan_query(&craft_query(sql)).unwrap() {
+            Plan::Partitioned(partitioned) => partitioned,
+            Plan::Join(_) => panic!("Expected {} to be planned as a partitioned workload.", sql),
+        }
+    }
+
+    fn plan_join(sql: &str) -> DistributedJoin {
+        match plan_query(&craft_query(sql)).unwrap() {
+            Plan::Join(join) => join,
+            Plan::Partitioned(_) => panic!("Expected {} to be planned as a join.", sql),
+        }
+    }
+
+    /// Whether or not the query is turned away as an invalid request.
+    fn is_rejected(sql: &str) -> bool {
+        match plan_query(&craft_query(sql)) {
+            Err(err) => match err.downcast_ref::<SchedulerError>() {
+                Some(SchedulerError::InvalidRequest(_)) => true,
+                _ => false,
+            },
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_plan_partitioned() {
+        let partitioned = plan_partitioned("SELECT id, fare FROM trips WHERE fare > 10");
+        assert_eq!(partitioned.get_paths(), &["s3://bucket/trips/".to_owned()]);
+        assert_eq!(partitioned.get_n_partitions(), 4);
+        assert_eq!(
+            partitioned.get_statement(),
+            "SELECT id, fare FROM {partition} AS trips WHERE fare > 10"
+        );
+        // There is nothing to merge, so the partitions' result sets are the query's.
+        assert_eq!(partitioned.get_merge_statement(), "");
+
+        // Sorting and limits run over each partition, and again in the merge.
+        let partitioned = plan_partitioned("SELECT id, fare FROM trips ORDER BY fare LIMIT 5");
+        assert_eq!(
+            partitioned.get_statement(),
+            "SELECT id, fare FROM {partition} AS trips ORDER BY fare LIMIT 5"
+        );
+        assert_eq!(
+            partitioned.get_merge_statement(), "SELECT * FROM partials ORDER BY \"fare\" LIMIT 5"
+        );
+        let partitioned = plan_partitioned("SELECT DISTINCT t.vendor FROM trips AS t");
+        assert_eq!(partitioned.get_merge_statement(), "SELECT DISTINCT * FROM partials");
+    }
+
+    #[test]
+    fn test_plan_partitioned_aggregate() {
+        let partitioned = plan_partitioned(
+            "SELECT vendor, AVG(fare) AS fare FROM trips WHERE fare > 0 GROUP BY vendor \
+            ORDER BY fare DESC LIMIT 10"
+        );
+        assert_eq!(
+            partitioned.get_statement(),
+            "SELECT vendor AS group_1, SUM(fare) AS partial_1, COUNT(fare) AS partial_2 \
+            FROM {partition} AS trips WHERE fare > 0 GROUP BY vendor"
+        );
+        assert_eq!(
+            partitioned.get_merge_statement(),
+            "SELECT \"vendor\" AS \"vendor\", \"fare\" FROM (SELECT group_1 AS \"vendor\", \
+            CAST(SUM(partial_1) AS REAL) / SUM(partial_2) AS \"fare\" FROM partials \
+            GROUP BY group_1) ORDER BY \"fare\" DESC LIMIT 10"
+        );
+
+        // Aggregates without a name are named after what they select.
+        let partitioned = plan_partitioned("SELECT COUNT(*), MAX(fare) FROM trips");
+        assert_eq!(
+            partitioned.get_merge_statement(),
+            "SELECT \"COUNT(*)\", \"MAX(fare)\" FROM (SELECT SUM(partial_1) AS \"COUNT(*)\", \
+            MAX(partial_2) AS \"MAX(fare)\" FROM partials)"
+        );
+    }
+
+    #[test]
+    fn test_plan_join() {
+        let join = plan_join(
+            "SELECT t.vendor, COUNT(*) AS n FROM trips AS t JOIN zones AS z \
+            ON t.zone = z.id AND (z.borough = t.borough) GROUP BY t.vendor"
+        );
+        assert_eq!(join.get_left(), &["s3://bucket/trips/".to_owned()]);
+        assert_eq!(join.get_right(), &["s3://bucket/zones.csv".to_owned()]);
+        // Keys are lined up by side, whichever side of the equality they're on.
+        assert_eq!(join.get_left_keys(), &["zone".to_owned(), "borough".to_owned()]);
+        assert_eq!(join.get_right_keys(), &["id".to_owned(), "borough".to_owned()]);
+        assert_eq!(join.get_staging(), "s3://bucket/staging/");
+        assert_eq!(join.get_n_partitions(), 4);
+        assert!(join.get_statement().starts_with(
+            "SELECT t.vendor AS group_1, COUNT(*) AS partial_1 FROM {left} AS t JOIN {right} AS z"
+        ));
+        assert!(join.get_statement().ends_with(" GROUP BY t.vendor"));
+        let merge_statement = join.get_merge_statement();
+        assert!(merge_statement.starts_with("SELECT \"t.vendor\" AS \"t.vendor\", \"n\" FROM ("));
+        assert!(crate::join::check_join(&join).is_ok());
+
+        // Datasets without an alias are known by their name.
+        let join = plan_join("SELECT trips.id FROM trips JOIN zones ON zones.id = trips.zone");
+        assert_eq!(join.get_left_keys(), &["zone".to_owned()]);
+        assert_eq!(join.get_right_keys(), &["id".to_owned()]);
+        assert!(join.get_statement().contains("FROM {left} AS trips JOIN {right} AS zones"));
+    }
+
+    #[test]
+    fn test_rejected_queries() {
+        for sql in &[
+            "SELEC id FROM trips",
+            "SELECT 1 FROM trips; SELECT 2 FROM trips",
+            "DELETE FROM trips",
+            "WITH t AS (SELECT 1) SELECT * FROM trips",
+            "SELECT id FROM trips LIMIT 10 OFFSET 5",
+            "SELECT id FROM trips UNION SELECT id FROM zones",
+            "SELECT id FROM trips, zones",
+            "SELECT id FROM rides",
+            "SELECT id FROM (SELECT id FROM trips) AS t",
+            // Joins have to be inner joins of two datasets, on equal columns of either side.
+            "SELECT t.id FROM trips AS t LEFT JOIN zones AS z ON t.zone = z.id",
+            "SELECT t.id FROM trips AS t JOIN zones AS z USING (id)",
+            "SELECT t.id FROM trips AS t JOIN zones AS z ON t.zone > z.id",
+            "SELECT t.id FROM trips AS t JOIN zones AS z ON t.zone = z.id OR t.zone = z.alt",
+            "SELECT t.id FROM trips AS t JOIN zones AS z ON t.zone = t.id",
+            "SELECT t.id FROM trips AS t JOIN zones AS z ON t.zone = 1",
+            "SELECT t.id FROM trips AS t JOIN zones AS z ON t.zone = z.id \
+                JOIN zones AS y ON t.zone = y.id",
+            // Aggregates have to split up into partials that merge correctly.
+            "SELECT vendor, COUNT(*) FROM trips GROUP BY vendor HAVING COUNT(*) > 1",
+            "SELECT DISTINCT vendor, COUNT(*) FROM trips GROUP BY vendor",
+            "SELECT COUNT(DISTINCT vendor) FROM trips",
+            "SELECT vendor, fare, COUNT(*) FROM trips GROUP BY vendor",
+            "SELECT * FROM trips GROUP BY vendor",
+            "SELECT vendor, COUNT(*) AS n FROM trips GROUP BY vendor ORDER BY fare",
+            // Sorting happens again in the merge, where only the result's columns are left.
+            "SELECT id FROM trips ORDER BY fare",
+        ] {
+            assert!(is_rejected(sql), "Expected {} to be rejected.", sql);
+        }
+    }
+}
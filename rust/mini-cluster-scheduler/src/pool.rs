@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use mini_cluster_worker::tls::TlsClient;
+use mini_cluster_worker::transport::Address;
+use mini_cluster_worker::workload::Workload;
+
+use crate::err::{Result, SchedulerError, ErrKind};
+use crate::worker_proxy::WorkerProxy;
+
+/// How many idle connections are kept open to each worker.
+pub const POOL_SIZE: usize = 4;
+
+/// How long a connection may sit idle in the pool before it is closed. This is well under the
+/// worker's own idle timeout (five minutes by default), so that the worker doesn't hang up on
+/// connections the pool still thinks are good.
+pub const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Talking to a worker used to mean opening a connection, authenticating, sending one request
+// and hanging up again, so every heartbeat, job and cancellation paid for a TCP handshake (and
+// an AUTH round trip, with a secret). Connections are sessions that can carry any number of
+// requests, so instead the scheduler keeps a few of them open to each worker, checking one out
+// of the pool for as long as it needs it and handing it back afterwards.
+//
+// A connection that goes wrong is simply not handed back, and the next one is opened afresh. A
+// pooled connection can also have gone bad while it sat idle (e.g. because the worker
+// restarted), which only shows once it is used; `send_workload` tries again on a fresh
+// connection when that happens, so long as the worker can't have got the workload.
+
+struct IdleConnection {
+    proxy: WorkerProxy,
+    idle_since: Instant,
+}
+
+/// Connections to the workers, kept open between requests.
+pub struct ConnectionPool {
+    secret: Option<String>,
+    tls: Option<TlsClient>,
+    // Idle connections, by worker address. As elsewhere, the lock is never held across an
+    // `.await`.
+    idle: Mutex<HashMap<String, Vec<IdleConnection>>>,
+}
+
+impl ConnectionPool {
+    /// Creates an empty pool. Connections are authenticated with `secret`, if there is one, and
+    /// go over TLS if `tls` is given.
+    pub fn new(secret: Option<String>, tls: Option<TlsClient>) -> ConnectionPool {
+        ConnectionPool { secret, tls, idle: Mutex::new(HashMap::new()) }
+    }
+
+    /// Takes the most recently used idle connection to the worker at `address` out of the pool,
+    /// closing any that sat idle for too long.
+    fn take_idle(&self, address: &Address) -> Option<WorkerProxy> {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.get_mut(&address.to_string())?;
+        connections.retain(|connection| connection.idle_since.elapsed() < POOL_IDLE_TIMEOUT);
+        connections.pop().map(|connection| connection.proxy)
+    }
+
+    /// Opens a fresh connection to the worker at `address`, bypassing the pool.
+    pub async fn connect(&self, address: &Address) -> Result<WorkerProxy> {
+        let mut proxy = WorkerProxy::new(address.clone());
+        proxy.tls = self.tls.clone();
+        proxy.connect().await?;
+        if let Some(secret) = &self.secret {
+            proxy.authenticate(secret).await?;
+        }
+        Ok(proxy)
+    }
+
+    /// Returns a connection to the worker at `address`: an idle one out of the pool if there is
+    /// one, and a fresh one otherwise. Hand it back with `checkin` once done with it.
+    pub async fn checkout(&self, address: &Address) -> Result<WorkerProxy> {
+        match self.take_idle(address) {
+            Some(proxy) => Ok(proxy),
+            None => self.connect(address).await,
+        }
+    }
+
+    /// Hands a connection back to the pool, to be used again. Only hand back connections whose
+    /// last request went through; anything else may have left them halfway through a response.
+    /// If the pool already has enough idle connections to the worker, this one is closed.
+    pub fn checkin(&self, proxy: WorkerProxy) {
+        if proxy.connection.is_none() {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.entry(proxy.address.to_string()).or_insert_with(Vec::new);
+        if connections.len() < POOL_SIZE {
+            connections.push(IdleConnection { proxy, idle_since: Instant::now() });
+        }
+    }
+
+    /// Closes every idle connection to the worker at `address`, e.g. because it died.
+    pub fn forget(&self, address: &Address) {
+        self.idle.lock().unwrap().remove(&address.to_string());
+    }
+
+    /// Submits a workload to the worker at `address` over a pooled connection, returning the
+    /// connection along with the job ID the worker queued the workload under. If an idle
+    /// connection turns out to have gone bad before the worker got the workload, the workload is
+    /// sent again over a fresh one (see `WorkerProxy::send_workload`).
+    pub async fn send_workload(
+        &self, address: &Address, workload: &Workload
+    ) -> Result<(WorkerProxy, u64)> {
+        if let Some(mut proxy) = self.take_idle(address) {
+            // Our errors aren't `Send`, so only the message of an error the worker reported, or
+            // of one that leaves the worker possibly holding the job, is kept. Anything else
+            // means the connection is no good, and that the worker doesn't have the job.
+            let outcome = proxy.send_workload(workload).await.map_err(|err| {
+                match err.downcast_ref::<SchedulerError>() {
+                    Some(SchedulerError::RemoteError(kind, inner)) => {
+                        Some(SchedulerError::remote(*kind, &inner.to_string()))
+                    },
+                    Some(SchedulerError::Unacknowledged(inner)) => {
+                        Some(SchedulerError::new(ErrKind::Unacknowledged, &inner.to_string()))
+                    },
+                    _ => None,
+                }
+            });
+            match outcome {
+                Ok(worker_job_id) => return Ok((proxy, worker_job_id)),
+                // Sending the workload again could run it twice, so it is left to the caller.
+                Err(Some(err)) => {
+                    match err {
+                        // The worker answered the workload, so the connection is still good.
+                        SchedulerError::RemoteError(..) => self.checkin(proxy),
+                        _ => self.forget(address),
+                    }
+                    Err(err)?
+                },
+                Err(None) => self.forget(address),
+            }
+        }
+        let mut proxy = self.connect(address).await?;
+        match proxy.send_workload(workload).await {
+            Ok(worker_job_id) => Ok((proxy, worker_job_id)),
+            Err(err) => {
+                let nacked = err.downcast_ref::<SchedulerError>();
+                if let Some(SchedulerError::RemoteError(..)) = nacked {
+                    self.checkin(proxy);
+                }
+                Err(err)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::RepeatedField;
+    use serial_test::serial;
+
+    use mini_cluster_worker::Worker;
+    use mini_cluster_worker::config::WorkerConfig;
+    use mini_cluster_worker::fixtures::{
+        craft_op_message, craft_workload_message, spawn_in_process_worker,
+    };
+
+    use super::*;
+
+    /// A workload of a single ephemeral op running `statement`.
+    fn craft_workload(statement: &str) -> Workload {
+        let op = craft_op_message(Some(RepeatedField::new()), Some(statement.to_owned()), Some(1));
+        let mut workload = craft_workload_message(Some(RepeatedField::from_vec(vec![op])));
+        workload.set_ephemeral(true);
+        workload
+    }
+
+    /// How many idle connections the pool holds to the worker.
+    fn n_idle(pool: &ConnectionPool, worker: &Worker) -> usize {
+        pool.idle.lock().unwrap().get(&worker.address.to_string()).map_or(0, Vec::len)
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_reuse() {
+        let (worker, _) = spawn_in_process_worker(WorkerConfig::default()).await;
+        let pool = ConnectionPool::new(None, None);
+        let mut proxy = pool.checkout(&worker.address).await.unwrap();
+        proxy.ping().await.unwrap();
+        pool.checkin(proxy);
+        assert_eq!(n_idle(&pool, &worker), 1);
+
+        // The idle connection is handed out again, rather than a new one opened.
+        let mut proxy = pool.checkout(&worker.address).await.unwrap();
+        assert_eq!(n_idle(&pool, &worker), 0);
+        proxy.ping().await.unwrap();
+        pool.checkin(proxy);
+
+        // Only so many connections are kept, and closed ones not at all.
+        let mut proxies = vec![];
+        for _ in 0..POOL_SIZE + 1 {
+            proxies.push(pool.checkout(&worker.address).await.unwrap());
+        }
+        for proxy in proxies {
+            pool.checkin(proxy);
+        }
+        assert_eq!(n_idle(&pool, &worker), POOL_SIZE);
+        pool.forget(&worker.address);
+        let mut proxy = pool.checkout(&worker.address).await.unwrap();
+        proxy.close().await.unwrap();
+        pool.checkin(proxy);
+        assert_eq!(n_idle(&pool, &worker), 0);
+
+        // A NACKed workload leaves the connection as good as it was, be it fresh or pooled.
+        for _ in 0..2 {
+            let nacked = pool.send_workload(&worker.address, &craft_workload("SELEC 1")).await;
+            match nacked.err().unwrap().downcast_ref::<SchedulerError>() {
+                Some(SchedulerError::RemoteError(..)) => {},
+                _ => panic!("Expected the workload to be NACKed."),
+            }
+            assert_eq!(n_idle(&pool, &worker), 1);
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_idle_expiry() {
+        let (worker, _) = spawn_in_process_worker(WorkerConfig::default()).await;
+        let pool = ConnectionPool::new(None, None);
+        // Makes the oldest idle connection look like it sat idle for too long.
+        let expire_oldest = || {
+            let mut idle = pool.idle.lock().unwrap();
+            let connections = idle.get_mut(&worker.address.to_string()).unwrap();
+            connections[0].idle_since = Instant::now().checked_sub(POOL_IDLE_TIMEOUT).unwrap();
+        };
+        let first = pool.checkout(&worker.address).await.unwrap();
+        let second = pool.checkout(&worker.address).await.unwrap();
+        pool.checkin(first);
+        pool.checkin(second);
+        expire_oldest();
+
+        // Connections that sat idle for too long are closed, rather than handed out.
+        let proxy = pool.take_idle(&worker.address).unwrap();
+        assert_eq!(n_idle(&pool, &worker), 0);
+        pool.checkin(proxy);
+        expire_oldest();
+        assert!(pool.take_idle(&worker.address).is_none());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_retry_on_stale_connection() {
+        let (worker, _) = spawn_in_process_worker(WorkerConfig::default()).await;
+        let pool = ConnectionPool::new(None, None);
+        // The worker hangs up on a session that is over, without the pool knowing.
+        let mut proxy = pool.checkout(&worker.address).await.unwrap();
+        proxy.end_session().await.unwrap();
+        pool.checkin(proxy);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The workload never got to the worker, so it is sent again over a fresh connection.
+        let (mut proxy, job_id) =
+            pool.send_workload(&worker.address, &craft_workload("SELECT 1")).await.unwrap();
+        assert_eq!(n_idle(&pool, &worker), 0);
+        let mut batches = vec![];
+        proxy.fetch_results(job_id, |batch| batches.push(batch), |_| {}).await.unwrap();
+        assert!(batches.last().unwrap().get_last());
+        pool.checkin(proxy);
+        assert_eq!(n_idle(&pool, &worker), 1);
+    }
+}
@@ -0,0 +1,1156 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use protobuf::Message;
+use tokio::sync::{Notify, watch};
+use tokio::time::timeout;
+
+use mini_cluster_worker::cluster::{ClusterJob, ClusterJob_State};
+use mini_cluster_worker::queue::IDEMPOTENCY_WINDOW;
+use mini_cluster_worker::response::{JobProgress, ResultBatch};
+use mini_cluster_worker::workload::Workload;
+
+/// The lifecycle of a job submitted to the scheduler.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobState {
+    Queued,
+    /// Sent to a worker, which queued it under `worker_job_id` (zero until the worker has
+    /// acknowledged it).
+    Dispatched { worker_id: u64, worker_job_id: u64 },
+    /// The worker reported (in a heartbeat) that it started running the job.
+    Running { worker_id: u64, worker_job_id: u64 },
+    /// Done, having produced `n_rows` result rows.
+    Done { worker_id: u64, worker_job_id: u64, n_rows: u64 },
+    Failed(String),
+    /// Cancelled by a client (see `JobQueue::cancel`).
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        match self {
+            JobState::Done { .. } | JobState::Failed(_) | JobState::Cancelled => true,
+            _ => false,
+        }
+    }
+
+    /// The worker the job is currently out on, if any.
+    pub fn worker_id(&self) -> Option<u64> {
+        match self {
+            JobState::Dispatched { worker_id, .. } | JobState::Running { worker_id, .. } => {
+                Some(*worker_id)
+            },
+            _ => None,
+        }
+    }
+
+    /// The worker the job is out on, and the job ID that worker queued it under, if the worker
+    /// has acknowledged it yet. This is what it takes to cancel the job on the worker.
+    pub fn worker_job(&self) -> Option<(u64, u64)> {
+        match self {
+            JobState::Dispatched { worker_id, worker_job_id }
+            | JobState::Running { worker_id, worker_job_id } if *worker_job_id != 0 => {
+                Some((*worker_id, *worker_job_id))
+            },
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ScheduledJob {
+    pub id: u64,
+    pub workload: Workload,
+    pub state: JobState,
+    pub submitted_at: Instant,
+    /// How many times the job has been sent to a worker.
+    pub attempts: u32,
+    /// When the job was last sent to a worker.
+    pub dispatched_at: Option<Instant>,
+    /// How long the job took, from being sent to a worker to being done.
+    pub runtime: Option<Duration>,
+    /// The state of the job's speculative duplicate, if one was sent to a second worker (see
+    /// `JobQueue::speculate`). Whichever of the two attempts finishes first wins.
+    pub backup: Option<JobState>,
+    /// For a partitioned job, the IDs of the jobs it was split into. The parent job itself is
+    /// never queued; its state is worked out from theirs (see `fan_in`).
+    pub partitions: Vec<u64>,
+    /// For the job of a partition, the ID of the partitioned job it is a part of.
+    pub parent: Option<u64>,
+    /// For a partitioned job, the statement that merges its partitions' result sets (see
+    /// `PartitionedWorkload.merge_statement`). Empty if there is none.
+    pub merge_statement: String,
+    /// The job's result batches, once it is done. These are kept for the scheduler's
+    /// `results_ttl` (see `expire_results`), so that the job's results can be fetched from the
+    /// scheduler as often as need be until then.
+    pub results: Vec<ResultBatch>,
+    /// When the job's results came in, if they are still held.
+    pub results_at: Option<Instant>,
+    /// Whether or not the job's results were fetched, after which they no longer count against
+    /// its tenant's quota (see `result_bytes_by_owner`).
+    pub fetched: bool,
+    /// Whether or not the job's `notify_url` was handed out to be told that the job finished
+    /// (see `take_notifications`).
+    pub notified: bool,
+    /// How far along the job is, as last reported by the worker it is out on (see
+    /// `set_progress`). Cleared whenever the job is sent to a worker afresh.
+    pub progress: Option<JobProgress>,
+}
+
+/// Works out the state of a partitioned job from the states of its partitions. The job has
+/// failed if any partition has, is cancelled if any partition is, is done once all of them
+/// are, and is queued for as long as all of them are. Otherwise it counts as running if any
+/// partition is running (or done), and as dispatched if not. A partitioned job isn't out on any
+/// one worker, so its worker IDs are zero.
+pub fn fan_in(states: &[JobState]) -> JobState {
+    if let Some(message) = states.iter().find_map(|state| match state {
+        JobState::Failed(message) => Some(message),
+        _ => None,
+    }) {
+        return JobState::Failed(format!("A partition failed: {}", message));
+    }
+    if states.iter().any(|state| *state == JobState::Cancelled) {
+        return JobState::Cancelled;
+    }
+    let n_done = states.iter().filter(|state| state.is_finished()).count();
+    if n_done == states.len() {
+        let n_rows = states.iter().map(|state| match state {
+            JobState::Done { n_rows, .. } => *n_rows,
+            _ => 0,
+        }).sum();
+        return JobState::Done { worker_id: 0, worker_job_id: 0, n_rows };
+    }
+    if states.iter().all(|state| *state == JobState::Queued) {
+        return JobState::Queued;
+    }
+    let started = n_done > 0 || states.iter().any(|state| match state {
+        JobState::Running { .. } => true,
+        _ => false,
+    });
+    if started {
+        JobState::Running { worker_id: 0, worker_job_id: 0 }
+    } else {
+        JobState::Dispatched { worker_id: 0, worker_job_id: 0 }
+    }
+}
+
+impl ScheduledJob {
+    /// Whether or not the job is out on the given worker, either as itself or as its speculative
+    /// duplicate.
+    fn is_out_on(&self, worker_id: u64) -> bool {
+        self.state.worker_id() == Some(worker_id)
+            || self.backup.as_ref().and_then(JobState::worker_id) == Some(worker_id)
+    }
+
+    /// Gives up on the attempt at the job on the given worker, if the job has a second attempt
+    /// out on another worker to fall back on. Returns whether or not it did.
+    fn drop_attempt(&mut self, worker_id: u64) -> bool {
+        let backup = match self.backup.take() {
+            Some(backup) => backup,
+            None => return false,
+        };
+        if backup.worker_id() == Some(worker_id) {
+            return true;
+        }
+        if self.state.worker_id() == Some(worker_id) {
+            self.state = backup;
+            return true;
+        }
+        self.backup = Some(backup);
+        false
+    }
+
+    /// Describes the job the way a client asking after it (with JOB_QUERY) sees it. The state
+    /// of a partitioned job is passed in, since it depends on the jobs of its partitions.
+    pub fn describe(&self, state: &JobState) -> ClusterJob {
+        let mut job = ClusterJob::new();
+        job.set_job_id(self.id);
+        job.set_attempts(self.attempts);
+        job.set_partitions(self.partitions.clone());
+        match state {
+            JobState::Queued => job.set_state(ClusterJob_State::QUEUED),
+            JobState::Dispatched { worker_id, worker_job_id } => {
+                job.set_state(ClusterJob_State::DISPATCHED);
+                job.set_worker_id(*worker_id);
+                job.set_worker_job_id(*worker_job_id);
+            },
+            JobState::Running { worker_id, worker_job_id } => {
+                job.set_state(ClusterJob_State::RUNNING);
+                job.set_worker_id(*worker_id);
+                job.set_worker_job_id(*worker_job_id);
+            },
+            JobState::Done { worker_id, worker_job_id, n_rows } => {
+                job.set_state(ClusterJob_State::DONE);
+                job.set_worker_id(*worker_id);
+                job.set_worker_job_id(*worker_job_id);
+                job.set_n_rows(*n_rows);
+            },
+            JobState::Failed(message) => {
+                job.set_state(ClusterJob_State::FAILED);
+                job.set_error(message.clone());
+            },
+            JobState::Cancelled => job.set_state(ClusterJob_State::CANCELLED),
+        }
+        match (&self.progress, state) {
+            (Some(progress), JobState::Dispatched { .. })
+                | (Some(progress), JobState::Running { .. }) => job.set_progress(progress.clone()),
+            _ => {},
+        }
+        job
+    }
+}
+
+/// The scheduler's queue of submitted jobs, and the record of how each of them is doing.
+///
+/// Clients `submit` workloads and get a job ID back straight away. The scheduler's dispatcher
+/// takes jobs off of the queue in the order they were submitted in (`take_next`), and sends each
+/// of them to an idle worker. Jobs whose worker dies are `requeue`d at the front of the queue.
+/// Jobs that wait on other jobs (see `Workload.after_jobs`) are held in the queue until those
+/// are done, letting the jobs behind them go first.
+///
+/// The partitions of a partitioned job are only as fast as the slowest of them, so a partition
+/// that runs far longer than its siblings (a "straggler") can be sent to a second worker as
+/// well (see `stragglers` and `speculate`). Whichever worker finishes the job first wins.
+pub struct JobQueue {
+    // As in the worker's job queue, the lock is never held across an `.await`.
+    jobs: Mutex<HashMap<u64, ScheduledJob>>,
+    queue: Mutex<VecDeque<u64>>,
+    notify: Notify,
+    next_id: AtomicU64,
+    // Every time a job finishes, the counter in this channel is bumped, which wakes up anyone
+    // `wait`ing on a job. This works the same way as in the worker's job queue.
+    finished_tx: watch::Sender<u64>,
+    finished_rx: watch::Receiver<u64>,
+    // The IDs of the jobs that changed since they were last written to the state database (see
+    // `take_dirty`). This lock is never taken before the one on `jobs`.
+    dirty: Mutex<HashSet<u64>>,
+    // The idempotency keys seen within the last `IDEMPOTENCY_WINDOW`, by principal and key (see
+    // `Workload.idempotency_key`), along with the ID of the job each was first seen with, and
+    // when. This lock is taken before any of the others.
+    keys: Mutex<HashMap<(String, String), (u64, Instant)>>,
+}
+
+impl JobQueue {
+    pub fn new() -> JobQueue {
+        let (finished_tx, finished_rx) = watch::channel(0);
+        JobQueue {
+            jobs: Mutex::new(HashMap::new()),
+            queue: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            next_id: AtomicU64::new(1),
+            finished_tx,
+            finished_rx,
+            dirty: Mutex::new(HashSet::new()),
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Puts back the jobs read out of the state database (see `store`) when the scheduler
+    /// starts. Jobs that were queued are queued again, in the order they were submitted in, as
+    /// are jobs that were sent to a worker that never acknowledged them. A partitioned job
+    /// whose merge was underway is set back to have it merged again (see `pending_merges`).
+    /// Jobs that were out on a worker are left as they were, for the scheduler to look into
+    /// (see `in_flight`).
+    pub fn restore(&self, restored: Vec<ScheduledJob>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut queue = self.queue.lock().unwrap();
+        for mut job in restored {
+            self.next_id.fetch_max(job.id + 1, Ordering::SeqCst);
+            if !job.partitions.is_empty() {
+                if let JobState::Running { .. } = job.state {
+                    job.state = JobState::Queued;
+                }
+            } else {
+                if let JobState::Dispatched { worker_job_id: 0, .. } = job.state {
+                    job.state = JobState::Queued;
+                }
+                if job.state == JobState::Queued {
+                    queue.push_back(job.id);
+                }
+                if job.state.worker_id().is_some() {
+                    job.dispatched_at = Some(Instant::now());
+                }
+            }
+            jobs.insert(job.id, job);
+        }
+        // Whoever wanted to be told about the jobs that already finished was told by the last
+        // scheduler, or, if it went down before it got around to it, never will be.
+        let finished = jobs.values()
+            .filter(|job| JobQueue::job_state(&jobs, job).is_finished())
+            .map(|job| job.id)
+            .collect::<Vec<_>>();
+        for id in finished {
+            jobs.get_mut(&id).unwrap().notified = true;
+        }
+        drop(queue);
+        drop(jobs);
+        self.notify.notify_one();
+    }
+
+    /// The jobs that are out on a worker which acknowledged them, as the job ID, the worker ID,
+    /// and the job ID that worker queued the job under.
+    pub fn in_flight(&self) -> Vec<(u64, u64, u64)> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut in_flight = jobs.values()
+            .filter_map(|job| job.state.worker_job().map(|(worker_id, worker_job_id)| {
+                (job.id, worker_id, worker_job_id)
+            }))
+            .collect::<Vec<_>>();
+        in_flight.sort();
+        in_flight
+    }
+
+    /// The partitioned jobs whose partitions' result sets haven't been merged yet. Whether or
+    /// not their partitions are done is for `take_merge` to say.
+    pub fn pending_merges(&self) -> Vec<u64> {
+        self.jobs.lock().unwrap().values()
+            .filter(|job| !job.merge_statement.is_empty() && job.state == JobState::Queued)
+            .map(|job| job.id)
+            .collect()
+    }
+
+    /// Takes the jobs that changed since the last call, to be written to the state database.
+    pub fn take_dirty(&self) -> Vec<ScheduledJob> {
+        let ids = std::mem::take(&mut *self.dirty.lock().unwrap());
+        let jobs = self.jobs.lock().unwrap();
+        ids.iter().filter_map(|id| jobs.get(id).cloned()).collect()
+    }
+
+    /// Marks jobs as changed, e.g. because writing them to the state database failed, so that
+    /// they are written again next time.
+    pub fn mark_dirty(&self, ids: impl Iterator<Item = u64>) {
+        self.dirty.lock().unwrap().extend(ids);
+    }
+
+    fn touch(&self, id: u64) {
+        self.dirty.lock().unwrap().insert(id);
+    }
+
+    fn insert(&self, workload: Workload, parent: Option<u64>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.jobs.lock().unwrap().insert(id, ScheduledJob {
+            id,
+            workload,
+            state: JobState::Queued,
+            submitted_at: Instant::now(),
+            attempts: 0,
+            dispatched_at: None,
+            runtime: None,
+            backup: None,
+            partitions: vec![],
+            parent,
+            merge_statement: String::new(),
+            results: vec![],
+            results_at: None,
+            fetched: false,
+            notified: false,
+            progress: None,
+        });
+        self.touch(id);
+        id
+    }
+
+    /// The ID of the job that the given principal submitted under an idempotency key within the
+    /// last `IDEMPOTENCY_WINDOW`, if there was one.
+    pub fn keyed_job(&self, principal: &str, key: &str) -> Option<u64> {
+        self.keys.lock().unwrap().get(&(principal.to_owned(), key.to_owned()))
+            .filter(|(_, seen_at)| seen_at.elapsed() < IDEMPOTENCY_WINDOW)
+            .map(|(id, _)| *id)
+    }
+
+    /// Calls `submit` to submit a job with the given idempotency key, unless the principal
+    /// already submitted one under it within the last `IDEMPOTENCY_WINDOW`, in which case the
+    /// ID of that job is returned instead. Jobs without a key are always submitted. Returns the
+    /// job ID, and whether or not the job was submitted just now.
+    fn submit_once<F: FnOnce() -> u64>(
+        &self, principal: &str, key: &str, submit: F
+    ) -> (u64, bool) {
+        if key.is_empty() {
+            return (submit(), true);
+        }
+        // Held until the job is submitted, so that two submissions under the same key can't
+        // both find it unused.
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|_, (_, seen_at)| seen_at.elapsed() < IDEMPOTENCY_WINDOW);
+        let key = (principal.to_owned(), key.to_owned());
+        if let Some((id, _)) = keys.get(&key) {
+            return (*id, false);
+        }
+        let id = submit();
+        keys.insert(key, (id, Instant::now()));
+        (id, true)
+    }
+
+    /// Remembers the idempotency keys of the jobs restored out of the state database (see
+    /// `restore`), as though they were submitted just now. `principal` says who submitted each
+    /// of them, by job ID.
+    pub fn restore_keys<F: Fn(u64) -> String>(&self, principal: F) {
+        let mut keys = self.keys.lock().unwrap();
+        for job in self.jobs.lock().unwrap().values() {
+            let key = job.workload.get_idempotency_key();
+            if job.parent.is_none() && !key.is_empty() {
+                keys.insert((principal(job.id), key.to_owned()), (job.id, Instant::now()));
+            }
+        }
+    }
+
+    /// Queues a workload submitted by the given principal, returning the job ID it was queued
+    /// under, and `true`. A workload with an idempotency key the principal already submitted a
+    /// job under isn't queued again (see `Workload.idempotency_key`); the ID of that job is
+    /// returned instead, with `false`.
+    pub fn submit(&self, workload: Workload, principal: &str) -> (u64, bool) {
+        let key = workload.get_idempotency_key().to_owned();
+        self.submit_once(principal, &key, || {
+            let id = self.insert(workload, None);
+            self.queue.lock().unwrap().push_back(id);
+            self.notify.notify_one();
+            id
+        })
+    }
+
+    /// Queues the workloads of a partitioned job, one job per partition, returning the ID of
+    /// the parent job that tracks them all. The parent job's workload is never run, and only
+    /// holds the job's settings (e.g. its `notify_url`, and its idempotency key, which works as
+    /// it does for `submit`).
+    pub fn submit_partitioned(
+        &self, options: Workload, workloads: Vec<Workload>, merge_statement: &str, principal: &str
+    ) -> (u64, bool) {
+        let key = options.get_idempotency_key().to_owned();
+        self.submit_once(principal, &key, || {
+            self.insert_partitioned(options, workloads, merge_statement)
+        })
+    }
+
+    fn insert_partitioned(
+        &self, options: Workload, workloads: Vec<Workload>, merge_statement: &str
+    ) -> u64 {
+        let id = self.insert(options, None);
+        let partitions = workloads.into_iter().map(|workload| self.insert(workload, Some(id)))
+            .collect::<Vec<_>>();
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            job.partitions = partitions.clone();
+            job.merge_statement = merge_statement.to_owned();
+        }
+        self.touch(id);
+        self.queue.lock().unwrap().extend(partitions);
+        self.notify.notify_one();
+        id
+    }
+
+    /// Waits until a job is submitted or re-queued, or until `max_wait` is up, whichever comes
+    /// first. Jobs don't only become dispatchable when they are queued, but also when a worker
+    /// frees up, which nothing notifies us of; hence the time limit.
+    pub async fn wait_for_work(&self, max_wait: Duration) {
+        let _ = timeout(max_wait, self.notify.notified()).await;
+    }
+
+    /// Returns the workload of the job `take_next` would take next, if `eligible` is all that
+    /// stands in its way, without taking it. Dispatch policies that weigh up where the job would
+    /// be cheapest to run look at this (see `DispatchPolicy::pick_for`).
+    pub fn peek_next<F: Fn(u64, &Workload) -> bool>(&self, eligible: F) -> Option<Workload> {
+        let queue = self.queue.lock().unwrap();
+        let jobs = self.jobs.lock().unwrap();
+        queue.iter()
+            .filter_map(|id| jobs.get(id))
+            .find(|job| {
+                job.state == JobState::Queued
+                    && eligible(job.parent.unwrap_or(job.id), &job.workload)
+                    && JobQueue::prerequisites(&jobs, job.workload.get_after_jobs()) == Ok(true)
+            })
+            .map(|job| job.workload.clone())
+    }
+
+    /// Takes the next job off of the queue and hands it to the given worker, returning its ID
+    /// and its workload. The job is `Dispatched` from here on, with a worker job ID of zero
+    /// until the worker acknowledges it (see `update`). Jobs still waiting on other jobs (see
+    /// `Workload.after_jobs`) are passed over, and keep their place in the queue, as are jobs
+    /// for which `eligible` (which is given the job's owner, see `owner`, and its workload)
+    /// returns `false`.
+    pub fn take_next<F: Fn(u64, &Workload) -> bool>(
+        &self, worker_id: u64, eligible: F
+    ) -> Option<(u64, Workload)> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut jobs = self.jobs.lock().unwrap();
+        // Jobs that are no longer queued (e.g. because they were cancelled) are dropped.
+        queue.retain(|id| jobs.get(id).map_or(false, |job| job.state == JobState::Queued));
+        let position = queue.iter().position(|id| {
+            let job = &jobs[id];
+            eligible(job.parent.unwrap_or(job.id), &job.workload)
+                && JobQueue::prerequisites(&jobs, job.workload.get_after_jobs()) == Ok(true)
+        })?;
+        let id = queue.remove(position)?;
+        let job = jobs.get_mut(&id)?;
+        job.state = JobState::Dispatched { worker_id, worker_job_id: 0 };
+        job.attempts += 1;
+        job.dispatched_at = Some(Instant::now());
+        job.progress = None;
+        self.touch(id);
+        Some((id, job.workload.clone()))
+    }
+
+    /// Checks on the jobs a job waits on (see `Workload.after_jobs`), returning whether or not
+    /// they are all done, or, if one of them failed or was cancelled, why the job can never run.
+    fn prerequisites(
+        jobs: &HashMap<u64, ScheduledJob>, after: &[u64]
+    ) -> std::result::Result<bool, String> {
+        let mut all_done = true;
+        for id in after {
+            match jobs.get(id).map(|job| JobQueue::job_state(jobs, job)) {
+                Some(JobState::Done { .. }) => {},
+                Some(JobState::Failed(_)) => {
+                    return Err(format!("Job {}, which this job runs after, failed.", id));
+                },
+                Some(JobState::Cancelled) => {
+                    return Err(format!("Job {}, which this job runs after, was cancelled.", id));
+                },
+                Some(_) => all_done = false,
+                None => {
+                    return Err(format!("Job {}, which this job runs after, doesn't exist.", id));
+                },
+            }
+        }
+        Ok(all_done)
+    }
+
+    /// Fails the queued jobs that wait on a job that failed or was cancelled, as they can never
+    /// run. Returns their IDs, along with why they failed.
+    pub fn fail_blocked(&self) -> Vec<(u64, String)> {
+        let queue = self.queue.lock().unwrap();
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut failed = vec![];
+        for id in queue.iter() {
+            let blocked = match jobs.get(id) {
+                Some(job) if job.state == JobState::Queued => {
+                    JobQueue::prerequisites(&jobs, job.workload.get_after_jobs()).err()
+                },
+                _ => None,
+            };
+            if let Some(message) = blocked {
+                jobs.get_mut(id).unwrap().state = JobState::Failed(message.clone());
+                self.touch(*id);
+                failed.push((*id, message));
+            }
+        }
+        drop(jobs);
+        drop(queue);
+        if !failed.is_empty() {
+            self.bump_finished();
+        }
+        failed
+    }
+
+    /// Puts a job that is out on the given worker back at the front of the queue, e.g. because
+    /// the worker died, or because the job could not be sent to it. Returns whether or not it
+    /// did (see `update`).
+    pub fn requeue(&self, id: u64, worker_id: u64) -> bool {
+        if !self.update(id, worker_id, JobState::Queued) {
+            return false;
+        }
+        self.queue.lock().unwrap().push_front(id);
+        self.notify.notify_one();
+        true
+    }
+
+    /// Records that the worker a job was sent to is running it. Only jobs that are still out on
+    /// that worker are updated, so a late report from a worker that has since been given up on
+    /// doesn't count.
+    pub fn running(&self, worker_id: u64, worker_job_id: u64) {
+        let dispatched = JobState::Dispatched { worker_id, worker_job_id };
+        for job in self.jobs.lock().unwrap().values_mut() {
+            if job.state == dispatched {
+                job.state = JobState::Running { worker_id, worker_job_id };
+                self.touch(job.id);
+            }
+            if job.backup.as_ref() == Some(&dispatched) {
+                job.backup = Some(JobState::Running { worker_id, worker_job_id });
+            }
+        }
+    }
+
+    /// Records how far along a job is, as reported by the worker it is out on. Reports from a
+    /// worker the job is no longer out on are ignored.
+    pub fn set_progress(&self, id: u64, worker_id: u64, progress: JobProgress) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            let out_on_worker = |state: &JobState| match state {
+                JobState::Dispatched { worker_id: id, .. } => *id == worker_id,
+                JobState::Running { worker_id: id, .. } => *id == worker_id,
+                _ => false,
+            };
+            if out_on_worker(&job.state) || job.backup.as_ref().map_or(false, out_on_worker) {
+                job.progress = Some(progress);
+            }
+        }
+    }
+
+    /// Moves a job on to its next state. As with `running`, this only applies if the job is
+    /// still out on the given worker; otherwise this returns `false`, and the job is left be.
+    ///
+    /// A job with a speculative duplicate out is out on two workers. If the attempt on one of
+    /// them fails, or has to be re-queued, the job is simply left to the other one, and this
+    /// returns `false` too.
+    pub fn update(&self, id: u64, worker_id: u64, state: JobState) -> bool {
+        let finished = state.is_finished();
+        // Even an update that doesn't apply can give up on one of the job's attempts.
+        self.touch(id);
+        let updated = match self.jobs.lock().unwrap().get_mut(&id) {
+            Some(job) => match state {
+                JobState::Queued | JobState::Failed(_) if job.drop_attempt(worker_id) => false,
+                state if job.state.worker_id() == Some(worker_id) => {
+                    job.state = state;
+                    true
+                },
+                state if job.is_out_on(worker_id) => {
+                    job.backup = Some(state);
+                    true
+                },
+                _ => false,
+            },
+            None => false,
+        };
+        if updated && finished {
+            self.bump_finished();
+        }
+        updated
+    }
+
+    /// Like `update`, for a job that is done, keeping its result batches. If the job had a
+    /// speculative duplicate out, the attempt that lost the race is returned as a worker ID and
+    /// the job ID that worker queued it under, so that it can be cancelled. An attempt that the
+    /// worker hasn't acknowledged yet can't be cancelled, and isn't returned; its outcome is
+    /// simply ignored.
+    pub fn done(
+        &self, id: u64, worker_id: u64, state: JobState, results: Vec<ResultBatch>
+    ) -> Option<(u64, u64)> {
+        let mut loser = None;
+        let updated = match self.jobs.lock().unwrap().get_mut(&id) {
+            Some(job) if job.is_out_on(worker_id) => {
+                let attempts = Some(job.state.clone()).into_iter().chain(job.backup.take());
+                loser = attempts.filter(|attempt| attempt.worker_id() != Some(worker_id))
+                    .find_map(|attempt| attempt.worker_job());
+                job.runtime = job.dispatched_at.map(|dispatched_at| dispatched_at.elapsed());
+                job.state = state;
+                job.results = results;
+                job.results_at = Some(Instant::now());
+                true
+            },
+            _ => false,
+        };
+        if updated {
+            self.touch(id);
+            self.bump_finished();
+        }
+        loser
+    }
+
+    /// Finds the partitions that are straggling: those that have been out for more than `factor`
+    /// times as long as their finished siblings took (going by the median), without a
+    /// speculative duplicate out yet. Partitioned jobs whose partitions haven't at least half
+    /// finished are left out, as there isn't much to go by yet. Returns the ID of each straggler,
+    /// along with the worker it is out on.
+    pub fn stragglers(&self, factor: f64) -> Vec<(u64, u64)> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut stragglers = vec![];
+        for parent in jobs.values().filter(|job| !job.partitions.is_empty()) {
+            let partitions = parent.partitions.iter()
+                .filter_map(|id| jobs.get(id))
+                .collect::<Vec<_>>();
+            let mut runtimes = partitions.iter()
+                .filter_map(|partition| partition.runtime)
+                .collect::<Vec<_>>();
+            if runtimes.is_empty() || runtimes.len() * 2 < partitions.len() {
+                continue;
+            }
+            runtimes.sort();
+            let cutoff = runtimes[runtimes.len() / 2].mul_f64(factor);
+            for partition in partitions {
+                let worker_id = match partition.state.worker_id() {
+                    Some(worker_id) if partition.backup.is_none() => worker_id,
+                    _ => continue,
+                };
+                let straggling = partition.dispatched_at
+                    .map_or(false, |dispatched_at| dispatched_at.elapsed() > cutoff);
+                if straggling {
+                    stragglers.push((partition.id, worker_id));
+                }
+            }
+        }
+        stragglers
+    }
+
+    /// Hands a duplicate of a job that is out on another worker to the given worker, returning
+    /// its workload, unless the job already has a duplicate out (or is no longer out at all).
+    /// The duplicate starts out `Dispatched`, like any other job.
+    pub fn speculate(&self, id: u64, worker_id: u64) -> Option<Workload> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(&id)?;
+        if job.backup.is_some() || job.state.worker_id().map_or(true, |other| other == worker_id) {
+            return None;
+        }
+        job.backup = Some(JobState::Dispatched { worker_id, worker_job_id: 0 });
+        job.attempts += 1;
+        self.touch(id);
+        Some(job.workload.clone())
+    }
+
+    /// Takes the jobs that finished since the last call, and that want to be told so (see
+    /// `Workload.notify_url`), returning their IDs and their notify URLs. The partitions of a
+    /// partitioned job are left out; it's the partitioned job that is notified about.
+    pub fn take_notifications(&self) -> Vec<(u64, String)> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let finished = jobs.values()
+            .filter(|job| {
+                !job.notified && job.parent.is_none() && !job.workload.get_notify_url().is_empty()
+            })
+            .filter(|job| JobQueue::job_state(&jobs, job).is_finished())
+            .map(|job| job.id)
+            .collect::<Vec<_>>();
+        finished.into_iter().map(|id| {
+            let job = jobs.get_mut(&id).unwrap();
+            job.notified = true;
+            (id, job.workload.get_notify_url().to_owned())
+        }).collect()
+    }
+
+    /// Returns a receiver that sees a change every time a job finishes.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.finished_rx.clone()
+    }
+
+    /// The partitioned job the given job is a partition of, if any.
+    pub fn parent(&self, id: u64) -> Option<u64> {
+        self.jobs.lock().unwrap().get(&id).and_then(|job| job.parent)
+    }
+
+    /// If every partition of a partitioned job is done, and their result sets still have to be
+    /// merged, marks the merge as started, and returns the merge statement, along with the
+    /// result batches of all of the partitions. Otherwise returns `None`. This only ever
+    /// returns the merge once, so it's safe to call whenever any partition finishes.
+    pub fn take_merge(&self, id: u64) -> Option<(String, Vec<ResultBatch>)> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(&id)?;
+        if job.merge_statement.is_empty() || job.state != JobState::Queued {
+            return None;
+        }
+        let all_done = job.partitions.iter().all(|partition| match jobs.get(partition) {
+            Some(partition) => match partition.state {
+                JobState::Done { .. } => true,
+                _ => false,
+            },
+            None => false,
+        });
+        if !all_done {
+            return None;
+        }
+        let statement = job.merge_statement.clone();
+        let partials = job.partitions.iter()
+            .flat_map(|partition| jobs[partition].results.iter().cloned())
+            .collect::<Vec<_>>();
+        jobs.get_mut(&id).unwrap().state = JobState::Running { worker_id: 0, worker_job_id: 0 };
+        self.touch(id);
+        Some((statement, partials))
+    }
+
+    /// Records how the merge of a partitioned job's result sets went, unless the job was
+    /// cancelled in the meantime.
+    pub fn finish_merge(&self, id: u64, outcome: std::result::Result<ResultBatch, String>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut merged_from = vec![];
+        if let Some(job) = jobs.get_mut(&id) {
+            if job.state == JobState::Cancelled {
+                return;
+            }
+            match outcome {
+                Ok(merged) => {
+                    let n_rows = merged.get_rows().len() as u64;
+                    job.state = JobState::Done { worker_id: 0, worker_job_id: 0, n_rows };
+                    job.results = vec![merged];
+                    job.results_at = Some(Instant::now());
+                    merged_from = job.partitions.clone();
+                },
+                Err(message) => {
+                    job.state = JobState::Failed(format!("Merge failed: {}", message));
+                },
+            }
+            self.touch(id);
+        }
+        // The partitions' results are in the merged ones now, so they needn't be held as well.
+        for partition in merged_from {
+            if let Some(partition) = jobs.get_mut(&partition) {
+                partition.results = vec![];
+                partition.results_at = None;
+                self.touch(partition.id);
+            }
+        }
+        drop(jobs);
+        self.bump_finished();
+    }
+
+    /// Cancels a job that hasn't finished yet, along with its partitions, if it has any. A job
+    /// that is still queued is simply never dispatched. Returns the attempts at the job that are
+    /// out on workers (see `JobState::worker_job`), so that the workers can be told to stop
+    /// them. An attempt that its worker hasn't acknowledged yet can't be stopped, and is left to
+    /// run; its outcome is ignored. Returns `None` if there is no such job, or if it already
+    /// finished.
+    pub fn cancel(&self, id: u64) -> Option<Vec<(u64, u64)>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(&id)?;
+        if JobQueue::job_state(&jobs, job).is_finished() {
+            return None;
+        }
+        let mut ids = job.partitions.clone();
+        ids.push(id);
+        let mut attempts = vec![];
+        for id in ids {
+            let job = match jobs.get_mut(&id) {
+                Some(job) if !job.state.is_finished() => job,
+                // Partitions that are already done are left be.
+                _ => continue,
+            };
+            let out = Some(job.state.clone()).into_iter().chain(job.backup.take());
+            attempts.extend(out.filter_map(|attempt| attempt.worker_job()));
+            job.state = JobState::Cancelled;
+            self.touch(id);
+        }
+        drop(jobs);
+        self.bump_finished();
+        Some(attempts)
+    }
+
+    /// Returns the result batches of a job that is done. A partitioned job without a merge
+    /// statement returns those of its partitions, one after the other. The batches are
+    /// relabeled with the scheduler's job ID, and only the final one is marked `last`.
+    pub fn results(&self, id: u64) -> Option<Vec<ResultBatch>> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(&id)?;
+        let mut results = if job.partitions.is_empty() || !job.merge_statement.is_empty() {
+            job.results.clone()
+        } else {
+            job.partitions.iter()
+                .filter_map(|partition| jobs.get(partition))
+                .flat_map(|partition| partition.results.iter().cloned())
+                .collect()
+        };
+        let n_batches = results.len();
+        for (i, batch) in results.iter_mut().enumerate() {
+            batch.set_job_id(id);
+            batch.set_last(i + 1 == n_batches);
+        }
+        Some(results)
+    }
+
+    /// Waits for a job to finish, returning its final state, or `None` if the scheduler has
+    /// never seen it.
+    pub async fn wait(&self, id: u64) -> Option<JobState> {
+        // As in the worker, the receiver has to be cloned before the state is checked.
+        let mut finished_rx = self.finished_rx.clone();
+        loop {
+            match self.state(id) {
+                None => return None,
+                Some(state) if state.is_finished() => return Some(state),
+                _ => {},
+            }
+            if finished_rx.changed().await.is_err() {
+                return self.state(id);
+            }
+        }
+    }
+
+    fn bump_finished(&self) {
+        let finished = *self.finished_rx.borrow() + 1;
+        // This can only fail if there are no receivers, but we hold one ourselves.
+        let _ = self.finished_tx.send(finished);
+    }
+
+    pub fn state(&self, id: u64) -> Option<JobState> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(&id).map(|job| JobQueue::job_state(&jobs, job))
+    }
+
+    pub fn describe(&self, id: u64) -> Option<ClusterJob> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(&id).map(|job| job.describe(&JobQueue::job_state(&jobs, job)))
+    }
+
+    fn job_state(jobs: &HashMap<u64, ScheduledJob>, job: &ScheduledJob) -> JobState {
+        if job.partitions.is_empty() {
+            return job.state.clone();
+        }
+        let states = job.partitions.iter()
+            .filter_map(|id| jobs.get(id).map(|partition| partition.state.clone()))
+            .collect::<Vec<_>>();
+        match fan_in(&states) {
+            // Once the partitions are all done, a job with a merge statement is still running
+            // until the merge is done too. The merge's progress is tracked in the job's own state.
+            JobState::Done { .. } if !job.merge_statement.is_empty() => match &job.state {
+                JobState::Queued => JobState::Running { worker_id: 0, worker_job_id: 0 },
+                state => state.clone(),
+            },
+            state => state,
+        }
+    }
+
+    /// The IDs of the jobs that haven't finished yet, leaving out the partitions of partitioned
+    /// jobs (whose parents cover them).
+    pub fn unfinished(&self) -> Vec<u64> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut unfinished = jobs.values()
+            .filter(|job| job.parent.is_none() && !JobQueue::job_state(&jobs, job).is_finished())
+            .map(|job| job.id)
+            .collect::<Vec<_>>();
+        unfinished.sort_unstable();
+        unfinished
+    }
+
+    /// How many jobs of each owner (the job itself, or for a partition, its partitioned job)
+    /// are out on workers, and whether or not any of them is still queued, by owner.
+    pub fn load_by_owner(&self) -> HashMap<u64, (usize, bool)> {
+        let mut load = HashMap::new();
+        for job in self.jobs.lock().unwrap().values() {
+            let (running, queued) = load.entry(job.parent.unwrap_or(job.id)).or_insert((0, false));
+            match job.state {
+                JobState::Dispatched { .. } | JobState::Running { .. } => *running += 1,
+                // Partitioned jobs are queued through their partitions, never themselves.
+                JobState::Queued if job.partitions.is_empty() => *queued = true,
+                _ => {},
+            }
+        }
+        load
+    }
+
+    /// How many bytes of results nobody has fetched yet the scheduler holds for each owner (see
+    /// `load_by_owner`).
+    pub fn result_bytes_by_owner(&self) -> HashMap<u64, u64> {
+        let mut bytes = HashMap::new();
+        for job in self.jobs.lock().unwrap().values().filter(|job| !job.fetched) {
+            let size = job.results.iter().map(|batch| batch.compute_size() as u64).sum::<u64>();
+            *bytes.entry(job.parent.unwrap_or(job.id)).or_insert(0) += size;
+        }
+        bytes
+    }
+
+    /// Records that a job's results were fetched, along with those of its partitions, which are
+    /// what a partitioned job without a merge statement returns (see `results`).
+    pub fn mark_fetched(&self, id: u64) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let partitions = match jobs.get_mut(&id) {
+            Some(job) => {
+                job.fetched = true;
+                job.partitions.clone()
+            },
+            None => return,
+        };
+        for partition in partitions {
+            if let Some(partition) = jobs.get_mut(&partition) {
+                partition.fetched = true;
+            }
+        }
+    }
+
+    /// Throws away the results of the jobs whose results came in more than `ttl` ago, returning
+    /// the IDs of those jobs. The jobs themselves are kept, so they still show up as done.
+    pub fn expire_results(&self, ttl: Duration) -> Vec<u64> {
+        let mut expired = vec![];
+        for job in self.jobs.lock().unwrap().values_mut() {
+            if job.results_at.map_or(false, |results_at| results_at.elapsed() >= ttl) {
+                job.results = vec![];
+                job.results_at = None;
+                self.touch(job.id);
+                expired.push(job.id);
+            }
+        }
+        expired.sort_unstable();
+        expired
+    }
+
+    /// The workload a job was submitted with.
+    pub fn workload(&self, id: u64) -> Option<Workload> {
+        self.jobs.lock().unwrap().get(&id).map(|job| job.workload.clone())
+    }
+
+    /// How many times a job has been sent to a worker so far.
+    pub fn attempts(&self, id: u64) -> u32 {
+        self.jobs.lock().unwrap().get(&id).map_or(0, |job| job.attempts)
+    }
+
+    /// How long a job took on its worker, once it is done.
+    pub fn runtime(&self, id: u64) -> Option<Duration> {
+        self.jobs.lock().unwrap().get(&id).and_then(|job| job.runtime)
+    }
+
+    /// The number of jobs waiting to be dispatched.
+    pub fn depth(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A result batch for the given job.
+    fn craft_batch(job_id: u64) -> ResultBatch {
+        let mut batch = ResultBatch::new();
+        batch.set_job_id(job_id);
+        batch.set_last(true);
+        batch
+    }
+
+    /// Hands the next job to the given worker, and has it come back done with a batch of
+    /// results. Returns the job's ID.
+    fn finish_next(queue: &JobQueue, worker_id: u64) -> u64 {
+        let (id, _) = queue.take_next(worker_id, |_, _| true).unwrap();
+        let state = JobState::Done { worker_id, worker_job_id: id, n_rows: 1 };
+        assert_eq!(queue.done(id, worker_id, state, vec![craft_batch(id)]), None);
+        id
+    }
+
+    #[test]
+    fn test_result_bytes_by_owner() {
+        let queue = JobQueue::new();
+        let (id, _) = queue.submit(Workload::new(), "alice");
+        finish_next(&queue, 1);
+        let size = craft_batch(id).compute_size() as u64;
+        assert_eq!(queue.result_bytes_by_owner()[&id], size);
+
+        // Results that were fetched don't count.
+        queue.mark_fetched(id);
+        assert_eq!(queue.result_bytes_by_owner().get(&id), None);
+
+        // The results of the partitions of a merged job only count once, as the merged ones.
+        let workloads = vec![Workload::new(), Workload::new()];
+        let (parent, _) = queue.submit_partitioned(Workload::new(), workloads, "SELECT 1", "bob");
+        finish_next(&queue, 1);
+        finish_next(&queue, 2);
+        assert_eq!(queue.result_bytes_by_owner()[&parent], 2 * size);
+        let (_, partials) = queue.take_merge(parent).unwrap();
+        assert_eq!(partials.len(), 2);
+        queue.finish_merge(parent, Ok(craft_batch(parent)));
+        assert_eq!(queue.result_bytes_by_owner()[&parent], size);
+        assert_eq!(queue.results(parent).unwrap().len(), 1);
+
+        // Nor do results that expired, though the jobs are still done.
+        assert!(queue.expire_results(Duration::from_secs(60)).is_empty());
+        assert_eq!(queue.expire_results(Duration::from_secs(0)), vec![id, parent]);
+        assert_eq!(queue.result_bytes_by_owner()[&parent], 0);
+        assert!(queue.state(parent).unwrap().is_finished());
+    }
+
+    /// A workload that only runs once the given jobs are done.
+    fn craft_after(after: Vec<u64>) -> Workload {
+        let mut workload = Workload::new();
+        workload.set_after_jobs(after);
+        workload
+    }
+
+    #[test]
+    fn test_dispatch_order() {
+        let queue = JobQueue::new();
+        let ids = (0..3).map(|_| queue.submit(Workload::new(), "alice").0).collect::<Vec<_>>();
+        assert_eq!(queue.depth(), 3);
+
+        // Jobs that aren't eligible are passed over, but keep their place.
+        let (id, _) = queue.take_next(1, |owner, _| owner != ids[0]).unwrap();
+        assert_eq!(id, ids[1]);
+        assert_eq!(queue.state(id), Some(JobState::Dispatched { worker_id: 1, worker_job_id: 0 }));
+        assert_eq!(queue.attempts(id), 1);
+        assert!(queue.take_next(1, |owner, _| owner == ids[1]).is_none());
+        assert_eq!(queue.take_next(2, |_, _| true).unwrap().0, ids[0]);
+
+        // Cancelled jobs are dropped from the queue.
+        queue.cancel(ids[2]);
+        assert!(queue.take_next(3, |_, _| true).is_none());
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[test]
+    fn test_requeue() {
+        let queue = JobQueue::new();
+        let (first, _) = queue.submit(Workload::new(), "alice");
+        let (second, _) = queue.submit(Workload::new(), "alice");
+        queue.take_next(1, |_, _| true).unwrap();
+
+        // Only the worker the job is out on can hand it back.
+        assert!(!queue.requeue(first, 2));
+        assert!(queue.requeue(first, 1));
+        assert_eq!(queue.state(first), Some(JobState::Queued));
+        assert!(!queue.requeue(first, 1));
+
+        // Re-queued jobs go back to the front of the queue.
+        assert_eq!(queue.take_next(2, |_, _| true).unwrap().0, first);
+        assert_eq!(queue.attempts(first), 2);
+        assert_eq!(queue.take_next(2, |_, _| true).unwrap().0, second);
+    }
+
+    #[test]
+    fn test_speculation() {
+        let queue = JobQueue::new();
+        let (id, _) = queue.submit(Workload::new(), "alice");
+        queue.take_next(1, |_, _| true).unwrap();
+        assert!(queue.update(id, 1, JobState::Dispatched { worker_id: 1, worker_job_id: 11 }));
+
+        // A job only gets one duplicate, and never on the worker it is already out on.
+        assert!(queue.speculate(id, 1).is_none());
+        assert!(queue.speculate(id, 2).is_some());
+        assert!(queue.speculate(id, 3).is_none());
+        assert_eq!(queue.attempts(id), 2);
+        assert!(queue.update(id, 2, JobState::Dispatched { worker_id: 2, worker_job_id: 22 }));
+        queue.running(2, 22);
+
+        // The duplicate finishes first, so the original attempt lost, and is to be cancelled.
+        let state = JobState::Done { worker_id: 2, worker_job_id: 22, n_rows: 1 };
+        assert_eq!(queue.done(id, 2, state.clone(), vec![craft_batch(id)]), Some((1, 11)));
+        assert_eq!(queue.state(id), Some(state.clone()));
+
+        // Whatever the loser has to say after that is ignored.
+        let late = JobState::Done { worker_id: 1, worker_job_id: 11, n_rows: 2 };
+        assert_eq!(queue.done(id, 1, late, vec![]), None);
+        assert_eq!(queue.state(id), Some(state));
+        assert_eq!(queue.results(id).unwrap().len(), 1);
+
+        // If either attempt fails, the job is left to the other one. A duplicate the worker
+        // hasn't acknowledged yet can't be cancelled, so it isn't returned as the loser.
+        let (id, _) = queue.submit(Workload::new(), "alice");
+        queue.take_next(1, |_, _| true).unwrap();
+        queue.speculate(id, 2).unwrap();
+        assert!(!queue.update(id, 1, JobState::Failed("Worker died.".to_owned())));
+        assert_eq!(queue.state(id), Some(JobState::Dispatched { worker_id: 2, worker_job_id: 0 }));
+        assert!(queue.speculate(id, 3).is_some());
+        let state = JobState::Done { worker_id: 2, worker_job_id: 0, n_rows: 0 };
+        assert_eq!(queue.done(id, 2, state, vec![]), None);
+    }
+
+    #[test]
+    fn test_after_jobs() {
+        let queue = JobQueue::new();
+        let (first, _) = queue.submit(Workload::new(), "alice");
+        let (blocked, _) = queue.submit(craft_after(vec![first]), "alice");
+        let (free, _) = queue.submit(Workload::new(), "alice");
+
+        // A job waiting on another lets the jobs behind it go first.
+        assert_eq!(queue.take_next(1, |_, _| true).unwrap().0, first);
+        assert_eq!(queue.peek_next(|_, _| true).unwrap(), Workload::new());
+        assert_eq!(queue.take_next(2, |_, _| true).unwrap().0, free);
+        assert!(queue.take_next(3, |_, _| true).is_none());
+        assert!(queue.peek_next(|_, _| true).is_none());
+
+        // Once the job it waits on is done, it goes.
+        let state = JobState::Done { worker_id: 1, worker_job_id: first, n_rows: 1 };
+        queue.done(first, 1, state, vec![]);
+        assert_eq!(queue.take_next(3, |_, _| true).unwrap().0, blocked);
+
+        // Jobs waiting on a job that failed, or that doesn't exist, can never run.
+        let (failing, _) = queue.submit(Workload::new(), "alice");
+        let (doomed, _) = queue.submit(craft_after(vec![failing]), "alice");
+        let (orphan, _) = queue.submit(craft_after(vec![1000]), "alice");
+        queue.take_next(1, |_, _| true).unwrap();
+        let failed = queue.fail_blocked();
+        assert_eq!(failed.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![orphan]);
+        queue.update(failing, 1, JobState::Failed("Bad SQL.".to_owned()));
+        let failed = queue.fail_blocked();
+        assert_eq!(failed.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![doomed]);
+        assert!(queue.state(doomed).unwrap().is_finished());
+        assert!(queue.take_next(1, |_, _| true).is_none());
+    }
+}
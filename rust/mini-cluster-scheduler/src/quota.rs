@@ -0,0 +1,75 @@
+use mini_cluster_worker::ratelimit::RateLimit;
+
+use crate::err::{Result, SchedulerError, ErrKind};
+
+// The scheduler is shared between tenants: whoever submits jobs, as named by the principal
+// they submit them as (see `history`). So that one tenant can't crowd out the others, each of
+// them is held to the same limits:
+//
+// * how many of its jobs may be out on workers at once. Jobs over the limit stay queued, and
+//   are dispatched as the tenant's other jobs finish. Each partition of a partitioned job
+//   counts as a job of its own here.
+// * how many of its jobs may wait in the queue at once. Jobs over the limit are turned away
+//   when they are submitted.
+// * how many bytes of results the scheduler may hold on its behalf, across all of its jobs. A
+//   job whose results would take the tenant over the limit is failed, and its results dropped.
+//   Results only count until they are fetched, or until they expire (see
+//   `SchedulerConfig.results_ttl`), whichever comes first.
+// * how fast it may submit jobs (see `ratelimit`). Submissions over the limit are turned away,
+//   with word of when to try again, before the scheduler so much as looks at them.
+//
+// Each limit is off unless it is set. Principals are whatever clients say they are, so quotas
+// keep well-meaning tenants out of each other's way, rather than keeping anyone out.
+
+/// The limits every tenant is held to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TenantQuota {
+    /// How many of a tenant's jobs may be out on workers at once
+    /// (`SCHEDULER_TENANT_MAX_RUNNING`).
+    pub max_running: Option<usize>,
+    /// How many of a tenant's jobs may be queued at once (`SCHEDULER_TENANT_MAX_QUEUED`).
+    pub max_queued: Option<usize>,
+    /// How many bytes of results the scheduler holds on a tenant's behalf at most
+    /// (`SCHEDULER_TENANT_MAX_RESULT_BYTES`).
+    pub max_result_bytes: Option<u64>,
+    /// How fast a tenant may submit jobs: `SCHEDULER_TENANT_SUBMIT_RATE` a second, in bursts of
+    /// up to `SCHEDULER_TENANT_SUBMIT_BURST`.
+    pub submit_rate: Option<RateLimit>,
+}
+
+/// The error for a tenant going over its quota.
+pub fn quota_exceeded(principal: &str, message: &str) -> SchedulerError {
+    SchedulerError::new(ErrKind::QuotaExceeded, &format!("{} ({})", message, principal))
+}
+
+impl TenantQuota {
+    /// Whether or not a tenant with `running` jobs out on workers may have another one sent.
+    pub fn may_run(&self, running: usize) -> bool {
+        self.max_running.map_or(true, |max| running < max)
+    }
+
+    /// Checks that a tenant with `queued` jobs waiting may queue another one.
+    pub fn check_queued(&self, principal: &str, queued: usize) -> Result<()> {
+        match self.max_queued {
+            Some(max) if queued >= max => Err(quota_exceeded(
+                principal, &format!("Already {} jobs queued, the most allowed", queued)
+            ))?,
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that a tenant whose jobs hold `held` bytes of results may be handed `more`.
+    pub fn check_result_bytes(&self, principal: &str, held: u64, more: u64) -> Result<()> {
+        match self.max_result_bytes {
+            Some(max) if held + more > max => Err(quota_exceeded(
+                principal,
+                &format!(
+                    "The job's {} bytes of results would take the tenant past its {} bytes \
+                    ({} held already)",
+                    more, max, held
+                )
+            ))?,
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+
+use serde_json::Value as Json;
+
+use crate::client::ApiClient;
+use crate::err::{Result, SchedulerError, ErrKind};
+use crate::spec::{FileSpec, OpSpec, OutputSpec, WorkloadSpec};
+
+/// How often the REPL asks the scheduler whether a statement is done yet.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+const HELP: &str = "\
+Enter a SQL statement to run it on the cluster. Files are referred to by name, in braces:
+
+    SELECT COUNT(*) FROM {trips}
+
+Commands:
+    \\files add <path> [name]   declares a file (named after the file, unless a name is given)
+    \\files remove <name>       forgets a file
+    \\files                     lists the declared files
+    \\help                      shows this message
+    \\quit                      leaves the REPL";
+
+// The REPL is a way to poke at data on the cluster without writing workload specs. Each
+// statement entered becomes a workload of its own, with a single op, targeting whichever of the
+// session's files the statement mentions. The workload is submitted through the scheduler's
+// HTTP API, like `submit` does, and the REPL waits for it to finish before printing its results
+// as a table. The files are loaded by the first statement that needs them, and kept around on
+// the workers, so later statements over the same files don't wait on them again.
+
+/// The state of a REPL session: the files declared so far.
+pub struct Repl {
+    client: ApiClient,
+    files: Vec<FileSpec>,
+}
+
+/// Names a file after the last part of its path, minus the extension, e.g. `trips` for
+/// `s3://bucket/trips.csv`. Characters that can't go in a name are replaced with underscores.
+fn default_name(path: &str) -> String {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let stem = file_name.split('.').next().unwrap_or(file_name);
+    stem.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// Renders a result set as a text table.
+pub fn render_table(result_set: &Json) -> String {
+    let columns = result_set["columns"].as_array().cloned().unwrap_or_default().iter()
+        .map(|column| column.as_str().unwrap_or("").to_owned())
+        .collect::<Vec<_>>();
+    let rows = result_set["rows"].as_array().cloned().unwrap_or_default().iter()
+        .map(|row| row.as_array().cloned().unwrap_or_default().iter().map(|value| match value {
+            Json::Null => "NULL".to_owned(),
+            Json::String(text) => text.clone(),
+            other => other.to_string(),
+        }).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let mut widths = columns.iter().map(|column| column.chars().count()).collect::<Vec<_>>();
+    for row in rows.iter() {
+        for (i, value) in row.iter().enumerate() {
+            if i < widths.len() {
+                widths[i] = widths[i].max(value.chars().count());
+            }
+        }
+    }
+    let render_row = |values: &[String]| {
+        values.iter().zip(widths.iter())
+            .map(|(value, width)| format!(" {:width$} ", value, width = width))
+            .collect::<Vec<_>>()
+            .join("|")
+    };
+    let separator = widths.iter().map(|width| "-".repeat(width + 2)).collect::<Vec<_>>().join("+");
+
+    let mut table = vec![render_row(&columns), separator];
+    table.extend(rows.iter().map(|row| render_row(row)));
+    let n_rows = rows.len();
+    table.push(format!("({} row{})", n_rows, if n_rows == 1 { "" } else { "s" }));
+    if result_set["truncated"].as_bool().unwrap_or(false) {
+        table.push("(truncated)".to_owned());
+    }
+    table.join("\n")
+}
+
+impl Repl {
+    pub fn new(client: ApiClient) -> Repl {
+        Repl { client, files: vec![] }
+    }
+
+    /// Reads statements and commands off of standard input until it runs out, or until the
+    /// user quits.
+    pub async fn run(&mut self) -> Result<()> {
+        println!("Connected to the scheduler at {}. Enter \\help for help.", self.client.url);
+        let stdin = io::stdin();
+        loop {
+            print!("mini-cluster> ");
+            io::stdout().flush()?;
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                println!();
+                return Ok(());
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "\\quit" || line == "\\q" {
+                return Ok(());
+            }
+            // Our errors aren't `Send`, so they are turned into messages straight away.
+            let outcome = if line.starts_with('\\') {
+                self.command(line).map_err(|err| err.to_string())
+            } else {
+                self.statement(line).await.map_err(|err| err.to_string())
+            };
+            match outcome {
+                Ok(output) => println!("{}", output),
+                Err(message) => println!("Error: {}", message),
+            }
+        }
+    }
+
+    /// Runs a backslash command, returning what to print.
+    fn command(&mut self, line: &str) -> Result<String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["\\help"] | ["\\h"] => Ok(HELP.to_owned()),
+            ["\\files"] | ["\\files", "list"] => {
+                if self.files.is_empty() {
+                    return Ok("No files declared yet (see \\help).".to_owned());
+                }
+                Ok(self.files.iter()
+                    .map(|file| format!("{{{}}}: {}", file.name, file.path))
+                    .collect::<Vec<_>>()
+                    .join("\n"))
+            },
+            ["\\files", "add", path] | ["\\files", "add", path, _] => {
+                let name = match words.get(3) {
+                    Some(name) => name.to_string(),
+                    None => default_name(path),
+                };
+                if self.files.iter().any(|file| file.name == name) {
+                    Err(SchedulerError::new(
+                        ErrKind::InvalidRequest,
+                        &format!("There already is a file named {:?}.", name)
+                    ))?
+                }
+                self.files.push(FileSpec {
+                    name: name.clone(),
+                    path: path.to_string(),
+                    null_tokens: vec![],
+                    requester_pays: false,
+                    sse_customer_key: None,
+                });
+                Ok(format!("Declared {{{}}}.", name))
+            },
+            ["\\files", "remove", name] => {
+                let n_files = self.files.len();
+                self.files.retain(|file| file.name != *name);
+                if self.files.len() == n_files {
+                    Err(SchedulerError::new(
+                        ErrKind::InvalidRequest, &format!("There is no file named {:?}.", name)
+                    ))?
+                }
+                Ok(format!("Forgot {{{}}}.", name))
+            },
+            _ => Err(SchedulerError::new(
+                ErrKind::InvalidRequest,
+                &format!("Unknown command {:?}. Enter \\help for help.", line)
+            ))?,
+        }
+    }
+
+    /// Runs a statement on the cluster, and returns its results, rendered as tables.
+    async fn statement(&self, statement: &str) -> Result<String> {
+        let files = self.files.iter()
+            .filter(|file| statement.contains(&format!("{{{}}}", file.name)))
+            .cloned()
+            .collect::<Vec<_>>();
+        let spec = WorkloadSpec {
+            ops: vec![OpSpec {
+                name: "statement".to_owned(),
+                statement: statement.to_owned(),
+                files: files.iter().map(|file| file.name.clone()).collect(),
+                after: vec![],
+                return_result: true,
+            }],
+            files,
+            output: OutputSpec::default(),
+            priority: 0,
+            ephemeral: false,
+            force_reload: false,
+            dry_run: false,
+            explain: false,
+            after: vec![],
+            notify_url: None,
+            idempotency_key: None,
+            checkpoint: false,
+            engine: None,
+            requires: vec![],
+        };
+        let submitted = self.client.submit(serde_json::to_string(&spec)?).await?;
+        let job_id = submitted["job_id"].as_u64().ok_or_else(|| SchedulerError::new(
+            ErrKind::RemoteError, "The scheduler did not say which job it queued."
+        ))?;
+
+        loop {
+            let status = self.client.status(job_id).await?;
+            match status["state"].as_str().unwrap_or("") {
+                "DONE" => break,
+                "FAILED" => Err(SchedulerError::new(
+                    ErrKind::RemoteError, status["error"].as_str().unwrap_or("Job failed.")
+                ))?,
+                "CANCELLED" => Err(SchedulerError::new(
+                    ErrKind::RemoteError, &format!("Job {} was cancelled.", job_id)
+                ))?,
+                _ => tokio::time::sleep(POLL_INTERVAL).await,
+            }
+        }
+
+        let results = self.client.results(job_id).await?;
+        let tables = results["result_sets"].as_array().cloned().unwrap_or_default().iter()
+            .map(render_table)
+            .collect::<Vec<_>>();
+        Ok(tables.join("\n\n"))
+    }
+}
@@ -1,32 +1,2090 @@
-// use std::fmt;
-// use std::future::Future;
-
-// use crate::worker_proxy::WorkerProxy;
-
-// pub struct Scheduler {
-//     pub port: u16,
-//     pub workers: Vec<WorkerProxy>
-// }
-
-// impl fmt::Display for Scheduler {
-//     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-//         write!(f, "<Scheduler port:{}>", self.port.to_string())
-//     }
-// }
-
-// impl Scheduler {
-//     pub fn new(port: u16) -> Scheduler {
-//         Scheduler { port, workers: vec![] }
-//     }
-
-//     // pub fn scale(nworkers: u16)
-
-//     pub async fn init(&self) {
-//         let processes: Vec<&dyn Future<Output=()>> = vec![];
-//         for worker in &self.workers {
-//             let init_fn: Future<Output=()> = worker.init().await;
-//             processes.push(init_fn);
-//         }
-//         // future::join!(processes);
-//     }
-// }
\ No newline at end of file
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use protobuf::Message;
+use serde_json::{json, Value as Json};
+use tokio::sync::watch;
+use tokio::time::{interval, timeout};
+
+use mini_cluster_worker::auth::{generate_nonce, verify_nonce};
+use mini_cluster_worker::capabilities::{allowed_statements, check_workload};
+use mini_cluster_worker::cluster::{
+    ClusterQuery, DistributedJoin, JobQuery, PartitionedWorkload, Registered, WorkerRegistration,
+};
+use mini_cluster_worker::membership::registration_address;
+use mini_cluster_worker::protocol::{self, read_frame, write_frame};
+use mini_cluster_worker::ratelimit::{RateLimited, RateLimiter};
+use mini_cluster_worker::response::{
+    Ack, CacheReport, ErrorResponse, ErrorResponse_Kind, ResultBatch, TableStats,
+    WorkerCapabilities, WorkerStatus,
+};
+use mini_cluster_worker::result::split_result_batch;
+use mini_cluster_worker::sandbox::validate_workload;
+use mini_cluster_worker::transport::{Address, Listener, Stream};
+use mini_cluster_worker::workload::{CacheCommand, FetchResults, File, Preload, Workload};
+
+use crate::aggregate::expand_aggregation;
+use crate::config::SchedulerConfig;
+use crate::dispatch::DispatchPolicy;
+use crate::err::{Result, SchedulerError, ErrKind};
+use crate::history::{describe_reads, now_millis, EventKind, History, ANONYMOUS};
+use crate::http::describe_job;
+use crate::join::{plan_join, resolve_side, staging_prefix};
+use crate::keys::{ApiKey, ApiKeys, Role};
+use crate::merge::merge;
+use crate::notify::{check_notify_url, notify};
+use crate::partition::{partition, resolve_paths};
+use crate::planner::{plan_query, Plan};
+use crate::pool::ConnectionPool;
+use crate::queue::{JobQueue, JobState};
+use crate::store::Store;
+use crate::topology::{self, StaticWorker};
+
+/// How often the dispatcher looks for workers when no jobs are being submitted. A job that is
+/// submitted whilst there are no live workers waits at least this long once one turns up.
+pub const DISPATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often changes to the jobs and the roster are written to the state database.
+pub const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do about the jobs that haven't finished yet when the scheduler shuts down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Drain {
+    /// Leave them be. A scheduler with a state database picks them back up when it restarts.
+    Leave,
+    /// Wait for them to finish, for up to the scheduler's `drain_timeout`, and then leave the
+    /// rest be. This is the default.
+    Wait,
+    /// Cancel them.
+    Cancel,
+}
+
+impl Drain {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Drain::Leave => "leave",
+            Drain::Wait => "wait",
+            Drain::Cancel => "cancel",
+        }
+    }
+}
+
+impl std::str::FromStr for Drain {
+    type Err = SchedulerError;
+
+    fn from_str(name: &str) -> std::result::Result<Drain, SchedulerError> {
+        match name {
+            "leave" => Ok(Drain::Leave),
+            "wait" => Ok(Drain::Wait),
+            "cancel" => Ok(Drain::Cancel),
+            _ => Err(SchedulerError::new(
+                ErrKind::InvalidRequest,
+                &format!("Unknown drain mode {:?}; expected leave, wait or cancel.", name)
+            )),
+        }
+    }
+}
+
+/// A worker that registered itself with the scheduler.
+#[derive(Debug, Clone)]
+pub struct RegisteredWorker {
+    pub id: u64,
+    pub address: Address,
+    pub capabilities: Vec<String>,
+    /// What the worker says it can do (see `mini_cluster_worker::capabilities`), if it said.
+    /// Workers listed in the cluster file don't until they answer a heartbeat, and neither do
+    /// workers read out of the state database, or ones too old to advertise anything.
+    pub advertised: Option<WorkerCapabilities>,
+    /// How much the worker had cached on disk when it registered, in bytes.
+    pub cache_size: u64,
+    /// The worker's labels, as listed in the cluster file (see `topology`).
+    pub labels: Vec<String>,
+    pub registered_at: Instant,
+    /// Whether or not the worker is answering its heartbeats. Dead workers aren't sent any work,
+    /// but they stay on the roster, and come back to life if they start answering again.
+    pub alive: bool,
+    /// Whether or not the worker is draining (see `Scheduler::drain_worker`), as of its last
+    /// heartbeat. Draining workers finish the jobs they have, but aren't sent new ones.
+    pub draining: bool,
+    /// How many heartbeats in a row the worker has missed.
+    pub missed_beats: u32,
+    /// When the worker last answered a heartbeat (or registered, if it hasn't had one yet).
+    pub last_beat: Instant,
+    /// The worker's job queue depth and running job count, as of its last heartbeat.
+    pub queue_depth: u32,
+    pub running_jobs: u32,
+    /// The statistics of the tables in the worker's database, as of its last heartbeat. These
+    /// say which files the worker has loaded already, and how big they are (see `dispatch`).
+    pub tables: Vec<TableStats>,
+    /// The (scheduler) IDs of the jobs that were sent to the worker and haven't finished yet.
+    /// If the worker dies, these are handed back to the scheduler to run somewhere else.
+    pub in_flight: Vec<u64>,
+}
+
+impl RegisteredWorker {
+    /// Checks that the worker can run a workload, going by what it advertised, and says why not
+    /// if it can't. Workers that haven't advertised anything are given the benefit of the doubt.
+    pub fn can_run(&self, workload: &Workload) -> std::result::Result<(), String> {
+        let advertised = match &self.advertised {
+            Some(advertised) => advertised,
+            None => return Ok(()),
+        };
+        check_workload(workload, advertised).map_err(|err| err.to_string())?;
+        validate_workload(workload, allowed_statements(advertised).as_deref())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// The workers the scheduler knows about. Workers join it by registering themselves (see
+/// `mini_cluster_worker::membership`), so there is no need to hand the scheduler a list of them
+/// up front.
+pub struct Roster {
+    // As in the worker's job queue, the lock is never held across an `.await`.
+    workers: Mutex<HashMap<u64, RegisteredWorker>>,
+    next_id: AtomicU64,
+    // Whether or not workers joined or left since the roster was last written to the state
+    // database. Liveness isn't written, so heartbeats don't count.
+    changed: AtomicBool,
+}
+
+impl Roster {
+    pub fn new() -> Roster {
+        Roster {
+            workers: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    /// Puts back the workers read out of the state database (see `store`) when the scheduler
+    /// starts, under the IDs they had.
+    pub fn restore(&self, restored: Vec<RegisteredWorker>) {
+        let mut workers = self.workers.lock().unwrap();
+        for worker in restored {
+            self.next_id.fetch_max(worker.id + 1, Ordering::SeqCst);
+            workers.insert(worker.id, worker);
+        }
+    }
+
+    /// Puts the workers listed in the cluster file (see `topology`) on the roster. A listed
+    /// worker that is on the roster already (e.g. restored out of the state database) just gets
+    /// its labels; the others are added as dead, until they answer a heartbeat.
+    pub fn add_static(&self, listed: Vec<StaticWorker>) {
+        let mut workers = self.workers.lock().unwrap();
+        for static_worker in listed {
+            let address = static_worker.address();
+            if let Some(worker) = workers.values_mut().find(|worker| worker.address == address) {
+                worker.labels = static_worker.labels;
+                continue;
+            }
+            let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            workers.insert(id, RegisteredWorker {
+                id,
+                address,
+                capabilities: vec![],
+                advertised: None,
+                cache_size: 0,
+                labels: static_worker.labels,
+                registered_at: Instant::now(),
+                alive: false,
+                draining: false,
+                missed_beats: 0,
+                last_beat: Instant::now(),
+                queue_depth: 0,
+                running_jobs: 0,
+                tables: vec![],
+                in_flight: vec![],
+            });
+            self.mark_changed();
+        }
+    }
+
+    /// Returns whether or not workers joined or left since the last call.
+    pub fn take_changed(&self) -> bool {
+        self.changed.swap(false, Ordering::SeqCst)
+    }
+
+    /// Marks the roster as changed, e.g. because writing it to the state database failed, so
+    /// that it is written again next time.
+    pub fn mark_changed(&self) {
+        self.changed.store(true, Ordering::SeqCst);
+    }
+
+    /// Adds a worker to the roster, returning its worker ID. A worker that registers from the
+    /// address of one already on the roster (e.g. because it restarted) replaces it, under a new
+    /// ID.
+    pub fn register(&self, registration: &WorkerRegistration) -> Result<u64> {
+        let address = registration_address(registration)?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut workers = self.workers.lock().unwrap();
+        let mut labels = vec![];
+        workers.retain(|_, worker| {
+            if worker.address != address {
+                return true;
+            }
+            labels = std::mem::take(&mut worker.labels);
+            false
+        });
+        workers.insert(id, RegisteredWorker {
+            id,
+            address,
+            capabilities: registration.get_capabilities().to_vec(),
+            advertised: if registration.has_advertised() {
+                Some(registration.get_advertised().clone())
+            } else {
+                None
+            },
+            cache_size: registration.get_cache_size(),
+            labels,
+            registered_at: Instant::now(),
+            alive: true,
+            draining: false,
+            missed_beats: 0,
+            last_beat: Instant::now(),
+            queue_depth: 0,
+            running_jobs: 0,
+            tables: vec![],
+            in_flight: vec![],
+        });
+        self.mark_changed();
+        Ok(id)
+    }
+
+    /// Records a heartbeat the worker answered, bringing it back to life if it was dead.
+    pub fn record_beat(&self, id: u64, status: &WorkerStatus) {
+        if let Some(worker) = self.workers.lock().unwrap().get_mut(&id) {
+            if !worker.alive {
+                println!("Worker {} is answering heartbeats again.", id);
+            }
+            worker.alive = true;
+            worker.missed_beats = 0;
+            worker.last_beat = Instant::now();
+            worker.queue_depth = status.get_queue_depth();
+            worker.running_jobs = status.get_running_jobs();
+            worker.tables = status.get_tables().to_vec();
+            worker.draining = status.get_draining();
+            // Workers listed in the cluster file never register, so this is how we find out.
+            if worker.capabilities.is_empty() {
+                worker.capabilities = status.get_capabilities().to_vec();
+            }
+            if status.has_advertised() {
+                worker.advertised = Some(status.get_advertised().clone());
+            }
+        }
+    }
+
+    /// Marks a worker as draining, without waiting for its next heartbeat to say so.
+    pub fn mark_draining(&self, id: u64) {
+        if let Some(worker) = self.workers.lock().unwrap().get_mut(&id) {
+            worker.draining = true;
+        }
+    }
+
+    /// Records a heartbeat the worker missed. If that makes `max_missed_beats` in a row, the
+    /// worker is marked dead, and the jobs it had in flight are returned so that they can be
+    /// re-queued. Otherwise (or if the worker was already dead) this returns `None`.
+    pub fn record_missed_beat(&self, id: u64, max_missed_beats: u32) -> Option<Vec<u64>> {
+        let mut workers = self.workers.lock().unwrap();
+        let worker = workers.get_mut(&id)?;
+        worker.missed_beats += 1;
+        if !worker.alive || worker.missed_beats < max_missed_beats {
+            return None;
+        }
+        worker.alive = false;
+        Some(worker.in_flight.drain(..).collect())
+    }
+
+    /// Marks a worker dead straight away, e.g. because its process is known to have exited,
+    /// and returns the jobs it had in flight so that they can be re-queued. Returns `None` if
+    /// the worker was already dead.
+    pub fn mark_dead(&self, id: u64) -> Option<Vec<u64>> {
+        let mut workers = self.workers.lock().unwrap();
+        let worker = workers.get_mut(&id)?;
+        if !worker.alive {
+            return None;
+        }
+        worker.alive = false;
+        Some(worker.in_flight.drain(..).collect())
+    }
+
+    /// Finds the worker on the roster at the given address.
+    pub fn find(&self, address: &Address) -> Option<RegisteredWorker> {
+        self.workers.lock().unwrap().values().find(|worker| &worker.address == address).cloned()
+    }
+
+    /// Records that a job was sent to the worker.
+    pub fn assign(&self, id: u64, job_id: u64) {
+        if let Some(worker) = self.workers.lock().unwrap().get_mut(&id) {
+            worker.in_flight.push(job_id);
+        }
+    }
+
+    /// Records that a job the worker was sent has finished (one way or another).
+    pub fn release(&self, id: u64, job_id: u64) {
+        if let Some(worker) = self.workers.lock().unwrap().get_mut(&id) {
+            worker.in_flight.retain(|&in_flight| in_flight != job_id);
+        }
+    }
+
+    /// Removes a worker from the roster. Returns whether or not it was on it.
+    pub fn deregister(&self, id: u64) -> bool {
+        let removed = self.workers.lock().unwrap().remove(&id).is_some();
+        if removed {
+            self.mark_changed();
+        }
+        removed
+    }
+
+    pub fn get(&self, id: u64) -> Option<RegisteredWorker> {
+        self.workers.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Returns every worker on the roster, ordered by worker ID.
+    pub fn workers(&self) -> Vec<RegisteredWorker> {
+        let mut workers = self.workers.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        workers.sort_by_key(|worker| worker.id);
+        workers
+    }
+
+    /// Returns the workers that are answering their heartbeats, ordered by worker ID.
+    pub fn live_workers(&self) -> Vec<RegisteredWorker> {
+        self.workers().into_iter().filter(|worker| worker.alive).collect()
+    }
+
+    /// Returns the live workers that aren't draining, e.g. the ones new work may be sent to,
+    /// ordered by worker ID.
+    pub fn dispatchable_workers(&self) -> Vec<RegisteredWorker> {
+        self.live_workers().into_iter().filter(|worker| !worker.draining).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.lock().unwrap().len()
+    }
+}
+
+pub struct Scheduler {
+    pub config: SchedulerConfig,
+    pub listener: Listener,
+    pub roster: Roster,
+    pub jobs: JobQueue,
+    /// Everything that happened to every job, and who submitted it.
+    pub history: History,
+    pub policy: Box<dyn DispatchPolicy>,
+    /// The files broadcast to the workers with PRELOAD so far. Workers that register later on
+    /// are sent them too, as soon as they do.
+    pub preloaded: Mutex<Vec<File>>,
+    /// The state database, unless the scheduler keeps its state in memory only.
+    pub store: Option<Store>,
+    /// Connections to the workers, kept open between requests (see `pool`).
+    pub pool: ConnectionPool,
+    /// The keys clients of the HTTP API may authenticate with (see `keys`).
+    pub api_keys: ApiKeys,
+    // How fast each tenant is submitting jobs, if there's a limit to it (see `quota`).
+    submissions: Option<RateLimiter>,
+    // Flipped to `false` as soon as the scheduler is asked to shut down, after which no more
+    // jobs are accepted.
+    accepting: AtomicBool,
+    // Flipped to `true` when the scheduler is done shutting down (see `shutdown`).
+    shutdown_tx: watch::Sender<bool>,
+    shutdown_rx: watch::Receiver<bool>,
+}
+
+impl fmt::Display for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<Scheduler {}>", self.config.address)
+    }
+}
+
+/// Whether or not an error was reported by the worker itself (e.g. the workload failed), as
+/// opposed to being a problem reaching the worker.
+fn is_remote(err: &(dyn Error + 'static)) -> bool {
+    remote_kind(err).is_some()
+}
+
+/// The kind of error the worker reported, if the error was reported by the worker.
+fn remote_kind(err: &(dyn Error + 'static)) -> Option<ErrorResponse_Kind> {
+    match err.downcast_ref::<SchedulerError>() {
+        Some(SchedulerError::RemoteError(kind, _)) => Some(*kind),
+        _ => None,
+    }
+}
+
+/// Whether or not an error leaves it unknown if the worker got the workload it was sent (see
+/// `WorkerProxy::send_workload`).
+fn is_unacknowledged(err: &(dyn Error + 'static)) -> bool {
+    match err.downcast_ref::<SchedulerError>() {
+        Some(SchedulerError::Unacknowledged(_)) => true,
+        _ => false,
+    }
+}
+
+/// The ERROR frame to answer a submission that was turned away with.
+fn submission_error(err: &(dyn Error + 'static)) -> ErrorResponse {
+    if let Some(limited) = err.downcast_ref::<RateLimited>() {
+        return limited.craft_error();
+    }
+    let kind = match err.downcast_ref::<SchedulerError>() {
+        Some(SchedulerError::QuotaExceeded(_)) => ErrorResponse_Kind::QUOTA_EXCEEDED,
+        _ => ErrorResponse_Kind::VALIDATION,
+    };
+    craft_error(kind, &err.to_string())
+}
+
+/// Who a workload submitted over the framed protocol says it is submitted by, if anyone (see
+/// `Workload.principal`).
+fn framed_principal(workload: &Workload) -> &str {
+    match workload.get_principal() {
+        "" => ANONYMOUS,
+        principal => principal,
+    }
+}
+
+/// Who the client on the other end of a framed connection is, and so what it may do (see
+/// `keys`). Signals are held to the same roles as the HTTP API's endpoints are.
+struct FramedClient {
+    /// The API key the client showed in an AUTH frame, if it showed one.
+    key: Option<ApiKey>,
+    /// Whether or not the client may do anything without a key, as whoever its workloads say it
+    /// is: it authenticated with the secret, or the scheduler neither has one nor requires API
+    /// keys.
+    trusted: bool,
+}
+
+impl FramedClient {
+    fn role(&self) -> Option<Role> {
+        match &self.key {
+            Some(key) => Some(key.role),
+            None if self.trusted => Some(Role::Admin),
+            None => None,
+        }
+    }
+
+    /// Who a workload the client submits is put down to: the holder of its key, if it showed
+    /// one, and whoever the workload says otherwise.
+    fn principal<'a>(&'a self, workload: &'a Workload) -> &'a str {
+        match &self.key {
+            Some(key) => &key.principal,
+            None => framed_principal(workload),
+        }
+    }
+
+    /// Checks that the client may send a frame with the given signal, returning the ERROR frame
+    /// to answer it with if it may not.
+    fn authorize(&self, signal: u8) -> std::result::Result<(), ErrorResponse> {
+        let (name, required) = match signal {
+            protocol::WORK => ("WORK", Role::Submit),
+            protocol::PARTITION => ("PARTITION", Role::Submit),
+            protocol::JOIN => ("JOIN", Role::Submit),
+            protocol::QUERY => ("QUERY", Role::Submit),
+            protocol::FETCH => ("FETCH", Role::Submit),
+            protocol::PRELOAD => ("PRELOAD", Role::Admin),
+            _ => return Ok(()),
+        };
+        match self.role() {
+            Some(role) if role.allows(required) => Ok(()),
+            _ => Err(craft_error(
+                ErrorResponse_Kind::UNAUTHORIZED,
+                &format!("{} takes an API key with the {} role.", name, required.name())
+            )),
+        }
+    }
+
+    /// Whether or not the client may fetch the results of a job `owner` submitted. Only admins
+    /// may fetch other tenants' results.
+    fn may_fetch(&self, owner: &str) -> bool {
+        match &self.key {
+            Some(key) => key.role == Role::Admin || key.principal == owner,
+            None => self.trusted,
+        }
+    }
+}
+
+fn craft_error(kind: ErrorResponse_Kind, message: &str) -> ErrorResponse {
+    let mut error = ErrorResponse::new();
+    error.set_kind(kind);
+    error.set_message(message.to_owned());
+    error.set_op_index(-1);
+    error
+}
+
+impl Scheduler {
+    /// Creates a scheduler listening on the configured address. If the scheduler has a state
+    /// database, the jobs and the roster it holds are put back; see `recover` for picking up
+    /// the jobs that were out on workers.
+    pub async fn new(config: SchedulerConfig) -> Result<Scheduler> {
+        let listener = Listener::bind(&config.address).await?;
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let roster = Roster::new();
+        let jobs = JobQueue::new();
+        let history = History::new();
+        let api_keys = ApiKeys::new();
+        let store = match &config.state_path {
+            Some(path) => {
+                let store = Store::open(path).await?;
+                let workers = store.load_workers().await?;
+                let restored = store.load_jobs().await?;
+                if !workers.is_empty() || !restored.is_empty() {
+                    println!(
+                        "Restored {} job(s) and {} worker(s) from {}.",
+                        restored.len(), workers.len(), path.display()
+                    );
+                }
+                roster.restore(workers);
+                jobs.restore(restored);
+                history.restore(store.load_events().await?);
+                jobs.restore_keys(|job_id| history.principal(job_id));
+                api_keys.restore(store.load_api_keys().await?);
+                Some(store)
+            },
+            None => None,
+        };
+        if let Some(path) = &config.cluster_file {
+            let listed = topology::load(path)?;
+            println!("Read {} worker(s) from {}.", listed.len(), path.display());
+            roster.add_static(listed);
+        }
+        let submissions = config.tenant_quota.submit_rate.map(RateLimiter::new);
+        // The certificates are read now, so that a bad one stops the scheduler from starting
+        // rather than every connection to a worker from going through.
+        let tls = config.tls.as_ref().map(|tls| tls.connector()).transpose()?;
+        let pool = ConnectionPool::new(config.secret.clone(), tls);
+        Ok(Scheduler {
+            policy: config.dispatch_policy.build(),
+            config,
+            listener,
+            roster,
+            jobs,
+            history,
+            preloaded: Mutex::new(vec![]),
+            store,
+            pool,
+            api_keys,
+            submissions,
+            accepting: AtomicBool::new(true),
+            shutdown_tx,
+            shutdown_rx,
+        })
+    }
+
+    /// Picks back up the jobs that were out on workers when the scheduler last went down (see
+    /// `resume_job`), and merges the partitioned jobs whose merges never finished.
+    pub fn recover(self: Arc<Self>) {
+        for (job_id, worker_id, worker_job_id) in self.jobs.in_flight() {
+            match self.roster.get(worker_id) {
+                Some(worker) => {
+                    self.roster.assign(worker_id, job_id);
+                    tokio::spawn(Arc::clone(&self).resume_job(worker, job_id, worker_job_id));
+                },
+                None => self.retry(job_id, worker_id, "Its worker is no longer on the roster."),
+            }
+        }
+        for parent in self.jobs.pending_merges() {
+            let scheduler = Arc::clone(&self);
+            tokio::spawn(async move { scheduler.merge_partitions(parent).await });
+        }
+    }
+
+    /// Writes changes to the jobs and the roster through to the state database, every
+    /// `PERSIST_INTERVAL`, for as long as the scheduler runs (see `store`). Does nothing if the
+    /// scheduler has no state database.
+    pub async fn persist(self: Arc<Self>) {
+        if self.store.is_none() {
+            return;
+        }
+        let mut ticks = interval(PERSIST_INTERVAL);
+        loop {
+            ticks.tick().await;
+            self.flush().await;
+        }
+    }
+
+    /// Writes whatever changed since the last flush to the state database. Whatever fails to
+    /// be written is tried again by the next flush.
+    pub async fn flush(&self) {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return,
+        };
+        let jobs = self.jobs.take_dirty();
+        if !jobs.is_empty() {
+            if let Err(message) = store.save_jobs(&jobs).await.map_err(|err| err.to_string()) {
+                println!("Failed to save {} job(s): {}", jobs.len(), message);
+                self.jobs.mark_dirty(jobs.iter().map(|job| job.id));
+            }
+        }
+        let events = self.history.take_unsaved();
+        if !events.is_empty() {
+            if let Err(message) = store.save_events(&events).await.map_err(|err| err.to_string()) {
+                println!("Failed to save {} job history event(s): {}", events.len(), message);
+                self.history.mark_unsaved(events);
+            }
+        }
+        if self.roster.take_changed() {
+            let workers = self.roster.workers();
+            let outcome = store.save_workers(&workers).await.map_err(|err| err.to_string());
+            if let Err(message) = outcome {
+                println!("Failed to save the roster: {}", message);
+                self.roster.mark_changed();
+            }
+        }
+    }
+
+    /// Shuts the scheduler down. From here on, no more jobs are accepted. The jobs that haven't
+    /// finished yet are then left be, waited for, or cancelled, as `drain` says. If `all` is
+    /// set, every live worker on the roster is then told to exit, and each has to confirm that
+    /// it will (see `WorkerProxy::stop`); dead ones are skipped, as there's no reaching them.
+    /// Returns the workers that could not be stopped, and why.
+    /// It's up to whoever is waiting on `stopped` (e.g. the scheduler binary) to actually stop.
+    pub async fn shutdown(&self, all: bool, drain: Drain) -> Vec<(u64, String)> {
+        self.accepting.store(false, Ordering::SeqCst);
+        let unfinished = self.jobs.unfinished();
+        match drain {
+            Drain::Leave => {},
+            Drain::Wait => {
+                println!(
+                    "Waiting for {} unfinished job(s) before shutting down.", unfinished.len()
+                );
+                let waits = join_all(unfinished.iter().map(|id| self.jobs.wait(*id)));
+                if timeout(self.config.drain_timeout, waits).await.is_err() {
+                    println!(
+                        "Gave up waiting for {} unfinished job(s) after {:?}.",
+                        self.jobs.unfinished().len(), self.config.drain_timeout
+                    );
+                }
+            },
+            Drain::Cancel => {
+                println!(
+                    "Cancelling {} unfinished job(s) before shutting down.", unfinished.len()
+                );
+                join_all(unfinished.iter().map(|id| self.cancel(*id))).await;
+            },
+        }
+        let mut failures = vec![];
+        if all {
+            let stops = self.roster.live_workers().into_iter().map(|worker| async move {
+                let outcome = self.stop_worker(&worker.address).await
+                    .map_err(|err| err.to_string());
+                (worker.id, outcome)
+            });
+            for (worker_id, outcome) in join_all(stops).await {
+                match outcome {
+                    Ok(()) => {
+                        println!("Stopped worker {}.", worker_id);
+                        self.roster.deregister(worker_id);
+                    },
+                    Err(message) => failures.push((worker_id, message)),
+                }
+            }
+        }
+        println!("Shutting down.");
+        // This can only fail if there are no receivers, but we hold one ourselves.
+        let _ = self.shutdown_tx.send(true);
+        failures
+    }
+
+    /// Waits until the scheduler is done shutting down.
+    pub async fn stopped(&self) {
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        loop {
+            if *shutdown_rx.borrow() {
+                return;
+            }
+            if shutdown_rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Has a single worker exit, over a connection of its own.
+    async fn stop_worker(&self, address: &Address) -> Result<()> {
+        // A stopping worker takes no new work, so its pooled connections are no more use.
+        self.pool.forget(address);
+        let mut proxy = self.pool.connect(address).await?;
+        proxy.stop().await
+    }
+
+    /// Has a worker drain, so that it can be restarted without losing any work: it finishes
+    /// the jobs it has, and turns away new ones, which the scheduler stops sending it. Returns
+    /// `false` if there is no such worker.
+    pub async fn drain_worker(&self, id: u64) -> Result<bool> {
+        let worker = match self.roster.get(id) {
+            Some(worker) => worker,
+            None => return Ok(false),
+        };
+        let mut proxy = self.pool.checkout(&worker.address).await?;
+        proxy.drain().await?;
+        self.pool.checkin(proxy);
+        self.roster.mark_draining(id);
+        println!("Worker {} is draining.", id);
+        Ok(true)
+    }
+
+    /// Has a worker reload its configuration (see the worker's `Worker::reload`), returning the
+    /// names of the settings that changed, or `None` if there is no such worker.
+    pub async fn reload_worker(&self, id: u64) -> Result<Option<Vec<String>>> {
+        let worker = match self.roster.get(id) {
+            Some(worker) => worker,
+            None => return Ok(None),
+        };
+        let mut proxy = self.pool.checkout(&worker.address).await?;
+        let changed = proxy.reload().await?;
+        self.pool.checkin(proxy);
+        println!("Worker {} reloaded its configuration.", id);
+        Ok(Some(changed))
+    }
+
+    /// Has a worker list, evict, or clear what it has cached (see the worker's `eviction`),
+    /// without restarting it. Returns `None` if there is no such worker.
+    pub async fn worker_cache(
+        &self, id: u64, command: &CacheCommand
+    ) -> Result<Option<CacheReport>> {
+        let worker = match self.roster.get(id) {
+            Some(worker) => worker,
+            None => return Ok(None),
+        };
+        let mut proxy = self.pool.checkout(&worker.address).await?;
+        let report = proxy.cache(command).await?;
+        self.pool.checkin(proxy);
+        Ok(Some(report))
+    }
+
+    /// Creates an API key made out to `principal` (see `keys`), writing it to the state
+    /// database, if there is one. Returns the key along with the key itself, which is the only
+    /// time it's ever seen.
+    pub async fn create_api_key(&self, principal: &str, role: Role) -> Result<(ApiKey, String)> {
+        let (key, token) = self.api_keys.create(principal, role)?;
+        if let Some(store) = &self.store {
+            if let Err(err) = store.save_api_key(&key).await {
+                self.api_keys.revoke(&key.id);
+                return Err(err);
+            }
+        }
+        println!("Created {} key {} for {}.", role.name(), key.id, principal);
+        Ok((key, token))
+    }
+
+    /// Revokes the API key with the given ID, returning it, or `None` if there is no such key.
+    pub async fn revoke_api_key(&self, id: &str) -> Result<Option<ApiKey>> {
+        let key = match self.api_keys.revoke(id) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        if let Some(store) = &self.store {
+            store.delete_api_key(id).await?;
+        }
+        println!("Revoked key {} of {}.", key.id, key.principal);
+        Ok(Some(key))
+    }
+
+    /// Has every live worker load the given files into its database ahead of time, e.g. a
+    /// small dimension table that the partitions of a partitioned job all join against. Without
+    /// this, every worker would download the table when its first partition needed it, all at
+    /// the same time. Returns the workers that failed to load the files, and why.
+    pub async fn broadcast_preload(&self, files: Vec<File>) -> Vec<(u64, String)> {
+        {
+            let mut preloaded = self.preloaded.lock().unwrap();
+            for file in files.iter() {
+                preloaded.retain(|other| other.get_id() != file.get_id());
+                preloaded.push(file.clone());
+            }
+        }
+        let preloads = self.roster.live_workers().into_iter().map(|worker| {
+            let files = &files;
+            async move {
+                let outcome = self.preload(&worker.address, files).await
+                    .map_err(|err| err.to_string());
+                (worker.id, outcome)
+            }
+        });
+        join_all(preloads).await.into_iter()
+            .filter_map(|(worker_id, outcome)| outcome.err().map(|message| (worker_id, message)))
+            .collect()
+    }
+
+    /// Has a single worker load files into its database.
+    async fn preload(&self, address: &Address, files: &[File]) -> Result<()> {
+        let mut proxy = self.pool.checkout(address).await?;
+        proxy.preload(files).await?;
+        self.pool.checkin(proxy);
+        Ok(())
+    }
+
+    /// Hands the jobs of a dead worker back to the job queue, to be dispatched again. Jobs
+    /// that have already been sent out `max_attempts` times are failed instead: a job whose
+    /// workers keep dying on it is likely the reason they do.
+    pub fn requeue(&self, worker_id: u64, job_ids: Vec<u64>, reason: &str) {
+        for job_id in job_ids {
+            let attempts = self.jobs.attempts(job_id);
+            if attempts < self.config.max_attempts {
+                self.retry(job_id, worker_id, reason);
+                continue;
+            }
+            let message = format!("{} Gave up after {} attempts.", reason, attempts);
+            if self.jobs.update(job_id, worker_id, JobState::Failed(message.clone())) {
+                println!("Job {} failed, its workers died on it {} times.", job_id, attempts);
+                self.log(job_id, EventKind::Failed, Some(worker_id), &message);
+            }
+        }
+    }
+
+    /// Marks the worker at the given address dead at once, rather than after it misses its
+    /// heartbeats, and re-queues its in-flight jobs. Used when the worker is known to be gone,
+    /// e.g. because it was a local worker whose process exited (see `local`).
+    pub fn worker_lost(&self, address: &Address, reason: &str) {
+        let worker = match self.roster.find(address) {
+            Some(worker) => worker,
+            None => return,
+        };
+        self.pool.forget(address);
+        if let Some(orphans) = self.roster.mark_dead(worker.id) {
+            println!(
+                "Worker {} is gone ({}), re-queueing its {} in-flight job(s).",
+                worker.id, reason, orphans.len()
+            );
+            self.requeue(worker.id, orphans, &format!("Its worker is gone ({}).", reason));
+        }
+    }
+
+    /// Hands a job that is out on the given worker back to the job queue (see
+    /// `JobQueue::requeue`), and records why in the job history.
+    fn retry(&self, job_id: u64, worker_id: u64, reason: &str) {
+        if self.jobs.requeue(job_id, worker_id) {
+            self.log(job_id, EventKind::Retried, Some(worker_id), reason);
+        }
+    }
+
+    /// Records something that happened to a job in the job history. The partitions of a
+    /// partitioned job are put down to whoever submitted the job.
+    fn log(&self, job_id: u64, kind: EventKind, worker_id: Option<u64>, detail: &str) {
+        let owner = self.jobs.parent(job_id).unwrap_or(job_id);
+        self.history.record(job_id, owner, kind, worker_id, detail);
+    }
+
+    /// Queues a workload, returning its job ID, and records who submitted it (see `history`).
+    /// A workload the principal already submitted (see `Workload.idempotency_key`) isn't queued
+    /// again; the ID of the job it was first submitted as is returned instead.
+    pub fn submit(&self, workload: Workload, principal: &str) -> Result<u64> {
+        self.check_accepting()?;
+        // Checked ahead of the quotas, which a workload that is already in doesn't count
+        // against again.
+        if let Some(job_id) = self.resubmitted(&workload, principal) {
+            return Ok(job_id);
+        }
+        self.check_submit_rate(principal)?;
+        self.check_queue_quota(principal)?;
+        self.check_workload(&workload)?;
+        let reads = describe_reads(&workload);
+        let (job_id, queued) = self.jobs.submit(workload, principal);
+        if queued {
+            self.history.submitted(job_id, principal, &reads);
+            println!("Job {} queued.", job_id);
+        }
+        Ok(job_id)
+    }
+
+    /// The job a workload was already submitted as by the principal, under the workload's
+    /// idempotency key, if it was.
+    fn resubmitted(&self, workload: &Workload, principal: &str) -> Option<u64> {
+        let key = workload.get_idempotency_key();
+        if key.is_empty() {
+            return None;
+        }
+        let job_id = self.jobs.keyed_job(principal, key)?;
+        println!("Idempotency key {:?} was already submitted as job {}.", key, job_id);
+        Some(job_id)
+    }
+
+    /// Checks that the scheduler isn't shutting down.
+    fn check_accepting(&self) -> Result<()> {
+        if !self.accepting.load(Ordering::SeqCst) {
+            Err(SchedulerError::new(
+                ErrKind::InvalidRequest, "The scheduler is shutting down, and takes no more jobs."
+            ))?
+        }
+        Ok(())
+    }
+
+    /// How many jobs each tenant has out on workers, and how many it has queued (see `quota`).
+    fn tenant_load(&self) -> HashMap<String, (usize, usize)> {
+        let mut load = HashMap::new();
+        for (owner, (running, queued)) in self.jobs.load_by_owner() {
+            let tenant = load.entry(self.history.principal(owner)).or_insert((0, 0));
+            tenant.0 += running;
+            tenant.1 += queued as usize;
+        }
+        load
+    }
+
+    /// Checks that a tenant isn't submitting jobs faster than it may. Each submission that gets
+    /// this far counts, whether or not it's queued in the end.
+    fn check_submit_rate(&self, principal: &str) -> Result<()> {
+        match &self.submissions {
+            Some(limiter) => Ok(limiter.take(principal)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that a tenant may queue another job.
+    fn check_queue_quota(&self, principal: &str) -> Result<()> {
+        let quota = &self.config.tenant_quota;
+        if quota.max_queued.is_none() {
+            return Ok(());
+        }
+        let queued = self.tenant_load().get(principal).map_or(0, |load| load.1);
+        quota.check_queued(principal, queued)
+    }
+
+    /// Checks that the results of a job that just finished fit within its tenant's quota.
+    fn check_result_quota(
+        &self, job_id: u64, results: &[ResultBatch]
+    ) -> std::result::Result<(), String> {
+        let quota = &self.config.tenant_quota;
+        if quota.max_result_bytes.is_none() {
+            return Ok(());
+        }
+        let principal = self.history.principal(self.jobs.parent(job_id).unwrap_or(job_id));
+        let held = self.jobs.result_bytes_by_owner().into_iter()
+            .filter(|(owner, _)| self.history.principal(*owner) == principal)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        let more = results.iter().map(|batch| batch.compute_size() as u64).sum();
+        quota.check_result_bytes(&principal, held, more).map_err(|err| err.to_string())
+    }
+
+    /// Checks the settings of a workload that are for the scheduler, rather than the workers:
+    /// that the jobs it waits on (see `Workload.after_jobs`) exist, and that its notify URL (if
+    /// it has one) is one the scheduler can POST to.
+    fn check_workload(&self, workload: &Workload) -> Result<()> {
+        self.check_capabilities(workload)?;
+        if !workload.get_notify_url().is_empty() {
+            check_notify_url(workload.get_notify_url())?;
+        }
+        for job_id in workload.get_after_jobs() {
+            if self.jobs.state(*job_id).is_none() {
+                Err(SchedulerError::new(
+                    ErrKind::InvalidRequest,
+                    &format!("The workload runs after job {}, which doesn't exist.", job_id)
+                ))?
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that at least one of the live workers can run a workload, going by what they
+    /// advertised (see `RegisteredWorker::can_run`). With no live workers to go by, any workload
+    /// passes, and waits in the queue for one that can run it to turn up.
+    fn check_capabilities(&self, workload: &Workload) -> Result<()> {
+        let mut reasons = vec![];
+        for worker in self.roster.live_workers() {
+            match worker.can_run(workload) {
+                Ok(()) => return Ok(()),
+                Err(reason) => reasons.push(format!("worker {}: {}", worker.id, reason)),
+            }
+        }
+        if !reasons.is_empty() {
+            Err(SchedulerError::new(
+                ErrKind::InvalidRequest,
+                &format!("None of the workers can run the workload ({}).", reasons.join("; "))
+            ))?
+        }
+        Ok(())
+    }
+
+    /// Splits a partitioned workload into one job per partition, and queues them, returning the
+    /// ID of the parent job. Unless the client asked for a particular number of partitions,
+    /// there is one per live worker, so that each worker gets one. Like `submit`, this records
+    /// who submitted the job.
+    pub async fn submit_partitioned(
+        &self, partitioned: &PartitionedWorkload, principal: &str
+    ) -> Result<u64> {
+        self.check_accepting()?;
+        if let Some(job_id) = self.resubmitted(partitioned.get_options(), principal) {
+            return Ok(job_id);
+        }
+        self.check_submit_rate(principal)?;
+        self.check_queue_quota(principal)?;
+        self.check_workload(partitioned.get_options())?;
+        let partitioned = &expand_aggregation(partitioned)?;
+        let paths = resolve_paths(partitioned).await?;
+        let n_partitions = match partitioned.get_n_partitions() {
+            0 => self.roster.dispatchable_workers().len(),
+            n => n as usize,
+        };
+        let workloads = partition(partitioned, &paths, n_partitions)?;
+        let (job_id, queued) = self.jobs.submit_partitioned(
+            partitioned.get_options().clone(), workloads, partitioned.get_merge_statement(),
+            principal
+        );
+        if !queued {
+            return Ok(job_id);
+        }
+        let reads = if partitioned.get_prefix().is_empty() {
+            format!("reads {}", paths.join(", "))
+        } else {
+            format!("reads the {} file(s) under {}", paths.len(), partitioned.get_prefix())
+        };
+        self.history.submitted(job_id, principal, &reads);
+        Ok(job_id)
+    }
+
+    /// Queues both stages of a distributed join (see `join`), returning the ID of the parent job
+    /// of the join's partitions, whose results are the join's. Like `submit_partitioned`, there
+    /// is one partition per live worker unless the client asked for a particular number.
+    pub async fn submit_join(&self, join: &DistributedJoin, principal: &str) -> Result<u64> {
+        self.check_accepting()?;
+        if let Some(job_id) = self.resubmitted(join.get_options(), principal) {
+            return Ok(job_id);
+        }
+        self.check_submit_rate(principal)?;
+        self.check_queue_quota(principal)?;
+        self.check_workload(join.get_options())?;
+        let left = resolve_side(join.get_left()).await?;
+        let right = resolve_side(join.get_right()).await?;
+        let n_partitions = match join.get_n_partitions() {
+            0 => self.roster.dispatchable_workers().len(),
+            n => n as usize,
+        };
+        let prefix = staging_prefix(join);
+        let stages = plan_join(join, &left, &right, n_partitions, &prefix)?;
+
+        // The exchanges are queued under a job of their own, with no idempotency key, as it's
+        // the join's job that the key stands for.
+        let mut options = join.get_options().clone();
+        options.clear_idempotency_key();
+        options.clear_notify_url();
+        let (exchange_id, _) = self.jobs.submit_partitioned(
+            options, stages.exchanges, "", principal
+        );
+        let mut options = join.get_options().clone();
+        options.mut_after_jobs().push(exchange_id);
+        let partitions = stages.partitions.into_iter().map(|mut workload| {
+            workload.mut_after_jobs().push(exchange_id);
+            workload
+        }).collect();
+        let (job_id, queued) = self.jobs.submit_partitioned(
+            options, partitions, join.get_merge_statement(), principal
+        );
+        if !queued {
+            // The same join got in first, whilst we were resolving its files.
+            self.cancel(exchange_id).await;
+            return Ok(job_id);
+        }
+        self.history.submitted(exchange_id, principal, &format!(
+            "exchanges {} file(s) for job {}, staging them under {}",
+            left.len() + right.len(), job_id, prefix
+        ));
+        self.history.submitted(job_id, principal, &format!(
+            "joins {} with {}", left.join(", "), right.join(", ")
+        ));
+        Ok(job_id)
+    }
+
+    /// Plans a query (see `planner`), and queues it as whatever it was planned as, returning the
+    /// ID of the job whose results are the query's.
+    pub async fn submit_query(&self, query: &ClusterQuery, principal: &str) -> Result<u64> {
+        let plan = plan_query(query)?;
+        match plan {
+            Plan::Partitioned(partitioned) => {
+                self.submit_partitioned(&partitioned, principal).await
+            },
+            Plan::Join(join) => self.submit_join(&join, principal).await,
+        }
+    }
+
+    /// Sends queued jobs to live workers, as the dispatch policy sees fit, for as long as the
+    /// scheduler runs. Jobs are sent on as soon as they are submitted; it's the workers' own job
+    /// queues that hold them until they can be run.
+    pub async fn dispatch(self: Arc<Self>) {
+        loop {
+            self.jobs.wait_for_work(DISPATCH_INTERVAL).await;
+            for (job_id, message) in self.jobs.fail_blocked() {
+                println!("Job {} can never run: {}", job_id, message);
+                self.log(job_id, EventKind::Failed, None, &message);
+            }
+            while self.jobs.depth() > 0 {
+                // Tenants with as many jobs out as they may have wait their turn.
+                let quota = &self.config.tenant_quota;
+                let load = match quota.max_running {
+                    Some(_) => self.tenant_load(),
+                    None => HashMap::new(),
+                };
+                // The roster is looked at afresh for every job, so that the policy sees the jobs
+                // it just handed out among the workers' in-flight jobs. The policy is shown the
+                // job that is up next, which is the one the worker it picks gets, unless it
+                // can't run it (see `RegisteredWorker::can_run`). In that case, the worker gets
+                // the next one it can run, and if it can't run any of the queued jobs, the
+                // policy gets to pick again from the others.
+                let next = self.jobs.peek_next(|owner, _| {
+                    let principal = self.history.principal(owner);
+                    quota.may_run(load.get(&principal).map_or(0, |load| load.0))
+                });
+                let next = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let mut workers = self.roster.dispatchable_workers();
+                let taken = loop {
+                    let worker = match self.policy.pick_for(&workers, &next) {
+                        Some(worker) => worker.clone(),
+                        None => break None,
+                    };
+                    let eligible = |owner, workload: &Workload| {
+                        let principal = self.history.principal(owner);
+                        quota.may_run(load.get(&principal).map_or(0, |load| load.0))
+                            && worker.can_run(workload).is_ok()
+                    };
+                    match self.jobs.take_next(worker.id, eligible) {
+                        Some(job) => break Some((worker, job)),
+                        None => workers.retain(|other| other.id != worker.id),
+                    }
+                };
+                let (worker, (job_id, workload)) = match taken {
+                    Some(taken) => taken,
+                    None => break,
+                };
+                self.roster.assign(worker.id, job_id);
+                self.log(job_id, EventKind::Dispatched, Some(worker.id), "");
+                tokio::spawn(Arc::clone(&self).run_job(worker, job_id, workload));
+            }
+            if let Some(factor) = self.config.speculation_factor {
+                Arc::clone(&self).speculate(factor);
+            }
+        }
+    }
+
+    /// Sends a duplicate of every straggling partition to a worker other than the one it is
+    /// out on (see `JobQueue::stragglers`). This happens after the queued jobs are handed out,
+    /// since those haven't had a go at all yet.
+    fn speculate(self: Arc<Self>, factor: f64) {
+        for (job_id, busy_worker_id) in self.jobs.stragglers(factor) {
+            let workload = match self.jobs.workload(job_id) {
+                Some(workload) => workload,
+                None => continue,
+            };
+            let workers = self.roster.dispatchable_workers().into_iter()
+                .filter(|worker| worker.id != busy_worker_id && worker.can_run(&workload).is_ok())
+                .collect::<Vec<_>>();
+            let worker = match self.policy.pick_for(&workers, &workload) {
+                Some(worker) => worker.clone(),
+                None => return,
+            };
+            if let Some(workload) = self.jobs.speculate(job_id, worker.id) {
+                println!(
+                    "Job {} is straggling on worker {}, sending a duplicate to worker {}.",
+                    job_id, busy_worker_id, worker.id
+                );
+                self.roster.assign(worker.id, job_id);
+                self.log(
+                    job_id, EventKind::Dispatched, Some(worker.id), "A speculative duplicate."
+                );
+                tokio::spawn(Arc::clone(&self).run_job(worker, job_id, workload));
+            }
+        }
+    }
+
+    /// Runs a job on a worker, and records how it went. Jobs that fail on the worker are failed;
+    /// jobs that fail to reach the worker (or whose worker stops answering) are re-queued.
+    async fn run_job(self: Arc<Self>, worker: RegisteredWorker, job_id: u64, workload: Workload) {
+        let outcome = self.send_job(&worker, job_id, &workload).await.map_err(|err| {
+            if is_unacknowledged(&*err) {
+                println!(
+                    "Worker {} never acknowledged job {} again, and may run it even though it \
+                    is re-queued.",
+                    worker.id, job_id
+                );
+            }
+            (is_remote(&*err), err.to_string())
+        });
+        self.record_outcome(&worker, job_id, outcome).await;
+        self.roster.release(worker.id, job_id);
+    }
+
+    /// Picks a job that was out on a worker when the scheduler last went down back up. The
+    /// worker is asked how it is doing first, and the job's results are then fetched from it,
+    /// as though the job had just been sent. If the worker doesn't answer, or no longer knows
+    /// the job (e.g. because it restarted too, or because it already sent the results to the
+    /// scheduler that went down), the job is re-queued.
+    async fn resume_job(
+        self: Arc<Self>, worker: RegisteredWorker, job_id: u64, worker_job_id: u64
+    ) {
+        let outcome = match self.beat(&worker.address).await.map_err(|err| err.to_string()) {
+            Ok(status) => {
+                self.roster.record_beat(worker.id, &status);
+                for progress in status.get_jobs() {
+                    self.jobs.running(worker.id, progress.get_job_id());
+                }
+                let classify = |err: Box<dyn Error>| {
+                    let kind = remote_kind(&*err);
+                    let failed = kind.is_some() && kind != Some(ErrorResponse_Kind::NOT_FOUND);
+                    (kind, (failed, err.to_string()))
+                };
+                let checkpointed = self.jobs.workload(job_id)
+                    .map_or(false, |workload| workload.get_checkpoint());
+                let fetched = self.fetch_job(&worker, job_id, worker_job_id).await
+                    .map_err(classify);
+                let outcome = match fetched {
+                    // A worker that went down too may still have the job's checkpoint, which
+                    // saves starting it over elsewhere.
+                    Err((Some(ErrorResponse_Kind::NOT_FOUND), _)) if checkpointed => {
+                        self.resume_checkpoint(&worker, job_id).await.map_err(classify)
+                    },
+                    fetched => fetched.map(|results| (worker_job_id, results)),
+                };
+                outcome.map_err(|(_, outcome)| outcome)
+            },
+            Err(message) => Err((false, message)),
+        };
+        self.record_outcome(&worker, job_id, outcome).await;
+        self.roster.release(worker.id, job_id);
+    }
+
+    /// Has a worker pick a checkpointed job back up where it left off, and waits for it to
+    /// finish, as `send_job` does.
+    async fn resume_checkpoint(
+        &self, worker: &RegisteredWorker, job_id: u64
+    ) -> Result<(u64, Vec<ResultBatch>)> {
+        let mut proxy = self.pool.checkout(&worker.address).await?;
+        let worker_job_id = proxy.resume(&self.attempt_key(job_id)).await?;
+        self.pool.checkin(proxy);
+        println!("Worker {} resumed job {} from its checkpoint.", worker.id, job_id);
+        self.jobs.update(job_id, worker.id, JobState::Dispatched {
+            worker_id: worker.id, worker_job_id
+        });
+        let results = self.fetch_job(worker, job_id, worker_job_id).await?;
+        Ok((worker_job_id, results))
+    }
+
+    /// The idempotency key the current attempt at a job goes out to its worker under (see
+    /// `send_job`).
+    fn attempt_key(&self, job_id: u64) -> String {
+        format!("scheduler-job-{}-{}", job_id, self.jobs.attempts(job_id))
+    }
+
+    /// Records how a job that was out on a worker went: the worker job ID and the result
+    /// batches, or whether or not the worker reported the error, and the error message.
+    async fn record_outcome(
+        &self,
+        worker: &RegisteredWorker,
+        job_id: u64,
+        outcome: std::result::Result<(u64, Vec<ResultBatch>), (bool, String)>,
+    ) {
+        // Results that don't fit in the tenant's quota fail the job, like an error on the
+        // worker would.
+        let outcome = match outcome {
+            Ok((worker_job_id, results)) => match self.check_result_quota(job_id, &results) {
+                Ok(()) => Ok((worker_job_id, results)),
+                Err(message) => Err((true, message)),
+            },
+            outcome => outcome,
+        };
+        match outcome {
+            Ok((worker_job_id, results)) => {
+                println!("Job {} finished on worker {}.", job_id, worker.id);
+                let n_rows = results.iter().map(|batch| batch.get_rows().len() as u64).sum();
+                let done = JobState::Done { worker_id: worker.id, worker_job_id, n_rows };
+                let loser = self.jobs.done(job_id, worker.id, done.clone(), results);
+                // A late report from an attempt that was given up on doesn't count.
+                if self.jobs.state(job_id) == Some(done) {
+                    self.log(job_id, EventKind::Completed, Some(worker.id), "");
+                }
+                if let Some((loser_id, loser_job_id)) = loser {
+                    self.cancel_attempt(loser_id, loser_job_id).await;
+                }
+                if let Some(parent) = self.jobs.parent(job_id) {
+                    self.merge_partitions(parent).await;
+                }
+            },
+            Err((true, message)) => {
+                println!("Job {} failed on worker {}: {}", job_id, worker.id, message);
+                if self.jobs.update(job_id, worker.id, JobState::Failed(message.clone())) {
+                    self.log(job_id, EventKind::Failed, Some(worker.id), &message);
+                }
+            },
+            Err((false, message)) => {
+                println!(
+                    "Lost touch with worker {} running job {} ({}), re-queueing it.",
+                    worker.id, job_id, message
+                );
+                self.retry(job_id, worker.id, &message);
+            },
+        }
+    }
+
+    /// Cancels a job (see `JobQueue::cancel`), and has the workers it is out on stop it.
+    /// Returns `false` if there is no such job, or if it already finished.
+    pub async fn cancel(&self, id: u64) -> bool {
+        let attempts = match self.jobs.cancel(id) {
+            Some(attempts) => attempts,
+            None => return false,
+        };
+        println!("Job {} cancelled.", id);
+        self.log(id, EventKind::Cancelled, None, "");
+        let cancels = attempts.into_iter()
+            .map(|(worker_id, worker_job_id)| self.cancel_attempt(worker_id, worker_job_id));
+        join_all(cancels).await;
+        true
+    }
+
+    /// Has a worker stop its attempt at a job, e.g. one that lost the race to its speculative
+    /// duplicate. This is best effort: if the worker can't be reached, or the job finished in
+    /// the meantime, there is nothing left to stop anyway.
+    async fn cancel_attempt(&self, worker_id: u64, worker_job_id: u64) {
+        let worker = match self.roster.get(worker_id) {
+            Some(worker) => worker,
+            None => return,
+        };
+        let cancel = async {
+            let mut proxy = self.pool.checkout(&worker.address).await?;
+            let cancelled = proxy.cancel(worker_job_id).await?;
+            self.pool.checkin(proxy);
+            Result::<bool>::Ok(cancelled)
+        };
+        match cancel.await.map_err(|err| err.to_string()) {
+            Ok(true) => println!("Cancelled job {} on worker {}.", worker_job_id, worker_id),
+            Ok(false) => println!(
+                "Job {} on worker {} had already finished.", worker_job_id, worker_id
+            ),
+            Err(message) => println!(
+                "Failed to cancel job {} on worker {}: {}", worker_job_id, worker_id, message
+            ),
+        }
+    }
+
+    /// Merges the result sets of a partitioned job, if all of its partitions are done and it
+    /// has a merge statement. Otherwise this does nothing.
+    async fn merge_partitions(&self, id: u64) {
+        if let Some((statement, partials)) = self.jobs.take_merge(id) {
+            let outcome = merge(&statement, &partials).await.map_err(|err| err.to_string());
+            match &outcome {
+                Ok(_) => println!("Merged the partitions of job {}.", id),
+                Err(message) => {
+                    println!("Failed to merge the partitions of job {}: {}", id, message)
+                },
+            }
+            let event = match &outcome {
+                Ok(_) => (EventKind::Completed, String::new()),
+                Err(message) => (EventKind::Failed, format!("Merge failed: {}", message)),
+            };
+            self.jobs.finish_merge(id, outcome);
+            if self.jobs.state(id).map_or(false, |state| state != JobState::Cancelled) {
+                self.log(id, event.0, None, &event.1);
+            }
+        }
+    }
+
+    /// Sends a job to a worker and waits for it to finish, returning the job ID the worker
+    /// queued it under, and the result batches it produced.
+    ///
+    /// The workload goes out under an idempotency key of the attempt's own (see
+    /// `Workload.idempotency_key`), whatever key it was submitted under. So if the worker
+    /// doesn't acknowledge it, it can safely be sent again: a worker that did get it the first
+    /// time answers with the job it already has. Later attempts get keys of their own, since
+    /// whatever became of the earlier ones is what they are there to make up for.
+    async fn send_job(
+        &self, worker: &RegisteredWorker, job_id: u64, workload: &Workload
+    ) -> Result<(u64, Vec<ResultBatch>)> {
+        let mut workload = workload.clone();
+        workload.set_idempotency_key(self.attempt_key(job_id));
+        let mut resent = false;
+        let (mut proxy, worker_job_id) = loop {
+            match self.pool.send_workload(&worker.address, &workload).await {
+                Ok(sent) => break sent,
+                Err(err) if !resent && is_unacknowledged(&*err) => {
+                    println!(
+                        "Worker {} never acknowledged job {}, sending it again.", worker.id, job_id
+                    );
+                    resent = true;
+                },
+                Err(err) => return Err(err),
+            }
+        };
+        self.jobs.update(job_id, worker.id, JobState::Dispatched {
+            worker_id: worker.id, worker_job_id
+        });
+        let mut results = vec![];
+        proxy.fetch_results(
+            worker_job_id,
+            |batch| results.push(batch),
+            |progress| self.jobs.set_progress(job_id, worker.id, progress)
+        ).await?;
+        self.pool.checkin(proxy);
+        Ok((worker_job_id, results))
+    }
+
+    /// Fetches the results of a job that is already out on a worker, waiting for the job to
+    /// finish if needs be.
+    async fn fetch_job(
+        &self, worker: &RegisteredWorker, job_id: u64, worker_job_id: u64
+    ) -> Result<Vec<ResultBatch>> {
+        let mut proxy = self.pool.checkout(&worker.address).await?;
+        let mut results = vec![];
+        proxy.fetch_results(
+            worker_job_id,
+            |batch| results.push(batch),
+            |progress| self.jobs.set_progress(job_id, worker.id, progress)
+        ).await?;
+        self.pool.checkin(proxy);
+        Ok(results)
+    }
+
+    /// Tells whoever asked to be told (see `Workload.notify_url`) that their jobs finished, for
+    /// as long as the scheduler runs. See `notify`.
+    pub async fn notify(self: Arc<Self>) {
+        let mut finished_rx = self.jobs.subscribe();
+        loop {
+            for (job_id, url) in self.jobs.take_notifications() {
+                let payload = self.notification(job_id);
+                tokio::spawn(async move {
+                    match notify(&url, &payload).await.map_err(|err| err.to_string()) {
+                        Ok(()) => println!("Notified {} that job {} finished.", url, job_id),
+                        Err(message) => println!(
+                            "Failed to notify {} that job {} finished: {}", url, job_id, message
+                        ),
+                    }
+                });
+            }
+            if finished_rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Describes a finished job for its notify URL: as `GET /jobs/{id}` does, along with when
+    /// the job was submitted and when it finished (as Unix timestamps, in seconds), and for how
+    /// long it ran on its worker (for jobs that aren't partitioned).
+    fn notification(&self, job_id: u64) -> Json {
+        let mut payload = self.jobs.describe(job_id).map_or(json!({}), |job| describe_job(&job));
+        payload["submitted_at"] =
+            json!(self.history.submitted_at(job_id).map(|at| at as f64 / 1000.0));
+        payload["finished_at"] = json!(now_millis() as f64 / 1000.0);
+        payload["runtime_seconds"] =
+            json!(self.jobs.runtime(job_id).map(|runtime| runtime.as_secs_f64()));
+        payload
+    }
+
+    /// Pings every worker on the roster, every `heartbeat_interval`, for as long as the
+    /// scheduler runs. Workers that miss `max_missed_beats` heartbeats in a row are marked dead,
+    /// and their in-flight jobs are re-queued. Results held for longer than `results_ttl` are
+    /// thrown away as it goes.
+    pub async fn heartbeat(self: Arc<Self>) {
+        let mut ticks = interval(self.config.heartbeat_interval);
+        loop {
+            ticks.tick().await;
+            for job_id in self.jobs.expire_results(self.config.results_ttl) {
+                println!("The results of job {} expired.", job_id);
+            }
+            // The workers are pinged all at once, so that one slow worker doesn't hold up the
+            // heartbeats of the others.
+            let beats = self.roster.workers().into_iter().map(|worker| {
+                let scheduler = Arc::clone(&self);
+                async move {
+                    let outcome = scheduler.beat(&worker.address).await
+                        .map_err(|err| err.to_string());
+                    (worker.id, outcome)
+                }
+            });
+            for (worker_id, outcome) in join_all(beats).await {
+                match outcome {
+                    Ok(status) => {
+                        self.roster.record_beat(worker_id, &status);
+                        for progress in status.get_jobs() {
+                            self.jobs.running(worker_id, progress.get_job_id());
+                        }
+                    },
+                    Err(message) => {
+                        let max_missed_beats = self.config.max_missed_beats;
+                        if let Some(orphans) =
+                            self.roster.record_missed_beat(worker_id, max_missed_beats) {
+                            println!(
+                                "Worker {} missed {} heartbeats in a row ({}), marking it dead \
+                                and re-queueing its {} in-flight job(s).",
+                                worker_id, max_missed_beats, message, orphans.len()
+                            );
+                            self.requeue(
+                                worker_id, orphans, "Its worker stopped answering heartbeats."
+                            );
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    /// Sends a single heartbeat (a PING) to a worker.
+    async fn beat(&self, address: &Address) -> Result<WorkerStatus> {
+        let beat = async {
+            let mut proxy = self.pool.checkout(address).await?;
+            let status = proxy.ping().await?;
+            self.pool.checkin(proxy);
+            Result::<WorkerStatus>::Ok(status)
+        };
+        let outcome = match timeout(self.config.heartbeat_timeout, beat).await {
+            Ok(outcome) => outcome,
+            Err(_) => Err(SchedulerError::new(
+                ErrKind::NetworkError, "Worker did not answer the heartbeat in time."
+            ).into()),
+        };
+        // Whatever went wrong with this connection likely went wrong with the worker's other
+        // idle ones too (e.g. because it restarted), so they are all closed.
+        if outcome.is_err() {
+            self.pool.forget(address);
+        }
+        outcome
+    }
+
+    /// Serves workers and clients dialing in, one task per connection.
+    pub async fn listen(self: Arc<Self>) -> Result<()> {
+        loop {
+            let stream = self.listener.accept().await?;
+            let scheduler = Arc::clone(&self);
+            tokio::spawn(async move {
+                let outcome = scheduler.handle_connection(stream).await
+                    .map_err(|err| err.to_string());
+                if let Err(message) = outcome {
+                    println!("Connection to {} failed: {}", scheduler, message);
+                }
+            });
+        }
+    }
+
+    /// Serves a single connection, from either a worker (which REGISTERs itself) or a client
+    /// (which submits WORK, and asks after it with JOB_QUERY). Like the worker's own sessions,
+    /// the connection starts with a NONCE challenge (if the scheduler has a secret), and ends
+    /// with SHUTDOWN.
+    ///
+    /// Clients can show an API key (see `keys`) by sending it in an AUTH frame, after which
+    /// they are taken to be its principal, and may do what its role allows. Clients that didn't
+    /// authenticate with the secret have to, if the scheduler requires API keys; until then,
+    /// they may only ask after jobs. A client showing a key that isn't one of ours is hung up on.
+    async fn handle_connection(&self, mut stream: Stream) -> Result<()> {
+        if let Some(secret) = &self.config.secret {
+            let nonce = generate_nonce();
+            write_frame(&mut stream, protocol::NONCE, 0, 0, &nonce).await?;
+            let (header, signature) = read_frame(&mut stream).await?;
+            if header.signal != protocol::AUTH || !verify_nonce(secret, &nonce, &signature) {
+                println!("Peer failed to authenticate, hanging up.");
+                return Ok(());
+            }
+        }
+        let mut client = FramedClient {
+            key: None,
+            trusted: self.config.secret.is_some() || !self.config.require_api_keys,
+        };
+
+        loop {
+            let (header, payload) = read_frame(&mut stream).await?;
+            let flags = header.response_flags();
+            if let Err(error) = client.authorize(header.signal) {
+                write_frame(
+                    &mut stream, protocol::ERROR, header.request_id, flags,
+                    &error.write_to_bytes()?
+                ).await?;
+                continue;
+            }
+            match header.signal {
+                protocol::AUTH => {
+                    let key = std::str::from_utf8(&payload).ok()
+                        .and_then(|key| self.api_keys.authenticate(key.trim()));
+                    if key.is_none() {
+                        println!("Client showed an unknown API key, hanging up.");
+                        let error = craft_error(
+                            ErrorResponse_Kind::UNAUTHORIZED, "Unknown or malformed API key."
+                        );
+                        write_frame(
+                            &mut stream, protocol::ERROR, header.request_id, flags,
+                            &error.write_to_bytes()?
+                        ).await?;
+                        return Ok(());
+                    }
+                    client.key = key;
+                },
+                protocol::REGISTER => {
+                    // Our errors aren't `Send`, so only the message is kept past this point.
+                    let outcome = match WorkerRegistration::parse_from_bytes(&payload) {
+                        Ok(registration) => {
+                            // Connections to whatever was at the address before are no good.
+                            if let Ok(address) = registration_address(&registration) {
+                                self.pool.forget(&address);
+                            }
+                            self.roster.register(&registration)
+                        },
+                        Err(err) => Err(err.into()),
+                    }.map_err(|err| err.to_string());
+                    match outcome {
+                        Ok(worker_id) => {
+                            println!("Worker {} registered.", worker_id);
+                            let mut registered = Registered::new();
+                            registered.set_worker_id(worker_id);
+                            write_frame(
+                                &mut stream,
+                                protocol::REGISTERED,
+                                header.request_id,
+                                flags,
+                                &registered.write_to_bytes()?
+                            ).await?;
+                            // Catch the newcomer up on the files the other workers preloaded.
+                            // It is still registering, so this only gets through once it starts
+                            // serving connections, right after.
+                            let files = self.preloaded.lock().unwrap().clone();
+                            let worker = self.roster.get(worker_id).filter(|_| !files.is_empty());
+                            if let Some(worker) = worker {
+                                let outcome = self.preload(&worker.address, &files).await
+                                    .map_err(|err| err.to_string());
+                                if let Err(message) = outcome {
+                                    println!(
+                                        "Worker {} failed to preload files: {}", worker_id, message
+                                    );
+                                }
+                            }
+                        },
+                        Err(message) => {
+                            let error = craft_error(ErrorResponse_Kind::PROTOCOL, &message);
+                            write_frame(
+                                &mut stream,
+                                protocol::ERROR,
+                                header.request_id,
+                                flags,
+                                &error.write_to_bytes()?
+                            ).await?;
+                        },
+                    }
+                },
+                protocol::WORK => {
+                    let workload = Workload::parse_from_bytes(&payload)?;
+                    let principal = client.principal(&workload).to_owned();
+                    let outcome = self.submit(workload, &principal)
+                        .map_err(|err| submission_error(&*err));
+                    match outcome {
+                        Ok(job_id) => {
+                            let mut ack = Ack::new();
+                            ack.set_job_id(job_id);
+                            ack.set_queue_depth(self.jobs.depth() as u32);
+                            write_frame(
+                                &mut stream, protocol::ACK, header.request_id, flags,
+                                &ack.write_to_bytes()?
+                            ).await?;
+                        },
+                        Err(error) => {
+                            write_frame(
+                                &mut stream, protocol::ERROR, header.request_id, flags,
+                                &error.write_to_bytes()?
+                            ).await?;
+                        },
+                    }
+                },
+                protocol::PARTITION => {
+                    let partitioned = PartitionedWorkload::parse_from_bytes(&payload)?;
+                    let principal = client.principal(partitioned.get_options()).to_owned();
+                    let outcome = self.submit_partitioned(&partitioned, &principal).await
+                        .map_err(|err| submission_error(&*err));
+                    match outcome {
+                        Ok(job_id) => {
+                            println!("Partitioned job {} queued.", job_id);
+                            let mut ack = Ack::new();
+                            ack.set_job_id(job_id);
+                            ack.set_queue_depth(self.jobs.depth() as u32);
+                            write_frame(
+                                &mut stream, protocol::ACK, header.request_id, flags,
+                                &ack.write_to_bytes()?
+                            ).await?;
+                        },
+                        Err(error) => {
+                            write_frame(
+                                &mut stream, protocol::ERROR, header.request_id, flags,
+                                &error.write_to_bytes()?
+                            ).await?;
+                        },
+                    }
+                },
+                protocol::JOIN => {
+                    let join = DistributedJoin::parse_from_bytes(&payload)?;
+                    let principal = client.principal(join.get_options()).to_owned();
+                    let outcome = self.submit_join(&join, &principal).await
+                        .map_err(|err| submission_error(&*err));
+                    match outcome {
+                        Ok(job_id) => {
+                            println!("Distributed join {} queued.", job_id);
+                            let mut ack = Ack::new();
+                            ack.set_job_id(job_id);
+                            ack.set_queue_depth(self.jobs.depth() as u32);
+                            write_frame(
+                                &mut stream, protocol::ACK, header.request_id, flags,
+                                &ack.write_to_bytes()?
+                            ).await?;
+                        },
+                        Err(error) => {
+                            write_frame(
+                                &mut stream, protocol::ERROR, header.request_id, flags,
+                                &error.write_to_bytes()?
+                            ).await?;
+                        },
+                    }
+                },
+                protocol::QUERY => {
+                    let query = ClusterQuery::parse_from_bytes(&payload)?;
+                    let principal = client.principal(query.get_options()).to_owned();
+                    let outcome = self.submit_query(&query, &principal).await
+                        .map_err(|err| submission_error(&*err));
+                    match outcome {
+                        Ok(job_id) => {
+                            println!("Query {} queued.", job_id);
+                            let mut ack = Ack::new();
+                            ack.set_job_id(job_id);
+                            ack.set_queue_depth(self.jobs.depth() as u32);
+                            write_frame(
+                                &mut stream, protocol::ACK, header.request_id, flags,
+                                &ack.write_to_bytes()?
+                            ).await?;
+                        },
+                        Err(error) => {
+                            write_frame(
+                                &mut stream, protocol::ERROR, header.request_id, flags,
+                                &error.write_to_bytes()?
+                            ).await?;
+                        },
+                    }
+                },
+                protocol::FETCH => {
+                    // Like the worker, the scheduler waits for the job to finish before it
+                    // answers, so this can take a while.
+                    let fetch = FetchResults::parse_from_bytes(&payload)?;
+                    let job_id = fetch.get_job_id();
+                    let owner = self.history.principal(self.jobs.parent(job_id).unwrap_or(job_id));
+                    if self.jobs.state(job_id).is_some() && !client.may_fetch(&owner) {
+                        let error = craft_error(
+                            ErrorResponse_Kind::UNAUTHORIZED,
+                            &format!("Job {} was submitted by someone else.", job_id)
+                        );
+                        write_frame(
+                            &mut stream, protocol::ERROR, header.request_id, flags,
+                            &error.write_to_bytes()?
+                        ).await?;
+                        continue;
+                    }
+                    let (kind, message) = match self.jobs.wait(job_id).await {
+                        Some(JobState::Failed(message)) => {
+                            (ErrorResponse_Kind::INTERNAL, message)
+                        },
+                        Some(JobState::Cancelled) => {
+                            (ErrorResponse_Kind::INTERNAL, format!("Job {} was cancelled.", job_id))
+                        },
+                        Some(_) => {
+                            let results = self.jobs.results(job_id).unwrap_or_default();
+                            for batch in results {
+                                let pieces =
+                                    split_result_batch(batch, protocol::MAX_PAYLOAD_SIZE)?;
+                                for piece in pieces {
+                                    write_frame(
+                                        &mut stream, protocol::RESULTS, header.request_id, flags,
+                                        &piece.write_to_bytes()?
+                                    ).await?;
+                                }
+                            }
+                            self.jobs.mark_fetched(job_id);
+                            continue;
+                        },
+                        None => {
+                            (ErrorResponse_Kind::NOT_FOUND, format!("No job with ID {}.", job_id))
+                        },
+                    };
+                    let error = craft_error(kind, &message);
+                    write_frame(
+                        &mut stream, protocol::ERROR, header.request_id, flags,
+                        &error.write_to_bytes()?
+                    ).await?;
+                },
+                protocol::PRELOAD => {
+                    let mut preload = Preload::parse_from_bytes(&payload)?;
+                    let failures = self.broadcast_preload(preload.take_files().into_vec()).await;
+                    if failures.is_empty() {
+                        write_frame(
+                            &mut stream, protocol::PRELOADED, header.request_id, flags, &[]
+                        ).await?;
+                    } else {
+                        let message = failures.iter()
+                            .map(|(worker_id, message)| {
+                                format!("worker {}: {}", worker_id, message)
+                            })
+                            .collect::<Vec<_>>()
+                            .join("; ");
+                        let error = craft_error(
+                            ErrorResponse_Kind::INTERNAL,
+                            &format!("Some workers failed to preload the files ({}).", message)
+                        );
+                        write_frame(
+                            &mut stream, protocol::ERROR, header.request_id, flags,
+                            &error.write_to_bytes()?
+                        ).await?;
+                    }
+                },
+                protocol::JOB_QUERY => {
+                    let query = JobQuery::parse_from_bytes(&payload)?;
+                    match self.jobs.describe(query.get_job_id()) {
+                        Some(job) => {
+                            write_frame(
+                                &mut stream, protocol::JOB_STATE, header.request_id, flags,
+                                &job.write_to_bytes()?
+                            ).await?;
+                        },
+                        None => {
+                            let error = craft_error(
+                                ErrorResponse_Kind::NOT_FOUND,
+                                &format!("No job with ID {}.", query.get_job_id())
+                            );
+                            write_frame(
+                                &mut stream, protocol::ERROR, header.request_id, flags,
+                                &error.write_to_bytes()?
+                            ).await?;
+                        },
+                    }
+                },
+                protocol::SHUTDOWN => return Ok(()),
+                signal => {
+                    let error = craft_error(
+                        ErrorResponse_Kind::PROTOCOL,
+                        &format!("The scheduler does not accept signal {}.", signal)
+                    );
+                    write_frame(
+                        &mut stream, protocol::ERROR, header.request_id, flags,
+                        &error.write_to_bytes()?
+                    ).await?;
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::RepeatedField;
+    use serial_test::serial;
+
+    use mini_cluster_worker::fixtures::{craft_op_message, craft_workload_message};
+    use mini_cluster_worker::protocol::FrameHeader;
+
+    use super::*;
+
+    /// A workload of a single op over the given files, ephemeral so that it doesn't touch the
+    /// worker's database.
+    fn craft_workload(files: Vec<File>, statement: &str) -> Workload {
+        let op = craft_op_message(
+            Some(RepeatedField::from_vec(files)), Some(statement.to_owned()), Some(1)
+        );
+        let mut workload = craft_workload_message(Some(RepeatedField::from_vec(vec![op])));
+        workload.set_ephemeral(true);
+        workload
+    }
+
+    /// Starts a scheduler with no workers, and connects to it.
+    async fn start_scheduler(config: SchedulerConfig) -> (Arc<Scheduler>, Stream) {
+        let scheduler = Arc::new(Scheduler::new(config).await.unwrap());
+        tokio::spawn(Arc::clone(&scheduler).listen());
+        let stream = Stream::connect(&scheduler.config.address).await.unwrap();
+        (scheduler, stream)
+    }
+
+    /// Sends the scheduler a frame, and reads what it answers with.
+    async fn request(stream: &mut Stream, signal: u8, payload: &[u8]) -> (FrameHeader, Vec<u8>) {
+        write_frame(stream, signal, 1, 0, payload).await.unwrap();
+        read_frame(stream).await.unwrap()
+    }
+
+    /// The kind of error an answer is, panicking if it isn't an ERROR frame.
+    fn error_kind(answer: &(FrameHeader, Vec<u8>)) -> ErrorResponse_Kind {
+        assert_eq!(answer.0.signal, protocol::ERROR);
+        ErrorResponse::parse_from_bytes(&answer.1).unwrap().get_kind()
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_framed_clients_need_api_keys() {
+        let config = SchedulerConfig {
+            require_api_keys: true,
+            ..SchedulerConfig::for_test("framed-keys")
+        };
+        let (scheduler, mut stream) = start_scheduler(config).await;
+        let mut workload = craft_workload(vec![], "SELECT 1 AS x");
+        workload.set_principal("mallory".to_owned());
+        let work = workload.write_to_bytes().unwrap();
+        let preload = Preload::new().write_to_bytes().unwrap();
+
+        // Without a key, a client may ask after jobs, and nothing else.
+        let answer = request(&mut stream, protocol::WORK, &work).await;
+        assert_eq!(error_kind(&answer), ErrorResponse_Kind::UNAUTHORIZED);
+        let answer = request(&mut stream, protocol::PRELOAD, &preload).await;
+        assert_eq!(error_kind(&answer), ErrorResponse_Kind::UNAUTHORIZED);
+        let mut query = JobQuery::new();
+        query.set_job_id(1);
+        let answer = request(&mut stream, protocol::JOB_QUERY, &query.write_to_bytes().unwrap());
+        assert_eq!(error_kind(&answer.await), ErrorResponse_Kind::NOT_FOUND);
+        assert_eq!(scheduler.jobs.depth(), 0);
+
+        // With a submit key, it may submit jobs, which are put down to the key's holder whoever
+        // the workload says submitted it, but it may not preload, or fetch others' results.
+        let (_, key) = scheduler.api_keys.create("alice", Role::Submit).unwrap();
+        write_frame(&mut stream, protocol::AUTH, 2, 0, key.as_bytes()).await.unwrap();
+        let answer = request(&mut stream, protocol::WORK, &work).await;
+        assert_eq!(answer.0.signal, protocol::ACK);
+        let job_id = Ack::parse_from_bytes(&answer.1).unwrap().get_job_id();
+        assert_eq!(scheduler.history.principal(job_id), "alice");
+        let answer = request(&mut stream, protocol::PRELOAD, &preload).await;
+        assert_eq!(error_kind(&answer), ErrorResponse_Kind::UNAUTHORIZED);
+        let other = scheduler.submit(craft_workload(vec![], "SELECT 2 AS x"), "bob").unwrap();
+        let mut fetch = FetchResults::new();
+        fetch.set_job_id(other);
+        let answer = request(&mut stream, protocol::FETCH, &fetch.write_to_bytes().unwrap());
+        assert_eq!(error_kind(&answer.await), ErrorResponse_Kind::UNAUTHORIZED);
+
+        // A client showing a key the scheduler doesn't know is hung up on.
+        let mut stream = Stream::connect(&scheduler.config.address).await.unwrap();
+        let answer = request(&mut stream, protocol::AUTH, b"mck_0_0").await;
+        assert_eq!(error_kind(&answer), ErrorResponse_Kind::UNAUTHORIZED);
+        assert!(read_frame(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_framed_clients_without_required_keys() {
+        let (scheduler, mut stream) = start_scheduler(SchedulerConfig::for_test("framed")).await;
+        let mut workload = craft_workload(vec![], "SELECT 1 AS x");
+        workload.set_principal("mallory".to_owned());
+        let work = workload.write_to_bytes().unwrap();
+
+        // Without a key, the client is taken at its word.
+        let answer = request(&mut stream, protocol::WORK, &work).await;
+        assert_eq!(answer.0.signal, protocol::ACK);
+        let job_id = Ack::parse_from_bytes(&answer.1).unwrap().get_job_id();
+        assert_eq!(scheduler.history.principal(job_id), "mallory");
+
+        // Showing a key holds it to the key's role all the same.
+        let (_, key) = scheduler.api_keys.create("alice", Role::Submit).unwrap();
+        write_frame(&mut stream, protocol::AUTH, 2, 0, key.as_bytes()).await.unwrap();
+        let preload = Preload::new().write_to_bytes().unwrap();
+        let answer = request(&mut stream, protocol::PRELOAD, &preload).await;
+        assert_eq!(error_kind(&answer), ErrorResponse_Kind::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_shutdown_gives_up_waiting() {
+        use mini_cluster_worker::membership::craft_registration;
+
+        let mut config = SchedulerConfig::for_test("shutdown-wait");
+        config.drain_timeout = Duration::from_millis(100);
+        let (scheduler, _) = start_scheduler(config).await;
+        // Nothing listens at the worker's address, so stopping it could only fail.
+        let address = Address::from(
+            std::env::temp_dir().join("mini-cluster-shutdown-wait-worker.sock")
+        );
+        let registration = craft_registration(&address, &WorkerCapabilities::new(), 0);
+        let worker_id = scheduler.roster.register(&registration).unwrap();
+        scheduler.roster.mark_dead(worker_id);
+
+        // There's no worker to run the job, so it never finishes, and isn't waited for forever.
+        let (job_id, _) = scheduler.jobs.submit(Workload::new(), "alice");
+        let failures = timeout(Duration::from_secs(5), scheduler.shutdown(true, Drain::Wait))
+            .await
+            .unwrap();
+        // The dead worker isn't told to stop at all.
+        assert!(failures.is_empty());
+        assert!(scheduler.roster.get(worker_id).is_some());
+        assert_eq!(scheduler.jobs.state(job_id), Some(JobState::Queued));
+        scheduler.stopped().await;
+    }
+
+    #[cfg(feature = "chaos")]
+    mod chaos {
+        use tokio::time::sleep;
+
+        use mini_cluster_worker::chaos::{self, Faults};
+        use mini_cluster_worker::config::WorkerConfig;
+        use mini_cluster_worker::fixtures::craft_file_message;
+        use mini_cluster_worker::membership::craft_registration;
+        use mini_cluster_worker::Worker;
+
+        use super::*;
+
+        /// How long a job has to get where a test expects it to.
+        const WAIT: Duration = Duration::from_secs(30);
+
+        /// Starts a worker, and a scheduler that dispatches to it alone. Both listen on Unix
+        /// sockets named after the test, so the tests don't have to hand out ports.
+        async fn start_cluster(name: &str) -> Arc<Scheduler> {
+            let dir = std::env::temp_dir();
+            let address = Address::from(dir.join(format!("mini-cluster-{}-worker.sock", name)));
+            let worker = Arc::new(Worker::new(address, WorkerConfig::default()).await.unwrap());
+            tokio::spawn(Arc::clone(&worker).listen());
+            let config = SchedulerConfig::for_test(name);
+            let scheduler = Arc::new(Scheduler::new(config).await.unwrap());
+            let registration = craft_registration(&worker.address, &worker.advertised, 0);
+            scheduler.roster.register(&registration).unwrap();
+            tokio::spawn(Arc::clone(&scheduler).dispatch());
+            scheduler
+        }
+
+        /// Waits for a job to finish, returning how it ended, or `None` if it didn't in time.
+        async fn wait_for_finish(scheduler: &Scheduler, job_id: u64) -> Option<JobState> {
+            let finished = async {
+                loop {
+                    match scheduler.jobs.state(job_id) {
+                        Some(state) if state.is_finished() => return state,
+                        _ => sleep(Duration::from_millis(10)).await,
+                    }
+                }
+            };
+            timeout(WAIT, finished).await.ok()
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn test_job_survives_dropped_connections() {
+            let scheduler = start_cluster("chaos-drops").await;
+            // Whilst every connection drops, every attempt at the job is lost on its way to the
+            // worker, and re-queued.
+            chaos::inject(Faults { drop_rate: 1.0, ..Faults::default() }).unwrap();
+            let workload = craft_workload(vec![], "SELECT 1 AS x");
+            let job_id = scheduler.submit(workload, ANONYMOUS).unwrap();
+            let retried = timeout(WAIT, async {
+                while scheduler.jobs.attempts(job_id) < 3 {
+                    sleep(Duration::from_millis(10)).await;
+                }
+            }).await;
+            chaos::clear();
+            assert!(retried.is_ok());
+
+            match wait_for_finish(&scheduler, job_id).await {
+                Some(JobState::Done { n_rows, .. }) => assert_eq!(n_rows, 1),
+                state => panic!("Expected the job to be done, got {:?}.", state),
+            }
+            let results = scheduler.jobs.results(job_id).unwrap();
+            let row = results.iter().flat_map(|batch| batch.get_rows()).next().unwrap();
+            assert_eq!(row.get_values()[0].get_integer(), 1);
+        }
+
+        #[tokio::test]
+        #[serial]
+        async fn test_job_fails_on_s3_errors() {
+            let scheduler = start_cluster("chaos-s3").await;
+            // S3 turning the worker away is an error the worker reports, so the job fails, rather
+            // than being re-queued the way it would be if the worker couldn't be reached.
+            chaos::inject(Faults { s3_error_rate: 1.0, ..Faults::default() }).unwrap();
+            let path = "s3://mini-cluster-chaos/a.csv".to_owned();
+            let file = craft_file_message(Some(1), Some(path));
+            let workload = craft_workload(vec![file], "SELECT * FROM dataset_1");
+            let job_id = scheduler.submit(workload, ANONYMOUS).unwrap();
+            let state = wait_for_finish(&scheduler, job_id).await;
+            chaos::clear();
+
+            match state {
+                Some(JobState::Failed(message)) => assert!(message.contains("503"), "{}", message),
+                state => panic!("Expected the job to fail, got {:?}.", state),
+            }
+            assert_eq!(scheduler.jobs.attempts(job_id), 1);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+use protobuf::RepeatedField;
+use serde::{Deserialize, Serialize};
+
+use mini_cluster_worker::workload::{File, Op, Workload};
+
+use crate::err::{Result, SchedulerError, ErrKind};
+
+// A workload spec describes a workload in a JSON or YAML file, for people who would rather not
+// build `Workload` protobuf messages by hand. It names things that the protobuf message numbers:
+//
+//     files:
+//       - name: trips
+//         path: s3://bucket/trips.csv
+//         null_tokens: ["NA"]
+//         requester_pays: true
+//     ops:
+//       - name: daily
+//         statement: SELECT date, COUNT(*) FROM {trips} GROUP BY date
+//         files: [trips]
+//       - name: busiest
+//         statement: SELECT * FROM {trips} ORDER BY fare DESC LIMIT 10
+//         files: [trips]
+//     output:
+//       max_rows: 1000
+//       truncate: true
+//
+// Files are given IDs in the order they are listed in (starting from 1), and ops sequence
+// numbers likewise. In a statement, `{trips}` stands for the table the file named `trips` is
+// loaded into (`dataset_1`, here). Ops that have to run after others list them in `after`, by
+// name; if none do, each op runs after the one before it, same as in a `Workload`.
+//
+// A workload can also wait on other jobs, listed by job ID in the top-level `after`, e.g. a
+// second stage reading the files the first stage wrote:
+//
+//     after: [41]
+
+/// A file, as listed in a workload spec.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSpec {
+    pub name: String,
+    pub path: String,
+    #[serde(default)]
+    pub null_tokens: Vec<String>,
+    /// Whether or not the file is in a requester-pays bucket.
+    #[serde(default)]
+    pub requester_pays: bool,
+    /// The base64-encoded key the file was encrypted with, if it was encrypted with a
+    /// customer-provided key (SSE-C).
+    #[serde(default)]
+    pub sse_customer_key: Option<String>,
+}
+
+/// An op, as listed in a workload spec.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpSpec {
+    pub name: String,
+    pub statement: String,
+    /// The names of the files the op reads from.
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// The names of the ops that have to run before this one.
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// Whether or not to send back the op's result set, even if it isn't a sink.
+    #[serde(default)]
+    pub return_result: bool,
+}
+
+/// What to do with a workload's result sets (see the fields of the same names on `Workload`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputSpec {
+    /// Caps on the size of the result sets. Zero means no limit.
+    pub max_rows: u64,
+    pub max_bytes: u64,
+    /// Cut the result sets short when they hit a cap, rather than failing the job.
+    pub truncate: bool,
+    /// Serve the results out of the worker's result cache, if possible.
+    pub cache: bool,
+}
+
+/// A workload, as described in a JSON or YAML file. See the top of this module for an example.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkloadSpec {
+    #[serde(default)]
+    pub files: Vec<FileSpec>,
+    pub ops: Vec<OpSpec>,
+    #[serde(default)]
+    pub output: OutputSpec,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub ephemeral: bool,
+    #[serde(default)]
+    pub force_reload: bool,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub explain: bool,
+    /// The IDs of the jobs that have to finish successfully before this one runs, e.g. jobs
+    /// writing the files it reads.
+    #[serde(default)]
+    pub after: Vec<u64>,
+    /// An HTTP URL to POST to once the job finishes (see `notify`).
+    #[serde(default)]
+    pub notify_url: Option<String>,
+    /// A key that has the workload run only once, however many times it is submitted (see
+    /// `Workload.idempotency_key`).
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Whether or not to checkpoint the job, so that it picks up where it left off if its worker
+    /// goes away part-way through (see `Workload.checkpoint`).
+    #[serde(default)]
+    pub checkpoint: bool,
+    /// The database engine to run the ops on, e.g. `sqlite` (see `Workload.engine`).
+    #[serde(default)]
+    pub engine: Option<String>,
+    /// The optional parts of the SQL surface the ops need, e.g. `json1`. The job is only sent to
+    /// workers that have all of them (see `Workload.requires`).
+    #[serde(default)]
+    pub requires: Vec<String>,
+}
+
+fn invalid(message: String) -> Box<dyn std::error::Error> {
+    SchedulerError::new(ErrKind::InvalidRequest, &message).into()
+}
+
+/// Numbers a list of names from 1, erroring out if any name is used twice.
+fn number_names<'a>(
+    kind: &str, names: impl Iterator<Item = &'a String>
+) -> Result<HashMap<&'a str, i32>> {
+    let mut numbers = HashMap::new();
+    for (i, name) in names.enumerate() {
+        if numbers.insert(name.as_str(), i as i32 + 1).is_some() {
+            return Err(invalid(format!("There is more than one {} named {:?}.", kind, name)));
+        }
+    }
+    Ok(numbers)
+}
+
+impl WorkloadSpec {
+    /// Reads a spec out of JSON or YAML text. YAML is a superset of JSON, so one parser covers
+    /// both.
+    pub fn parse(text: &str) -> Result<WorkloadSpec> {
+        serde_yaml::from_str(text)
+            .map_err(|err| invalid(format!("Could not parse the workload spec: {}", err)))
+    }
+
+    /// Converts the spec into the `Workload` message the workers run.
+    pub fn to_workload(&self) -> Result<Workload> {
+        if self.ops.is_empty() {
+            return Err(invalid("The workload spec has no ops.".to_owned()));
+        }
+        let file_ids = number_names("file", self.files.iter().map(|file| &file.name))?;
+        let op_numbers = number_names("op", self.ops.iter().map(|op| &op.name))?;
+        let files = self.files.iter().map(|spec| {
+            let mut file = File::new();
+            file.set_path(spec.path.clone());
+            file.set_id(file_ids[spec.name.as_str()]);
+            file.set_null_tokens(RepeatedField::from_vec(spec.null_tokens.clone()));
+            file.set_requester_pays(spec.requester_pays);
+            file.set_sse_customer_key(spec.sse_customer_key.clone().unwrap_or_default());
+            (spec.name.as_str(), file)
+        }).collect::<HashMap<_, _>>();
+
+        let ops = self.ops.iter().map(|spec| {
+            let mut op = Op::new();
+            let mut statement = spec.statement.clone();
+            for (name, file) in files.iter() {
+                statement = statement.replace(
+                    &format!("{{{}}}", name), &format!("dataset_{}", file.get_id())
+                );
+            }
+            op.set_statement(statement);
+            let targets = spec.files.iter().map(|name| match files.get(name.as_str()) {
+                Some(file) => Ok(file.clone()),
+                None => Err(invalid(format!(
+                    "Op {:?} reads from file {:?}, which isn't listed.", spec.name, name
+                ))),
+            }).collect::<Result<Vec<_>>>()?;
+            op.set_targets(RepeatedField::from_vec(targets));
+            op.set_op_sequence_num(op_numbers[spec.name.as_str()]);
+            let depends_on = spec.after.iter().map(|name| match op_numbers.get(name.as_str()) {
+                Some(number) => Ok(*number),
+                None => Err(invalid(format!(
+                    "Op {:?} runs after op {:?}, which isn't listed.", spec.name, name
+                ))),
+            }).collect::<Result<Vec<_>>>()?;
+            op.set_depends_on(depends_on);
+            op.set_return_result(spec.return_result);
+            Ok(op)
+        }).collect::<Result<Vec<_>>>()?;
+
+        let mut workload = Workload::new();
+        workload.set_ops(RepeatedField::from_vec(ops));
+        workload.set_max_result_rows(self.output.max_rows);
+        workload.set_max_result_bytes(self.output.max_bytes);
+        workload.set_truncate_results(self.output.truncate);
+        workload.set_use_result_cache(self.output.cache);
+        workload.set_priority(self.priority);
+        workload.set_ephemeral(self.ephemeral);
+        workload.set_force_reload(self.force_reload);
+        workload.set_dry_run(self.dry_run);
+        workload.set_explain(self.explain);
+        workload.set_checkpoint(self.checkpoint);
+        if let Some(engine) = &self.engine {
+            workload.set_engine(engine.clone());
+        }
+        workload.set_requires(RepeatedField::from_vec(self.requires.clone()));
+        workload.set_after_jobs(self.after.clone());
+        if let Some(url) = &self.notify_url {
+            workload.set_notify_url(url.clone());
+        }
+        if let Some(key) = &self.idempotency_key {
+            workload.set_idempotency_key(key.clone());
+        }
+        Ok(workload)
+    }
+}
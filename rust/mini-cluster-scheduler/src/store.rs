@@ -0,0 +1,336 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use protobuf::{Message, RepeatedField};
+use sqlx::{Executor, Row};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+
+use mini_cluster_worker::response::CachedResults;
+use mini_cluster_worker::transport::Address;
+use mini_cluster_worker::workload::Workload;
+
+use crate::err::{Result, SchedulerError, ErrKind};
+use crate::history::{EventKind, JobEvent};
+use crate::keys::ApiKey;
+use crate::queue::{JobState, ScheduledJob};
+use crate::scheduler::RegisteredWorker;
+
+// The scheduler keeps its jobs and its roster in memory, and writes them through to a SQLite
+// database of its own, so that a scheduler that restarts picks up where it left off. Only what
+// can't be worked out again is kept: a job's workload, state and results, the address of each
+// worker, the job history (see `history`), and the API keys (see `keys`). Timestamps (which are
+// `Instant`s) start afresh, as do speculative duplicates, which are simply sent again if the job
+// is still straggling.
+//
+// Jobs are written as they change, in batches (see `Scheduler::persist`), rather than on every
+// change, so that a busy scheduler doesn't spend its time waiting on the disk. A scheduler that
+// crashes can therefore lose the last moment's worth of changes; a job that was dispatched in
+// that moment is queued again on restart, and run twice.
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS jobs (
+        id INTEGER PRIMARY KEY,
+        workload BLOB NOT NULL,
+        state TEXT NOT NULL,
+        worker_id INTEGER NOT NULL,
+        worker_job_id INTEGER NOT NULL,
+        n_rows INTEGER NOT NULL,
+        error TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        runtime_ms INTEGER,
+        parent INTEGER,
+        partitions TEXT NOT NULL,
+        merge_statement TEXT NOT NULL,
+        results BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS workers (
+        id INTEGER PRIMARY KEY,
+        address TEXT NOT NULL,
+        capabilities TEXT NOT NULL,
+        cache_size INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS events (
+        job_id INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        at INTEGER NOT NULL,
+        principal TEXT NOT NULL,
+        worker_id INTEGER,
+        detail TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS api_keys (
+        id TEXT PRIMARY KEY,
+        principal TEXT NOT NULL,
+        role TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        hash TEXT NOT NULL
+    );";
+
+/// The scheduler's state database.
+pub struct Store {
+    pool: SqlitePool,
+}
+
+/// Flattens a job state into the columns of the `jobs` table: the state's name, the worker ID,
+/// the worker job ID, the number of result rows and the error message.
+fn encode_state(state: &JobState) -> (&'static str, u64, u64, u64, &str) {
+    match state {
+        JobState::Queued => ("queued", 0, 0, 0, ""),
+        JobState::Dispatched { worker_id, worker_job_id } => {
+            ("dispatched", *worker_id, *worker_job_id, 0, "")
+        },
+        JobState::Running { worker_id, worker_job_id } => {
+            ("running", *worker_id, *worker_job_id, 0, "")
+        },
+        JobState::Done { worker_id, worker_job_id, n_rows } => {
+            ("done", *worker_id, *worker_job_id, *n_rows, "")
+        },
+        JobState::Failed(message) => ("failed", 0, 0, 0, message.as_str()),
+        JobState::Cancelled => ("cancelled", 0, 0, 0, ""),
+    }
+}
+
+fn decode_state(
+    name: &str, worker_id: u64, worker_job_id: u64, n_rows: u64, error: String
+) -> Result<JobState> {
+    match name {
+        "queued" => Ok(JobState::Queued),
+        "dispatched" => Ok(JobState::Dispatched { worker_id, worker_job_id }),
+        "running" => Ok(JobState::Running { worker_id, worker_job_id }),
+        "done" => Ok(JobState::Done { worker_id, worker_job_id, n_rows }),
+        "failed" => Ok(JobState::Failed(error)),
+        "cancelled" => Ok(JobState::Cancelled),
+        _ => Err(SchedulerError::new(
+            ErrKind::ConfigError, &format!("Unknown job state {:?} in the state database.", name)
+        ))?,
+    }
+}
+
+/// Writes an address the way `Address::parse` reads it back.
+pub fn encode_address(address: &Address) -> String {
+    match address {
+        Address::Tcp(port) => port.to_string(),
+        Address::Host(..) => address.to_string(),
+        Address::Unix(path) => path.display().to_string(),
+    }
+}
+
+/// Splits a comma-separated list, e.g. of partition job IDs, ignoring empty items.
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').filter(|item| !item.is_empty())
+}
+
+impl Store {
+    /// Opens the database at `path`, creating it (and its tables) if it doesn't exist yet.
+    pub async fn open(path: &Path) -> Result<Store> {
+        let options = SqliteConnectOptions::new().filename(path).create_if_missing(true);
+        // Writes come from a single task, so there is no use for more than one connection.
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await?;
+        pool.execute(SCHEMA).await?;
+        Ok(Store { pool })
+    }
+
+    /// Writes the given jobs, replacing whatever was written for them before.
+    pub async fn save_jobs(&self, jobs: &[ScheduledJob]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for job in jobs {
+            let (state, worker_id, worker_job_id, n_rows, error) = encode_state(&job.state);
+            let mut results = CachedResults::new();
+            results.set_batches(RepeatedField::from_vec(job.results.clone()));
+            let partitions = job.partitions.iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            sqlx::query(
+                "INSERT OR REPLACE INTO jobs VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            )
+                .bind(job.id as i64)
+                .bind(job.workload.write_to_bytes()?)
+                .bind(state)
+                .bind(worker_id as i64)
+                .bind(worker_job_id as i64)
+                .bind(n_rows as i64)
+                .bind(error)
+                .bind(job.attempts as i64)
+                .bind(job.runtime.map(|runtime| runtime.as_millis() as i64))
+                .bind(job.parent.map(|parent| parent as i64))
+                .bind(partitions)
+                .bind(job.merge_statement.as_str())
+                .bind(results.write_to_bytes()?)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Reads every job back, ordered by job ID.
+    pub async fn load_jobs(&self) -> Result<Vec<ScheduledJob>> {
+        let rows = sqlx::query("SELECT * FROM jobs ORDER BY id").fetch_all(&self.pool).await?;
+        let mut jobs = vec![];
+        for row in rows {
+            let state = decode_state(
+                row.try_get("state")?,
+                row.try_get::<i64, _>("worker_id")? as u64,
+                row.try_get::<i64, _>("worker_job_id")? as u64,
+                row.try_get::<i64, _>("n_rows")? as u64,
+                row.try_get("error")?,
+            )?;
+            let partitions = split_list(row.try_get("partitions")?)
+                .map(|id| id.parse::<u64>())
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            let results = CachedResults::parse_from_bytes(row.try_get("results")?)?;
+            jobs.push(ScheduledJob {
+                id: row.try_get::<i64, _>("id")? as u64,
+                workload: Workload::parse_from_bytes(row.try_get("workload")?)?,
+                state,
+                submitted_at: Instant::now(),
+                attempts: row.try_get::<i64, _>("attempts")? as u32,
+                dispatched_at: None,
+                runtime: row.try_get::<Option<i64>, _>("runtime_ms")?
+                    .map(|ms| Duration::from_millis(ms as u64)),
+                backup: None,
+                partitions,
+                parent: row.try_get::<Option<i64>, _>("parent")?.map(|parent| parent as u64),
+                merge_statement: row.try_get("merge_statement")?,
+                results: results.get_batches().to_vec(),
+                // Results that were held when the scheduler went down are kept for another
+                // `results_ttl`, and count against their tenant's quota until they're fetched.
+                results_at: Some(Instant::now()).filter(|_| !results.get_batches().is_empty()),
+                fetched: false,
+                // Worked out again by `JobQueue::restore`.
+                notified: false,
+                progress: None,
+            });
+        }
+        Ok(jobs)
+    }
+
+    /// Writes the roster, replacing the one written before.
+    pub async fn save_workers(&self, workers: &[RegisteredWorker]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM workers").execute(&mut *tx).await?;
+        for worker in workers {
+            sqlx::query("INSERT INTO workers VALUES (?, ?, ?, ?)")
+                .bind(worker.id as i64)
+                .bind(encode_address(&worker.address))
+                .bind(worker.capabilities.join(","))
+                .bind(worker.cache_size as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Reads the roster back, ordered by worker ID. The workers start out dead, as nobody knows
+    /// whether they are still around; they come back to life with their first heartbeat.
+    pub async fn load_workers(&self) -> Result<Vec<RegisteredWorker>> {
+        let rows = sqlx::query("SELECT * FROM workers ORDER BY id").fetch_all(&self.pool).await?;
+        let mut workers = vec![];
+        for row in rows {
+            workers.push(RegisteredWorker {
+                id: row.try_get::<i64, _>("id")? as u64,
+                address: Address::parse(row.try_get("address")?),
+                capabilities: split_list(row.try_get("capabilities")?)
+                    .map(|capability| capability.to_owned())
+                    .collect(),
+                // Advertisements aren't written; they come back with the first heartbeat.
+                advertised: None,
+                cache_size: row.try_get::<i64, _>("cache_size")? as u64,
+                // Labels come from the cluster file, which is read again on restart.
+                labels: vec![],
+                registered_at: Instant::now(),
+                alive: false,
+                draining: false,
+                missed_beats: 0,
+                last_beat: Instant::now(),
+                queue_depth: 0,
+                running_jobs: 0,
+                // Like the advertisements, table statistics come back with the first heartbeat.
+                tables: vec![],
+                in_flight: vec![],
+            });
+        }
+        Ok(workers)
+    }
+
+    /// Appends events to the job history.
+    pub async fn save_events(&self, events: &[JobEvent]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for event in events {
+            sqlx::query("INSERT INTO events VALUES (?, ?, ?, ?, ?, ?)")
+                .bind(event.job_id as i64)
+                .bind(event.kind.name())
+                .bind(event.at as i64)
+                .bind(event.principal.as_str())
+                .bind(event.worker_id.map(|worker_id| worker_id as i64))
+                .bind(event.detail.as_str())
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Reads the job history back, oldest event first.
+    pub async fn load_events(&self) -> Result<Vec<JobEvent>> {
+        let rows = sqlx::query("SELECT * FROM events ORDER BY rowid")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut events = vec![];
+        for row in rows {
+            let kind: &str = row.try_get("kind")?;
+            let kind = EventKind::from_name(kind).ok_or_else(|| SchedulerError::new(
+                ErrKind::ConfigError,
+                &format!("Unknown event kind {:?} in the state database.", kind)
+            ))?;
+            events.push(JobEvent {
+                job_id: row.try_get::<i64, _>("job_id")? as u64,
+                kind,
+                at: row.try_get::<i64, _>("at")? as u64,
+                principal: row.try_get("principal")?,
+                worker_id: row.try_get::<Option<i64>, _>("worker_id")?
+                    .map(|worker_id| worker_id as u64),
+                detail: row.try_get("detail")?,
+            });
+        }
+        Ok(events)
+    }
+    /// Writes an API key. Keys come and go rarely enough that they're written straight away,
+    /// rather than in batches.
+    pub async fn save_api_key(&self, key: &ApiKey) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO api_keys VALUES (?, ?, ?, ?, ?)")
+            .bind(key.id.as_str())
+            .bind(key.principal.as_str())
+            .bind(key.role.name())
+            .bind(key.created_at as i64)
+            .bind(key.hash.as_str())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_api_key(&self, id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM api_keys WHERE id = ?").bind(id).execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Reads the API keys back.
+    pub async fn load_api_keys(&self) -> Result<Vec<ApiKey>> {
+        let rows = sqlx::query("SELECT * FROM api_keys").fetch_all(&self.pool).await?;
+        let mut keys = vec![];
+        for row in rows {
+            keys.push(ApiKey {
+                id: row.try_get("id")?,
+                principal: row.try_get("principal")?,
+                role: row.try_get::<&str, _>("role")?.parse()?,
+                created_at: row.try_get::<i64, _>("created_at")? as u64,
+                hash: row.try_get("hash")?,
+            });
+        }
+        Ok(keys)
+    }
+}
@@ -0,0 +1,63 @@
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use mini_cluster_worker::transport::Address;
+
+use crate::err::{Result, SchedulerError, ErrKind};
+
+// Workers usually register themselves with the scheduler (see `mini_cluster_worker::membership`),
+// but a fleet that is set up ahead of time (e.g. a fixed set of machines) can just as well be
+// written down in a cluster file, which the scheduler reads when it starts:
+//
+//     [[workers]]
+//     host = "worker-1.internal"
+//     port = 8080
+//     labels = ["gpu", "zone-a"]
+//
+//     [[workers]]
+//     port = 8081
+//
+// A worker with no `host` is on the scheduler's own machine. The workers listed are put on the
+// roster as dead, and come to life with their first heartbeat, just like workers restored out
+// of the state database. Workers that register themselves join them on the roster as usual; one
+// that registers from the address of a listed worker takes its place, labels and all.
+
+/// A worker, as listed in a cluster file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticWorker {
+    #[serde(default)]
+    pub host: Option<String>,
+    pub port: u16,
+    /// Free-form tags, e.g. the kind of machine the worker is on.
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+impl StaticWorker {
+    /// Where the scheduler can reach the worker.
+    pub fn address(&self) -> Address {
+        match &self.host {
+            Some(host) => Address::Host(host.clone(), self.port),
+            None => Address::Tcp(self.port),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClusterFile {
+    #[serde(default)]
+    workers: Vec<StaticWorker>,
+}
+
+/// Reads the workers listed in the cluster file at `path`.
+pub fn load(path: &Path) -> Result<Vec<StaticWorker>> {
+    let contents = fs::read_to_string(path)?;
+    let cluster: ClusterFile = toml::from_str(&contents).map_err(|err| SchedulerError::new(
+        ErrKind::ConfigError, &format!("Invalid cluster file {}: {}", path.display(), err)
+    ))?;
+    Ok(cluster.workers)
+}
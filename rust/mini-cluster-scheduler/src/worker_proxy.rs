@@ -1,24 +1,135 @@
 use std::fmt;
+use std::io;
 use std::option::Option;
+use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio::io::AsyncWriteExt;
+use tokio::time::timeout;
+
+use protobuf::Message;
+
+use mini_cluster_worker::auth::sign_nonce;
+use mini_cluster_worker::protocol::{self, write_frame, FrameHeader, FLAG_ZSTD};
+use mini_cluster_worker::response::{
+    Ack, CacheReport, Cancelled, ErrorResponse, ErrorResponse_Kind, JobProgress, Reloaded,
+    ResultBatch, Snapshot, WorkerStatus,
+};
+use mini_cluster_worker::tls::TlsClient;
+use mini_cluster_worker::transport::{Address, Stream};
+use mini_cluster_worker::workload::{
+    CacheCommand, CancelJob, FetchResults, File, Preload, ResumeJob, TakeSnapshot, Workload
+};
+use mini_cluster_worker::Worker;
 
 use crate::err::{Result, SchedulerError, ErrKind};
 
+/// How long the worker has to acknowledge a workload (see `WorkerProxy::send_workload`). The
+/// worker answers before it runs the workload, so this needn't allow for the job itself.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A frame the worker sent, with its payload parsed according to its signal (see
+/// `mini_cluster_worker::protocol`).
+#[derive(Debug)]
+pub enum WorkerResponse {
+    /// The challenge a worker with a shared secret opens every connection with.
+    Nonce(Vec<u8>),
+    Status(WorkerStatus),
+    Ack(Ack),
+    /// The worker is serving too many connections, and turned this one away.
+    Busy,
+    Error(ErrorResponse),
+    /// One batch of a job's results. The last one has `last` set.
+    Results(ResultBatch),
+    /// How far along a job whose results are being waited on is.
+    Progress(JobProgress),
+    Valid,
+    Preloaded,
+    /// Whether or not the job was cancelled.
+    Cancelled(bool),
+    /// Where the worker uploaded the snapshot of its database it was asked for.
+    Snapshotted(Snapshot),
+    /// What the worker has cached, or what it threw away, as it was asked.
+    CacheReport(CacheReport),
+    /// The names of the tunables that changed when the worker reloaded its configuration.
+    Reloaded(Vec<String>),
+    Stopped,
+    Draining,
+}
+
+impl WorkerResponse {
+    /// The name of the signal the response came with, e.g. for error messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            WorkerResponse::Nonce(_) => "NONCE",
+            WorkerResponse::Status(_) => "STATUS",
+            WorkerResponse::Ack(_) => "ACK",
+            WorkerResponse::Busy => "BUSY",
+            WorkerResponse::Error(_) => "ERROR",
+            WorkerResponse::Results(_) => "RESULTS",
+            WorkerResponse::Progress(_) => "PROGRESS",
+            WorkerResponse::Valid => "VALID",
+            WorkerResponse::Preloaded => "PRELOADED",
+            WorkerResponse::Cancelled(_) => "CANCELLED",
+            WorkerResponse::Snapshotted(_) => "SNAPSHOTTED",
+            WorkerResponse::CacheReport(_) => "CACHE_REPORT",
+            WorkerResponse::Reloaded(_) => "RELOADED",
+            WorkerResponse::Stopped => "STOPPED",
+            WorkerResponse::Draining => "DRAINING",
+        }
+    }
+}
+
+/// The error for a worker answering a request with something other than what it should have.
+fn unexpected(expected: &str, response: &WorkerResponse) -> SchedulerError {
+    SchedulerError::new(
+        ErrKind::NetworkError,
+        &format!("Expected {} from the worker, got {}.", expected, response.name()),
+    )
+}
+
 pub struct WorkerProxy {
-    pub port: u16,
-    pub connection: Option<TcpStream>,
+    /// Where the worker listens: a TCP port, on this machine or another one, or a Unix domain
+    /// socket path. `new` takes anything that converts into an `Address`, e.g. a `SocketAddr`.
+    pub address: Address,
+    pub connection: Option<Stream>,
+    /// Whether or not to zstd-compress the frames sent to the worker. The worker compresses its
+    /// responses to compressed requests, so this covers both directions.
+    pub compress: bool,
+    /// What to connect to the worker over TLS with, if it serves TLS (see
+    /// `mini_cluster_worker::tls`).
+    pub tls: Option<TlsClient>,
+    // Connections are sessions carrying many requests; each request gets its own ID, which the
+    // worker echoes back on the matching response.
+    next_request_id: u32,
 }
 
 impl fmt::Display for WorkerProxy {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "<WorkerProxy port:{}>", self.port.to_string())
+        write!(f, "<WorkerProxy {}>", self.address)
     }
 }
 
 impl WorkerProxy {
-    pub fn new(port: u16) -> WorkerProxy {
-        WorkerProxy { port, connection: Option::None }
+    pub fn new(address: impl Into<Address>) -> WorkerProxy {
+        WorkerProxy {
+            address: address.into(),
+            connection: Option::None,
+            compress: false,
+            tls: None,
+            next_request_id: 1,
+        }
+    }
+
+    /// A proxy for a worker running in this very process, already connected to it over an
+    /// in-memory pipe rather than the network (see `Worker::connect_in_process`). Everything
+    /// else works as it would against a remote worker, which makes for end-to-end tests that
+    /// don't have to hand out ports. The proxy goes by the address the worker listens on, so
+    /// calling `connect` again (e.g. after `close`) reaches the same worker over the network.
+    pub async fn in_process(worker: &Arc<Worker>) -> Result<WorkerProxy> {
+        let mut proxy = WorkerProxy::new(worker.address.clone());
+        proxy.connection = Some(worker.connect_in_process().await?);
+        Ok(proxy)
     }
 
     /// Connects to the remote worker process.
@@ -31,14 +142,352 @@ impl WorkerProxy {
         // not added until 2016 or so, resulting in this interesting syntactic quirk.
         //
         // Cf. https://stackoverflow.com/questions/25445761/returning-a-closure-from-a-function
-        let conn = TcpStream::connect(format!("localhost:{}", self.port)).await?;
+        let conn = match &self.tls {
+            Some(tls) => Stream::connect_tls(&self.address, tls).await?,
+            None => Stream::connect(&self.address).await?,
+        };
         self.connection = Some(conn);
         Ok(())
     }
     
+    fn get_connection(&mut self) -> Result<&mut Stream> {
+        let conn = self.connection.as_mut().ok_or_else(|| SchedulerError::new(
+            ErrKind::NetworkError,
+            "Cannot communicate over a connection that is not currently open.",
+        ))?;
+        Ok(conn)
+    }
+
+    fn flags(&self) -> u8 {
+        if self.compress { FLAG_ZSTD } else { 0 }
+    }
+
+    fn take_request_id(&mut self) -> u32 {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1).max(1);
+        request_id
+    }
+
+    /// Reads a single frame from the worker, returning its header and its payload. Frames from a
+    /// worker speaking a different protocol version, or whose payload fails its checksum, are
+    /// rejected.
+    pub async fn read_frame(&mut self) -> Result<(FrameHeader, Vec<u8>)> {
+        protocol::read_frame(self.get_connection()?).await
+    }
+
+    /// Reads a single frame from the worker and parses its payload according to its signal,
+    /// returning the ID of the request it answers along with it.
+    pub async fn read_response(&mut self) -> Result<(u32, WorkerResponse)> {
+        let (header, payload) = self.read_frame().await?;
+        let response = match header.signal {
+            protocol::NONCE => WorkerResponse::Nonce(payload),
+            protocol::STATUS => WorkerResponse::Status(WorkerStatus::parse_from_bytes(&payload)?),
+            protocol::ACK => WorkerResponse::Ack(Ack::parse_from_bytes(&payload)?),
+            protocol::BUSY => WorkerResponse::Busy,
+            protocol::ERROR => WorkerResponse::Error(ErrorResponse::parse_from_bytes(&payload)?),
+            protocol::RESULTS => {
+                WorkerResponse::Results(ResultBatch::parse_from_bytes(&payload)?)
+            },
+            protocol::PROGRESS => {
+                WorkerResponse::Progress(JobProgress::parse_from_bytes(&payload)?)
+            },
+            protocol::VALID => WorkerResponse::Valid,
+            protocol::PRELOADED => WorkerResponse::Preloaded,
+            protocol::CANCELLED => {
+                WorkerResponse::Cancelled(Cancelled::parse_from_bytes(&payload)?.get_cancelled())
+            },
+            protocol::SNAPSHOTTED => {
+                WorkerResponse::Snapshotted(Snapshot::parse_from_bytes(&payload)?)
+            },
+            protocol::CACHE_REPORT => {
+                WorkerResponse::CacheReport(CacheReport::parse_from_bytes(&payload)?)
+            },
+            protocol::RELOADED => WorkerResponse::Reloaded(
+                Reloaded::parse_from_bytes(&payload)?.take_changed().into_vec()
+            ),
+            protocol::STOPPED => WorkerResponse::Stopped,
+            protocol::DRAINING => WorkerResponse::Draining,
+            signal => Err(SchedulerError::new(
+                ErrKind::NetworkError,
+                &format!("Worker sent signal {}, which it never should.", signal),
+            ))?,
+        };
+        Ok((header.request_id, response))
+    }
+
+    /// Reads the worker's response to the given request. A worker turning the connection away
+    /// (BUSY) or reporting an error (ERROR) is returned as an error, so whatever comes back is
+    /// the response proper.
+    async fn expect_response(&mut self, request_id: u32) -> Result<WorkerResponse> {
+        let (response_id, response) = self.read_response().await?;
+        match response {
+            WorkerResponse::Busy => Err(SchedulerError::new(
+                ErrKind::NetworkError,
+                "Worker is serving too many connections and turned this one away.",
+            ))?,
+            // A draining worker turning work away is no fault of the work's, so it is treated
+            // like not reaching the worker at all: the job goes back on the queue.
+            WorkerResponse::Error(error) if error.get_kind() == ErrorResponse_Kind::DRAINING => {
+                Err(SchedulerError::new(ErrKind::NetworkError, error.get_message()))?
+            },
+            WorkerResponse::Error(error) => Err(SchedulerError::remote(
+                error.get_kind(),
+                &format!("{:?}: {}", error.get_kind(), error.get_message()),
+            ))?,
+            _ => {},
+        }
+        if response_id != request_id {
+            Err(SchedulerError::new(
+                ErrKind::NetworkError,
+                &format!(
+                    "Expected a response to request {} from the worker, got one to request {}.",
+                    request_id, response_id
+                ),
+            ))?
+        }
+        Ok(response)
+    }
+
+    /// Answers the worker's AUTH challenge. Must be called right after `connect` when the worker
+    /// is configured with a shared secret; the worker hangs up on clients that send it anything
+    /// else first.
+    pub async fn authenticate(&mut self, secret: &str) -> Result<()> {
+        let nonce = match self.expect_response(0).await? {
+            WorkerResponse::Nonce(nonce) => nonce,
+            other => Err(unexpected("NONCE", &other))?,
+        };
+        // The handshake happens before any compression is negotiated, so it is always sent plain.
+        write_frame(self.get_connection()?, protocol::AUTH, 0, 0, &sign_nonce(secret, &nonce))
+            .await?;
+        Ok(())
+    }
+
+    /// Pings the worker, returning its status (e.g. its job queue depth).
+    pub async fn ping(&mut self) -> Result<WorkerStatus> {
+        let request_id = self.take_request_id();
+        let flags = self.flags();
+        write_frame(self.get_connection()?, protocol::PING, request_id, flags, &[]).await?;
+        match self.expect_response(request_id).await? {
+            WorkerResponse::Status(status) => Ok(status),
+            other => Err(unexpected("STATUS", &other))?,
+        }
+    }
+
+    /// Submits a workload to the worker, returning the job ID the worker queued it under.
+    ///
+    /// The worker answers every workload it reads before it starts running it: with an ACK if it
+    /// queued the workload, and with an ERROR (a NACK) if it wouldn't take it. How this fails
+    /// says whether or not the worker could have the job:
+    ///
+    /// * A `NetworkError` means it doesn't: the workload never made it to the worker, the worker
+    ///   turned the connection away, or it hung up without answering (which it only does if it
+    ///   went away, taking its queue with it).
+    /// * A `RemoteError` means it doesn't either: the worker NACKed the workload.
+    /// * An `Unacknowledged` error means it may: the workload was sent, but no answer came back
+    ///   within `ACK_TIMEOUT`, or the connection broke off in some other way while waiting on
+    ///   one. Sending the workload again may run it twice.
+    pub async fn send_workload(&mut self, workload: &Workload) -> Result<u64> {
+        let request_id = self.take_request_id();
+        let workload_bytes = workload.write_to_bytes()?;
+        let flags = self.flags();
+        write_frame(self.get_connection()?, protocol::WORK, request_id, flags, &workload_bytes)
+            .await?;
+        let response = match timeout(ACK_TIMEOUT, self.expect_response(request_id)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(err)) => match err.downcast_ref::<io::Error>().map(|err| err.kind()) {
+                Some(io::ErrorKind::UnexpectedEof) | Some(io::ErrorKind::ConnectionReset) => {
+                    Err(SchedulerError::new(
+                        ErrKind::NetworkError,
+                        &format!("Worker hung up without answering the workload: {}", err)
+                    ))?
+                },
+                Some(_) => Err(SchedulerError::new(ErrKind::Unacknowledged, &err.to_string()))?,
+                None => Err(err)?,
+            },
+            Err(_) => Err(SchedulerError::new(
+                ErrKind::Unacknowledged,
+                &format!("Worker did not answer the workload within {:?}.", ACK_TIMEOUT)
+            ))?,
+        };
+        match response {
+            WorkerResponse::Ack(ack) => Ok(ack.get_job_id()),
+            other => Err(unexpected("ACK", &other))?,
+        }
+    }
+
+    /// Asks the worker to check a workload without running it. A workload that doesn't pass
+    /// comes back as a `RemoteError` naming the offending op.
+    pub async fn validate_workload(&mut self, workload: &Workload) -> Result<()> {
+        let request_id = self.take_request_id();
+        let workload_bytes = workload.write_to_bytes()?;
+        let flags = self.flags();
+        write_frame(
+            self.get_connection()?, protocol::VALIDATE, request_id, flags, &workload_bytes
+        ).await?;
+        match self.expect_response(request_id).await? {
+            WorkerResponse::Valid => Ok(()),
+            other => Err(unexpected("VALID", &other))?,
+        }
+    }
+
+    /// Has the worker load files into its database ahead of time, waiting until it has.
+    pub async fn preload(&mut self, files: &[File]) -> Result<()> {
+        let request_id = self.take_request_id();
+        let mut preload = Preload::new();
+        preload.set_files(files.to_vec().into());
+        let flags = self.flags();
+        write_frame(
+            self.get_connection()?, protocol::PRELOAD, request_id, flags,
+            &preload.write_to_bytes()?
+        ).await?;
+        match self.expect_response(request_id).await? {
+            WorkerResponse::Preloaded => Ok(()),
+            other => Err(unexpected("PRELOADED", &other))?,
+        }
+    }
+
+    /// Asks the worker to cancel a job, queued or running. Returns whether or not it was: a job
+    /// that already finished can't be.
+    pub async fn cancel(&mut self, job_id: u64) -> Result<bool> {
+        let request_id = self.take_request_id();
+        let mut cancel = CancelJob::new();
+        cancel.set_job_id(job_id);
+        let flags = self.flags();
+        write_frame(
+            self.get_connection()?, protocol::CANCEL, request_id, flags, &cancel.write_to_bytes()?
+        ).await?;
+        match self.expect_response(request_id).await? {
+            WorkerResponse::Cancelled(cancelled) => Ok(cancelled),
+            other => Err(unexpected("CANCELLED", &other))?,
+        }
+    }
+
+    /// Has the worker snapshot its database to the given S3 path, waiting until the snapshot is
+    /// uploaded. New workers can then start out from it (see `WORKER_RESTORE_SNAPSHOT`).
+    pub async fn snapshot(&mut self, path: &str) -> Result<Snapshot> {
+        let request_id = self.take_request_id();
+        let mut take = TakeSnapshot::new();
+        take.set_path(path.to_owned());
+        let flags = self.flags();
+        write_frame(
+            self.get_connection()?, protocol::SNAPSHOT, request_id, flags, &take.write_to_bytes()?
+        ).await?;
+        match self.expect_response(request_id).await? {
+            WorkerResponse::Snapshotted(snapshot) => Ok(snapshot),
+            other => Err(unexpected("SNAPSHOTTED", &other))?,
+        }
+    }
+
+    /// Has the worker list, evict, or clear what it has cached, waiting until it has. Evicting
+    /// and clearing wait for the worker's running jobs to finish first.
+    pub async fn cache(&mut self, command: &CacheCommand) -> Result<CacheReport> {
+        let request_id = self.take_request_id();
+        let flags = self.flags();
+        write_frame(
+            self.get_connection()?, protocol::CACHE, request_id, flags,
+            &command.write_to_bytes()?
+        ).await?;
+        match self.expect_response(request_id).await? {
+            WorkerResponse::CacheReport(report) => Ok(report),
+            other => Err(unexpected("CACHE_REPORT", &other))?,
+        }
+    }
+
+    /// Has the worker reload its configuration, returning the names of the tunables that
+    /// changed.
+    pub async fn reload(&mut self) -> Result<Vec<String>> {
+        let request_id = self.take_request_id();
+        let flags = self.flags();
+        write_frame(self.get_connection()?, protocol::RELOAD, request_id, flags, &[]).await?;
+        match self.expect_response(request_id).await? {
+            WorkerResponse::Reloaded(changed) => Ok(changed),
+            other => Err(unexpected("RELOADED", &other))?,
+        }
+    }
+
+    /// Asks the worker to pick a checkpointed job back up where it left off (see
+    /// `Workload.checkpoint`), returning the job ID the worker queued it under. A worker that has
+    /// no checkpoint under the key answers with a NOT_FOUND error.
+    pub async fn resume(&mut self, idempotency_key: &str) -> Result<u64> {
+        let request_id = self.take_request_id();
+        let mut resume = ResumeJob::new();
+        resume.set_idempotency_key(idempotency_key.to_owned());
+        let flags = self.flags();
+        write_frame(
+            self.get_connection()?, protocol::RESUME, request_id, flags, &resume.write_to_bytes()?
+        ).await?;
+        match self.expect_response(request_id).await? {
+            WorkerResponse::Ack(ack) => Ok(ack.get_job_id()),
+            other => Err(unexpected("ACK", &other))?,
+        }
+    }
+
+    /// Fetches the results of a job, waiting for the job to finish if needs be. The worker streams
+    /// the rows back in batches, which `on_batch` is called with as they arrive. Whilst waiting,
+    /// the worker says how far along the job is every so often, which `on_progress` is called
+    /// with.
+    pub async fn fetch_results<F: FnMut(ResultBatch), P: FnMut(JobProgress)>(
+        &mut self, job_id: u64, mut on_batch: F, mut on_progress: P
+    ) -> Result<()> {
+        let request_id = self.take_request_id();
+        let mut fetch = FetchResults::new();
+        fetch.set_job_id(job_id);
+        fetch.set_progress(true);
+        let flags = self.flags();
+        write_frame(
+            self.get_connection()?, protocol::FETCH, request_id, flags, &fetch.write_to_bytes()?
+        ).await?;
+        loop {
+            let batch = match self.expect_response(request_id).await? {
+                WorkerResponse::Results(batch) => batch,
+                WorkerResponse::Progress(progress) => {
+                    on_progress(progress);
+                    continue;
+                },
+                other => Err(unexpected("RESULTS", &other))?,
+            };
+            let last = batch.get_last();
+            on_batch(batch);
+            if last { return Ok(()); }
+        }
+    }
+
+    /// Has the worker process exit once it has drained, waiting until it says it will. The
+    /// connection is dropped, so there is no need to `close` it afterwards.
+    pub async fn stop(&mut self) -> Result<()> {
+        let request_id = self.take_request_id();
+        let flags = self.flags();
+        write_frame(self.get_connection()?, protocol::STOP, request_id, flags, &[]).await?;
+        match self.expect_response(request_id).await? {
+            WorkerResponse::Stopped => {},
+            other => Err(unexpected("STOPPED", &other))?,
+        }
+        self.connection = None;
+        Ok(())
+    }
+
+    /// Has the worker drain: finish the jobs it has, but turn away new ones.
+    pub async fn drain(&mut self) -> Result<()> {
+        let request_id = self.take_request_id();
+        let flags = self.flags();
+        write_frame(self.get_connection()?, protocol::DRAIN, request_id, flags, &[]).await?;
+        match self.expect_response(request_id).await? {
+            WorkerResponse::Draining => Ok(()),
+            other => Err(unexpected("DRAINING", &other))?,
+        }
+    }
+
+    /// Ends the session. The connection should be `close`d afterwards.
+    pub async fn end_session(&mut self) -> Result<()> {
+        let request_id = self.take_request_id();
+        let flags = self.flags();
+        write_frame(self.get_connection()?, protocol::SHUTDOWN, request_id, flags, &[]).await?;
+        Ok(())
+    }
+
     /// Closes the connection.
     pub async fn close(&mut self) -> Result<()> {
-        // Oddly enough, it doesn't appear to be possible to call `TcpStream.shutdown()` unless
+        // Oddly enough, it doesn't appear to be possible to call `shutdown()` on a stream unless
         // you use the `io-util` feature of `tokio`, which adds this function as part of the
         // `tokio::io::AsyncWriteExt` trait.
         //
@@ -54,4 +503,106 @@ impl WorkerProxy {
             .await?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use protobuf::RepeatedField;
+    use serial_test::serial;
+
+    use mini_cluster_worker::config::WorkerConfig;
+    use mini_cluster_worker::fixtures::{artifact_path, craft_op_message, craft_workload_message};
+    use mini_cluster_worker::tls::{ClientTls, Identity, ServerTls};
+
+    use super::*;
+
+    /// A workload selecting `x`. It's ephemeral, so that it doesn't touch the worker's database.
+    fn craft_select(x: i64) -> Workload {
+        let statement = format!("SELECT {} AS x", x);
+        let op = craft_op_message(Some(RepeatedField::new()), Some(statement), Some(1));
+        let mut workload = craft_workload_message(Some(RepeatedField::from_vec(vec![op])));
+        workload.set_ephemeral(true);
+        workload
+    }
+
+    /// Has the worker select `x`, and returns what comes back.
+    async fn select(proxy: &mut WorkerProxy, x: i64) -> i64 {
+        let job_id = proxy.send_workload(&craft_select(x)).await.unwrap();
+        let mut batches = vec![];
+        proxy.fetch_results(job_id, |batch| batches.push(batch), |_| {}).await.unwrap();
+        assert!(batches.last().unwrap().get_last());
+        let row = batches.iter().flat_map(|batch| batch.get_rows()).next().unwrap();
+        row.get_values()[0].get_integer()
+    }
+
+    // Serial with the chaos tests (see `scheduler`), whose faults go for the whole process.
+    #[tokio::test]
+    #[serial]
+    async fn test_in_process() {
+        let worker = Worker::new(Address::Tcp(0), WorkerConfig::default()).await.unwrap();
+        let worker = Arc::new(worker);
+        tokio::spawn(Arc::clone(&worker).listen());
+        let mut proxy = WorkerProxy::in_process(&worker).await.unwrap();
+        assert_eq!(select(&mut proxy, 1).await, 1);
+
+        // Reconnecting goes over the network, to the port the worker got.
+        assert_ne!(proxy.address, Address::Tcp(0));
+        proxy.end_session().await.unwrap();
+        proxy.close().await.unwrap();
+        proxy.connect().await.unwrap();
+        assert_eq!(select(&mut proxy, 2).await, 2);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_stop() {
+        let worker = Worker::new(Address::Tcp(0), WorkerConfig::default()).await.unwrap();
+        let worker = Arc::new(worker);
+        tokio::spawn(Arc::clone(&worker).listen());
+        let mut proxy = WorkerProxy::in_process(&worker).await.unwrap();
+        let job_id = proxy.send_workload(&craft_select(1)).await.unwrap();
+
+        // A worker that is told to stop drains first...
+        let mut stopping = WorkerProxy::in_process(&worker).await.unwrap();
+        stopping.stop().await.unwrap();
+        assert!(worker.is_draining());
+        assert!(proxy.send_workload(&craft_select(2)).await.is_err());
+        assert!(timeout(Duration::from_millis(200), worker.stopped()).await.is_err());
+
+        // ...and has only stopped once the results of its jobs have been read.
+        let mut batches = vec![];
+        proxy.fetch_results(job_id, |batch| batches.push(batch), |_| {}).await.unwrap();
+        assert!(batches.last().unwrap().get_last());
+        assert!(timeout(Duration::from_secs(30), worker.stopped()).await.is_ok());
+    }
+
+    /// One of the worker's test certificates (see `mini_cluster_worker::tls`).
+    fn identity(name: &str) -> Identity {
+        Identity {
+            cert: artifact_path(&format!("tls/{}.pem", name)),
+            key: artifact_path(&format!("tls/{}-key.pem", name)),
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_client_certificate() {
+        let config = WorkerConfig {
+            tls: Some(ServerTls {
+                identity: identity("worker"),
+                client_ca: Some(artifact_path("tls/ca.pem")),
+            }),
+            ..WorkerConfig::default()
+        };
+        let worker = Arc::new(Worker::new(Address::Tcp(0), config).await.unwrap());
+        tokio::spawn(Arc::clone(&worker).listen());
+        let tls = ClientTls {
+            ca: artifact_path("tls/ca.pem"),
+            identity: Some(identity("client")),
+            server_name: None,
+        };
+        let mut proxy = WorkerProxy::new(worker.address.clone());
+        proxy.tls = Some(tls.connector().unwrap());
+        proxy.connect().await.unwrap();
+        assert_eq!(select(&mut proxy, 1).await, 1);
+    }
+}
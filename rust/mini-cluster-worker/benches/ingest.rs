@@ -0,0 +1,50 @@
+/// Benchmarks loading CSV files into the database (`Table::dump`), in rows per second.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::Runtime;
+
+use mini_cluster_worker::db::{Database, Table};
+use mini_cluster_worker::fixtures::craft_csv_file;
+
+const ROW_COUNTS: &[usize] = &[1_000, 10_000, 100_000];
+
+fn craft_rows(n_rows: usize) -> Vec<Vec<String>> {
+    (0..n_rows)
+        .map(|i| vec![i.to_string(), format!("{}.5", i), format!("row {}", i)])
+        .collect()
+}
+
+fn bench_dump(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    // The table is loaded into an in-memory database, so that the benchmark measures parsing and
+    // inserting rows, rather than the disk (and doesn't touch the worker's own cache).
+    let db = rt.block_on(Database::new_in_memory()).unwrap();
+
+    let mut group = c.benchmark_group("dump");
+    group.sample_size(10);
+    for &n_rows in ROW_COUNTS {
+        let rows = craft_rows(n_rows);
+        let rows: Vec<Vec<&str>> = rows.iter()
+            .map(|row| row.iter().map(String::as_str).collect())
+            .collect();
+        let rows: Vec<&[&str]> = rows.iter().map(Vec::as_slice).collect();
+        let fp = craft_csv_file(
+            &format!("mini-cluster-bench-dump-{}.csv", n_rows),
+            &["a_int", "b_real", "c_text"],
+            &rows
+        );
+        let table = Table::new("bench", &fp).force_reload(true);
+
+        group.throughput(Throughput::Elements(n_rows as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n_rows), &table, |b, table| {
+            b.iter(|| rt.block_on(async {
+                let mut conn = db.connection().await.unwrap();
+                table.dump_into(&mut conn).await.unwrap();
+            }))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_dump);
+criterion_main!(benches);
@@ -0,0 +1,55 @@
+/// Benchmarks the latency of a job end to end: downloading its files (from a mock S3), loading
+/// them, and running its statement.
+
+use std::fs;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use protobuf::RepeatedField;
+use tokio::runtime::Runtime;
+
+use mini_cluster_worker::file::{WorkerS3ClientAdapter, WorkerS3ClientMock};
+use mini_cluster_worker::fixtures::*;
+use mini_cluster_worker::job::Job;
+
+fn bench_job(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let rows: Vec<Vec<String>> = (0..10_000)
+        .map(|i| vec![(i % 100).to_string(), format!("row {}", i)])
+        .collect();
+    let rows: Vec<Vec<&str>> = rows.iter()
+        .map(|row| row.iter().map(String::as_str).collect())
+        .collect();
+    let rows: Vec<&[&str]> = rows.iter().map(Vec::as_slice).collect();
+    let generated = craft_csv_file("mini-cluster-bench-job.csv", &["a_int", "d_text"], &rows);
+    let client = WorkerS3ClientMock::with_objects(vec![
+        ("s3://foo/simple.csv", fs::read(artifact_path("simple-csv.csv")).unwrap()),
+        ("s3://foo/generated.csv", fs::read(&generated).unwrap()),
+    ]);
+    let files = vec![
+        craft_file_message(Some(1), Some("s3://foo/simple.csv".to_owned())),
+        craft_file_message(Some(2), Some("s3://foo/generated.csv".to_owned())),
+    ];
+    let op = craft_op_message(
+        Some(RepeatedField::from_vec(files)),
+        Some("SELECT d, a + c FROM dataset_1 JOIN dataset_2 USING (a) ORDER BY d".to_owned()),
+        Some(1)
+    );
+    let mut workload = craft_workload_message(Some(RepeatedField::from_vec(vec![op])));
+    // Ephemeral jobs get an in-memory database of their own, so every iteration loads its tables
+    // from scratch.
+    workload.set_ephemeral(true);
+
+    let mut group = c.benchmark_group("job");
+    group.sample_size(10);
+    group.bench_function("build_and_run", |b| {
+        b.iter(|| rt.block_on(async {
+            let job = Job::new(workload.clone()).await.unwrap();
+            job.build(WorkerS3ClientAdapter::new(client.clone())).await.unwrap();
+            job.run(1, 1000, |_| {}).await.unwrap()
+        }))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_job);
+criterion_main!(benches);
@@ -0,0 +1,35 @@
+/// Benchmarks encoding frames (`craft_frame`) and decoding them (`read_frame`), with and without
+/// compression.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use mini_cluster_worker::protocol::{craft_frame, read_frame, FLAG_ZSTD, MAX_PAYLOAD_SIZE, WORK};
+
+const PAYLOAD_SIZES: &[usize] = &[64, 4 * 1024, MAX_PAYLOAD_SIZE];
+
+/// A payload that compresses about as well as a result batch does: repetitive, but not entirely.
+fn craft_payload(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i % 251) as u8 ^ (i / 1024) as u8).collect()
+}
+
+fn bench_frames(c: &mut Criterion) {
+    for &(name, flags) in &[("uncompressed", 0), ("zstd", FLAG_ZSTD)] {
+        let mut group = c.benchmark_group(format!("frame/{}", name));
+        for &size in PAYLOAD_SIZES {
+            let payload = craft_payload(size);
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(BenchmarkId::new("encode", size), &payload, |b, payload| {
+                b.iter(|| craft_frame(WORK, 1, flags, payload).unwrap())
+            });
+
+            let frame = craft_frame(WORK, 1, flags, &payload).unwrap();
+            group.bench_with_input(BenchmarkId::new("decode", size), &frame, |b, frame| {
+                b.iter(|| futures::executor::block_on(read_frame(&mut &frame[..])).unwrap())
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_frames);
+criterion_main!(benches);
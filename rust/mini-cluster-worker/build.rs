@@ -3,8 +3,14 @@ extern crate protobuf_codegen_pure;
 fn main() {
     protobuf_codegen_pure::Codegen::new()
     .out_dir("src/")
-    .inputs(&["../protos/workload.proto"])
+    .inputs(&["../protos/workload.proto", "../protos/response.proto", "../protos/cluster.proto"])
     .include("../protos/")
     .run()
     .expect("Codegen failed.");
+
+    // The gRPC service goes to `OUT_DIR`, where `tonic::include_proto!` picks it up.
+    tonic_build::configure()
+    .build_client(false)
+    .compile(&["../protos/service.proto"], &["../protos/"])
+    .expect("gRPC codegen failed.");
 }
@@ -0,0 +1,76 @@
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Size of the nonce the worker challenges clients with, in bytes.
+pub const NONCE_LENGTH: usize = 16;
+
+/// Generates a fresh random nonce. A new nonce is used for every connection, so that a captured
+/// AUTH frame cannot simply be replayed.
+pub fn generate_nonce() -> [u8; NONCE_LENGTH] {
+    let mut nonce = [0 as u8; NONCE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+fn craft_mac(secret: &str, nonce: &[u8]) -> HmacSha256 {
+    // HMAC accepts keys of any length, so `new_varkey` cannot actually fail here.
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes())
+        .expect("HMAC accepts keys of any length.");
+    mac.update(nonce);
+    mac
+}
+
+/// Computes the HMAC-SHA256 of the nonce, keyed on the shared secret. This is what the client
+/// sends back in its AUTH frame.
+pub fn sign_nonce(secret: &str, nonce: &[u8]) -> Vec<u8> {
+    craft_mac(secret, nonce).finalize().into_bytes().to_vec()
+}
+
+/// Checks a client's signature against the nonce it was sent. `verify` performs the comparison
+/// in constant time, so this doesn't leak how much of the signature was correct.
+pub fn verify_nonce(secret: &str, nonce: &[u8], signature: &[u8]) -> bool {
+    craft_mac(secret, nonce).verify(signature).is_ok()
+}
+
+/// Checks a secret presented by a client against the configured one, in constant time. This is
+/// used by transports which, unlike the raw TCP framing, have no room for a nonce handshake (e.g.
+/// gRPC, where the secret travels in request metadata, protected by TLS).
+///
+/// Both secrets are used to sign the same fixed message, and the signatures compared. This way the
+/// comparison is constant time even when the secrets differ in length.
+pub fn secrets_match(expected: &str, provided: &str) -> bool {
+    const CONTEXT: &[u8] = b"mini-cluster-secret";
+    verify_nonce(expected, CONTEXT, &sign_nonce(provided, CONTEXT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_nonce() {
+        let nonce = generate_nonce();
+        let signature = sign_nonce("hunter2", &nonce);
+        assert!(verify_nonce("hunter2", &nonce, &signature));
+
+        // Wrong secret.
+        assert!(!verify_nonce("hunter3", &nonce, &signature));
+
+        // Right secret, but a different nonce (e.g. a replayed AUTH frame).
+        let other_nonce = generate_nonce();
+        assert!(!verify_nonce("hunter2", &other_nonce, &signature));
+
+        // Garbage signature.
+        assert!(!verify_nonce("hunter2", &nonce, &[1, 2, 3]));
+    }
+
+    #[test]
+    fn test_secrets_match() {
+        assert!(secrets_match("hunter2", "hunter2"));
+        assert!(!secrets_match("hunter2", "hunter3"));
+        assert!(!secrets_match("hunter2", ""));
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::{HashMap, HashSet};
+
+use protobuf::{Message, RepeatedField};
+
+use crate::dag::schedule;
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::protocol::{craft_frame, WORK};
+use crate::workload::{File, Op, Workload};
+
+/// Builds a `Workload` one call at a time, instead of through the protobuf setters:
+///
+/// ```ignore
+/// let workload = Workload::builder()
+///     .file("s3://bucket/trips.csv")
+///     .op("SELECT date, COUNT(*) AS n FROM dataset_1 GROUP BY date")
+///     .op("SELECT * FROM dataset_1 ORDER BY fare DESC LIMIT 10")
+///     .returning_last()
+///     .build()?;
+/// ```
+///
+/// Files are given IDs in the order they are declared in (starting from 1), unless declared
+/// with `file_with_id`, and ops get sequence numbers the same way. Every op targets every file
+/// declared before it; a table that is already loaded isn't loaded again, so this costs nothing
+/// for the ops after the first. Calls that configure an op (e.g. `after`) apply to the op added
+/// last. Mistakes (e.g. two files with the same ID) are reported by `build`.
+#[derive(Debug, Clone, Default)]
+pub struct WorkloadBuilder {
+    workload: Workload,
+    files: Vec<File>,
+    ops: Vec<Op>,
+}
+
+impl Workload {
+    pub fn builder() -> WorkloadBuilder {
+        WorkloadBuilder::default()
+    }
+}
+
+impl WorkloadBuilder {
+    /// Declares a file, with the next free file ID.
+    pub fn file(self, path: &str) -> WorkloadBuilder {
+        let id = self.files.iter().map(|file| file.get_id()).max().unwrap_or(0) + 1;
+        self.file_with_id(id, path)
+    }
+
+    /// Declares a file with the given file ID, i.e. one that is loaded into `dataset_<id>`.
+    pub fn file_with_id(mut self, id: i32, path: &str) -> WorkloadBuilder {
+        let mut file = File::new();
+        file.set_id(id);
+        file.set_path(path.to_owned());
+        self.files.push(file);
+        self
+    }
+
+    /// Sets the cell values to load as NULL for the file declared last.
+    pub fn null_tokens(mut self, tokens: &[&str]) -> WorkloadBuilder {
+        if let Some(file) = self.files.last_mut() {
+            let tokens = tokens.iter().map(|token| token.to_string()).collect();
+            file.set_null_tokens(RepeatedField::from_vec(tokens));
+        }
+        self
+    }
+
+    /// Reads the file declared last from a requester-pays bucket.
+    pub fn requester_pays(mut self) -> WorkloadBuilder {
+        if let Some(file) = self.files.last_mut() {
+            file.set_requester_pays(true);
+        }
+        self
+    }
+
+    /// Sets the key the file declared last was encrypted with, if it was encrypted with a
+    /// customer-provided key (SSE-C), base64-encoded.
+    pub fn sse_customer_key(mut self, key: &str) -> WorkloadBuilder {
+        if let Some(file) = self.files.last_mut() {
+            file.set_sse_customer_key(key.to_owned());
+        }
+        self
+    }
+
+    /// Adds an op, with the next free sequence number.
+    pub fn op(self, statement: &str) -> WorkloadBuilder {
+        let n = self.ops.iter().map(|op| op.get_op_sequence_num()).max().unwrap_or(0) + 1;
+        self.op_with_sequence_num(n, statement)
+    }
+
+    /// Adds an op with the given sequence number.
+    pub fn op_with_sequence_num(mut self, n: i32, statement: &str) -> WorkloadBuilder {
+        let mut op = Op::new();
+        op.set_statement(statement.to_owned());
+        op.set_op_sequence_num(n);
+        op.set_targets(RepeatedField::from_vec(self.files.clone()));
+        self.ops.push(op);
+        self
+    }
+
+    /// Makes the op added last run after the ops with the given sequence numbers.
+    pub fn after(mut self, dependencies: &[i32]) -> WorkloadBuilder {
+        if let Some(op) = self.ops.last_mut() {
+            op.mut_depends_on().extend_from_slice(dependencies);
+        }
+        self
+    }
+
+    /// Has the op added last send back its result set, even if it isn't a sink.
+    pub fn returning_last(mut self) -> WorkloadBuilder {
+        if let Some(op) = self.ops.last_mut() {
+            op.set_return_result(true);
+        }
+        self
+    }
+
+    /// Has every op added so far send back its result set.
+    pub fn returning_all(mut self) -> WorkloadBuilder {
+        for op in self.ops.iter_mut() {
+            op.set_return_result(true);
+        }
+        self
+    }
+
+    /// Caps the number of result rows (see `Workload.max_result_rows`).
+    pub fn max_rows(mut self, max_rows: u64) -> WorkloadBuilder {
+        self.workload.set_max_result_rows(max_rows);
+        self
+    }
+
+    /// Caps the size of the result set, in bytes (see `Workload.max_result_bytes`).
+    pub fn max_bytes(mut self, max_bytes: u64) -> WorkloadBuilder {
+        self.workload.set_max_result_bytes(max_bytes);
+        self
+    }
+
+    /// Cuts the result set short when it hits a cap, rather than failing the job.
+    pub fn truncate(mut self) -> WorkloadBuilder {
+        self.workload.set_truncate_results(true);
+        self
+    }
+
+    pub fn use_result_cache(mut self) -> WorkloadBuilder {
+        self.workload.set_use_result_cache(true);
+        self
+    }
+
+    pub fn ephemeral(mut self) -> WorkloadBuilder {
+        self.workload.set_ephemeral(true);
+        self
+    }
+
+    pub fn force_reload(mut self) -> WorkloadBuilder {
+        self.workload.set_force_reload(true);
+        self
+    }
+
+    pub fn dry_run(mut self) -> WorkloadBuilder {
+        self.workload.set_dry_run(true);
+        self
+    }
+
+    pub fn explain(mut self) -> WorkloadBuilder {
+        self.workload.set_explain(true);
+        self
+    }
+
+    pub fn priority(mut self, priority: i32) -> WorkloadBuilder {
+        self.workload.set_priority(priority);
+        self
+    }
+
+    /// Has the scheduler hold the workload until the scheduler jobs with the given IDs are done
+    /// (see `Workload.after_jobs`).
+    pub fn after_jobs(mut self, job_ids: &[u64]) -> WorkloadBuilder {
+        self.workload.mut_after_jobs().extend_from_slice(job_ids);
+        self
+    }
+
+    /// Has the scheduler POST to the given URL once the job finishes (see
+    /// `Workload.notify_url`).
+    pub fn notify_url(mut self, url: &str) -> WorkloadBuilder {
+        self.workload.set_notify_url(url.to_owned());
+        self
+    }
+
+    /// Says who the workload is submitted by, to a scheduler (see `Workload.principal`).
+    pub fn principal(mut self, principal: &str) -> WorkloadBuilder {
+        self.workload.set_principal(principal.to_owned());
+        self
+    }
+
+    /// Has the workload run only once, however many times it is submitted under the given key
+    /// (see `Workload.idempotency_key`).
+    pub fn idempotency_key(mut self, key: &str) -> WorkloadBuilder {
+        self.workload.set_idempotency_key(key.to_owned());
+        self
+    }
+
+    /// Has the workload keep a checkpoint, so that it can be resumed where it left off if its
+    /// worker goes away part-way through (see `Workload.checkpoint`).
+    pub fn checkpoint(mut self) -> WorkloadBuilder {
+        self.workload.set_checkpoint(true);
+        self
+    }
+
+    /// Sets the database engine to run the ops on (see `Workload.engine`).
+    pub fn engine(mut self, engine: &str) -> WorkloadBuilder {
+        self.workload.set_engine(engine.to_owned());
+        self
+    }
+
+    /// Adds a part of the SQL surface the ops need, e.g. `json1` (see `Workload.requires`).
+    pub fn requires(mut self, capability: &str) -> WorkloadBuilder {
+        self.workload.mut_requires().push(capability.to_owned());
+        self
+    }
+
+    /// Checks the file IDs and the op sequence numbers (and the dependencies between the ops),
+    /// and returns the workload.
+    pub fn build(self) -> Result<Workload> {
+        if self.ops.is_empty() {
+            Err(WorkerError::new(ErrKind::ValidationError, "Workload has no ops."))?
+        }
+        let mut paths: HashMap<i32, &str> = HashMap::new();
+        for file in self.files.iter() {
+            if file.get_id() < 1 {
+                Err(WorkerError::new(
+                    ErrKind::ValidationError,
+                    &format!(
+                        "File {} has ID {}, but IDs start at 1.", file.get_path(), file.get_id()
+                    )
+                ))?
+            }
+            if let Some(other) = paths.insert(file.get_id(), file.get_path()) {
+                Err(WorkerError::new(
+                    ErrKind::ValidationError,
+                    &format!(
+                        "Files {} and {} both have ID {}.", other, file.get_path(), file.get_id()
+                    )
+                ))?
+            }
+        }
+        let mut sequence_nums = HashSet::new();
+        for op in self.ops.iter() {
+            let n = op.get_op_sequence_num();
+            if n < 1 {
+                Err(WorkerError::new(
+                    ErrKind::ValidationError,
+                    &format!("Op has sequence number {}, but sequence numbers start at 1.", n)
+                ))?
+            }
+            if !sequence_nums.insert(n) {
+                Err(WorkerError::new(
+                    ErrKind::ValidationError,
+                    &format!("More than one op has sequence number {}.", n)
+                ))?
+            }
+        }
+        // This catches dependencies on ops that don't exist, and dependency cycles.
+        schedule(&self.ops)?;
+
+        let mut workload = self.workload;
+        workload.set_ops(RepeatedField::from_vec(self.ops));
+        Ok(workload)
+    }
+
+    /// Builds the workload, and wraps it in a WORK frame, ready to be sent to a worker.
+    pub fn frame(self, request_id: u32) -> Result<Vec<u8>> {
+        let workload = self.build()?;
+        craft_frame(WORK, request_id, 0, &workload.write_to_bytes()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{FrameHeader, HEADER_LENGTH};
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let workload = Workload::builder()
+            .file("s3://foo/bar.csv")
+            .null_tokens(&["NA"])
+            .requester_pays()
+            .op("SELECT COUNT(*) FROM dataset_1")
+            .file("s3://foo/baz.csv")
+            .op("SELECT * FROM dataset_1 JOIN dataset_2 USING (id)")
+            .returning_last()
+            .max_rows(10)
+            .after_jobs(&[3, 4])
+            .principal("alice")
+            .idempotency_key("job-1")
+            .checkpoint()
+            .requires("json1")
+            .build()
+            .unwrap();
+        let ops = workload.get_ops();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].get_op_sequence_num(), 1);
+        assert_eq!(ops[0].get_targets().len(), 1);
+        assert_eq!(ops[0].get_targets()[0].get_null_tokens().to_vec(), vec!["NA".to_owned()]);
+        assert!(ops[0].get_targets()[0].get_requester_pays());
+        assert!(!ops[1].get_targets()[1].get_requester_pays());
+        assert_eq!(ops[1].get_op_sequence_num(), 2);
+        assert_eq!(
+            ops[1].get_targets().iter().map(|file| file.get_id()).collect::<Vec<_>>(), vec![1, 2]
+        );
+        assert!(!ops[0].get_return_result());
+        assert!(ops[1].get_return_result());
+        assert_eq!(workload.get_max_result_rows(), 10);
+        assert_eq!(workload.get_after_jobs(), &[3, 4]);
+        assert_eq!(workload.get_principal(), "alice");
+        assert_eq!(workload.get_idempotency_key(), "job-1");
+        assert!(workload.get_checkpoint());
+        assert_eq!(workload.get_requires().to_vec(), vec!["json1".to_owned()]);
+    }
+
+    #[test]
+    fn test_builder_validates() {
+        // No ops.
+        assert!(Workload::builder().file("s3://foo/bar.csv").build().is_err());
+        // Clashing file IDs.
+        let builder = Workload::builder()
+            .file_with_id(1, "s3://foo/bar.csv")
+            .file_with_id(1, "s3://foo/baz.csv")
+            .op("SELECT 1");
+        assert!(builder.build().is_err());
+        // Clashing sequence numbers.
+        let builder = Workload::builder()
+            .op_with_sequence_num(1, "SELECT 1")
+            .op_with_sequence_num(1, "SELECT 2");
+        assert!(builder.build().is_err());
+        // A dependency on an op that doesn't exist.
+        assert!(Workload::builder().op("SELECT 1").after(&[5]).build().is_err());
+    }
+
+    #[test]
+    fn test_builder_frame() {
+        let frame = Workload::builder().op("SELECT 1").frame(7).unwrap();
+        let mut header_bytes = [0; HEADER_LENGTH];
+        header_bytes.copy_from_slice(&frame[..HEADER_LENGTH]);
+        let header = FrameHeader::from_bytes(header_bytes);
+        assert_eq!(header.signal, WORK);
+        assert_eq!(header.request_id, 7);
+        let workload = Workload::parse_from_bytes(&frame[HEADER_LENGTH..]).unwrap();
+        assert_eq!(workload.get_ops()[0].get_statement(), "SELECT 1");
+    }
+}
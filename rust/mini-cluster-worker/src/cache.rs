@@ -0,0 +1,110 @@
+use std::fs;
+use std::path::PathBuf;
+
+use protobuf::{Message, RepeatedField};
+use sha2::{Digest, Sha256};
+
+use crate::encryption::{read_cached, write_cached};
+use crate::err::Result;
+use crate::file::{
+    get_cache_dir, get_workload_files, with_suffix, WorkerS3ClientAdapter, WorkerS3ClientTrait,
+};
+use crate::response::{CachedResults, ResultBatch};
+use crate::workload::Workload;
+
+// The result cache. Interactive users tend to re-run the same query over and over again, and
+// there's no sense in downloading the same files and running the same SQL every time. So a
+// workload can opt into having its results stored on disk, keyed on what went into them: the
+// workload itself (statements, limits, and all) and the ETags of its input files. If any input
+// file changes, so does its ETag, and with it the key, so stale results are never served.
+
+/// Returns the directory cached results are stored in. S3 bucket names cannot contain
+/// underscores, so this can't collide with a bucket's cache directory.
+pub fn get_result_cache_dir() -> PathBuf {
+    get_cache_dir().join("_results")
+}
+
+/// Computes the result cache key for a workload. This costs one (cheap) S3 HEAD request per input
+/// file.
+pub async fn result_cache_key<T: WorkerS3ClientTrait>(
+    workload: &Workload, client: &WorkerS3ClientAdapter<T>
+) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(&workload.write_to_bytes()?);
+    for file in get_workload_files(workload) {
+        let etag = client.for_file(file).get_etag(file.get_path()).await?;
+        // Every field is followed by a separator, so that e.g. the paths `ab` + `c` and `a` + `bc`
+        // don't hash the same.
+        hasher.update(&file.get_id().to_be_bytes());
+        hasher.update(file.get_path().as_bytes());
+        hasher.update(b"\0");
+        hasher.update(etag.as_bytes());
+        hasher.update(b"\0");
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Reads a workload's results out of the cache, or returns `None` if they aren't in it.
+pub fn read_cached_results(key: &str) -> Result<Option<Vec<ResultBatch>>> {
+    let path = get_result_cache_dir().join(key);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut cached = CachedResults::parse_from_bytes(&read_cached(&path)?)?;
+    Ok(Some(cached.take_batches().into_vec()))
+}
+
+/// Writes a workload's results to the cache.
+pub fn write_cached_results(key: &str, batches: &[ResultBatch]) -> Result<()> {
+    let cache_dir = get_result_cache_dir();
+    fs::create_dir_all(&cache_dir)?;
+    let mut cached = CachedResults::new();
+    cached.set_batches(RepeatedField::from_vec(batches.to_vec()));
+
+    // Write to a temporary file and then move it into place, so that a job reading the cache at
+    // the same time never sees a half-written file.
+    let path = cache_dir.join(key);
+    let tmp_path = with_suffix(&path, ".tmp");
+    write_cached(&tmp_path, &cached.write_to_bytes()?)?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use crate::file::WorkerS3ClientMock;
+    use crate::fixtures::*;
+    use super::*;
+
+    #[test]
+    fn test_result_cache_key() {
+        let client = WorkerS3ClientAdapter::new(WorkerS3ClientMock::default());
+        let craft_workload = |statement: &str| {
+            let file = craft_file_message(Some(1), Some("s3://foo/bar".to_owned()));
+            let op = craft_op_message(
+                Some(RepeatedField::from_vec(vec![file])), Some(statement.to_owned()), None
+            );
+            craft_workload_message(Some(RepeatedField::from_vec(vec![op])))
+        };
+
+        let key = block_on(result_cache_key(&craft_workload("SELECT 1"), &client)).unwrap();
+        let same_key = block_on(result_cache_key(&craft_workload("SELECT 1"), &client)).unwrap();
+        let other_key = block_on(result_cache_key(&craft_workload("SELECT 2"), &client)).unwrap();
+        assert_eq!(key, same_key);
+        assert_ne!(key, other_key);
+    }
+
+    #[test]
+    fn test_cached_results_round_trip() {
+        let key = "test_cached_results_round_trip";
+        let mut batch = ResultBatch::new();
+        batch.set_columns(RepeatedField::from_vec(vec!["a".to_owned()]));
+        batch.set_last(true);
+
+        write_cached_results(key, &[batch.clone()]).unwrap();
+        assert_eq!(read_cached_results(key).unwrap(), Some(vec![batch]));
+        assert_eq!(read_cached_results("no_such_key").unwrap(), None);
+    }
+}
@@ -0,0 +1,188 @@
+use protobuf::{Message, RepeatedField};
+
+use crate::config::WorkerConfig;
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::manifest::is_manifest;
+use crate::presigned::without_query;
+use crate::protocol::{MAX_PAYLOAD_SIZE, PROTOCOL_VERSION};
+use crate::response::WorkerCapabilities;
+use crate::sandbox::StatementClass;
+use crate::workload::Workload;
+
+/// The file formats workers can load.
+pub const FORMATS: [&str; 1] = ["csv"];
+
+/// The database engines workers can run ops on. The first one is the default.
+pub const ENGINES: [&str; 1] = ["sqlite"];
+
+// Capability advertisement. Not every worker can run every workload: a worker may have been
+// configured without a custom function an op calls, or restricted to read queries, and some
+// workloads are out of every worker's reach (e.g. ones reading Parquet files). Until now, the
+// only way to find out was to send the workload and see it fail.
+//
+// So every worker advertises what it can do (a `WorkerCapabilities`), when it registers and in
+// every `WorkerStatus`: the file formats and database engines it supports, the protocol versions
+// it speaks, the extensions available to op statements (see `functions::capabilities`), and its
+// limits. The scheduler checks workloads against it (`check_workload`) before sending them to the
+// worker, and turns away workloads that none of its workers can run. Workers check workloads
+// against their own advertisement too, so a workload sent straight to a worker is turned away the
+// same way.
+
+/// The format of the file at `path`, going by its extension. Files without a known extension
+/// are taken to be CSV, as they always have been.
+pub fn file_format(path: &str) -> &'static str {
+    let path = without_query(path);
+    let name = path.split('/').last().unwrap_or(path);
+    let extension = match name.rfind('.') {
+        Some(i) => name[i + 1..].to_lowercase(),
+        None => return "csv",
+    };
+    match extension.as_str() {
+        "parquet" => "parquet",
+        "json" | "jsonl" | "ndjson" => "json",
+        _ => "csv",
+    }
+}
+
+/// Describes what a worker with the given config can do. `extensions` are the optional parts of
+/// the SQL surface available to its op statements (see `Worker.capabilities`).
+pub fn advertise(config: &WorkerConfig, extensions: &[String]) -> WorkerCapabilities {
+    let strings = |values: &[&str]| -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    };
+    let mut advertised = WorkerCapabilities::new();
+    advertised.set_formats(RepeatedField::from_vec(strings(&FORMATS)));
+    advertised.set_engines(RepeatedField::from_vec(strings(&ENGINES)));
+    advertised.set_protocol_versions(vec![PROTOCOL_VERSION as u32]);
+    advertised.set_extensions(RepeatedField::from_vec(extensions.to_vec()));
+    if let Some(allowed) = &config.allowed_statements {
+        let names = allowed.iter().map(|class| class.name()).collect::<Vec<_>>();
+        advertised.set_allowed_statements(RepeatedField::from_vec(strings(&names)));
+    }
+    advertised.set_max_payload_size(MAX_PAYLOAD_SIZE as u64);
+    advertised.set_executors(config.executors as u32);
+    advertised.set_memory_ceiling(config.memory_ceiling.unwrap_or(0));
+    advertised.set_disk_quota(config.disk_limits.quota.unwrap_or(0));
+    advertised
+}
+
+/// The statement classes an advertisement restricts ops to, if it does. Names this worker
+/// doesn't know are left out.
+pub fn allowed_statements(advertised: &WorkerCapabilities) -> Option<Vec<StatementClass>> {
+    if advertised.get_allowed_statements().is_empty() {
+        return None;
+    }
+    let allowed = advertised.get_allowed_statements().iter()
+        .filter_map(|name| StatementClass::from_name(name).ok())
+        .collect();
+    Some(allowed)
+}
+
+/// Checks that a worker that advertised `advertised` can run a workload: that it speaks our
+/// protocol version, supports the workload's engine and the formats of all of its files, has
+/// every extension the workload requires, and that the workload fits in a frame. Statement
+/// classes are left to `sandbox::validate_workload` (see `allowed_statements`).
+pub fn check_workload(workload: &Workload, advertised: &WorkerCapabilities) -> Result<()> {
+    let versions = advertised.get_protocol_versions();
+    if !versions.is_empty() && !versions.contains(&(PROTOCOL_VERSION as u32)) {
+        Err(WorkerError::new(
+            ErrKind::ValidationError,
+            &format!(
+                "The worker speaks protocol versions {:?}, not version {}.",
+                versions, PROTOCOL_VERSION
+            )
+        ))?
+    }
+    let engine = match workload.get_engine() {
+        "" => ENGINES[0],
+        engine => engine,
+    };
+    if !advertised.get_engines().iter().any(|supported| supported == engine) {
+        Err(WorkerError::new(
+            ErrKind::ValidationError,
+            &format!("The worker doesn't support the {:?} engine.", engine)
+        ))?
+    }
+    for op in workload.get_ops() {
+        for file in op.get_targets() {
+            // Manifests say what format their objects are in, and are checked when they're read.
+            if is_manifest(file.get_path()) {
+                continue;
+            }
+            let format = file_format(file.get_path());
+            if !advertised.get_formats().iter().any(|supported| supported == format) {
+                Err(WorkerError::new(
+                    ErrKind::ValidationError,
+                    &format!("The worker can't load file {}, which is {}.", file.get_path(), format)
+                ))?
+            }
+        }
+    }
+    for required in workload.get_requires() {
+        if !advertised.get_extensions().contains(required) {
+            Err(WorkerError::new(
+                ErrKind::ValidationError, &format!("The worker doesn't have {:?}.", required)
+            ))?
+        }
+    }
+    let size = workload.compute_size() as u64;
+    let max_size = advertised.get_max_payload_size();
+    if max_size > 0 && size > max_size {
+        Err(WorkerError::new(
+            ErrKind::ValidationError,
+            &format!("The workload is {} bytes, but the worker reads at most {}.", size, max_size)
+        ))?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::SANDBOX_STATEMENTS;
+
+    #[test]
+    fn test_file_format() {
+        assert_eq!(file_format("s3://foo/bar.csv"), "csv");
+        assert_eq!(file_format("s3://foo/bar"), "csv");
+        assert_eq!(file_format("s3://foo.bar/baz"), "csv");
+        assert_eq!(file_format("s3://foo/bar.PARQUET"), "parquet");
+        assert_eq!(file_format("s3://foo/bar.jsonl"), "json");
+        let url = "https://foo.s3.amazonaws.com/bar.parquet?X-Amz-Signature=a.b";
+        assert_eq!(file_format(url), "parquet");
+    }
+
+    #[test]
+    fn test_check_workload() {
+        let mut config = WorkerConfig::default();
+        config.allowed_statements = Some(SANDBOX_STATEMENTS.to_vec());
+        let advertised = advertise(&config, &["json1".to_owned()]);
+        assert_eq!(allowed_statements(&advertised), Some(SANDBOX_STATEMENTS.to_vec()));
+
+        let workload = Workload::builder()
+            .file("s3://foo/bar.csv")
+            .op("SELECT json_extract(a, '$.b') FROM dataset_1")
+            .requires("json1")
+            .build()
+            .unwrap();
+        assert!(check_workload(&workload, &advertised).is_ok());
+
+        // Every file has to be in a format the worker can load...
+        let parquet = Workload::builder()
+            .file("s3://foo/bar.parquet")
+            .op("SELECT * FROM dataset_1")
+            .build()
+            .unwrap();
+        assert!(check_workload(&parquet, &advertised).is_err());
+        // ...the engine one the worker has...
+        let duckdb = Workload::builder().op("SELECT 1").engine("duckdb").build().unwrap();
+        assert!(check_workload(&duckdb, &advertised).is_err());
+        // ...and every extension it requires too.
+        let regex = Workload::builder()
+            .op("SELECT regex_match('a', 'b')")
+            .requires("function:regex_match")
+            .build()
+            .unwrap();
+        assert!(check_workload(&regex, &advertised).is_err());
+    }
+}
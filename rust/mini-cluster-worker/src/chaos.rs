@@ -0,0 +1,377 @@
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::RwLock;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use rand::Rng;
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CreateMultipartUploadRequest,
+    GetObjectRequest, HeadObjectRequest, ListObjectsV2Request, PutObjectRequest,
+    UploadPartRequest,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::file::{ObjectHead, WorkerS3ClientTrait};
+
+// Fault injection (the `chaos` feature). The scheduler retries jobs whose worker went away, gives
+// up on workers that miss their heartbeats, and resumes jobs from their checkpoints, but none of
+// that happens unless something goes wrong, which on a developer's machine it seldom does.
+//
+// Built with the `chaos` feature, the worker can be made to go wrong on purpose, at random: its
+// connections (see `transport`) are slowed down or dropped, checking a connection out of the
+// database pool fails, and S3 requests are slowed down or turned away with a 503, the way S3
+// does when it's asked for too much at once. The worker's S3 clients go through `ChaosS3Client`
+// (see `file::with_s3_faults`), which can wrap any other client too, e.g. the mock.
+//
+// How often each of these happens is set with `inject`, and goes for the whole process. Nothing
+// happens until it's called, so the feature can be turned on for a whole test run, and only the
+// tests that want faults get them. The feature is meant for tests only: never ship a worker
+// built with it.
+
+/// How often each kind of fault is injected. The rates are chances, from 0 (never) to 1 (every
+/// time), and apply to each operation on their own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Faults {
+    /// How often an operation is held up before it goes ahead.
+    pub delay_rate: f64,
+    /// The longest an operation is held up for. Each delay is anywhere up to this long.
+    pub max_delay: Duration,
+    /// How often a read or a write on a connection fails as if the other end had hung up. A
+    /// connection that was dropped stays dropped.
+    pub drop_rate: f64,
+    /// How often an S3 request fails with a 503.
+    pub s3_error_rate: f64,
+    /// How often checking a connection out of the database fails.
+    pub db_error_rate: f64,
+}
+
+impl Default for Faults {
+    fn default() -> Faults {
+        Faults {
+            delay_rate: 0.0,
+            max_delay: Duration::from_millis(0),
+            drop_rate: 0.0,
+            s3_error_rate: 0.0,
+            db_error_rate: 0.0,
+        }
+    }
+}
+
+impl Faults {
+    /// Errors out if any of the rates isn't a chance.
+    pub fn validate(&self) -> Result<()> {
+        let rates = [
+            ("delay_rate", self.delay_rate),
+            ("drop_rate", self.drop_rate),
+            ("s3_error_rate", self.s3_error_rate),
+            ("db_error_rate", self.db_error_rate),
+        ];
+        for (name, rate) in rates.iter() {
+            if !(0.0..=1.0).contains(rate) {
+                Err(WorkerError::new(
+                    ErrKind::ValidationError,
+                    &format!("Fault rate {} is {}, but it has to be between 0 and 1.", name, rate)
+                ))?
+            }
+        }
+        Ok(())
+    }
+
+    /// Rolls for a delay, returning how long it is if there is one.
+    fn roll_delay(&self) -> Option<Duration> {
+        if roll(self.delay_rate) && self.max_delay > Duration::from_millis(0) {
+            Some(self.max_delay.mul_f64(rand::thread_rng().gen::<f64>()))
+        } else {
+            None
+        }
+    }
+}
+
+lazy_static! {
+    static ref FAULTS: RwLock<Faults> = RwLock::new(Faults::default());
+}
+
+/// Injects faults at the given rates from here on, in place of whatever was injected before.
+pub fn inject(faults: Faults) -> Result<()> {
+    faults.validate()?;
+    *FAULTS.write().unwrap() = faults;
+    Ok(())
+}
+
+/// Stops injecting faults.
+pub fn clear() {
+    *FAULTS.write().unwrap() = Faults::default();
+}
+
+/// The faults being injected.
+pub fn faults() -> Faults {
+    *FAULTS.read().unwrap()
+}
+
+fn roll(rate: f64) -> bool {
+    rate > 0.0 && rand::thread_rng().gen::<f64>() < rate
+}
+
+/// Holds the caller up, if the dice say so.
+async fn maybe_delay() {
+    if let Some(delay) = faults().roll_delay() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Called before checking a connection out of the database (see `Database::connection`).
+pub async fn database_fault() -> Result<()> {
+    maybe_delay().await;
+    if roll(faults().db_error_rate) {
+        Err(WorkerError::new(
+            ErrKind::DatabaseError, "Could not check out a connection: injected fault."
+        ))?
+    }
+    Ok(())
+}
+
+/// Called before every S3 request `ChaosS3Client` makes, to the given bucket (or URL).
+async fn s3_fault(target: &str) -> Result<()> {
+    maybe_delay().await;
+    if roll(faults().s3_error_rate) {
+        Err(WorkerError::new(
+            ErrKind::AWSError,
+            &format!("ServiceUnavailable (503): request to {} failed: injected fault.", target)
+        ))?
+    }
+    Ok(())
+}
+
+/// An S3 client that makes its requests with another one, injecting faults into them first.
+#[derive(Clone)]
+pub struct ChaosS3Client<T: WorkerS3ClientTrait> {
+    pub client: T,
+}
+
+impl<T: WorkerS3ClientTrait> ChaosS3Client<T> {
+    pub fn new(client: T) -> ChaosS3Client<T> {
+        ChaosS3Client { client }
+    }
+}
+
+#[async_trait]
+impl<T: WorkerS3ClientTrait + Send + Sync> WorkerS3ClientTrait for ChaosS3Client<T> {
+    async fn _get_object(
+        &self,
+        input: GetObjectRequest,
+        out: &mut (dyn Write + Send),
+        on_bytes: &(dyn Fn(u64) + Sync),
+    ) -> Result<u64> {
+        s3_fault(&input.bucket).await?;
+        self.client._get_object(input, out, on_bytes).await
+    }
+
+    async fn _head_object(&self, input: HeadObjectRequest) -> Result<ObjectHead> {
+        s3_fault(&input.bucket).await?;
+        self.client._head_object(input).await
+    }
+
+    async fn _list_objects(&self, input: ListObjectsV2Request)
+        -> Result<(Vec<String>, Option<String>)> {
+        s3_fault(&input.bucket).await?;
+        self.client._list_objects(input).await
+    }
+
+    async fn _put_object(&self, input: PutObjectRequest) -> Result<Option<String>> {
+        s3_fault(&input.bucket).await?;
+        self.client._put_object(input).await
+    }
+
+    async fn _create_multipart_upload(&self, input: CreateMultipartUploadRequest)
+        -> Result<Option<String>> {
+        s3_fault(&input.bucket).await?;
+        self.client._create_multipart_upload(input).await
+    }
+
+    async fn _upload_part(&self, input: UploadPartRequest) -> Result<Option<String>> {
+        s3_fault(&input.bucket).await?;
+        self.client._upload_part(input).await
+    }
+
+    async fn _complete_multipart_upload(&self, input: CompleteMultipartUploadRequest)
+        -> Result<Option<String>> {
+        s3_fault(&input.bucket).await?;
+        self.client._complete_multipart_upload(input).await
+    }
+
+    async fn _abort_multipart_upload(&self, input: AbortMultipartUploadRequest) -> Result<()> {
+        s3_fault(&input.bucket).await?;
+        self.client._abort_multipart_upload(input).await
+    }
+
+    async fn _get_url(
+        &self,
+        url: &str,
+        range: Option<String>,
+        out: &mut (dyn Write + Send),
+        on_bytes: &(dyn Fn(u64) + Sync),
+    ) -> Result<ObjectHead> {
+        s3_fault(url).await?;
+        self.client._get_url(url, range, out, on_bytes).await
+    }
+}
+
+/// A connection that injects faults into the reads and writes made on it, which are otherwise
+/// made on the connection it wraps. Faults are rolled for every time the connection is polled.
+pub struct ChaosStream<S> {
+    inner: S,
+    delay: Option<Pin<Box<Sleep>>>,
+    dropped: bool,
+}
+
+impl<S> ChaosStream<S> {
+    pub fn new(inner: S) -> ChaosStream<S> {
+        ChaosStream { inner, delay: None, dropped: false }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Ready with `Ok` once the operation may go ahead: right away, or once a delay is over. A
+    /// dropped connection is ready with an error instead.
+    fn poll_fault(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.delay.is_none() && !self.dropped {
+            let faults = faults();
+            self.dropped = roll(faults.drop_rate);
+            self.delay = faults.roll_delay().map(|delay| Box::pin(tokio::time::sleep(delay)));
+        }
+        if self.dropped {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionReset, "Connection dropped: injected fault."
+            )));
+        }
+        if let Some(delay) = &mut self.delay {
+            match delay.as_mut().poll(cx) {
+                Poll::Ready(()) => self.delay = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ChaosStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_fault(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_read(cx, buf),
+            fault => fault,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ChaosStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_fault(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_write(cx, buf),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::file::{WorkerS3ClientAdapter, WorkerS3ClientMock};
+    use crate::db::Database;
+
+    #[test]
+    fn test_validate() {
+        assert!(Faults::default().validate().is_ok());
+        assert!(Faults { drop_rate: 1.0, ..Faults::default() }.validate().is_ok());
+        assert!(Faults { drop_rate: 1.5, ..Faults::default() }.validate().is_err());
+        assert!(Faults { s3_error_rate: -0.1, ..Faults::default() }.validate().is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_s3_errors() {
+        let client = WorkerS3ClientAdapter::new(ChaosS3Client::new(
+            WorkerS3ClientMock::with_objects(vec![("s3://foo/bar.csv", vec![1, 2, 3])])
+        ));
+        assert!(client.head("s3://foo/bar.csv").await.is_ok());
+
+        inject(Faults { s3_error_rate: 1.0, ..Faults::default() }).unwrap();
+        let err = client.head("s3://foo/bar.csv").await.unwrap_err();
+        clear();
+        assert!(err.to_string().contains("503"));
+        assert!(client.head("s3://foo/bar.csv").await.is_ok());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_database_errors() {
+        let db = Database::new_in_memory().await.unwrap();
+        inject(Faults { db_error_rate: 1.0, ..Faults::default() }).unwrap();
+        let checked_out = db.connection().await;
+        clear();
+        assert!(checked_out.is_err());
+        assert!(db.connection().await.is_ok());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_dropped_connection() {
+        let (client, server) = tokio::io::duplex(64);
+        let (mut client, mut server) = (ChaosStream::new(client), ChaosStream::new(server));
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        inject(Faults { drop_rate: 1.0, ..Faults::default() }).unwrap();
+        let err = client.write_all(b"ping").await.unwrap_err();
+        clear();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        // Once dropped, a connection stays that way.
+        assert!(client.write_all(b"ping").await.is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_delays() {
+        let (client, server) = tokio::io::duplex(64);
+        let (mut client, mut server) = (ChaosStream::new(client), ChaosStream::new(server));
+        inject(Faults {
+            delay_rate: 1.0, max_delay: Duration::from_millis(20), ..Faults::default()
+        }).unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        clear();
+        assert_eq!(&buf, b"ping");
+    }
+}
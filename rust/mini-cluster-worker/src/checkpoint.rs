@@ -0,0 +1,168 @@
+use protobuf::Message;
+use sqlx::SqliteConnection;
+
+use crate::err::Result;
+use crate::workload::Workload;
+
+/// The table the worker keeps the workloads of checkpointed jobs in.
+pub const CHECKPOINT_TABLE: &str = "_mini_cluster_checkpoints";
+/// The table the worker records the ops that checkpointed jobs have completed in.
+pub const CHECKPOINT_OPS_TABLE: &str = "_mini_cluster_checkpoint_ops";
+
+// Checkpoints. A long job that dies with its worker (e.g. because the worker was restarted)
+// would ordinarily have to start over from scratch, redoing its downloads and every op that
+// came before the one it died in. Most of that work is still there in the worker's database,
+// though: the tables `build` loaded, and whatever tables the job's ops made out of them.
+//
+// So a job whose workload has `checkpoint` set keeps track of how far it got, in the database
+// itself, right alongside that work: its workload, under its idempotency key (which checkpointed
+// workloads have to have), whether or not it has loaded its tables, and which of its ops are
+// done. A RESUME frame naming the key (see `Worker::resume`) queues the job again from there:
+//
+// * The tables aren't loaded again if they were loaded before and are still up to date, even if
+//   the workload has `force_reload` set.
+// * Ops that were done are skipped, unless they return a result set. Those are run again, since
+//   their results went with the job that died (and they can't have changed anything).
+//
+// Only what is in the database survives, so ops whose work is kept elsewhere (e.g. in `TEMP`
+// tables, which belong to a connection) don't checkpoint well. The checkpoint is removed once
+// the job is done; jobs that fail keep theirs, so that they can be resumed once whatever went
+// wrong is put right.
+
+/// How far a checkpointed job got.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    pub workload: Workload,
+    /// Whether or not the job's tables were all loaded.
+    pub built: bool,
+    /// The sequence numbers of the ops that were done.
+    pub done_ops: Vec<i32>,
+}
+
+async fn create_tables_if_missing(conn: &mut SqliteConnection) -> Result<()> {
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (\n\
+            key TEXT PRIMARY KEY,\n\
+            workload BLOB NOT NULL,\n\
+            built INTEGER NOT NULL\n\
+        );",
+        CHECKPOINT_TABLE
+    )).execute(&mut *conn).await?;
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (\n\
+            key TEXT NOT NULL,\n\
+            op_sequence_num INTEGER NOT NULL,\n\
+            PRIMARY KEY (key, op_sequence_num)\n\
+        );",
+        CHECKPOINT_OPS_TABLE
+    )).execute(&mut *conn).await?;
+    Ok(())
+}
+
+/// Starts a checkpoint for a workload, under its idempotency key, replacing any earlier one.
+pub async fn start(conn: &mut SqliteConnection, workload: &Workload) -> Result<()> {
+    create_tables_if_missing(&mut *conn).await?;
+    remove(&mut *conn, workload.get_idempotency_key()).await?;
+    sqlx::query(&format!("INSERT INTO {} VALUES (?, ?, 0)", CHECKPOINT_TABLE))
+        .bind(workload.get_idempotency_key())
+        .bind(workload.write_to_bytes()?)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Records that the job checkpointed under `key` has loaded its tables.
+pub async fn mark_built(conn: &mut SqliteConnection, key: &str) -> Result<()> {
+    create_tables_if_missing(&mut *conn).await?;
+    sqlx::query(&format!("UPDATE {} SET built = 1 WHERE key = ?", CHECKPOINT_TABLE))
+        .bind(key)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Records that an op of the job checkpointed under `key` is done.
+pub async fn mark_op_done(
+    conn: &mut SqliteConnection, key: &str, op_sequence_num: i32
+) -> Result<()> {
+    create_tables_if_missing(&mut *conn).await?;
+    sqlx::query(&format!("INSERT OR IGNORE INTO {} VALUES (?, ?)", CHECKPOINT_OPS_TABLE))
+        .bind(key)
+        .bind(op_sequence_num)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Reads the checkpoint kept under `key`, if there is one.
+pub async fn load(conn: &mut SqliteConnection, key: &str) -> Result<Option<Checkpoint>> {
+    create_tables_if_missing(&mut *conn).await?;
+    let row: Option<(Vec<u8>, bool)> = sqlx::query_as(
+        &format!("SELECT workload, built FROM {} WHERE key = ?", CHECKPOINT_TABLE)
+    ).bind(key).fetch_optional(&mut *conn).await?;
+    let (workload, built) = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let done_ops: Vec<(i32,)> = sqlx::query_as(&format!(
+        "SELECT op_sequence_num FROM {} WHERE key = ? ORDER BY op_sequence_num",
+        CHECKPOINT_OPS_TABLE
+    )).bind(key).fetch_all(&mut *conn).await?;
+    Ok(Some(Checkpoint {
+        workload: Workload::parse_from_bytes(&workload)?,
+        built,
+        done_ops: done_ops.into_iter().map(|(op_sequence_num,)| op_sequence_num).collect(),
+    }))
+}
+
+/// Removes the checkpoint kept under `key`, if there is one.
+pub async fn remove(conn: &mut SqliteConnection, key: &str) -> Result<()> {
+    create_tables_if_missing(&mut *conn).await?;
+    for table in [CHECKPOINT_TABLE, CHECKPOINT_OPS_TABLE].iter() {
+        sqlx::query(&format!("DELETE FROM {} WHERE key = ?", table))
+            .bind(key)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use crate::db::Database;
+    use crate::fixtures::*;
+    use super::*;
+
+    #[test]
+    fn test_checkpoint() {
+        block_on(async {
+            let database = Database::new_in_memory().await.unwrap();
+            let mut conn = database.connection().await.unwrap();
+            assert_eq!(load(&mut conn, "a").await.unwrap(), None);
+
+            let mut workload = craft_workload_message(None);
+            workload.set_idempotency_key("a".to_owned());
+            workload.set_checkpoint(true);
+            start(&mut conn, &workload).await.unwrap();
+            mark_op_done(&mut conn, "a", 2).await.unwrap();
+            mark_op_done(&mut conn, "a", 1).await.unwrap();
+            mark_op_done(&mut conn, "a", 1).await.unwrap();
+            let checkpoint = load(&mut conn, "a").await.unwrap().unwrap();
+            assert_eq!(checkpoint.workload, workload);
+            assert!(!checkpoint.built);
+            assert_eq!(checkpoint.done_ops, vec![1, 2]);
+
+            mark_built(&mut conn, "a").await.unwrap();
+            assert!(load(&mut conn, "a").await.unwrap().unwrap().built);
+
+            // Starting over forgets how far the job got.
+            start(&mut conn, &workload).await.unwrap();
+            assert_eq!(load(&mut conn, "a").await.unwrap().unwrap().done_ops, Vec::<i32>::new());
+
+            remove(&mut conn, "a").await.unwrap();
+            assert_eq!(load(&mut conn, "a").await.unwrap(), None);
+        });
+    }
+}
@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::sandbox::{StatementClass, SANDBOX_STATEMENTS};
+use crate::result::RESULT_BATCH_SIZE;
+use crate::db::DatabaseOptions;
+use crate::functions::builtin_function;
+use crate::job::PARALLEL_LOADS;
+use crate::queue::PRIORITY_AGING;
+use crate::slowlog::default_slow_op_log;
+use crate::gc::GC_INTERVAL;
+use crate::file::{DiskLimits, S3Options};
+use crate::credentials::CredentialRoute;
+use crate::memory::MEMORY_WAIT;
+use crate::transport::Address;
+use crate::log::LogLevel;
+use crate::ratelimit::RateLimit;
+use crate::encryption::KeySource;
+use crate::tls::{Identity, ServerTls};
+
+/// The wire protocol the worker serves.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    /// The minimal hand-rolled framing described in `protocol.rs`.
+    Tcp,
+    /// The `WorkerService` gRPC service described in `service.proto`. This is heavier than the
+    /// raw framing, but gets you standard load balancing, deadlines, and TLS for free.
+    Grpc,
+}
+
+impl Transport {
+    pub fn from_name(name: &str) -> Result<Transport> {
+        match name.trim().to_lowercase().as_str() {
+            "tcp" => Ok(Transport::Tcp),
+            "grpc" => Ok(Transport::Grpc),
+            _ => Err(WorkerError::new(
+                ErrKind::NetworkError, &format!("Unknown transport {:?}.", name)
+            ))?,
+        }
+    }
+}
+
+/// Worker configuration. Values are read out of environment variables by `from_env`, which makes
+/// them easy to set from e.g. `docker-compose.yaml`, or out of a config file of the same
+/// variables (`WORKER_CONFIG_FILE`), which is read again whenever the worker is asked to reload
+/// its configuration (see `Tunables`).
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    /// Shared secret used to authenticate clients (`WORKER_SECRET`). If this is not set,
+    /// authentication is disabled, and anything that can reach the port may submit work.
+    pub secret: Option<String>,
+    /// The statement classes ops are allowed to run. `None` means no restriction.
+    ///
+    /// Set with either `WORKER_SANDBOX=1`, which allows `SANDBOX_STATEMENTS`, or an explicit
+    /// comma-separated list, e.g. `WORKER_ALLOWED_STATEMENTS=select,create_table_as,insert`.
+    pub allowed_statements: Option<Vec<StatementClass>>,
+    /// Number of executor tasks pulling jobs off of the job queue (`WORKER_EXECUTORS`), e.g. the
+    /// maximum number of jobs that may run at the same time.
+    pub executors: usize,
+    /// How long a connection may sit idle between frames before the worker hangs up on it
+    /// (`WORKER_IDLE_TIMEOUT_SECS`).
+    pub idle_timeout: Duration,
+    /// How long the worker waits for a frame's payload to arrive once its header has been read
+    /// (`WORKER_READ_TIMEOUT_SECS`).
+    pub read_timeout: Duration,
+    /// How long the worker waits for a response frame to be written (`WORKER_WRITE_TIMEOUT_SECS`).
+    pub write_timeout: Duration,
+    /// Maximum number of connections the worker serves at once (`WORKER_MAX_CONNECTIONS`).
+    pub max_connections: usize,
+    /// What to do with new connections when `max_connections` is reached
+    /// (`WORKER_REJECT_WHEN_BUSY`). By default they wait until a slot frees up; if this is set,
+    /// they are sent a BUSY frame and closed instead.
+    pub reject_when_busy: bool,
+    /// How fast each client may open connections: `WORKER_ACCEPT_RATE` a second, in bursts of
+    /// up to `WORKER_ACCEPT_BURST` (see `ratelimit`). Clients are told apart by IP address, and
+    /// those over the limit are sent a RATE_LIMITED error and closed. There's no limit unless
+    /// this is set.
+    pub accept_rate: Option<RateLimit>,
+    /// The wire protocol to serve, either `tcp` (the default) or `grpc` (`WORKER_TRANSPORT`).
+    pub transport: Transport,
+    /// Whether or not to serve TLS, and if so, with which certificate and key
+    /// (`WORKER_TLS_CERT` and `WORKER_TLS_KEY`; see `tls`). If the cluster's CA certificate is
+    /// given too (`WORKER_TLS_CLIENT_CA`), only clients presenting a certificate it signed are
+    /// served, which makes the shared secret optional.
+    pub tls: Option<ServerTls>,
+    /// How many result rows are sent to the client at a time (`WORKER_RESULT_BATCH_SIZE`).
+    pub result_batch_size: usize,
+    /// SQLite settings (`WORKER_SQLITE_JOURNAL_MODE`, `WORKER_SQLITE_SYNCHRONOUS`,
+    /// `WORKER_SQLITE_PAGE_SIZE`, `WORKER_SQLITE_CACHE_SIZE`, and `WORKER_SQLITE_MMAP_SIZE`),
+    /// and the custom SQL functions to make available to op statements, as a comma-separated
+    /// list of the ones that ship with the worker (`WORKER_SQL_FUNCTIONS`, e.g.
+    /// `regex_match,url_host`; see `functions::builtin_function`). Embedders can add functions
+    /// of their own with `DatabaseOptions::register_function`.
+    pub database: DatabaseOptions,
+    /// How many of a job's tables are loaded at once (`WORKER_PARALLEL_LOADS`). Each load needs
+    /// a database connection, so there's no point setting this higher than the size of a job's
+    /// connection pool (`db::MAX_POOL_CONNECTIONS`).
+    pub parallel_loads: usize,
+    /// How long a queued job has to wait to gain one level of priority
+    /// (`WORKER_PRIORITY_AGING_SECS`). Zero turns aging off, in which case a low-priority job
+    /// waits for as long as there are higher-priority ones queued.
+    pub priority_aging: Duration,
+    /// Where the scheduler listens (`WORKER_SCHEDULER`): a TCP port, a `host:port` on another
+    /// machine, or the path to a Unix domain socket. If this is set, the worker registers itself
+    /// with the scheduler when it starts up (see `membership`), authenticating with `secret`.
+    pub scheduler: Option<Address>,
+    /// The TCP port to answer HTTP health and readiness probes on (`WORKER_ADMIN_PORT`; see
+    /// `health`). Probes aren't answered unless this is set.
+    pub admin_port: Option<u16>,
+    /// How long an op has to run for to go in the slow-op log (`WORKER_SLOW_OP_MILLIS`; see
+    /// `slowlog`). Slow ops aren't logged unless this is set.
+    pub slow_op_threshold: Option<Duration>,
+    /// Where slow ops are logged (`WORKER_SLOW_OP_LOG`).
+    pub slow_op_log: PathBuf,
+    /// How long a table is kept once the last job to use it is done
+    /// (`WORKER_TABLE_RETENTION_SECS`; see `gc`). Tables are kept forever unless this is set.
+    /// Note that a checkpointed job can't be resumed once its tables are gone.
+    pub table_retention: Option<Duration>,
+    /// How often the worker collects garbage in its database (`WORKER_GC_INTERVAL_SECS`; see
+    /// `gc`). Zero turns garbage collection off.
+    pub gc_interval: Duration,
+    /// How much of the disk the worker's cache directory may take up: at most
+    /// `WORKER_DISK_QUOTA_BYTES` bytes, if set, and never so much that fewer than
+    /// `WORKER_DISK_RESERVE_BYTES` bytes are left free. Jobs whose files don't fit fail with a
+    /// `ResourceError` before they download anything (see `file::check_disk_space`).
+    pub disk_limits: DiskLimits,
+    /// Roughly how many bytes of memory the worker's unread result batches may take up between
+    /// them (`WORKER_MEMORY_CEILING_BYTES`; see `memory`). There's no limit
+    /// unless this is set.
+    pub memory_ceiling: Option<u64>,
+    /// How long a job waits for memory to free up under the ceiling before it fails
+    /// (`WORKER_MEMORY_WAIT_SECS`).
+    pub memory_wait: Duration,
+    /// The options S3 requests are made with, unless a file's own say otherwise: whether to
+    /// read from requester-pays buckets (`WORKER_S3_REQUESTER_PAYS`), and the base64-encoded key
+    /// objects encrypted with a customer-provided key were encrypted with
+    /// (`WORKER_S3_SSE_CUSTOMER_KEY`; see `file::S3Options`).
+    pub s3: S3Options,
+    /// Which credentials to read which buckets with (`WORKER_S3_CREDENTIALS`, a comma-separated
+    /// list of routes like `partner-*=role:<role ARN>` or `archive=profile:<profile name>`; see
+    /// `credentials`). Buckets no route matches are read with the environment's credentials.
+    pub s3_credentials: Vec<CredentialRoute>,
+    /// The snapshot of another worker's database to start out with (`WORKER_RESTORE_SNAPSHOT`,
+    /// an `s3://` path or a pre-signed URL; see `snapshot`). It is only restored if the worker
+    /// has no database of its own yet, so restarting a worker doesn't throw away what it loaded.
+    pub restore_snapshot: Option<String>,
+    /// The key to encrypt the cache directory and the database with (`WORKER_CACHE_KEY`, the
+    /// base64 of a 32-byte key, or `kms:` followed by the base64 of one encrypted with KMS; see
+    /// `encryption`). Nothing is encrypted unless this is set.
+    pub cache_key: Option<KeySource>,
+    /// How much the worker logs: `error`, `info` (the default), or `debug`
+    /// (`WORKER_LOG_LEVEL`; see `log`).
+    pub log_level: LogLevel,
+    /// The config file the worker reads its configuration out of, as well as the environment
+    /// (`WORKER_CONFIG_FILE`; see `read_config_file`). Variables set in it win over the
+    /// environment's.
+    pub config_file: Option<PathBuf>,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> WorkerConfig {
+        WorkerConfig {
+            secret: None,
+            allowed_statements: None,
+            executors: 1,
+            idle_timeout: Duration::from_secs(300),
+            read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_connections: 64,
+            reject_when_busy: false,
+            accept_rate: None,
+            transport: Transport::Tcp,
+            tls: None,
+            result_batch_size: RESULT_BATCH_SIZE,
+            database: DatabaseOptions::default(),
+            parallel_loads: PARALLEL_LOADS,
+            priority_aging: PRIORITY_AGING,
+            scheduler: None,
+            admin_port: None,
+            slow_op_threshold: None,
+            slow_op_log: default_slow_op_log(),
+            table_retention: None,
+            gc_interval: GC_INTERVAL,
+            disk_limits: DiskLimits::default(),
+            memory_ceiling: None,
+            memory_wait: MEMORY_WAIT,
+            s3: S3Options::default(),
+            s3_credentials: vec![],
+            restore_snapshot: None,
+            cache_key: None,
+            log_level: LogLevel::default(),
+            config_file: None,
+        }
+    }
+}
+
+// Reloading. Restarting a worker to change how many jobs it runs at once, say, throws away
+// everything it has in memory, and drops every job it has running. So the worker can be asked to
+// reload its configuration whilst it runs, either by sending it a SIGHUP or a RELOAD frame (see
+// `Worker::reload`). The environment of a running process can't be changed from the outside, so
+// reloading only picks up changes made to the config file (`WORKER_CONFIG_FILE`).
+//
+// Only the `Tunables` are applied to a running worker. Everything else (its secret, its
+// transport, its SQLite settings...) is fixed at startup, and changes to it are ignored until the
+// worker is next restarted.
+
+/// The parts of the worker's configuration that can be changed whilst it runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tunables {
+    pub executors: usize,
+    pub max_connections: usize,
+    pub parallel_loads: usize,
+    pub result_batch_size: usize,
+    pub disk_limits: DiskLimits,
+    pub memory_ceiling: Option<u64>,
+    pub memory_wait: Duration,
+    pub log_level: LogLevel,
+}
+
+impl Tunables {
+    pub fn of(config: &WorkerConfig) -> Tunables {
+        Tunables {
+            executors: config.executors,
+            max_connections: config.max_connections,
+            parallel_loads: config.parallel_loads,
+            result_batch_size: config.result_batch_size,
+            disk_limits: config.disk_limits,
+            memory_ceiling: config.memory_ceiling,
+            memory_wait: config.memory_wait,
+            log_level: config.log_level,
+        }
+    }
+
+    /// Lists the names of the tunables that differ between this and `other`, e.g. to log what a
+    /// reload changed.
+    pub fn changes(&self, other: &Tunables) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.executors != other.executors { changed.push("executors"); }
+        if self.max_connections != other.max_connections { changed.push("max_connections"); }
+        if self.parallel_loads != other.parallel_loads { changed.push("parallel_loads"); }
+        if self.result_batch_size != other.result_batch_size {
+            changed.push("result_batch_size");
+        }
+        if self.disk_limits != other.disk_limits { changed.push("disk_limits"); }
+        if self.memory_ceiling != other.memory_ceiling { changed.push("memory_ceiling"); }
+        if self.memory_wait != other.memory_wait { changed.push("memory_wait"); }
+        if self.log_level != other.log_level { changed.push("log_level"); }
+        changed
+    }
+}
+
+/// Parses a config file: one `KEY=VALUE` line per variable, as in a Docker env file. Blank lines,
+/// and lines starting with `#`, are skipped, and values may be quoted.
+pub fn parse_config_file(contents: &str) -> Result<HashMap<String, String>> {
+    let mut vars = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(2, '=');
+        let (key, value) = match (fields.next(), fields.next()) {
+            (Some(key), Some(value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+            _ => Err(WorkerError::new(
+                ErrKind::ValidationError,
+                &format!("Line {} of the config file is not of the form KEY=VALUE.", i + 1)
+            ))?,
+        };
+        let unquoted = ['"', '\''].iter().find_map(|quote| {
+            value.strip_prefix(*quote).and_then(|v| v.strip_suffix(*quote))
+        });
+        vars.insert(key.to_owned(), unquoted.unwrap_or(value).to_owned());
+    }
+    Ok(vars)
+}
+
+/// Reads the config file at `WORKER_CONFIG_FILE`, if it's set (see `parse_config_file`).
+pub fn read_config_file() -> Result<HashMap<String, String>> {
+    match env::var("WORKER_CONFIG_FILE") {
+        Ok(path) if !path.is_empty() => parse_config_file(&fs::read_to_string(path)?),
+        _ => Ok(HashMap::new()),
+    }
+}
+
+/// Where configuration variables are read from: the config file, and then the environment.
+struct Vars {
+    file: HashMap<String, String>,
+}
+
+impl Vars {
+    fn get(&self, key: &str) -> std::result::Result<String, env::VarError> {
+        match self.file.get(key) {
+            Some(v) => Ok(v.clone()),
+            None => env::var(key),
+        }
+    }
+
+    /// Reads a variable and parses it, falling back to `default` if it is not set.
+    fn parse<T: std::str::FromStr>(&self, key: &str, default: T) -> Result<T>
+    where T::Err: std::error::Error + 'static {
+        match self.get(key) {
+            Ok(v) if !v.is_empty() => Ok(v.parse::<T>()?),
+            _ => Ok(default),
+        }
+    }
+}
+
+impl WorkerConfig {
+    pub fn from_env() -> Result<WorkerConfig> {
+        let vars = Vars { file: read_config_file()? };
+        // An empty secret is almost certainly a misconfiguration (e.g. `WORKER_SECRET=""` left
+        // over in a compose file), so we treat it the same as an unset one.
+        let secret = vars.get("WORKER_SECRET").ok().filter(|v| !v.is_empty());
+
+        let allowed_statements = match vars.get("WORKER_ALLOWED_STATEMENTS") {
+            Ok(v) if !v.is_empty() => Some(
+                v.split(",").map(StatementClass::from_name).collect::<Result<Vec<_>>>()?
+            ),
+            _ => match vars.get("WORKER_SANDBOX") {
+                Ok(v) if v == "1" || v == "true" => Some(SANDBOX_STATEMENTS.to_vec()),
+                _ => None,
+            },
+        };
+
+        let defaults = WorkerConfig::default();
+        let executors = vars.parse("WORKER_EXECUTORS", defaults.executors)?;
+        let idle_timeout = Duration::from_secs(
+            vars.parse("WORKER_IDLE_TIMEOUT_SECS", defaults.idle_timeout.as_secs())?
+        );
+        let read_timeout = Duration::from_secs(
+            vars.parse("WORKER_READ_TIMEOUT_SECS", defaults.read_timeout.as_secs())?
+        );
+        let write_timeout = Duration::from_secs(
+            vars.parse("WORKER_WRITE_TIMEOUT_SECS", defaults.write_timeout.as_secs())?
+        );
+        let max_connections =
+            vars.parse("WORKER_MAX_CONNECTIONS", defaults.max_connections)?;
+        let reject_when_busy =
+            vars.parse("WORKER_REJECT_WHEN_BUSY", defaults.reject_when_busy)?;
+        let accept_rate = match vars.parse("WORKER_ACCEPT_RATE", 0.0)? {
+            rate if rate > 0.0 => Some(RateLimit::new(
+                rate,
+                match vars.get("WORKER_ACCEPT_BURST") {
+                    Ok(v) if !v.is_empty() => Some(v.parse::<f64>()?),
+                    _ => None,
+                }
+            )?),
+            _ => defaults.accept_rate,
+        };
+        let transport = match vars.get("WORKER_TRANSPORT") {
+            Ok(v) if !v.is_empty() => Transport::from_name(&v)?,
+            _ => defaults.transport,
+        };
+        let path = |key: &str| vars.get(key).ok().filter(|v| !v.is_empty()).map(PathBuf::from);
+        let tls = match (path("WORKER_TLS_CERT"), path("WORKER_TLS_KEY")) {
+            (Some(cert), Some(key)) => Some(ServerTls {
+                identity: Identity { cert, key },
+                client_ca: path("WORKER_TLS_CLIENT_CA"),
+            }),
+            (None, None) if path("WORKER_TLS_CLIENT_CA").is_none() => defaults.tls,
+            _ => Err(WorkerError::new(
+                ErrKind::ValidationError,
+                "Serving TLS takes both WORKER_TLS_CERT and WORKER_TLS_KEY."
+            ))?,
+        };
+
+        // A batch has to hold at least one row.
+        let result_batch_size =
+            vars.parse("WORKER_RESULT_BATCH_SIZE", defaults.result_batch_size)?.max(1);
+
+        let database = DatabaseOptions {
+            journal_mode: vars.parse(
+                "WORKER_SQLITE_JOURNAL_MODE", defaults.database.journal_mode.clone()
+            )?,
+            synchronous: vars.parse(
+                "WORKER_SQLITE_SYNCHRONOUS", defaults.database.synchronous.clone()
+            )?,
+            page_size: vars.parse("WORKER_SQLITE_PAGE_SIZE", defaults.database.page_size)?,
+            cache_size: vars.parse("WORKER_SQLITE_CACHE_SIZE", defaults.database.cache_size)?,
+            mmap_size: vars.parse("WORKER_SQLITE_MMAP_SIZE", defaults.database.mmap_size)?,
+            functions: match vars.get("WORKER_SQL_FUNCTIONS") {
+                Ok(v) if !v.is_empty() => {
+                    v.split(",").map(builtin_function).collect::<Result<Vec<_>>>()?
+                },
+                _ => vec![],
+            },
+        };
+        database.validate()?;
+        let parallel_loads =
+            vars.parse("WORKER_PARALLEL_LOADS", defaults.parallel_loads)?.max(1);
+        let priority_aging = Duration::from_secs(
+            vars.parse("WORKER_PRIORITY_AGING_SECS", defaults.priority_aging.as_secs())?
+        );
+        let scheduler = match vars.get("WORKER_SCHEDULER") {
+            Ok(v) if !v.is_empty() => Some(Address::parse(&v)),
+            _ => defaults.scheduler,
+        };
+        let admin_port = match vars.get("WORKER_ADMIN_PORT") {
+            Ok(v) if !v.is_empty() => Some(v.parse::<u16>()?),
+            _ => defaults.admin_port,
+        };
+        let slow_op_threshold = match vars.get("WORKER_SLOW_OP_MILLIS") {
+            Ok(v) if !v.is_empty() => Some(Duration::from_millis(v.parse::<u64>()?)),
+            _ => defaults.slow_op_threshold,
+        };
+        let slow_op_log = match vars.get("WORKER_SLOW_OP_LOG") {
+            Ok(v) if !v.is_empty() => PathBuf::from(v),
+            _ => defaults.slow_op_log,
+        };
+        let table_retention = match vars.get("WORKER_TABLE_RETENTION_SECS") {
+            Ok(v) if !v.is_empty() => Some(Duration::from_secs(v.parse::<u64>()?)),
+            _ => defaults.table_retention,
+        };
+        let gc_interval = Duration::from_secs(
+            vars.parse("WORKER_GC_INTERVAL_SECS", defaults.gc_interval.as_secs())?
+        );
+        let disk_limits = DiskLimits {
+            quota: match vars.get("WORKER_DISK_QUOTA_BYTES") {
+                Ok(v) if !v.is_empty() => Some(v.parse::<u64>()?),
+                _ => defaults.disk_limits.quota,
+            },
+            reserve: vars.parse("WORKER_DISK_RESERVE_BYTES", defaults.disk_limits.reserve)?,
+        };
+        let memory_ceiling = match vars.get("WORKER_MEMORY_CEILING_BYTES") {
+            Ok(v) if !v.is_empty() => Some(v.parse::<u64>()?),
+            _ => defaults.memory_ceiling,
+        };
+        let memory_wait = Duration::from_secs(
+            vars.parse("WORKER_MEMORY_WAIT_SECS", defaults.memory_wait.as_secs())?
+        );
+
+        let s3 = S3Options {
+            requester_pays: vars.parse("WORKER_S3_REQUESTER_PAYS", defaults.s3.requester_pays)?,
+            sse_customer_key: vars.get("WORKER_S3_SSE_CUSTOMER_KEY").ok().filter(|v| !v.is_empty()),
+        };
+        s3.validate()?;
+        let s3_credentials = match vars.get("WORKER_S3_CREDENTIALS") {
+            Ok(v) if !v.is_empty() => CredentialRoute::parse_list(&v)?,
+            _ => defaults.s3_credentials,
+        };
+        let restore_snapshot = vars.get("WORKER_RESTORE_SNAPSHOT").ok().filter(|v| !v.is_empty());
+        let cache_key = match vars.get("WORKER_CACHE_KEY") {
+            Ok(v) if !v.is_empty() => Some(KeySource::parse(&v)?),
+            _ => defaults.cache_key,
+        };
+        let log_level = match vars.get("WORKER_LOG_LEVEL") {
+            Ok(v) if !v.is_empty() => LogLevel::from_name(&v)?,
+            _ => defaults.log_level,
+        };
+        // The config file can't point at another config file.
+        let config_file = env::var("WORKER_CONFIG_FILE").ok()
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from);
+
+        Ok(WorkerConfig {
+            secret,
+            allowed_statements,
+            executors,
+            idle_timeout,
+            read_timeout,
+            write_timeout,
+            max_connections,
+            reject_when_busy,
+            accept_rate,
+            transport,
+            tls,
+            result_batch_size,
+            database,
+            parallel_loads,
+            priority_aging,
+            scheduler,
+            admin_port,
+            slow_op_threshold,
+            slow_op_log,
+            table_retention,
+            gc_interval,
+            disk_limits,
+            memory_ceiling,
+            memory_wait,
+            s3,
+            s3_credentials,
+            restore_snapshot,
+            cache_key,
+            log_level,
+            config_file,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config_file() {
+        let vars = parse_config_file(
+            "# Turned up for the backfill.\nWORKER_EXECUTORS=4\n\n WORKER_LOG_LEVEL = \"debug\"\n"
+        ).unwrap();
+        assert_eq!(vars.len(), 2);
+        assert_eq!(vars["WORKER_EXECUTORS"], "4");
+        assert_eq!(vars["WORKER_LOG_LEVEL"], "debug");
+        assert_eq!(parse_config_file("A='b=c'").unwrap()["A"], "b=c");
+        assert!(parse_config_file("WORKER_EXECUTORS").is_err());
+        assert!(parse_config_file("=4").is_err());
+    }
+
+    #[test]
+    fn test_tunables_changes() {
+        let config = WorkerConfig::default();
+        let tunables = Tunables::of(&config);
+        assert!(tunables.changes(&tunables).is_empty());
+        let reloaded = Tunables {
+            executors: 4, log_level: LogLevel::Debug, ..tunables.clone()
+        };
+        assert_eq!(tunables.changes(&reloaded), vec!["executors", "log_level"]);
+    }
+}
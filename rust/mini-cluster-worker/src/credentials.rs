@@ -0,0 +1,226 @@
+use std::io::Write;
+
+use async_trait::async_trait;
+use rusoto_core::{HttpClient, Region};
+use rusoto_credential::{AutoRefreshingProvider, ProfileProvider};
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CreateMultipartUploadRequest,
+    GetObjectRequest, HeadObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client,
+    UploadPartRequest,
+};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
+
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::file::{
+    matches_pattern, with_s3_faults, FaultyS3Client, ObjectHead, WorkerS3ClientAdapter,
+    WorkerS3ClientTrait,
+};
+
+/// The session name roles are assumed under, which shows up in the owning account's CloudTrail.
+pub const ROLE_SESSION_NAME: &str = "mini-cluster-worker";
+
+// Per-bucket credentials. Out of the box, the worker reads from S3 with whatever credentials the
+// environment gives it (environment variables, the default profile, the instance role...). That
+// only works for buckets those credentials can read, so reading buckets owned by several
+// different accounts meant handing the worker credentials that could read all of them.
+//
+// Instead, the worker can be configured with credential routes (`WORKER_S3_CREDENTIALS`), which
+// map bucket name patterns to where the credentials for reading them come from: either a profile
+// in the AWS credentials file, or a role to assume with STS. Routes are separated by commas, e.g.
+//
+//     partner-*=role:arn:aws:iam::123456789012:role/reader,archive=profile:archive
+//
+// Requests to a bucket are made with the credentials of the first route matching it (patterns are
+// globs, see `file::matches_pattern`), or the environment's if none does. Assumed roles'
+// credentials are fetched on a job's first request to one of their buckets, and refreshed as
+// they expire.
+
+/// Where the credentials for reading a bucket come from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CredentialSource {
+    /// A profile in the AWS credentials file.
+    Profile(String),
+    /// The ARN of a role to assume (with the environment's credentials).
+    Role(String),
+}
+
+/// The credentials to read the buckets whose names match `bucket` with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CredentialRoute {
+    pub bucket: String,
+    pub source: CredentialSource,
+}
+
+impl CredentialRoute {
+    /// Parses a route from its config form: `<bucket pattern>=profile:<profile name>` or
+    /// `<bucket pattern>=role:<role ARN>`.
+    pub fn parse(route: &str) -> Result<CredentialRoute> {
+        let invalid = || WorkerError::new(
+            ErrKind::ValidationError,
+            &format!(
+                "Bad credential route {:?}: expected <bucket>=profile:<name> or \
+                <bucket>=role:<ARN>.",
+                route
+            )
+        );
+        let mut parts = route.trim().splitn(2, '=');
+        let bucket = parts.next().unwrap_or("").trim();
+        let source = parts.next().ok_or_else(invalid)?.trim();
+        if bucket.is_empty() {
+            Err(invalid())?
+        }
+        let source = if let Some(profile) = source.strip_prefix("profile:") {
+            CredentialSource::Profile(profile.to_owned())
+        } else if let Some(arn) = source.strip_prefix("role:") {
+            if !arn.starts_with("arn:") {
+                Err(invalid())?
+            }
+            CredentialSource::Role(arn.to_owned())
+        } else {
+            Err(invalid())?
+        };
+        Ok(CredentialRoute { bucket: bucket.to_owned(), source })
+    }
+
+    /// Parses a comma-separated list of routes, as found in `WORKER_S3_CREDENTIALS`.
+    pub fn parse_list(routes: &str) -> Result<Vec<CredentialRoute>> {
+        routes.split(",")
+            .filter(|route| !route.trim().is_empty())
+            .map(CredentialRoute::parse)
+            .collect()
+    }
+}
+
+/// Returns the index of the first route matching the bucket, if any does.
+pub fn route_for(routes: &[CredentialRoute], bucket: &str) -> Option<usize> {
+    routes.iter().position(|route| matches_pattern(&route.bucket, bucket))
+}
+
+/// An S3 client that makes every request with the credentials its bucket's route says to (see
+/// `route_for`), or the environment's if there is no such route.
+#[derive(Clone)]
+pub struct RoutedS3Client {
+    default: S3Client,
+    routes: Vec<CredentialRoute>,
+    clients: Vec<S3Client>,
+}
+
+impl RoutedS3Client {
+    pub fn new(region: Region, routes: &[CredentialRoute]) -> Result<RoutedS3Client> {
+        let mut clients = vec![];
+        for route in routes {
+            let client = match &route.source {
+                CredentialSource::Profile(profile) => {
+                    let mut provider = ProfileProvider::new()?;
+                    provider.set_profile(profile.as_str());
+                    S3Client::new_with(HttpClient::new()?, provider, region.clone())
+                },
+                CredentialSource::Role(arn) => {
+                    let provider = StsAssumeRoleSessionCredentialsProvider::new(
+                        StsClient::new(region.clone()),
+                        arn.clone(),
+                        ROLE_SESSION_NAME.to_owned(),
+                        None, None, None, None,
+                    );
+                    // Without this, the role would be assumed all over again for every request.
+                    let provider = AutoRefreshingProvider::new(provider)?;
+                    S3Client::new_with(HttpClient::new()?, provider, region.clone())
+                },
+            };
+            clients.push(client);
+        }
+        Ok(RoutedS3Client { default: S3Client::new(region), routes: routes.to_vec(), clients })
+    }
+
+    fn client_for(&self, bucket: &str) -> &S3Client {
+        match route_for(&self.routes, bucket) {
+            Some(i) => &self.clients[i],
+            None => &self.default,
+        }
+    }
+}
+
+#[async_trait]
+impl WorkerS3ClientTrait for RoutedS3Client {
+    async fn _get_object(
+        &self,
+        input: GetObjectRequest,
+        out: &mut (dyn Write + Send),
+        on_bytes: &(dyn Fn(u64) + Sync),
+    ) -> Result<u64> {
+        self.client_for(&input.bucket)._get_object(input, out, on_bytes).await
+    }
+
+    async fn _head_object(&self, input: HeadObjectRequest) -> Result<ObjectHead> {
+        self.client_for(&input.bucket)._head_object(input).await
+    }
+
+    async fn _list_objects(&self, input: ListObjectsV2Request)
+        -> Result<(Vec<String>, Option<String>)> {
+        self.client_for(&input.bucket)._list_objects(input).await
+    }
+
+    async fn _put_object(&self, input: PutObjectRequest) -> Result<Option<String>> {
+        self.client_for(&input.bucket)._put_object(input).await
+    }
+
+    async fn _create_multipart_upload(&self, input: CreateMultipartUploadRequest)
+        -> Result<Option<String>> {
+        self.client_for(&input.bucket)._create_multipart_upload(input).await
+    }
+
+    async fn _upload_part(&self, input: UploadPartRequest) -> Result<Option<String>> {
+        self.client_for(&input.bucket)._upload_part(input).await
+    }
+
+    async fn _complete_multipart_upload(&self, input: CompleteMultipartUploadRequest)
+        -> Result<Option<String>> {
+        self.client_for(&input.bucket)._complete_multipart_upload(input).await
+    }
+
+    async fn _abort_multipart_upload(&self, input: AbortMultipartUploadRequest) -> Result<()> {
+        self.client_for(&input.bucket)._abort_multipart_upload(input).await
+    }
+}
+
+/// Creates an S3 client that reads buckets with the credentials the routes say to.
+pub fn create_s3_client(
+    routes: &[CredentialRoute]
+) -> Result<WorkerS3ClientAdapter<FaultyS3Client<RoutedS3Client>>> {
+    let client = RoutedS3Client::new(Region::UsEast1, routes)?;
+    Ok(WorkerS3ClientAdapter::new(with_s3_faults(client)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_routes() {
+        let routes = CredentialRoute::parse_list(
+            "partner-*=role:arn:aws:iam::123456789012:role/reader, archive=profile:archive,"
+        ).unwrap();
+        assert_eq!(routes, vec![
+            CredentialRoute {
+                bucket: "partner-*".to_owned(),
+                source: CredentialSource::Role(
+                    "arn:aws:iam::123456789012:role/reader".to_owned()
+                ),
+            },
+            CredentialRoute {
+                bucket: "archive".to_owned(),
+                source: CredentialSource::Profile("archive".to_owned()),
+            },
+        ]);
+        assert!(CredentialRoute::parse("archive").is_err());
+        assert!(CredentialRoute::parse("=profile:archive").is_err());
+        assert!(CredentialRoute::parse("archive=key:hunter2").is_err());
+        assert!(CredentialRoute::parse("archive=role:reader").is_err());
+
+        // The first route to match a bucket is the one it takes.
+        let routes = CredentialRoute::parse_list("a-*=profile:a,*=profile:b").unwrap();
+        assert_eq!(route_for(&routes, "a-1"), Some(0));
+        assert_eq!(route_for(&routes, "b-1"), Some(1));
+        assert_eq!(route_for(&routes[..1], "b-1"), None);
+    }
+}
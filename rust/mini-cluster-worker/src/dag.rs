@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use crate::err::Result;
+use crate::sandbox::InvalidOp;
+use crate::workload::Op;
+
+// The order ops run in. Originally, a workload was a straight line: every op ran after the one
+// before it, and the last one returned the result. That can't express e.g. two tables being
+// prepared independently of one another and then joined, so ops can now name the ops they
+// depend on instead (`Op.depends_on`, by sequence number). Ops that don't depend on one another,
+// directly or indirectly, can run at the same time.
+//
+// A workload in which no op names any dependencies at all gets the straight line, so that older
+// clients keep working as they always have.
+
+/// Returns, for every op, the positions of the ops it depends on.
+pub fn dependencies(ops: &[Op]) -> Result<Vec<Vec<usize>>> {
+    if ops.iter().all(|op| op.get_depends_on().is_empty()) {
+        return Ok((0..ops.len()).map(|i| if i == 0 { vec![] } else { vec![i - 1] }).collect());
+    }
+
+    let mut positions = HashMap::new();
+    for (op_index, op) in ops.iter().enumerate() {
+        if positions.insert(op.get_op_sequence_num(), op_index).is_some() {
+            Err(InvalidOp {
+                op_index,
+                message: format!(
+                    "Another op already has sequence number {}.", op.get_op_sequence_num()
+                ),
+            })?
+        }
+    }
+    ops.iter().enumerate().map(|(op_index, op)| {
+        op.get_depends_on().iter().map(|op_sequence_num| match positions.get(op_sequence_num) {
+            Some(&dependency) if dependency != op_index => Ok(dependency),
+            Some(_) => Err(InvalidOp {
+                op_index, message: "Op depends on itself.".to_owned()
+            }.into()),
+            None => Err(InvalidOp {
+                op_index,
+                message: format!("Op depends on op {}, which doesn't exist.", op_sequence_num),
+            }.into()),
+        }).collect::<Result<Vec<_>>>()
+    }).collect()
+}
+
+/// Groups the ops into stages, each of which only depends on the stages before it, so that the
+/// ops within a stage can all run at once. Every op goes into the earliest stage it can. Ops that
+/// are caught up in a dependency cycle can't go into any stage at all, which is an `InvalidOp`.
+pub fn schedule(ops: &[Op]) -> Result<Vec<Vec<usize>>> {
+    let dependencies = dependencies(ops)?;
+    let mut scheduled = vec![false; ops.len()];
+    let mut stages: Vec<Vec<usize>> = vec![];
+    while scheduled.iter().any(|done| !done) {
+        let stage = (0..ops.len())
+            .filter(|&i| !scheduled[i] && dependencies[i].iter().all(|&j| scheduled[j]))
+            .collect::<Vec<_>>();
+        if stage.is_empty() {
+            let op_index = scheduled.iter().position(|done| !done).unwrap();
+            Err(InvalidOp {
+                op_index,
+                message: "Op is part of (or depends on) a dependency cycle.".to_owned(),
+            })?
+        }
+        for &i in stage.iter() {
+            scheduled[i] = true;
+        }
+        stages.push(stage);
+    }
+    Ok(stages)
+}
+
+/// Returns whether or not each op is a sink: an op that no other op depends on. Sinks always
+/// return their result sets. In a straight line of ops, the only sink is the last op.
+pub fn sinks(ops: &[Op]) -> Result<Vec<bool>> {
+    let mut is_sink = vec![true; ops.len()];
+    for &dependency in dependencies(ops)?.iter().flatten() {
+        is_sink[dependency] = false;
+    }
+    Ok(is_sink)
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::RepeatedField;
+
+    use crate::fixtures::*;
+    use super::*;
+
+    fn craft_ops(depends_on: &[&[i32]]) -> Vec<Op> {
+        depends_on.iter().enumerate().map(|(i, dependencies)| {
+            let mut op = craft_op_message(
+                Some(RepeatedField::new()), Some("SELECT 1".to_owned()), Some(i as i32 + 1)
+            );
+            op.set_depends_on(dependencies.to_vec());
+            op
+        }).collect()
+    }
+
+    #[test]
+    fn test_linear_schedule() {
+        let ops = craft_ops(&[&[], &[], &[]]);
+        assert_eq!(schedule(&ops).unwrap(), vec![vec![0], vec![1], vec![2]]);
+        assert_eq!(sinks(&ops).unwrap(), vec![false, false, true]);
+        assert!(schedule(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dag_schedule() {
+        // Ops 1 and 2 prepare two tables, op 3 joins them, and op 4 reports on op 1 on the side.
+        let ops = craft_ops(&[&[], &[], &[1, 2], &[1]]);
+        assert_eq!(schedule(&ops).unwrap(), vec![vec![0, 1], vec![2, 3]]);
+        assert_eq!(sinks(&ops).unwrap(), vec![false, false, true, true]);
+    }
+
+    #[test]
+    fn test_invalid_dependencies() {
+        let op_index = |ops: &[Op]| {
+            schedule(ops).unwrap_err().downcast_ref::<InvalidOp>().unwrap().op_index
+        };
+        assert_eq!(op_index(&craft_ops(&[&[], &[5]])), 1);
+        assert_eq!(op_index(&craft_ops(&[&[], &[2]])), 1);
+        assert_eq!(op_index(&craft_ops(&[&[3], &[1], &[2]])), 0);
+
+        let mut ops = craft_ops(&[&[], &[1]]);
+        ops[1].set_op_sequence_num(1);
+        assert_eq!(op_index(&ops), 1);
+    }
+}
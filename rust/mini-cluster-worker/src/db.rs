@@ -1,47 +1,241 @@
-use sqlx::{Connection, Sqlite, SqliteConnection, migrate::MigrateDatabase};
-use sqlx::sqlite::SqliteRow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use sqlx::{Connection, Executor, Sqlite, SqliteConnection, migrate::MigrateDatabase};
+use sqlx::pool::PoolConnection;
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions, SqliteRow};
 
 use crate::Result;
 use crate::err::{WorkerError, ErrKind};
+use crate::encryption::{self, open_cached};
 use crate::file::get_cache_dir;
+use crate::functions::{math_functions, register_functions, ScalarFunction};
+use crate::stats;
+
+/// The most connections a job's pool will hold open at once. This is also the most ops a job runs
+/// at the same time (see `dag::schedule`), and the most tables it loads at the same time.
+pub const MAX_POOL_CONNECTIONS: u32 = 4;
+
+/// SQLite settings (pragmas) applied to every connection the worker opens.
+///
+/// SQLite's defaults are tuned for safety on ancient hardware, which makes loading tables with
+/// `Table::dump` an order of magnitude slower than it needs to be. The database on the worker is
+/// just a cache of data that lives in S3, so we can afford to trade some durability for speed:
+/// with `journal_mode=WAL` and `synchronous=NORMAL`, a crash can lose the last few transactions,
+/// but never corrupts the database.
+///
+/// The options also carry any custom SQL functions the worker registers on its connections.
+#[derive(Debug, Clone)]
+pub struct DatabaseOptions {
+    /// `PRAGMA journal_mode`. One of `DELETE`, `TRUNCATE`, `PERSIST`, `MEMORY`, `WAL`, or `OFF`.
+    pub journal_mode: String,
+    /// `PRAGMA synchronous`. One of `OFF`, `NORMAL`, `FULL`, or `EXTRA`.
+    pub synchronous: String,
+    /// `PRAGMA page_size`, in bytes. Note that this only takes effect on a brand new database.
+    pub page_size: u32,
+    /// `PRAGMA cache_size`. Positive values are in pages, negative values in KiB.
+    pub cache_size: i64,
+    /// `PRAGMA mmap_size`, in bytes. 0 disables memory-mapped I/O.
+    pub mmap_size: u64,
+    /// Custom scalar functions to register on every connection, on top of the math functions
+    /// (see `functions::math_functions`), which are always there.
+    pub functions: Vec<ScalarFunction>,
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> DatabaseOptions {
+        DatabaseOptions {
+            journal_mode: "WAL".to_owned(),
+            synchronous: "NORMAL".to_owned(),
+            page_size: 4096,
+            cache_size: -64 * 1024,
+            mmap_size: 256 * 1024 * 1024,
+            functions: vec![],
+        }
+    }
+}
+
+const JOURNAL_MODES: [&str; 6] = ["DELETE", "TRUNCATE", "PERSIST", "MEMORY", "WAL", "OFF"];
+const SYNCHRONOUS_MODES: [&str; 4] = ["OFF", "NORMAL", "FULL", "EXTRA"];
 
-// Best practice when working with SQLite is to only ever have a single connection open at a time
-// per program instance, and to close those connections often. When interacting with SQLite, it is
-// apparently cheaper in the long run to e.g. create, query, and immediately close a connection
-// then it is to just keep connections open indefinitely. This is because the time cost of opening
-// a new connection every time a query needs to made is smaller than the resource exhaustion risk
-// of having too many connections open at once (e.g. you might run out of file descriptors at the
-// OS level). See e.g. https://stackoverflow.com/a/19187244/1993206.
+impl DatabaseOptions {
+    /// Checks that the options are ones SQLite understands. The string options are pasted
+    /// straight into the pragmas, so this also keeps anything but a known keyword out of them.
+    pub fn validate(&self) -> Result<()> {
+        if !JOURNAL_MODES.contains(&self.journal_mode.to_uppercase().as_str()) {
+            Err(WorkerError::new(
+                ErrKind::DatabaseError, &format!("Unknown journal mode {:?}.", self.journal_mode)
+            ))?
+        }
+        if !SYNCHRONOUS_MODES.contains(&self.synchronous.to_uppercase().as_str()) {
+            Err(WorkerError::new(
+                ErrKind::DatabaseError, &format!("Unknown synchronous mode {:?}.", self.synchronous)
+            ))?
+        }
+        Ok(())
+    }
+
+    /// Returns the `PRAGMA` statements that apply these options.
+    pub fn pragmas(&self) -> String {
+        // `page_size` has to come before `journal_mode`: once a database is in WAL mode, its page
+        // size can no longer be changed. If the cache is encrypted, the key has to come before
+        // anything at all (see `encryption`).
+        let key = encryption::key_pragma().map_or(String::new(), |pragma| pragma + "\n");
+        key + &format!(
+            "PRAGMA page_size = {};\n\
+            PRAGMA journal_mode = {};\n\
+            PRAGMA synchronous = {};\n\
+            PRAGMA cache_size = {};\n\
+            PRAGMA mmap_size = {};",
+            self.page_size,
+            self.journal_mode.to_uppercase(),
+            self.synchronous.to_uppercase(),
+            self.cache_size,
+            self.mmap_size,
+        )
+    }
+
+    /// Adds a custom scalar function, to be registered on every connection.
+    pub fn register_function(&mut self, function: ScalarFunction) {
+        self.functions.push(function);
+    }
+
+    /// Applies the options to a connection.
+    pub async fn apply(&self, conn: &mut SqliteConnection) -> Result<()> {
+        register_functions(conn, &self.functions)?;
+        conn.execute(self.pragmas().as_str()).await?;
+        Ok(())
+    }
+}
+
+// Best practice when working with SQLite is to only ever have a small number of connections open
+// at a time per program instance. Too many open connections risks resource exhaustion (e.g. you
+// might run out of file descriptors at the OS level). See e.g.
+// https://stackoverflow.com/a/19187244/1993206.
+//
+// We used to take this to the extreme, and create a fresh connection for every single call (and
+// close it straight after). But when ops are small the connect-close churn dominates the runtime.
+// So nowadays every `Database` holds a small pool of connections, which the job it belongs to
+// draws from, and which is closed when the job is done with it.
 //
-// As such, we do not store a single connection at the struct level, preferring instead to create
-// then whenever needed, on a function-by-function basis.
-pub struct Database {}
+// The in-memory database used by ephemeral jobs needs some extra care. An in-memory SQLite
+// database only lives as long as the connection that created it, so its pool holds exactly one
+// connection, which is never allowed to expire. This has the nice side effect that ephemeral
+// jobs never touch the disk, and can't see (or leave behind) anyone else's tables.
+pub struct Database {
+    pub pool: SqlitePool,
+    in_memory: bool,
+}
 
 impl Database {
     /// Returns the database path (e.g. the filesystem path).
-    pub fn get_db_path() -> String { get_cache_dir() + "db.sqlite" }
-
-    /// Returns the database URL (e.g. the URI that can be passed to SQLx).
-    pub fn get_db_url() -> String { "file://".to_owned() + Database::get_db_path().as_str() }
+    pub fn get_db_path() -> PathBuf { get_cache_dir().join("db.sqlite") }
 
-    /// Connects to the database, returning an open connection usable for querying.
-    pub async fn connect() -> Result<SqliteConnection> {
-        let db_path = Database::get_db_path();
-        let db_url = Database::get_db_url();
+    /// Returns the database URL (e.g. the URI that can be passed to SQLx). SQLx takes whatever
+    /// follows the scheme as a filesystem path, so this holds up with Windows paths too, which
+    /// `file://` URIs don't.
+    pub fn get_db_url() -> String { format!("sqlite://{}", Database::get_db_path().display()) }
 
-        if !std::path::Path::new(&db_path).exists() {
-            Sqlite::create_database(&db_path).await?;
+    /// Creates the database file, if it doesn't exist yet.
+    async fn create_if_missing() -> Result<()> {
+        if !Database::get_db_path().exists() {
+            Sqlite::create_database(&Database::get_db_url()).await?;
         }
+        Ok(())
+    }
 
-        let conn: SqliteConnection = SqliteConnection::connect(&db_url).await?;
+    /// Connects to the database, returning a single open connection usable for querying, outside
+    /// of any pool. The connection uses the default `DatabaseOptions`.
+    pub async fn connect() -> Result<SqliteConnection> {
+        #[cfg(feature = "chaos")]
+        crate::chaos::database_fault().await?;
+        Database::create_if_missing().await?;
+        let mut conn: SqliteConnection = SqliteConnection::connect(&Database::get_db_url()).await?;
+        register_functions(&mut conn, &math_functions())?;
+        DatabaseOptions::default().apply(&mut conn).await?;
         Ok(conn)
     }
 
     pub async fn new() -> Result<Database> {
-        // Check that the connection can successfully be made first.
-        let conn = Database::connect().await?;
-        conn.close();
-        Ok(Database {})
+        Database::with_options(&DatabaseOptions::default()).await
+    }
+
+    /// Like `new`, but with the given options applied to every connection in the pool.
+    pub async fn with_options(options: &DatabaseOptions) -> Result<Database> {
+        options.validate()?;
+        Database::create_if_missing().await?;
+        let pragmas = options.pragmas();
+        let functions = options.functions.clone();
+        // `connect` makes the first connection straight away, which checks that the connection
+        // can successfully be made.
+        let pool = SqlitePoolOptions::new()
+            .max_connections(MAX_POOL_CONNECTIONS)
+            .after_connect(move |conn| {
+                let pragmas = pragmas.clone();
+                let functions = functions.clone();
+                Box::pin(async move {
+                    Database::setup_connection(conn, &functions)?;
+                    conn.execute(pragmas.as_str()).await?;
+                    Ok(())
+                })
+            })
+            .connect(&Database::get_db_url())
+            .await?;
+        Ok(Database { pool, in_memory: false })
+    }
+
+    /// Creates a private, in-memory database (`sqlite::memory:`), which goes away when this
+    /// `Database` is dropped.
+    pub async fn new_in_memory() -> Result<Database> {
+        Database::new_in_memory_with_options(&DatabaseOptions::default()).await
+    }
+
+    /// Like `new_in_memory`, but with the given options. There's no disk I/O to tune, so only
+    /// their `functions` apply.
+    pub async fn new_in_memory_with_options(options: &DatabaseOptions) -> Result<Database> {
+        let functions = options.functions.clone();
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .min_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .after_connect(move |conn| {
+                let functions = functions.clone();
+                Box::pin(async move {
+                    Database::setup_connection(conn, &functions)?;
+                    Ok(())
+                })
+            })
+            .connect("sqlite::memory:")
+            .await?;
+        Ok(Database { pool, in_memory: true })
+    }
+
+    /// Sets up a freshly opened pool connection, e.g. registers the worker's SQL functions on it.
+    /// Our errors aren't `Send`, which the pool needs, so they're turned into `sqlx` ones.
+    fn setup_connection(
+        conn: &mut SqliteConnection, functions: &[ScalarFunction]
+    ) -> std::result::Result<(), sqlx::Error> {
+        register_functions(conn, &math_functions())
+            .and_then(|_| register_functions(conn, functions))
+            .map_err(|err| sqlx::Error::Configuration(err.to_string().into()))
+    }
+
+    pub fn is_in_memory(&self) -> bool {
+        self.in_memory
+    }
+
+    /// The most connections the pool holds open at once.
+    pub fn max_connections(&self) -> usize {
+        if self.in_memory { 1 } else { MAX_POOL_CONNECTIONS as usize }
+    }
+
+    /// Takes a connection out of the pool. It goes back in when it is dropped. The connection
+    /// derefs to a `SqliteConnection`, so `&mut *conn` can be used to run queries.
+    pub async fn connection(&self) -> Result<PoolConnection<Sqlite>> {
+        #[cfg(feature = "chaos")]
+        crate::chaos::database_fault().await?;
+        Ok(self.pool.acquire().await?)
     }
 
     /// Drops the database (e.g. clears out the database cache) if it exists.
@@ -55,14 +249,254 @@ impl Database {
     }
 }
 
+/// The table the worker keeps its table registry in. See `TableFingerprint`.
+pub const TABLE_REGISTRY: &str = "_mini_cluster_tables";
+
+/// Where a table's contents came from: the S3 object it was loaded from, and the version of that
+/// object (its ETag and size) at the time.
+///
+/// The database outlives any one job, so a `dataset_N` table is usually still around from some
+/// earlier job when the next one asks for it. But `dataset_N` is just a name, and S3 objects can be
+/// overwritten, so the table on hand isn't necessarily the one the job wants. The worker keeps
+/// a registry table recording the fingerprint of every table it loads, so that `Job::build` can
+/// tell the two cases apart.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableFingerprint {
+    pub path: String,
+    pub etag: String,
+    pub size: u64,
+}
+
+/// Creates the table registry, if it doesn't exist yet.
+pub(crate) async fn create_registry_if_missing(conn: &mut SqliteConnection) -> Result<()> {
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (\n\
+            name TEXT PRIMARY KEY,\n\
+            path TEXT NOT NULL,\n\
+            etag TEXT NOT NULL,\n\
+            size INTEGER NOT NULL\n\
+        );",
+        TABLE_REGISTRY
+    )).execute(conn).await?;
+    Ok(())
+}
+
+/// How many CSV records `Table::dump` inserts per transaction, by default.
+pub const INGEST_CHUNK_SIZE: usize = 10_000;
+
+/// How far along `Table::dump` is with loading a file: the number of rows inserted, and the
+/// number of bytes of the file they were read from.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IngestProgress {
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+/// Returns whether or not a column of the given SQL type has numeric type affinity, going by
+/// SQLite's rules for working out a column's affinity from its declared type (see
+/// https://www.sqlite.org/datatype3.html#determination_of_column_affinity).
+fn is_numeric_type(col_type: &str) -> bool {
+    let col_type = col_type.to_uppercase();
+    if col_type.contains("INT") {
+        return true;
+    }
+    if col_type.is_empty()
+        || ["CHAR", "CLOB", "TEXT", "BLOB"].iter().any(|name| col_type.contains(name)) {
+        return false;
+    }
+    // Everything else (`REAL`, `FLOAT`, `DOUBLE`, `NUMERIC`, `DECIMAL`, `BOOLEAN`, `DATE`...) is
+    // either REAL or NUMERIC.
+    true
+}
+
+/// Returns the SQLite function that normalizes values for a column of the given SQL type, if it
+/// is a date/time type.
+///
+/// SQLite has no date/time storage class of its own. We store dates and datetimes as ISO-8601
+/// TEXT (`YYYY-MM-DD` and `YYYY-MM-DD HH:MM:SS`), which sorts and compares correctly, and which
+/// all of SQLite's date and time functions understand. The CSV may have them in any format those
+/// functions understand (e.g. `2021-03-01T10:00:00Z`, or a Julian day number like `2459275.5`);
+/// running the values through `date` or `datetime` on the way in puts them in the standard one.
+fn time_function(col_type: &str) -> Option<&'static str> {
+    match col_type.to_lowercase().as_str() {
+        "date" => Some("date"),
+        "datetime" | "timestamp" => Some("datetime"),
+        _ => None,
+    }
+}
+
+/// Returns whether or not a value looks like something `time_function` can normalize: an
+/// ISO-8601 date (optionally followed by a time) or a Julian day number. SQLite's date and time
+/// functions return NULL for anything else, so without this check a malformed value would
+/// silently turn into a NULL.
+fn is_time_value(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    let is_digits = |range: std::ops::Range<usize>| bytes[range].iter().all(u8::is_ascii_digit);
+    let is_iso_date = bytes.len() >= 10
+        && is_digits(0..4) && bytes[4] == b'-' && is_digits(5..7) && bytes[7] == b'-'
+        && is_digits(8..10);
+    is_iso_date || value.parse::<f64>().is_ok()
+}
+
+/// Runs `EXPLAIN QUERY PLAN` on a statement, returning SQLite's plan for it as text, one step
+/// per line. Steps nested inside other steps (e.g. the scans in a subquery) are indented under
+/// them, same as in the `sqlite3` shell. Statements that don't read any tables (e.g. `CREATE
+/// TABLE`) have an empty plan.
+///
+/// The plan's wording is SQLite's own, and changes from version to version. It's meant for
+/// humans, not for parsing.
+pub async fn explain_query_plan(conn: &mut SqliteConnection, sql: &str) -> Result<String> {
+    let steps: Vec<(i64, i64, i64, String)> =
+        sqlx::query_as(&format!("EXPLAIN QUERY PLAN {}", sql)).fetch_all(conn).await?;
+    // Every step names its parent step, which always comes before it (0 means it has none).
+    let mut depths: HashMap<i64, usize> = HashMap::new();
+    let mut lines = vec![];
+    for (id, parent, _, detail) in steps {
+        let depth = depths.get(&parent).map_or(0, |depth| depth + 1);
+        depths.insert(id, depth);
+        lines.push(format!("{}{}", "  ".repeat(depth), detail));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// A column of a CSV file, as declared in its header. Every header field is a column name and a
+/// SQL type, separated by the first underscore: e.g. `price_real` is a `REAL` column named `price`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnSpec {
+    /// The position of the column in the CSV.
+    pub index: usize,
+    pub name: String,
+    pub col_type: String,
+}
+
+/// Parses a CSV header into its columns. Empty header fields are skipped, in case there are any.
+pub fn parse_columns(headers: &csv::StringRecord) -> Result<Vec<ColumnSpec>> {
+    // Although it's headers plural, there's only one real header, which is always the first
+    // column. If there is no first column (e.g. the CSV is empty) headers is an empty record.
+    if headers.len() == 0 {
+        Err(WorkerError::new(ErrKind::DatabaseError,"Error: CSV is empty."))?
+    }
+    let mut columns = vec![];
+    for (index, col) in headers.iter().enumerate() {
+        if col == "" { continue }
+
+        let splitter_idx = match col.find("_") {
+            Some(v) => v,
+            None => return Err(
+                WorkerError::new(ErrKind::DatabaseError,"Error: CSV field has no type.")
+            )?
+        };
+        columns.push(ColumnSpec {
+            index,
+            name: col[..splitter_idx].to_owned(),
+            col_type: col[(splitter_idx + 1)..].to_owned(),
+        });
+    }
+    Ok(columns)
+}
+
 pub struct Table {
     name: String,
-    source: String
+    source: PathBuf,
+    force_reload: bool,
+    append: bool,
+    columns: Option<Vec<ColumnSpec>>,
+    partition: Vec<(String, Option<String>)>,
+    chunk_size: usize,
+    null_tokens: Vec<String>,
 }
 
 impl Table {
-    pub fn new(name: &str, source: &str) -> Table {
-        Table { name: name.to_owned(), source: source.to_owned() }
+    pub fn new(name: &str, source: impl AsRef<Path>) -> Table {
+        Table {
+            name: name.to_owned(),
+            source: source.as_ref().to_owned(),
+            force_reload: false,
+            append: false,
+            columns: None,
+            partition: vec![],
+            chunk_size: INGEST_CHUNK_SIZE,
+            null_tokens: vec![],
+        }
+    }
+
+    /// Sets the cell values that `dump` loads as NULL, e.g. `NA` or `\N`, in any column.
+    pub fn null_tokens(mut self, null_tokens: &[String]) -> Table {
+        self.null_tokens = null_tokens.to_vec();
+        self
+    }
+
+    /// Sets the table's columns, rather than going by the file's header, which `dump` then skips.
+    /// The file's columns are taken to be these, in order.
+    pub fn columns(mut self, columns: &[ColumnSpec]) -> Table {
+        self.columns = Some(columns.to_vec());
+        self
+    }
+
+    /// Sets the partition the file belongs to, as key/value pairs (see `file::hive_partition`).
+    /// Every key becomes a `TEXT` column of the table, after the file's own, holding the value
+    /// (or NULL) in every row the file has.
+    pub fn partition(mut self, partition: &[(String, Option<String>)]) -> Table {
+        self.partition = partition.to_vec();
+        self
+    }
+
+    /// Sets the number of records inserted per transaction (see `dump_into_with_progress`).
+    pub fn chunk_size(mut self, chunk_size: usize) -> Table {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// If set, `dump` drops and reloads the table even if it already exists.
+    pub fn force_reload(mut self, force_reload: bool) -> Table {
+        self.force_reload = force_reload;
+        self
+    }
+
+    /// If set, `dump` adds the file's rows to the table if it already exists, rather than
+    /// leaving it as it is. The file has to have the same columns as the table.
+    pub fn append(mut self, append: bool) -> Table {
+        self.append = append;
+        self
+    }
+
+    /// Checks whether or not this table exists in the database.
+    pub async fn exists(&self, conn: &mut SqliteConnection) -> Result<bool> {
+        // The name is passed as a bound parameter, rather than pasted into the query, so that it
+        // is compared as a string.
+        let (count,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?"
+        ).bind(&self.name).fetch_one(conn).await?;
+        Ok(count > 0)
+    }
+
+    /// Returns the fingerprint this table was registered with, if any. Tables without one (e.g.
+    /// ones loaded before the registry existed) should be assumed to be out of date.
+    pub async fn fingerprint(
+        &self, conn: &mut SqliteConnection
+    ) -> Result<Option<TableFingerprint>> {
+        create_registry_if_missing(&mut *conn).await?;
+        let row: Option<(String, String, i64)> = sqlx::query_as(
+            &format!("SELECT path, etag, size FROM {} WHERE name = ?", TABLE_REGISTRY)
+        ).bind(&self.name).fetch_optional(conn).await?;
+        Ok(row.map(|(path, etag, size)| TableFingerprint { path, etag, size: size as u64 }))
+    }
+
+    /// Records where this table's contents came from, replacing any earlier record.
+    pub async fn register(
+        &self, conn: &mut SqliteConnection, fingerprint: &TableFingerprint
+    ) -> Result<()> {
+        create_registry_if_missing(&mut *conn).await?;
+        sqlx::query(
+            &format!("INSERT OR REPLACE INTO {} VALUES (?, ?, ?, ?)", TABLE_REGISTRY)
+        )
+            .bind(&self.name)
+            .bind(&fingerprint.path)
+            .bind(&fingerprint.etag)
+            .bind(fingerprint.size as i64)
+            .execute(conn)
+            .await?;
+        Ok(())
     }
 
     /// Dumps the contents of the file at `source` into the database instance.
@@ -70,86 +504,190 @@ impl Table {
     /// The header in the chosen CSV must follow the schema `name_type`, where `name` is the
     /// column name and `type` is a SQL type that SQLite understands.
     ///
+    /// Columns of type `date` and `datetime` (or `timestamp`) are stored as ISO-8601 text. Their
+    /// cells have to be ISO-8601 dates or datetimes, or Julian day numbers.
+    ///
+    /// Cells matching one of the `null_tokens` are loaded as NULL. So are empty cells in numeric
+    /// columns, where an empty string isn't a meaningful value. In any other column, an empty
+    /// cell is just that: an empty string.
+    ///
     /// If the table already exists, it is assumed that the information is already cached, so this
-    /// method is a no-op.
+    /// method is a no-op, unless `force_reload` is set, in which case the table is dropped and
+    /// loaded from scratch.
+    ///
+    /// Once the file is loaded, the table's statistics are collected (see `stats`).
     pub async fn dump(&self) -> Result<()> {
-        let mut reader = csv::Reader::from_path(&self.source)?;
         let mut conn = Database::connect().await?;
+        self.dump_into(&mut conn).await?;
+        conn.close();
+        Ok(())
+    }
 
-        let table_exists = sqlx::query(
-            &format!(
-                "SELECT name FROM sqlite_master WHERE type='table' AND name={}", self.name
-            )
-        ).fetch_all(&mut conn).await;
-        let table_exists = table_exists.is_ok();
-
-        if !table_exists {
-            let headers = reader.headers()?;
-            // Although it's headers plural, there's only one real header, which is always the
-            // first column. If there is no first column (e.g. the CSV is empty) headers returns
-            // an empty record.
-            if headers.len() == 0 {
-                Err(WorkerError::new(ErrKind::DatabaseError,"Error: CSV is empty."))?
-            }
+    /// Like `dump`, but over an existing connection (e.g. to an in-memory database).
+    pub async fn dump_into(&self, conn: &mut SqliteConnection) -> Result<()> {
+        self.dump_into_with_progress(conn, |_| {}).await?;
+        Ok(())
+    }
 
-            let mut columns = vec![];
-            let mut column_types = vec![];
-            for col in headers {
-                // Skip empty columns, in case there is one.
-                if col == "" { continue }
-
-                let splitter_idx = match col.find("_") {
-                    Some(v) => v,
-                    None => return Err(
-                        WorkerError::new(ErrKind::DatabaseError,"Error: CSV field has no type.")
-                    )?
-                };
-
-                let col_name = &col[..splitter_idx];
-                columns.push(col_name);
-                let col_type = &col[(splitter_idx + 1)..];
-                column_types.push(col_type);
-            }
+    /// Like `dump_into`, but calls `on_progress` after every chunk of records is committed, and
+    /// once more at the end. Returns the final progress, which is all zeroes if the table was
+    /// already loaded.
+    pub async fn dump_into_with_progress<F: FnMut(IngestProgress)>(
+        &self, conn: &mut SqliteConnection, mut on_progress: F
+    ) -> Result<IngestProgress> {
+        let mut progress = IngestProgress::default();
+        if self.force_reload {
+            self.drop_from(&mut *conn).await?;
+        }
+        let exists = self.exists(&mut *conn).await?;
+        if self.append || !exists {
+            // Rows appended to a table add to the bytes it was loaded from.
+            let loaded_before = match exists {
+                true => stats::read(&mut *conn, &self.name).await?.map_or(0, |s| s.get_bytes()),
+                false => 0,
+            };
+            let mut reader = csv::Reader::from_reader(open_cached(&self.source)?.0);
+            let columns = match &self.columns {
+                Some(columns) => columns.clone(),
+                None => parse_columns(reader.headers()?)?,
+            };
+            // Whether or not each column is numeric, and how its values are normalized.
+            let numeric_columns = columns.iter()
+                .map(|column| is_numeric_type(&column.col_type))
+                .collect::<Vec<_>>();
+            let time_functions = columns.iter()
+                .map(|column| time_function(&column.col_type))
+                .collect::<Vec<_>>();
 
             // Build the query.
             let mut create_query = format!("CREATE TABLE {} (\n", self.name).to_owned();
-            for (col_name, col_type) in columns.into_iter().zip(column_types) {
-                create_query += &format!("{} {},\n", col_name, col_type);
+            for column in columns.iter() {
+                create_query += &format!("{} {},\n", column.name, column.col_type);
+            }
+            for (key, _) in self.partition.iter() {
+                create_query += &format!("{} TEXT,\n", key);
             }
             // Remove the last `,\n` to get rid of the trailing comma, which is invalid in SQL.
             create_query = create_query[..(create_query.len() - 2)].to_owned();
             create_query += "\n);";
 
-            sqlx::query(&create_query).execute(&mut conn).await?;
+            if exists {
+                self.check_columns(&mut *conn, &columns).await?;
+            } else {
+                sqlx::query(&create_query).execute(&mut *conn).await?;
+            }
 
-            for record in reader.records() {
-                let record = record?;
-                let record = record.into_iter().collect::<Vec<_>>();
-                let mut insert_query = format!("INSERT INTO {} VALUES (", self.name);
-    
-                for col in record {
-                    insert_query += col;
-                    insert_query += ", "
+            // Outside of a transaction, SQLite commits (and syncs) every single INSERT, which is
+            // painfully slow. But one transaction for the whole file would make loading a
+            // multi-GB file one opaque, multi-minute step. So we go in chunks: each chunk of
+            // records gets a transaction of its own, and committing it is our cue to report
+            // progress.
+            //
+            // The values are bound as parameters, rather than pasted into the query, so that they
+            // can't break (or inject into) the SQL. They are all bound as text; SQLite's type
+            // affinity converts them into numbers wherever the column calls for it.
+            let mut placeholders = time_functions.iter().map(|time_function| match time_function {
+                Some(function) => format!("{}(?)", function),
+                None => "?".to_owned(),
+            }).collect::<Vec<_>>();
+            placeholders.extend(self.partition.iter().map(|_| "?".to_owned()));
+            let insert_query = format!(
+                "INSERT INTO {} VALUES ({});", self.name, placeholders.join(", ")
+            );
+            let mut record = csv::StringRecord::new();
+            let mut rows_in_chunk = 0;
+            let mut tx = conn.begin().await?;
+            while reader.read_record(&mut record)? {
+                let mut query = sqlx::query(&insert_query);
+                for (i, column) in columns.iter().enumerate() {
+                    let value = record.get(column.index).unwrap_or("");
+                    let is_null = self.null_tokens.iter().any(|token| token == value)
+                        || (numeric_columns[i] && value.is_empty());
+                    if !is_null && time_functions[i].is_some() && !is_time_value(value) {
+                        Err(WorkerError::new(
+                            ErrKind::DatabaseError,
+                            &format!(
+                                "Value {:?} in column {} is not an ISO-8601 date or a Julian day \
+                                number.",
+                                value, column.name
+                            )
+                        ))?
+                    }
+                    query = query.bind(if is_null { None } else { Some(value) });
+                }
+                for (_, value) in self.partition.iter() {
+                    query = query.bind(value.as_deref());
+                }
+                query.execute(&mut *tx).await?;
+
+                progress.rows += 1;
+                rows_in_chunk += 1;
+                if rows_in_chunk == self.chunk_size {
+                    tx.commit().await?;
+                    progress.bytes = reader.position().byte();
+                    on_progress(progress);
+                    rows_in_chunk = 0;
+                    tx = conn.begin().await?;
                 }
-                insert_query = insert_query[..(insert_query.len() - 2)].to_owned();
-                insert_query += ");";
-                sqlx::query(&insert_query).execute(&mut conn).await?;
-            }    
+            }
+            tx.commit().await?;
+            progress.bytes = reader.position().byte();
+            on_progress(progress);
+            log!("Loaded {} rows into table {}.", progress.rows, self.name);
+            stats::collect(&mut *conn, &self.name, loaded_before + progress.bytes).await?;
         }
 
-        conn.close();
+        Ok(progress)
+    }
 
+    /// Checks that the table has the given columns, in the same order, e.g. before appending a
+    /// file's rows to it.
+    async fn check_columns(
+        &self, conn: &mut SqliteConnection, columns: &[ColumnSpec]
+    ) -> Result<()> {
+        let existing: Vec<(String,)> = sqlx::query_as(
+            "SELECT name FROM pragma_table_info(?) ORDER BY cid"
+        ).bind(&self.name).fetch_all(conn).await?;
+        let existing = existing.into_iter().map(|(name,)| name).collect::<Vec<_>>();
+        let expected = columns.iter()
+            .map(|column| column.name.clone())
+            .chain(self.partition.iter().map(|(key, _)| key.clone()))
+            .collect::<Vec<_>>();
+        if existing != expected {
+            Err(WorkerError::new(
+                ErrKind::DatabaseError,
+                &format!(
+                    "{} has columns {:?}, but table {} has columns {:?}.",
+                    self.source.display(), expected, self.name, existing
+                )
+            ))?
+        }
         Ok(())
     }
 
     /// Drops this table from the database, if it exists.
     pub async fn drop(&self) -> Result<()> {
         let mut conn = Database::connect().await?;
-        sqlx::query(&format!("DROP TABLE IF EXISTS {}", self.name)).execute(&mut conn).await?;
+        self.drop_from(&mut conn).await?;
         conn.close();
         Ok(())
     }
 
+    /// Like `drop`, but over an existing connection (e.g. to an in-memory database).
+    pub async fn drop_from(&self, conn: &mut SqliteConnection) -> Result<()> {
+        // The registry entry goes first. That way, if something goes wrong between here and the
+        // table being loaded again, we're left with a table that has no fingerprint, which is
+        // treated as out of date, rather than a half-loaded table with a valid-looking one.
+        create_registry_if_missing(&mut *conn).await?;
+        sqlx::query(&format!("DELETE FROM {} WHERE name = ?", TABLE_REGISTRY))
+            .bind(&self.name)
+            .execute(&mut *conn)
+            .await?;
+        stats::remove(&mut *conn, &self.name).await?;
+        sqlx::query(&format!("DROP TABLE IF EXISTS {}", self.name)).execute(conn).await?;
+        Ok(())
+    }
+
     /// Loads the contents of this table into memory.
     pub async fn load(&self) -> Result<Vec<SqliteRow>> {
         let mut conn = Database::connect().await?;
@@ -175,14 +713,14 @@ mod tests {
     use futures::executor::block_on;
     use serial_test::serial;
     
-    // use crate::fixtures::*;
+    use crate::fixtures::*;
     use super::*;
 
     #[test]
     fn test_create_database() {
         let db = block_on(Database::new());
         assert!(db.is_ok());
-        assert!(std::path::Path::new(&Database::get_db_path()).exists())
+        assert!(Database::get_db_path().exists())
     }
 
     #[test]
@@ -200,11 +738,7 @@ mod tests {
     #[test]
     #[serial]
     fn test_dump_table() {
-        let t = Table::new(
-            "foo",
-            "/Users/alekseybilogur/Desktop/mini-cluster/\
-            rust/mini-cluster-worker/tests/artifacts/simple-csv.csv"
-        );
+        let t = Table::new("foo", artifact_path("simple-csv.csv"));
 
         let drop = block_on(t.drop());
         assert!(drop.is_ok());
@@ -232,11 +766,7 @@ mod tests {
     #[test]
     #[serial]
     fn test_load_table() {
-        let t = Table::new(
-            "foo",
-            "/Users/alekseybilogur/Desktop/mini-cluster/\
-            rust/mini-cluster-worker/tests/artifacts/simple-csv.csv"
-        );
+        let t = Table::new("foo", artifact_path("simple-csv.csv"));
 
         let drop = block_on(t.drop());
         assert!(drop.is_ok());
@@ -252,4 +782,262 @@ mod tests {
         let drop = block_on(t.drop());
         assert!(drop.is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    #[serial]
+    fn test_table_exists() {
+        let t = Table::new("foo", artifact_path("simple-csv.csv"));
+        let db = block_on(Database::new_in_memory()).unwrap();
+        let mut conn = block_on(db.connection()).unwrap();
+        assert!(!block_on(t.exists(&mut *conn)).unwrap());
+
+        block_on(t.dump_into(&mut *conn)).unwrap();
+        assert!(block_on(t.exists(&mut *conn)).unwrap());
+        let (rows,): (i64,) = block_on(
+            sqlx::query_as("SELECT COUNT(*) FROM foo").fetch_one(&mut *conn)
+        ).unwrap();
+
+        // Dumping again is a no-op...
+        block_on(t.dump_into(&mut *conn)).unwrap();
+        // ...unless a reload is forced, which replaces the table rather than appending to it.
+        let t = t.force_reload(true);
+        block_on(t.dump_into(&mut *conn)).unwrap();
+        let (reloaded_rows,): (i64,) = block_on(
+            sqlx::query_as("SELECT COUNT(*) FROM foo").fetch_one(&mut *conn)
+        ).unwrap();
+        assert_eq!(rows, reloaded_rows);
+    }
+
+    #[test]
+    fn test_dump_in_chunks() {
+        let path = &craft_csv_file(
+            "mini-cluster-test-dump-in-chunks.csv",
+            &["a_int", "b_int"],
+            &[&["1", "2"], &["3", "4"], &["5", "6"], &["7", "8"], &["9", "10"]]
+        );
+        let t = Table::new("foo", path).chunk_size(2);
+        let db = block_on(Database::new_in_memory()).unwrap();
+        let mut conn = block_on(db.connection()).unwrap();
+
+        let mut events = vec![];
+        let progress = block_on(
+            t.dump_into_with_progress(&mut *conn, |progress| events.push(progress))
+        ).unwrap();
+        // Two full chunks, then the leftover row.
+        assert_eq!(events.iter().map(|p| p.rows).collect::<Vec<_>>(), vec![2, 4, 5]);
+        assert!(events[0].bytes < events[1].bytes);
+        assert_eq!(progress.bytes, std::fs::metadata(path).unwrap().len());
+
+        let (rows,): (i64,) = block_on(
+            sqlx::query_as("SELECT COUNT(*) FROM foo").fetch_one(&mut *conn)
+        ).unwrap();
+        assert_eq!(rows, 5);
+    }
+
+    #[test]
+    fn test_dump_append() {
+        let first = &craft_csv_file(
+            "mini-cluster-test-dump-append-1.csv", &["a_int", "b_int"], &[&["1", "2"], &["3", "4"]]
+        );
+        let second = &craft_csv_file(
+            "mini-cluster-test-dump-append-2.csv", &["a_int", "b_int"], &[&["5", "6"]]
+        );
+        let other = &craft_csv_file(
+            "mini-cluster-test-dump-append-3.csv", &["a_int", "c_int"], &[&["7", "8"]]
+        );
+        let db = block_on(Database::new_in_memory()).unwrap();
+        let mut conn = block_on(db.connection()).unwrap();
+        block_on(Table::new("foo", first).dump_into(&mut *conn)).unwrap();
+        block_on(Table::new("foo", second).append(true).dump_into(&mut *conn)).unwrap();
+        let (rows,): (i64,) = block_on(
+            sqlx::query_as("SELECT COUNT(*) FROM foo").fetch_one(&mut *conn)
+        ).unwrap();
+        assert_eq!(rows, 3);
+
+        // Without `append`, the table is left as it is, and files with other columns don't go.
+        block_on(Table::new("foo", second).dump_into(&mut *conn)).unwrap();
+        assert!(block_on(Table::new("foo", other).append(true).dump_into(&mut *conn)).is_err());
+        let (rows,): (i64,) = block_on(
+            sqlx::query_as("SELECT COUNT(*) FROM foo").fetch_one(&mut *conn)
+        ).unwrap();
+        assert_eq!(rows, 3);
+    }
+
+    #[test]
+    fn test_dump_partition() {
+        let path = &craft_csv_file(
+            "mini-cluster-test-dump-partition.csv", &["a_int"], &[&["1"], &["2"]]
+        );
+        let partition = vec![
+            ("date".to_owned(), Some("2021-03-01".to_owned())), ("region".to_owned(), None)
+        ];
+        let t = Table::new("foo", path).partition(&partition);
+        let db = block_on(Database::new_in_memory()).unwrap();
+        let mut conn = block_on(db.connection()).unwrap();
+        block_on(t.dump_into(&mut *conn)).unwrap();
+
+        let (rows,): (i64,) = block_on(sqlx::query_as(
+            "SELECT COUNT(*) FROM foo WHERE date = '2021-03-01' AND region IS NULL"
+        ).fetch_one(&mut *conn)).unwrap();
+        assert_eq!(rows, 2);
+    }
+
+    #[test]
+    fn test_dump_nulls() {
+        let path = &std::env::temp_dir().join("mini-cluster-test-dump-nulls.csv");
+        std::fs::write(path, "a_int,b_text,c_real\n1,,2.5\n,x,NA\nNA,NA,\n4,it's,1\n").unwrap();
+        let t = Table::new("foo", path).null_tokens(&["NA".to_owned()]);
+        let db = block_on(Database::new_in_memory()).unwrap();
+        let mut conn = block_on(db.connection()).unwrap();
+        block_on(t.dump_into(&mut *conn)).unwrap();
+
+        let count = |conn: &mut SqliteConnection, condition: &str| {
+            let (count,): (i64,) = block_on(
+                sqlx::query_as(&format!("SELECT COUNT(*) FROM foo WHERE {}", condition))
+                    .fetch_one(conn)
+            ).unwrap();
+            count
+        };
+        assert_eq!(count(&mut *conn, "a IS NULL"), 2);
+        assert_eq!(count(&mut *conn, "b IS NULL"), 1);
+        assert_eq!(count(&mut *conn, "b = ''"), 1);
+        assert_eq!(count(&mut *conn, "b = 'it''s'"), 1);
+        assert_eq!(count(&mut *conn, "c IS NULL"), 2);
+        // Numbers still end up as numbers.
+        assert_eq!(count(&mut *conn, "typeof(a) = 'integer'"), 2);
+        assert_eq!(count(&mut *conn, "a + c = 5"), 1);
+    }
+
+    #[test]
+    fn test_dump_dates() {
+        let path = &std::env::temp_dir().join("mini-cluster-test-dump-dates.csv");
+        std::fs::write(
+            path,
+            "d_date,t_datetime\n\
+            2021-03-01,2021-03-01T10:00:00Z\n\
+            2459275.5,2021-03-01 10:00:00\n\
+            ,\n"
+        ).unwrap();
+        let t = Table::new("foo", path);
+        let db = block_on(Database::new_in_memory()).unwrap();
+        let mut conn = block_on(db.connection()).unwrap();
+        block_on(t.dump_into(&mut *conn)).unwrap();
+
+        let rows: Vec<(Option<String>, Option<String>)> = block_on(
+            sqlx::query_as("SELECT d, t FROM foo").fetch_all(&mut *conn)
+        ).unwrap();
+        let date = Some("2021-03-01".to_owned());
+        let datetime = Some("2021-03-01 10:00:00".to_owned());
+        assert_eq!(rows, vec![
+            (date.clone(), datetime.clone()), (date, datetime), (None, None)
+        ]);
+
+        // A value that isn't a date is an error, rather than a silent NULL.
+        std::fs::write(path, "d_date\nyesterday\n").unwrap();
+        let t = Table::new("bar", path);
+        assert!(block_on(t.dump_into(&mut *conn)).is_err());
+    }
+
+    #[test]
+    fn test_explain_query_plan() {
+        let db = block_on(Database::new_in_memory()).unwrap();
+        let mut conn = block_on(db.connection()).unwrap();
+        block_on(sqlx::query("CREATE TABLE foo (a INTEGER)").execute(&mut *conn)).unwrap();
+
+        let plan = block_on(
+            explain_query_plan(&mut *conn, "SELECT * FROM foo WHERE a IN (SELECT a FROM foo)")
+        ).unwrap();
+        assert!(plan.contains("SCAN"));
+        // The subquery's steps are nested under the outer query's.
+        assert!(plan.lines().any(|line| line.starts_with("  ")));
+
+        let plan = block_on(explain_query_plan(&mut *conn, "CREATE TABLE bar (b INTEGER)"));
+        assert_eq!(plan.unwrap(), "");
+        // `EXPLAIN` doesn't actually run anything.
+        assert!(!block_on(Table::new("bar", "").exists(&mut *conn)).unwrap());
+    }
+
+    #[test]
+    fn test_parse_columns() {
+        let headers = csv::StringRecord::from(vec!["a_int", "", "b_date_time", "c_text"]);
+        let columns = parse_columns(&headers).unwrap();
+        assert_eq!(columns.len(), 3);
+        assert_eq!(
+            columns[1],
+            ColumnSpec { index: 2, name: "b".to_owned(), col_type: "date_time".to_owned() }
+        );
+
+        assert!(parse_columns(&csv::StringRecord::new()).is_err());
+        assert!(parse_columns(&csv::StringRecord::from(vec!["a_int", "b"])).is_err());
+    }
+
+    #[test]
+    fn test_is_numeric_type() {
+        assert!(is_numeric_type("int"));
+        assert!(is_numeric_type("BIGINT"));
+        assert!(is_numeric_type("real"));
+        assert!(is_numeric_type("numeric"));
+        assert!(!is_numeric_type("text"));
+        assert!(!is_numeric_type("VARCHAR(10)"));
+        assert!(!is_numeric_type("blob"));
+    }
+
+    #[test]
+    fn test_table_fingerprint() {
+        let t = Table::new("foo", "/dev/null");
+        let db = block_on(Database::new_in_memory()).unwrap();
+        let mut conn = block_on(db.connection()).unwrap();
+        assert_eq!(block_on(t.fingerprint(&mut *conn)).unwrap(), None);
+
+        let fingerprint = TableFingerprint {
+            path: "s3://foo/bar".to_owned(), etag: "\"abc\"".to_owned(), size: 3
+        };
+        block_on(t.register(&mut *conn, &fingerprint)).unwrap();
+        assert_eq!(block_on(t.fingerprint(&mut *conn)).unwrap(), Some(fingerprint.clone()));
+
+        // Registering again replaces the old fingerprint.
+        let fingerprint = TableFingerprint { etag: "\"def\"".to_owned(), ..fingerprint };
+        block_on(t.register(&mut *conn, &fingerprint)).unwrap();
+        assert_eq!(block_on(t.fingerprint(&mut *conn)).unwrap(), Some(fingerprint));
+
+        // Dropping the table forgets where it came from.
+        block_on(t.drop_from(&mut *conn)).unwrap();
+        assert_eq!(block_on(t.fingerprint(&mut *conn)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_database_options() {
+        let options = DatabaseOptions::default();
+        assert!(options.validate().is_ok());
+        assert!(options.pragmas().contains("PRAGMA journal_mode = WAL;"));
+
+        let db = block_on(Database::with_options(&options)).unwrap();
+        let mut conn = block_on(db.connection()).unwrap();
+        let (journal_mode,): (String,) = block_on(
+            sqlx::query_as("PRAGMA journal_mode").fetch_one(&mut *conn)
+        ).unwrap();
+        assert_eq!(journal_mode.to_uppercase(), "WAL");
+
+        let mut options = DatabaseOptions::default();
+        options.synchronous = "NORMAL; DROP TABLE foo".to_owned();
+        assert!(options.validate().is_err());
+    }
+
+    #[test]
+    fn test_in_memory_database() {
+        let db = block_on(Database::new_in_memory()).unwrap();
+        assert!(db.is_in_memory());
+
+        // Tables survive from one connection to the next, because it's really the same one.
+        let mut conn = block_on(db.connection()).unwrap();
+        block_on(sqlx::query("CREATE TABLE foo (a INTEGER)").execute(&mut *conn)).unwrap();
+        drop(conn);
+        let mut conn = block_on(db.connection()).unwrap();
+        assert!(block_on(sqlx::query("SELECT * FROM foo").fetch_all(&mut *conn)).is_ok());
+
+        // But a different in-memory database can't see them.
+        let other_db = block_on(Database::new_in_memory()).unwrap();
+        let mut other_conn = block_on(other_db.connection()).unwrap();
+        assert!(block_on(sqlx::query("SELECT * FROM foo").fetch_all(&mut *other_conn)).is_err());
+    }
+}
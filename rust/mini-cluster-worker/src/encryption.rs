@@ -0,0 +1,556 @@
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead};
+use lazy_static::lazy_static;
+use rand::RngCore;
+use rusoto_core::Region;
+use rusoto_kms::{DecryptRequest, Kms, KmsClient};
+use sqlx::SqliteConnection;
+
+use crate::cache::get_result_cache_dir;
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::file::get_objects_dir;
+use crate::snapshot::is_database_file;
+
+// Encryption at rest. Everything a worker downloads, loads, and computes lands in its cache
+// directory (see `get_cache_dir`), which on a shared host may well be readable by others.
+// Deployments that can't have customer data lying around in the clear there can give the worker
+// a key (`WorkerConfig.cache_key`), which it then encrypts everything it writes to the cache
+// with:
+//
+// * the files it downloads (see `localize_file`), the results it caches (see `cache`), and the
+//   partitions it exchanges (see `exchange`) are sealed with ChaCha20-Poly1305, a chunk at a
+//   time, so that they can be read back (and, in the case of downloads, written) without
+//   holding all of them in memory at once. Each chunk is authenticated, and so is where it
+//   falls in the file, so a chunk that's been tampered with, moved, or cut off is an error
+//   rather than bad data.
+// * the database is encrypted by SQLite itself, which takes a SQLite built with SQLCipher (see
+//   the `sqlcipher` feature). A worker with a key and an ordinary SQLite refuses to start,
+//   rather than write the database in the clear.
+//
+// The key is 32 bytes, given either as base64, or as a data key encrypted with KMS (`kms:`
+// followed by the base64 of the ciphertext blob), which the worker has KMS decrypt when it
+// starts up. There's no rotating keys in place: a worker given a new key (or a key for the
+// first time) has to start out with an empty cache, so it refuses to start if it finds files
+// it can't vouch for there (see `find_cleartext`).
+//
+// Sealed files start with `MAGIC`, followed by a random nonce prefix. Every chunk is sealed
+// under a nonce of the prefix, the chunk's position, and whether or not it's the last chunk.
+// Files without `MAGIC` are read as they are, so the same code reads both, but only so long as
+// the cache isn't encrypted: in an encrypted cache, a file in the clear is one that someone
+// without the key swapped in, and reading it is an error (see `open_cached`).
+
+/// How long keys are, in bytes.
+pub const KEY_BYTES: usize = 32;
+
+/// How many bytes of plaintext go in each sealed chunk.
+pub const CHUNK_BYTES: usize = 64 * 1024;
+
+/// What every sealed file starts with.
+const MAGIC: &[u8] = b"MCSEAL1\0";
+const PREFIX_BYTES: usize = 7;
+const HEADER_BYTES: usize = 8 + PREFIX_BYTES;
+const TAG_BYTES: usize = 16;
+
+/// A key to encrypt the cache with.
+#[derive(Clone, Copy)]
+pub struct CacheKey([u8; KEY_BYTES]);
+
+impl CacheKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<CacheKey> {
+        let bytes: [u8; KEY_BYTES] = bytes.try_into().map_err(|_| WorkerError::new(
+            ErrKind::ValidationError,
+            &format!("Cache keys are {} bytes, not {}.", KEY_BYTES, bytes.len())
+        ))?;
+        Ok(CacheKey(bytes))
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+}
+
+// Keys are kept out of the logs, along with everything else they're printed into.
+impl std::fmt::Debug for CacheKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<CacheKey>")
+    }
+}
+
+/// Where the worker gets its cache key from.
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    /// The key itself.
+    Plain(CacheKey),
+    /// The key, encrypted with KMS.
+    Kms(Vec<u8>),
+}
+
+impl KeySource {
+    /// Parses a key source from its config form: the base64 of the key, or `kms:` followed by
+    /// the base64 of the key's KMS ciphertext blob.
+    pub fn parse(source: &str) -> Result<KeySource> {
+        let invalid = |err: base64::DecodeError| WorkerError::new(
+            ErrKind::ValidationError, &format!("Cache key isn't valid base64: {}", err)
+        );
+        match source.trim().strip_prefix("kms:") {
+            Some(blob) => Ok(KeySource::Kms(base64::decode(blob).map_err(invalid)?)),
+            None => {
+                let key = base64::decode(source.trim()).map_err(invalid)?;
+                Ok(KeySource::Plain(CacheKey::from_bytes(&key)?))
+            },
+        }
+    }
+
+    /// Gets the key, having KMS decrypt it if need be.
+    pub async fn resolve(&self) -> Result<CacheKey> {
+        let blob = match self {
+            KeySource::Plain(key) => return Ok(*key),
+            KeySource::Kms(blob) => blob,
+        };
+        let request = DecryptRequest { ciphertext_blob: blob.clone().into(), ..Default::default() };
+        let decrypted = KmsClient::new(Region::default()).decrypt(request).await
+            .map_err(|err| WorkerError::new(
+                ErrKind::AWSError, &format!("KMS couldn't decrypt the cache key: {}", err)
+            ))?;
+        let plaintext = decrypted.plaintext.ok_or_else(|| WorkerError::new(
+            ErrKind::AWSError, "KMS decrypted the cache key to nothing."
+        ))?;
+        CacheKey::from_bytes(&plaintext)
+    }
+}
+
+lazy_static! {
+    // The key everything the worker writes to its cache is encrypted with, if any. Like the
+    // cache directory, this goes for the whole process.
+    static ref KEY: RwLock<Option<CacheKey>> = RwLock::new(None);
+}
+
+/// Sets the key the cache is encrypted with from here on (see `key`).
+pub fn install(key: Option<CacheKey>) {
+    *KEY.write().unwrap() = key;
+}
+
+/// The key the cache is encrypted with, if it is.
+pub fn key() -> Option<CacheKey> {
+    *KEY.read().unwrap()
+}
+
+fn craft_nonce(prefix: &[u8; PREFIX_BYTES], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[..PREFIX_BYTES].copy_from_slice(prefix);
+    nonce[PREFIX_BYTES..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+fn corrupted() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Sealed file could not be decrypted: it was sealed with another key, or it's corrupted."
+    )
+}
+
+/// Seals plaintext a chunk at a time, handing back the sealed bytes to write out as it goes.
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    prefix: [u8; PREFIX_BYTES],
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl Sealer {
+    pub fn new(key: &CacheKey) -> Sealer {
+        let mut prefix = [0; PREFIX_BYTES];
+        rand::thread_rng().fill_bytes(&mut prefix);
+        Sealer { cipher: key.cipher(), prefix, counter: 0, buffer: vec![] }
+    }
+
+    /// What a sealed file starts with, ahead of the chunks.
+    pub fn header(&self) -> Vec<u8> {
+        [MAGIC, &self.prefix[..]].concat()
+    }
+
+    fn seal_chunk(&mut self, chunk: &[u8], last: bool) -> io::Result<Vec<u8>> {
+        let nonce = craft_nonce(&self.prefix, self.counter, last);
+        self.counter = self.counter.checked_add(1).ok_or_else(|| io::Error::new(
+            io::ErrorKind::Other, "Too much data to seal in one file."
+        ))?;
+        self.cipher.encrypt(Nonce::from_slice(&nonce), chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Could not seal a chunk."))
+    }
+
+    /// Takes more plaintext, returning the chunks it completes, sealed. Chunks are only sealed
+    /// once there's more plaintext after them, since the last chunk is sealed differently.
+    pub fn update(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        self.buffer.extend_from_slice(plaintext);
+        let mut sealed = vec![];
+        while self.buffer.len() > CHUNK_BYTES {
+            let chunk = self.buffer.drain(..CHUNK_BYTES).collect::<Vec<_>>();
+            sealed.extend(self.seal_chunk(&chunk, false)?);
+        }
+        Ok(sealed)
+    }
+
+    /// Seals what's left as the last chunk.
+    pub fn finish(mut self) -> io::Result<Vec<u8>> {
+        let chunk = std::mem::take(&mut self.buffer);
+        self.seal_chunk(&chunk, true)
+    }
+}
+
+/// Writes what's written to it to `inner`, sealed. Nothing is sealed for good until `finish`.
+pub struct SealedWriter<W: Write> {
+    inner: W,
+    sealer: Sealer,
+}
+
+impl<W: Write> SealedWriter<W> {
+    pub fn new(key: &CacheKey, mut inner: W) -> io::Result<SealedWriter<W>> {
+        let sealer = Sealer::new(key);
+        inner.write_all(&sealer.header())?;
+        Ok(SealedWriter { inner, sealer })
+    }
+
+    /// Seals the last chunk, returning `inner`.
+    pub fn finish(self) -> io::Result<W> {
+        let SealedWriter { mut inner, sealer } = self;
+        inner.write_all(&sealer.finish()?)?;
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for SealedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sealed = self.sealer.update(buf)?;
+        self.inner.write_all(&sealed)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the plaintext of what `inner` has to read, which has to have been sealed.
+pub struct OpenedReader<R: Read> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    prefix: [u8; PREFIX_BYTES],
+    counter: u32,
+    plaintext: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+/// Reads as much of `buf` as `reader` has left, returning how much that was.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(n)
+}
+
+impl<R: Read> OpenedReader<R> {
+    pub fn new(key: &CacheKey, mut inner: R) -> io::Result<OpenedReader<R>> {
+        let mut header = [0; HEADER_BYTES];
+        if read_up_to(&mut inner, &mut header)? < HEADER_BYTES || &header[..8] != MAGIC {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "Not a sealed file."))?
+        }
+        let mut prefix = [0; PREFIX_BYTES];
+        prefix.copy_from_slice(&header[8..]);
+        Ok(OpenedReader {
+            inner, cipher: key.cipher(), prefix, counter: 0, plaintext: vec![], pos: 0, done: false
+        })
+    }
+
+    fn open_chunk(&self, chunk: &[u8], last: bool) -> Option<Vec<u8>> {
+        let nonce = craft_nonce(&self.prefix, self.counter, last);
+        self.cipher.decrypt(Nonce::from_slice(&nonce), chunk).ok()
+    }
+
+    /// Reads and opens the next chunk.
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = vec![0; CHUNK_BYTES + TAG_BYTES];
+        let n = read_up_to(&mut self.inner, &mut chunk)?;
+        chunk.truncate(n);
+        // A full chunk may or may not be the last one; anything less has to be.
+        let opened = match n == CHUNK_BYTES + TAG_BYTES {
+            true => self.open_chunk(&chunk, false).map(|plaintext| (plaintext, false))
+                .or_else(|| self.open_chunk(&chunk, true).map(|plaintext| (plaintext, true))),
+            false => self.open_chunk(&chunk, true).map(|plaintext| (plaintext, true)),
+        };
+        let (plaintext, last) = opened.ok_or_else(corrupted)?;
+        if last && read_up_to(&mut self.inner, &mut [0])? > 0 {
+            Err(corrupted())?
+        }
+        self.counter = self.counter.checked_add(1).ok_or_else(corrupted)?;
+        self.plaintext = plaintext;
+        self.pos = 0;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for OpenedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plaintext.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = buf.len().min(self.plaintext.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plaintext[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Seals `plaintext` whole.
+pub fn seal(key: &CacheKey, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let mut writer = SealedWriter::new(key, vec![])?;
+    writer.write_all(plaintext)?;
+    writer.finish()
+}
+
+/// Opens what `seal` sealed.
+pub fn open(key: &CacheKey, sealed: &[u8]) -> io::Result<Vec<u8>> {
+    let mut plaintext = vec![];
+    OpenedReader::new(key, sealed)?.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+/// How many bytes of plaintext a sealed file of `len` bytes holds.
+fn plaintext_len(len: u64) -> u64 {
+    let body = len.saturating_sub(HEADER_BYTES as u64);
+    let sealed_chunk = (CHUNK_BYTES + TAG_BYTES) as u64;
+    let n_chunks = ((body + sealed_chunk - 1) / sealed_chunk).max(1);
+    body.saturating_sub(n_chunks * TAG_BYTES as u64)
+}
+
+/// Whether or not the file at `fp` is sealed.
+pub fn is_sealed(fp: impl AsRef<Path>) -> Result<bool> {
+    let mut header = [0; 8];
+    let n = read_up_to(&mut fs::File::open(fp)?, &mut header)?;
+    Ok(n == header.len() && &header[..] == MAGIC)
+}
+
+/// Opens a file in the cache for reading, returning a reader of its plaintext and how long
+/// that is. Sealed files are opened with the cache key. Anything else is read as it is, unless
+/// the cache is encrypted, in which case it's an error.
+pub fn open_cached(fp: impl AsRef<Path>) -> Result<(Box<dyn Read + Send>, u64)> {
+    open_with(fp.as_ref(), key())
+}
+
+fn open_with(fp: &Path, key: Option<CacheKey>) -> Result<(Box<dyn Read + Send>, u64)> {
+    let mut file = fs::File::open(fp)?;
+    let len = file.metadata()?.len();
+    match (is_sealed(fp)?, key) {
+        (false, None) => Ok((Box::new(file), len)),
+        (false, Some(_)) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} isn't sealed, and the cache is encrypted.", fp.display())
+        ))?,
+        (true, None) => Err(WorkerError::new(
+            ErrKind::ValidationError,
+            &format!("{} is sealed, and the worker has no cache key.", fp.display())
+        ))?,
+        (true, Some(key)) => {
+            file.seek(SeekFrom::Start(0))?;
+            Ok((Box::new(OpenedReader::new(&key, file)?), plaintext_len(len)))
+        },
+    }
+}
+
+/// Opens a file in the cache that isn't sealed even if the cache is encrypted, as it is: a copy
+/// of the database, which SQLite encrypts itself (see `key_pragma`).
+pub fn open_unsealed(fp: impl AsRef<Path>) -> Result<(Box<dyn Read + Send>, u64)> {
+    let file = fs::File::open(fp)?;
+    let len = file.metadata()?.len();
+    Ok((Box::new(file), len))
+}
+
+/// Reads the plaintext of a file in the cache whole (see `open_cached`).
+pub fn read_cached(fp: impl AsRef<Path>) -> Result<Vec<u8>> {
+    let mut plaintext = vec![];
+    open_cached(fp)?.0.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+/// Writes a file to the cache whole, sealed if the cache is encrypted.
+pub fn write_cached(fp: impl AsRef<Path>, plaintext: &[u8]) -> Result<()> {
+    match key() {
+        Some(key) => fs::write(fp, seal(&key, plaintext)?)?,
+        None => fs::write(fp, plaintext)?,
+    }
+    Ok(())
+}
+
+/// A file being written to the cache, sealed if the cache is encrypted.
+pub enum CacheWriter {
+    Plain(fs::File),
+    Sealed(SealedWriter<fs::File>),
+}
+
+impl CacheWriter {
+    pub fn create(fp: impl AsRef<Path>) -> Result<CacheWriter> {
+        let file = fs::File::create(fp)?;
+        match key() {
+            Some(key) => Ok(CacheWriter::Sealed(SealedWriter::new(&key, file)?)),
+            None => Ok(CacheWriter::Plain(file)),
+        }
+    }
+
+    /// Finishes the file off. A sealed file isn't readable until it has been finished.
+    pub fn finish(self) -> Result<()> {
+        let mut file = match self {
+            CacheWriter::Plain(file) => file,
+            CacheWriter::Sealed(writer) => writer.finish()?,
+        };
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl Write for CacheWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CacheWriter::Plain(file) => file.write(buf),
+            CacheWriter::Sealed(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CacheWriter::Plain(file) => file.flush(),
+            CacheWriter::Sealed(writer) => writer.flush(),
+        }
+    }
+}
+
+/// The `PRAGMA` that unlocks the database, if the cache is encrypted. It has to come before
+/// anything else is done with a connection.
+pub fn key_pragma() -> Option<String> {
+    key().map(|key| {
+        let hex = key.0.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        format!("PRAGMA key = \"x'{}'\";", hex)
+    })
+}
+
+/// Checks that the SQLite `conn` is connected with can encrypt databases, i.e. that it's
+/// SQLCipher.
+pub async fn check_database_support(conn: &mut SqliteConnection) -> Result<()> {
+    // Ordinary SQLite ignores pragmas it doesn't know, so this comes back empty.
+    let version: Option<(String,)> = sqlx::query_as("PRAGMA cipher_version")
+        .fetch_optional(&mut *conn)
+        .await?;
+    if version.is_none() {
+        Err(WorkerError::new(
+            ErrKind::DatabaseError,
+            "The worker has a cache key, but its SQLite can't encrypt databases. Build it with \
+            the `sqlcipher` feature."
+        ))?
+    }
+    Ok(())
+}
+
+/// Lists the files in the cache that are in the clear: a database that isn't encrypted, and
+/// downloads and cached results that aren't sealed.
+pub fn find_cleartext(db_path: &Path) -> Result<Vec<PathBuf>> {
+    let mut cleartext = vec![];
+    if db_path.exists() && is_database_file(db_path)? {
+        cleartext.push(db_path.to_owned());
+    }
+    for dir in [get_objects_dir(), get_result_cache_dir()].iter() {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let entry = entry?;
+            let fp = entry.path();
+            // Offset markers (see `PartialDownload`) only say how far along a download is.
+            let marker = fp.extension().map_or(false, |extension| extension == "offset");
+            let empty = entry.metadata()?.len() == 0;
+            if !marker && !empty && !is_sealed(&fp)? {
+                cleartext.push(fp);
+            }
+        }
+    }
+    Ok(cleartext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_round_trip() {
+        let key = CacheKey::from_bytes(&[7; KEY_BYTES]).unwrap();
+        // Empty, less than a chunk, exactly a chunk, and a few chunks and a bit.
+        for len in [0, 10, CHUNK_BYTES, 3 * CHUNK_BYTES + 5].iter() {
+            let plaintext = (0..*len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let sealed = seal(&key, &plaintext).unwrap();
+            assert_ne!(&sealed[HEADER_BYTES..], &plaintext[..]);
+            assert_eq!(plaintext_len(sealed.len() as u64), *len as u64);
+            assert_eq!(open(&key, &sealed).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn test_open_tampered() {
+        let key = CacheKey::from_bytes(&[7; KEY_BYTES]).unwrap();
+        let other_key = CacheKey::from_bytes(&[8; KEY_BYTES]).unwrap();
+        let plaintext = vec![1; 2 * CHUNK_BYTES + 1];
+        let sealed = seal(&key, &plaintext).unwrap();
+
+        assert!(open(&other_key, &sealed).is_err());
+        let mut flipped = sealed.clone();
+        flipped[HEADER_BYTES + 100] ^= 1;
+        assert!(open(&key, &flipped).is_err());
+        // Cut off after a whole chunk, which would otherwise look like a shorter file.
+        assert!(open(&key, &sealed[..HEADER_BYTES + CHUNK_BYTES + TAG_BYTES]).is_err());
+        assert!(open(&key, &plaintext).is_err());
+    }
+
+    #[test]
+    fn test_open_swapped() {
+        let key = CacheKey::from_bytes(&[7; KEY_BYTES]).unwrap();
+        let fp = std::env::temp_dir().join("mini-cluster-test-open-swapped");
+        fs::write(&fp, seal(&key, b"a,b\n1,2\n").unwrap()).unwrap();
+        let mut plaintext = vec![];
+        open_with(&fp, Some(key)).unwrap().0.read_to_end(&mut plaintext).unwrap();
+        assert_eq!(plaintext, b"a,b\n1,2\n");
+
+        // A file in the clear in place of a sealed one is only read if the cache isn't
+        // encrypted.
+        fs::write(&fp, b"a,b\n3,4\n").unwrap();
+        let err = open_with(&fp, Some(key)).err().unwrap();
+        assert_eq!(err.downcast_ref::<io::Error>().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(open_with(&fp, None).unwrap().1, 8);
+        fs::remove_file(&fp).unwrap();
+    }
+
+    #[test]
+    fn test_key_source() {
+        let encoded = base64::encode(&[3; KEY_BYTES]);
+        assert!(matches!(KeySource::parse(&encoded).unwrap(), KeySource::Plain(_)));
+        assert!(matches!(KeySource::parse("kms:AQID").unwrap(), KeySource::Kms(blob)
+            if blob == vec![1, 2, 3]));
+        assert!(KeySource::parse(&base64::encode(&[3; 16])).is_err());
+        assert!(KeySource::parse("not base64!").is_err());
+    }
+}
@@ -8,6 +8,10 @@ pub enum WorkerError {
     NetworkError(io::Error),
     AWSError(io::Error),
     DatabaseError(io::Error),
+    ValidationError(io::Error),
+    ProtocolError(io::Error),
+    ResultLimitError(io::Error),
+    ResourceError(io::Error),
 }
 
 impl fmt::Display for WorkerError {
@@ -38,6 +42,18 @@ impl fmt::Display for WorkerError {
             WorkerError::DatabaseError(err) => {
                 write!(f, "DatabaseError when trying to communicate with the DB: {}", err)
             }
+            WorkerError::ValidationError(err) => {
+                write!(f, "ValidationError when checking the workload: {}", err)
+            }
+            WorkerError::ProtocolError(err) => {
+                write!(f, "ProtocolError when decoding a frame: {}", err)
+            }
+            WorkerError::ResultLimitError(err) => {
+                write!(f, "ResultLimitError when collecting the result set: {}", err)
+            }
+            WorkerError::ResourceError(err) => {
+                write!(f, "ResourceError when checking the worker's resources: {}", err)
+            }
         }
     }
 }
@@ -116,6 +132,10 @@ pub enum ErrKind {
     NetworkError,
     AWSError,
     DatabaseError,
+    ValidationError,
+    ProtocolError,
+    ResultLimitError,
+    ResourceError,
 }
 
 impl WorkerError {
@@ -129,7 +149,19 @@ impl WorkerError {
             },
             ErrKind::DatabaseError => {
                 WorkerError::DatabaseError(io::Error::new(io::ErrorKind::Other, msg))
-            }
+            },
+            ErrKind::ValidationError => {
+                WorkerError::ValidationError(io::Error::new(io::ErrorKind::Other, msg))
+            },
+            ErrKind::ProtocolError => {
+                WorkerError::ProtocolError(io::Error::new(io::ErrorKind::Other, msg))
+            },
+            ErrKind::ResultLimitError => {
+                WorkerError::ResultLimitError(io::Error::new(io::ErrorKind::Other, msg))
+            },
+            ErrKind::ResourceError => {
+                WorkerError::ResourceError(io::Error::new(io::ErrorKind::Other, msg))
+            },
         }
     }
 }
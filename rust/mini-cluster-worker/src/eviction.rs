@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use protobuf::RepeatedField;
+use sqlx::SqliteConnection;
+
+use crate::db::{create_registry_if_missing, Database, Table, TABLE_REGISTRY};
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::file::{
+    get_cache_dir, get_index_path, get_objects_dir, is_pattern, matches_pattern, read_index,
+    with_suffix,
+};
+use crate::gc::{collect, user_tables};
+use crate::response::{CacheEntry, CacheReport};
+
+// Cache administration. Everything a worker downloads stays in its cache (see
+// `cache_entry_name`), and everything it loads stays in its database (see `TableFingerprint`),
+// until garbage collection gets around to it, if it ever does (see `gc`). That's what makes the
+// second job over the same files fast, but it leaves an operator with no way of seeing what a
+// worker is holding on to, or of making it let go: of a file that's known to be bad, say, or of
+// everything, to free up the disk, short of stopping the worker and deleting its cache by hand.
+//
+// So a worker can be sent a CACHE frame (see `CacheCommand`), asking it to:
+//
+// * list what it has cached, by path: how big the downloaded copy is, how old it is, and which
+//   tables were loaded from it;
+// * evict a path (or every path matching a pattern): its downloaded copies, every version of
+//   them, and the tables loaded from them, go;
+// * clear everything: every downloaded file, every table, and every cached result go, and the
+//   database is `VACUUM`ed. Only the database file itself is left.
+//
+// As with garbage collection, nothing can be evicted out from under a running job, so evicting
+// and clearing wait for the jobs using the database to finish first (and jobs that come along in
+// the meantime wait for them in turn).
+
+/// Lists what the worker has cached, by path, in path order.
+pub async fn list(conn: &mut SqliteConnection) -> Result<Vec<CacheEntry>> {
+    let mut entries = BTreeMap::new();
+    for (path, entry) in read_index() {
+        let mut cached = CacheEntry::new();
+        cached.set_path(path.clone());
+        // The index outlives the entries it names, e.g. when a download never finished.
+        if let Ok(metadata) = fs::metadata(get_objects_dir().join(&entry)) {
+            cached.set_bytes(metadata.len());
+            let age = metadata.modified().ok().and_then(|modified| modified.elapsed().ok());
+            cached.set_age_secs(age.map_or(0, |age| age.as_secs()));
+        }
+        entries.insert(path, cached);
+    }
+    for (name, path) in registered_tables(&mut *conn).await? {
+        let cached = entries.entry(path.clone()).or_insert_with(|| {
+            let mut cached = CacheEntry::new();
+            cached.set_path(path);
+            cached
+        });
+        cached.mut_tables().push(name);
+    }
+    Ok(entries.into_iter().map(|(_, cached)| cached).collect())
+}
+
+/// Evicts `path`, or every path matching it if it is a pattern: every cached copy of it, and
+/// every table loaded from it. This mustn't run whilst a job is using the database.
+pub async fn evict(conn: &mut SqliteConnection, path: &str) -> Result<CacheReport> {
+    if path.is_empty() {
+        Err(WorkerError::new(ErrKind::ValidationError, "No path to evict."))?
+    }
+    let mut report = CacheReport::new();
+    report.set_tables_dropped(RepeatedField::from_vec(drop_tables_from(&mut *conn, path).await?));
+
+    let contents = fs::read_to_string(get_index_path()).unwrap_or_default();
+    let (kept, evicted) = split_index(&contents, path);
+    let mut bytes_freed = 0;
+    for entry in evicted.iter() {
+        for suffix in ["", ".partial", ".offset"].iter() {
+            bytes_freed += remove_file(&with_suffix(&get_objects_dir().join(entry), suffix))?;
+        }
+    }
+    if !evicted.is_empty() {
+        // As with cached results, the new index is moved into place whole.
+        let tmp_path = with_suffix(&get_index_path(), ".tmp");
+        fs::write(&tmp_path, kept)?;
+        fs::rename(&tmp_path, get_index_path())?;
+    }
+    report.set_bytes_freed(bytes_freed);
+    log!(
+        "Evicted {} from the cache, freeing {} bytes and dropping {} tables.",
+        path, bytes_freed, report.get_tables_dropped().len()
+    );
+    Ok(report)
+}
+
+/// Clears the cache: drops every table in the worker's database, and removes everything in the
+/// cache directory but the database itself. This mustn't run whilst a job is using the database.
+pub async fn clear(conn: &mut SqliteConnection) -> Result<CacheReport> {
+    let mut report = CacheReport::new();
+    let tables = user_tables(&mut *conn).await?;
+    for table in tables.iter() {
+        Table::new(table, "").drop_from(&mut *conn).await?;
+    }
+    report.set_tables_dropped(RepeatedField::from_vec(tables));
+    // Prunes whatever bookkeeping is left over for the tables that were dropped.
+    collect(&mut *conn, None).await?;
+    sqlx::query("VACUUM").execute(&mut *conn).await?;
+
+    // The database may have a journal (e.g. `db.sqlite-wal`) alongside it, which has to stay
+    // too.
+    let db_name = Database::get_db_path()
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut bytes_freed = 0;
+    if let Ok(children) = fs::read_dir(get_cache_dir()) {
+        for child in children {
+            let child = child?;
+            if child.file_name().to_string_lossy().starts_with(&db_name) {
+                continue;
+            }
+            bytes_freed += remove_file(&child.path())?;
+        }
+    }
+    report.set_bytes_freed(bytes_freed);
+    log!(
+        "Cleared the cache, freeing {} bytes and dropping {} tables.",
+        bytes_freed, report.get_tables_dropped().len()
+    );
+    Ok(report)
+}
+
+/// Lists the name and path of every table in the table registry.
+async fn registered_tables(conn: &mut SqliteConnection) -> Result<Vec<(String, String)>> {
+    create_registry_if_missing(&mut *conn).await?;
+    let tables = sqlx::query_as(&format!("SELECT name, path FROM {} ORDER BY name", TABLE_REGISTRY))
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(tables)
+}
+
+/// Whether or not `path` is evicted by evicting `evicted`, which may be a prefix or a glob.
+fn is_evicted(evicted: &str, path: &str) -> bool {
+    if !is_pattern(evicted) {
+        evicted == path
+    } else if evicted.ends_with('/') {
+        path.starts_with(evicted)
+    } else {
+        matches_pattern(evicted, path)
+    }
+}
+
+/// Drops the tables loaded from the paths evicted by evicting `path`, returning their names.
+async fn drop_tables_from(conn: &mut SqliteConnection, path: &str) -> Result<Vec<String>> {
+    let mut dropped = vec![];
+    for (name, source) in registered_tables(&mut *conn).await? {
+        if is_evicted(path, &source) {
+            Table::new(&name, "").drop_from(&mut *conn).await?;
+            dropped.push(name);
+        }
+    }
+    Ok(dropped)
+}
+
+/// Splits the contents of the cache index into what's left of it once `path` is evicted, and
+/// the entries evicted with it. Every entry a path was ever localized to goes, not just the
+/// last, since any of them could still be on disk.
+fn split_index(contents: &str, path: &str) -> (String, Vec<String>) {
+    let mut kept = String::new();
+    let mut evicted = vec![];
+    for line in contents.lines() {
+        let mut fields = line.splitn(2, '\t');
+        match (fields.next(), fields.next()) {
+            (Some(entry), Some(indexed)) if is_evicted(path, indexed) => {
+                if !evicted.iter().any(|known| known == entry) {
+                    evicted.push(entry.to_owned());
+                }
+            },
+            _ => {
+                kept += line;
+                kept += "\n";
+            },
+        }
+    }
+    (kept, evicted)
+}
+
+/// Removes a file or directory, if it's there, returning how many bytes of files were in it.
+fn remove_file(fp: &Path) -> Result<u64> {
+    let metadata = match fs::symlink_metadata(fp) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(0),
+    };
+    if !metadata.is_dir() {
+        fs::remove_file(fp)?;
+        return Ok(metadata.len());
+    }
+    let mut bytes = 0;
+    for child in fs::read_dir(fp)? {
+        bytes += remove_file(&child?.path())?;
+    }
+    fs::remove_dir(fp)?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use crate::db::TableFingerprint;
+    use super::*;
+
+    #[test]
+    fn test_split_index() {
+        let contents =
+            "a\ts3://foo/1.csv\nb\ts3://foo/2.csv\nc\ts3://foo/1.csv\nd\ts3://bar/1.csv\n";
+        let (kept, evicted) = split_index(contents, "s3://foo/1.csv");
+        assert_eq!(kept, "b\ts3://foo/2.csv\nd\ts3://bar/1.csv\n");
+        assert_eq!(evicted, vec!["a", "c"]);
+
+        let (kept, evicted) = split_index(contents, "s3://foo/*.csv");
+        assert_eq!(kept, "d\ts3://bar/1.csv\n");
+        assert_eq!(evicted, vec!["a", "b", "c"]);
+
+        let (kept, _) = split_index(contents, "s3://bar/");
+        assert_eq!(kept, "a\ts3://foo/1.csv\nb\ts3://foo/2.csv\nc\ts3://foo/1.csv\n");
+
+        let (kept, evicted) = split_index(contents, "s3://baz/1.csv");
+        assert_eq!(kept, contents);
+        assert!(evicted.is_empty());
+    }
+
+    #[test]
+    fn test_drop_tables_from() {
+        block_on(async {
+            let database = Database::new_in_memory().await.unwrap();
+            let mut conn = database.connection().await.unwrap();
+            for (table, path) in [("dataset_1", "s3://foo/1.csv"), ("dataset_2", "s3://foo/2.csv")]
+                .iter() {
+                sqlx::query(&format!("CREATE TABLE {} (a INTEGER)", table))
+                    .execute(&mut *conn).await.unwrap();
+                let fingerprint = TableFingerprint {
+                    path: path.to_string(), etag: "abc".to_owned(), size: 1
+                };
+                Table::new(table, "").register(&mut conn, &fingerprint).await.unwrap();
+            }
+
+            let dropped = drop_tables_from(&mut conn, "s3://foo/1.csv").await.unwrap();
+            assert_eq!(dropped, vec!["dataset_1"]);
+            assert_eq!(user_tables(&mut conn).await.unwrap(), vec!["dataset_2"]);
+            assert!(Table::new("dataset_1", "").fingerprint(&mut conn).await.unwrap().is_none());
+            assert!(Table::new("dataset_2", "").fingerprint(&mut conn).await.unwrap().is_some());
+
+            let dropped = drop_tables_from(&mut conn, "s3://foo/*.csv").await.unwrap();
+            assert_eq!(dropped, vec!["dataset_2"]);
+            assert!(user_tables(&mut conn).await.unwrap().is_empty());
+        });
+    }
+}
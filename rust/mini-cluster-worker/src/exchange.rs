@@ -0,0 +1,260 @@
+use std::fs;
+use std::path::PathBuf;
+
+use futures::TryStreamExt;
+use sqlx::{Column, Executor, SqliteConnection, TypeInfo};
+use uuid::Uuid;
+
+use crate::encryption::CacheWriter;
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::file::{get_cache_dir, parse_file_path, WorkerS3ClientAdapter, WorkerS3ClientTrait};
+use crate::response::{Row, Value};
+use crate::result::{craft_row, format_value};
+use crate::workload::Exchange;
+
+/// What NULLs are written as in exchanged files. Load them with this as a null token.
+pub const EXCHANGE_NULL: &str = "\\N";
+
+// Data exchange. Joining two big inputs on one worker means downloading and loading both of them
+// there in full, however many workers the cluster has. An exchange lets the join be split up
+// instead: each side is hash-partitioned on its join keys, so that partition N of the left side
+// only has rows that can match rows in partition N of the right side, and the partitions can be
+// joined pairwise, on as many workers as there are partitions.
+//
+// Workers don't talk to each other, so partitions are handed over by way of S3 staging: an op
+// with an `Exchange` writes each partition of its result set to a CSV file of its own (one per
+// partition per sender), and whichever worker handles the partition next loads every sender's
+// file for it with a glob, as it would any other file. The scheduler strings the two stages
+// together (see its `join`).
+//
+// Rows are assigned to partitions by the CRC32 of the text of their keys, which is the same
+// for equal keys on either side of the join, so long as they are of the same type.
+
+/// The columns of the result set an op with an exchange returns instead of its own: one row per
+/// partition, saying where it went, and how many rows went there.
+pub const EXCHANGE_COLUMNS: [&str; 3] = ["partition", "path", "n_rows"];
+
+/// Where a partition of an exchanged result set went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExchangedPartition {
+    pub partition: u32,
+    pub path: String,
+    pub n_rows: u64,
+}
+
+impl ExchangedPartition {
+    /// Serializes the partition as a row of an op's result set (see `EXCHANGE_COLUMNS`).
+    pub fn to_row(&self) -> Row {
+        let mut values = vec![Value::new(), Value::new(), Value::new()];
+        values[0].set_integer(self.partition as i64);
+        values[1].set_text(self.path.clone());
+        values[2].set_integer(self.n_rows as i64);
+        let mut row = Row::new();
+        row.set_values(values.into());
+        row
+    }
+}
+
+/// Checks that an exchange makes sense, before anything is run.
+pub fn check_exchange(exchange: &Exchange) -> Result<()> {
+    let problem = if exchange.get_keys().is_empty() {
+        Some("Exchange has no keys to partition on.")
+    } else if exchange.get_n_partitions() == 0 {
+        Some("Exchange has no partitions to write to.")
+    } else if !exchange.get_destination().ends_with('/') {
+        Some("Exchange destination has to be an S3 prefix, ending in a slash.")
+    } else if exchange.get_sender().is_empty() || exchange.get_sender().contains('/') {
+        Some("Exchange sender has to be a non-empty name, without any slashes in it.")
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        Err(WorkerError::new(ErrKind::ValidationError, problem))?
+    }
+    parse_file_path(exchange.get_destination())?;
+    Ok(())
+}
+
+/// Returns the S3 path the given sender writes the given partition to.
+pub fn partition_path(exchange: &Exchange, partition: u32) -> String {
+    format!(
+        "{}part-{:05}/{}.csv", exchange.get_destination(), partition, exchange.get_sender()
+    )
+}
+
+/// Returns the partition a row with the given keys belongs in.
+pub fn partition_of(keys: &[&Value], n_partitions: u32) -> u32 {
+    let text = keys.iter()
+        .map(|key| if key.get_null() { EXCHANGE_NULL.to_owned() } else { format_value(key) })
+        .collect::<Vec<_>>()
+        .join("\x1f");
+    crc32fast::hash(text.as_bytes()) % n_partitions.max(1)
+}
+
+/// Formats a value as a CSV cell.
+fn cell(value: &Value) -> Result<String> {
+    if value.get_null() {
+        Ok(EXCHANGE_NULL.to_owned())
+    } else if value.has_blob() {
+        Err(WorkerError::new(ErrKind::ValidationError, "Blobs can't be exchanged."))?
+    } else {
+        Ok(format_value(value))
+    }
+}
+
+/// Works out the type of a column to declare in an exchanged file's header, from the type
+/// SQLite says it has. Columns that aren't plain column references often don't have one, and
+/// are declared as text.
+fn header_type(type_name: &str) -> &'static str {
+    match type_name {
+        "INTEGER" | "BOOLEAN" => "int",
+        "REAL" | "NUMERIC" => "real",
+        "DATE" => "date",
+        "DATETIME" => "datetime",
+        _ => "text",
+    }
+}
+
+/// Runs `sql`, splitting its result set into the exchange's partitions and uploading each to
+/// its place in the staging area, and returns where each one went.
+pub async fn exchange<T: WorkerS3ClientTrait>(
+    client: &WorkerS3ClientAdapter<T>,
+    conn: &mut SqliteConnection,
+    sql: &str,
+    exchange: &Exchange,
+) -> Result<Vec<ExchangedPartition>> {
+    check_exchange(exchange)?;
+    // The header has to be written whether or not there are any rows, so the columns are
+    // worked out up front, rather than off of the first row.
+    let described = (&mut *conn).describe(sql).await?;
+    let columns = described.columns();
+    let mut header = vec![];
+    for column in columns.iter() {
+        if column.name().contains('_') {
+            Err(WorkerError::new(
+                ErrKind::ValidationError,
+                &format!("Exchanged column {} has an underscore in its name.", column.name())
+            ))?
+        }
+        header.push(format!("{}_{}", column.name(), header_type(column.type_info().name())));
+    }
+    let keys = exchange.get_keys().iter().map(|key| {
+        columns.iter().position(|column| column.name() == key).ok_or_else(|| WorkerError::new(
+            ErrKind::ValidationError,
+            &format!("Exchange key {} is not a column of the op's result set.", key)
+        ))
+    }).collect::<std::result::Result<Vec<_>, _>>()?;
+
+    let n_partitions = exchange.get_n_partitions();
+    fs::create_dir_all(get_cache_dir())?;
+    let id = Uuid::new_v4();
+    let fps = (0..n_partitions)
+        .map(|i| get_cache_dir().join(format!("exchange-{}-{}.csv", id, i)))
+        .collect::<Vec<_>>();
+    let written = write_partitions(conn, sql, &header, &keys, n_partitions, &fps).await;
+    let mut uploaded = vec![];
+    if let Ok(counts) = &written {
+        for (i, n_rows) in counts.iter().enumerate() {
+            let path = partition_path(exchange, i as u32);
+            if let Err(err) = client.put_file(&path, &fps[i]).await {
+                uploaded.push(Err(err));
+                break;
+            }
+            uploaded.push(Ok(ExchangedPartition { partition: i as u32, path, n_rows: *n_rows }));
+        }
+    }
+    for fp in fps.iter() {
+        let _ = fs::remove_file(fp);
+    }
+    written?;
+    let partitions = uploaded.into_iter().collect::<Result<Vec<_>>>()?;
+    log!(
+        "Exchanged {} rows into {} partitions under {}.",
+        partitions.iter().map(|partition| partition.n_rows).sum::<u64>(),
+        n_partitions, exchange.get_destination()
+    );
+    Ok(partitions)
+}
+
+/// Writes the rows of `sql` to one local file per partition, returning how many rows went into
+/// each.
+async fn write_partitions(
+    conn: &mut SqliteConnection,
+    sql: &str,
+    header: &[String],
+    keys: &[usize],
+    n_partitions: u32,
+    fps: &[PathBuf],
+) -> Result<Vec<u64>> {
+    let mut writers = vec![];
+    for fp in fps {
+        let mut writer = csv::Writer::from_writer(CacheWriter::create(fp)?);
+        writer.write_record(header)?;
+        writers.push(writer);
+    }
+    let mut counts = vec![0; n_partitions as usize];
+    let mut rows = sqlx::query(sql).fetch(&mut *conn);
+    while let Some(row) = rows.try_next().await? {
+        let values = craft_row(&row)?.take_values().into_vec();
+        let key_values = keys.iter().map(|&i| &values[i]).collect::<Vec<_>>();
+        let partition = partition_of(&key_values, n_partitions) as usize;
+        let cells = values.iter().map(cell).collect::<Result<Vec<_>>>()?;
+        writers[partition].write_record(&cells)?;
+        counts[partition] += 1;
+    }
+    for writer in writers {
+        writer.into_inner().map_err(|err| err.into_error())?.finish()?;
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn integer(v: i64) -> Value {
+        let mut value = Value::new();
+        value.set_integer(v);
+        value
+    }
+
+    fn text(v: &str) -> Value {
+        let mut value = Value::new();
+        value.set_text(v.to_owned());
+        value
+    }
+
+    #[test]
+    fn test_partition_of() {
+        // Equal keys land in the same partition, whichever side of the join they are on.
+        for v in 0..100 {
+            let n = partition_of(&[&integer(v)], 8);
+            assert!(n < 8);
+            assert_eq!(n, partition_of(&[&text(&v.to_string())], 8));
+        }
+        let spread = (0..100).map(|v| partition_of(&[&integer(v)], 8))
+            .collect::<std::collections::HashSet<_>>();
+        assert!(spread.len() > 1);
+        assert_eq!(partition_of(&[&integer(1), &text("a")], 1), 0);
+    }
+
+    #[test]
+    fn test_check_exchange() {
+        let mut exchange = Exchange::new();
+        exchange.set_keys(protobuf::RepeatedField::from_vec(vec!["a".to_owned()]));
+        exchange.set_n_partitions(4);
+        exchange.set_destination("s3://bucket/stage/".to_owned());
+        exchange.set_sender("1".to_owned());
+        assert!(check_exchange(&exchange).is_ok());
+        assert_eq!(partition_path(&exchange, 3), "s3://bucket/stage/part-00003/1.csv");
+
+        let mut unprefixed = exchange.clone();
+        unprefixed.set_destination("s3://bucket/stage".to_owned());
+        assert!(check_exchange(&unprefixed).is_err());
+        let mut unnamed = exchange.clone();
+        unnamed.set_sender("".to_owned());
+        assert!(check_exchange(&unnamed).is_err());
+        exchange.set_n_partitions(0);
+        assert!(check_exchange(&exchange).is_err());
+    }
+}
@@ -1,12 +1,28 @@
 use std::{collections::{HashMap, HashSet}};
+use std::env;
 use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use fs2::FileExt;
 
-use rusoto_s3::{GetObjectRequest, S3, S3Client};
+use md5::Md5;
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CreateMultipartUploadRequest, GetObjectRequest, HeadObjectRequest,
+    ListObjectsV2Request, PutObjectRequest, S3, S3Client, StreamingBody, UploadPartRequest,
+};
 use rusoto_core::region::Region;
+use sha2::{Digest, Sha256};
 use tokio::io::AsyncReadExt;
+use uuid::Uuid;
 
+use crate::encryption::{self, open_cached, Sealer};
+use crate::manifest::{is_manifest, read_manifest, MANIFEST_SCHEME};
+use crate::presigned::{get_url, is_presigned_url, without_query};
 use crate::workload::{Workload,File};
 use crate::Result;
 use crate::{WorkerError,ErrKind};
@@ -28,10 +44,131 @@ pub fn get_workload_files(workload: &Workload) -> Vec<&File> {
     return files
 }
 
-pub fn create_new_s3_client() -> WorkerS3ClientAdapter<S3Client> {
+pub fn create_new_s3_client() -> WorkerS3ClientAdapter<FaultyS3Client<S3Client>> {
     let region = Region::UsEast1;
     let client = S3Client::new(region);
-    WorkerS3ClientAdapter { client }
+    WorkerS3ClientAdapter::new(with_s3_faults(client))
+}
+
+/// An S3 client the worker makes its requests with, built with the `chaos` feature: faults are
+/// injected into the requests before they go out (see `chaos::ChaosS3Client`). Without the
+/// feature, this is the client as it is.
+#[cfg(feature = "chaos")]
+pub type FaultyS3Client<T> = crate::chaos::ChaosS3Client<T>;
+
+#[cfg(not(feature = "chaos"))]
+pub type FaultyS3Client<T> = T;
+
+/// Has faults injected into an S3 client's requests, if the worker was built with the `chaos`
+/// feature. Otherwise, it is left as it is.
+#[cfg(feature = "chaos")]
+pub fn with_s3_faults<T: WorkerS3ClientTrait>(client: T) -> FaultyS3Client<T> {
+    crate::chaos::ChaosS3Client::new(client)
+}
+
+#[cfg(not(feature = "chaos"))]
+pub fn with_s3_faults<T: WorkerS3ClientTrait>(client: T) -> FaultyS3Client<T> {
+    client
+}
+
+// File targets can stand for more than one object. Datasets are almost always directories of
+// part files (e.g. what a Spark job writes out), not single objects, so a file's path can also
+// be a prefix ending in a slash (`s3://bucket/trips/`), which stands for every object under it,
+// or a glob (`s3://bucket/trips/*.csv`). In a glob, `?` matches any one character and `*` any
+// run of characters, neither of them crossing a `/`, and `**` matches any run of characters at
+// all, `/`s included.
+//
+// The objects a pattern stands for are listed when the job is built (see `expand`). They are
+// all loaded into the file's table, one after another, so they have to share a header. The
+// table's fingerprint goes by all of them (see `head`): it is reloaded if any of them changes,
+// or if objects come or go.
+
+/// Whether or not a file path is a prefix or a glob, standing for every object it matches,
+/// rather than for a single object. Pre-signed URLs (see `presigned`) never are, their query
+/// strings notwithstanding.
+pub fn is_pattern(path: &str) -> bool {
+    if is_presigned_url(path) {
+        return false;
+    }
+    path.ends_with('/') || path.contains(|c: char| c == '*' || c == '?')
+}
+
+fn matches_chars(pattern: &[char], path: &[char]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            (0..=path.len()).any(|i| matches_chars(&pattern[2..], &path[i..]))
+        },
+        Some('*') => {
+            let end = path.iter().position(|c| *c == '/').unwrap_or(path.len());
+            (0..=end).any(|i| matches_chars(&pattern[1..], &path[i..]))
+        },
+        Some('?') => {
+            !path.is_empty() && path[0] != '/' && matches_chars(&pattern[1..], &path[1..])
+        },
+        Some(c) => path.first() == Some(c) && matches_chars(&pattern[1..], &path[1..]),
+    }
+}
+
+/// How Hive marks a partition whose key is NULL.
+pub const HIVE_DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// Undoes the escaping Hive does in partition values, e.g. `10%3A00` for `10:00`.
+fn unescape_partition_value(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut unescaped = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = value.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                unescaped.push(byte);
+                i += 3;
+            },
+            (byte, _) => {
+                unescaped.push(byte);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+/// Parses the Hive-style partition an object is in out of its path: every directory named
+/// `key=value` (e.g. `s3://bucket/events/date=2021-03-01/region=eu/part-0.csv`) is a key and
+/// its value, in the order they appear in. A value of `__HIVE_DEFAULT_PARTITION__` is NULL.
+/// Directories whose key isn't a plain column name are left out.
+///
+/// The objects a pattern stands for (see `is_pattern`) are often laid out like this, with the
+/// partition's values left out of the objects themselves. So they're put back as columns of the
+/// table the objects are loaded into (see `Table::partition`), which means ops can filter on
+/// them.
+pub fn hive_partition(path: &str) -> Vec<(String, Option<String>)> {
+    let directories = match path.rfind('/') {
+        Some(i) => &path[..i],
+        None => return vec![],
+    };
+    directories.split('/').filter_map(|directory| {
+        let i = directory.find('=')?;
+        let (key, value) = (&directory[..i], &directory[i + 1..]);
+        let is_column_name = !key.is_empty()
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_column_name {
+            return None;
+        }
+        let value = match value {
+            HIVE_DEFAULT_PARTITION => None,
+            value => Some(unescape_partition_value(value)),
+        };
+        Some((key.to_owned(), value))
+    }).collect()
+}
+
+/// Whether or not the path matches the glob (see `is_pattern`).
+pub fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let path = path.chars().collect::<Vec<_>>();
+    matches_chars(&pattern, &path)
 }
 
 pub fn parse_file_path(path: &str) -> Result<HashMap<&str, String>> {
@@ -57,35 +194,390 @@ pub fn parse_file_path(path: &str) -> Result<HashMap<&str, String>> {
 
 /// Creates the cache directory for a given bucket, if one is needed. If the expected directory
 /// structure already exists, this is a no-op.
-pub fn create_cache_dir(bucket: &str) -> Result<String> {
-    let worker_cache_base_fp = std::path::Path::new("/tmp/mini-cluster-worker/");
-    let worker_cache_fp = std::path::Path::new("/tmp/mini-cluster-worker/cache/");
-    let bucket_cache_fp_str = format!("/tmp/mini-cluster-worker/cache/{}", bucket);
-    let bucket_cache_fp = std::path::Path::new(&bucket_cache_fp_str);
-    if !worker_cache_base_fp.exists() {
-        std::fs::create_dir(worker_cache_base_fp)?;
-    }
-    if !worker_cache_fp.exists() {
-        std::fs::create_dir(worker_cache_fp)?;
-    }
-    if !bucket_cache_fp.exists() {
-        std::fs::create_dir(bucket_cache_fp)?;
+pub fn create_cache_dir(bucket: &str) -> Result<PathBuf> {
+    // Without this check a "bucket" like `../../etc` would happily create directories outside of
+    // the cache.
+    check_relative_path(bucket)?;
+    let bucket_cache_fp = get_cache_dir().join(bucket);
+    fs::create_dir_all(&bucket_cache_fp)?;
+    Ok(bucket_cache_fp)
+}
+
+/// Returns the directory everything the worker keeps on disk goes in: `WORKER_DIR`, if that is
+/// set, or else the platform's cache directory (e.g. `~/.cache` on Linux, `~/Library/Caches` on
+/// macOS, and `%LOCALAPPDATA%` on Windows), or failing that, the temporary directory. Workers
+/// on the same machine need directories of their own, or they trample each other's caches and
+/// databases.
+pub fn get_worker_dir() -> PathBuf {
+    match env::var("WORKER_DIR") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => dirs::cache_dir().unwrap_or_else(env::temp_dir).join("mini-cluster-worker"),
     }
-    Ok(bucket_cache_fp_str)
 }
 
 /// Returns the cache directory path. For use by other functions in the library. Does not
 /// guarantee that the cache directory actually exists yet! For that, call `create_cache_dir`
 /// first.
-pub fn get_cache_dir() -> String {
-    return "/tmp/mini-cluster-worker/cache/".to_owned()
+pub fn get_cache_dir() -> PathBuf {
+    get_worker_dir().join("cache")
+}
+
+/// Returns the total size of everything in the cache directory (downloaded files, the database,
+/// cached results...), in bytes. A missing cache directory is an empty one.
+pub fn cache_size() -> u64 {
+    fn dir_size(path: &Path) -> u64 {
+        let entries = match fs::read_dir(path) {
+            Ok(v) => v,
+            Err(_) => return 0,
+        };
+        entries.filter_map(|entry| entry.ok()).map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        }).sum()
+    }
+    dir_size(&get_cache_dir())
+}
+
+/// How much of the disk the worker may take up with its cache directory (downloaded files, the
+/// database, cached results...). See `check_disk_space`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DiskLimits {
+    /// The most bytes the cache directory may hold, if there's a cap.
+    pub quota: Option<u64>,
+    /// How many bytes to always leave free on the disk, e.g. for the logs.
+    pub reserve: u64,
+}
+
+/// Checks that another `needed` bytes fit in the cache directory: within its quota, if it has
+/// one, and within the space left on the disk it lives on (less the reserve). If they don't,
+/// this is a `ResourceError`.
+///
+/// Better to find out before downloading a file than to run out of room half-way through
+/// writing it (or loading it), which leaves the job failed with a half-written file to show for
+/// it, and every other job on the worker short of space too.
+pub fn check_disk_space(needed: u64, limits: &DiskLimits) -> Result<()> {
+    if let Some(quota) = limits.quota {
+        let used = cache_size();
+        if used.saturating_add(needed) > quota {
+            Err(WorkerError::new(
+                ErrKind::ResourceError,
+                &format!(
+                    "Needs {} more bytes of disk, but the cache already takes up {} of its {} \
+                    byte quota.",
+                    needed, used, quota
+                )
+            ))?
+        }
+    }
+    fs::create_dir_all(get_cache_dir())?;
+    let available = fs2::available_space(get_cache_dir())?;
+    if needed.saturating_add(limits.reserve) > available {
+        Err(WorkerError::new(
+            ErrKind::ResourceError,
+            &format!(
+                "Needs {} more bytes of disk, but only {} are free (keeping {} in reserve).",
+                needed, available, limits.reserve
+            )
+        ))?
+    }
+    Ok(())
+}
+
+/// Errors out if `path` is not a plain relative path, e.g. if it is absolute or contains a `..`.
+fn check_relative_path(path: &str) -> Result<()> {
+    let is_relative = Path::new(path).components().all(|component| match component {
+        Component::Normal(_) | Component::CurDir => true,
+        _ => false,
+    });
+    if path.is_empty() || !is_relative {
+        Err(WorkerError::new(
+            ErrKind::AWSError,
+            &format!("Illegal S3 path component {:?}: path escapes the cache directory!", path)
+        ))?
+    }
+    Ok(())
+}
+
+/// Returns the cache path that the object `object` in the bucket cache directory
+/// `bucket_cache_fp` should be written to.
+///
+/// Object keys come from the scheduler, so we cannot trust them. A key like
+/// `../../etc/cron.d/evil` would otherwise turn into a write target outside of the cache, so we
+/// reject keys that aren't plain relative paths. We also canonicalize the bucket cache directory
+/// and check it really lives under the cache root, which covers any symlink shenanigans.
+pub fn get_cache_file_path(bucket_cache_fp: &Path, object: &str) -> Result<PathBuf> {
+    check_relative_path(object)?;
+
+    let cache_root = fs::canonicalize(get_cache_dir())?;
+    let bucket_root = fs::canonicalize(bucket_cache_fp)?;
+    if !bucket_root.starts_with(&cache_root) {
+        Err(WorkerError::new(
+            ErrKind::AWSError,
+            &format!(
+                "Bucket cache directory {} is outside of the cache!", bucket_cache_fp.display()
+            )
+        ))?
+    }
+
+    Ok(bucket_root.join(object))
+}
+
+// Content-addressed cache entries. Files used to be cached at their object path (e.g.
+// `cache/<bucket>/<key>`), so when a key was overwritten with new content, the new copy landed on
+// top of the old one, and a job still reading the old copy got a mix of the two. Now every
+// version of an object gets an entry of its own, named after a hash of its bucket, key, and ETag
+// (see `cache_entry_name`), in `cache/_objects/`. Bucket names can't start with an underscore, so
+// this can't collide with anything else in the cache.
+//
+// Since entry names don't say what's in them, a small index (`cache/_index`) records which entry
+// each path was last localized to. It's appended to, one `<entry>\t<path>` line at a time, so
+// that concurrent downloads don't have to coordinate over it; the last line for a path wins.
+
+/// Returns `fp` with `suffix` tacked on to the end of its file name, e.g. `<entry>.partial` for
+/// the entry at `<entry>`.
+pub fn with_suffix(fp: &Path, suffix: &str) -> PathBuf {
+    let mut name = fp.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Returns the directory cache entries are kept in.
+pub fn get_objects_dir() -> PathBuf {
+    get_cache_dir().join("_objects")
+}
+
+/// Returns the path of the cache index.
+pub fn get_index_path() -> PathBuf {
+    get_cache_dir().join("_index")
+}
+
+/// Returns the name of the cache entry for the version of the object with the given ETag.
+pub fn cache_entry_name(bucket: &str, object: &str, etag: &str) -> String {
+    let mut hasher = Sha256::new();
+    // As in `result_cache_key`, every field is followed by a separator.
+    for field in [bucket, object, etag].iter() {
+        hasher.update(field.as_bytes());
+        hasher.update(b"\0");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Reads the cache index: every path that has been localized, and the entry it was last
+/// localized to. A missing index is an empty one.
+pub fn read_index() -> HashMap<String, String> {
+    let contents = fs::read_to_string(get_index_path()).unwrap_or_default();
+    contents.lines().filter_map(|line| {
+        let mut fields = line.splitn(2, '\t');
+        let entry = fields.next()?;
+        let path = fields.next()?;
+        Some((path.to_owned(), entry.to_owned()))
+    }).collect()
+}
+
+/// Records in the index that `path` was localized to `entry`, unless it already says so.
+fn record_in_index(path: &str, entry: &str) -> Result<()> {
+    if read_index().get(path).map(|known| known.as_str()) == Some(entry) {
+        return Ok(());
+    }
+    let mut index = fs::OpenOptions::new().create(true).append(true).open(get_index_path())?;
+    index.write_all(format!("{}\t{}\n", entry, path).as_bytes())?;
+    Ok(())
+}
+
+// Resumable downloads. Entries are downloaded into `<entry>.partial`, and every so often (every
+// `DOWNLOAD_PROGRESS_BYTES`), once what's been written so far is safely on disk, how much that
+// is gets written down in a marker next to it, `<entry>.offset`. If the download is interrupted,
+// whether by a network blip or by the worker going down, the next attempt at it picks up from
+// where the marker says with a ranged GET, rather than from the first byte. Since the entry
+// name is tied to the object's ETag, and ranged GETs are conditional on it too, the bytes picked
+// up from are always from the same version of the object.
+//
+// Only one download of an entry can write to its partial file at a time (it's locked while they
+// do). Anyone else who wants the same entry at the same time downloads it into a file of their
+// own, which isn't resumed if they're interrupted.
+//
+// If the cache is encrypted (see `encryption`), downloads are sealed as they're written, and
+// can't be picked up by the next worker process: the chunk it'd pick up in the middle of was
+// sealed with a nonce it doesn't know. Retries within the same download still resume.
+
+/// How many times in a row a download is attempted, resuming from where the last attempt left
+/// off, before giving up on it.
+pub const DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// A download into a cache entry, which picks up where an earlier one left off if there was one
+/// (see `open`).
+struct PartialDownload {
+    file: fs::File,
+    path: PathBuf,
+    /// Where the offset marker is kept, if the download can be resumed.
+    marker: Option<PathBuf>,
+    /// How many bytes of the object are in the file.
+    offset: u64,
+    /// How many bytes of the object were in the file when the marker was last updated.
+    marked: u64,
+    /// What the download is sealed with, if the cache is encrypted.
+    sealer: Option<Sealer>,
+}
+
+impl PartialDownload {
+    /// Opens the partial file for the cache entry at `entry_fp`, keeping whatever the marker
+    /// says an earlier download got done.
+    fn open(entry_fp: &Path) -> Result<PartialDownload> {
+        let path = with_suffix(entry_fp, ".partial");
+        let mut file = fs::OpenOptions::new().create(true).write(true).open(&path)?;
+        if file.try_lock_exclusive().is_err() {
+            // Someone else is downloading the same object right now. Rather than wait on them,
+            // we download it too, into a file of our own.
+            let path = with_suffix(entry_fp, &format!(".{}.partial", Uuid::new_v4()));
+            let mut file = fs::File::create(&path)?;
+            let sealer = PartialDownload::start_sealing(&mut file)?;
+            return Ok(PartialDownload { file, path, marker: None, offset: 0, marked: 0, sealer });
+        }
+        if encryption::key().is_some() {
+            file.set_len(0)?;
+            let sealer = PartialDownload::start_sealing(&mut file)?;
+            return Ok(PartialDownload { file, path, marker: None, offset: 0, marked: 0, sealer });
+        }
+        let marker = with_suffix(entry_fp, ".offset");
+        // Only the bytes the marker vouches for are kept. Anything written after them might not
+        // have made it to disk intact.
+        let len = file.metadata()?.len();
+        let offset = fs::read_to_string(&marker).ok()
+            .and_then(|offset| offset.trim().parse::<u64>().ok())
+            .filter(|offset| *offset <= len)
+            .unwrap_or(0);
+        file.set_len(offset)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(PartialDownload {
+            file, path, marker: Some(marker), offset, marked: offset, sealer: None
+        })
+    }
+
+    /// Starts sealing what's written to `file`, if the cache is encrypted.
+    fn start_sealing(file: &mut fs::File) -> Result<Option<Sealer>> {
+        match encryption::key() {
+            Some(key) => {
+                let sealer = Sealer::new(&key);
+                file.write_all(&sealer.header())?;
+                Ok(Some(sealer))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Seals the last of the download, if it's being sealed. Nothing more can be written after.
+    fn seal(&mut self) -> Result<()> {
+        if let Some(sealer) = self.sealer.take() {
+            self.file.write_all(&sealer.finish()?)?;
+        }
+        Ok(())
+    }
+
+    /// Writes down how far along the download is in the marker, once what's been written so
+    /// far is on disk.
+    fn mark(&mut self) -> io::Result<()> {
+        if let Some(marker) = &self.marker {
+            self.file.sync_data()?;
+            fs::write(marker, self.offset.to_string())?;
+            self.marked = self.offset;
+        }
+        Ok(())
+    }
+
+    /// Throws away what's been downloaded, so that the next attempt starts from the first byte.
+    fn discard(&self) {
+        let _ = fs::remove_file(&self.path);
+        if let Some(marker) = &self.marker {
+            let _ = fs::remove_file(marker);
+        }
+    }
+
+    /// Moves the finished download into place as the entry at `entry_fp`. As with anything else
+    /// in the cache, anyone who finds the entry finds all of it.
+    fn finish(self, entry_fp: &Path) -> Result<()> {
+        self.file.sync_data()?;
+        fs::rename(&self.path, entry_fp)?;
+        if let Some(marker) = &self.marker {
+            let _ = fs::remove_file(marker);
+        }
+        Ok(())
+    }
+}
+
+impl Write for PartialDownload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match &mut self.sealer {
+            Some(sealer) => {
+                self.file.write_all(&sealer.update(buf)?)?;
+                buf.len()
+            },
+            None => self.file.write(buf)?,
+        };
+        self.offset += n as u64;
+        if self.offset - self.marked >= DOWNLOAD_PROGRESS_BYTES {
+            self.mark()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
 }
 
-// TODO: implement this method.
-// pub fn get_cache_file_path(f: &File) -> String {
-//     let cache_home = get_cache_dir();
-//     cache_home + f.path
-// }
+/// Checks downloaded bytes against the object's ETag, where that's possible. The ETag of an
+/// object uploaded in one go is the MD5 of its contents. The ETags of multipart uploads (which
+/// look like `<hash>-<number of parts>`) and of objects encrypted with KMS or customer-provided
+/// keys aren't, so those are taken on trust.
+pub fn verify_etag(path: &str, bytes: &[u8], head: &ObjectHead) -> Result<()> {
+    match md5_etag(head) {
+        Some(etag) => check_md5(path, &format!("{:x}", Md5::digest(bytes)), &etag),
+        None => Ok(()),
+    }
+}
+
+/// Like `verify_etag`, but checks what `file` reads (e.g. a file opened with `open_cached`)
+/// instead, without reading all of it into memory at once.
+pub fn verify_file_etag(path: &str, mut file: impl Read, head: &ObjectHead) -> Result<()> {
+    let etag = match md5_etag(head) {
+        Some(etag) => etag,
+        None => return Ok(()),
+    };
+    let mut hasher = Md5::new();
+    let mut chunk = vec![0; DOWNLOAD_CHUNK_BYTES];
+    loop {
+        let n = file.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&chunk[..n]);
+    }
+    check_md5(path, &format!("{:x}", hasher.finalize()), &etag)
+}
+
+/// Returns the object's ETag, if it's the MD5 of the object's contents (see `verify_etag`).
+fn md5_etag(head: &ObjectHead) -> Option<String> {
+    let etag = head.etag.as_deref().unwrap_or("").trim_matches('"').to_lowercase();
+    let is_md5 = etag.len() == 32 && etag.chars().all(|c: char| c.is_ascii_hexdigit());
+    let encryption = head.encryption.as_deref().unwrap_or("AES256");
+    if !is_md5 || encryption != "AES256" {
+        return None;
+    }
+    Some(etag)
+}
+
+fn check_md5(path: &str, md5: &str, etag: &str) -> Result<()> {
+    if md5 != etag {
+        Err(WorkerError::new(
+            ErrKind::AWSError,
+            &format!(
+                "Download of {} is corrupt: its MD5 is {}, but its ETag is {}.", path, md5, etag
+            )
+        ))?
+    }
+    Ok(())
+}
 
 // Our next function, `localize_file`, is what we use to download data from S3. Because it
 // performs network I/O, in order to unit test it we need to stub it.
@@ -98,7 +590,9 @@ pub fn get_cache_dir() -> String {
 // (`S3Client` in this case) directly. This allows us to swap out the inner object at test time
 // with a mock of our own design.
 pub struct WorkerS3ClientAdapter<T: WorkerS3ClientTrait> {
-    pub client: T
+    pub client: T,
+    /// The options every request is made with.
+    pub options: S3Options,
 }
 
 // This implementation does not use generics, but instead uses associated types.
@@ -121,22 +615,287 @@ pub struct WorkerS3ClientAdapter<T: WorkerS3ClientTrait> {
 // I ended up giving up on fighting the compiler and switched to using a Vec<u8> concrete return
 // type. This has the important disadvantage that it means that the file I/O is no longer under
 // unit tests but there's only so much I can do...
+//
+// UPDATE: downloads are written to a `Write` handed in by the caller now, rather than returned,
+// so that they can go straight to disk (see `PartialDownload`). The mock writes into it too.
+/// How big a piece of an object downloads read at a time.
+pub const DOWNLOAD_CHUNK_BYTES: usize = 64 * 1024;
+
+/// How often downloads report their progress: every time another this many bytes come in.
+pub const DOWNLOAD_PROGRESS_BYTES: u64 = 1024 * 1024;
+
+/// Calls `on_bytes` with how many bytes of a download have come in, `after`, if another
+/// `DOWNLOAD_PROGRESS_BYTES` have come in since there were `before`. Downloads also call
+/// `on_bytes` once more when they're done, whether or not they have just reported.
+pub fn report_download(before: u64, after: u64, on_bytes: &(dyn Fn(u64) + Sync)) {
+    if after / DOWNLOAD_PROGRESS_BYTES > before / DOWNLOAD_PROGRESS_BYTES {
+        on_bytes(after);
+    }
+}
+
+/// How big a piece of an object uploads send at a time. Objects any bigger than this are
+/// uploaded in parts (see `WorkerS3ClientAdapter::put_object`). S3 takes at most 10,000 parts,
+/// so this caps uploads at about 160 GB.
+pub const UPLOAD_PART_BYTES: u64 = 16 * 1024 * 1024;
+
+/// What a HEAD request tells us about an S3 object.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectHead {
+    pub etag: Option<String>,
+    pub size: Option<i64>,
+    /// The server-side encryption the object is stored with, e.g. `aws:kms`, if any.
+    pub encryption: Option<String>,
+}
+
+/// Options for the S3 requests made on behalf of a file, beyond its path. Files get these from
+/// the `File` message, falling back to the worker's config (see `for_file`).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct S3Options {
+    /// Whether or not to agree to pay for requests to requester-pays buckets.
+    pub requester_pays: bool,
+    /// The base64-encoded AES-256 key the objects were encrypted with, if they were encrypted
+    /// with a customer-provided key (SSE-C). Objects encrypted with S3- or KMS-managed keys need
+    /// no key: S3 decrypts those for anyone allowed to read them.
+    pub sse_customer_key: Option<String>,
+}
+
+impl S3Options {
+    /// The options for reading the given file: the file's own, on top of these.
+    pub fn for_file(&self, file: &File) -> S3Options {
+        S3Options {
+            requester_pays: self.requester_pays || file.get_requester_pays(),
+            sse_customer_key: match file.get_sse_customer_key() {
+                "" => self.sse_customer_key.clone(),
+                key => Some(key.to_owned()),
+            },
+        }
+    }
+
+    /// Checks that the SSE-C key, if there is one, is a base64-encoded 256-bit key.
+    pub fn validate(&self) -> Result<()> {
+        self.sse_customer()?;
+        Ok(())
+    }
+
+    /// The `request_payer` to send with requests.
+    pub fn request_payer(&self) -> Option<String> {
+        if self.requester_pays { Some("requester".to_owned()) } else { None }
+    }
+
+    /// The SSE-C algorithm, key, and key MD5 to send with requests, if there is a key. Note that
+    /// the key itself never goes in an error message.
+    pub fn sse_customer(&self) -> Result<(Option<String>, Option<String>, Option<String>)> {
+        let key = match &self.sse_customer_key {
+            Some(key) => key,
+            None => return Ok((None, None, None)),
+        };
+        let bytes = base64::decode(key).map_err(|_| WorkerError::new(
+            ErrKind::ValidationError, "The SSE-C key isn't valid base64."
+        ))?;
+        if bytes.len() != 32 {
+            Err(WorkerError::new(
+                ErrKind::ValidationError,
+                &format!("The SSE-C key is {} bytes long, but has to be 32.", bytes.len())
+            ))?
+        }
+        let key_md5 = base64::encode(Md5::digest(&bytes));
+        Ok((Some("AES256".to_owned()), Some(key.clone()), Some(key_md5)))
+    }
+}
+
 #[async_trait]
-pub trait WorkerS3ClientTrait {
-    async fn _get_object(&self, input: GetObjectRequest) -> Result<Vec<u8>>;
+pub trait WorkerS3ClientTrait: Clone {
+    /// Downloads an object into `out` as it comes in, calling `on_bytes` with how many bytes of
+    /// it have come in so far as they do (see `report_download`). Returns how many bytes there
+    /// were in all.
+    async fn _get_object(
+        &self,
+        input: GetObjectRequest,
+        out: &mut (dyn Write + Send),
+        on_bytes: &(dyn Fn(u64) + Sync),
+    ) -> Result<u64>;
+    async fn _head_object(&self, input: HeadObjectRequest) -> Result<ObjectHead>;
+    /// Returns one page of the keys of the objects matching the request, and the token to pass
+    /// back to get the next page (if there is one).
+    async fn _list_objects(&self, input: ListObjectsV2Request)
+        -> Result<(Vec<String>, Option<String>)>;
+    /// Uploads an object in one go, returning its ETag.
+    async fn _put_object(&self, input: PutObjectRequest) -> Result<Option<String>>;
+    /// Starts a multipart upload, returning its upload ID. Big objects are uploaded in parts
+    /// (`_upload_part`), which are then put together into the object
+    /// (`_complete_multipart_upload`), or thrown away if the upload fails partway through
+    /// (`_abort_multipart_upload`).
+    async fn _create_multipart_upload(&self, input: CreateMultipartUploadRequest)
+        -> Result<Option<String>>;
+    /// Uploads one part of a multipart upload, returning the part's ETag.
+    async fn _upload_part(&self, input: UploadPartRequest) -> Result<Option<String>>;
+    /// Puts the parts of a multipart upload together, returning the object's ETag.
+    async fn _complete_multipart_upload(&self, input: CompleteMultipartUploadRequest)
+        -> Result<Option<String>>;
+    async fn _abort_multipart_upload(&self, input: AbortMultipartUploadRequest) -> Result<()>;
+    /// Sends a GET request for a pre-signed URL (see `presigned::get_url`). Pre-signed URLs
+    /// don't need an S3 client at all, so unlike the others, this has a default.
+    async fn _get_url(
+        &self,
+        url: &str,
+        range: Option<String>,
+        out: &mut (dyn Write + Send),
+        on_bytes: &(dyn Fn(u64) + Sync),
+    ) -> Result<ObjectHead> {
+        get_url(url, range, out, on_bytes).await
+    }
+}
+/// A stand-in for S3, for tests. Out of the box, every object is the same three bytes,
+/// `[1, 2, 3]`, which is all that most tests need. Tests that need objects with something in
+/// them (e.g. a CSV file for a job to load) give the mock objects of their own instead (see
+/// `with_objects`), which come with realistic ETags and sizes.
+#[derive(Clone, Default)]
+pub struct WorkerS3ClientMock {
+    /// The objects, keyed on their S3 paths (e.g. `s3://foo/bar.csv`), if the mock was given any.
+    objects: Option<Arc<HashMap<String, Vec<u8>>>>,
+}
+
+/// What every object is, unless the mock was given objects of its own.
+const MOCK_OBJECT: &[u8] = &[1, 2, 3];
+
+impl WorkerS3ClientMock {
+    /// A mock holding just the given objects, keyed on their S3 paths. Their ETags are the MD5s
+    /// of their contents, as they would be on S3, and any other object doesn't exist.
+    pub fn with_objects<K: Into<String>, V: Into<Vec<u8>>>(
+        objects: impl IntoIterator<Item = (K, V)>
+    ) -> WorkerS3ClientMock {
+        let objects = objects.into_iter().map(|(path, body)| (path.into(), body.into())).collect();
+        WorkerS3ClientMock { objects: Some(Arc::new(objects)) }
+    }
+
+    /// Returns the contents and the ETag of the object at `path`.
+    fn object(&self, path: &str) -> Result<(&[u8], String)> {
+        let objects = match &self.objects {
+            Some(objects) => objects,
+            None => return Ok((MOCK_OBJECT, "\"mock-etag\"".to_owned())),
+        };
+        let body = objects.get(path).ok_or_else(|| WorkerError::new(
+            ErrKind::AWSError, &format!("NoSuchKey: there is no object at {}.", path)
+        ))?;
+        Ok((body, format!("\"{:x}\"", Md5::digest(body))))
+    }
+}
+
+/// The part of `body` a `Range` header asks for, e.g. everything from the second byte on for
+/// `bytes=1-`.
+fn mock_range<'a>(body: &'a [u8], range: Option<&str>) -> &'a [u8] {
+    let mut bounds = range.and_then(|range| range.strip_prefix("bytes=")).unwrap_or("").split('-');
+    let start = bounds.next().and_then(|start| start.parse().ok()).unwrap_or(0usize);
+    let end = bounds.next()
+        .and_then(|end| end.parse::<usize>().ok())
+        .map_or(body.len(), |end| end + 1)
+        .min(body.len());
+    &body[start.min(end)..end]
 }
-pub struct WorkerS3ClientMock {}
 
 #[async_trait]
 impl WorkerS3ClientTrait for WorkerS3ClientMock {
-    async fn _get_object(&self, _: GetObjectRequest) -> Result<Vec<u8>> {
-        Ok(vec![1, 2, 3])
+    async fn _get_object(
+        &self,
+        input: GetObjectRequest,
+        out: &mut (dyn Write + Send),
+        on_bytes: &(dyn Fn(u64) + Sync),
+    ) -> Result<u64> {
+        let (body, _) = self.object(&format!("s3://{}/{}", input.bucket, input.key))?;
+        let body = mock_range(body, input.range.as_deref());
+        out.write_all(body)?;
+        on_bytes(body.len() as u64);
+        Ok(body.len() as u64)
+    }
+
+    async fn _head_object(&self, input: HeadObjectRequest) -> Result<ObjectHead> {
+        let (body, etag) = self.object(&format!("s3://{}/{}", input.bucket, input.key))?;
+        Ok(ObjectHead { etag: Some(etag), size: Some(body.len() as i64), encryption: None })
+    }
+
+    async fn _get_url(
+        &self,
+        url: &str,
+        range: Option<String>,
+        out: &mut (dyn Write + Send),
+        on_bytes: &(dyn Fn(u64) + Sync),
+    ) -> Result<ObjectHead> {
+        // Objects given to the mock are found at their URLs without the signature.
+        let (body, etag) = self.object(without_query(url))?;
+        let size = Some(body.len() as i64);
+        let body = mock_range(body, range.as_deref());
+        out.write_all(body)?;
+        on_bytes(body.len() as u64);
+        Ok(ObjectHead { etag: Some(etag), size, encryption: None })
+    }
+
+    async fn _list_objects(&self, input: ListObjectsV2Request)
+        -> Result<(Vec<String>, Option<String>)> {
+        let prefix = input.prefix.unwrap_or_default();
+        if let Some(objects) = &self.objects {
+            let bucket = format!("s3://{}/", input.bucket);
+            let mut keys = objects.keys()
+                .filter_map(|path| path.strip_prefix(&bucket))
+                .filter(|key| key.starts_with(&prefix))
+                .map(str::to_owned)
+                .collect::<Vec<_>>();
+            keys.sort();
+            return Ok((keys, None));
+        }
+        // Two pages of two objects each, so that paging gets exercised too.
+        match input.continuation_token {
+            None => Ok((
+                vec![format!("{}a.csv", prefix), format!("{}b.csv", prefix)],
+                Some("page-2".to_owned())
+            )),
+            Some(_) => Ok((vec![format!("{}c.csv", prefix), format!("{}d.csv", prefix)], None)),
+        }
+    }
+
+    async fn _put_object(&self, input: PutObjectRequest) -> Result<Option<String>> {
+        // Like S3, the ETag of an object uploaded in one go is the MD5 of its contents.
+        let body = read_mock_body(input.body).await?;
+        Ok(Some(format!("\"{:x}\"", Md5::digest(&body))))
+    }
+
+    async fn _create_multipart_upload(&self, _: CreateMultipartUploadRequest)
+        -> Result<Option<String>> {
+        Ok(Some("mock-upload".to_owned()))
+    }
+
+    async fn _upload_part(&self, input: UploadPartRequest) -> Result<Option<String>> {
+        let body = read_mock_body(input.body).await?;
+        Ok(Some(format!("\"{:x}\"", Md5::digest(&body))))
+    }
+
+    async fn _complete_multipart_upload(&self, input: CompleteMultipartUploadRequest)
+        -> Result<Option<String>> {
+        // Multipart uploads' ETags end in how many parts there were.
+        let parts = input.multipart_upload.and_then(|upload| upload.parts).unwrap_or_default();
+        Ok(Some(format!("\"mock-etag-{}\"", parts.len())))
     }
+
+    async fn _abort_multipart_upload(&self, _: AbortMultipartUploadRequest) -> Result<()> {
+        Ok(())
+    }
+}
+
+async fn read_mock_body(body: Option<StreamingBody>) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    if let Some(body) = body {
+        body.into_async_read().read_to_end(&mut buf).await?;
+    }
+    Ok(buf)
 }
 
 #[async_trait]
 impl WorkerS3ClientTrait for S3Client {
-    async fn _get_object(&self, input: GetObjectRequest) -> Result<Vec<u8>> {
+    async fn _get_object(
+        &self,
+        input: GetObjectRequest,
+        out: &mut (dyn Write + Send),
+        on_bytes: &(dyn Fn(u64) + Sync),
+    ) -> Result<u64> {
         // `get_object` is the S3Client object download function.
         // let obj = self.get_object(input).await?;
         let future = self.get_object(input);
@@ -155,45 +914,577 @@ impl WorkerS3ClientTrait for S3Client {
             )
             .map(|v| { v.into_async_read() })?;
 
-        // Another limitation here: the file size has to be sufficiently small such that it can
-        // fit into main memory, since we are not spilling to disk incrementally.
-        //
-        // TODO: switch to incremental read-write.
-        let mut buf = vec![];
-        obj_reader.read_to_end(&mut buf).await?;
-        Ok(buf)
+        // The object is written out a chunk at a time as it comes in, so it never has to fit in
+        // memory all at once (unless `out` is itself in memory, of course).
+        let mut total = 0;
+        let mut chunk = vec![0; DOWNLOAD_CHUNK_BYTES];
+        loop {
+            let n = obj_reader.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            out.write_all(&chunk[..n])?;
+            report_download(total, total + n as u64, on_bytes);
+            total += n as u64;
+        }
+        on_bytes(total);
+        Ok(total)
+    }
+
+    /// Returns the object's ETag and size, without downloading it.
+    async fn _head_object(&self, input: HeadObjectRequest) -> Result<ObjectHead> {
+        let obj = self.head_object(input).await?;
+        // Objects encrypted with a customer-provided key say so in a header of their own.
+        let encryption = match obj.sse_customer_algorithm {
+            Some(_) => Some("SSE-C".to_owned()),
+            None => obj.server_side_encryption,
+        };
+        Ok(ObjectHead { etag: obj.e_tag, size: obj.content_length, encryption })
+    }
+
+    async fn _list_objects(&self, input: ListObjectsV2Request)
+        -> Result<(Vec<String>, Option<String>)> {
+        let page = self.list_objects_v2(input).await?;
+        let keys = page.contents.unwrap_or_default().into_iter().filter_map(|obj| obj.key);
+        let next = match page.is_truncated {
+            Some(true) => page.next_continuation_token,
+            _ => None,
+        };
+        Ok((keys.collect(), next))
+    }
+
+    async fn _put_object(&self, input: PutObjectRequest) -> Result<Option<String>> {
+        Ok(self.put_object(input).await?.e_tag)
+    }
+
+    async fn _create_multipart_upload(&self, input: CreateMultipartUploadRequest)
+        -> Result<Option<String>> {
+        Ok(self.create_multipart_upload(input).await?.upload_id)
+    }
+
+    async fn _upload_part(&self, input: UploadPartRequest) -> Result<Option<String>> {
+        Ok(self.upload_part(input).await?.e_tag)
+    }
+
+    async fn _complete_multipart_upload(&self, input: CompleteMultipartUploadRequest)
+        -> Result<Option<String>> {
+        Ok(self.complete_multipart_upload(input).await?.e_tag)
+    }
+
+    async fn _abort_multipart_upload(&self, input: AbortMultipartUploadRequest) -> Result<()> {
+        self.abort_multipart_upload(input).await?;
+        Ok(())
     }
 }
 
 impl<T: WorkerS3ClientTrait> WorkerS3ClientAdapter<T> {
+    pub fn new(client: T) -> WorkerS3ClientAdapter<T> {
+        WorkerS3ClientAdapter { client, options: S3Options::default() }
+    }
+
+    /// Returns the same client, making its requests with the given options instead.
+    pub fn with_options(self, options: S3Options) -> WorkerS3ClientAdapter<T> {
+        WorkerS3ClientAdapter { options, ..self }
+    }
+
+    /// Returns a client for reading the given file, i.e. one making its requests with the file's
+    /// options, on top of this one's (see `S3Options::for_file`).
+    pub fn for_file(&self, file: &File) -> WorkerS3ClientAdapter<T> {
+        let options = self.options.for_file(file);
+        WorkerS3ClientAdapter { client: self.client.clone(), options }
+    }
+
     pub async fn get_object(&self, req: GetObjectRequest) -> Result<Vec<u8>> {
-        Ok(self.client._get_object(req).await?)
+        let mut buf = vec![];
+        self.get_object_into(req, &mut buf, &|_| {}).await?;
+        Ok(buf)
+    }
+
+    /// Like `get_object`, but writes the object to `out` as it comes in, and calls `on_bytes` as
+    /// the download goes (see `report_download`). Returns how many bytes were written.
+    pub async fn get_object_into(
+        &self,
+        req: GetObjectRequest,
+        out: &mut (dyn Write + Send),
+        on_bytes: &(dyn Fn(u64) + Sync),
+    ) -> Result<u64> {
+        Ok(self.client._get_object(req, out, on_bytes).await?)
+    }
+
+    /// Downloads (the given range of) the object at a pre-signed URL (see `presigned`).
+    pub async fn get_url(&self, url: &str, range: Option<String>) -> Result<(Vec<u8>, ObjectHead)> {
+        let mut buf = vec![];
+        let head = self.get_url_into(url, range, &mut buf, &|_| {}).await?;
+        Ok((buf, head))
+    }
+
+    /// Like `get_url`, but writes the object to `out` as it comes in, and calls `on_bytes` as the
+    /// download goes (see `report_download`).
+    pub async fn get_url_into(
+        &self,
+        url: &str,
+        range: Option<String>,
+        out: &mut (dyn Write + Send),
+        on_bytes: &(dyn Fn(u64) + Sync),
+    ) -> Result<ObjectHead> {
+        self.client._get_url(url, range, out, on_bytes).await
+    }
+
+    /// Sends a HEAD request for the object at the given S3 path. This fails if there is no such
+    /// object.
+    ///
+    /// If the path is a pattern (see `is_pattern`), every object it matches is looked up instead,
+    /// and they are reported as one: the ETag stands for all of them (and the paths they're at),
+    /// and the size is their total. The same goes for the objects a manifest lists (see
+    /// `manifest`), whose ETag stands for the manifest itself too.
+    pub async fn head(&self, path: &str) -> Result<ObjectHead> {
+        if !is_pattern(path) && !is_manifest(path) {
+            return self.head_object(path).await;
+        }
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        if is_manifest(path) {
+            let manifest = self.head_object(&path[MANIFEST_SCHEME.len()..]).await?;
+            hasher.update(manifest.etag.unwrap_or_default().as_bytes());
+            hasher.update(b"\0");
+        }
+        for member in self.expand(path).await? {
+            let head = self.head_object(&member).await?;
+            // As in `result_cache_key`, every field is followed by a separator.
+            hasher.update(member.as_bytes());
+            hasher.update(b"\0");
+            hasher.update(head.etag.unwrap_or_default().as_bytes());
+            hasher.update(b"\0");
+            size += head.size.unwrap_or(0).max(0);
+        }
+        Ok(ObjectHead {
+            etag: Some(format!("{:x}", hasher.finalize())), size: Some(size), encryption: None
+        })
+    }
+
+    async fn head_object(&self, path: &str) -> Result<ObjectHead> {
+        // A pre-signed URL is only good for a GET, so we GET the first byte.
+        if is_presigned_url(path) {
+            return Ok(self.get_url(path, Some("bytes=0-0".to_owned())).await?.1);
+        }
+        let bucket_map = parse_file_path(path)?;
+        // Unlike `GetObjectRequest` below, we only need a couple of the fields here, so we let
+        // `Default` fill in the rest.
+        let (sse_customer_algorithm, sse_customer_key, sse_customer_key_md5) =
+            self.options.sse_customer()?;
+        let req = HeadObjectRequest {
+            bucket: bucket_map.get("bucket").unwrap().clone(),
+            key: bucket_map.get("object").unwrap().clone(),
+            request_payer: self.options.request_payer(),
+            sse_customer_algorithm,
+            sse_customer_key,
+            sse_customer_key_md5,
+            ..Default::default()
+        };
+        self.client._head_object(req).await
+    }
+
+    /// Returns the ETag of the object at the given S3 path. The ETag changes whenever the object
+    /// does, which makes it a cheap way of telling whether our copy of a file is out of date.
+    pub async fn get_etag(&self, path: &str) -> Result<String> {
+        let etag = self.head(path).await?.etag.ok_or_else(|| WorkerError::new(
+            ErrKind::AWSError, &format!("Object {} has no ETag.", path)
+        ))?;
+        Ok(etag)
+    }
+
+    /// Downloads (at most) the first `n_bytes` bytes of the object at the given S3 path, e.g. to
+    /// have a look at a CSV file's header without downloading the whole file.
+    pub async fn get_object_prefix(&self, path: &str, n_bytes: u64) -> Result<Vec<u8>> {
+        // HTTP byte ranges are inclusive at both ends.
+        let range = format!("bytes=0-{}", n_bytes.max(1) - 1);
+        if is_presigned_url(path) {
+            return Ok(self.get_url(path, Some(range)).await?.0);
+        }
+        let bucket_map = parse_file_path(path)?;
+        let (sse_customer_algorithm, sse_customer_key, sse_customer_key_md5) =
+            self.options.sse_customer()?;
+        let req = GetObjectRequest {
+            bucket: bucket_map.get("bucket").unwrap().clone(),
+            key: bucket_map.get("object").unwrap().clone(),
+            range: Some(range),
+            request_payer: self.options.request_payer(),
+            sse_customer_algorithm,
+            sse_customer_key,
+            sse_customer_key_md5,
+            ..Default::default()
+        };
+        self.get_object(req).await
+    }
+
+    /// Lists the objects whose keys start with the given S3 path (`s3://bucket/some/prefix/`),
+    /// returning their full S3 paths in key order. Directory placeholder objects (keys ending in
+    /// `/`) are left out.
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let bucket_map = parse_file_path(prefix)?;
+        let bucket = bucket_map.get("bucket").unwrap().clone();
+        let mut paths = vec![];
+        let mut continuation_token = None;
+        loop {
+            let req = ListObjectsV2Request {
+                bucket: bucket.clone(),
+                prefix: Some(bucket_map.get("object").unwrap().clone()),
+                continuation_token,
+                request_payer: self.options.request_payer(),
+                ..Default::default()
+            };
+            let (keys, next) = self.client._list_objects(req).await?;
+            paths.extend(
+                keys.into_iter()
+                    .filter(|key| !key.ends_with("/"))
+                    .map(|key| format!("s3://{}/{}", bucket, key))
+            );
+            match next {
+                Some(token) => continuation_token = Some(token),
+                None => return Ok(paths),
+            }
+        }
+    }
+
+    /// Lists the objects a file path stands for, in key order: just the one, unless the path is
+    /// a pattern (see `is_pattern`). A pattern that doesn't match anything is an error. A
+    /// manifest (see `manifest`) stands for the objects it lists, in the order it lists them.
+    pub async fn expand(&self, path: &str) -> Result<Vec<String>> {
+        if is_manifest(path) {
+            return Ok(read_manifest(path, self).await?.paths());
+        }
+        if !is_pattern(path) {
+            return Ok(vec![path.to_owned()]);
+        }
+        // Only the part of the path up to the first wildcard narrows down the listing.
+        let prefix = &path[..path.find(|c: char| c == '*' || c == '?').unwrap_or(path.len())];
+        let members = self.list(prefix).await?.into_iter()
+            .filter(|member| prefix == path || matches_pattern(path, member))
+            .collect::<Vec<_>>();
+        if members.is_empty() {
+            Err(WorkerError::new(ErrKind::AWSError, &format!("No objects match {}.", path)))?
+        }
+        Ok(members)
+    }
+
+    /// Uploads `body` to the given S3 path, returning the new object's ETag. Uploads are made
+    /// with the client's options, so an object uploaded with a customer-provided key has to be
+    /// read back with the same key.
+    pub async fn put_object(&self, path: &str, body: Vec<u8>) -> Result<String> {
+        let len = body.len() as u64;
+        self.put_from(path, &mut io::Cursor::new(body), len, UPLOAD_PART_BYTES).await
+    }
+
+    /// Like `put_object`, but uploads the file at `fp`, reading it a part at a time rather than
+    /// all at once.
+    pub async fn put_file(&self, path: &str, fp: impl AsRef<Path>) -> Result<String> {
+        // Files sealed in the cache (see `encryption`) are uploaded as their plaintext.
+        let (mut file, len) = open_cached(fp)?;
+        self.put_from(path, &mut file, len, UPLOAD_PART_BYTES).await
+    }
+
+    /// Like `put_file`, but uploads the `len` bytes `body` has to read, e.g. a file opened with
+    /// `open_unsealed`.
+    pub async fn put_reader(
+        &self, path: &str, body: &mut (dyn Read + Send), len: u64
+    ) -> Result<String> {
+        self.put_from(path, body, len, UPLOAD_PART_BYTES).await
+    }
+
+    /// Uploads the `len` bytes `body` has to read to the given S3 path: in one go, if there's
+    /// no more than `part_bytes` of them, and in parts of `part_bytes` each otherwise.
+    async fn put_from(
+        &self, path: &str, body: &mut (dyn Read + Send), len: u64, part_bytes: u64
+    ) -> Result<String> {
+        // Pre-signed URLs (see `presigned`) are signed for a GET, and patterns and manifests
+        // stand for several objects, none of which make sense to upload to.
+        if is_presigned_url(path) || is_pattern(path) || is_manifest(path) {
+            Err(WorkerError::new(
+                ErrKind::ValidationError,
+                &format!("Cannot upload to {}: uploads need a plain S3 path.", without_query(path))
+            ))?
+        }
+        let bucket_map = parse_file_path(path)?;
+        let bucket = bucket_map.get("bucket").unwrap().clone();
+        let key = bucket_map.get("object").unwrap().clone();
+        let (sse_customer_algorithm, sse_customer_key, sse_customer_key_md5) =
+            self.options.sse_customer()?;
+
+        let etag = if len <= part_bytes {
+            let part = read_part(body, len)?;
+            let req = PutObjectRequest {
+                bucket,
+                key,
+                content_length: Some(part.len() as i64),
+                content_md5: Some(base64::encode(Md5::digest(&part))),
+                body: Some(StreamingBody::from(part)),
+                request_payer: self.options.request_payer(),
+                sse_customer_algorithm,
+                sse_customer_key,
+                sse_customer_key_md5,
+                ..Default::default()
+            };
+            self.client._put_object(req).await?
+        } else {
+            let req = CreateMultipartUploadRequest {
+                bucket: bucket.clone(),
+                key: key.clone(),
+                request_payer: self.options.request_payer(),
+                sse_customer_algorithm,
+                sse_customer_key,
+                sse_customer_key_md5,
+                ..Default::default()
+            };
+            let upload_id = self.client._create_multipart_upload(req).await?.ok_or_else(|| {
+                WorkerError::new(ErrKind::AWSError, &format!("No upload ID for {}.", path))
+            })?;
+            // An abandoned multipart upload's parts stick around (and get billed for) until
+            // it's aborted, so if anything goes wrong, we abort it before giving up. The error
+            // is turned into a message first, since it has to be held on to while we do.
+            let parts = self.put_parts(&bucket, &key, &upload_id, body, len, part_bytes).await
+                .map_err(|err| err.to_string());
+            let parts = match parts {
+                Ok(parts) => parts,
+                Err(message) => {
+                    let req = AbortMultipartUploadRequest {
+                        bucket,
+                        key,
+                        upload_id,
+                        request_payer: self.options.request_payer(),
+                        ..Default::default()
+                    };
+                    if let Err(err) = self.client._abort_multipart_upload(req).await {
+                        log_error!("Could not abort the upload to {}: {}", path, err);
+                    }
+                    Err(WorkerError::new(
+                        ErrKind::AWSError, &format!("Upload to {} failed: {}", path, message)
+                    ))?
+                },
+            };
+            let req = CompleteMultipartUploadRequest {
+                bucket,
+                key,
+                upload_id,
+                multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+                request_payer: self.options.request_payer(),
+                ..Default::default()
+            };
+            self.client._complete_multipart_upload(req).await?
+        };
+        let etag = etag.ok_or_else(|| WorkerError::new(
+            ErrKind::AWSError, &format!("Object {} has no ETag.", path)
+        ))?;
+        log!("Uploaded {} ({} bytes).", path, len);
+        Ok(etag)
+    }
+
+    /// Uploads the parts of a multipart upload (see `put_from`), returning what S3 needs to
+    /// know about them to put them together.
+    async fn put_parts(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        body: &mut (dyn Read + Send),
+        len: u64,
+        part_bytes: u64,
+    ) -> Result<Vec<CompletedPart>> {
+        let mut parts = vec![];
+        let mut uploaded = 0;
+        while uploaded < len {
+            let part = read_part(body, part_bytes.min(len - uploaded))?;
+            uploaded += part.len() as u64;
+            // Parts are numbered from 1.
+            let part_number = parts.len() as i64 + 1;
+            let (sse_customer_algorithm, sse_customer_key, sse_customer_key_md5) =
+                self.options.sse_customer()?;
+            let req = UploadPartRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                upload_id: upload_id.to_owned(),
+                part_number,
+                content_length: Some(part.len() as i64),
+                content_md5: Some(base64::encode(Md5::digest(&part))),
+                body: Some(StreamingBody::from(part)),
+                request_payer: self.options.request_payer(),
+                sse_customer_algorithm,
+                sse_customer_key,
+                sse_customer_key_md5,
+                ..Default::default()
+            };
+            let e_tag = self.client._upload_part(req).await?;
+            parts.push(CompletedPart { e_tag, part_number: Some(part_number) });
+        }
+        Ok(parts)
     }
 }
 
+/// Reads exactly `n_bytes` bytes out of an upload's body. A body that runs out before then is an
+/// error: it has changed since its length was taken, and S3 would reject it anyway.
+fn read_part(body: &mut (dyn Read + Send), n_bytes: u64) -> Result<Vec<u8>> {
+    let mut part = vec![0; n_bytes as usize];
+    body.read_exact(&mut part)?;
+    Ok(part)
+}
+
 /// Downloads the file to local disk cache. If the file already exists in the cache, this is a
 /// no-op. If the file path is invalid, the file does not exist, or an error occurs while trying
 /// to download the file, an error is bubbled up.
 pub async fn localize_file<T: WorkerS3ClientTrait>(
     file: &File, client: &WorkerS3ClientAdapter<T>
-) -> Result<String> {
+) -> Result<PathBuf> {
+    localize_file_within(file, client, &DiskLimits::default()).await
+}
+
+/// Like `localize_file`, but a file that doesn't fit within `limits` (see `check_disk_space`)
+/// is a `ResourceError`, rather than being written to disk.
+pub async fn localize_file_within<T: WorkerS3ClientTrait>(
+    file: &File, client: &WorkerS3ClientAdapter<T>, limits: &DiskLimits
+) -> Result<PathBuf> {
+    localize_file_with_progress(file, client, limits, &|_| {}).await
+}
+
+/// Like `localize_file_within`, but calls `on_bytes` as the file downloads, with how many bytes
+/// of it have come in so far (see `report_download`). It isn't called at all if the file is
+/// already in the cache.
+pub async fn localize_file_with_progress<T: WorkerS3ClientTrait>(
+    file: &File,
+    client: &WorkerS3ClientAdapter<T>,
+    limits: &DiskLimits,
+    on_bytes: &(dyn Fn(u64) + Sync),
+) -> Result<PathBuf> {
 
     // let client = create_new_s3_client();
     let path = file.get_path();
-    let bucket_map = parse_file_path(path)?;
+    // What the file goes by in the logs and the index: its path, minus the signature if it's a
+    // pre-signed URL (see `presigned`).
+    let shown = without_query(path);
 
-    // `clone` is necessary here because the `bucket_map` struct owns its values. `get` returns
-    // a pointer to those values. Since the `String` type is a move type, not a copy type, trying
-    // to move ownership of it to the `bucket` and `object` variables doesn't work, because it
-    // would invalidate the `bucket_map` value references.
-    //
-    // Why do we need a `String` instead of an `&str` pointer anyway? Well, because
-    // `GetObjectRequest` requires `String` parameters, that's why.
-    let bucket = bucket_map.get("bucket").unwrap().clone();
-    let object = bucket_map.get("object").unwrap().clone();
+    // Which version of the object we get decides which cache entry it goes in (see
+    // `cache_entry_name`). If we already have that version, there's nothing to download.
+    let head = client.head_object(path).await?;
+    let etag = head.etag.clone().ok_or_else(|| WorkerError::new(
+        ErrKind::AWSError, &format!("Object {} has no ETag.", shown)
+    ))?;
+    let (bucket, object) = if is_presigned_url(path) {
+        // There's no bucket to speak of here, but the URL minus its signature (which changes
+        // every time the URL is signed) picks out the object just as well.
+        (String::new(), shown.to_owned())
+    } else {
+        let bucket_map = parse_file_path(path)?;
 
-    let bucket_cache_fp = create_cache_dir(bucket.as_str())?;
-    let file_cache_fp = format!("{}/{}", bucket_cache_fp, object);
+        // `clone` is necessary here because the `bucket_map` struct owns its values. `get`
+        // returns a pointer to those values. Since the `String` type is a move type, not a copy
+        // type, trying to move ownership of it to the `bucket` and `object` variables doesn't
+        // work, because it would invalidate the `bucket_map` value references.
+        //
+        // Why do we need a `String` instead of an `&str` pointer anyway? Well, because
+        // `GetObjectRequest` requires `String` parameters, that's why.
+        (bucket_map.get("bucket").unwrap().clone(), bucket_map.get("object").unwrap().clone())
+    };
+    let entry = cache_entry_name(&bucket, &object, &etag);
+    fs::create_dir_all(get_objects_dir())?;
+    let file_cache_fp = get_objects_dir().join(&entry);
+    if file_cache_fp.exists() {
+        log!("{} is already in the cache.", shown);
+        record_in_index(shown, &entry)?;
+        return Ok(file_cache_fp);
+    }
+
+    // The entry is downloaded under a name of its own first (see `PartialDownload`), and then
+    // moved into place.
+    let mut partial = PartialDownload::open(&file_cache_fp)?;
+    let resumed_from = partial.offset;
+    let size = head.size.unwrap_or(0).max(0) as u64;
+    check_disk_space(size.saturating_sub(resumed_from), limits)?;
+    let mut attempt = 1;
+    loop {
+        let range = match partial.offset {
+            0 => None,
+            offset => {
+                log!("Resuming the download of {} from byte {}.", shown, offset);
+                Some(format!("bytes={}-", offset))
+            },
+        };
+        // Progress counts every byte this call has downloaded, over however many attempts.
+        let fetched = partial.offset - resumed_from;
+        let on_attempt_bytes = |bytes: u64| on_bytes(fetched + bytes);
+        let downloaded = if is_presigned_url(path) {
+            download_url(client, path, &head, range, &mut partial, &on_attempt_bytes).await
+        } else {
+            let (bucket, object, etag) = (bucket.clone(), object.clone(), etag.clone());
+            download_object(client, bucket, object, etag, range, &mut partial, &on_attempt_bytes)
+                .await
+        };
+        match downloaded {
+            Ok(()) => break,
+            Err(err) if attempt < DOWNLOAD_ATTEMPTS => {
+                log!("The download of {} was interrupted, retrying: {}", shown, err);
+                attempt += 1;
+            },
+            Err(err) => return Err(err),
+        }
+    }
+    log!("Downloaded {} ({} bytes, {} of them just now).",
+        shown, partial.offset, partial.offset - resumed_from);
+
+    // Whatever went wrong here, the bytes we have are no good, so there's no resuming from them.
+    if head.size.is_some() && partial.offset != size {
+        let got = partial.offset;
+        partial.discard();
+        Err(WorkerError::new(
+            ErrKind::AWSError,
+            &format!("Download of {} came to {} bytes, but it is {}.", shown, got, size)
+        ))?
+    }
+    partial.seal()?;
+    partial.flush()?;
+    let verified = open_cached(&partial.path)
+        .and_then(|(file, _)| verify_file_etag(shown, file, &head));
+    if let Err(err) = verified {
+        partial.discard();
+        return Err(err);
+    }
+    partial.finish(&file_cache_fp)?;
+    record_in_index(shown, &entry)?;
+    Ok(file_cache_fp)
+}
+
+/// Downloads (the given range of) the object at a pre-signed URL into `out`. The object has to be
+/// the version `head` describes.
+pub(crate) async fn download_url<T: WorkerS3ClientTrait>(
+    client: &WorkerS3ClientAdapter<T>,
+    url: &str,
+    head: &ObjectHead,
+    range: Option<String>,
+    out: &mut (dyn Write + Send),
+    on_bytes: &(dyn Fn(u64) + Sync),
+) -> Result<()> {
+    let got = client.get_url_into(url, range, out, on_bytes).await?;
+    // Unlike `download_object`, we can't make the download conditional on the ETag, so we check
+    // it afterwards instead.
+    if got.etag != head.etag {
+        Err(WorkerError::new(
+            ErrKind::AWSError,
+            &format!("{} changed while it was being downloaded.", without_query(url))
+        ))?
+    }
+    Ok(())
+}
+
+/// Downloads (the given range of) the given version (ETag) of an object into `out`.
+pub(crate) async fn download_object<T: WorkerS3ClientTrait>(
+    client: &WorkerS3ClientAdapter<T>,
+    bucket: String,
+    object: String,
+    etag: String,
+    range: Option<String>,
+    out: &mut (dyn Write + Send),
+    on_bytes: &(dyn Fn(u64) + Sync),
+) -> Result<()> {
+
+    let (sse_customer_algorithm, sse_customer_key, sse_customer_key_md5) =
+        client.options.sse_customer()?;
 
     // Why is this so verbose? I have no idea, the documentation doesn't seem to have any simpler
     // constructors...ew.
@@ -201,34 +1492,42 @@ pub async fn localize_file<T: WorkerS3ClientTrait>(
         bucket: bucket,
         key: object,
         expected_bucket_owner: None,
-        if_match: None,
+        // In case the object changes between the HEAD and now, in which case the download
+        // would end up in the wrong entry.
+        if_match: Some(etag),
         if_modified_since: None,
         if_none_match: None,
         if_unmodified_since: None,
         part_number: None,
-        range: None,
-        request_payer: None,
+        range,
+        request_payer: client.options.request_payer(),
         response_cache_control: None,
         response_content_disposition: None,
         response_content_encoding: None,
         response_content_language: None,
         response_content_type: None,
         response_expires: None,
-        sse_customer_algorithm: None,
-        sse_customer_key: None,
-        sse_customer_key_md5: None,
+        sse_customer_algorithm,
+        sse_customer_key,
+        sse_customer_key_md5,
         version_id: None,
     };
-    let buf = client.get_object(req).await?;
-    fs::write(&file_cache_fp, buf)?;
-    Ok(file_cache_fp)
+    client.get_object_into(req, out, on_bytes).await?;
+    Ok(())
+}
+
+/// A file that has been downloaded into the local cache, and what it took to get it there.
+pub struct LocalizedFile {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub duration: Duration,
 }
 
 /// Downloads all of the files needed by the job to the disk cache. Calls `localize_file`
 /// repeatedly to do so.
 pub async fn localize_files<'a, T: WorkerS3ClientTrait>(
     workload: &'a Workload, client: &WorkerS3ClientAdapter<T>
-) -> Result<(Vec<&'a File>, Vec<String>)> {
+) -> Result<(Vec<&'a File>, Vec<LocalizedFile>)> {
     // Interesting quirk here. According to the Rust VSCode extension this vector has the
     // following contained type:
     //
@@ -265,12 +1564,17 @@ pub async fn localize_files<'a, T: WorkerS3ClientTrait>(
         futures.push(future);
     }
 
-    let mut file_paths: Vec<String> = vec![];
+    // Futures are lazy, so nothing is downloaded until the future is awaited. Which means we can
+    // time each download by timing its await.
+    let mut localized_files: Vec<LocalizedFile> = vec![];
     for future in futures {
-        let file_path = future.await?;
-        file_paths.push(file_path);
+        let start = Instant::now();
+        let path = future.await?;
+        let duration = start.elapsed();
+        let bytes = fs::metadata(&path)?.len();
+        localized_files.push(LocalizedFile { path, bytes, duration });
     }
-    Ok((workload_files, file_paths))
+    Ok((workload_files, localized_files))
 }
 
 #[cfg(test)]
@@ -364,17 +1668,163 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    /// Test that object keys cannot be used to write outside of the cache.
+    fn test_get_cache_file_path() {
+        let bucket_cache_fp = create_cache_dir("foo").unwrap();
+
+        let result = get_cache_file_path(&bucket_cache_fp, "bar.csv");
+        assert!(result.is_ok());
+        assert!(result.unwrap().ends_with(Path::new("foo").join("bar.csv")));
+
+        let result = get_cache_file_path(&bucket_cache_fp, "../../etc/cron.d/evil");
+        assert!(result.is_err());
+
+        let result = get_cache_file_path(&bucket_cache_fp, "/etc/cron.d/evil");
+        assert!(result.is_err());
+
+        let result = create_cache_dir("../../etc");
+        assert!(result.is_err());
+
+        let result = create_cache_dir("..");
+        assert!(result.is_err());
+    }
+
     #[test]
     /// Test the S3 file getter. Note that this unit test doesn't test the actual network
     /// transaction, only writing to the output file. For network tests refer to the integration
     /// tests.
     fn test_localize_file() {
         let file = craft_file_message(None, None);
-        let client_mock = WorkerS3ClientMock {};
-        let client_adapter = WorkerS3ClientAdapter { client: client_mock };
+        let client_mock = WorkerS3ClientMock::default();
+        let client_adapter = WorkerS3ClientAdapter::new(client_mock);
+        let result = block_on(localize_file(&file, &client_adapter));
+
+        assert!(result.is_ok());
+        // The file is cached under its version's entry, and the index knows it's there.
+        let entry = cache_entry_name("foo", "bar", "\"mock-etag\"");
+        assert_eq!(result.unwrap(), get_objects_dir().join(&entry));
+        assert_eq!(read_index().get(file.get_path()), Some(&entry));
+    }
+
+    #[test]
+    fn test_localize_presigned_url() {
+        let url = "https://foo.s3.amazonaws.com/bar.csv?X-Amz-Expires=60&X-Amz-Signature=abc";
+        assert!(!is_pattern(url));
+        let file = craft_file_message(None, Some(url.to_owned()));
+        let client_adapter = WorkerS3ClientAdapter::new(WorkerS3ClientMock::default());
         let result = block_on(localize_file(&file, &client_adapter));
 
+        // The signature goes in neither the entry's name nor the index.
+        let shown = "https://foo.s3.amazonaws.com/bar.csv";
+        let entry = cache_entry_name("", shown, "\"mock-etag\"");
+        assert_eq!(result.unwrap(), get_objects_dir().join(&entry));
+        assert_eq!(read_index().get(shown), Some(&entry));
+        assert!(!fs::read_to_string(get_index_path()).unwrap().contains("X-Amz-Signature"));
+    }
+
+    #[test]
+    fn test_report_download() {
+        use std::sync::Mutex;
+
+        let reports = Mutex::new(vec![]);
+        let on_bytes = |bytes: u64| reports.lock().unwrap().push(bytes);
+        report_download(0, DOWNLOAD_PROGRESS_BYTES - 1, &on_bytes);
+        report_download(DOWNLOAD_PROGRESS_BYTES - 1, DOWNLOAD_PROGRESS_BYTES + 1, &on_bytes);
+        report_download(DOWNLOAD_PROGRESS_BYTES + 1, DOWNLOAD_PROGRESS_BYTES + 2, &on_bytes);
+        assert_eq!(*reports.lock().unwrap(), vec![DOWNLOAD_PROGRESS_BYTES + 1]);
+
+        // Downloads report how they ended, too.
+        reports.lock().unwrap().clear();
+        let entry = cache_entry_name("foo", "progress.csv", "\"mock-etag\"");
+        let _ = fs::remove_file(get_objects_dir().join(&entry));
+        let file = craft_file_message(None, Some("s3://foo/progress.csv".to_owned()));
+        let client_adapter = WorkerS3ClientAdapter::new(WorkerS3ClientMock::default());
+        let result = block_on(localize_file_with_progress(
+            &file, &client_adapter, &DiskLimits::default(), &on_bytes
+        ));
         assert!(result.is_ok());
+        assert_eq!(reports.lock().unwrap().last(), Some(&3));
+    }
+
+    #[test]
+    fn test_resume_download() {
+        // An earlier download of the object got its first byte onto disk before it was cut off,
+        // and then some that the marker doesn't vouch for.
+        let entry_fp = get_objects_dir()
+            .join(cache_entry_name("foo", "resume.csv", "\"mock-etag\""));
+        fs::create_dir_all(get_objects_dir()).unwrap();
+        let _ = fs::remove_file(&entry_fp);
+        fs::write(with_suffix(&entry_fp, ".partial"), &[1, 9]).unwrap();
+        fs::write(with_suffix(&entry_fp, ".offset"), "1").unwrap();
+
+        let file = craft_file_message(None, Some("s3://foo/resume.csv".to_owned()));
+        let client_adapter = WorkerS3ClientAdapter::new(WorkerS3ClientMock::default());
+        let reports = std::sync::Mutex::new(vec![]);
+        let on_bytes = |bytes: u64| reports.lock().unwrap().push(bytes);
+        let result = block_on(localize_file_with_progress(
+            &file, &client_adapter, &DiskLimits::default(), &on_bytes
+        ));
+
+        // Only the rest of the object is downloaded, and the partial file and its marker are
+        // gone once the entry is in place.
+        assert_eq!(result.unwrap(), entry_fp);
+        assert_eq!(fs::read(&entry_fp).unwrap(), vec![1, 2, 3]);
+        assert_eq!(reports.lock().unwrap().last(), Some(&2));
+        assert!(!with_suffix(&entry_fp, ".partial").exists());
+        assert!(!with_suffix(&entry_fp, ".offset").exists());
+    }
+
+    #[test]
+    fn test_put_object() {
+        let client_adapter = WorkerS3ClientAdapter::new(WorkerS3ClientMock::default());
+        // Small objects go up in one go...
+        let etag = block_on(client_adapter.put_object("s3://foo/bar", vec![1, 2, 3])).unwrap();
+        assert_eq!(etag, "\"5289df737df57326fcdd22597afb1fac\"");
+        // ...and bigger ones in parts, the last of which can be smaller than the rest.
+        let mut body = io::Cursor::new(vec![1, 2, 3, 4, 5]);
+        let etag = block_on(client_adapter.put_from("s3://foo/bar", &mut body, 5, 2)).unwrap();
+        assert_eq!(etag, "\"mock-etag-3\"");
+        // A body shorter than it says it is doesn't get uploaded.
+        let mut body = io::Cursor::new(vec![1, 2, 3]);
+        assert!(block_on(client_adapter.put_from("s3://foo/bar", &mut body, 5, 2)).is_err());
+
+        // Files go up the same way.
+        let fp = get_cache_dir().join("put-object-test");
+        fs::create_dir_all(get_cache_dir()).unwrap();
+        fs::write(&fp, &[1, 2, 3]).unwrap();
+        let etag = block_on(client_adapter.put_file("s3://foo/bar", &fp)).unwrap();
+        assert_eq!(etag, "\"5289df737df57326fcdd22597afb1fac\"");
+        fs::remove_file(&fp).unwrap();
+
+        // There's nowhere to upload a pattern or a pre-signed URL to.
+        assert!(block_on(client_adapter.put_object("s3://foo/*.csv", vec![1])).is_err());
+        let url = "https://foo.s3.amazonaws.com/bar.csv?X-Amz-Signature=abc";
+        assert!(block_on(client_adapter.put_object(url, vec![1])).is_err());
+    }
+
+    #[test]
+    fn test_cache_entry_name() {
+        let entry = cache_entry_name("foo", "bar.csv", "\"a\"");
+        assert_eq!(entry, cache_entry_name("foo", "bar.csv", "\"a\""));
+        // A new version of the same object gets a new entry...
+        assert_ne!(entry, cache_entry_name("foo", "bar.csv", "\"b\""));
+        // ...and fields can't run into each other.
+        assert_ne!(cache_entry_name("foo", "bar", ""), cache_entry_name("foob", "ar", ""));
+    }
+
+    #[test]
+    fn test_verify_etag() {
+        let head = |etag: &str, encryption: Option<&str>| ObjectHead {
+            etag: Some(etag.to_owned()), size: Some(3), encryption: encryption.map(String::from)
+        };
+        let md5 = "\"5289df737df57326fcdd22597afb1fac\"";
+        assert!(verify_etag("s3://foo/bar", &[1, 2, 3], &head(md5, None)).is_ok());
+        assert!(verify_etag("s3://foo/bar", &[1, 2, 4], &head(md5, None)).is_err());
+        // Multipart and KMS-encrypted objects' ETags aren't MD5s, so they can't be checked.
+        let multipart = "\"5289df737df57326fcdd22597afb1fac-2\"";
+        assert!(verify_etag("s3://foo/bar", &[1, 2, 4], &head(multipart, None)).is_ok());
+        assert!(verify_etag("s3://foo/bar", &[1, 2, 4], &head(md5, Some("aws:kms"))).is_ok());
     }
 
     #[test]
@@ -391,11 +1841,142 @@ mod tests {
         let ops = RepeatedField::<Op>::from_vec(vec![op1, op2]);
         let workload = craft_workload_message(Some(ops));
 
-        let client_mock = WorkerS3ClientMock {};
-        let client_adapter = WorkerS3ClientAdapter { client: client_mock };
+        let client_mock = WorkerS3ClientMock::default();
+        let client_adapter = WorkerS3ClientAdapter::new(client_mock);
 
         let result = block_on(localize_files(&workload, &client_adapter));
 
         assert!(result.is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_s3_options() {
+        let worker = S3Options::default();
+        let mut file = craft_file_message(None, None);
+        assert_eq!(worker.for_file(&file), worker);
+        assert_eq!(worker.request_payer(), None);
+        assert_eq!(worker.sse_customer().unwrap(), (None, None, None));
+
+        // A file's own options win out over the worker's.
+        let key = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        file.set_requester_pays(true);
+        file.set_sse_customer_key(key.to_owned());
+        let options = worker.for_file(&file);
+        assert_eq!(options.request_payer(), Some("requester".to_owned()));
+        let (algorithm, sent_key, key_md5) = options.sse_customer().unwrap();
+        assert_eq!(algorithm, Some("AES256".to_owned()));
+        assert_eq!(sent_key, Some(key.to_owned()));
+        assert_eq!(key_md5, Some("cLyPS3KoaSFGi/joRB3OUQ==".to_owned()));
+
+        // Keys have to be 256 bits, in base64.
+        let options = |key: &str| S3Options {
+            requester_pays: false, sse_customer_key: Some(key.to_owned())
+        };
+        assert!(options(key).validate().is_ok());
+        assert!(options("AAAAAAAAAAAAAAAAAAAAAA==").validate().is_err());
+        assert!(options("not base64!").validate().is_err());
+    }
+
+    #[test]
+    fn test_check_disk_space() {
+        assert!(check_disk_space(0, &DiskLimits::default()).is_ok());
+
+        let limits = DiskLimits { quota: Some(0), reserve: 0 };
+        let err = check_disk_space(1, &limits).unwrap_err();
+        assert!(matches!(err.downcast_ref::<WorkerError>(), Some(WorkerError::ResourceError(_))));
+
+        let limits = DiskLimits { quota: None, reserve: u64::MAX };
+        let err = check_disk_space(0, &limits).unwrap_err();
+        assert!(matches!(err.downcast_ref::<WorkerError>(), Some(WorkerError::ResourceError(_))));
+    }
+
+    #[test]
+    fn test_get_etag() {
+        let client_adapter = WorkerS3ClientAdapter::new(WorkerS3ClientMock::default());
+        let etag = block_on(client_adapter.get_etag("s3://foo/bar"));
+        assert_eq!(etag.unwrap(), "\"mock-etag\"");
+
+        assert!(block_on(client_adapter.get_etag("foo/bar")).is_err());
+    }
+
+    #[test]
+    fn test_head() {
+        let client_adapter = WorkerS3ClientAdapter::new(WorkerS3ClientMock::default());
+        let head = block_on(client_adapter.head("s3://foo/bar")).unwrap();
+        assert_eq!(head.size, Some(3));
+    }
+
+    #[test]
+    fn test_list() {
+        let client_adapter = WorkerS3ClientAdapter::new(WorkerS3ClientMock::default());
+        let paths = block_on(client_adapter.list("s3://foo/bar/")).unwrap();
+        assert_eq!(paths, vec![
+            "s3://foo/bar/a.csv", "s3://foo/bar/b.csv", "s3://foo/bar/c.csv", "s3://foo/bar/d.csv"
+        ]);
+        assert!(block_on(client_adapter.list("foo/bar/")).is_err());
+    }
+
+    #[test]
+    fn test_mock_objects() {
+        let client_adapter = WorkerS3ClientAdapter::new(WorkerS3ClientMock::with_objects(vec![
+            ("s3://foo/bar/a.csv", "a_int\n1\n"), ("s3://foo/baz.csv", "b_int\n2\n"),
+        ]));
+        let head = block_on(client_adapter.head("s3://foo/bar/a.csv")).unwrap();
+        assert_eq!(head.size, Some(8));
+        assert_eq!(head.etag.unwrap(), format!("\"{:x}\"", Md5::digest(b"a_int\n1\n")));
+        let prefix = block_on(client_adapter.get_object_prefix("s3://foo/baz.csv", 5)).unwrap();
+        assert_eq!(prefix, b"b_int");
+        let paths = block_on(client_adapter.list("s3://foo/bar/")).unwrap();
+        assert_eq!(paths, vec!["s3://foo/bar/a.csv"]);
+        // Other objects don't exist.
+        assert!(block_on(client_adapter.head("s3://foo/missing.csv")).is_err());
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(is_pattern("s3://foo/bar/"));
+        assert!(is_pattern("s3://foo/bar/*.csv"));
+        assert!(!is_pattern("s3://foo/bar.csv"));
+        assert!(matches_pattern("s3://foo/*.csv", "s3://foo/bar.csv"));
+        assert!(!matches_pattern("s3://foo/*.csv", "s3://foo/bar/baz.csv"));
+        assert!(matches_pattern("s3://foo/**.csv", "s3://foo/bar/baz.csv"));
+        assert!(matches_pattern("s3://foo/part-?.csv", "s3://foo/part-1.csv"));
+        assert!(!matches_pattern("s3://foo/part-?.csv", "s3://foo/part-10.csv"));
+    }
+
+    #[test]
+    fn test_hive_partition() {
+        assert_eq!(
+            hive_partition("s3://foo/events/date=2021-03-01/hour=10%3A00/part-0.csv"),
+            vec![
+                ("date".to_owned(), Some("2021-03-01".to_owned())),
+                ("hour".to_owned(), Some("10:00".to_owned())),
+            ]
+        );
+        assert_eq!(
+            hive_partition("s3://foo/region=__HIVE_DEFAULT_PARTITION__/a=b.csv"),
+            vec![("region".to_owned(), None)]
+        );
+        assert!(hive_partition("s3://foo/bar/part-0.csv").is_empty());
+        assert!(hive_partition("s3://foo/no key=1/part-0.csv").is_empty());
+    }
+
+    #[test]
+    fn test_expand() {
+        let client_adapter = WorkerS3ClientAdapter::new(WorkerS3ClientMock::default());
+        let paths = block_on(client_adapter.expand("s3://foo/bar.csv")).unwrap();
+        assert_eq!(paths, vec!["s3://foo/bar.csv"]);
+        // A prefix stands for everything under it...
+        let paths = block_on(client_adapter.expand("s3://foo/bar/")).unwrap();
+        assert_eq!(paths.len(), 4);
+        // ...and a glob for everything under it that matches.
+        let paths = block_on(client_adapter.expand("s3://foo/**a.csv")).unwrap();
+        assert_eq!(paths, vec!["s3://foo/a.csv"]);
+        assert!(block_on(client_adapter.expand("s3://foo/*/a.csv")).is_err());
+
+        // The objects are looked up as one.
+        let head = block_on(client_adapter.head("s3://foo/bar/")).unwrap();
+        assert_eq!(head.size, Some(12));
+        assert_ne!(head.etag, block_on(client_adapter.head("s3://foo/**a.csv")).unwrap().etag);
+    }
+}
@@ -2,7 +2,16 @@
 
 use crate::workload::{File, Op, Workload};
 use protobuf::{Message, RepeatedField};
+use std::env;
 use std::option::Option;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::auth::sign_nonce;
+use crate::config::WorkerConfig;
+use crate::protocol::{craft_frame, AUTH, FLAG_ZSTD, WORK};
+use crate::transport::{Address, Stream};
+use crate::Worker;
 
 pub fn craft_file_message(id: Option<i32>, path: Option<String>) -> File {
     let mut file = File::new();
@@ -42,20 +51,48 @@ pub fn craft_workload_message(ops: Option<RepeatedField<Op>>) -> Workload {
 
 pub fn craft_workload_buffer(workload: Option<Workload>) -> Vec<u8> {
     let workload = workload.unwrap_or(craft_workload_message(None));
+    let workload_bytes = workload.write_to_bytes().unwrap();
+    craft_frame(WORK, 1, 0, &workload_bytes).unwrap()
+}
+
+pub fn craft_compressed_workload_buffer(workload: Option<Workload>) -> Vec<u8> {
+    let workload = workload.unwrap_or(craft_workload_message(None));
+    let workload_bytes = workload.write_to_bytes().unwrap();
+    craft_frame(WORK, 1, FLAG_ZSTD, &workload_bytes).unwrap()
+}
+
+pub fn craft_auth_buffer(secret: &str, nonce: &[u8]) -> Vec<u8> {
+    craft_frame(AUTH, 0, 0, &sign_nonce(secret, nonce)).unwrap()
+}
+
+/// Starts a worker with the given config, and opens a connection to it that doesn't go over the
+/// network (see `Worker::connect_in_process`). The worker listens on a port the OS picks, so
+/// tests using this can run alongside each other without fighting over ports.
+pub async fn spawn_in_process_worker(config: WorkerConfig) -> (Arc<Worker>, Stream) {
+    let worker = Arc::new(Worker::new(Address::Tcp(0), config).await.unwrap());
+    tokio::spawn(Arc::clone(&worker).listen());
+    let stream = worker.connect_in_process().await.unwrap();
+    (worker, stream)
+}
 
-    let operation_byte: u8 = 1;
-    let workload_bytesize = workload.compute_size() as usize;
-    let size_bytes: [u8; 2] = [
-        (workload_bytesize / 256) as u8, (workload_bytesize % 256) as u8
-    ];
-    let mut workload_bytes = workload.write_to_bytes().unwrap();
-
-    let mut buffer: Vec<u8> = Vec::with_capacity(workload_bytes.len() + 3);
-    buffer.push(operation_byte);
-    buffer.push(size_bytes[0]);
-    buffer.push(size_bytes[1]);
-    buffer.append(&mut workload_bytes);
-    buffer
+/// Returns the path of the test artifact (see `tests/artifacts`) with the given name. Paths are
+/// resolved against the crate's own directory, so they hold up wherever the crate is checked out.
+pub fn artifact_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("artifacts").join(name)
+}
+
+/// Writes a CSV file with the given header and rows to the temporary directory, under the given
+/// name, and returns its path. Column names carry their type as a suffix (e.g. `a_int`), just
+/// like they do in the files the worker loads. Fields are quoted as needed.
+pub fn craft_csv_file(name: &str, columns: &[&str], rows: &[&[&str]]) -> PathBuf {
+    let path = env::temp_dir().join(name);
+    let mut writer = csv::Writer::from_path(&path).unwrap();
+    writer.write_record(columns).unwrap();
+    for row in rows {
+        writer.write_record(*row).unwrap();
+    }
+    writer.flush().unwrap();
+    path
 }
 
 #[cfg(test)]
@@ -69,7 +106,7 @@ mod tests {
         assert_eq!(buffer[0], 1);
         assert_eq!(buffer[1], 0);
         assert_eq!(buffer[2], 43);
-        assert_eq!(buffer.len(), 46);
+        assert_eq!(buffer.len(), 55);
     }
 
     #[test]
@@ -92,4 +129,29 @@ mod tests {
         assert_eq!(buffer[1], 1);
         assert_eq!(buffer[2], 35);
     }
+
+    #[test]
+    /// Asserts that compressing a workload buffer with lots of redundancy shrinks it.
+    fn test_compressed_workload_buffer() {
+        let op = craft_op_message(None, Some("SELECT 1 UNION ALL ".repeat(100) + "SELECT 1"), None);
+        let workload = craft_workload_message(Some(RepeatedField::from_vec(vec![op])));
+        let buffer = craft_workload_buffer(Some(workload.clone()));
+        let compressed_buffer = craft_compressed_workload_buffer(Some(workload));
+        assert_eq!(compressed_buffer[8], FLAG_ZSTD);
+        assert!(compressed_buffer.len() < buffer.len());
+    }
+
+    #[test]
+    fn test_artifact_path() {
+        assert!(artifact_path("simple-csv.csv").exists());
+    }
+
+    #[test]
+    /// Asserts that generated CSV files come out as written, quoting and all.
+    fn test_craft_csv_file() {
+        let path = craft_csv_file(
+            "mini-cluster-test-craft-csv.csv", &["a_int", "b_text"], &[&["1", "x"], &["2", "y, z"]]
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a_int,b_text\n1,x\n2,\"y, z\"\n");
+    }
 }
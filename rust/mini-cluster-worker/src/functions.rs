@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+use libsqlite3_sys as ffi;
+use regex::Regex;
+use sqlx::SqliteConnection;
+
+use crate::err::{Result, WorkerError, ErrKind};
+
+// SQLite lets programs add SQL functions of their own, implemented in the host language. `sqlx`
+// doesn't wrap this API, but it does give us the raw `sqlite3*` handle underneath a connection,
+// so we go straight to the C API (via `libsqlite3-sys`, the same bindings `sqlx` itself uses).
+//
+// Functions are registered per connection, not per database, so every connection the worker
+// opens has to have them registered on it (see `Database`).
+
+/// A SQL value, as passed into or returned out of a `ScalarFunction`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl SqlValue {
+    /// Returns the value as a number, converting text the way SQLite would (e.g. `'1.5'` is 1.5,
+    /// `'abc'` is 0). NULL is `None`.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            SqlValue::Null => None,
+            SqlValue::Integer(v) => Some(*v as f64),
+            SqlValue::Real(v) => Some(*v),
+            SqlValue::Text(v) => Some(v.trim().parse().unwrap_or(0.0)),
+            SqlValue::Blob(_) => Some(0.0),
+        }
+    }
+}
+
+/// The body of a scalar function. Errors are reported back to SQLite, and fail the statement.
+pub type ScalarFn =
+    Arc<dyn Fn(&[SqlValue]) -> std::result::Result<SqlValue, String> + Send + Sync>;
+
+/// A scalar (row-by-row) SQL function implemented in Rust.
+#[derive(Clone)]
+pub struct ScalarFunction {
+    pub name: String,
+    /// The number of arguments the function takes, or -1 for any number.
+    pub n_args: i32,
+    pub func: ScalarFn,
+}
+
+impl ScalarFunction {
+    pub fn new<F>(name: &str, n_args: i32, func: F) -> ScalarFunction
+    where F: Fn(&[SqlValue]) -> std::result::Result<SqlValue, String> + Send + Sync + 'static {
+        ScalarFunction { name: name.to_owned(), n_args, func: Arc::new(func) }
+    }
+}
+
+impl std::fmt::Debug for ScalarFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<ScalarFunction {}/{}>", self.name, self.n_args)
+    }
+}
+
+/// Reads an argument passed to a function by SQLite.
+unsafe fn read_value(value: *mut ffi::sqlite3_value) -> SqlValue {
+    match ffi::sqlite3_value_type(value) {
+        ffi::SQLITE_INTEGER => SqlValue::Integer(ffi::sqlite3_value_int64(value)),
+        ffi::SQLITE_FLOAT => SqlValue::Real(ffi::sqlite3_value_double(value)),
+        ffi::SQLITE_TEXT => {
+            // `sqlite3_value_text` has to be called before `sqlite3_value_bytes`, see
+            // https://www.sqlite.org/c3ref/value_blob.html.
+            let text = ffi::sqlite3_value_text(value);
+            let len = ffi::sqlite3_value_bytes(value) as usize;
+            if text.is_null() {
+                SqlValue::Text(String::new())
+            } else {
+                let bytes = std::slice::from_raw_parts(text, len);
+                SqlValue::Text(String::from_utf8_lossy(bytes).into_owned())
+            }
+        },
+        ffi::SQLITE_BLOB => {
+            let blob = ffi::sqlite3_value_blob(value) as *const u8;
+            let len = ffi::sqlite3_value_bytes(value) as usize;
+            if blob.is_null() {
+                SqlValue::Blob(vec![])
+            } else {
+                SqlValue::Blob(std::slice::from_raw_parts(blob, len).to_vec())
+            }
+        },
+        _ => SqlValue::Null,
+    }
+}
+
+/// Hands a function's return value back to SQLite.
+unsafe fn write_result(
+    ctx: *mut ffi::sqlite3_context, result: std::result::Result<SqlValue, String>
+) {
+    match result {
+        Ok(SqlValue::Null) => ffi::sqlite3_result_null(ctx),
+        Ok(SqlValue::Integer(v)) => ffi::sqlite3_result_int64(ctx, v),
+        // SQLite has no NaN; its own math functions return NULL instead (e.g. for `sqrt(-1)`).
+        Ok(SqlValue::Real(v)) if v.is_nan() => ffi::sqlite3_result_null(ctx),
+        Ok(SqlValue::Real(v)) => ffi::sqlite3_result_double(ctx, v),
+        Ok(SqlValue::Text(v)) => ffi::sqlite3_result_text(
+            ctx, v.as_ptr() as *const c_char, v.len() as c_int, ffi::SQLITE_TRANSIENT()
+        ),
+        Ok(SqlValue::Blob(v)) => ffi::sqlite3_result_blob(
+            ctx, v.as_ptr() as *const c_void, v.len() as c_int, ffi::SQLITE_TRANSIENT()
+        ),
+        Err(message) => ffi::sqlite3_result_error(
+            ctx, message.as_ptr() as *const c_char, message.len() as c_int
+        ),
+    }
+}
+
+/// The C callback SQLite calls for every invocation of a function. The function itself rides
+/// along as the "user data" it was registered with.
+unsafe extern "C" fn call_scalar_function(
+    ctx: *mut ffi::sqlite3_context, argc: c_int, argv: *mut *mut ffi::sqlite3_value
+) {
+    let func = &*(ffi::sqlite3_user_data(ctx) as *const ScalarFn);
+    let args = (0..argc as usize).map(|i| read_value(*argv.add(i))).collect::<Vec<_>>();
+    // Unwinding across the FFI boundary is undefined behavior, so a panic has to be caught here.
+    let result = catch_unwind(AssertUnwindSafe(|| (**func)(&args)))
+        .unwrap_or_else(|_| Err("Function panicked.".to_owned()));
+    write_result(ctx, result);
+}
+
+/// Called by SQLite when a function is unregistered, e.g. because its connection was closed.
+unsafe extern "C" fn destroy_scalar_function(func: *mut c_void) {
+    drop(Box::from_raw(func as *mut ScalarFn));
+}
+
+/// Registers functions on a connection. A function with the same name and number of arguments as
+/// an existing one (including SQLite's own) replaces it.
+pub fn register_functions(
+    conn: &mut SqliteConnection, functions: &[ScalarFunction]
+) -> Result<()> {
+    let db = conn.as_raw_handle();
+    for function in functions {
+        let name = CString::new(function.name.as_str())?;
+        let user_data = Box::into_raw(Box::new(Arc::clone(&function.func)));
+        // SQLite takes ownership of the user data, and frees it through `xDestroy`, even if the
+        // registration fails.
+        let rc = unsafe {
+            ffi::sqlite3_create_function_v2(
+                db,
+                name.as_ptr(),
+                function.n_args,
+                ffi::SQLITE_UTF8 | ffi::SQLITE_DETERMINISTIC,
+                user_data as *mut c_void,
+                Some(call_scalar_function),
+                None,
+                None,
+                Some(destroy_scalar_function),
+            )
+        };
+        if rc != ffi::SQLITE_OK {
+            let message = unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(db)) };
+            Err(WorkerError::new(
+                ErrKind::DatabaseError,
+                &format!(
+                    "Could not register function {}: {}",
+                    function.name, message.to_string_lossy()
+                )
+            ))?
+        }
+    }
+    Ok(())
+}
+
+/// Wraps a numeric function of one argument. NULL in is NULL out.
+fn math1(name: &str, f: fn(f64) -> f64) -> ScalarFunction {
+    ScalarFunction::new(name, 1, move |args| Ok(match args[0].as_f64() {
+        Some(x) => SqlValue::Real(f(x)),
+        None => SqlValue::Null,
+    }))
+}
+
+/// Wraps a numeric function of two arguments. NULL in is NULL out.
+fn math2(name: &str, f: fn(f64, f64) -> f64) -> ScalarFunction {
+    ScalarFunction::new(name, 2, move |args| Ok(match (args[0].as_f64(), args[1].as_f64()) {
+        (Some(x), Some(y)) => SqlValue::Real(f(x, y)),
+        _ => SqlValue::Null,
+    }))
+}
+
+/// SQLite's math functions (https://www.sqlite.org/lang_mathfunc.html). SQLite only has these
+/// if it was compiled with `SQLITE_ENABLE_MATH_FUNCTIONS`, and only since 3.35, which is newer
+/// than the one bundled with our version of `sqlx`. So we bring our own, with the same names and
+/// semantics.
+pub fn math_functions() -> Vec<ScalarFunction> {
+    vec![
+        math1("acos", f64::acos),
+        math1("asin", f64::asin),
+        math1("atan", f64::atan),
+        math2("atan2", f64::atan2),
+        math1("ceil", f64::ceil),
+        math1("ceiling", f64::ceil),
+        math1("cos", f64::cos),
+        math1("degrees", f64::to_degrees),
+        math1("exp", f64::exp),
+        math1("floor", f64::floor),
+        math1("ln", f64::ln),
+        // With one argument `log` is base 10, with two the base comes first.
+        math1("log", f64::log10),
+        math2("log", |base, x| x.log(base)),
+        math1("log10", f64::log10),
+        math1("log2", f64::log2),
+        math2("mod", |x, y| x % y),
+        ScalarFunction::new("pi", 0, |_| Ok(SqlValue::Real(std::f64::consts::PI))),
+        math2("pow", f64::powf),
+        math2("power", f64::powf),
+        math1("radians", f64::to_radians),
+        math1("sin", f64::sin),
+        math1("sqrt", f64::sqrt),
+        math1("tan", f64::tan),
+        math1("trunc", f64::trunc),
+    ]
+}
+
+/// How many compiled patterns `regex_match` keeps around. A statement almost always uses the same
+/// pattern for every row, so this only needs to be big enough to cover a handful of statements.
+const REGEX_CACHE_SIZE: usize = 64;
+
+/// `regex_match(pattern, text)`: 1 if `text` matches the regular expression `pattern` (anywhere,
+/// unless the pattern is anchored), 0 if it doesn't. An invalid pattern is an error.
+fn regex_match() -> ScalarFunction {
+    // Compiling a regex is far more expensive than running it, so compiled patterns are cached.
+    let cache: Mutex<HashMap<String, Regex>> = Mutex::new(HashMap::new());
+    ScalarFunction::new("regex_match", 2, move |args| {
+        let (pattern, text) = match (&args[0], &args[1]) {
+            (SqlValue::Null, _) | (_, SqlValue::Null) => return Ok(SqlValue::Null),
+            (SqlValue::Text(pattern), SqlValue::Text(text)) => (pattern, text),
+            _ => return Err("regex_match() takes two text arguments.".to_owned()),
+        };
+        let mut cache = cache.lock().unwrap();
+        if !cache.contains_key(pattern) {
+            if cache.len() >= REGEX_CACHE_SIZE {
+                cache.clear();
+            }
+            let regex = Regex::new(pattern).map_err(|err| err.to_string())?;
+            cache.insert(pattern.clone(), regex);
+        }
+        Ok(SqlValue::Integer(cache[pattern].is_match(text) as i64))
+    })
+}
+
+/// `url_host(url)`: the host part of a URL, e.g. `example.com` for
+/// `https://user@example.com:8080/path?q=1`, or NULL if the URL doesn't have one.
+fn url_host() -> ScalarFunction {
+    ScalarFunction::new("url_host", 1, |args| {
+        let url = match &args[0] {
+            SqlValue::Text(url) => url,
+            _ => return Ok(SqlValue::Null),
+        };
+        let rest = match url.find("://") {
+            Some(idx) => &url[(idx + 3)..],
+            None => return Ok(SqlValue::Null),
+        };
+        let authority = rest.split(|c: char| c == '/' || c == '?' || c == '#').next().unwrap_or("");
+        let host_port = authority.rsplit('@').next().unwrap_or("");
+        // IPv6 addresses come wrapped in brackets, and have colons of their own.
+        let host = if host_port.starts_with('[') {
+            host_port.split(']').next().map(|host| &host[1..]).unwrap_or("")
+        } else {
+            host_port.split(':').next().unwrap_or("")
+        };
+        if host.is_empty() {
+            Ok(SqlValue::Null)
+        } else {
+            Ok(SqlValue::Text(host.to_lowercase()))
+        }
+    })
+}
+
+/// Looks up one of the custom functions that ship with the worker by name, e.g. for
+/// `WORKER_SQL_FUNCTIONS`. Currently `regex_match` and `url_host`.
+pub fn builtin_function(name: &str) -> Result<ScalarFunction> {
+    match name.trim().to_lowercase().as_str() {
+        "regex_match" => Ok(regex_match()),
+        "url_host" => Ok(url_host()),
+        _ => Err(WorkerError::new(
+            ErrKind::DatabaseError, &format!("Unknown SQL function {:?}.", name)
+        ))?,
+    }
+}
+
+/// Probes a connection for the optional parts of SQLite's SQL surface, returning the names of
+/// those it has: `json1` (`json_extract` and friends) and `math` (`sqrt` and friends).
+pub async fn capabilities(conn: &mut SqliteConnection) -> Vec<String> {
+    let probes = [
+        ("json1", "SELECT json_extract('{\"a\": 1}', '$.a')"),
+        ("math", "SELECT sqrt(4)"),
+    ];
+    let mut capabilities = vec![];
+    for &(name, probe) in probes.iter() {
+        if sqlx::query(probe).fetch_one(&mut *conn).await.is_ok() {
+            capabilities.push(name.to_owned());
+        }
+    }
+    capabilities
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use sqlx::Connection;
+
+    use super::*;
+
+    #[test]
+    fn test_math_functions() {
+        let mut conn = block_on(SqliteConnection::connect("sqlite::memory:")).unwrap();
+        register_functions(&mut conn, &math_functions()).unwrap();
+        let (root, power, log, null_root, bad_root): (f64, f64, f64, Option<f64>, Option<f64>) =
+            block_on(
+                sqlx::query_as("SELECT sqrt(16), pow(2, 10), log(2, 8), sqrt(NULL), sqrt(-1)")
+                    .fetch_one(&mut conn)
+            ).unwrap();
+        assert_eq!(root, 4.0);
+        assert_eq!(power, 1024.0);
+        assert!((log - 3.0).abs() < 1e-9);
+        assert_eq!(null_root, None);
+        assert_eq!(bad_root, None);
+
+        let capabilities = block_on(capabilities(&mut conn));
+        assert_eq!(capabilities, vec!["json1".to_owned(), "math".to_owned()]);
+    }
+
+    #[test]
+    fn test_builtin_functions() {
+        let mut conn = block_on(SqliteConnection::connect("sqlite::memory:")).unwrap();
+        let functions = vec![
+            builtin_function("regex_match").unwrap(), builtin_function("url_host").unwrap()
+        ];
+        register_functions(&mut conn, &functions).unwrap();
+        assert!(builtin_function("no_such_function").is_err());
+
+        let (matched, unmatched, null): (i64, i64, Option<i64>) = block_on(
+            sqlx::query_as(
+                "SELECT regex_match('^a+b$', 'aaab'), regex_match('^a+b$', 'ba'), \
+                regex_match('a', NULL)"
+            ).fetch_one(&mut conn)
+        ).unwrap();
+        assert_eq!((matched, unmatched, null), (1, 0, None));
+        let invalid = block_on(sqlx::query("SELECT regex_match('(', 'a')").fetch_one(&mut conn));
+        assert!(invalid.is_err());
+
+        let hosts: (Option<String>, Option<String>, Option<String>, Option<String>) = block_on(
+            sqlx::query_as(
+                "SELECT url_host('https://user@Example.com:8080/path?q=1'), \
+                url_host('http://[::1]:80/'), url_host('s3://bucket'), url_host('not a url')"
+            ).fetch_one(&mut conn)
+        ).unwrap();
+        assert_eq!(hosts, (
+            Some("example.com".to_owned()),
+            Some("::1".to_owned()),
+            Some("bucket".to_owned()),
+            None
+        ));
+    }
+
+    #[test]
+    fn test_function_errors() {
+        let mut conn = block_on(SqliteConnection::connect("sqlite::memory:")).unwrap();
+        let fail = ScalarFunction::new("fail", 1, |_| Err("Nope.".to_owned()));
+        register_functions(&mut conn, &[fail]).unwrap();
+        let result = block_on(sqlx::query("SELECT fail(1)").fetch_one(&mut conn));
+        assert!(result.err().unwrap().to_string().contains("Nope."));
+    }
+}
@@ -0,0 +1,181 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sqlx::SqliteConnection;
+
+use crate::db::{create_registry_if_missing, Database, Table, TABLE_REGISTRY};
+use crate::err::Result;
+use crate::stats::{create_stats_if_missing, COLUMN_STATS, TABLE_STATS};
+
+/// The table the worker records when each of its tables was last used in.
+pub const TABLE_LEASES: &str = "_mini_cluster_table_leases";
+
+/// How often the worker collects garbage, by default.
+pub const GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Garbage collection. The worker's database outlives any one job, which is what lets a job reuse
+// the tables an earlier one loaded (see `TableFingerprint`). But nothing ever got rid of them
+// again: every `dataset_N` table ever loaded, and every table an op ever made, stayed put until
+// the disk filled up.
+//
+// So every table now has a lease: when a job is done (and its results have gone out), its
+// `dataset_N` tables, along with any table that doesn't have a lease yet (e.g. one its ops
+// made), are marked as used just now. Every so often (`WorkerConfig.gc_interval`), the worker
+// collects garbage:
+//
+// * Tables nobody has used for longer than `WorkerConfig.table_retention` are dropped. A table
+//   without a lease (e.g. one from before leases existed) counts as used when it is first seen.
+//   If no retention is set, tables are kept forever, as they used to be.
+// * Orphans are pruned either way: `dataset_N` tables without a fingerprint, which are left over
+//   from loads that never finished, and would be loaded from scratch anyway, and bookkeeping
+//   (fingerprints and leases) for tables that are no longer there.
+// * The database is `VACUUM`ed, which hands the space the dropped tables took up back to the OS.
+//
+// Dropping a table out from under a running job would fail the job, and `VACUUM` can't run
+// alongside a write anyway, so garbage is only ever collected whilst no job is using the
+// database. A pass that comes around whilst one is, is skipped.
+//
+// Ephemeral jobs have a database of their own, which goes away with them, so they have no part
+// in any of this.
+
+fn now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as i64)
+}
+
+async fn create_leases_if_missing(conn: &mut SqliteConnection) -> Result<()> {
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (\n\
+            name TEXT PRIMARY KEY,\n\
+            last_used INTEGER NOT NULL\n\
+        );",
+        TABLE_LEASES
+    )).execute(&mut *conn).await?;
+    Ok(())
+}
+
+/// Lists the tables jobs can see (and make), i.e. every table but the worker's own bookkeeping
+/// (`_mini_cluster_*`) and SQLite's (`sqlite_*`).
+pub async fn user_tables(conn: &mut SqliteConnection) -> Result<Vec<String>> {
+    let tables: Vec<(String,)> = sqlx::query_as(
+        "SELECT name FROM sqlite_master WHERE type = 'table'\n\
+            AND name NOT LIKE '\\_mini\\_cluster\\_%' ESCAPE '\\'\n\
+            AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'\n\
+        ORDER BY name"
+    ).fetch_all(&mut *conn).await?;
+    Ok(tables.into_iter().map(|(name,)| name).collect())
+}
+
+/// Marks the given tables as used just now, and gives every table that has no lease yet one
+/// starting now. Tables that don't exist are left out.
+pub async fn lease(conn: &mut SqliteConnection, tables: &[String]) -> Result<()> {
+    create_leases_if_missing(&mut *conn).await?;
+    let now = now_millis();
+    for table in user_tables(&mut *conn).await? {
+        let verb = if tables.contains(&table) { "INSERT OR REPLACE" } else { "INSERT OR IGNORE" };
+        sqlx::query(&format!("{} INTO {} VALUES (?, ?)", verb, TABLE_LEASES))
+            .bind(&table)
+            .bind(now)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Drops the tables in the worker's database that are orphaned, or that have gone unused for
+/// longer than `retention` (if set), and prunes the bookkeeping for tables that are gone. Returns
+/// the names of the tables dropped. This mustn't run whilst a job is using the database.
+pub async fn collect(
+    conn: &mut SqliteConnection, retention: Option<Duration>
+) -> Result<Vec<String>> {
+    create_leases_if_missing(&mut *conn).await?;
+    create_registry_if_missing(&mut *conn).await?;
+    create_stats_if_missing(&mut *conn).await?;
+    let mut dropped = vec![];
+    for table in user_tables(&mut *conn).await? {
+        let orphaned = table.starts_with("dataset_")
+            && Table::new(&table, "").fingerprint(&mut *conn).await?.is_none();
+        if orphaned {
+            Table::new(&table, "").drop_from(&mut *conn).await?;
+            dropped.push(table);
+        }
+    }
+    if let Some(retention) = retention {
+        lease(&mut *conn, &[]).await?;
+        let expired: Vec<(String,)> = sqlx::query_as(
+            &format!("SELECT name FROM {} WHERE last_used <= ? ORDER BY name", TABLE_LEASES)
+        ).bind(now_millis() - retention.as_millis() as i64).fetch_all(&mut *conn).await?;
+        for (table,) in expired {
+            Table::new(&table, "").drop_from(&mut *conn).await?;
+            dropped.push(table);
+        }
+    }
+
+    // `drop_from` takes care of the fingerprints (and statistics) of the tables it drops, but not
+    // of any that were dropped some other way (e.g. by an op).
+    let tables = user_tables(&mut *conn).await?;
+    for bookkeeping in [TABLE_LEASES, TABLE_REGISTRY, TABLE_STATS, COLUMN_STATS].iter() {
+        let names: Vec<(String,)> = sqlx::query_as(
+            &format!("SELECT DISTINCT name FROM {}", bookkeeping)
+        ).fetch_all(&mut *conn).await?;
+        for (name,) in names.into_iter().filter(|(name,)| !tables.contains(name)) {
+            sqlx::query(&format!("DELETE FROM {} WHERE name = ?", bookkeeping))
+                .bind(&name)
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+    Ok(dropped)
+}
+
+/// Collects garbage in the worker's database (see `collect`), and then `VACUUM`s it. Returns the
+/// names of the tables dropped.
+pub async fn collect_garbage(retention: Option<Duration>) -> Result<Vec<String>> {
+    let mut conn = Database::connect().await?;
+    let dropped = collect(&mut conn, retention).await?;
+    sqlx::query("VACUUM").execute(&mut conn).await?;
+    conn.close();
+    Ok(dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use crate::db::TableFingerprint;
+    use super::*;
+
+    #[test]
+    fn test_collect() {
+        block_on(async {
+            let database = Database::new_in_memory().await.unwrap();
+            let mut conn = database.connection().await.unwrap();
+            for table in ["dataset_1", "dataset_2", "foo"].iter() {
+                sqlx::query(&format!("CREATE TABLE {} (a INTEGER)", table))
+                    .execute(&mut *conn).await.unwrap();
+            }
+            let fingerprint = TableFingerprint {
+                path: "s3://foo/bar.csv".to_owned(), etag: "abc".to_owned(), size: 1
+            };
+            Table::new("dataset_1", "").register(&mut conn, &fingerprint).await.unwrap();
+            Table::new("dataset_3", "").register(&mut conn, &fingerprint).await.unwrap();
+            let tables = user_tables(&mut conn).await.unwrap();
+            assert_eq!(tables, vec!["dataset_1", "dataset_2", "foo"]);
+
+            // Without a retention, only the orphans go: `dataset_2`, which has no fingerprint,
+            // and the fingerprint of `dataset_3`, which isn't there.
+            assert_eq!(collect(&mut conn, None).await.unwrap(), vec!["dataset_2"]);
+            assert_eq!(user_tables(&mut conn).await.unwrap(), vec!["dataset_1", "foo"]);
+            assert!(Table::new("dataset_3", "").fingerprint(&mut conn).await.unwrap().is_none());
+
+            // Tables that were used just now are kept.
+            lease(&mut conn, &["dataset_1".to_owned()]).await.unwrap();
+            let retention = Some(Duration::from_secs(60));
+            assert_eq!(collect(&mut conn, retention).await.unwrap(), Vec::<String>::new());
+
+            // Tables that have been around for longer than the retention aren't.
+            let retention = Some(Duration::from_secs(0));
+            assert_eq!(collect(&mut conn, retention).await.unwrap(), vec!["dataset_1", "foo"]);
+            assert_eq!(user_tables(&mut conn).await.unwrap(), Vec::<String>::new());
+            assert!(Table::new("dataset_1", "").fingerprint(&mut conn).await.unwrap().is_none());
+        });
+    }
+}
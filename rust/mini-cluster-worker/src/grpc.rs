@@ -0,0 +1,215 @@
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+use tonic::transport::server::Connected;
+
+use crate::Worker;
+use crate::err::{Result, WorkerError};
+use crate::auth::secrets_match;
+use crate::queue::JobState;
+use crate::sandbox::InvalidOp;
+use crate::transport::Stream as WorkerStream;
+
+/// The gRPC service, generated by `tonic-build` out of `service.proto`.
+///
+/// Note that this generates its own (`prost`) versions of the workload and response messages,
+/// alongside the `protobuf` ones in `workload.rs` and `response.rs` that the rest of the worker
+/// uses. The two are wire-compatible, so we convert between them by way of their encoded bytes.
+pub mod proto {
+    tonic::include_proto!("minicluster");
+}
+
+use proto::worker_service_server::{WorkerService, WorkerServiceServer};
+
+/// Metadata key clients put the shared secret in, if the worker is configured with one.
+pub const SECRET_METADATA_KEY: &str = "x-mini-cluster-secret";
+
+fn to_prost<T: protobuf::Message, U: prost::Message + Default>(
+    message: &T
+) -> std::result::Result<U, Status> {
+    let bytes = message.write_to_bytes().map_err(|err| Status::internal(err.to_string()))?;
+    U::decode(&bytes[..]).map_err(|err| Status::internal(err.to_string()))
+}
+
+fn from_prost<U: prost::Message, T: protobuf::Message>(
+    message: &U
+) -> std::result::Result<T, Status> {
+    let mut bytes = Vec::with_capacity(message.encoded_len());
+    message.encode(&mut bytes).map_err(|err| Status::internal(err.to_string()))?;
+    T::parse_from_bytes(&bytes).map_err(|err| Status::invalid_argument(err.to_string()))
+}
+
+/// Maps a worker error onto the closest gRPC status code.
+fn to_status(err: Box<dyn std::error::Error>) -> Status {
+    match err.downcast_ref::<WorkerError>() {
+        Some(WorkerError::ValidationError(_)) | Some(WorkerError::ProtocolError(_)) =>
+            Status::invalid_argument(err.to_string()),
+        _ if err.is::<InvalidOp>() => Status::invalid_argument(err.to_string()),
+        _ => Status::internal(err.to_string()),
+    }
+}
+
+pub struct WorkerGrpcService {
+    worker: Arc<Worker>,
+}
+
+impl WorkerGrpcService {
+    pub fn new(worker: Arc<Worker>) -> WorkerGrpcService {
+        WorkerGrpcService { worker }
+    }
+}
+
+#[tonic::async_trait]
+impl WorkerService for WorkerGrpcService {
+    async fn submit_workload(
+        &self, request: Request<proto::Workload>
+    ) -> std::result::Result<Response<proto::Ack>, Status> {
+        if self.worker.is_draining() {
+            return Err(Status::unavailable("The worker is draining, and not taking new work."));
+        }
+        let workload: crate::workload::Workload = from_prost(request.get_ref())?;
+        // The error is converted straight away, before anything else is awaited: our errors are
+        // not `Send`, and tonic needs this future to be.
+        let ack = self.worker.submit(workload).await.map_err(to_status)?;
+        Ok(Response::new(to_prost(&ack)?))
+    }
+
+    async fn validate(
+        &self, request: Request<proto::Workload>
+    ) -> std::result::Result<Response<proto::ValidateResponse>, Status> {
+        let workload: crate::workload::Workload = from_prost(request.get_ref())?;
+        self.worker.validate(&workload).map_err(to_status)?;
+        Ok(Response::new(proto::ValidateResponse {}))
+    }
+
+    async fn ping(
+        &self, _request: Request<proto::PingRequest>
+    ) -> std::result::Result<Response<proto::WorkerStatus>, Status> {
+        Ok(Response::new(to_prost(&self.worker.status().await)?))
+    }
+
+    async fn cancel(
+        &self, request: Request<proto::CancelRequest>
+    ) -> std::result::Result<Response<proto::CancelResponse>, Status> {
+        let cancelled = self.worker.queue.cancel(request.get_ref().job_id);
+        Ok(Response::new(proto::CancelResponse { cancelled }))
+    }
+
+    type StreamResultsStream = Pin<Box<
+        dyn Stream<Item = std::result::Result<proto::ResultBatch, Status>> + Send + Sync + 'static
+    >>;
+
+    /// Streams the job's result set back as the job produces it. If the job fails, the stream
+    /// ends in an error status.
+    async fn stream_results(
+        &self, request: Request<proto::FetchResults>
+    ) -> std::result::Result<Response<Self::StreamResultsStream>, Status> {
+        let job_id = request.get_ref().job_id;
+        let mut results = self.worker.queue.take_results(job_id).ok_or_else(|| Status::not_found(
+            format!(
+                "No results for job {}: there is no such job, or they were already fetched.",
+                job_id
+            )
+        ))?;
+
+        // tonic wants a stream that is `Sync`, which an `async` block reading off of our channel
+        // is not. So we forward the batches through a (small, bounded) channel of tonic's own.
+        let (tx, rx) = mpsc::channel::<std::result::Result<proto::ResultBatch, Status>>(4);
+        let queue = Arc::clone(&self.worker.queue);
+        tokio::spawn(async move {
+            while let Some((batch, _held)) = results.recv().await {
+                if tx.send(to_prost(&batch)).await.is_err() {
+                    // The client went away.
+                    return;
+                }
+            }
+            // The result stream closes once the job is over, one way or another.
+            let status = match queue.wait(job_id).await {
+                Some(JobState::Failed(message)) => Status::aborted(message),
+                Some(JobState::Cancelled) => Status::cancelled(
+                    format!("Job {} was cancelled.", job_id)
+                ),
+                _ => return,
+            };
+            let _ = tx.send(Err(status)).await;
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
+
+// tonic wants to know who is on the other end of each connection. Unix domain sockets (and
+// in-memory pipes) have no such thing as a remote address.
+impl Connected for WorkerStream {
+    fn remote_addr(&self) -> Option<std::net::SocketAddr> {
+        match self {
+            WorkerStream::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            WorkerStream::Unix(_) => None,
+            WorkerStream::TlsClient(stream) => stream.get_ref().0.remote_addr(),
+            WorkerStream::TlsServer(stream) => stream.get_ref().0.remote_addr(),
+            WorkerStream::Duplex(_) => None,
+            #[cfg(feature = "chaos")]
+            WorkerStream::Chaos(stream) => stream.get_ref().remote_addr(),
+        }
+    }
+}
+
+/// Serves the `WorkerService` gRPC service on the worker's listener.
+pub async fn serve(worker: Arc<Worker>) -> Result<()> {
+    worker.spawn_executors();
+    worker.spawn_gc();
+
+    // If a shared secret is configured, every request has to carry it in its metadata.
+    let secret = worker.config.secret.clone();
+    let service = WorkerServiceServer::with_interceptor(
+        WorkerGrpcService::new(Arc::clone(&worker)),
+        move |request: Request<()>| {
+            let secret = match &secret {
+                Some(v) => v,
+                None => return Ok(request),
+            };
+            let provided = request.metadata().get(SECRET_METADATA_KEY)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("");
+            if secrets_match(secret, provided) {
+                Ok(request)
+            } else {
+                Err(Status::unauthenticated("Missing or incorrect secret."))
+            }
+        }
+    );
+
+    // We already bound a listener in `Worker::new`, so we feed tonic the connections accepted on
+    // it, rather than having it bind its own. Over TLS, connections are handed over once their
+    // handshakes are done, several of which can be under way at once, so that one slow client
+    // doesn't hold up the rest; those whose handshakes fail are dropped.
+    let accepted = futures::stream::unfold(Arc::clone(&worker), |worker| async move {
+        let connection = worker.listener.accept().await;
+        Some((connection, worker))
+    });
+    let handshaking = Arc::clone(&worker);
+    let incoming = accepted
+        .map(move |connection| {
+            let worker = Arc::clone(&handshaking);
+            async move {
+                match connection {
+                    Ok(stream) => worker.secure(stream).await.map(Ok),
+                    Err(err) => Some(Err(err)),
+                }
+            }
+        })
+        .buffer_unordered(worker.config.max_connections.max(1))
+        .filter_map(futures::future::ready);
+
+    Server::builder()
+        .timeout(worker.config.read_timeout)
+        .add_service(service)
+        .serve_with_incoming(Box::pin(incoming))
+        .await?;
+    Ok(())
+}
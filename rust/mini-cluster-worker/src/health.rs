@@ -0,0 +1,140 @@
+use std::convert::Infallible;
+use std::fs;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use rusoto_core::credential::{DefaultCredentialsProvider, ProvideAwsCredentials};
+
+use crate::Worker;
+use crate::db::Database;
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::file::get_cache_dir;
+use crate::transport::Stream;
+
+// Kubernetes (and most load balancers) probe whatever they route to over plain HTTP, and don't
+// speak our framing. So a worker can be asked to answer probes on an admin port of its own
+// (`WorkerConfig.admin_port`), alongside the port it serves clients on:
+//
+// * `GET /healthz` answers 200 for as long as the worker is up at all. Failing it gets the
+//   worker restarted, so it checks nothing that a restart wouldn't fix.
+// * `GET /readyz` answers 200 if the worker can take work right now, and 503 if it can't: if
+//   its listener can't be connected to, if it is draining, if its database can't be opened, if
+//   its cache directory can't be written to, or if there are no S3 credentials to be had.
+//   Failing it only gets the worker taken out of rotation until it passes again.
+//
+// Either way the body lists every check, one per line (e.g. `database: ok`), for humans. Probes
+// are unauthenticated, since they give nothing away beyond whether the worker is healthy.
+
+/// How long a single readiness check may take before it counts as failed. Resolving credentials
+/// can mean asking the instance metadata service, which hangs off of EC2.
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The outcome of one of the checks behind `/readyz`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    /// Why the check failed, or `None` if it passed.
+    pub failure: Option<String>,
+}
+
+impl Check {
+    async fn run(name: &'static str, check: impl Future<Output = Result<()>>) -> Check {
+        let failure = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(err.to_string()),
+            Err(_) => Some(format!("timed out after {:?}", CHECK_TIMEOUT)),
+        };
+        Check { name, failure }
+    }
+}
+
+async fn check_listening(worker: &Worker) -> Result<()> {
+    // The connection is closed straight away, which the worker takes in its stride.
+    Stream::connect(&worker.address).await?;
+    Ok(())
+}
+
+async fn check_accepting_work(worker: &Worker) -> Result<()> {
+    if worker.is_draining() {
+        Err(WorkerError::new(ErrKind::NetworkError, "The worker is draining."))?
+    }
+    Ok(())
+}
+
+async fn check_database() -> Result<()> {
+    let mut conn = Database::connect().await?;
+    sqlx::query("SELECT 1").execute(&mut conn).await?;
+    Ok(())
+}
+
+async fn check_cache_dir() -> Result<()> {
+    let probe = get_cache_dir().join(".readyz");
+    fs::create_dir_all(get_cache_dir())?;
+    fs::write(&probe, b"ok")?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
+
+async fn check_s3_credentials() -> Result<()> {
+    DefaultCredentialsProvider::new()?.credentials().await?;
+    Ok(())
+}
+
+/// Runs every readiness check against the worker.
+pub async fn readiness(worker: &Worker) -> Vec<Check> {
+    vec![
+        Check::run("listening", check_listening(worker)).await,
+        Check::run("accepting_work", check_accepting_work(worker)).await,
+        Check::run("database", check_database()).await,
+        Check::run("cache_dir", check_cache_dir()).await,
+        Check::run("s3_credentials", check_s3_credentials()).await,
+    ]
+}
+
+fn text_response(status: StatusCode, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response.headers_mut().insert(CONTENT_TYPE, "text/plain".parse().unwrap());
+    response
+}
+
+async fn handle(
+    worker: Arc<Worker>, request: Request<Body>
+) -> std::result::Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/healthz") => text_response(StatusCode::OK, "ok\n".to_owned()),
+        (&Method::GET, "/readyz") => {
+            let checks = readiness(&worker).await;
+            let status = match checks.iter().all(|check| check.failure.is_none()) {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            let body = checks.iter().map(|check| match &check.failure {
+                None => format!("{}: ok\n", check.name),
+                Some(failure) => format!("{}: failed ({})\n", check.name, failure),
+            }).collect();
+            text_response(status, body)
+        },
+        (method, path) => text_response(
+            StatusCode::NOT_FOUND, format!("No such endpoint: {} {}.\n", method, path)
+        ),
+    };
+    Ok(response)
+}
+
+/// Answers health and readiness probes for the worker on the given port, on every interface.
+pub async fn serve(worker: Arc<Worker>, port: u16) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let worker = Arc::clone(&worker);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| handle(Arc::clone(&worker), request)))
+        }
+    });
+    Server::try_bind(&SocketAddr::from(([0, 0, 0, 0], port)))?.serve(make_service).await?;
+    Ok(())
+}
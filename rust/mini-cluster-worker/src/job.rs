@@ -1,65 +1,722 @@
-use crate::file::{WorkerS3ClientAdapter, WorkerS3ClientTrait};
-use crate::workload::Workload;
-use crate::db::{Database, Table};
-use crate::err::Result;
-use crate::file::localize_files;
+use crate::file::{FaultyS3Client, WorkerS3ClientAdapter, WorkerS3ClientTrait};
+use crate::credentials::{create_s3_client, CredentialRoute, RoutedS3Client};
+use crate::workload::{File, Op, Workload};
+use crate::db::{
+    explain_query_plan, parse_columns, ColumnSpec, Database, DatabaseOptions, IngestProgress,
+    Table, TableFingerprint,
+};
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::file::{
+    check_disk_space, get_workload_files, hive_partition, is_pattern, localize_file_with_progress,
+    DiskLimits, S3Options,
+};
+use crate::sandbox::{split_statements, StatementClass, validate_workload};
+use crate::dag::{schedule, sinks};
 
-use sqlx::sqlite::SqliteRow;
+use crate::response::{
+    ExecutionPlan, FileMetrics, FilePlan, JobMetrics, JobProgress, OpMetrics, OpPlan, ResultBatch
+};
+use crate::result::{craft_batch, craft_columns, craft_row, ResultLimits};
+use crate::slowlog::SlowOpLog;
+use crate::log::LogContext;
+use crate::checkpoint::{self, Checkpoint};
+use crate::gc;
+use crate::stats;
+use crate::exchange::{exchange, EXCHANGE_COLUMNS};
+use crate::manifest::{is_manifest, read_manifest};
+use crate::memory::{MemoryBudget, MEMORY_WAIT};
+
+use std::fs;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sqlx::SqliteConnection;
+use std::time::{Duration, Instant};
+
+use futures::TryStreamExt;
+use protobuf::Message;
+
+/// How many of a job's tables `Job::build` loads at once, by default.
+pub const PARALLEL_LOADS: usize = 4;
+
+/// How much of a file `Job::plan` downloads to get at its header. A header any longer than this
+/// would be a very wide table indeed.
+pub const PLAN_HEADER_BYTES: u64 = 64 * 1024;
+
+/// Returns the name of the table a file is loaded into.
+pub(crate) fn table_name(file: &File) -> String {
+    "dataset_".to_owned() + &file.id.to_string()
+}
+
+/// Works out a throughput, in bytes per second. Anything that took no measurable time at all
+/// (e.g. because it didn't have to happen) gets zero.
+pub(crate) fn bytes_per_second(bytes: u64, micros: u64) -> u64 {
+    if micros == 0 {
+        return 0;
+    }
+    (bytes as u128 * 1_000_000 / micros as u128) as u64
+}
 
 pub struct Job {
     pub workload: Workload,
-    pub database: Database
+    pub database: Database,
+    /// Timings and row counts for every stage of the job, filled in by `build` and `run`, and
+    /// sent back to the client on the final result batch.
+    pub metrics: Mutex<JobMetrics>,
+    /// How far along the job is, filled in by `build` and `run` as they go (see
+    /// `build_with_progress` and `run_with_progress`).
+    pub progress: Mutex<JobProgress>,
+    /// How many tables `build` downloads and loads at once.
+    pub parallel_loads: usize,
+    /// How much of the disk `build` may take up with the job's files and tables.
+    pub disk_limits: DiskLimits,
+    /// The options the job's S3 requests are made with, unless its files say otherwise (see
+    /// `S3Options::for_file`).
+    pub s3_options: S3Options,
+    /// Which credentials the job reads which buckets with (see `credentials`).
+    pub s3_credentials: Vec<CredentialRoute>,
+    /// The worker's memory budget, which the job's result batches are counted against, and how
+    /// long the job waits for room under it (see `memory`).
+    pub memory: Arc<MemoryBudget>,
+    pub memory_wait: Duration,
+    /// Where `run` logs ops that are slow to run, if anywhere (see `slowlog`).
+    pub slow_ops: Option<Arc<SlowOpLog>>,
+    /// The connection the job was submitted over, and the job's own UUID, for its log lines
+    /// (see `log`).
+    pub log_context: LogContext,
+    /// The checkpoint the job picks up from, if it is being resumed (see `checkpoint`).
+    pub resumed_from: Option<Checkpoint>,
 }
 
 impl Job {
     pub async fn new(workload: Workload) -> Result<Job> {
-        let database = Database::new().await?;
-        Ok(Job { workload, database })
+        Job::with_options(workload, &DatabaseOptions::default()).await
+    }
+
+    /// Like `new`, but with the given SQLite settings.
+    pub async fn with_options(workload: Workload, options: &DatabaseOptions) -> Result<Job> {
+        // Ephemeral jobs get a private in-memory database, which goes away along with the job.
+        let database = if workload.get_ephemeral() {
+            Database::new_in_memory_with_options(options).await?
+        } else {
+            Database::with_options(options).await?
+        };
+        Ok(Job {
+            workload,
+            database,
+            metrics: Mutex::new(JobMetrics::new()),
+            progress: Mutex::new(JobProgress::new()),
+            parallel_loads: PARALLEL_LOADS,
+            disk_limits: DiskLimits::default(),
+            s3_options: S3Options::default(),
+            s3_credentials: vec![],
+            memory: Arc::new(MemoryBudget::new(None)),
+            memory_wait: MEMORY_WAIT,
+            slow_ops: None,
+            log_context: LogContext::default(),
+            resumed_from: None,
+        })
+    }
+
+    /// Checks that every op in the workload only runs statements of the `allowed` classes. This
+    /// is meant to be called before `build`, so that a rejected workload costs no downloads.
+    pub fn validate(&self, allowed: &[StatementClass]) -> Result<()> {
+        validate_workload(&self.workload, Some(allowed))
     }
 
     /// Performs the build portion of the job -- namely, downloading all of the files from S3 and
     /// loading them into the SQLite database.
+    ///
+    /// Tables which were loaded from the current version of their file (see `TableFingerprint`)
+    /// are left as they are, which saves both the download and the load. Setting `force_reload`
+    /// on the workload skips this check.
+    ///
+    /// Before anything is downloaded, the files that need to be are checked against
+    /// `disk_limits`, going by the sizes S3 declares for them. If they don't fit, the job fails
+    /// with a `ResourceError` straight away.
     pub async fn build<T: WorkerS3ClientTrait>(
         &self, client: WorkerS3ClientAdapter<T>
     ) -> Result<()> {
-        let (files, file_paths) = localize_files(&self.workload, &client).await?;
+        self.build_with_progress(client, |_| {}).await
+    }
+
+    /// Like `build`, but calls `on_progress` as the files are downloaded and the tables are
+    /// loaded, with how far along the job is: how many of its files are loaded, the total number
+    /// of bytes downloaded by the job so far, and the total number of rows and bytes loaded.
+    pub async fn build_with_progress<T: WorkerS3ClientTrait, F: FnMut(JobProgress) + Send>(
+        &self, client: WorkerS3ClientAdapter<T>, on_progress: F
+    ) -> Result<()> {
+        let files = get_workload_files(&self.workload);
+        let progress = self.update_progress(|progress| {
+            progress.set_files_total(files.len() as u32)
+        });
+        let on_progress = Mutex::new(on_progress);
+        (&mut *on_progress.lock().unwrap())(progress);
+        self.check_disk_space(&client).await?;
+        if self.workload.get_checkpoint() && self.resumed_from.is_none() {
+            checkpoint::start(&mut *self.database.connection().await?, &self.workload).await?;
+        }
+        // Every file becomes a table of its own, so there's nothing stopping us from loading
+        // several at once, each over its own connection out of the pool. Only one connection can
+        // write to SQLite at a time, so the inserts themselves still take turns (chunk by chunk),
+        // but the downloads and the CSV parsing overlap. That makes up most of the time spent.
+        //
+        // The loads all report their progress into one running total (see `update_progress`),
+        // and take turns at `on_progress`, hence the mutex.
+        futures::stream::iter(files.into_iter().map(Ok))
+            .try_for_each_concurrent(self.parallel_loads.max(1), |file| {
+                self.load_file(file, &client, &on_progress)
+            })
+            .await?;
+        if self.workload.get_checkpoint() {
+            let key = self.workload.get_idempotency_key();
+            checkpoint::mark_built(&mut *self.database.connection().await?, key).await?;
+        }
+        Ok(())
+    }
+
+    /// Marks the job's tables as used just now, so that they're kept for a while yet (see `gc`).
+    /// This is meant to be called once the job is done.
+    pub async fn lease_tables(&self) -> Result<()> {
+        if self.database.is_in_memory() {
+            return Ok(());
+        }
+        let tables = get_workload_files(&self.workload).into_iter()
+            .map(table_name)
+            .collect::<Vec<_>>();
+        gc::lease(&mut *self.database.connection().await?, &tables).await
+    }
+
+    /// Checks that the files the job has to download fit on disk (see `file::check_disk_space`),
+    /// along with the tables they're loaded into, which take up about as much room again (unless
+    /// they're in memory). Files whose tables are up to date don't count.
+    async fn check_disk_space<T: WorkerS3ClientTrait>(
+        &self, client: &WorkerS3ClientAdapter<T>
+    ) -> Result<()> {
+        let mut needed: u64 = 0;
+        for file in get_workload_files(&self.workload) {
+            let head = client.for_file(file).head(file.get_path()).await?;
+            let etag = head.etag.unwrap_or_default();
+            if self.workload.get_force_reload()
+                || !self.is_up_to_date(&table_name(file), file, &etag).await? {
+                needed += head.size.unwrap_or(0).max(0) as u64;
+            }
+        }
+        if !self.database.is_in_memory() {
+            needed *= 2;
+        }
+        check_disk_space(needed, &self.disk_limits)
+    }
+
+    /// Updates the job's progress, returning it as it is afterwards.
+    fn update_progress<F: FnOnce(&mut JobProgress)>(&self, update: F) -> JobProgress {
+        let mut progress = self.progress.lock().unwrap();
+        update(&mut progress);
+        progress.clone()
+    }
+
+    /// Returns whether or not the table is already loaded from the version of the file with the
+    /// given ETag.
+    async fn is_up_to_date(&self, table_name: &str, file: &File, etag: &str) -> Result<bool> {
+        let mut conn = self.database.connection().await?;
+        let fingerprint = Table::new(table_name, "").fingerprint(&mut conn).await?;
+        Ok(match fingerprint {
+            Some(fingerprint) => fingerprint.path == file.get_path() && fingerprint.etag == etag,
+            None => false,
+        })
+    }
+
+    /// Creates the client the job's S3 requests are made with: one with the job's credentials
+    /// and options.
+    pub fn s3_client(&self) -> Result<WorkerS3ClientAdapter<FaultyS3Client<RoutedS3Client>>> {
+        Ok(create_s3_client(&self.s3_credentials)?.with_options(self.s3_options.clone()))
+    }
+
+    /// Works out what running the job would involve, without downloading any files or running
+    /// any statements. Every file is looked up with a HEAD request, and only the first few
+    /// kilobytes of it are downloaded, to check its header. A file that doesn't exist, or whose
+    /// header doesn't declare a name and a type for every column, is an error.
+    ///
+    /// The statements aren't checked here: `Worker::submit` already did that.
+    pub async fn plan<T: WorkerS3ClientTrait>(
+        &self, client: &WorkerS3ClientAdapter<T>
+    ) -> Result<ExecutionPlan> {
+        let mut plan = ExecutionPlan::new();
+        for file in get_workload_files(&self.workload) {
+            let client = &client.for_file(file);
+            let path = file.get_path();
+            let table_name = table_name(file);
+            let head = client.head(path).await.map_err(|err| WorkerError::new(
+                ErrKind::AWSError, &format!("Could not find {}: {}", path, err)
+            ))?;
+            let etag = head.etag.unwrap_or_default();
+
+            let schema = if is_manifest(path) {
+                read_manifest(path, client).await?.columns()
+            } else {
+                None
+            };
+            let columns = match schema {
+                Some(columns) => columns,
+                None => {
+                    // A pattern's objects all share a header, so the first one's will do.
+                    let first = client.expand(path).await?.remove(0);
+                    let prefix = client.get_object_prefix(&first, PLAN_HEADER_BYTES).await?;
+                    let mut reader = csv::Reader::from_reader(&prefix[..]);
+                    let mut columns = match reader.headers() {
+                        Ok(headers) => parse_columns(headers),
+                        Err(err) => Err(err.into()),
+                    }.map_err(|err| WorkerError::new(
+                        ErrKind::DatabaseError, &format!("Bad header in {}: {}", path, err)
+                    ))?;
+                    if is_pattern(path) {
+                        for (key, _) in hive_partition(&first) {
+                            let index = columns.len();
+                            let col_type = "TEXT".to_owned();
+                            columns.push(ColumnSpec { index, name: key, col_type });
+                        }
+                    }
+                    columns
+                },
+            };
 
-        // This syntactic sugar is sweet.
-        for (&file, path) in files.iter().zip(file_paths) {
-            let table = Table::new(
-                &("dataset_".to_owned() + &file.id.to_string()),
-                &path
+            let mut file_plan = FilePlan::new();
+            file_plan.set_path(path.to_owned());
+            file_plan.set_size(head.size.unwrap_or(0).max(0) as u64);
+            file_plan.set_download(
+                self.workload.get_force_reload()
+                    || !self.is_up_to_date(&table_name, file, &etag).await?
             );
-            table.drop().await?;
-            table.dump().await?;
+            file_plan.set_etag(etag);
+            file_plan.set_columns(columns.iter()
+                .map(|column| format!("{} {}", column.name, column.col_type))
+                .collect());
+            file_plan.set_table(table_name);
+            plan.mut_files().push(file_plan);
+        }
+
+        let sinks = sinks(self.workload.get_ops())?;
+        for (i, op) in self.workload.get_ops().iter().enumerate() {
+            let mut op_plan = OpPlan::new();
+            op_plan.set_op_sequence_num(op.get_op_sequence_num());
+            op_plan.set_statement(op.get_statement().to_owned());
+            op_plan.set_tables(op.get_targets().iter().map(table_name).collect());
+            op_plan.set_return_result(sinks[i] || op.get_return_result());
+            plan.mut_ops().push(op_plan);
         }
+        Ok(plan)
+    }
+
+    /// Downloads a file (or every object it stands for, if it is a pattern) and loads it into
+    /// its table, unless the table is up to date.
+    async fn load_file<T: WorkerS3ClientTrait, F: FnMut(JobProgress) + Send>(
+        &self,
+        file: &File,
+        client: &WorkerS3ClientAdapter<T>,
+        on_progress: &Mutex<F>,
+    ) -> Result<()> {
+        let client = &client.for_file(file);
+        let table_name = table_name(file);
+        let mut file_metrics = FileMetrics::new();
+        file_metrics.set_path(file.get_path().to_owned());
+
+        // We take the ETag before downloading the file. If the object gets overwritten in
+        // between, we end up recording the old ETag against the new contents, which just means
+        // the table gets (needlessly) reloaded next time. The other way around, we could end up
+        // recording the new ETag against the old contents, and serve them forever.
+        let etag = client.get_etag(file.get_path()).await?;
+        let up_to_date = self.is_up_to_date(&table_name, file, &etag).await?;
+
+        if self.workload.get_force_reload() || !up_to_date {
+            // A file that is a pattern (see `file::is_pattern`) or a manifest (see `manifest`)
+            // stands for several objects, which are loaded into the table one after another.
+            // Anything else stands for just the one.
+            let (members, columns) = if is_manifest(file.get_path()) {
+                let manifest = read_manifest(file.get_path(), client).await?;
+                (manifest.paths(), manifest.columns())
+            } else {
+                (client.expand(file.get_path()).await?, None)
+            };
+            let mut size = 0;
+            for (i, member) in members.into_iter().enumerate() {
+                // Objects a pattern stands for get the columns of their Hive-style partition.
+                let partition = if is_pattern(file.get_path()) {
+                    hive_partition(&member)
+                } else {
+                    vec![]
+                };
+                let mut member_file = file.clone();
+                member_file.set_path(member);
+
+                let start = Instant::now();
+                // As with loading below, `on_bytes` is handed this object's progress so far, so
+                // we add on whatever it gained since the last time it reported.
+                let downloaded = AtomicU64::new(0);
+                let on_bytes = |bytes: u64| {
+                    let gained = bytes.saturating_sub(downloaded.swap(bytes, Ordering::Relaxed));
+                    let progress = self.update_progress(|progress| {
+                        progress.bytes_downloaded += gained;
+                    });
+                    (&mut *on_progress.lock().unwrap())(progress);
+                };
+                let path = localize_file_with_progress(
+                    &member_file, client, &self.disk_limits, &on_bytes
+                ).await?;
+                let member_size = fs::metadata(&path)?.len();
+                size += member_size;
+                file_metrics.bytes_downloaded += member_size;
+                file_metrics.download_micros += start.elapsed().as_micros() as u64;
+
+                let start = Instant::now();
+                let mut table = Table::new(&table_name, &path)
+                    .force_reload(i == 0)
+                    .append(i > 0)
+                    .partition(&partition)
+                    .null_tokens(file.get_null_tokens());
+                if let Some(columns) = &columns {
+                    table = table.columns(columns);
+                }
+                let mut conn = self.database.connection().await?;
+                // `on_progress` is handed this table's progress so far, so we add on whatever it
+                // gained since the last time it reported.
+                let mut reported = IngestProgress::default();
+                table.dump_into_with_progress(&mut conn, |table_progress| {
+                    let progress = self.update_progress(|progress| {
+                        progress.rows_loaded += table_progress.rows - reported.rows;
+                        progress.bytes_loaded += table_progress.bytes - reported.bytes;
+                    });
+                    reported = table_progress;
+                    (&mut *on_progress.lock().unwrap())(progress);
+                }).await?;
+                file_metrics.load_micros += start.elapsed().as_micros() as u64;
+            }
+            let fingerprint = TableFingerprint { path: file.get_path().to_owned(), etag, size };
+            let mut conn = self.database.connection().await?;
+            Table::new(&table_name, "").register(&mut conn, &fingerprint).await?;
+            file_metrics.download_bytes_per_second =
+                bytes_per_second(file_metrics.bytes_downloaded, file_metrics.download_micros);
+            file_metrics.load_bytes_per_second = bytes_per_second(size, file_metrics.load_micros);
+        }
+        let mut conn = self.database.connection().await?;
+        if let Some(table_stats) = stats::read(&mut conn, &table_name).await? {
+            file_metrics.set_table(table_stats);
+        }
+        self.metrics.lock().unwrap().mut_files().push(file_metrics);
+        let progress = self.update_progress(|progress| progress.files_loaded += 1);
+        (&mut *on_progress.lock().unwrap())(progress);
         Ok(())
     }
 
-    /// Performs the work portion of the job, e.g. the actual job execution.
-    pub async fn run(&self) -> Result<Vec<SqliteRow>> {
-        let mut conn = Database::connect().await?;
+    /// Performs the work portion of the job, e.g. the actual job execution. Returns the number of
+    /// result rows.
+    ///
+    /// The ops run in the order `dag::schedule` puts them in: each one once the ops it depends on
+    /// are done, and alongside any others that are ready at the same time. Every sink (see
+    /// `dag::sinks`) returns a result set, as does any other op with `return_result` set (e.g. to
+    /// report intermediate row counts or samples). Result sets are not collected into memory.
+    /// Instead, rows are read off of the database cursor as SQLite produces them, and handed to
+    /// `emit` in batches of `batch_size` rows, each tagged with the sequence number of the op it
+    /// came from. The final batch of each result set (which may be empty) is marked `op_last`.
+    /// The final batch of the final result set is also marked `last`, and carries the job's
+    /// metrics. If the final stage has more than one op in it, so that there is no telling which
+    /// result set will be the final one, this is instead an extra, empty batch sent after all of
+    /// the result sets. Use `collect_result_sets` to put them back together again.
+    ///
+    /// If the results outgrow the workload's `ResultLimits`, they are either cut short, in which
+    /// case the result set they were cut short in is marked `truncated`, or the job fails with a
+    /// `ResultLimitError`. The limits apply to all of the result sets put together.
+    ///
+    /// Batches that haven't been read yet count against the worker's memory budget. If they pile
+    /// up past its ceiling, the job waits for them to be read before it goes on (see `memory`).
+    pub async fn run<F: FnMut(ResultBatch)>(
+        &self, job_id: u64, batch_size: usize, mut emit: F
+    ) -> Result<u64> {
+        self.run_with_progress(job_id, batch_size, |batch| {
+            emit(batch);
+            futures::future::ready(())
+        }, |_| {}).await
+    }
+
+    /// Like `run`, but calls `on_progress` as each op starts and finishes, with how far along
+    /// the job is: which op started last, and how many of the job's ops are done.
+    ///
+    /// Here `emit` returns a future, which the job waits on before it goes on. This is how
+    /// whoever is reading the results holds the job back when it falls behind (see
+    /// `QueuedJob::send_result`).
+    pub async fn run_with_progress<F, R, P>(
+        &self, job_id: u64, batch_size: usize, emit: F, on_progress: P
+    ) -> Result<u64>
+    where F: FnMut(ResultBatch) -> R, R: Future<Output = ()>, P: FnMut(JobProgress) {
         let ops = self.workload.get_ops();
-        let mut result: Vec<SqliteRow> = vec![];
-        for i in 0..ops.len() {
-            let sql = ops[i].get_statement();
-
-            // Only the last op in the sequence should return a result. All other ops are
-            // preparatory: e.g. merging data, building new tables, and the like.
-            if i != (ops.len() - 1) {
-               sqlx::query(sql).execute(&mut conn).await?;
+        let stages = schedule(ops)?;
+        let run = JobRun {
+            job_id,
+            batch_size,
+            limits: ResultLimits::from_workload(&self.workload),
+            sinks: sinks(ops)?,
+            totals: Mutex::new(RunTotals { n_rows: 0, n_bytes: 0, emit, on_progress }),
+        };
+        let progress = self.update_progress(|progress| progress.set_ops_total(ops.len() as u32));
+        (run.totals.lock().unwrap().on_progress)(progress);
+
+        // Ops that run one after the other share a connection, so that they see each other's
+        // connection-specific state, e.g. `TEMP` tables. Ops that run at the same time each take
+        // a connection of their own out of the pool, so they can only see each other's work
+        // through the database itself.
+        let mut conn = None;
+        for (i, stage) in stages.iter().enumerate() {
+            let is_final_stage = i == stages.len() - 1;
+            if let &[op_index] = &stage[..] {
+                if conn.is_none() {
+                    conn = Some(self.database.connection().await?);
+                }
+                let conn = &mut **conn.as_mut().unwrap();
+                self.run_op(conn, op_index, is_final_stage, &run).await?;
             } else {
-                result = sqlx::query(sql).fetch_all(&mut conn).await?;
+                conn = None;
+                let run = &run;
+                futures::stream::iter(stage.iter().map(Ok))
+                    .try_for_each_concurrent(self.database.max_connections(), |&op_index| {
+                        async move {
+                            let mut conn = self.database.connection().await?;
+                            self.run_op(&mut conn, op_index, false, run).await
+                        }
+                    })
+                    .await?;
             }
         }
-        Ok(result)
+
+        drop(conn);
+        if self.workload.get_checkpoint() {
+            let key = self.workload.get_idempotency_key();
+            checkpoint::remove(&mut *self.database.connection().await?, key).await?;
+        }
+
+        let mut totals = run.totals.into_inner().unwrap();
+        if stages.last().map_or(false, |stage| stage.len() > 1) {
+            let mut last_batch = craft_batch(job_id, &[], vec![], true);
+            last_batch.set_metrics(self.metrics.lock().unwrap().clone());
+            (totals.emit)(last_batch).await;
+        }
+        Ok(totals.n_rows)
+    }
+
+    /// Runs a single op (see `run`). `is_final` is set if it's the very last op to run, and so
+    /// gets to mark its final batch `last`.
+    async fn run_op<F, R, P>(
+        &self, conn: &mut SqliteConnection, op_index: usize, is_final: bool, run: &JobRun<F, P>
+    ) -> Result<()>
+    where F: FnMut(ResultBatch) -> R, R: Future<Output = ()>, P: FnMut(JobProgress) {
+        let op = &self.workload.get_ops()[op_index];
+        let op_sequence_num = op.get_op_sequence_num();
+        let progress = self.update_progress(|progress| progress.set_current_op(op_sequence_num));
+        (run.totals.lock().unwrap().on_progress)(progress);
+        let mut op_metrics = OpMetrics::new();
+        op_metrics.set_op_sequence_num(op_sequence_num);
+
+        // A resumed job skips the ops it already did, save for the ones that return results.
+        let returns_result = run.sinks[op_index] || op.get_return_result();
+        let done_before = self.resumed_from.as_ref()
+            .map_or(false, |checkpoint| checkpoint.done_ops.contains(&op_sequence_num));
+        if done_before && !returns_result {
+            op_metrics.set_resumed(true);
+            self.metrics.lock().unwrap().mut_ops().push(op_metrics);
+            self.finish_op(run);
+            return Ok(());
+        }
+
+        // An op can be several statements long, e.g. `CREATE INDEX ...; ANALYZE; SELECT ...`.
+        // sqlx runs one statement at a time, so we split them up and run them one after the
+        // other. It's the last one that counts as the op's statement: its result set is the op's
+        // result set, and its plan is the op's plan.
+        let mut statements = split_statements(op.get_statement())?;
+        let sql = statements.pop().unwrap_or_default();
+        let mut rows_affected: u64 = 0;
+        let start = Instant::now();
+        for statement in statements.iter() {
+            rows_affected += sqlx::query(statement).execute(&mut *conn).await?.rows_affected();
+        }
+        let setup_duration = start.elapsed();
+
+        // The plan is worked out right before the statement runs, since it can depend on what
+        // ran before it (e.g. creating an index). Working it out doesn't count towards the op's
+        // duration.
+        if self.workload.get_explain() {
+            op_metrics.set_query_plan(explain_query_plan(&mut *conn, &sql).await?);
+        }
+        let start = Instant::now();
+
+        // Ops with an exchange hand their result set on to the staging area, and return where
+        // it went instead (see `exchange`).
+        if op.has_exchange() {
+            let client = self.s3_client()?;
+            let partitions = exchange(&client, &mut *conn, &sql, op.get_exchange()).await?;
+            let exchanged: u64 = partitions.iter().map(|partition| partition.n_rows).sum();
+            op_metrics.set_rows_affected(rows_affected + exchanged);
+            op_metrics.set_duration_micros((setup_duration + start.elapsed()).as_micros() as u64);
+            self.note_if_slow(run.job_id, op, &mut op_metrics);
+            if returns_result {
+                op_metrics.set_rows_returned(partitions.len() as u64);
+            }
+            self.metrics.lock().unwrap().mut_ops().push(op_metrics);
+            if returns_result {
+                let columns = EXCHANGE_COLUMNS.iter().map(|&c| c.to_owned()).collect::<Vec<_>>();
+                let rows = partitions.iter().map(|partition| partition.to_row()).collect();
+                let mut batch = craft_batch(run.job_id, &columns, rows, is_final);
+                batch.set_op_sequence_num(op_sequence_num);
+                batch.set_op_last(true);
+                if is_final {
+                    batch.set_metrics(self.metrics.lock().unwrap().clone());
+                }
+                self.memory.wait_for_room(batch.compute_size() as u64, self.memory_wait).await?;
+                let sent = {
+                    let mut totals = run.totals.lock().unwrap();
+                    totals.n_rows += partitions.len() as u64;
+                    (totals.emit)(batch)
+                };
+                sent.await;
+            } else if self.workload.get_checkpoint() {
+                let key = self.workload.get_idempotency_key();
+                checkpoint::mark_op_done(&mut *conn, key, op_sequence_num).await?;
+            }
+            self.finish_op(run);
+            return Ok(());
+        }
+
+        // Ops which don't return a result are preparatory: e.g. merging data, building new
+        // tables, and the like.
+        if !returns_result {
+            let done = sqlx::query(&sql).execute(&mut *conn).await?;
+            op_metrics.set_rows_affected(rows_affected + done.rows_affected());
+            op_metrics.set_duration_micros((setup_duration + start.elapsed()).as_micros() as u64);
+            self.note_if_slow(run.job_id, op, &mut op_metrics);
+            self.metrics.lock().unwrap().mut_ops().push(op_metrics);
+            if self.workload.get_checkpoint() {
+                let key = self.workload.get_idempotency_key();
+                checkpoint::mark_op_done(&mut *conn, key, op_sequence_num).await?;
+            }
+            self.finish_op(run);
+            return Ok(());
+        }
+
+        let mut rows = sqlx::query(&sql).fetch(&mut *conn);
+        let mut columns: Vec<String> = vec![];
+        let mut batch = Vec::with_capacity(run.batch_size);
+        let mut op_rows: u64 = 0;
+        let mut truncated = false;
+        while let Some(row) = rows.try_next().await? {
+            if columns.is_empty() { columns = craft_columns(&row); }
+            let out_row = craft_row(&row)?;
+            let row_bytes = out_row.compute_size() as u64;
+            // As with the metrics, the guards here are all temporaries, so the lock is never held
+            // across an `.await`.
+            let allowed = {
+                let mut totals = run.totals.lock().unwrap();
+                let allowed = run.limits.allows(totals.n_rows + 1, totals.n_bytes + row_bytes);
+                if allowed {
+                    totals.n_rows += 1;
+                    totals.n_bytes += row_bytes;
+                }
+                allowed
+            };
+            if !allowed {
+                if !run.limits.truncate { Err(run.limits.exceeded_error())? }
+                truncated = true;
+                break;
+            }
+            op_rows += 1;
+            batch.push(out_row);
+            if batch.len() == run.batch_size {
+                let mut result_batch =
+                    craft_batch(run.job_id, &columns, std::mem::take(&mut batch), false);
+                result_batch.set_op_sequence_num(op_sequence_num);
+                // Batches that haven't been read yet are held in memory, so if they're piling
+                // up, we wait for them to be read before making any more.
+                let bytes = result_batch.compute_size() as u64;
+                self.memory.wait_for_room(bytes, self.memory_wait).await?;
+                // The guard is dropped at the end of the statement, before we wait on the send.
+                let sent = (run.totals.lock().unwrap().emit)(result_batch);
+                sent.await;
+            }
+        }
+        op_metrics.set_rows_returned(op_rows);
+        op_metrics.set_rows_affected(rows_affected);
+        op_metrics.set_duration_micros((setup_duration + start.elapsed()).as_micros() as u64);
+        self.note_if_slow(run.job_id, op, &mut op_metrics);
+        // The guard is a temporary, dropped at the end of the statement, so the lock is never
+        // held across an `.await`.
+        self.metrics.lock().unwrap().mut_ops().push(op_metrics);
+
+        let mut op_last_batch = craft_batch(run.job_id, &columns, batch, is_final);
+        op_last_batch.set_op_sequence_num(op_sequence_num);
+        op_last_batch.set_op_last(true);
+        op_last_batch.set_truncated(truncated);
+        if is_final {
+            op_last_batch.set_metrics(self.metrics.lock().unwrap().clone());
+        }
+        // The last batch is held in memory like the rest, and for an op whose results fit in a
+        // single batch, it's the only one.
+        let bytes = op_last_batch.compute_size() as u64;
+        self.memory.wait_for_room(bytes, self.memory_wait).await?;
+        let sent = (run.totals.lock().unwrap().emit)(op_last_batch);
+        sent.await;
+        self.finish_op(run);
+        Ok(())
+    }
+
+    /// Counts an op as done, and reports as much.
+    fn finish_op<F, P: FnMut(JobProgress)>(&self, run: &JobRun<F, P>) {
+        let progress = self.update_progress(|progress| progress.ops_done += 1);
+        (run.totals.lock().unwrap().on_progress)(progress);
+    }
+
+    /// Logs an op that just ran to the slow-op log, if it was slow and there is one, and marks
+    /// its metrics to say so. An op that can't be logged is still counted.
+    fn note_if_slow(&self, job_id: u64, op: &Op, op_metrics: &mut OpMetrics) {
+        let log = match &self.slow_ops {
+            Some(log) => log,
+            None => return,
+        };
+        match log.record(job_id, op, op_metrics) {
+            Ok(true) => {
+                log!(
+                    "Op {} of job {} was slow ({} microseconds).",
+                    op.get_op_sequence_num(), job_id, op_metrics.get_duration_micros()
+                );
+                op_metrics.set_slow(true);
+            },
+            Ok(false) => {},
+            Err(err) => {
+                log_error!("Could not write to the slow-op log {}: {}", log.path.display(), err);
+                op_metrics.set_slow(true);
+            },
+        }
     }
 }
 
+/// The state shared by the ops of a job as `Job::run` runs them.
+struct JobRun<F, P> {
+    job_id: u64,
+    batch_size: usize,
+    limits: ResultLimits,
+    sinks: Vec<bool>,
+    totals: Mutex<RunTotals<F, P>>,
+}
+
+/// What the ops of a job have returned so far. Ops that run at the same time take turns at this.
+struct RunTotals<F, P> {
+    n_rows: u64,
+    n_bytes: u64,
+    emit: F,
+    on_progress: P,
+}
+
 #[cfg(test)]
 mod tests {
     use futures::executor::block_on;
 
+    use crate::file::WorkerS3ClientMock;
     use crate::fixtures::*;
     use super::*;
 
@@ -70,22 +727,315 @@ mod tests {
         assert!(job.is_ok());
     }
 
-    // I can't easily unit test build or run execution because the `_get_object` logic associated
-    // with the S3 downloader mock returns `vec![1,2,3]`. This is not valid CSV because it fails
-    // the CSV parsing rules: it doesn't have a header.
-    //
-    // In order to unit test these functions we would have to convert the following CSV:
-    //
-    // ```csv
-    // a_int,b_int,c_int
-    // 1,2,3
-    // ```
-    //
-    // Into UTF-8 bytes, and then reencode those bytes into decimal uint8 values. Doing this by
-    // hand is extremely fraught (how the hell are you going to tell why it's not working?), and
-    // I'm not about to also write my own encoder/decoder, so I'm just going to keep to
-    // integration tests (`test_build_job` and `test_run_job`) instead.
-    //
-    // Note that encoding to bytes isn't too bad, use https://codebeautify.org/utf8-converter,
-    // it's the double encoding that sucks.
+    #[test]
+    fn test_bytes_per_second() {
+        assert_eq!(bytes_per_second(1024, 500_000), 2048);
+        assert_eq!(bytes_per_second(1024, 0), 0);
+        assert_eq!(bytes_per_second(u64::MAX, 1_000_000), u64::MAX);
+    }
+
+    #[test]
+    fn test_validate_job() {
+        use crate::sandbox::SANDBOX_STATEMENTS;
+
+        let op = craft_op_message(None, Some("SELECT * FROM dataset_1".to_owned()), None);
+        let workload = craft_workload_message(
+            Some(protobuf::RepeatedField::from_vec(vec![op]))
+        );
+        let job = block_on(Job::new(workload)).unwrap();
+        assert!(job.validate(&SANDBOX_STATEMENTS).is_ok());
+
+        let op = craft_op_message(None, Some("DROP TABLE dataset_1".to_owned()), None);
+        let workload = craft_workload_message(
+            Some(protobuf::RepeatedField::from_vec(vec![op]))
+        );
+        let job = block_on(Job::new(workload)).unwrap();
+        assert!(job.validate(&SANDBOX_STATEMENTS).is_err());
+    }
+
+    #[test]
+    fn test_explain_ops() {
+        use protobuf::RepeatedField;
+
+        // These ops don't need any tables loaded, so the job can be run without building it.
+        let ops = vec![
+            craft_op_message(
+                Some(RepeatedField::new()), Some("CREATE TABLE foo (a INTEGER)".to_owned()), Some(1)
+            ),
+            craft_op_message(
+                Some(RepeatedField::new()), Some("SELECT * FROM foo".to_owned()), Some(2)
+            ),
+        ];
+        let mut workload = craft_workload_message(Some(RepeatedField::from_vec(ops)));
+        workload.set_ephemeral(true);
+        workload.set_explain(true);
+
+        let job = block_on(Job::new(workload)).unwrap();
+        block_on(job.run(1, 10, |_| {})).unwrap();
+        let metrics = job.metrics.lock().unwrap();
+        assert_eq!(metrics.get_ops()[0].get_query_plan(), "");
+        assert!(metrics.get_ops()[1].get_query_plan().contains("foo"));
+    }
+
+    #[test]
+    fn test_multi_statement_op() {
+        use protobuf::RepeatedField;
+
+        let op = craft_op_message(
+            Some(RepeatedField::new()),
+            Some(
+                "CREATE TABLE foo (a INTEGER); INSERT INTO foo VALUES (1), (2);\n\
+                SELECT COUNT(*) FROM foo;".to_owned()
+            ),
+            Some(1),
+        );
+        let mut workload = craft_workload_message(Some(RepeatedField::from_vec(vec![op])));
+        workload.set_ephemeral(true);
+
+        let job = block_on(Job::new(workload)).unwrap();
+        let mut batches = vec![];
+        block_on(job.run(1, 10, |batch| batches.push(batch))).unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].get_rows()[0].get_values()[0].get_integer(), 2);
+        assert_eq!(job.metrics.lock().unwrap().get_ops()[0].get_rows_affected(), 2);
+    }
+
+    /// A job selecting the numbers 1 to 10, under the given result limits.
+    fn craft_limited_job(max_rows: u64, max_bytes: u64, truncate: bool) -> Job {
+        use protobuf::RepeatedField;
+
+        let statement = "WITH RECURSIVE n(x) AS \
+            (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 10) SELECT x FROM n";
+        let op = craft_op_message(Some(RepeatedField::new()), Some(statement.to_owned()), Some(1));
+        let mut workload = craft_workload_message(Some(RepeatedField::from_vec(vec![op])));
+        workload.set_ephemeral(true);
+        workload.set_max_result_rows(max_rows);
+        workload.set_max_result_bytes(max_bytes);
+        workload.set_truncate_results(truncate);
+        block_on(Job::new(workload)).unwrap()
+    }
+
+    #[test]
+    fn test_run_truncates_results() {
+        let mut batches = vec![];
+        let n_rows = block_on(craft_limited_job(4, 0, true).run(1, 3, |batch| batches.push(batch)))
+            .unwrap();
+        assert_eq!(n_rows, 4);
+        let values = batches.iter()
+            .flat_map(|batch| batch.get_rows())
+            .map(|row| row.get_values()[0].get_integer())
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![1, 2, 3, 4]);
+        let last_batch = batches.last().unwrap();
+        assert!(last_batch.get_last() && last_batch.get_truncated());
+        assert!(batches[..batches.len() - 1].iter().all(|batch| !batch.get_truncated()));
+
+        // Every row is the same size, so a byte limit of three rows' worth lets three through.
+        let mut batches = vec![];
+        block_on(craft_limited_job(0, 0, true).run(1, 10, |batch| batches.push(batch))).unwrap();
+        let row_bytes = batches[0].get_rows()[0].compute_size() as u64;
+        let mut batches = vec![];
+        let job = craft_limited_job(0, 3 * row_bytes, true);
+        assert_eq!(block_on(job.run(1, 10, |batch| batches.push(batch))).unwrap(), 3);
+        assert_eq!(batches.iter().map(|batch| batch.get_rows().len()).sum::<usize>(), 3);
+        assert!(batches.last().unwrap().get_truncated());
+    }
+
+    #[test]
+    fn test_run_fails_past_result_limits() {
+        for job in vec![craft_limited_job(4, 0, false), craft_limited_job(0, 1, false)] {
+            let err = block_on(job.run(1, 3, |_| {})).unwrap_err();
+            match err.downcast_ref::<WorkerError>() {
+                Some(WorkerError::ResultLimitError(_)) => {},
+                _ => panic!("Expected a ResultLimitError, got {}.", err),
+            }
+        }
+        // Within the limits, the job runs as usual.
+        assert_eq!(block_on(craft_limited_job(10, 0, false).run(1, 3, |_| {})).unwrap(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_run_waits_for_room_for_last_batch() {
+        // All ten rows fit in a single batch, which is the op's last, and too big for the ceiling.
+        let mut job = craft_limited_job(0, 0, false);
+        job.memory = Arc::new(MemoryBudget::new(Some(10)));
+        let err = job.run(1, 100, |_| {}).await.unwrap_err();
+        match err.downcast_ref::<WorkerError>() {
+            Some(WorkerError::ResourceError(_)) => {},
+            _ => panic!("Expected a ResourceError, got {}.", err),
+        }
+
+        // With room for it, the batch goes out once whatever holds the room lets go of it.
+        let mut job = craft_limited_job(0, 0, false);
+        job.memory = Arc::new(MemoryBudget::new(Some(1024)));
+        job.memory_wait = Duration::from_secs(5);
+        let held = job.memory.hold(1024);
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(held);
+        };
+        let (n_rows, _) = tokio::join!(job.run(1, 100, |_| {}), release);
+        assert_eq!(n_rows.unwrap(), 10);
+    }
+
+    #[test]
+    fn test_run_with_progress() {
+        use protobuf::RepeatedField;
+
+        let ops = vec![
+            craft_op_message(Some(RepeatedField::new()), Some("SELECT 1".to_owned()), Some(1)),
+            craft_op_message(Some(RepeatedField::new()), Some("SELECT 2".to_owned()), Some(2)),
+        ];
+        let mut workload = craft_workload_message(Some(RepeatedField::from_vec(ops)));
+        workload.set_ephemeral(true);
+
+        let job = block_on(Job::new(workload)).unwrap();
+        let mut reports = vec![];
+        let emit = |_: ResultBatch| futures::future::ready(());
+        block_on(job.run_with_progress(1, 10, emit, |progress| reports.push(progress))).unwrap();
+        let steps = reports.iter()
+            .map(|progress| (progress.get_current_op(), progress.get_ops_done()))
+            .collect::<Vec<_>>();
+        assert_eq!(steps, vec![(0, 0), (1, 0), (1, 1), (2, 1), (2, 2)]);
+        assert!(reports.iter().all(|progress| progress.get_ops_total() == 2));
+        assert_eq!(*job.progress.lock().unwrap(), reports[4]);
+    }
+
+    #[test]
+    fn test_run_dag() {
+        use protobuf::RepeatedField;
+        use crate::result::collect_result_sets;
+        use crate::workload::Op;
+
+        let craft_op = |statement: &str, op_sequence_num: i32, depends_on: Vec<i32>| {
+            let mut op = craft_op_message(
+                Some(RepeatedField::new()), Some(statement.to_owned()), Some(op_sequence_num)
+            );
+            op.set_depends_on(depends_on);
+            op
+        };
+        let craft_job = |ops: Vec<Op>| {
+            let mut workload = craft_workload_message(Some(RepeatedField::from_vec(ops)));
+            workload.set_ephemeral(true);
+            block_on(Job::new(workload)).unwrap()
+        };
+
+        // Two tables are prepared independently, and then joined.
+        let job = craft_job(vec![
+            craft_op("CREATE TABLE a AS SELECT 1 AS x", 1, vec![]),
+            craft_op("CREATE TABLE b AS SELECT 2 AS y", 2, vec![]),
+            craft_op("SELECT x + y FROM a, b", 3, vec![1, 2]),
+        ]);
+        let mut batches = vec![];
+        block_on(job.run(1, 10, |batch| batches.push(batch))).unwrap();
+        assert_eq!(batches.len(), 1);
+        assert!(batches[0].get_last());
+        assert_eq!(batches[0].get_rows()[0].get_values()[0].get_integer(), 3);
+
+        // With two sinks in the final stage, the last batch comes on its own.
+        let job = craft_job(vec![
+            craft_op("CREATE TABLE a AS SELECT 1 AS x", 1, vec![]),
+            craft_op("SELECT x FROM a", 2, vec![1]),
+            craft_op("SELECT x * 10 FROM a", 3, vec![1]),
+        ]);
+        let mut batches = vec![];
+        block_on(job.run(1, 10, |batch| batches.push(batch))).unwrap();
+        assert_eq!(batches.len(), 3);
+        let last_batch = batches.last().unwrap();
+        assert!(last_batch.get_last() && last_batch.get_rows().is_empty());
+        assert!(last_batch.has_metrics());
+        let result_sets = collect_result_sets(batches);
+        assert_eq!(result_sets[&2].get_rows()[0].get_values()[0].get_integer(), 1);
+        assert_eq!(result_sets[&3].get_rows()[0].get_values()[0].get_integer(), 10);
+    }
+
+    #[test]
+    fn test_run_resumed() {
+        use protobuf::RepeatedField;
+
+        let ops = vec![
+            craft_op_message(
+                Some(RepeatedField::new()), Some("CREATE TABLE a AS SELECT 1 AS x".to_owned()),
+                Some(1)
+            ),
+            craft_op_message(
+                Some(RepeatedField::new()), Some("SELECT x FROM a".to_owned()), Some(2)
+            ),
+        ];
+        let mut workload = craft_workload_message(Some(RepeatedField::from_vec(ops)));
+        workload.set_ephemeral(true);
+
+        // The first op was done before the job was resumed, so it isn't run again (which would
+        // fail, since the table is there already). The second returns a result, so it is.
+        let mut job = block_on(Job::new(workload.clone())).unwrap();
+        block_on(async {
+            let mut conn = job.database.connection().await.unwrap();
+            sqlx::query("CREATE TABLE a AS SELECT 5 AS x").execute(&mut *conn).await.unwrap();
+        });
+        job.resumed_from = Some(Checkpoint { workload, built: true, done_ops: vec![1, 2] });
+        let mut batches = vec![];
+        block_on(job.run(1, 10, |batch| batches.push(batch))).unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].get_rows()[0].get_values()[0].get_integer(), 5);
+        let metrics = job.metrics.lock().unwrap();
+        assert!(metrics.get_ops()[0].get_resumed());
+        assert!(!metrics.get_ops()[1].get_resumed());
+        assert_eq!(job.progress.lock().unwrap().get_ops_done(), 2);
+    }
+
+    /// A job over the given files, ephemeral so that it doesn't touch the worker's database.
+    fn craft_job(files: Vec<File>, statement: &str) -> Job {
+        use protobuf::RepeatedField;
+
+        let op = craft_op_message(
+            Some(RepeatedField::from_vec(files)), Some(statement.to_owned()), Some(1)
+        );
+        let mut workload = craft_workload_message(Some(RepeatedField::from_vec(vec![op])));
+        workload.set_ephemeral(true);
+        block_on(Job::new(workload)).unwrap()
+    }
+
+    #[test]
+    fn test_build_and_run_job() {
+        let generated = craft_csv_file(
+            "mini-cluster-test-build-job.csv", &["a_int", "d_text"], &[&["1", "x"], &["4", "y"]]
+        );
+        let client = WorkerS3ClientAdapter::new(WorkerS3ClientMock::with_objects(vec![
+            ("s3://foo/simple.csv", fs::read(artifact_path("simple-csv.csv")).unwrap()),
+            ("s3://foo/generated.csv", fs::read(&generated).unwrap()),
+        ]));
+        let files = vec![
+            craft_file_message(Some(1), Some("s3://foo/simple.csv".to_owned())),
+            craft_file_message(Some(2), Some("s3://foo/generated.csv".to_owned())),
+        ];
+        let job = craft_job(
+            files, "SELECT a + c, d FROM dataset_1 JOIN dataset_2 USING (a) ORDER BY d"
+        );
+        block_on(job.build(client)).unwrap();
+        {
+            let metrics = job.metrics.lock().unwrap();
+            assert_eq!(metrics.get_files().len(), 2);
+            assert!(metrics.get_files().iter().all(|file| file.get_bytes_downloaded() > 0));
+            assert_eq!(job.progress.lock().unwrap().get_files_loaded(), 2);
+        }
+
+        let mut batches = vec![];
+        let n_rows = block_on(job.run(1, 10, |batch| batches.push(batch))).unwrap();
+        assert_eq!(n_rows, 1);
+        // Only the first row of the generated file has a match in the artifact (`1,2,3`).
+        let values = batches[0].get_rows()[0].get_values();
+        assert_eq!(values[0].get_integer(), 4);
+        assert_eq!(values[1].get_text(), "x");
+        assert!(batches.last().unwrap().get_last());
+    }
+
+    #[test]
+    fn test_build_job_missing_file() {
+        let client = WorkerS3ClientAdapter::new(WorkerS3ClientMock::with_objects(vec![
+            ("s3://foo/simple.csv", fs::read(artifact_path("simple-csv.csv")).unwrap()),
+        ]));
+        let file = craft_file_message(Some(1), Some("s3://foo/missing.csv".to_owned()));
+        let job = craft_job(vec![file], "SELECT * FROM dataset_1");
+        assert!(block_on(job.build(client)).is_err());
+    }
 }
\ No newline at end of file
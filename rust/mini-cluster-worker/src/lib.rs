@@ -1,48 +1,960 @@
+use std::any::Any;
 use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::RwLock as StdRwLock;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use futures::FutureExt;
 use sqlx::{Column, Row, sqlite::SqliteRow};
-use tokio::net::{TcpStream, TcpListener};
+use tokio::io::{AsyncRead, AsyncReadExt};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{Notify, RwLock, Semaphore, watch};
 use err::Result;
-use protobuf::Message;
+use protobuf::{Message, RepeatedField};
 
+#[macro_use]
+pub mod log;
 pub mod err;
 pub mod workload;
 pub mod file;
 pub mod fixtures;
 pub mod db;
+pub mod functions;
 pub mod job;
+pub mod config;
+pub mod protocol;
+pub mod auth;
+pub mod sandbox;
+pub mod queue;
+pub mod response;
+pub mod result;
+pub mod grpc;
+pub mod transport;
+pub mod cache;
+pub mod dag;
+pub mod cluster;
+pub mod membership;
+pub mod builder;
+pub mod health;
+pub mod slowlog;
+pub mod checkpoint;
+pub mod gc;
+pub mod memory;
+pub mod capabilities;
+pub mod manifest;
+pub mod credentials;
+pub mod presigned;
+pub mod snapshot;
+pub mod stats;
+pub mod exchange;
+pub mod eviction;
+pub mod ratelimit;
+pub mod encryption;
+pub mod tls;
+#[cfg(feature = "chaos")]
+pub mod chaos;
 
 use err::{WorkerError,ErrKind};
 use job::Job;
-use file::create_new_s3_client;
+use db::Database;
+use config::{Tunables, WorkerConfig, Transport};
+use protocol::{FrameHeader, HEADER_LENGTH};
+use auth::{generate_nonce, verify_nonce};
+use queue::{JobQueue, JobState, QueuedJob};
+use result::{craft_row, format_value, split_result_batch};
+use transport::{Address, Listener, Stream};
+use cache::{read_cached_results, result_cache_key, write_cached_results};
+use sandbox::{InvalidOp, validate_workload};
+use slowlog::SlowOpLog;
+use log::LogContext;
+use memory::MemoryBudget;
+use ratelimit::{RateLimited, RateLimiter};
+use tokio_rustls::TlsAcceptor;
 
 pub struct Worker {
-    pub port: u16,
-    pub listener: TcpListener
+    pub address: Address,
+    pub listener: Listener,
+    pub config: WorkerConfig,
+    pub queue: Arc<JobQueue>,
+    /// The optional parts of the SQL surface available to op statements (see
+    /// `functions::capabilities`).
+    pub capabilities: Vec<String>,
+    /// Everything the worker can do, which it advertises to the scheduler (see `capabilities`).
+    pub advertised: response::WorkerCapabilities,
+    /// Where slow ops are logged, if they are (see `slowlog`).
+    pub slow_ops: Option<Arc<SlowOpLog>>,
+    /// The memory budget that every job's result batches are counted against (see `memory`).
+    pub memory: Arc<MemoryBudget>,
+    // Held for reading by everything that uses the worker's database, and for writing whilst
+    // garbage is collected in it (see `gc`).
+    gc_lock: Arc<RwLock<()>>,
+    // Set once the worker has been asked to drain (see `drain`). There is no undoing it: a
+    // drained worker is on its way to being restarted.
+    draining: AtomicBool,
+    // Set once the worker has been asked to stop (see `stop`), which `stopped` waits on.
+    stop_tx: watch::Sender<bool>,
+    stop_rx: watch::Receiver<bool>,
+    // The parts of `config` that can be changed whilst the worker runs, as they currently are
+    // (see `reload`). These are read from here, not from `config`.
+    tunables: Arc<StdRwLock<Tunables>>,
+    // One permit per connection the worker may serve at once (see `listen`).
+    connection_permits: Arc<Semaphore>,
+    // How many executor tasks have been spawned, and a notification for them when
+    // `Tunables.executors` changes (see `spawn_executors`).
+    executors_spawned: AtomicUsize,
+    executors_resized: Arc<Notify>,
+    // How fast each client may connect, if there's a limit (see `ratelimit`).
+    accept_limiter: Option<RateLimiter>,
+    // What connections go over to TLS with, if they do (see `tls`).
+    tls: Option<TlsAcceptor>,
 }
 
 impl fmt::Display for Worker {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "<Worker port:{}>", self.port.to_string())
+        write!(f, "<Worker {}>", self.address)
+    }
+}
+
+/// If `err` means that the client sent us a workload that doesn't pass validation, returns the
+/// error message and the index of the offending op (-1 if it isn't about any one op).
+fn validation_failure(err: &(dyn std::error::Error + 'static)) -> Option<(String, i32)> {
+    if let Some(invalid_op) = err.downcast_ref::<InvalidOp>() {
+        return Some((invalid_op.to_string(), invalid_op.op_index as i32));
+    }
+    match err.downcast_ref::<WorkerError>() {
+        Some(WorkerError::ValidationError(_)) => Some((err.to_string(), -1)),
+        _ => None,
+    }
+}
+
+/// Turns whatever a panic was raised with into a message. `panic!` raises a `&str` or a `String`;
+/// anything else is anyone's guess.
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => "(no message)".to_owned(),
+        },
     }
 }
 
 impl Worker {
-    pub async fn new(port: u16) -> Result<Worker> {
-        let addr = format!("127.0.0.1:{port}", port=port.to_string());
-        let listener = TcpListener::bind(addr).await?;
-        Ok(Worker { port, listener })
+    /// Creates a worker listening on the given address: either a TCP port (e.g. `8080`) or the
+    /// path to a Unix domain socket.
+    pub async fn new(address: impl Into<Address>, config: WorkerConfig) -> Result<Worker> {
+        let address = address.into();
+        // The key has to be in place before anything touches the cache (see `encryption`).
+        let cache_key = match &config.cache_key {
+            Some(source) => Some(source.resolve().await?),
+            None => None,
+        };
+        encryption::install(cache_key);
+        if cache_key.is_some() {
+            let database = Database::new_in_memory().await?;
+            encryption::check_database_support(&mut *database.connection().await?).await?;
+            let cleartext = encryption::find_cleartext(&Database::get_db_path())?;
+            if !cleartext.is_empty() {
+                Err(WorkerError::new(
+                    ErrKind::ValidationError,
+                    &format!(
+                        "The cache has to be encrypted, but {} files in it are in the clear \
+                        (e.g. {}). Clear the cache out before starting the worker.",
+                        cleartext.len(), cleartext[0].display()
+                    )
+                ))?
+            }
+            log!("The cache is encrypted.");
+        }
+        // Nothing has connected to the database yet, so this is the one time it can be swapped
+        // out from under us.
+        if let Some(path) = &config.restore_snapshot {
+            if Database::get_db_path().exists() {
+                log!("Not restoring the snapshot at {}: there is a database already.",
+                    presigned::without_query(path));
+            } else {
+                let client = credentials::create_s3_client(&config.s3_credentials)?
+                    .with_options(config.s3.clone());
+                snapshot::restore(&client, path).await?;
+            }
+        }
+        let listener = Listener::bind(&address).await?;
+        // A worker asked to listen on port 0 goes by the port it got, e.g. when it registers.
+        let address = listener.local_address(&address)?;
+        let queue = Arc::new(JobQueue::with_aging(config.priority_aging));
+        // Every connection is set up the same way, so probing a throwaway in-memory one tells
+        // us what all of them can do.
+        let mut capabilities = {
+            let database = Database::new_in_memory().await?;
+            let mut conn = database.connection().await?;
+            functions::capabilities(&mut conn).await
+        };
+        // Custom functions are listed by name, e.g. `function:regex_match`.
+        capabilities.extend(
+            config.database.functions.iter().map(|function| format!("function:{}", function.name))
+        );
+        let slow_ops = config.slow_op_threshold.map(|threshold| {
+            Arc::new(SlowOpLog::new(threshold, &config.slow_op_log))
+        });
+        let memory = Arc::new(MemoryBudget::new(config.memory_ceiling));
+        let advertised = capabilities::advertise(&config, &capabilities);
+        config.log_level.apply();
+        let tunables = Tunables::of(&config);
+        let connection_permits = Arc::new(Semaphore::new(tunables.max_connections));
+        let accept_limiter = config.accept_rate.map(RateLimiter::new);
+        let tls = config.tls.as_ref().map(|tls| tls.acceptor()).transpose()?;
+        let (stop_tx, stop_rx) = watch::channel(false);
+        Ok(Worker {
+            address,
+            listener,
+            config,
+            queue,
+            capabilities,
+            advertised,
+            slow_ops,
+            memory,
+            gc_lock: Arc::new(RwLock::new(())),
+            draining: AtomicBool::new(false),
+            stop_tx,
+            stop_rx,
+            tunables: Arc::new(StdRwLock::new(tunables)),
+            connection_permits,
+            executors_spawned: AtomicUsize::new(0),
+            executors_resized: Arc::new(Notify::new()),
+            accept_limiter,
+            tls,
+        })
+    }
+
+    /// Registers the worker with the scheduler, returning the ID the scheduler gave it. If the
+    /// scheduler can't be reached (e.g. because it is still starting up), the worker tries again
+    /// a few times, backing off in between, before giving up.
+    pub async fn register(&self, scheduler: &Address) -> Result<u64> {
+        let registration = membership::craft_registration(
+            &self.address, &self.advertised, file::cache_size()
+        );
+        let mut backoff = membership::REGISTRATION_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            // As elsewhere, the error is turned into a `String` before we `.await` again.
+            let outcome = membership::register(
+                scheduler, &registration, self.config.secret.as_deref()
+            ).await.map_err(|err| err.to_string());
+            match outcome {
+                Ok(worker_id) => return Ok(worker_id),
+                Err(message) if attempt < membership::REGISTRATION_ATTEMPTS => {
+                    log!(
+                        "Could not register with the scheduler (attempt {}): {}", attempt, message
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                },
+                Err(message) => Err(WorkerError::new(ErrKind::NetworkError, &message))?,
+            }
+        }
+    }
+
+    /// Reloads the configuration whenever the process is sent a SIGHUP.
+    #[cfg(unix)]
+    fn reload_on_hangup(self: &Arc<Self>) -> Result<()> {
+        let mut hangups = signal(SignalKind::hangup())?;
+        let worker = Arc::clone(self);
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                log!("Received SIGHUP, reloading the configuration.");
+                if let Err(message) = worker.reload().map_err(|err| err.to_string()) {
+                    log_error!("Could not reload the configuration: {}", message);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// There's no SIGHUP outside of Unix. The configuration can still be reloaded with a RELOAD
+    /// frame.
+    #[cfg(not(unix))]
+    fn reload_on_hangup(self: &Arc<Self>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Serves clients over whichever transport the worker is configured to use. If the worker
+    /// is configured with a scheduler, it registers itself first. The worker is already
+    /// listening by then, so the scheduler can reach it as soon as it hears from it. Health
+    /// probes are answered on the admin port, if there is one (see `health`), and the
+    /// configuration is reloaded whenever the process is sent a SIGHUP (see `reload`).
+    pub async fn serve(self: Arc<Self>) -> Result<()> {
+        self.reload_on_hangup()?;
+        if let Some(scheduler) = &self.config.scheduler {
+            let worker_id = self.register(scheduler).await?;
+            log!("Registered with the scheduler at {} as worker {}.", scheduler, worker_id);
+        }
+        if let Some(port) = self.config.admin_port {
+            let worker = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(err) = health::serve(worker, port).await.map_err(|err| err.to_string()) {
+                    log_error!("Stopped answering health probes after error: {}", err);
+                }
+            });
+        }
+        match self.config.transport {
+            Transport::Tcp => self.listen().await,
+            Transport::Grpc => grpc::serve(self).await,
+        }
     }
 
     // This asynchronous listener courtesy of
     // https://docs.rs/tokio/1.3.0/tokio/net/struct.TcpListener.html.
-    pub async fn listen(&self) -> Result<()> {
+    //
+    // Each connection is served on its own task, which is why this takes an `Arc<Self>`: every
+    // task needs its own handle on the worker. The number of connections served at once is capped
+    // at `max_connections` using a semaphore, so a misbehaving scheduler can't run the worker out
+    // of file descriptors. (The cap can be changed whilst the worker runs; see `reload`.)
+    pub async fn listen(self: Arc<Self>) -> Result<()> {
+        self.spawn_executors();
+        self.spawn_gc();
+        let connection_permits = Arc::clone(&self.connection_permits);
         loop {
-            let (mut socket, _) = self.listener.accept().await?;
-            self.handle_connection(&mut socket).await?;
+            // Unless we've been asked to turn away excess clients, we don't accept a connection
+            // until we have a slot free for it. Clients then wait in the OS listen backlog until
+            // they can be served.
+            let permit = if self.config.reject_when_busy {
+                None
+            } else {
+                Some(Arc::clone(&connection_permits).acquire_owned().await?)
+            };
+            let mut socket = self.listener.accept().await?;
+            let permit = match permit {
+                Some(permit) => permit,
+                None => match Arc::clone(&connection_permits).try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        log!(
+                            "Rejecting connection, already serving {} connections.",
+                            self.tunables().max_connections
+                        );
+                        // A client expecting TLS can't read frames sent in the clear, so it's
+                        // just hung up on.
+                        if self.tls.is_none() {
+                            let _ = self.write_frame(&mut socket, protocol::BUSY, 0, 0, &[]).await;
+                        }
+                        continue;
+                    }
+                },
+            };
+            // A client connecting faster than it's allowed to is turned away before it costs us
+            // a task. Its slot goes straight back to the listener.
+            if let Some(limiter) = &self.accept_limiter {
+                if let Err(limited) = limiter.take(&socket.peer()) {
+                    log!("Rejecting connection: {}", limited);
+                    if self.tls.is_none() {
+                        let _ = self.write_rate_limited(&mut socket, &limited).await;
+                    }
+                    continue;
+                }
+            }
+
+            let worker = Arc::clone(&self);
+            let context = LogContext::current().with_new_connection();
+            tokio::spawn(context.scope(async move {
+                if let Some(socket) = worker.secure(socket).await {
+                    worker.serve_connection(socket).await;
+                }
+                // Hand the slot back to the listener.
+                drop(permit);
+            }));
+        }
+    }
+
+    /// Serves a connection until the client hangs up (or is hung up on), logging whatever ended
+    /// it.
+    async fn serve_connection(&self, mut socket: Stream) {
+        // Something going wrong with one connection (e.g. the client going quiet and timing
+        // out, or a bug of ours panicking) should only cost us that connection, not the whole
+        // worker.
+        let outcome = AssertUnwindSafe(self.handle_connection(&mut socket))
+            .catch_unwind()
+            .await
+            .map(|handled| handled.map_err(|err| err.to_string()));
+        match outcome {
+            Ok(Ok(())) => {},
+            Ok(Err(message)) => log_error!("Closing connection after error: {}", message),
+            Err(panic) => {
+                let message = panic_message(panic);
+                log_error!("Closing connection after panic: {}", message);
+                // There's no telling which request it panicked in the middle of, but the client
+                // is better off hearing that something went wrong than waiting for an answer
+                // that will never come.
+                let _ = self.write_error(
+                    &mut socket,
+                    0,
+                    0,
+                    response::ErrorResponse_Kind::INTERNAL,
+                    &format!("The worker panicked: {}", message)
+                ).await;
+            },
+        }
+    }
+
+    /// Opens a connection to the worker that doesn't go over the network at all: the worker
+    /// serves one end of an in-memory pipe (see `Stream::pair`), and the other end is returned.
+    /// This makes for tests that don't have to hand out ports, or wait on sockets. The
+    /// connection takes up a slot like any other, but it isn't rate-limited, and doesn't go
+    /// over TLS.
+    ///
+    /// Jobs sent over the connection only run if the worker is listening (see `listen`), which
+    /// is what starts its executors.
+    pub async fn connect_in_process(self: &Arc<Self>) -> Result<Stream> {
+        let permit = Arc::clone(&self.connection_permits).acquire_owned().await?;
+        let (client, server) = Stream::pair();
+        let worker = Arc::clone(self);
+        let context = LogContext::current().with_new_connection();
+        tokio::spawn(context.scope(async move {
+            worker.serve_connection(server).await;
+            drop(permit);
+        }));
+        Ok(client)
+    }
+
+    /// Has a connection that was just accepted go over to TLS, if the worker serves TLS. The
+    /// client has `read_timeout` to finish the handshake. Connections whose handshakes fail are
+    /// logged and closed.
+    async fn secure(&self, socket: Stream) -> Option<Stream> {
+        let acceptor = match &self.tls {
+            Some(acceptor) => acceptor,
+            None => return Some(socket),
+        };
+        let peer = socket.peer();
+        match tokio::time::timeout(self.config.read_timeout, socket.accept_tls(acceptor)).await {
+            Ok(Ok(socket)) => Some(socket),
+            // Clients that hang up without a word, like the health checks (see `health`),
+            // aren't worth a log line.
+            Ok(Err(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => None,
+            Ok(Err(err)) => {
+                log!("Closing connection from {}: TLS handshake failed: {}", peer, err);
+                None
+            },
+            Err(_) => {
+                log!("Closing connection from {}: TLS handshake timed out.", peer);
+                None
+            },
+        }
+    }
+
+    /// Runs `future`, erroring out if it does not finish within `duration`.
+    async fn with_timeout<T>(
+        duration: Duration, action: &str, future: impl Future<Output = Result<T>>
+    ) -> Result<T> {
+        match tokio::time::timeout(duration, future).await {
+            Ok(v) => v,
+            Err(_) => Err(WorkerError::new(
+                ErrKind::NetworkError,
+                &format!("Timed out after {:?} while {}.", duration, action)
+            ))?
+        }
+    }
+
+    // A client that connects and then goes quiet, or that sends only part of a frame, would
+    // otherwise hold its connection open forever. So every read and write on the wire goes
+    // through one of these three wrappers, each of which bounds the time it may take.
+
+    /// Reads the next frame header, waiting at most `idle_timeout` for it to arrive.
+    async fn read_header(&self, stream: &mut Stream) -> Result<Option<FrameHeader>> {
+        Worker::with_timeout(
+            self.config.idle_timeout,
+            "waiting for the next frame",
+            Worker::read_metadata_bytes(stream)
+        ).await
+    }
+
+    /// Reads a frame payload, waiting at most `read_timeout` for it to arrive. The payload is
+    /// checked against the checksum in the frame header, and decompressed if needs be, before it
+    /// is handed back.
+    async fn read_payload(
+        &self, stream: &mut Stream, header: FrameHeader
+    ) -> Result<Option<Vec<u8>>> {
+        let payload = Worker::with_timeout(
+            self.config.read_timeout,
+            "reading the frame payload",
+            Worker::read_payload_bytes(stream, header.payload_size)
+        ).await?;
+        match payload {
+            Some(payload) => {
+                header.check_payload(&payload)?;
+                Ok(Some(header.decode_payload(payload)?))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Writes a frame, waiting at most `write_timeout` for the write to go through.
+    async fn write_frame(
+        &self, stream: &mut Stream, signal: u8, request_id: u32, flags: u8, payload: &[u8]
+    ) -> Result<()> {
+        Worker::with_timeout(
+            self.config.write_timeout,
+            "writing a frame",
+            protocol::write_frame(stream, signal, request_id, flags, payload)
+        ).await
+    }
+
+    /// Spawns executor tasks that work through the job queue, until there are
+    /// `Tunables.executors` of them. Each executor runs one job at a time, so the number of
+    /// executors bounds how many jobs run concurrently.
+    ///
+    /// Executors are never stopped. When the number of executors is turned down (see `reload`),
+    /// the ones over the new number finish the job they are running, if any, and then sit idle
+    /// until it is turned back up.
+    pub(crate) fn spawn_executors(&self) {
+        let executors = self.tunables().executors;
+        while self.executors_spawned.load(Ordering::SeqCst) < executors {
+            let index = self.executors_spawned.fetch_add(1, Ordering::SeqCst);
+            let queue = Arc::clone(&self.queue);
+            let gc_lock = Arc::clone(&self.gc_lock);
+            let tunables = Arc::clone(&self.tunables);
+            let resized = Arc::clone(&self.executors_resized);
+            tokio::spawn(async move {
+                loop {
+                    // As in `MemoryBudget::wait_for_room`, the notification has to be waited on
+                    // from before we check.
+                    let resize = resized.notified();
+                    if index >= tunables.read().unwrap().executors {
+                        resize.await;
+                        continue;
+                    }
+                    // `pop` only takes a job off the queue once it returns, so giving up on it
+                    // part-way leaves the queue as it was.
+                    let queued_job = tokio::select! {
+                        queued_job = queue.pop() => queued_job,
+                        _ = resize => continue,
+                    };
+                    // The job is marked running before waiting on the garbage collector (see
+                    // `gc_lock`), so that it can be cancelled in the meantime, rather than being
+                    // neither queued nor running until the collector is done.
+                    let mut aborted = queue.mark_running(queued_job.id);
+                    let id = queued_job.id;
+                    let using_database = tokio::select! {
+                        using_database = gc_lock.read() => using_database,
+                        Ok(()) = &mut aborted => {
+                            log!("Job {} was cancelled before it started.", id);
+                            queue.mark_done(id, JobState::Cancelled);
+                            continue;
+                        },
+                    };
+                    let batch_size = tunables.read().unwrap().result_batch_size;
+                    let context = queued_job.job.log_context;
+                    // Note that `execute` consumes the queued job, dropping its end of the result
+                    // stream before the job is marked done. A cancelled job is stopped by simply
+                    // dropping the `execute` future, wherever it has got to. An open transaction
+                    // is rolled back when dropped, and a half-loaded table has no fingerprint
+                    // yet, so the next job to need it loads it again.
+                    let state = context.scope(async {
+                        tokio::select! {
+                            state = Worker::execute(&queue, queued_job, batch_size) => state,
+                            Ok(()) = aborted => {
+                                log!("Job {} was cancelled whilst running.", id);
+                                JobState::Cancelled
+                            },
+                        }
+                    }).await;
+                    queue.mark_done(id, state);
+                    drop(using_database);
+                }
+            });
+        }
+    }
+
+    /// Spawns the task that collects garbage in the worker's database every `gc_interval` (see
+    /// `gc`), unless that is zero.
+    pub(crate) fn spawn_gc(&self) {
+        let interval = self.config.gc_interval;
+        if interval == Duration::from_secs(0) {
+            return;
+        }
+        let gc_lock = Arc::clone(&self.gc_lock);
+        let retention = self.config.table_retention;
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            // The first tick comes straight away. There's nothing to collect yet.
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let collecting = match gc_lock.try_write() {
+                    Ok(guard) => guard,
+                    Err(_) => {
+                        log!("Skipping garbage collection, since the database is in use.");
+                        continue;
+                    },
+                };
+                match gc::collect_garbage(retention).await.map_err(|err| err.to_string()) {
+                    Ok(dropped) if dropped.is_empty() => log!("Collected garbage."),
+                    Ok(dropped) => log!("Collected garbage, dropping {}.", dropped.join(", ")),
+                    Err(message) => log_error!("Could not collect garbage: {}", message),
+                }
+                drop(collecting);
+            }
+        });
+    }
+
+    /// Executes a job pulled off the job queue, returning its final state. Errors are logged and
+    /// recorded, not bubbled up: a job failing should not take its executor down with it.
+    ///
+    /// Neither should a job panicking, e.g. on a bug in parsing its files or decoding its
+    /// results. The panic is caught, and the job fails with it like it would with an error, so
+    /// whoever is waiting on its results hears about it in an INTERNAL error.
+    async fn execute(queue: &JobQueue, queued_job: QueuedJob, batch_size: usize) -> JobState {
+        log!("Executing job {}.", queued_job.id);
+        // As elsewhere, the error is turned into a `String` straight away, because it isn't `Send`.
+        let outcome = AssertUnwindSafe(Worker::run_job(queue, &queued_job, batch_size))
+            .catch_unwind()
+            .await
+            .map_err(|panic| format!("Job panicked: {}", panic_message(panic)))
+            .and_then(|ran| ran.map_err(|err| err.to_string()));
+        // Whether or not the job worked out, its tables were just used.
+        let leased = queued_job.job.lease_tables().await.map_err(|err| err.to_string());
+        if let Err(message) = leased {
+            log_error!("Could not lease the tables of job {}: {}", queued_job.id, message);
+        }
+        // Results that nobody read in time were dropped, so what's left of them would only mislead
+        // whoever comes for them later.
+        let outcome = outcome.and_then(|n_rows| match queued_job.results_abandoned() {
+            true => {
+                queue.expire_results(queued_job.id);
+                Err("Nobody read the results in time, so they were dropped.".to_owned())
+            },
+            false => Ok(n_rows),
+        });
+        match outcome {
+            Ok(n_rows) => {
+                log!("Done processing job {} ({} result rows)!", queued_job.id, n_rows);
+                JobState::Done(n_rows)
+            },
+            Err(message) => {
+                log_error!("Job {} failed: {}", queued_job.id, message);
+                JobState::Failed(message)
+            },
+        }
+    }
+
+    async fn run_job(queue: &JobQueue, queued_job: &QueuedJob, batch_size: usize) -> Result<u64> {
+        let job = &queued_job.job;
+        if job.workload.get_dry_run() {
+            let plan = job.plan(&job.s3_client()?).await?;
+            let mut batch = response::ResultBatch::new();
+            batch.set_job_id(queued_job.id);
+            batch.set_last(true);
+            batch.set_plan(plan);
+            queued_job.send_result(batch).await;
+            return Ok(0);
+        }
+        let cache_key = if job.workload.get_use_result_cache() {
+            Some(result_cache_key(&job.workload, &job.s3_client()?).await?)
+        } else {
+            None
+        };
+        if let Some(key) = &cache_key {
+            if let Some(batches) = read_cached_results(key)? {
+                log!("Serving job {} out of the result cache.", queued_job.id);
+                let mut n_rows: u64 = 0;
+                for mut batch in batches {
+                    n_rows += batch.get_rows().len() as u64;
+                    batch.set_job_id(queued_job.id);
+                    batch.set_cached(true);
+                    queued_job.send_result(batch).await;
+                }
+                return Ok(n_rows);
+            }
+        }
+
+        job.build_with_progress(job.s3_client()?, |progress| {
+            queue.set_progress(queued_job.id, progress)
+        }).await?;
+        // Results are streamed to whoever is reading them as they are produced, and the job waits
+        // for a reader that falls behind. If the reader hung up half-way through, the rest of the
+        // batches are simply dropped. If the results are headed for the result cache, we also
+        // have to keep a copy of them around until the job is done.
+        let mut cache_batches = vec![];
+        let n_rows = job.run_with_progress(queued_job.id, batch_size, |batch| {
+            if cache_key.is_some() { cache_batches.push(batch.clone()); }
+            queued_job.send_result(batch)
+        }, |progress| queue.set_progress(queued_job.id, progress)).await?;
+        if let Some(key) = &cache_key {
+            write_cached_results(key, &cache_batches)?;
+        }
+        Ok(n_rows)
+    }
+
+    /// Checks that every op's statement parses, and that it falls within `allowed_statements`
+    /// (if set). The first op that doesn't is reported as an `InvalidOp`. The workload also has
+    /// to be one the worker can run at all (see `capabilities::check_workload`).
+    pub fn validate(&self, workload: &workload::Workload) -> Result<()> {
+        validate_workload(workload, self.config.allowed_statements.as_deref())?;
+        capabilities::check_workload(workload, &self.advertised)
+    }
+
+    /// Loads files into the worker's database ahead of time (see `workload::Preload`). This
+    /// happens right away, rather than on the job queue, and the files are loaded just as a
+    /// job's would be, so a table that is already up to date isn't loaded again.
+    pub async fn preload(&self, files: Vec<workload::File>) -> Result<()> {
+        let mut op = workload::Op::new();
+        op.set_targets(RepeatedField::from_vec(files));
+        let mut workload = workload::Workload::new();
+        workload.set_ops(RepeatedField::from_vec(vec![op]));
+        let tunables = self.tunables();
+        let mut job = Job::with_options(workload, &self.config.database).await?;
+        job.parallel_loads = tunables.parallel_loads;
+        job.disk_limits = tunables.disk_limits;
+        job.s3_options = self.config.s3.clone();
+        job.s3_credentials = self.config.s3_credentials.clone();
+        job.memory = Arc::clone(&self.memory);
+        job.memory_wait = tunables.memory_wait;
+        let _using_database = self.gc_lock.read().await;
+        job.build(job.s3_client()?).await?;
+        job.lease_tables().await
+    }
+
+    /// Validates a workload and queues it for execution. Both transports submit work through
+    /// here. A workload that doesn't pass `validate` is turned away before anything is queued.
+    pub async fn submit(&self, workload: workload::Workload) -> Result<response::Ack> {
+        // The job is logged under a UUID of its own from here on, wherever it runs (see `log`).
+        let context = LogContext::current().with_new_job();
+        context.scope(async {
+            log!("Workload plaintext representation is: {:?}", workload);
+            // A workload we already have (see `Workload.idempotency_key`) isn't run again: the
+            // client is told the ID of the job it was first queued as instead.
+            if let Some(job_id) = self.queue.keyed_job(workload.get_idempotency_key()) {
+                log!(
+                    "Workload has idempotency key {:?}, which job {} was queued under already.",
+                    workload.get_idempotency_key(), job_id
+                );
+                return Ok(self.ack(job_id));
+            }
+            // Validation comes first, since setting up the job's database is not free.
+            self.validate(&workload)?;
+            let job = self.create_job(workload, context).await?;
+
+            // Execution happens on the executor tasks. All we do here is queue the job and tell
+            // the client which ID it got.
+            let job_id = self.queue.push(job);
+            log!("Queued workload as job {}.", job_id);
+            Ok(self.ack(job_id))
+        }).await
+    }
+
+    /// Queues a checkpointed job again, to pick up where it left off (see `checkpoint`), as
+    /// `submit` would. Returns `None` if there is no checkpoint under the given idempotency key.
+    /// If the job is still here (e.g. because the client lost touch with us, rather than us
+    /// going away), the client is told its ID, and it carries on as it was.
+    pub async fn resume(&self, key: &str) -> Result<Option<response::Ack>> {
+        let context = LogContext::current().with_new_job();
+        context.scope(async {
+            if let Some(job_id) = self.queue.keyed_job(key) {
+                log!("Job {} under idempotency key {:?} is still here.", job_id, key);
+                return Ok(Some(self.ack(job_id)));
+            }
+            let checkpoint = match checkpoint::load(&mut Database::connect().await?, key).await? {
+                Some(checkpoint) => checkpoint,
+                None => return Ok(None),
+            };
+            let mut workload = checkpoint.workload.clone();
+            // Tables that were loaded once don't need loading again, unless they're out of date.
+            if checkpoint.built {
+                workload.set_force_reload(false);
+            }
+            // What was allowed when the job was first submitted may not be any longer.
+            self.validate(&workload)?;
+            let mut job = self.create_job(workload, context).await?;
+            log!(
+                "Resuming the job checkpointed under {:?}, with {} op(s) done.",
+                key, checkpoint.done_ops.len()
+            );
+            job.resumed_from = Some(checkpoint);
+            let job_id = self.queue.push(job);
+            log!("Queued resumed workload as job {}.", job_id);
+            Ok(Some(self.ack(job_id)))
+        }).await
+    }
+
+    /// Snapshots the worker's database to the given S3 path (see `snapshot`). Garbage isn't
+    /// collected whilst the snapshot is taken, so no table is dropped halfway through the copy.
+    pub async fn snapshot(&self, path: &str) -> Result<response::Snapshot> {
+        let client = credentials::create_s3_client(&self.config.s3_credentials)?
+            .with_options(self.config.s3.clone());
+        let _using_database = self.gc_lock.read().await;
+        snapshot::take(&client, path).await
+    }
+
+    /// Lists, evicts, or clears what the worker has cached (see `eviction`). Evicting and
+    /// clearing wait for every job using the database to finish first.
+    pub async fn cache(&self, command: &workload::CacheCommand) -> Result<response::CacheReport> {
+        let mut conn = Database::connect().await?;
+        let report = match command.get_action() {
+            workload::CacheCommand_Action::LIST => {
+                let _using_database = self.gc_lock.read().await;
+                let mut report = response::CacheReport::new();
+                report.set_entries(RepeatedField::from_vec(eviction::list(&mut conn).await?));
+                report
+            },
+            workload::CacheCommand_Action::EVICT => {
+                let _evicting = self.gc_lock.write().await;
+                eviction::evict(&mut conn, command.get_path()).await?
+            },
+            workload::CacheCommand_Action::CLEAR => {
+                let _clearing = self.gc_lock.write().await;
+                eviction::clear(&mut conn).await?
+            },
+        };
+        conn.close();
+        Ok(report)
+    }
+
+    async fn create_job(&self, workload: workload::Workload, context: LogContext) -> Result<Job> {
+        let tunables = self.tunables();
+        let mut job = Job::with_options(workload, &self.config.database).await?;
+        job.parallel_loads = tunables.parallel_loads;
+        job.disk_limits = tunables.disk_limits;
+        job.s3_options = self.config.s3.clone();
+        job.s3_credentials = self.config.s3_credentials.clone();
+        job.memory = Arc::clone(&self.memory);
+        job.memory_wait = tunables.memory_wait;
+        job.slow_ops = self.slow_ops.clone();
+        job.log_context = context;
+        Ok(job)
+    }
+
+    fn ack(&self, job_id: u64) -> response::Ack {
+        let mut ack = response::Ack::new();
+        ack.set_job_id(job_id);
+        ack.set_queue_depth(self.queue.depth() as u32);
+        ack
+    }
+
+    /// The parts of the worker's configuration that can be changed whilst it runs, as they
+    /// currently are.
+    pub fn tunables(&self) -> Tunables {
+        self.tunables.read().unwrap().clone()
+    }
+
+    /// Reads the worker's configuration again (see `WorkerConfig::from_env`), and applies
+    /// whatever `Tunables` changed, without restarting the worker, or dropping what it has
+    /// cached. Returns the names of the tunables that changed. Changes to anything else take
+    /// effect the next time the worker is restarted.
+    ///
+    /// Jobs that are already running carry on as they were (e.g. with the disk limits they
+    /// started out with), apart from waiting on the new memory ceiling.
+    pub fn reload(&self) -> Result<Vec<&'static str>> {
+        let reloaded = Tunables::of(&WorkerConfig::from_env()?);
+        let mut tunables = self.tunables.write().unwrap();
+        let changed = tunables.changes(&reloaded);
+        if reloaded.max_connections > tunables.max_connections {
+            self.connection_permits.add_permits(
+                reloaded.max_connections - tunables.max_connections
+            );
+        } else if reloaded.max_connections < tunables.max_connections {
+            // Connections that are already being served aren't hung up on. Their permits are
+            // taken out of circulation as they are handed back instead.
+            let surplus = tunables.max_connections - reloaded.max_connections;
+            let permits = Arc::clone(&self.connection_permits);
+            tokio::spawn(async move {
+                for _ in 0..surplus {
+                    match permits.acquire().await {
+                        Ok(permit) => permit.forget(),
+                        Err(_) => return,
+                    }
+                }
+            });
+        }
+        self.memory.set_ceiling(reloaded.memory_ceiling);
+        reloaded.log_level.apply();
+        *tunables = reloaded;
+        drop(tunables);
+        // Executors are only spawned once the worker is serving (see `listen`).
+        if self.executors_spawned.load(Ordering::SeqCst) > 0 {
+            self.spawn_executors();
+        }
+        self.executors_resized.notify_waiters();
+        match changed.is_empty() {
+            true => log!("Reloaded the configuration, which had not changed."),
+            false => log!("Reloaded the configuration, changing {}.", changed.join(", ")),
+        }
+        Ok(changed)
+    }
+
+    /// Stops the worker from taking new work. Jobs that are already queued or running carry
+    /// on to the end, and their results can still be fetched.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the worker has been asked to drain.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Has the worker drain, and then stop (see `stopped`).
+    pub fn stop(&self) {
+        self.drain();
+        // This can only fail if there are no receivers, but we hold one ourselves.
+        let _ = self.stop_tx.send(true);
+    }
+
+    /// Waits until the worker has been asked to `stop`, and has since drained: its jobs are
+    /// done, and their results have been read (or have expired). It's up to whoever is waiting
+    /// on this (e.g. the worker binary) to actually stop.
+    pub async fn stopped(&self) {
+        let mut stop_rx = self.stop_rx.clone();
+        while !*stop_rx.borrow() {
+            if stop_rx.changed().await.is_err() {
+                return;
+            }
+        }
+        // A result batch holds on to its share of the memory budget until it's read, so the
+        // last of the results are only out of the worker once none is held. Neither is
+        // announced, hence the polling.
+        while !self.queue.is_idle() || self.memory.used() > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Reports how busy the worker is, and what it knows about the tables in its database (see
+    /// `stats`).
+    pub async fn status(&self) -> response::WorkerStatus {
+        let mut status = response::WorkerStatus::new();
+        status.set_draining(self.is_draining());
+        status.set_queue_depth(self.queue.depth() as u32);
+        status.set_running_jobs(self.queue.running() as u32);
+        status.set_capabilities(RepeatedField::from_vec(self.capabilities.clone()));
+        // What the worker advertised at startup, as far as it has been reloaded since.
+        let tunables = self.tunables();
+        let mut advertised = self.advertised.clone();
+        advertised.set_executors(tunables.executors as u32);
+        advertised.set_memory_ceiling(tunables.memory_ceiling.unwrap_or(0));
+        advertised.set_disk_quota(tunables.disk_limits.quota.unwrap_or(0));
+        status.set_advertised(advertised);
+        status.set_slow_ops(self.slow_ops.as_ref().map_or(0, |log| log.count()));
+        status.set_memory_used(self.memory.used());
+        status.set_jobs(RepeatedField::from_vec(self.queue.progress()));
+        // Not being able to read the statistics (e.g. because the database is being vacuumed)
+        // is no reason not to answer.
+        let tables = Worker::table_stats().await.map_err(|err| err.to_string());
+        match tables {
+            Ok(tables) => status.set_tables(RepeatedField::from_vec(tables)),
+            Err(message) => log_error!("Could not read the table statistics: {}", message),
         }
+        status
+    }
+
+    async fn table_stats() -> Result<Vec<response::TableStats>> {
+        let mut conn = Database::connect().await?;
+        stats::read_all(&mut conn).await
     }
 
-    async fn read_metadata_bytes(stream: &mut TcpStream) -> Result<Option<[u8; 3]>> {
+    async fn read_metadata_bytes<S: AsyncRead + Unpin>(
+        stream: &mut S
+    ) -> Result<Option<FrameHeader>> {
         // `read` is inherited from the `Read` trait, with a `buf: &mut [u8]` signature. Here,
         // `&mut` means a mutable pointer reference, and `[u8]` specifies an array of unsigned
         // 8-bit ints.
@@ -52,11 +964,12 @@ impl Worker {
         // correct on its own because Rust won't cast the type for you automatically. You have
         // to cast it yourself--done so here using `as`.
         //
-        // Protocol buffers are arbitrarily sized, but the array TcpStream reads into needs to be
+        // Protocol buffers are arbitrarily sized, but the array the stream reads into needs to be
         // of a fixed size, because Rust. So we'll split the job across two buffers. The first
-        // buffer reads three bytes of metadata from the header: a one-byte signal, and two bytes
-        // describing the incoming protocol buffer's size.
-        let mut scheduler_request_metadata_buffer: [u8; 3] = [0 as u8; 3];
+        // buffer reads the fixed-size metadata from the header: a one-byte signal, two bytes
+        // describing the incoming protocol buffer's size, and a four-byte request ID (see
+        // `protocol.rs`).
+        let mut scheduler_request_metadata_buffer: [u8; HEADER_LENGTH] = [0 as u8; HEADER_LENGTH];
 
         // `read` will pull a number of bytes into `stream` in the range (0, usize). Reading zero
         // bytes indicates that the buffer recieved was zero bytes in length, or that the reader
@@ -80,52 +993,222 @@ impl Worker {
         // this case we wait until the sum of all segments received is at least `usize` in length
         // before proceeding forward.
         //
-        // In this case we want to hold until we have successfully read three bytes from the
-        // stream. If we see a nil read, indicating the client closed the connection, we close
+        // In this case we want to hold until we have successfully read the whole header from
+        // the stream. If we see a nil read, indicating the client closed the connection, we close
         // the socket and yield.
+        //
+        // Note that the running total has to live outside of the loop, and each read has to go
+        // into the part of the buffer that hasn't been filled yet. Otherwise a header split
+        // across two reads would have its second half written over its first half.
+        let mut total_bytes_received: usize = 0;
         loop {
-            let mut total_bytes_received: usize = 0;
-
-            stream.readable().await?;
-            let rsize = stream.try_read(&mut scheduler_request_metadata_buffer)?;
+            let rsize = stream.read(
+                &mut scheduler_request_metadata_buffer[total_bytes_received..]
+            ).await?;
             if rsize == 0 {
-                println!("Client sent empty (nil) input before closing the connection.");
+                log!("Client sent empty (nil) input before closing the connection.");
                 return Ok(None);
             } else {
                 total_bytes_received += rsize;
-                if total_bytes_received == 3 {
-                    return Ok(Some(scheduler_request_metadata_buffer));
+                if total_bytes_received == HEADER_LENGTH {
+                    return Ok(Some(FrameHeader::from_bytes(scheduler_request_metadata_buffer)));
                 }
             }
         }
     }
 
-    async fn read_protobuf_bytes(
-        stream: &mut TcpStream, buffer_length: usize
-    ) -> Result<Option<workload::Workload>> {
+    async fn read_payload_bytes<S: AsyncRead + Unpin>(
+        stream: &mut S, buffer_length: usize
+    ) -> Result<Option<Vec<u8>>> {
         // Allocate a fixed-size buffer matching the to-be-received size.
         // Rust differentiates between capacity and length. Setting capacity with_capacity
         // reserves the underlying memory, but it doesn't actually assign that length to
         // the vector, it's still length 0!
         let mut scheduler_request_buffer = Vec::<u8>::with_capacity(buffer_length);
         scheduler_request_buffer.resize(buffer_length, 0);
-        let scheduler_request_buffer = &mut scheduler_request_buffer[0..buffer_length];
-        loop {
-            let mut total_bytes_received: usize = 0;
+        // A read into an empty buffer returns zero bytes, which we would mistake for an EOF.
+        if buffer_length == 0 { return Ok(Some(scheduler_request_buffer)); }
 
-            stream.readable().await?;
-            let rsize = stream.try_read(scheduler_request_buffer)?;
+        let mut total_bytes_received: usize = 0;
+        loop {
+            let rsize = stream.read(
+                &mut scheduler_request_buffer[total_bytes_received..buffer_length]
+            ).await?;
             if rsize == 0 {
-                println!("Client closed the connection.");
+                log!("Client closed the connection.");
                 return Ok(None);
             } else {
                 total_bytes_received += rsize;
                 if total_bytes_received == buffer_length { break; }
             }
         }
-        println!("Received work buffer with length {:?}.", buffer_length);
-        let workload = workload::Workload::parse_from_bytes(scheduler_request_buffer)?;
-        Ok(Some(workload))
+        Ok(Some(scheduler_request_buffer))
+    }
+
+    /// Reads and parses a protobuf payload (e.g. a workload). A payload that fails its checksum
+    /// or is not a valid protobuf message results in a `ProtocolError`.
+    async fn read_protobuf_bytes<M: Message>(
+        &self, stream: &mut Stream, header: FrameHeader
+    ) -> Result<Option<M>> {
+        let scheduler_request_buffer =
+            match self.read_payload(stream, header).await? {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+        log!("Received buffer with length {:?}.", header.payload_size);
+        Ok(Some(protocol::parse_message(&scheduler_request_buffer)?))
+    }
+
+    /// Reads a request's payload, as `read_protobuf_bytes` does. A payload that doesn't parse is
+    /// no reason to end the session, so a `ProtocolError` comes back as the inner `Err`, for the
+    /// caller to answer with an ERROR frame; only its message is kept, since our errors are
+    /// `Box<dyn Error>`, which is not `Send`, and holding one across the `.await` that writes
+    /// the ERROR frame would make the whole future unusable with `tokio::spawn`. `None` means
+    /// that the client hung up.
+    async fn read_request<M: Message>(
+        &self, stream: &mut Stream, header: FrameHeader
+    ) -> Result<Option<std::result::Result<M, String>>> {
+        match self.read_protobuf_bytes::<M>(stream, header).await {
+            Ok(request) => Ok(request.map(Ok)),
+            Err(err) => match err.downcast_ref::<WorkerError>() {
+                Some(WorkerError::ProtocolError(_)) => Ok(Some(Err(err.to_string()))),
+                _ => Err(err),
+            },
+        }
+    }
+
+    /// Sends the client an ERROR frame in response to the given request.
+    async fn write_error(
+        &self,
+        stream: &mut Stream,
+        request_id: u32,
+        flags: u8,
+        kind: response::ErrorResponse_Kind,
+        message: &str,
+    ) -> Result<()> {
+        self.write_error_for_op(stream, request_id, flags, kind, message, -1).await
+    }
+
+    /// Like `write_error`, but for an error about one of a workload's ops in particular.
+    async fn write_error_for_op(
+        &self,
+        stream: &mut Stream,
+        request_id: u32,
+        flags: u8,
+        kind: response::ErrorResponse_Kind,
+        message: &str,
+        op_index: i32,
+    ) -> Result<()> {
+        let mut error = response::ErrorResponse::new();
+        error.set_kind(kind);
+        error.set_message(message.to_owned());
+        error.set_op_index(op_index);
+        let payload = error.write_to_bytes()?;
+        self.write_frame(stream, protocol::ERROR, request_id, flags, &payload).await
+    }
+
+    /// Sends a client that is over its rate limit an ERROR frame saying so, and when it may try
+    /// again.
+    async fn write_rate_limited(&self, stream: &mut Stream, limited: &RateLimited) -> Result<()> {
+        let payload = limited.craft_error().write_to_bytes()?;
+        self.write_frame(stream, protocol::ERROR, 0, 0, &payload).await
+    }
+
+    /// Streams a job's results to the client as RESULTS frames, as the job produces them. If the
+    /// job fails (or was cancelled) the stream ends with an ERROR frame instead of a batch marked
+    /// `last`.
+    ///
+    /// Note that the session is given over to the results until they are all sent, so a client
+    /// that wants to keep submitting work in the meantime should do so over another connection.
+    async fn send_results(
+        &self, stream: &mut Stream, header: FrameHeader, job_id: u64, send_progress: bool
+    ) -> Result<()> {
+        let flags = header.response_flags();
+        let mut results = match self.queue.take_results(job_id) {
+            Some(v) => v,
+            None => {
+                let message = format!(
+                    "No results for job {}: there is no such job, or they were already fetched.",
+                    job_id
+                );
+                return self.write_error(
+                    stream,
+                    header.request_id,
+                    flags,
+                    response::ErrorResponse_Kind::NOT_FOUND,
+                    &message
+                ).await;
+            },
+        };
+
+        // Whilst waiting on the next batch, the client is told how the job is getting on every
+        // so often, if it asked to be. Nothing is sent if the job hasn't moved on since the last
+        // time, e.g. because it is still queued.
+        let mut ticks = tokio::time::interval(protocol::PROGRESS_INTERVAL);
+        let mut reported = None;
+        loop {
+            let batch = tokio::select! {
+                batch = results.recv() => batch,
+                _ = ticks.tick(), if send_progress => {
+                    let progress = self.queue.job_progress(job_id);
+                    if progress.is_some() && progress != reported {
+                        let payload = progress.as_ref().unwrap().write_to_bytes()?;
+                        self.write_frame(
+                            stream, protocol::PROGRESS, header.request_id, flags, &payload
+                        ).await?;
+                        reported = progress;
+                    }
+                    continue;
+                },
+            };
+            // The batch stops counting against the memory budget once it's off the queue.
+            let batch = match batch {
+                Some((batch, _)) => batch,
+                None => break,
+            };
+            for piece in split_result_batch(batch, protocol::MAX_PAYLOAD_SIZE)? {
+                self.write_frame(
+                    stream, protocol::RESULTS, header.request_id, flags, &piece.write_to_bytes()?
+                ).await?;
+            }
+        }
+
+        // The result stream closes once the job is over, one way or another.
+        let message = match self.queue.wait(job_id).await {
+            Some(JobState::Failed(message)) => message,
+            Some(JobState::Cancelled) => format!("Job {} was cancelled.", job_id),
+            _ => return Ok(()),
+        };
+        self.write_error(
+            stream, header.request_id, flags, response::ErrorResponse_Kind::INTERNAL, &message
+        ).await
+    }
+
+    /// Performs the AUTH handshake. The worker sends the client a random nonce, which the client
+    /// must answer with an AUTH frame carrying the HMAC-SHA256 of that nonce, keyed on the shared
+    /// secret. Returns whether or not the client successfully authenticated.
+    async fn authenticate(&self, stream: &mut Stream, secret: &str) -> Result<bool> {
+        let nonce = generate_nonce();
+        self.write_frame(stream, protocol::NONCE, 0, 0, &nonce).await?;
+
+        let auth_header = match self.read_header(stream).await? {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        if auth_header.signal != protocol::AUTH {
+            log!(
+                "Client sent signal (first byte {}) without authenticating first.",
+                auth_header.signal
+            );
+            return Ok(false);
+        }
+
+        auth_header.check_version()?;
+        let signature = match self.read_payload(stream, auth_header).await? {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        Ok(verify_nonce(secret, &nonce, &signature))
     }
 
     /// Displays the result of a computation.
@@ -157,99 +1240,648 @@ impl Worker {
         //
         // So basically, as far as I can tell, decoding the values requires EITHER knowing the
         // type ahead of time, OR just trying every possible output type in sequence. This
-        // seems...super shitty. =(
+        // seems...super shitty. =( `result::craft_value` does the latter, and is what puts
+        // results on the wire, so we lean on it here too.
         //
         // Here is the conversion chart: https://docs.rs/sqlx/0.5.1/sqlx/sqlite/types/index.html.
         for row in rows.iter() {
             out += "\n";
             out += "|";
-            for i in 0..row.len() {
-                // Is it an INTEGER?
-                let v: std::result::Result<i64, sqlx::Error> = row.try_get(i);
-                if v.is_ok() {
-                    out += &v.unwrap().to_string();
-                    out += "|";
-                    continue;
-                }
-
-                // Is it a TEXT?
-                let v: std::result::Result<String, sqlx::Error> = row.try_get(i);
-                if v.is_ok() {
-                    out += &v.unwrap();
-                    out += "|";
-                    continue;
-                }
-
-                // TODO: add more possible types. Or, investigate online if there's a better way
-                // to do this, because this seems kind of mental.
-
-                Err(WorkerError::new(
-                    ErrKind::DatabaseError, 
-                    "Result set has output type not understood by the worker."
-                ))?
+            for value in craft_row(row)?.get_values() {
+                out += &format_value(value);
+                out += "|";
             }
         }
-        println!("{}", out);
+        log!("{}", out);
         Ok(())
     }
 
     /// Handles a connections into the worker's socket listener.
-    pub async fn handle_connection(&self, stream: &mut TcpStream) -> Result<()> {
-        // read_metadata_bytes handles reading the first three bytes of the stream. It returns
-        // Result<Option<[u8, 3]>>. Possible return values are: an error, if the stream reader
-        // throws one; an Ok([u8, 3]), if all is successful; or a None, if the stream is closed,
-        // probably by the client, before three bytes are successfully read.
-        let scheduler_request_metadata_buffer =
-            match Worker::read_metadata_bytes(stream).await {
-                Ok(v) => match v {
-                    Some(v) => v,
-                    None => return Ok(()),
-                },
-                Err(e) => return Err(e),
+    ///
+    /// A connection is a session: the client may send any number of frames over it (e.g. PING,
+    /// WORK, WORK, SHUTDOWN), which we read and answer one at a time until the client either
+    /// hangs up or sends a SHUTDOWN. Every response carries the request ID of the frame it
+    /// answers, so the client can tell which response belongs to which request.
+    pub async fn handle_connection(&self, stream: &mut Stream) -> Result<()> {
+        // If a shared secret is configured, the client has to prove it knows it before we will
+        // so much as look at its first real frame. Note that a failed handshake is not an error
+        // from the worker's point of view: we just hang up on the client.
+        if let Some(secret) = &self.config.secret {
+            if !self.authenticate(stream, secret).await? {
+                log_error!("Client failed to authenticate, closing the connection.");
+                return Ok(());
+            }
+        }
+
+        loop {
+            // read_metadata_bytes handles reading the header of the next frame in the stream. It
+            // returns Result<Option<FrameHeader>>. Possible return values are: an error, if the
+            // stream reader throws one; an Ok(FrameHeader), if all is successful; or a None, if
+            // the stream is closed, probably by the client, before a full header is read.
+            let header = match self.read_header(stream).await? {
+                Some(v) => v,
+                None => return Ok(()),
             };
+            // If the client speaks a different version of the protocol we can't trust anything
+            // else in the header (including the payload size), so all we can do is tell it so
+            // and hang up.
+            let version_error = header.check_version().err().map(|err| err.to_string());
+            if let Some(message) = version_error {
+                log!("Closing connection: {}", message);
+                // Nothing in the header can be trusted, so we reply uncompressed.
+                self.write_error(
+                    stream, header.request_id, 0, response::ErrorResponse_Kind::PROTOCOL, &message
+                ).await?;
+                return Ok(());
+            }
+            log_debug!(
+                "Read frame with signal {} (request {}, {} byte payload, flags {:#04x}).",
+                header.signal, header.request_id, header.payload_size, header.flags
+            );
+            if !self.handle_frame(stream, header).await? {
+                return Ok(());
+            }
+        }
+    }
 
+    /// Handles a single frame of a session. Returns whether or not the session should continue.
+    async fn handle_frame(&self, stream: &mut Stream, header: FrameHeader) -> Result<bool> {
         // The first byte describes the signal type: PING, WORK, or SHUTDOWN. When a PING or
-        // SHUTDOWN is received, all of the other bytes are ignored.
-        match scheduler_request_metadata_buffer[0] {
-            0 => println!("Scheduler sent PING signal (first byte 0)."),
-            1 => {
-                println!("Scheduler sent WORK signal (first byte 1).");
+        // SHUTDOWN is received, the payload is ignored.
+        match header.signal {
+            protocol::PING => {
+                log!("Scheduler sent PING signal (request {}).", header.request_id);
+                let status = self.status().await;
+                self.write_frame(
+                    stream,
+                    protocol::STATUS,
+                    header.request_id,
+                    header.response_flags(),
+                    &status.write_to_bytes()?
+                ).await?;
+            },
+            protocol::WORK => {
+                log!("Scheduler sent WORK signal (request {}).", header.request_id);
                 // The second and third byte describe the protocol buffer size (in bytes).
                 // The maximum size is 2**16=65636 bytes, e.g. ~65kB. This should be sufficient.
-                let buffer_length: usize = 256 *
-                    (scheduler_request_metadata_buffer[1] as usize) +
-                    (scheduler_request_metadata_buffer[2] as usize);
-
-                // read_protobuf_bytes handles reading the protobuf message out of the stream. Its
-                // return type and usage notes are the same as the ones for
-                // scheduler_request_metadata_buffer just above.
-                let workload = match
-                    Worker::read_protobuf_bytes(stream, buffer_length).await {
-                        Ok(v) => match v {
-                            Some(v) => v,
-                            None => return Ok(()),    
-                        },
-                        Err(e) => return Err(e),
-                    };
+                //
+                // read_request handles reading the protobuf message out of the stream. A
+                // corrupted payload is not fatal to the session: the header told us how long the
+                // payload was, so the stream is still correctly positioned at the start of the
+                // next frame. We tell the client what went wrong and carry on.
+                let workload = match self.read_request::<workload::Workload>(
+                    stream, header
+                ).await? {
+                    Some(request) => request,
+                    None => return Ok(false),
+                };
+                let workload = match workload {
+                    Ok(v) => v,
+                    Err(message) => {
+                        log!("Rejected WORK frame: {}", message);
+                        self.write_error(
+                            stream,
+                            header.request_id,
+                            header.response_flags(),
+                            response::ErrorResponse_Kind::PROTOCOL,
+                            &message
+                        ).await?;
+                        return Ok(true);
+                    },
+                };
+
+                if self.is_draining() {
+                    log!("Turned away workload, the worker is draining.");
+                    self.write_error(
+                        stream,
+                        header.request_id,
+                        header.response_flags(),
+                        response::ErrorResponse_Kind::DRAINING,
+                        "The worker is draining, and not taking new work."
+                    ).await?;
+                    return Ok(true);
+                }
 
-                println!("Workload plaintext representation is: {:?}", workload);
-                let job = Job::new(workload).await?;
-                job.build(create_new_s3_client()).await?;
-                let result = job.run().await?;
-                println!("Workload computation result is:");
-                Worker::print_result(result)?;
-                println!("Done processing workload!");
+                // A workload that fails validation is likewise answered with an ERROR frame,
+                // rather than by hanging up on the client. So is one that couldn't be queued for
+                // any other reason (e.g. because its database couldn't be set up): every workload
+                // we read is answered, with an ACK or with an ERROR, before it runs. That way a
+                // client that gets no answer at all knows that we went away, taking the job with
+                // us, and can safely send it elsewhere.
+                let ack = match self.submit(workload).await {
+                    Ok(v) => Ok(v),
+                    Err(err) => match validation_failure(err.as_ref()) {
+                        Some((message, op_index)) => {
+                            Err((response::ErrorResponse_Kind::VALIDATION, message, op_index))
+                        },
+                        None => Err((response::ErrorResponse_Kind::INTERNAL, err.to_string(), -1)),
+                    },
+                };
+                let ack = match ack {
+                    Ok(v) => v,
+                    Err((kind, message, op_index)) => {
+                        log!("Rejected workload: {}", message);
+                        self.write_error_for_op(
+                            stream,
+                            header.request_id,
+                            header.response_flags(),
+                            kind,
+                            &message,
+                            op_index
+                        ).await?;
+                        return Ok(true);
+                    },
+                };
+                self.write_frame(
+                    stream,
+                    protocol::ACK,
+                    header.request_id,
+                    header.response_flags(),
+                    &ack.write_to_bytes()?
+                ).await?;
+            },
+            protocol::VALIDATE => {
+                log!("Scheduler sent VALIDATE signal (request {}).", header.request_id);
+                // A dry run of WORK: the workload gets the same checks, but is never queued.
+                let workload = match self.read_request::<workload::Workload>(
+                    stream, header
+                ).await? {
+                    Some(request) => request,
+                    None => return Ok(false),
+                };
+                let failure = match workload {
+                    Ok(workload) => match self.validate(&workload) {
+                        Ok(()) => None,
+                        Err(err) => match validation_failure(err.as_ref()) {
+                            Some((message, op_index)) => Some((
+                                response::ErrorResponse_Kind::VALIDATION, message, op_index
+                            )),
+                            None => return Err(err),
+                        },
+                    },
+                    Err(message) => Some((response::ErrorResponse_Kind::PROTOCOL, message, -1)),
+                };
+                match failure {
+                    None => self.write_frame(
+                        stream, protocol::VALID, header.request_id, header.response_flags(), &[]
+                    ).await?,
+                    Some((kind, message, op_index)) => {
+                        log!("Rejected VALIDATE frame: {}", message);
+                        self.write_error_for_op(
+                            stream,
+                            header.request_id,
+                            header.response_flags(),
+                            kind,
+                            &message,
+                            op_index
+                        ).await?;
+                    },
+                }
+            },
+            protocol::FETCH => {
+                log!("Scheduler sent FETCH signal (request {}).", header.request_id);
+                // Same deal as with WORK: a bad payload gets an ERROR frame, not a hang up.
+                let fetch = match self.read_request::<workload::FetchResults>(
+                    stream, header
+                ).await? {
+                    Some(request) => request,
+                    None => return Ok(false),
+                };
+                match fetch {
+                    Ok(fetch) => {
+                        self.send_results(
+                            stream, header, fetch.get_job_id(), fetch.get_progress()
+                        ).await?
+                    },
+                    Err(message) => {
+                        log!("Rejected FETCH frame: {}", message);
+                        self.write_error(
+                            stream,
+                            header.request_id,
+                            header.response_flags(),
+                            response::ErrorResponse_Kind::PROTOCOL,
+                            &message
+                        ).await?;
+                    },
+                }
+            },
+            protocol::RESUME => {
+                log!("Scheduler sent RESUME signal (request {}).", header.request_id);
+                let resume = match self.read_request::<workload::ResumeJob>(
+                    stream, header
+                ).await? {
+                    Some(request) => request,
+                    None => return Ok(false),
+                };
+                // As with WORK, every RESUME is answered, with an ACK or with an ERROR.
+                let outcome = match resume {
+                    Ok(_) if self.is_draining() => Err((
+                        response::ErrorResponse_Kind::DRAINING,
+                        "The worker is draining, and not taking new work.".to_owned(),
+                        -1
+                    )),
+                    Ok(resume) => match self.resume(resume.get_idempotency_key()).await {
+                        Ok(Some(ack)) => Ok(ack),
+                        Ok(None) => Err((
+                            response::ErrorResponse_Kind::NOT_FOUND,
+                            format!(
+                                "No job is checkpointed under idempotency key {:?}.",
+                                resume.get_idempotency_key()
+                            ),
+                            -1
+                        )),
+                        Err(err) => match validation_failure(err.as_ref()) {
+                            Some((message, op_index)) => {
+                                Err((response::ErrorResponse_Kind::VALIDATION, message, op_index))
+                            },
+                            None => {
+                                Err((response::ErrorResponse_Kind::INTERNAL, err.to_string(), -1))
+                            },
+                        },
+                    },
+                    Err(message) => Err((response::ErrorResponse_Kind::PROTOCOL, message, -1)),
+                };
+                match outcome {
+                    Ok(ack) => self.write_frame(
+                        stream,
+                        protocol::ACK,
+                        header.request_id,
+                        header.response_flags(),
+                        &ack.write_to_bytes()?
+                    ).await?,
+                    Err((kind, message, op_index)) => {
+                        log!("Rejected RESUME frame: {}", message);
+                        self.write_error_for_op(
+                            stream,
+                            header.request_id,
+                            header.response_flags(),
+                            kind,
+                            &message,
+                            op_index
+                        ).await?;
+                    },
+                }
+            },
+            protocol::PRELOAD => {
+                log!("Scheduler sent PRELOAD signal (request {}).", header.request_id);
+                let preload = match self.read_request::<workload::Preload>(
+                    stream, header
+                ).await? {
+                    Some(request) => request,
+                    None => return Ok(false),
+                };
+                // A file that can't be loaded (e.g. because it doesn't exist) is the client's
+                // problem, not the session's, so it gets an ERROR frame too.
+                let failure = match preload {
+                    Ok(mut preload) => {
+                        let files = preload.take_files().into_vec();
+                        match self.preload(files).await.map_err(|err| err.to_string()) {
+                            Ok(()) => None,
+                            Err(message) => Some((response::ErrorResponse_Kind::INTERNAL, message)),
+                        }
+                    },
+                    Err(message) => Some((response::ErrorResponse_Kind::PROTOCOL, message)),
+                };
+                match failure {
+                    None => self.write_frame(
+                        stream, protocol::PRELOADED, header.request_id, header.response_flags(),
+                        &[]
+                    ).await?,
+                    Some((kind, message)) => {
+                        log_error!("PRELOAD failed: {}", message);
+                        self.write_error(
+                            stream, header.request_id, header.response_flags(), kind, &message
+                        ).await?;
+                    },
+                }
+            },
+            protocol::CANCEL => {
+                log!("Scheduler sent CANCEL signal (request {}).", header.request_id);
+                let cancel = match self.read_request::<workload::CancelJob>(
+                    stream, header
+                ).await? {
+                    Some(request) => request,
+                    None => return Ok(false),
+                };
+                match cancel {
+                    Ok(cancel) => {
+                        let mut cancelled = response::Cancelled::new();
+                        cancelled.set_cancelled(self.queue.cancel(cancel.get_job_id()));
+                        self.write_frame(
+                            stream,
+                            protocol::CANCELLED,
+                            header.request_id,
+                            header.response_flags(),
+                            &cancelled.write_to_bytes()?
+                        ).await?;
+                    },
+                    Err(message) => {
+                        log!("Rejected CANCEL frame: {}", message);
+                        self.write_error(
+                            stream,
+                            header.request_id,
+                            header.response_flags(),
+                            response::ErrorResponse_Kind::PROTOCOL,
+                            &message
+                        ).await?;
+                    },
+                }
+            },
+            protocol::SNAPSHOT => {
+                log!("Scheduler sent SNAPSHOT signal (request {}).", header.request_id);
+                let take = match self.read_request::<workload::TakeSnapshot>(
+                    stream, header
+                ).await? {
+                    Some(request) => request,
+                    None => return Ok(false),
+                };
+                // As with PRELOAD, a snapshot that can't be taken (e.g. because the bucket
+                // doesn't exist) gets an ERROR frame, and the session carries on.
+                let outcome = match take {
+                    Ok(take) => self.snapshot(take.get_path()).await
+                        .map_err(|err| (response::ErrorResponse_Kind::INTERNAL, err.to_string())),
+                    Err(message) => Err((response::ErrorResponse_Kind::PROTOCOL, message)),
+                };
+                match outcome {
+                    Ok(snapshot) => self.write_frame(
+                        stream,
+                        protocol::SNAPSHOTTED,
+                        header.request_id,
+                        header.response_flags(),
+                        &snapshot.write_to_bytes()?
+                    ).await?,
+                    Err((kind, message)) => {
+                        log_error!("SNAPSHOT failed: {}", message);
+                        self.write_error(
+                            stream, header.request_id, header.response_flags(), kind, &message
+                        ).await?;
+                    },
+                }
             },
-            2 => {
-                println!("Scheduler sent SHUTDOWN signal (first byte 2).")
+            protocol::CACHE => {
+                log!("Scheduler sent CACHE signal (request {}).", header.request_id);
+                let command = match self.read_request::<workload::CacheCommand>(
+                    stream, header
+                ).await? {
+                    Some(request) => request,
+                    None => return Ok(false),
+                };
+                let outcome = match command {
+                    Ok(command) => self.cache(&command).await
+                        .map_err(|err| (response::ErrorResponse_Kind::INTERNAL, err.to_string())),
+                    Err(message) => Err((response::ErrorResponse_Kind::PROTOCOL, message)),
+                };
+                match outcome {
+                    Ok(report) => self.write_frame(
+                        stream,
+                        protocol::CACHE_REPORT,
+                        header.request_id,
+                        header.response_flags(),
+                        &report.write_to_bytes()?
+                    ).await?,
+                    Err((kind, message)) => {
+                        log_error!("CACHE failed: {}", message);
+                        self.write_error(
+                            stream, header.request_id, header.response_flags(), kind, &message
+                        ).await?;
+                    },
+                }
+            },
+            protocol::RELOAD => {
+                log!("Scheduler sent RELOAD signal (request {}).", header.request_id);
+                match self.reload().map_err(|err| err.to_string()) {
+                    Ok(changed) => {
+                        let mut reloaded = response::Reloaded::new();
+                        reloaded.set_changed(RepeatedField::from_vec(
+                            changed.iter().map(|name| name.to_string()).collect()
+                        ));
+                        self.write_frame(
+                            stream,
+                            protocol::RELOADED,
+                            header.request_id,
+                            header.response_flags(),
+                            &reloaded.write_to_bytes()?
+                        ).await?;
+                    },
+                    Err(message) => {
+                        log_error!("RELOAD failed: {}", message);
+                        self.write_error(
+                            stream,
+                            header.request_id,
+                            header.response_flags(),
+                            response::ErrorResponse_Kind::VALIDATION,
+                            &message
+                        ).await?;
+                    },
+                }
+            },
+            protocol::SHUTDOWN => {
+                // The SHUTDOWN signal ends the session. Note that the worker process itself
+                // keeps running.
+                log!("Scheduler sent SHUTDOWN signal (request {}).", header.request_id);
+                return Ok(false);
+            }
+            protocol::DRAIN => {
+                log!("Scheduler sent DRAIN signal (request {}).", header.request_id);
+                self.drain();
+                self.write_frame(
+                    stream, protocol::DRAINING, header.request_id, header.response_flags(), &[]
+                ).await?;
+            }
+            protocol::STOP => {
+                // Unlike SHUTDOWN, STOP takes the whole worker process down, though not before
+                // the worker has drained: the jobs it has queued or running are seen through,
+                // and their results handed over (see `stopped`).
+                log!(
+                    "Scheduler sent STOP signal (request {}), stopping once drained.",
+                    header.request_id
+                );
+                self.stop();
+                self.write_frame(
+                    stream, protocol::STOPPED, header.request_id, header.response_flags(), &[]
+                ).await?;
+            }
+            _ => {
+                // The signal is the client's mistake, so rather than fail the whole session over
+                // it, we throw away its payload (which keeps us in step with the client) and tell
+                // it so.
+                log_error!(
+                    "Client sent invalid signal {} (request {}).",
+                    header.signal, header.request_id
+                );
+                let payload = Worker::with_timeout(
+                    self.config.read_timeout,
+                    "reading the frame payload",
+                    Worker::read_payload_bytes(stream, header.payload_size)
+                ).await?;
+                if payload.is_none() {
+                    return Ok(false);
+                }
+                self.write_error(
+                    stream,
+                    header.request_id,
+                    header.response_flags(),
+                    response::ErrorResponse_Kind::PROTOCOL,
+                    &format!("Invalid signal {}.", header.signal)
+                ).await?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncWriteExt, ReadBuf};
+    use tokio::runtime::Runtime;
+    use lazy_static::lazy_static;
+    use proptest::prelude::*;
+    use protocol::*;
+
+    /// A stream that hands out its bytes one chunk per read, the way a frame split across
+    /// several TCP segments would arrive.
+    struct Chunked(Vec<Vec<u8>>);
+
+    impl AsyncRead for Chunked {
+        fn poll_read(
+            mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>
+        ) -> Poll<std::io::Result<()>> {
+            if let Some(chunk) = self.0.first_mut() {
+                let n = chunk.len().min(buf.remaining());
+                buf.put_slice(&chunk[..n]);
+                chunk.drain(..n);
+                if chunk.is_empty() { self.0.remove(0); }
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Splits the bytes at the given offsets. Empty chunks are skipped, as a zero-byte read is
+    /// an EOF.
+    fn split(bytes: &[u8], cuts: &[prop::sample::Index]) -> Chunked {
+        let mut offsets: Vec<usize> = cuts.iter().map(|cut| cut.index(bytes.len() + 1)).collect();
+        offsets.push(0);
+        offsets.push(bytes.len());
+        offsets.sort_unstable();
+        let chunks = offsets.windows(2)
+            .filter(|pair| pair[0] < pair[1])
+            .map(|pair| bytes[pair[0]..pair[1]].to_vec())
+            .collect();
+        Chunked(chunks)
+    }
+
+    lazy_static! {
+        static ref RUNTIME: Runtime = Runtime::new().unwrap();
+        static ref WORKER: Worker = RUNTIME.block_on(
+            Worker::new(Address::Tcp(0), WorkerConfig::default())
+        ).unwrap();
+    }
+
+    /// The signals the worker handles. Any other is invalid.
+    const SIGNALS: &[u8] = &[
+        PING, WORK, VALIDATE, FETCH, RESUME, PRELOAD, CANCEL, SNAPSHOT, CACHE, RELOAD, SHUTDOWN,
+        DRAIN, STOP,
+    ];
+
+    /// The signals whose effects reach past the session, which the tests don't send: STOP exits
+    /// the process, and CACHE, SNAPSHOT and RELOAD act on the worker's files.
+    const OUT_OF_SESSION_SIGNALS: &[u8] = &[STOP, CACHE, SNAPSHOT, RELOAD];
+
+    /// Hands the worker a frame, returning what `handle_frame` did with it, and the frames the
+    /// worker answered with.
+    fn send_frame(
+        signal: u8, flags: u8, payload: &[u8]
+    ) -> (Result<bool>, Vec<(FrameHeader, Vec<u8>)>) {
+        RUNTIME.block_on(async {
+            let (mut client, mut server) = Stream::pair();
+            let frame = craft_frame(signal, 1, flags, payload).unwrap();
+            client.write_all(&frame[HEADER_LENGTH..]).await.unwrap();
+            let mut header: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+            header.copy_from_slice(&frame[..HEADER_LENGTH]);
+            let handled = WORKER.handle_frame(&mut server, FrameHeader::from_bytes(header)).await;
+            // Hanging up our end of the pipe lets the reads below run into an EOF once they have
+            // read everything the worker wrote.
+            drop(server);
+            let mut responses = vec![];
+            while let Ok(response) = read_frame(&mut client).await {
+                responses.push(response);
+            }
+            (handled, responses)
+        })
+    }
+
+    proptest! {
+        // An invalid signal is answered with a protocol error, and the session goes on.
+        #[test]
+        fn test_handle_invalid_signal(
+            signal in any::<u8>().prop_filter("valid signal", |signal| !SIGNALS.contains(signal)),
+            compress in any::<bool>(),
+            payload in prop::collection::vec(any::<u8>(), 0..512)
+        ) {
+            let flags = if compress { FLAG_ZSTD } else { 0 };
+            let (handled, responses) = send_frame(signal, flags, &payload);
+            prop_assert!(handled.unwrap());
+            prop_assert_eq!(responses.len(), 1);
+            let (header, payload) = &responses[0];
+            prop_assert_eq!(header.signal, ERROR);
+            prop_assert_eq!(header.request_id, 1);
+            let error: response::ErrorResponse = parse_message(payload).unwrap();
+            prop_assert_eq!(error.get_kind(), response::ErrorResponse_Kind::PROTOCOL);
+        }
+
+        // Whatever the signal, and whatever its payload, the worker doesn't panic, and if it
+        // gives up on the session, it's with a protocol error.
+        #[test]
+        fn test_handle_any_signal(
+            signal in any::<u8>().prop_filter(
+                "out-of-session signal", |signal| !OUT_OF_SESSION_SIGNALS.contains(signal)
+            ),
+            flags in any::<u8>(),
+            payload in prop::collection::vec(any::<u8>(), 0..512)
+        ) {
+            let (handled, _) = send_frame(signal, flags, &payload);
+            if let Err(err) = handled {
+                prop_assert!(matches!(
+                    err.downcast_ref::<WorkerError>(), Some(WorkerError::ProtocolError(_))
+                ));
+            }
+        }
+
+        #[test]
+        fn test_read_metadata_bytes(
+            bytes in prop::collection::vec(any::<u8>(), 0..2 * HEADER_LENGTH),
+            cuts in prop::collection::vec(any::<prop::sample::Index>(), 0..8)
+        ) {
+            let mut stream = split(&bytes, &cuts);
+            let header = futures::executor::block_on(Worker::read_metadata_bytes(&mut stream))
+                .unwrap();
+            if bytes.len() >= HEADER_LENGTH {
+                let mut expected: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+                expected.copy_from_slice(&bytes[..HEADER_LENGTH]);
+                prop_assert_eq!(header, Some(FrameHeader::from_bytes(expected)));
+            } else {
+                prop_assert_eq!(header, None);
+            }
+        }
+
+        #[test]
+        fn test_read_payload_bytes(
+            bytes in prop::collection::vec(any::<u8>(), 0..512),
+            buffer_length in 0..512usize,
+            cuts in prop::collection::vec(any::<prop::sample::Index>(), 0..8)
+        ) {
+            let mut stream = split(&bytes, &cuts);
+            let payload = futures::executor::block_on(
+                Worker::read_payload_bytes(&mut stream, buffer_length)
+            ).unwrap();
+            if bytes.len() >= buffer_length {
+                prop_assert_eq!(payload, Some(bytes[..buffer_length].to_vec()));
+            } else {
+                prop_assert_eq!(payload, None);
             }
-            _ => panic!(
-                format!(
-                    "Received invalid signal (first byte {:?}).",
-                    &scheduler_request_metadata_buffer[0..1]
-                )
-            )
         }
-        Ok(())
     }
 }
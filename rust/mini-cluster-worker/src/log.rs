@@ -0,0 +1,198 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use uuid::Uuid;
+
+use crate::err::{Result, WorkerError, ErrKind};
+
+// A worker serves many connections and runs several jobs at once, so its log lines come out
+// interleaved, and a line like "Job 3 failed" doesn't say much about which client it was for.
+// So every connection, and every job, gets a UUID of its own, and every log line says which
+// connection and which job it is about (as far as either applies):
+//
+//     [connection=6f1c... job=0b9e...] Executing job 3.
+//
+// Job IDs are only unique to a worker (and only until it restarts), whereas the UUIDs are
+// unique across the cluster. A job is logged with the UUID of the connection it was submitted
+// over, too, so its lines can be traced back to whoever submitted it.
+//
+// Rather than threading the UUIDs through every function that might log, they are kept in a
+// task-local `LogContext`, which `log!` picks up. The context is set with `LogContext::scope`,
+// around the futures serving a connection or running a job; code running outside of any scope
+// (e.g. at startup) logs without one.
+
+// Lines are logged at one of three levels: errors (`log_error!`), everything worth knowing about
+// in the ordinary course of things (`log!`), and the blow-by-blow of every frame
+// (`log_debug!`). The worker logs at `WorkerConfig.log_level` and above, which can be turned up
+// whilst it runs to look into a problem, and back down again after (see `Worker::reload`).
+
+/// How much the worker logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 0,
+    Info = 1,
+    Debug = 2,
+}
+
+impl LogLevel {
+    /// Parses a log level from its config name, e.g. `debug`.
+    pub fn from_name(name: &str) -> Result<LogLevel> {
+        match name.trim().to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(WorkerError::new(
+                ErrKind::ValidationError, &format!("Unknown log level {:?}.", name)
+            ))?,
+        }
+    }
+
+    /// The level the worker currently logs at.
+    pub fn current() -> LogLevel {
+        match LEVEL.load(Ordering::Relaxed) {
+            0 => LogLevel::Error,
+            1 => LogLevel::Info,
+            _ => LogLevel::Debug,
+        }
+    }
+
+    /// Has the worker log at this level (and above) from now on.
+    pub fn apply(self) {
+        LEVEL.store(self as u8, Ordering::Relaxed);
+    }
+
+    /// Whether or not lines at this level are logged.
+    pub fn enabled(self) -> bool {
+        self <= LogLevel::current()
+    }
+}
+
+impl Default for LogLevel {
+    fn default() -> LogLevel {
+        LogLevel::Info
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+tokio::task_local! {
+    static CONTEXT: LogContext;
+}
+
+/// What the log lines currently being written are about.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LogContext {
+    pub connection: Option<Uuid>,
+    pub job: Option<Uuid>,
+}
+
+impl LogContext {
+    /// The context of the task we're on, or an empty one if it doesn't have one.
+    pub fn current() -> LogContext {
+        CONTEXT.try_with(|context| *context).unwrap_or_default()
+    }
+
+    /// This context, for a new connection.
+    pub fn with_new_connection(self) -> LogContext {
+        LogContext { connection: Some(Uuid::new_v4()), ..self }
+    }
+
+    /// This context, for a new job.
+    pub fn with_new_job(self) -> LogContext {
+        LogContext { job: Some(Uuid::new_v4()), ..self }
+    }
+
+    /// Runs `future` with this as its context.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CONTEXT.scope(self, future).await
+    }
+}
+
+/// Formats as the prefix of a log line, e.g. `[connection=... job=...] `, or as nothing at all
+/// if the context is empty.
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut ids = vec![];
+        if let Some(connection) = self.connection {
+            ids.push(format!("connection={}", connection));
+        }
+        if let Some(job) = self.job {
+            ids.push(format!("job={}", job));
+        }
+        match ids.is_empty() {
+            true => Ok(()),
+            false => write!(f, "[{}] ", ids.join(" ")),
+        }
+    }
+}
+
+/// Like `println!`, but prefixes the line with the current `LogContext`, and only prints it if
+/// the worker logs at the given `LogLevel`.
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => {
+        if $level.enabled() {
+            println!("{}{}", $crate::log::LogContext::current(), format_args!($($arg)*))
+        }
+    };
+}
+
+/// Logs a line at the `Info` level (see `log_at!`).
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => { $crate::log_at!($crate::log::LogLevel::Info, $($arg)*) };
+}
+
+/// Logs a line at the `Error` level (see `log_at!`).
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => { $crate::log_at!($crate::log::LogLevel::Error, $($arg)*) };
+}
+
+/// Logs a line at the `Debug` level (see `log_at!`).
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => { $crate::log_at!($crate::log::LogLevel::Debug, $($arg)*) };
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_log_context() {
+        assert_eq!(LogContext::current(), LogContext::default());
+        assert_eq!(LogContext::default().to_string(), "");
+
+        let connection = LogContext::default().with_new_connection();
+        let job = connection.with_new_job();
+        assert_eq!(job.connection, connection.connection);
+        assert_ne!(connection.with_new_job().job, job.job);
+        let prefix = job.to_string();
+        let connection_id = connection.connection.unwrap();
+        assert!(prefix.starts_with(&format!("[connection={} job=", connection_id)));
+        assert!(prefix.ends_with("] "));
+
+        let current = block_on(job.scope(async {
+            // Nested scopes take over from the outer one, and hand back to it when they're done.
+            let inner = connection.scope(async { LogContext::current() }).await;
+            (inner, LogContext::current())
+        }));
+        assert_eq!(current, (connection, job));
+        assert_eq!(LogContext::current(), LogContext::default());
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(LogLevel::from_name(" Debug").unwrap(), LogLevel::Debug);
+        assert!(LogLevel::from_name("verbose").is_err());
+        assert!(LogLevel::Error < LogLevel::Info && LogLevel::Info < LogLevel::Debug);
+        // Only the default level is ever applied here, since tests share the level.
+        assert_eq!(LogLevel::current(), LogLevel::Info);
+        assert!(LogLevel::Error.enabled() && LogLevel::Info.enabled());
+        assert!(!LogLevel::Debug.enabled());
+    }
+}
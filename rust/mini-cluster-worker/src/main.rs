@@ -1,4 +1,10 @@
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+
 use mini_cluster_worker::Worker;
+use mini_cluster_worker::config::WorkerConfig;
+use mini_cluster_worker::transport::Address;
 
 /// This helper function generates the raw bytes of a valid Protobuf message. You can copy-paste
 /// the output into `nc` input in order to test that the process actually works:
@@ -44,6 +50,22 @@ fn generate_test_buffer_bytes() {
 #[tokio::main]
 async fn main() {
     // generate_test_buffer_bytes();
-    let worker = Worker::new(8080).await.unwrap();
-    worker.listen().await.unwrap();
+    // Listen on port 8080 (or `WORKER_PORT`), unless we're asked to use a Unix domain socket
+    // instead. Workers on a different machine than the scheduler listen on `WORKER_HOST`, a
+    // hostname or IP address of their machine that the scheduler can reach them at.
+    let port = match env::var("WORKER_PORT") {
+        Ok(port) if !port.is_empty() => port.parse::<u16>().unwrap(),
+        _ => 8080,
+    };
+    let address = match (env::var("WORKER_SOCKET"), env::var("WORKER_HOST")) {
+        (Ok(path), _) if !path.is_empty() => Address::from(PathBuf::from(path)),
+        (_, Ok(host)) if !host.is_empty() => Address::Host(host, port),
+        _ => Address::from(port),
+    };
+    let worker = Arc::new(Worker::new(address, WorkerConfig::from_env().unwrap()).await.unwrap());
+    // The worker serves until the scheduler has it stop (with a STOP frame), and it has drained.
+    tokio::select! {
+        outcome = Arc::clone(&worker).serve() => outcome.unwrap(),
+        _ = worker.stopped() => println!("Drained, exiting."),
+    }
 }
@@ -0,0 +1,179 @@
+use rusoto_s3::GetObjectRequest;
+use serde::Deserialize;
+
+use crate::capabilities::FORMATS;
+use crate::db::ColumnSpec;
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::file::{parse_file_path, WorkerS3ClientAdapter, WorkerS3ClientTrait};
+use crate::presigned::is_presigned_url;
+
+/// What a file path starts with if it points at a manifest, rather than at the data itself.
+pub const MANIFEST_SCHEME: &str = "manifest://";
+
+// Manifests. A glob (see `file::is_pattern`) covers a dataset laid out in one directory, but
+// datasets don't always line up with directories, and spelling out every part file in every
+// workload that reads the dataset is tedious. So a file's path can instead point at a manifest,
+// a JSON object in S3 describing the dataset:
+//
+//     manifest://s3://bucket/trips/manifest.json
+//
+// which lists the objects that make up the dataset (paths that are neither `s3://` paths nor
+// pre-signed URLs are relative to the manifest's own directory), what format each one is in, and
+// optionally the schema they share:
+//
+//     {
+//         "objects": [
+//             {"path": "2021/03/part-0.csv", "format": "csv"},
+//             {"path": "s3://other-bucket/part-1.csv"}
+//         ],
+//         "schema": [{"name": "date", "type": "date"}, {"name": "fare", "type": "real"}]
+//     }
+//
+// The objects are all loaded into the file's table, just as a glob's are. With a schema, the
+// objects' headers are skipped, and their columns are taken to be the schema's, in order. This
+// way, the dataset is defined once, in the manifest, and can change (e.g. as part files come and
+// go) without the workloads reading it having to.
+
+/// One of the objects a manifest lists.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestObject {
+    pub path: String,
+    #[serde(default = "default_format")]
+    pub format: String,
+}
+
+fn default_format() -> String {
+    FORMATS[0].to_owned()
+}
+
+/// One of the columns of a manifest's schema.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestColumn {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub col_type: String,
+}
+
+/// A dataset, as described by a manifest.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub objects: Vec<ManifestObject>,
+    #[serde(default)]
+    pub schema: Vec<ManifestColumn>,
+}
+
+/// Whether or not a file path points at a manifest.
+pub fn is_manifest(path: &str) -> bool {
+    path.starts_with(MANIFEST_SCHEME)
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl Manifest {
+    /// Parses the manifest at the given S3 path (without the `manifest://`). Relative object
+    /// paths are resolved against the manifest's directory.
+    pub fn parse(manifest_path: &str, bytes: &[u8]) -> Result<Manifest> {
+        let invalid = |message: String| WorkerError::new(
+            ErrKind::ValidationError, &format!("Bad manifest {}: {}", manifest_path, message)
+        );
+        let mut manifest: Manifest = serde_json::from_slice(bytes)
+            .map_err(|err| invalid(err.to_string()))?;
+        if manifest.objects.is_empty() {
+            Err(invalid("it lists no objects.".to_owned()))?
+        }
+        let directory = &manifest_path[..manifest_path.rfind('/').map_or(0, |i| i + 1)];
+        for object in manifest.objects.iter_mut() {
+            if !object.path.starts_with("s3://") && !is_presigned_url(&object.path) {
+                object.path = format!("{}{}", directory, object.path.trim_start_matches('/'));
+            }
+            if !FORMATS.contains(&object.format.as_str()) {
+                Err(invalid(
+                    format!("{} is {}, which can't be loaded.", object.path, object.format)
+                ))?
+            }
+        }
+        // The schema ends up in a `CREATE TABLE`, so it had better not have any SQL in it.
+        for column in manifest.schema.iter() {
+            if !is_identifier(&column.name) || !is_identifier(&column.col_type) {
+                Err(invalid(format!("column {:?} isn't a valid column.", column.name)))?
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// The paths of the objects the manifest lists, in the order it lists them.
+    pub fn paths(&self) -> Vec<String> {
+        self.objects.iter().map(|object| object.path.clone()).collect()
+    }
+
+    /// The columns of the manifest's schema, if it has one.
+    pub fn columns(&self) -> Option<Vec<ColumnSpec>> {
+        if self.schema.is_empty() {
+            return None;
+        }
+        Some(self.schema.iter().enumerate().map(|(index, column)| ColumnSpec {
+            index,
+            name: column.name.clone(),
+            col_type: column.col_type.clone(),
+        }).collect())
+    }
+}
+
+/// Downloads and parses the manifest at the given path (`manifest://s3://...`).
+pub async fn read_manifest<T: WorkerS3ClientTrait>(
+    path: &str, client: &WorkerS3ClientAdapter<T>
+) -> Result<Manifest> {
+    let manifest_path = &path[MANIFEST_SCHEME.len()..];
+    let bucket_map = parse_file_path(manifest_path)?;
+    let (sse_customer_algorithm, sse_customer_key, sse_customer_key_md5) =
+        client.options.sse_customer()?;
+    let req = GetObjectRequest {
+        bucket: bucket_map.get("bucket").unwrap().clone(),
+        key: bucket_map.get("object").unwrap().clone(),
+        request_payer: client.options.request_payer(),
+        sse_customer_algorithm,
+        sse_customer_key,
+        sse_customer_key_md5,
+        ..Default::default()
+    };
+    let bytes = client.get_object(req).await?;
+    Manifest::parse(manifest_path, &bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = Manifest::parse("s3://foo/trips/manifest.json", br#"{
+            "objects": [
+                {"path": "2021/part-0.csv", "format": "csv"},
+                {"path": "s3://bar/part-1.csv"}
+            ],
+            "schema": [{"name": "date", "type": "date"}, {"name": "fare", "type": "real"}]
+        }"#).unwrap();
+        assert_eq!(
+            manifest.paths(), vec!["s3://foo/trips/2021/part-0.csv", "s3://bar/part-1.csv"]
+        );
+        let columns = manifest.columns().unwrap();
+        assert_eq!(columns[1], ColumnSpec {
+            index: 1, name: "fare".to_owned(), col_type: "real".to_owned()
+        });
+
+        let parse = |json: &str| Manifest::parse("s3://foo/manifest.json", json.as_bytes());
+        assert_eq!(parse(r#"{"objects": [{"path": "a.csv"}]}"#).unwrap().columns(), None);
+        // No objects, objects in formats workers can't load, and schemas with SQL in them are
+        // all turned away.
+        assert!(parse(r#"{"objects": []}"#).is_err());
+        assert!(parse(r#"{"objects": [{"path": "a.parquet", "format": "parquet"}]}"#).is_err());
+        let schema = r#"[{"name": "a int); DROP TABLE foo; --", "type": "int"}]"#;
+        let json = format!(r#"{{"objects": [{{"path": "a.csv"}}], "schema": {}}}"#, schema);
+        assert!(parse(&json).is_err());
+    }
+}
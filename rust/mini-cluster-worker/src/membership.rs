@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use protobuf::{Message, RepeatedField};
+
+use crate::auth::sign_nonce;
+use crate::cluster::{Registered, WorkerRegistration};
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::protocol::{self, read_frame, write_frame};
+use crate::response::{ErrorResponse, WorkerCapabilities};
+use crate::transport::{Address, Stream};
+
+// Cluster membership. Originally the scheduler had to be handed a static list of workers. Now a
+// worker that is told where the scheduler is (`WorkerConfig::scheduler`) dials in when it starts
+// up and registers itself, and the scheduler keeps a roster of the workers that did.
+//
+// This is the one place where the worker is the client: it opens the connection, answers the
+// scheduler's NONCE challenge (if the scheduler has a secret), and sends a REGISTER frame, to
+// which the scheduler answers with REGISTERED. The connection is closed afterwards; the
+// scheduler talks to the worker over connections of its own.
+
+/// How many times the worker tries to register before giving up, e.g. because the scheduler is
+/// still starting up.
+pub const REGISTRATION_ATTEMPTS: u32 = 5;
+
+/// How long the worker waits before its second attempt at registering. The wait doubles after
+/// every attempt.
+pub const REGISTRATION_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Crafts the message a worker listening on `address`, which can do what `advertised` says (see
+/// `capabilities`), registers itself with.
+pub fn craft_registration(
+    address: &Address, advertised: &WorkerCapabilities, cache_size: u64
+) -> WorkerRegistration {
+    let mut registration = WorkerRegistration::new();
+    match address {
+        Address::Tcp(port) => registration.set_port(*port as u32),
+        Address::Host(host, port) => {
+            registration.set_host(host.clone());
+            registration.set_port(*port as u32);
+        },
+        Address::Unix(path) => registration.set_socket_path(path.to_string_lossy().into_owned()),
+    }
+    registration.set_capabilities(RepeatedField::from_vec(advertised.get_extensions().to_vec()));
+    registration.set_advertised(advertised.clone());
+    registration.set_cache_size(cache_size);
+    registration
+}
+
+/// Returns the address a registering worker can be reached at.
+pub fn registration_address(registration: &WorkerRegistration) -> Result<Address> {
+    if registration.has_port() && registration.get_port() <= u16::MAX as u32 {
+        let port = registration.get_port() as u16;
+        return Ok(match registration.get_host() {
+            "" => Address::Tcp(port),
+            host => Address::Host(host.to_owned(), port),
+        });
+    }
+    if registration.has_socket_path() {
+        return Ok(Address::from(registration.get_socket_path()));
+    }
+    Err(WorkerError::new(ErrKind::ProtocolError, "Registration has no valid worker address."))?
+}
+
+/// Registers a worker with the scheduler at `scheduler`, returning the ID the scheduler gave it.
+pub async fn register(
+    scheduler: &Address, registration: &WorkerRegistration, secret: Option<&str>
+) -> Result<u64> {
+    let mut stream = Stream::connect(scheduler).await?;
+    if let Some(secret) = secret {
+        let (header, nonce) = read_frame(&mut stream).await?;
+        if header.signal != protocol::NONCE {
+            Err(WorkerError::new(
+                ErrKind::ProtocolError,
+                &format!(
+                    "Expected a NONCE frame from the scheduler, got signal {}.", header.signal
+                )
+            ))?
+        }
+        write_frame(&mut stream, protocol::AUTH, 0, 0, &sign_nonce(secret, &nonce)).await?;
+    }
+
+    write_frame(&mut stream, protocol::REGISTER, 1, 0, &registration.write_to_bytes()?).await?;
+    let (header, payload) = read_frame(&mut stream).await?;
+    let worker_id = match header.signal {
+        protocol::REGISTERED => Registered::parse_from_bytes(&payload)?.get_worker_id(),
+        protocol::ERROR => {
+            let error = ErrorResponse::parse_from_bytes(&payload)?;
+            Err(WorkerError::new(
+                ErrKind::NetworkError,
+                &format!(
+                    "Scheduler turned down the registration: {:?}: {}",
+                    error.get_kind(), error.get_message()
+                )
+            ))?
+        },
+        signal => Err(WorkerError::new(
+            ErrKind::ProtocolError,
+            &format!("Expected a REGISTERED frame from the scheduler, got signal {}.", signal)
+        ))?,
+    };
+    write_frame(&mut stream, protocol::SHUTDOWN, 2, 0, &[]).await?;
+    Ok(worker_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registration_address() {
+        let addresses = vec![
+            Address::Tcp(8080),
+            Address::Host("worker-1.internal".to_owned(), 8080),
+            Address::from("/tmp/worker.sock"),
+        ];
+        for address in addresses {
+            let mut advertised = WorkerCapabilities::new();
+            advertised.set_extensions(RepeatedField::from_vec(vec!["json1".to_owned()]));
+            let registration = craft_registration(&address, &advertised, 123);
+            assert_eq!(registration_address(&registration).unwrap(), address);
+            assert_eq!(registration.get_cache_size(), 123);
+            assert_eq!(registration.get_capabilities().to_vec(), vec!["json1".to_owned()]);
+        }
+        assert!(registration_address(&WorkerRegistration::new()).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+
+use crate::err::{Result, WorkerError, ErrKind};
+
+/// How long a job waits for memory to free up before giving up, by default.
+pub const MEMORY_WAIT: Duration = Duration::from_secs(5 * 60);
+
+// Memory guardrails. Two things used to be able to make a worker balloon in memory: downloads,
+// since every file was read into memory in full on its way to disk, and result batches, which
+// are held on to until they're read (see `JobQueue`), however long that takes. Downloads now go
+// straight to disk (see `localize_file`), but result batches still can. Left alone, a
+// handful of large jobs is all it takes for the OOM killer to take the whole worker down, and
+// every other job on it along with it.
+//
+// So the worker keeps a tally of roughly how much memory these are taking up. If it is given a
+// ceiling (`WorkerConfig.memory_ceiling`, which can be changed whilst the worker runs; see
+// `Worker::reload`):
+//
+// A job that produces results faster than they're read waits for them to be read before it
+// produces any more.
+//
+// A job that waits for longer than `WorkerConfig.memory_wait` (e.g. because nobody is reading
+// its results), or that would need more memory than the ceiling allows to begin with, fails
+// with a `ResourceError`, which frees up whatever memory it had.
+//
+// The tally is approximate: it leaves out everything else the worker has in memory (e.g.
+// SQLite's page cache, see `DatabaseOptions`), and jobs checking for room at the same time can
+// overshoot the ceiling by a batch or two between them.
+
+/// A tally of the memory taken up by result batches (or anything else reserved against it),
+/// across all of the worker's jobs.
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    // The most bytes the tally may come to, or zero if there's no limit.
+    ceiling: AtomicU64,
+    used: AtomicU64,
+    freed: Notify,
+}
+
+/// Memory counted against a `MemoryBudget`, which is given back when this is dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::SeqCst);
+        self.budget.freed.notify_waiters();
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(ceiling: Option<u64>) -> MemoryBudget {
+        MemoryBudget {
+            ceiling: AtomicU64::new(ceiling.unwrap_or(0)),
+            used: AtomicU64::new(0),
+            freed: Notify::new(),
+        }
+    }
+
+    /// The most bytes the tally may come to, if there's a limit.
+    pub fn ceiling(&self) -> Option<u64> {
+        match self.ceiling.load(Ordering::SeqCst) {
+            0 => None,
+            ceiling => Some(ceiling),
+        }
+    }
+
+    /// Changes the ceiling. Jobs waiting for room check again straight away, since a higher
+    /// ceiling (or none) may have made some.
+    pub fn set_ceiling(&self, ceiling: Option<u64>) {
+        self.ceiling.store(ceiling.unwrap_or(0), Ordering::SeqCst);
+        self.freed.notify_waiters();
+    }
+
+    /// How many bytes are currently counted against the budget.
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// Counts `bytes` against the budget straight away, ceiling or no ceiling.
+    pub fn hold(self: &Arc<Self>, bytes: u64) -> Reservation {
+        self.used.fetch_add(bytes, Ordering::SeqCst);
+        Reservation { budget: Arc::clone(self), bytes }
+    }
+
+    /// Waits until there is room for another `bytes` under the ceiling, for at most `patience`.
+    /// Asking for more than the ceiling, or running out of patience, is a `ResourceError`.
+    pub async fn wait_for_room(&self, bytes: u64, patience: Duration) -> Result<()> {
+        let ceiling = match self.ceiling() {
+            Some(ceiling) => ceiling,
+            None => return Ok(()),
+        };
+        if bytes > ceiling {
+            Err(WorkerError::new(
+                ErrKind::ResourceError,
+                &format!("Needs {} bytes of memory, but the ceiling is {}.", bytes, ceiling)
+            ))?
+        }
+        let wait = async {
+            loop {
+                // `notify_waiters` only wakes up the futures that exist at the time, so this one
+                // has to be made before we check, not after.
+                let freed = self.freed.notified();
+                if self.used() + bytes <= self.ceiling().unwrap_or(u64::MAX) {
+                    return;
+                }
+                freed.await;
+            }
+        };
+        if tokio::time::timeout(patience, wait).await.is_err() {
+            Err(WorkerError::new(
+                ErrKind::ResourceError,
+                &format!(
+                    "Needs {} bytes of memory, but {} of the {} byte ceiling were still taken \
+                    after waiting {:?}.",
+                    bytes, self.used(), self.ceiling().unwrap_or(ceiling), patience
+                )
+            ))?
+        }
+        Ok(())
+    }
+
+    /// Waits for room for another `bytes` (see `wait_for_room`), and then counts them against
+    /// the budget.
+    pub async fn reserve(self: &Arc<Self>, bytes: u64, patience: Duration) -> Result<Reservation> {
+        self.wait_for_room(bytes, patience).await?;
+        Ok(self.hold(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_budget() {
+        let budget = Arc::new(MemoryBudget::new(Some(100)));
+        let first = budget.reserve(60, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(budget.used(), 60);
+
+        // More than the ceiling never fits.
+        assert!(budget.reserve(101, Duration::from_secs(1)).await.is_err());
+        // Less does, once there is room for it.
+        assert!(budget.reserve(50, Duration::from_millis(10)).await.is_err());
+        let waiting = {
+            let budget = Arc::clone(&budget);
+            tokio::spawn(async move {
+                budget.reserve(50, Duration::from_secs(5)).await.map(|_| ()).is_ok()
+            })
+        };
+        drop(first);
+        assert!(waiting.await.unwrap());
+        assert_eq!(budget.used(), 0);
+
+        // Raising the ceiling makes room for whoever is waiting on it.
+        let first = budget.reserve(60, Duration::from_secs(1)).await.unwrap();
+        let waiting = {
+            let budget = Arc::clone(&budget);
+            tokio::spawn(async move {
+                budget.reserve(50, Duration::from_secs(5)).await.map(|_| ()).is_ok()
+            })
+        };
+        budget.set_ceiling(Some(200));
+        assert!(waiting.await.unwrap());
+        assert_eq!(budget.ceiling(), Some(200));
+        drop(first);
+
+        // Without a ceiling, anything goes.
+        let budget = Arc::new(MemoryBudget::new(None));
+        let held = budget.reserve(u64::MAX / 2, Duration::from_secs(0)).await.unwrap();
+        assert_eq!(budget.used(), u64::MAX / 2);
+        drop(held);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,116 @@
+use std::io::Write;
+
+use hyper::{Body, Client, Request};
+use hyper::body::HttpBody;
+use hyper::header::RANGE;
+use hyper_rustls::HttpsConnector;
+
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::file::{report_download, ObjectHead};
+
+// Pre-signed URLs. Reading from S3 normally takes AWS credentials (see `credentials`), which
+// means handing every worker credentials that can read every bucket its workloads might read.
+// Where whoever submits the workloads (e.g. the scheduler) has the credentials and the workers
+// shouldn't, files can point at pre-signed URLs instead, e.g. ones made by `aws s3 presign`:
+//
+//     https://bucket.s3.amazonaws.com/trips.csv?X-Amz-Algorithm=...&X-Amz-Signature=...
+//
+// The signature is what grants access, so the worker downloads these with a plain HTTPS GET,
+// no credentials involved. A HEAD request can't be made with a URL signed for a GET, so we look
+// objects up by asking for their first byte instead.
+//
+// The signature is as good as a credential until it expires, so it is kept out of the logs and
+// error messages, and off of disk (see `without_query`).
+
+/// Whether or not a file path is a pre-signed S3 URL, as opposed to an `s3://` path.
+pub fn is_presigned_url(path: &str) -> bool {
+    (path.starts_with("https://") || path.starts_with("http://"))
+        && path.contains("X-Amz-Signature=")
+}
+
+/// Strips the query string (and with it, the signature) off of a pre-signed URL. Other paths
+/// are returned as they are.
+pub fn without_query(path: &str) -> &str {
+    if !is_presigned_url(path) {
+        return path;
+    }
+    match path.find('?') {
+        Some(i) => &path[..i],
+        None => path,
+    }
+}
+
+/// Reads the object's size out of a `Content-Range` header, e.g. `bytes 0-0/1234`.
+fn parse_content_range(content_range: &str) -> Option<i64> {
+    content_range.split('/').last()?.trim().parse::<i64>().ok()
+}
+
+/// Sends a GET request for a pre-signed URL, for the given byte range (e.g. `bytes=0-0`) if
+/// there is one. The response body is written to `out` as it comes in, and `on_bytes` is called
+/// as it does (see `file::report_download`). Returns what the response's headers say about the
+/// object: its size is the object's whole size, even if only a range of it was asked for.
+pub async fn get_url(
+    url: &str,
+    range: Option<String>,
+    out: &mut (dyn Write + Send),
+    on_bytes: &(dyn Fn(u64) + Sync),
+) -> Result<ObjectHead> {
+    let shown = without_query(url);
+    let client = Client::builder().build::<_, Body>(HttpsConnector::with_native_roots());
+    let mut req = Request::get(url);
+    if let Some(range) = range {
+        req = req.header(RANGE, range);
+    }
+    let resp = client.request(req.body(Body::empty())?).await.map_err(|err| WorkerError::new(
+        ErrKind::NetworkError, &format!("Could not GET {}: {}", shown, err)
+    ))?;
+    if !resp.status().is_success() {
+        Err(WorkerError::new(
+            ErrKind::AWSError, &format!("GET {} failed with {}.", shown, resp.status())
+        ))?
+    }
+
+    let header = |name: &str| resp.headers().get(name).and_then(|v| v.to_str().ok());
+    let size = match header("content-range") {
+        Some(content_range) => parse_content_range(content_range),
+        None => header("content-length").and_then(|v| v.parse::<i64>().ok()),
+    };
+    // As in `_head_object`, objects encrypted with a customer-provided key say so in a header of
+    // their own.
+    let encryption = match header("x-amz-server-side-encryption-customer-algorithm") {
+        Some(_) => Some("SSE-C".to_owned()),
+        None => header("x-amz-server-side-encryption").map(String::from),
+    };
+    let head = ObjectHead { etag: header("etag").map(String::from), size, encryption };
+
+    let mut body = resp.into_body();
+    let mut total = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        out.write_all(&chunk)?;
+        report_download(total, total + chunk.len() as u64, on_bytes);
+        total += chunk.len() as u64;
+    }
+    on_bytes(total);
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presigned_url() {
+        let url = "https://foo.s3.amazonaws.com/bar.csv?X-Amz-Algorithm=AWS4-HMAC-SHA256\
+            &X-Amz-Expires=3600&X-Amz-Signature=abc123";
+        assert!(is_presigned_url(url));
+        assert_eq!(without_query(url), "https://foo.s3.amazonaws.com/bar.csv");
+        // Plain URLs and S3 paths aren't pre-signed, and are left alone.
+        assert!(!is_presigned_url("https://example.com/bar.csv"));
+        assert!(!is_presigned_url("s3://foo/bar.csv?X-Amz-Signature=abc123"));
+        assert_eq!(without_query("s3://foo/ba?.csv"), "s3://foo/ba?.csv");
+
+        assert_eq!(parse_content_range("bytes 0-0/1234"), Some(1234));
+        assert_eq!(parse_content_range("bytes 0-0/*"), None);
+    }
+}
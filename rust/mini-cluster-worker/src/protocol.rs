@@ -0,0 +1,477 @@
+use std::io::Read;
+use std::time::Duration;
+
+use protobuf::Message;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::err::{Result, WorkerError, ErrKind};
+
+// Every frame sent over the wire, in either direction, starts with a thirteen-byte header:
+//
+// * A one-byte signal.
+// * Two bytes giving the size of the payload that follows.
+// * A four-byte request ID.
+// * A one-byte protocol version.
+// * A one-byte set of flags (see `FLAG_ZSTD`).
+// * A four-byte CRC32 checksum of the payload.
+//
+// All of the integers are big-endian, so the largest possible payload is 2**16-1 bytes. The size
+// and the checksum describe the payload as it is sent over the wire, e.g. after compression. The
+// signal and size come first because that is where they were before the header grew the other
+// fields; it keeps hand-crafted `nc` test frames recognizable.
+//
+// Connections are long-lived sessions which may carry any number of frames. The client picks a
+// request ID for each frame it sends, and the worker echoes that ID back on the response, which
+// is how the two get matched up. Frames which aren't part of a request-response pair (e.g. the
+// worker's NONCE challenge) use request ID 0.
+
+/// Size of the frame header, in bytes.
+pub const HEADER_LENGTH: usize = 13;
+
+/// The version of the wire protocol spoken by this build. Version 1 was the original header, which
+/// had no version byte and no checksum; version 2 had no flags byte. Frames with any other version
+/// are rejected.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// Flag set on frames whose payload is zstd-compressed. Responses are compressed if and only if
+/// the request they answer was, so clients opt into compression just by using it.
+pub const FLAG_ZSTD: u8 = 0b0000_0001;
+
+/// The zstd compression level used for compressed frames. Level 3 is zstd's own default, and a
+/// good tradeoff for the small, repetitive payloads (SQL, protobuf) that we send.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The largest a compressed payload is allowed to get once decompressed. Without a limit, a tiny
+/// frame could decompress into gigabytes of garbage (a "zip bomb").
+pub const MAX_DECOMPRESSED_PAYLOAD_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Client asks the worker whether or not it is alive. Has no payload.
+pub const PING: u8 = 0;
+/// Client submits a workload. The payload is a `Workload` protobuf message.
+pub const WORK: u8 = 1;
+/// Client ends the session. Has no payload.
+pub const SHUTDOWN: u8 = 2;
+/// Client answers the worker's challenge. The payload is the HMAC of the nonce.
+pub const AUTH: u8 = 3;
+/// Worker challenges the client to authenticate. The payload is a random nonce.
+pub const NONCE: u8 = 4;
+/// Worker answers a PING. The payload is a `WorkerStatus` protobuf message.
+pub const STATUS: u8 = 5;
+/// Worker accepted a workload onto its job queue. The payload is an `Ack` protobuf message.
+pub const ACK: u8 = 6;
+/// Worker is already serving as many connections as it is allowed to, and is hanging up. Has no
+/// payload.
+pub const BUSY: u8 = 7;
+/// Worker could not process a frame. The payload is an `ErrorResponse` protobuf message.
+/// In reply to WORK, this is a NACK: the workload was not queued, and never will be.
+pub const ERROR: u8 = 8;
+/// Client asks for the results of a job. The payload is a `FetchResults` protobuf message. The
+/// worker answers with RESULTS frames as the job produces them, so this can take a while.
+pub const FETCH: u8 = 9;
+/// Worker sends a batch of result rows in answer to a FETCH. The payload is a `ResultBatch`
+/// protobuf message; the final batch has its `last` field set.
+pub const RESULTS: u8 = 10;
+/// Client asks the worker to check a workload without running it. The payload is a `Workload`
+/// protobuf message. The worker answers with VALID, or with an ERROR frame saying which op is at
+/// fault.
+pub const VALIDATE: u8 = 11;
+/// Worker found nothing wrong with a workload sent with VALIDATE. Has no payload.
+pub const VALID: u8 = 12;
+/// Worker asks to join the scheduler's roster. Unlike the other frames, this one is sent by the
+/// worker, to the scheduler. The payload is a `WorkerRegistration` protobuf message.
+pub const REGISTER: u8 = 13;
+/// Scheduler added a worker to its roster. The payload is a `Registered` protobuf message.
+pub const REGISTERED: u8 = 14;
+/// Client asks the scheduler how a job is doing. Like REGISTER, this is sent to the scheduler,
+/// which also accepts WORK frames (answering them with ACK) and FETCH frames (answering them with
+/// RESULTS). The payload is a `JobQuery` protobuf message.
+pub const JOB_QUERY: u8 = 15;
+/// Scheduler answers a JOB_QUERY. The payload is a `ClusterJob` protobuf message.
+pub const JOB_STATE: u8 = 16;
+/// Client submits a workload to be split across the workers by file (see `PartitionedWorkload`).
+/// Sent to the scheduler, which answers with ACK. The payload is a `PartitionedWorkload`
+/// protobuf message.
+pub const PARTITION: u8 = 17;
+/// Client asks the worker to load files into its database ahead of time. The payload is a
+/// `Preload` protobuf message. The worker answers with PRELOADED once the files are loaded, or
+/// with an ERROR frame if any of them couldn't be. The scheduler accepts this frame too, and
+/// passes it on to every live worker.
+pub const PRELOAD: u8 = 18;
+/// Worker finished loading the files sent with PRELOAD. Has no payload.
+pub const PRELOADED: u8 = 19;
+/// Client asks the worker to cancel a job. The payload is a `CancelJob` protobuf message. A
+/// queued job is taken off the queue; a running one is stopped wherever it has got to.
+pub const CANCEL: u8 = 20;
+/// Worker answers a CANCEL. The payload is a `Cancelled` protobuf message.
+pub const CANCELLED: u8 = 21;
+/// Client asks the worker process to exit (as opposed to SHUTDOWN, which only ends the
+/// session), once it has drained (see DRAIN). Has no payload.
+pub const STOP: u8 = 22;
+/// Worker is draining, and will exit once it has, in answer to a STOP. Has no payload.
+pub const STOPPED: u8 = 23;
+/// Client asks the worker to drain: to turn away new WORK from now on, but finish the jobs it
+/// already has queued or running. Used to take workers out of rotation for a rolling restart.
+/// Has no payload.
+pub const DRAIN: u8 = 24;
+/// Worker is draining, in answer to a DRAIN. Has no payload.
+pub const DRAINING: u8 = 25;
+/// Worker reports how far along a job is, whilst the client waits on its results (see
+/// `FetchResults.progress`). Sent every `PROGRESS_INTERVAL` or so, with the FETCH's request ID,
+/// for as long as the job has made progress since the last one. The payload is a `JobProgress`
+/// protobuf message.
+pub const PROGRESS: u8 = 26;
+/// Client asks the worker to resume a checkpointed job (see `checkpoint`). The payload is a
+/// `ResumeJob` protobuf message. The worker answers with an ACK, like it does WORK.
+pub const RESUME: u8 = 27;
+/// Client asks the worker to snapshot its database to S3 (see `snapshot`). The payload is a
+/// `TakeSnapshot` protobuf message. The worker answers with SNAPSHOTTED once the snapshot is
+/// uploaded, or with an ERROR frame if it couldn't be.
+pub const SNAPSHOT: u8 = 28;
+/// Worker uploaded the snapshot asked for with SNAPSHOT. The payload is a `Snapshot` protobuf
+/// message.
+pub const SNAPSHOTTED: u8 = 29;
+/// Client submits a join to be spread across the workers (see `DistributedJoin`). Like
+/// PARTITION, this is sent to the scheduler, which answers with ACK. The payload is a
+/// `DistributedJoin` protobuf message.
+pub const JOIN: u8 = 30;
+/// Client submits a SQL query for the scheduler to split across the workers itself (see
+/// `ClusterQuery`). Sent to the scheduler, which answers with ACK. The payload is a
+/// `ClusterQuery` protobuf message.
+pub const QUERY: u8 = 31;
+/// Client asks the worker to show, or to throw away, what it has cached. The payload is a
+/// `CacheCommand` protobuf message. Evicting and clearing wait for running jobs to finish.
+pub const CACHE: u8 = 32;
+/// Worker answers a CACHE. The payload is a `CacheReport` protobuf message.
+pub const CACHE_REPORT: u8 = 33;
+/// Client asks the worker to reload its configuration, as it does on a SIGHUP. There is no
+/// payload.
+pub const RELOAD: u8 = 34;
+/// Worker answers a RELOAD. The payload is a `Reloaded` protobuf message.
+pub const RELOADED: u8 = 35;
+
+/// How often the worker sends PROGRESS frames, at most.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The largest payload a frame can carry, in bytes.
+pub const MAX_PAYLOAD_SIZE: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    pub signal: u8,
+    pub payload_size: usize,
+    pub request_id: u32,
+    pub version: u8,
+    pub flags: u8,
+    pub checksum: u32,
+}
+
+impl FrameHeader {
+    pub fn from_bytes(bytes: [u8; HEADER_LENGTH]) -> FrameHeader {
+        FrameHeader {
+            signal: bytes[0],
+            payload_size: 256 * (bytes[1] as usize) + (bytes[2] as usize),
+            request_id: u32::from_be_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]),
+            version: bytes[7],
+            flags: bytes[8],
+            checksum: u32::from_be_bytes([bytes[9], bytes[10], bytes[11], bytes[12]]),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LENGTH] {
+        let request_id = self.request_id.to_be_bytes();
+        let checksum = self.checksum.to_be_bytes();
+        [
+            self.signal,
+            (self.payload_size / 256) as u8,
+            (self.payload_size % 256) as u8,
+            request_id[0], request_id[1], request_id[2], request_id[3],
+            self.version,
+            self.flags,
+            checksum[0], checksum[1], checksum[2], checksum[3],
+        ]
+    }
+
+    /// Errors out if the frame was sent using a protocol version we don't speak.
+    pub fn check_version(&self) -> Result<()> {
+        if self.version != PROTOCOL_VERSION {
+            Err(WorkerError::new(
+                ErrKind::ProtocolError,
+                &format!(
+                    "Frame has protocol version {}, but only version {} is supported.",
+                    self.version, PROTOCOL_VERSION
+                )
+            ))?
+        }
+        Ok(())
+    }
+
+    /// Errors out if the payload doesn't match the checksum in the header, e.g. because it got
+    /// corrupted somewhere along the way.
+    pub fn check_payload(&self, payload: &[u8]) -> Result<()> {
+        let checksum = crc32fast::hash(payload);
+        if checksum != self.checksum {
+            Err(WorkerError::new(
+                ErrKind::ProtocolError,
+                &format!(
+                    "Frame payload has checksum {:#010x}, but the header says {:#010x}.",
+                    checksum, self.checksum
+                )
+            ))?
+        }
+        Ok(())
+    }
+
+    /// Returns the flags that a response to this frame should be sent with.
+    pub fn response_flags(&self) -> u8 {
+        self.flags & FLAG_ZSTD
+    }
+
+    /// Turns the payload of this frame, as received over the wire, back into the payload the
+    /// sender started with (e.g. decompresses it).
+    pub fn decode_payload(&self, payload: Vec<u8>) -> Result<Vec<u8>> {
+        if self.flags & FLAG_ZSTD == 0 {
+            return Ok(payload);
+        }
+        let undecodable = |err: std::io::Error| WorkerError::new(
+            ErrKind::ProtocolError, &format!("Could not decompress the payload: {}", err)
+        );
+        // We read one byte past the limit, which is how we tell "exactly at the limit" apart from
+        // "over the limit".
+        let decoder = zstd::stream::read::Decoder::new(&payload[..]).map_err(undecodable)?;
+        let mut decoded = vec![];
+        decoder.take(MAX_DECOMPRESSED_PAYLOAD_SIZE + 1)
+            .read_to_end(&mut decoded)
+            .map_err(undecodable)?;
+        if decoded.len() as u64 > MAX_DECOMPRESSED_PAYLOAD_SIZE {
+            Err(WorkerError::new(
+                ErrKind::ProtocolError,
+                &format!(
+                    "Payload decompresses to more than {} bytes.", MAX_DECOMPRESSED_PAYLOAD_SIZE
+                )
+            ))?
+        }
+        Ok(decoded)
+    }
+}
+
+/// Parses a (decoded) payload as a protobuf message of type `M`, e.g. a workload. A payload that
+/// isn't one results in a `ProtocolError`.
+pub fn parse_message<M: Message>(payload: &[u8]) -> Result<M> {
+    M::parse_from_bytes(payload).map_err(|err| WorkerError::new(
+        ErrKind::ProtocolError,
+        &format!("Could not parse the {}: {}", M::descriptor_static().name(), err)
+    ).into())
+}
+
+/// Prepends a frame header to the given payload. If `flags` has `FLAG_ZSTD` set, the payload is
+/// compressed first.
+pub fn craft_frame(signal: u8, request_id: u32, flags: u8, payload: &[u8]) -> Result<Vec<u8>> {
+    let compressed;
+    let payload = if flags & FLAG_ZSTD != 0 {
+        compressed = zstd::stream::encode_all(payload, ZSTD_LEVEL)?;
+        &compressed[..]
+    } else {
+        payload
+    };
+
+    if payload.len() > MAX_PAYLOAD_SIZE {
+        Err(WorkerError::new(
+            ErrKind::NetworkError,
+            &format!("Payload of {} bytes is too large to fit in a frame.", payload.len())
+        ))?
+    }
+    let header = FrameHeader {
+        signal,
+        payload_size: payload.len(),
+        request_id,
+        version: PROTOCOL_VERSION,
+        flags,
+        checksum: crc32fast::hash(payload),
+    };
+    let mut frame: Vec<u8> = Vec::with_capacity(payload.len() + HEADER_LENGTH);
+    frame.extend_from_slice(&header.to_bytes());
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Writes a complete frame to the stream.
+pub async fn write_frame<S: AsyncWrite + Unpin>(
+    stream: &mut S, signal: u8, request_id: u32, flags: u8, payload: &[u8]
+) -> Result<()> {
+    let frame = craft_frame(signal, request_id, flags, payload)?;
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+/// Reads a complete frame off of the stream, returning its header and its payload, decoded (see
+/// `FrameHeader::decode_payload`). Frames sent using a different protocol version, or whose
+/// payload fails its checksum, are rejected.
+pub async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(FrameHeader, Vec<u8>)> {
+    let mut header: [u8; HEADER_LENGTH] = [0 as u8; HEADER_LENGTH];
+    stream.read_exact(&mut header).await?;
+    let header = FrameHeader::from_bytes(header);
+    header.check_version()?;
+    let mut payload = vec![0 as u8; header.payload_size];
+    stream.read_exact(&mut payload).await?;
+    header.check_payload(&payload)?;
+    Ok((header, header.decode_payload(payload)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workload::Workload;
+    use proptest::prelude::*;
+
+    fn is_protocol_error(err: &(dyn std::error::Error + 'static)) -> bool {
+        matches!(err.downcast_ref::<WorkerError>(), Some(WorkerError::ProtocolError(_)))
+    }
+
+    fn is_ok_or_protocol_error<T>(result: &Result<T>) -> bool {
+        result.as_ref().map_or_else(|err| is_protocol_error(err.as_ref()), |_| true)
+    }
+
+    #[test]
+    fn test_craft_frame() {
+        let frame = craft_frame(NONCE, 258, 0, &[7; 300]).unwrap();
+        let mut header: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+        header.copy_from_slice(&frame[..HEADER_LENGTH]);
+        let header = FrameHeader::from_bytes(header);
+        assert_eq!(header.signal, NONCE);
+        assert_eq!(header.payload_size, 300);
+        assert_eq!(header.request_id, 258);
+        assert_eq!(header.version, PROTOCOL_VERSION);
+        assert_eq!(frame.len(), 313);
+        assert!(header.check_version().is_ok());
+        assert!(header.check_payload(&frame[HEADER_LENGTH..]).is_ok());
+
+        // The CRC32 of an empty payload is zero.
+        let frame = craft_frame(PING, 0, 0, &[]).unwrap();
+        assert_eq!(frame, vec![PING, 0, 0, 0, 0, 0, 0, PROTOCOL_VERSION, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_check_payload_detects_corruption() {
+        let mut frame = craft_frame(WORK, 1, 0, b"SELECT * FROM dataset_1").unwrap();
+        let mut header: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+        header.copy_from_slice(&frame[..HEADER_LENGTH]);
+        let header = FrameHeader::from_bytes(header);
+
+        frame[HEADER_LENGTH + 3] ^= 0x01;
+        assert!(header.check_payload(&frame[HEADER_LENGTH..]).is_err());
+    }
+
+    #[test]
+    fn test_check_version() {
+        let mut bytes: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+        bytes[7] = 1;
+        assert!(FrameHeader::from_bytes(bytes).check_version().is_err());
+    }
+
+    #[test]
+    fn test_craft_frame_too_large() {
+        let frame = craft_frame(WORK, 1, 0, &vec![0; 70000]);
+        assert!(frame.is_err());
+    }
+
+    #[test]
+    fn test_compressed_frame_round_trip() {
+        // Compression lets payloads that would be too large for an uncompressed frame through.
+        let payload = "SELECT * FROM dataset_1 UNION ALL ".repeat(4000).into_bytes();
+        let frame = craft_frame(WORK, 1, FLAG_ZSTD, &payload).unwrap();
+        assert!(frame.len() < payload.len());
+
+        let mut header: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+        header.copy_from_slice(&frame[..HEADER_LENGTH]);
+        let header = FrameHeader::from_bytes(header);
+        assert_eq!(header.flags, FLAG_ZSTD);
+        assert_eq!(header.response_flags(), FLAG_ZSTD);
+        assert!(header.check_payload(&frame[HEADER_LENGTH..]).is_ok());
+
+        let decoded = header.decode_payload(frame[HEADER_LENGTH..].to_vec()).unwrap();
+        assert_eq!(decoded, payload);
+    }
+
+    #[test]
+    fn test_decompression_bomb_rejected() {
+        let payload = vec![0; (MAX_DECOMPRESSED_PAYLOAD_SIZE + 1) as usize];
+        let frame = craft_frame(WORK, 1, FLAG_ZSTD, &payload).unwrap();
+
+        let mut header: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+        header.copy_from_slice(&frame[..HEADER_LENGTH]);
+        let header = FrameHeader::from_bytes(header);
+        assert!(header.decode_payload(frame[HEADER_LENGTH..].to_vec()).is_err());
+    }
+
+    proptest! {
+        // Whatever comes in over the wire, each step of reading a frame either accepts it or
+        // rejects it with a protocol error. It never panics, and never fails in some other way.
+        #[test]
+        fn fuzz_frame(
+            header in prop::array::uniform13(any::<u8>()),
+            payload in prop::collection::vec(any::<u8>(), 0..512)
+        ) {
+            let header = FrameHeader::from_bytes(header);
+            prop_assert!(is_ok_or_protocol_error(&header.check_version()));
+            prop_assert!(is_ok_or_protocol_error(&header.check_payload(&payload)));
+            match header.decode_payload(payload) {
+                Ok(decoded) => prop_assert!(
+                    is_ok_or_protocol_error(&parse_message::<Workload>(&decoded))
+                ),
+                Err(err) => prop_assert!(is_protocol_error(err.as_ref())),
+            }
+        }
+
+        // Same, but for frames that pass their checksum, so that garbage makes it as far as the
+        // decompressor and the protobuf parser.
+        #[test]
+        fn fuzz_payload(
+            signal in any::<u8>(),
+            flags in any::<u8>(),
+            payload in prop::collection::vec(any::<u8>(), 0..512)
+        ) {
+            let frame = craft_frame(signal, 1, flags & !FLAG_ZSTD, &payload).unwrap();
+            let mut header: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+            header.copy_from_slice(&frame[..HEADER_LENGTH]);
+            let mut header = FrameHeader::from_bytes(header);
+            header.flags = flags;
+            prop_assert!(header.check_version().is_ok());
+            prop_assert!(header.check_payload(&frame[HEADER_LENGTH..]).is_ok());
+            match header.decode_payload(frame[HEADER_LENGTH..].to_vec()) {
+                Ok(decoded) => prop_assert!(
+                    is_ok_or_protocol_error(&parse_message::<Workload>(&decoded))
+                ),
+                Err(err) => prop_assert!(is_protocol_error(err.as_ref())),
+            }
+        }
+
+        #[test]
+        fn round_trip(
+            signal in any::<u8>(),
+            request_id in any::<u32>(),
+            compress in any::<bool>(),
+            payload in prop::collection::vec(any::<u8>(), 0..4096)
+        ) {
+            let flags = if compress { FLAG_ZSTD } else { 0 };
+            let frame = craft_frame(signal, request_id, flags, &payload).unwrap();
+            let (header, decoded) = futures::executor::block_on(read_frame(&mut &frame[..]))
+                .unwrap();
+            prop_assert_eq!(header.signal, signal);
+            prop_assert_eq!(header.request_id, request_id);
+            prop_assert_eq!(decoded, payload);
+        }
+
+        // A frame cut short anywhere is an error, rather than a hang or a panic.
+        #[test]
+        fn truncated_frame(
+            compress in any::<bool>(),
+            payload in prop::collection::vec(any::<u8>(), 1..512),
+            cut in any::<prop::sample::Index>()
+        ) {
+            let flags = if compress { FLAG_ZSTD } else { 0 };
+            let frame = craft_frame(WORK, 1, flags, &payload).unwrap();
+            let truncated = &frame[..cut.index(frame.len())];
+            prop_assert!(futures::executor::block_on(read_frame(&mut &truncated[..])).is_err());
+        }
+    }
+}
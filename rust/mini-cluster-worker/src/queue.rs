@@ -0,0 +1,571 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use protobuf::Message;
+use tokio::sync::{Notify, mpsc, oneshot, watch};
+
+use crate::job::Job;
+use crate::memory::Reservation;
+use crate::response::{JobProgress, ResultBatch};
+
+/// How long a job has to wait in the queue to gain one level of priority, by default (see
+/// `JobQueue::pop`).
+pub const PRIORITY_AGING: Duration = Duration::from_secs(30);
+
+/// How long a workload's idempotency key is remembered for (see `Workload.idempotency_key`).
+pub const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// How many result batches a job can get ahead of whoever reads its results. Past that, the job
+/// waits for the reader to catch up.
+pub const RESULT_BUFFER_BATCHES: usize = 16;
+
+/// How long a job's results are kept for someone to read, by default: how long a job waits for
+/// its next result batch to be read before giving up on its reader, and how long a finished job's
+/// unread results (and its state) are kept around.
+pub const RESULTS_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// A result batch on its way to whoever reads the job's results, along with the memory it is
+/// counted against (see `memory`), which is given back once the batch is read.
+pub type HeldBatch = (ResultBatch, Reservation);
+
+pub struct QueuedJob {
+    pub id: u64,
+    pub job: Job,
+    /// The job's priority, from its workload.
+    pub priority: i32,
+    pub queued_at: Instant,
+    /// Where the executor sends the job's result batches as they are produced. Dropping this
+    /// tells whoever is reading the results that there are no more to come.
+    pub results: mpsc::Sender<HeldBatch>,
+    /// How long a batch can go unread before the job gives up on its reader (see `RESULTS_TTL`).
+    results_ttl: Duration,
+    /// Set once a batch went unread for `results_ttl`, after which the rest are dropped.
+    abandoned: AtomicBool,
+}
+
+impl QueuedJob {
+    /// Sends a result batch to whoever reads the job's results, counting it against the job's
+    /// memory budget until it is read. If the reader is `RESULT_BUFFER_BATCHES` behind, this
+    /// waits for it to catch up, so that a slow reader slows the job down, rather than have its
+    /// results pile up on the worker.
+    ///
+    /// If the reader hung up, the batch is simply dropped. So is every batch after one that
+    /// nobody read in time (see `RESULTS_TTL`).
+    pub async fn send_result(&self, batch: ResultBatch) {
+        if self.results_abandoned() {
+            return;
+        }
+        let held = self.job.memory.hold(batch.compute_size() as u64);
+        let sent = tokio::time::timeout(self.results_ttl, self.results.send((batch, held))).await;
+        if sent.is_err() {
+            self.abandoned.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether the job gave up on whoever was supposed to read its results, so that some of them
+    /// were dropped.
+    pub fn results_abandoned(&self) -> bool {
+        self.abandoned.load(Ordering::SeqCst)
+    }
+}
+
+/// The lifecycle of a job, from the moment it is queued.
+#[derive(Debug, Clone)]
+pub enum JobState {
+    Queued,
+    Running,
+    /// Done, having produced this many result rows.
+    Done(u64),
+    Failed(String),
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        match self {
+            JobState::Queued | JobState::Running => false,
+            _ => true,
+        }
+    }
+}
+
+/// A worker-local queue of jobs waiting to be executed.
+///
+/// The connection handler `push`es jobs onto the queue and immediately goes back to serving the
+/// network, whilst a fixed number of executor tasks `pop` jobs off of it and run them. This
+/// bounds the number of jobs that can be executing at any one time.
+///
+/// Jobs don't necessarily run in the order they were queued in: see `pop`.
+///
+/// The queue also keeps track of the state of every job it has seen, and hands out the receiving
+/// end of each job's result stream (see `take_results`). Up to `RESULT_BUFFER_BATCHES` result
+/// batches are held on the worker until they are read, after which the job waits for its reader.
+/// A finished job's state, and whatever of its results nobody took, are forgotten a while
+/// (`RESULTS_TTL`) after it finished.
+pub struct JobQueue {
+    // A plain `std` mutex is fine here (and is what the tokio docs recommend) because the lock is
+    // never held across an `.await`.
+    jobs: Mutex<VecDeque<QueuedJob>>,
+    aging: Duration,
+    results_ttl: Duration,
+    notify: Notify,
+    next_id: AtomicU64,
+    running: AtomicUsize,
+    states: Mutex<HashMap<u64, JobState>>,
+    results: Mutex<HashMap<u64, mpsc::Receiver<HeldBatch>>>,
+    // The jobs that finished, in the order they did, and when.
+    finished: Mutex<VecDeque<(u64, Instant)>>,
+    // How far along each running job is.
+    progress: Mutex<HashMap<u64, JobProgress>>,
+    // One sender per running job. Sending on it tells the job's executor to stop the job.
+    aborts: Mutex<HashMap<u64, oneshot::Sender<()>>>,
+    // The idempotency keys seen within the last `IDEMPOTENCY_WINDOW`, along with the ID of the
+    // job each of them was first seen with, and when.
+    keys: Mutex<HashMap<String, (u64, Instant)>>,
+    // Every time a job finishes, the counter in this channel is bumped, which wakes up anyone
+    // `wait`ing on a job. We keep a receiver around so that we can hand out clones of it.
+    finished_tx: watch::Sender<u64>,
+    finished_rx: watch::Receiver<u64>,
+}
+
+impl JobQueue {
+    pub fn new() -> JobQueue {
+        JobQueue::with_aging(PRIORITY_AGING)
+    }
+
+    /// Like `new`, but jobs gain a level of priority for every `aging` they spend in the queue.
+    /// Zero turns aging off.
+    pub fn with_aging(aging: Duration) -> JobQueue {
+        let (finished_tx, finished_rx) = watch::channel(0);
+        JobQueue {
+            jobs: Mutex::new(VecDeque::new()),
+            aging,
+            results_ttl: RESULTS_TTL,
+            notify: Notify::new(),
+            next_id: AtomicU64::new(1),
+            running: AtomicUsize::new(0),
+            states: Mutex::new(HashMap::new()),
+            results: Mutex::new(HashMap::new()),
+            finished: Mutex::new(VecDeque::new()),
+            progress: Mutex::new(HashMap::new()),
+            aborts: Mutex::new(HashMap::new()),
+            keys: Mutex::new(HashMap::new()),
+            finished_tx,
+            finished_rx,
+        }
+    }
+
+    /// Keeps jobs' results (and finished jobs' states) for `results_ttl`, rather than
+    /// `RESULTS_TTL`.
+    pub fn results_ttl(mut self, results_ttl: Duration) -> JobQueue {
+        self.results_ttl = results_ttl;
+        self
+    }
+
+    /// Adds a job to the back of the queue, returning its job ID.
+    ///
+    /// If the job's workload has an idempotency key that was seen within the last
+    /// `IDEMPOTENCY_WINDOW`, the job isn't queued at all, and the ID of the job that was first
+    /// submitted under the key is returned instead.
+    pub fn push(&self, job: Job) -> u64 {
+        self.forget_expired();
+        let key = job.workload.get_idempotency_key().to_owned();
+        // Held until the job is queued, so that two submissions under the same key can't both
+        // find it unused.
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|_, (_, seen_at)| seen_at.elapsed() < IDEMPOTENCY_WINDOW);
+        if let Some((id, _)) = keys.get(&key) {
+            return *id;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        if !key.is_empty() {
+            keys.insert(key, (id, Instant::now()));
+        }
+        let (results, results_rx) = mpsc::channel(RESULT_BUFFER_BATCHES);
+        self.states.lock().unwrap().insert(id, JobState::Queued);
+        self.results.lock().unwrap().insert(id, results_rx);
+        let priority = job.workload.get_priority();
+        self.jobs.lock().unwrap().push_back(
+            QueuedJob {
+                id, job, priority, queued_at: Instant::now(), results,
+                results_ttl: self.results_ttl,
+                abandoned: AtomicBool::new(false),
+            }
+        );
+        // If no executor is currently waiting, `notify_one` stores a permit, so the next call to
+        // `notified` returns immediately. So there is no lost wakeup here.
+        self.notify.notify_one();
+        id
+    }
+
+    /// The ID of the job that was submitted under an idempotency key within the last
+    /// `IDEMPOTENCY_WINDOW`, if there was one.
+    pub fn keyed_job(&self, key: &str) -> Option<u64> {
+        self.keys.lock().unwrap().get(key)
+            .filter(|(_, seen_at)| seen_at.elapsed() < IDEMPOTENCY_WINDOW)
+            .map(|(id, _)| *id)
+    }
+
+    /// Returns a queued job's priority, plus whatever it has gained by waiting.
+    fn effective_priority(&self, queued_job: &QueuedJob, now: Instant) -> i64 {
+        let aged = match self.aging.as_nanos() {
+            0 => 0,
+            aging => (now.duration_since(queued_job.queued_at).as_nanos() / aging) as i64,
+        };
+        queued_job.priority as i64 + aged
+    }
+
+    /// Removes the job that should run next from the queue, if there are any.
+    fn take_next(&self) -> Option<QueuedJob> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let now = Instant::now();
+        let mut next: Option<(usize, i64)> = None;
+        for (position, queued_job) in jobs.iter().enumerate() {
+            let priority = self.effective_priority(queued_job, now);
+            // Strictly greater, so that jobs of equal priority go first come, first served.
+            if next.map_or(true, |(_, next_priority)| priority > next_priority) {
+                next = Some((position, priority));
+            }
+        }
+        next.and_then(|(position, _)| jobs.remove(position))
+    }
+
+    /// Waits for a job to become available, then removes it from the queue and returns it.
+    ///
+    /// The job with the highest priority goes first, so that e.g. a small interactive query
+    /// doesn't have to wait behind a long batch job. Left at that, a steady enough stream of
+    /// high-priority jobs would keep the low-priority ones waiting forever. So jobs also gain
+    /// priority as they wait (they "age"): one level for every `aging` they spend in the queue.
+    /// Between jobs of equal priority, the one that was queued first goes first.
+    ///
+    /// Priorities change as time passes, which rules out keeping the jobs in a heap. Instead we
+    /// look through the whole queue every time, which is cheap enough for the few dozen jobs a
+    /// worker ever has queued.
+    pub async fn pop(&self) -> QueuedJob {
+        loop {
+            // The guard is dropped before `take_next` returns -- before we hit the `.await`
+            // below.
+            let queued_job = self.take_next();
+            if let Some(queued_job) = queued_job {
+                return queued_job;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Cancels a job. A job that hasn't started running yet is simply removed from the queue. A
+    /// running job is stopped by its executor (see `mark_running`), which marks it cancelled.
+    /// Returns whether or not the job was cancelled: a job that already finished can't be.
+    pub fn cancel(&self, id: u64) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(position) = jobs.iter().position(|queued_job| queued_job.id == id) {
+            jobs.remove(position);
+            drop(jobs);
+            self.set_finished(id, JobState::Cancelled);
+            return true;
+        }
+        drop(jobs);
+        // Sending fails if the executor already dropped its end, i.e. the job just finished.
+        match self.aborts.lock().unwrap().remove(&id) {
+            Some(abort) => abort.send(()).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Returns the number of jobs waiting to be executed.
+    pub fn depth(&self) -> usize {
+        self.jobs.lock().unwrap().len()
+    }
+
+    /// Returns the number of jobs currently being executed.
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Takes the receiving end of a job's result stream. Each job's results can only be read
+    /// once, so this returns `None` if someone else already took them (or if there is no such
+    /// job).
+    pub fn take_results(&self, id: u64) -> Option<mpsc::Receiver<HeldBatch>> {
+        self.results.lock().unwrap().remove(&id)
+    }
+
+    /// Throws away whatever of a job's results nobody took yet, e.g. because some of them were
+    /// dropped (see `QueuedJob::results_abandoned`), so what's left would be misleading.
+    pub fn expire_results(&self, id: u64) {
+        self.results.lock().unwrap().remove(&id);
+    }
+
+    /// Forgets the jobs that finished more than `results_ttl` ago: their states, and whatever of
+    /// their results nobody took.
+    fn forget_expired(&self) {
+        let mut finished = self.finished.lock().unwrap();
+        while let Some(&(id, finished_at)) = finished.front() {
+            if finished_at.elapsed() < self.results_ttl {
+                break;
+            }
+            finished.pop_front();
+            self.states.lock().unwrap().remove(&id);
+            self.results.lock().unwrap().remove(&id);
+        }
+    }
+
+    /// Whether or not there's nothing left to do: no jobs queued or running, and no results for
+    /// anyone to take.
+    pub fn is_idle(&self) -> bool {
+        self.forget_expired();
+        self.depth() == 0 && self.running() == 0 && self.results.lock().unwrap().is_empty()
+    }
+
+    /// Returns the current state of a job, or `None` if the queue has never seen it (or it
+    /// finished too long ago, see `RESULTS_TTL`).
+    pub fn state(&self, id: u64) -> Option<JobState> {
+        self.states.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Waits for a job to finish, returning its final state, or `None` if the queue has never
+    /// seen it.
+    pub async fn wait(&self, id: u64) -> Option<JobState> {
+        // The receiver has to be cloned *before* we check the state. Otherwise a job finishing in
+        // between the check and the clone would go unnoticed, and we'd wait forever.
+        let mut finished_rx = self.finished_rx.clone();
+        loop {
+            match self.state(id) {
+                None => return None,
+                Some(state) if state.is_finished() => return Some(state),
+                _ => {},
+            }
+            if finished_rx.changed().await.is_err() {
+                return self.state(id);
+            }
+        }
+    }
+
+    /// Records how far along a running job is.
+    pub fn set_progress(&self, id: u64, mut progress: JobProgress) {
+        progress.set_job_id(id);
+        self.progress.lock().unwrap().insert(id, progress);
+    }
+
+    /// Returns how far along a running job is, if it has reported any progress yet.
+    pub fn job_progress(&self, id: u64) -> Option<JobProgress> {
+        self.progress.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Returns the progress of every running job that has reported any, ordered by job ID.
+    pub fn progress(&self) -> Vec<JobProgress> {
+        let mut progress = self.progress.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        progress.sort_by_key(|progress| progress.get_job_id());
+        progress
+    }
+
+    /// Records that a job started running. The executor running it should stop the job when
+    /// the returned receiver fires, which it does when the job is `cancel`led.
+    pub fn mark_running(&self, id: u64) -> oneshot::Receiver<()> {
+        let (abort, aborted) = oneshot::channel();
+        self.aborts.lock().unwrap().insert(id, abort);
+        self.running.fetch_add(1, Ordering::SeqCst);
+        self.states.lock().unwrap().insert(id, JobState::Running);
+        aborted
+    }
+
+    /// Records the final state of a job that was running.
+    pub fn mark_done(&self, id: u64, state: JobState) {
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        self.progress.lock().unwrap().remove(&id);
+        self.aborts.lock().unwrap().remove(&id);
+        self.set_finished(id, state);
+    }
+
+    fn set_finished(&self, id: u64, state: JobState) {
+        self.states.lock().unwrap().insert(id, state);
+        self.finished.lock().unwrap().push_back((id, Instant::now()));
+        let finished = *self.finished_rx.borrow() + 1;
+        // This can only fail if there are no receivers, but we hold one ourselves.
+        let _ = self.finished_tx.send(finished);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+
+    use crate::fixtures::*;
+    use crate::memory::MemoryBudget;
+    use super::*;
+
+    #[test]
+    fn test_job_queue_is_fifo() {
+        let queue = JobQueue::new();
+        let first = queue.push(block_on(Job::new(craft_workload_message(None))).unwrap());
+        let second = queue.push(block_on(Job::new(craft_workload_message(None))).unwrap());
+        assert_ne!(first, second);
+        assert_eq!(queue.depth(), 2);
+
+        assert_eq!(block_on(queue.pop()).id, first);
+        assert_eq!(block_on(queue.pop()).id, second);
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[test]
+    fn test_job_queue_priorities() {
+        let craft_job = |priority: i32| {
+            let mut workload = craft_workload_message(None);
+            workload.set_priority(priority);
+            block_on(Job::new(workload)).unwrap()
+        };
+
+        let queue = JobQueue::new();
+        let batch = queue.push(craft_job(0));
+        let interactive = queue.push(craft_job(5));
+        let other_batch = queue.push(craft_job(0));
+        assert_eq!(block_on(queue.pop()).id, interactive);
+        assert_eq!(block_on(queue.pop()).id, batch);
+        assert_eq!(block_on(queue.pop()).id, other_batch);
+
+        // A job that has waited long enough overtakes a higher-priority one.
+        let queue = JobQueue::with_aging(Duration::from_millis(10));
+        let old = queue.push(craft_job(0));
+        std::thread::sleep(Duration::from_millis(100));
+        let new = queue.push(craft_job(3));
+        assert_eq!(block_on(queue.pop()).id, old);
+        assert_eq!(block_on(queue.pop()).id, new);
+    }
+
+    #[test]
+    fn test_job_queue_idempotency_keys() {
+        let craft_job = |key: &str| {
+            let mut workload = craft_workload_message(None);
+            workload.set_idempotency_key(key.to_owned());
+            block_on(Job::new(workload)).unwrap()
+        };
+
+        let queue = JobQueue::new();
+        let first = queue.push(craft_job("a"));
+        assert_eq!(queue.keyed_job("a"), Some(first));
+        // The same key again is answered with the first job, which is only queued once.
+        assert_eq!(queue.push(craft_job("a")), first);
+        assert_eq!(queue.depth(), 1);
+        let other = queue.push(craft_job("b"));
+        assert_ne!(other, first);
+        // Workloads without a key are queued every time.
+        assert_ne!(queue.push(craft_job("")), queue.push(craft_job("")));
+        assert_eq!(queue.keyed_job(""), None);
+        assert_eq!(queue.depth(), 4);
+    }
+
+    #[test]
+    fn test_job_queue_tracks_state() {
+        let queue = JobQueue::new();
+        let id = queue.push(block_on(Job::new(craft_workload_message(None))).unwrap());
+        assert!(matches!(queue.state(id), Some(JobState::Queued)));
+
+        let queued_job = block_on(queue.pop());
+        queue.mark_running(queued_job.id);
+        assert!(matches!(queue.state(id), Some(JobState::Running)));
+        assert_eq!(queue.running(), 1);
+
+        let mut progress = JobProgress::new();
+        progress.set_rows_loaded(10);
+        progress.set_bytes_loaded(100);
+        queue.set_progress(id, progress.clone());
+        progress.set_job_id(id);
+        assert_eq!(queue.progress(), vec![progress.clone()]);
+        assert_eq!(queue.job_progress(id), Some(progress));
+
+        let memory = Arc::new(MemoryBudget::new(None));
+        queued_job.results.try_send((ResultBatch::new(), memory.hold(10))).unwrap();
+        drop(queued_job);
+        queue.mark_done(id, JobState::Done(0));
+        assert!(queue.progress().is_empty());
+        assert!(matches!(block_on(queue.wait(id)), Some(JobState::Done(_))));
+        assert_eq!(queue.running(), 0);
+
+        let mut results = queue.take_results(id).unwrap();
+        assert_eq!(memory.used(), 10);
+        assert!(block_on(results.recv()).is_some());
+        assert_eq!(memory.used(), 0);
+        assert!(block_on(results.recv()).is_none());
+        assert!(queue.take_results(id).is_none());
+
+        assert!(block_on(queue.wait(12345)).is_none());
+    }
+
+    #[test]
+    fn test_job_queue_cancel() {
+        let queue = JobQueue::new();
+        let id = queue.push(block_on(Job::new(craft_workload_message(None))).unwrap());
+        assert!(queue.cancel(id));
+        assert_eq!(queue.depth(), 0);
+        assert!(matches!(queue.state(id), Some(JobState::Cancelled)));
+
+        // Already gone.
+        assert!(!queue.cancel(id));
+
+        // A running job is cancelled through its executor.
+        let id = queue.push(block_on(Job::new(craft_workload_message(None))).unwrap());
+        let queued_job = block_on(queue.pop());
+        let aborted = queue.mark_running(queued_job.id);
+        assert!(queue.cancel(id));
+        assert!(block_on(aborted).is_ok());
+        queue.mark_done(id, JobState::Cancelled);
+        assert!(matches!(queue.state(id), Some(JobState::Cancelled)));
+
+        // A finished job can't be cancelled.
+        let id = queue.push(block_on(Job::new(craft_workload_message(None))).unwrap());
+        let queued_job = block_on(queue.pop());
+        let _aborted = queue.mark_running(queued_job.id);
+        queue.mark_done(id, JobState::Done(0));
+        assert!(!queue.cancel(id));
+    }
+
+    #[tokio::test]
+    async fn test_job_queue_results_backpressure() {
+        let queue = JobQueue::new();
+        let id = queue.push(Job::new(craft_workload_message(None)).await.unwrap());
+        let queued_job = Arc::new(queue.pop().await);
+        let mut results = queue.take_results(id).unwrap();
+
+        // The job gets `RESULT_BUFFER_BATCHES` ahead of the reader, and no further.
+        let sent = Arc::new(AtomicUsize::new(0));
+        let (sender, counter) = (Arc::clone(&queued_job), Arc::clone(&sent));
+        let sending = tokio::spawn(async move {
+            for _ in 0..RESULT_BUFFER_BATCHES + 1 {
+                sender.send_result(ResultBatch::new()).await;
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(sent.load(Ordering::SeqCst), RESULT_BUFFER_BATCHES);
+        assert!(results.recv().await.is_some());
+        sending.await.unwrap();
+        assert_eq!(sent.load(Ordering::SeqCst), RESULT_BUFFER_BATCHES + 1);
+        assert!(!queued_job.results_abandoned());
+    }
+
+    #[tokio::test]
+    async fn test_job_queue_expires_results() {
+        let queue = JobQueue::new().results_ttl(Duration::from_millis(50));
+        let id = queue.push(Job::new(craft_workload_message(None)).await.unwrap());
+        let queued_job = queue.pop().await;
+        queue.mark_running(id);
+
+        // Nobody reads the results, so the job gives up on them once the buffer is full...
+        for _ in 0..RESULT_BUFFER_BATCHES + 2 {
+            queued_job.send_result(ResultBatch::new()).await;
+        }
+        assert!(queued_job.results_abandoned());
+        drop(queued_job);
+        queue.mark_done(id, JobState::Done(0));
+        assert!(queue.state(id).is_some());
+
+        // ...and the job is forgotten a while after it finished, results and all.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        queue.push(Job::new(craft_workload_message(None)).await.unwrap());
+        assert!(queue.state(id).is_none());
+        assert!(queue.take_results(id).is_none());
+    }
+}
\ No newline at end of file
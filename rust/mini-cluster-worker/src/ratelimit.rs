@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::response::{ErrorResponse, ErrorResponse_Kind};
+
+// Rate limiting. Quotas (see the scheduler's `quota`) bound how much a client has on the go at
+// once, but not how fast it asks for more: a script stuck in a loop can open connections, or
+// submit jobs that are turned away, as fast as the network lets it, and every one of them costs
+// the worker (or the scheduler) a task, a handshake, and a parse before it's turned away.
+//
+// So clients can be held to a rate, with a token bucket per client: every client starts out
+// with `burst` tokens, each connection (or submission) takes one, and tokens come back at
+// `per_second`, up to `burst` again. A client that has run out of tokens is turned away with a
+// RATE_LIMITED error, saying how long until it has one again, much like an HTTP 429 with a
+// `Retry-After`.
+//
+// The worker holds clients to `WorkerConfig.accept_rate` when they connect, by address. The
+// scheduler holds tenants to `TenantQuota.submit_rate` when they submit jobs, by principal.
+
+/// How many idle clients a `RateLimiter` keeps track of before it forgets the ones that have
+/// all of their tokens back (which is the same as never having seen them).
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+/// How fast clients may do something: `per_second` times a second on average, and up to
+/// `burst` times in a row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl RateLimit {
+    /// A limit of `per_second`, with bursts of `burst`. Without a burst, clients may burst up
+    /// to a second's worth (and at least once).
+    pub fn new(per_second: f64, burst: Option<f64>) -> Result<RateLimit> {
+        if !(per_second > 0.0) || burst.map_or(false, |burst| !(burst >= 1.0)) {
+            Err(WorkerError::new(
+                ErrKind::ValidationError,
+                "Rate limits have to be positive, with bursts of at least one."
+            ))?
+        }
+        Ok(RateLimit { per_second, burst: burst.unwrap_or(per_second.max(1.0)) })
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    filled_at: Instant,
+}
+
+/// A token bucket per client (see `RateLimit`).
+#[derive(Debug)]
+pub struct RateLimiter {
+    pub limit: RateLimit,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter { limit, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Takes a token out of `client`'s bucket, or says how long until it has one again if it has
+    /// none.
+    pub fn take(&self, client: &str) -> std::result::Result<(), RateLimited> {
+        self.take_at(client, Instant::now()).map_err(|retry_after| RateLimited {
+            client: client.to_owned(), retry_after
+        })
+    }
+
+    fn take_at(&self, client: &str, now: Instant) -> std::result::Result<(), Duration> {
+        let limit = self.limit;
+        let refill = |bucket: &mut TokenBucket| {
+            let elapsed = now.saturating_duration_since(bucket.filled_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(limit.burst);
+            bucket.filled_at = now;
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_IDLE_BUCKETS {
+            buckets.retain(|_, bucket| {
+                refill(bucket);
+                bucket.tokens < limit.burst
+            });
+        }
+        let bucket = buckets.entry(client.to_owned())
+            .or_insert(TokenBucket { tokens: limit.burst, filled_at: now });
+        refill(bucket);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.per_second))
+        }
+    }
+}
+
+/// The error for a client going over its rate limit.
+#[derive(Debug)]
+pub struct RateLimited {
+    pub client: String,
+    /// How long until the client may try again.
+    pub retry_after: Duration,
+}
+
+impl RateLimited {
+    /// The ERROR frame payload to turn the client away with.
+    pub fn craft_error(&self) -> ErrorResponse {
+        let mut error = ErrorResponse::new();
+        error.set_kind(ErrorResponse_Kind::RATE_LIMITED);
+        error.set_message(self.to_string());
+        error.set_op_index(-1);
+        error.set_retry_after_millis(self.retry_after_millis());
+        error
+    }
+
+    /// Rounded up, so that a client that waits as long as it's told isn't turned away again.
+    pub fn retry_after_millis(&self) -> u64 {
+        self.retry_after.as_millis() as u64 + 1
+    }
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "RateLimited: too many requests from {}, try again in {:.1}s",
+            self.client, self.retry_after.as_secs_f64()
+        )
+    }
+}
+
+impl Error for RateLimited {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(RateLimit::new(2.0, Some(3.0)).unwrap());
+        let start = Instant::now();
+        // A full bucket's worth goes straight through, and then the client has to wait.
+        for _ in 0..3 {
+            assert!(limiter.take_at("a", start).is_ok());
+        }
+        let retry_after = limiter.take_at("a", start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(500));
+        // Other clients have buckets of their own.
+        assert!(limiter.take_at("b", start).is_ok());
+        // Tokens come back at the rate, and no faster.
+        assert!(limiter.take_at("a", start + Duration::from_millis(500)).is_ok());
+        assert!(limiter.take_at("a", start + Duration::from_millis(600)).is_err());
+        // Nor past the burst.
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.take_at("a", later).is_ok());
+        }
+        assert!(limiter.take_at("a", later).is_err());
+
+        let limited = limiter.take("a").unwrap_err();
+        assert_eq!(limited.client, "a");
+        let error = limited.craft_error();
+        assert_eq!(error.get_kind(), ErrorResponse_Kind::RATE_LIMITED);
+        assert!(error.get_retry_after_millis() > 0);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        assert_eq!(RateLimit::new(0.5, None).unwrap().burst, 1.0);
+        assert_eq!(RateLimit::new(10.0, None).unwrap().burst, 10.0);
+        assert!(RateLimit::new(0.0, None).is_err());
+        assert!(RateLimit::new(1.0, Some(0.5)).is_err());
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+
+use protobuf::{Message, RepeatedField};
+use sqlx::{Column, Connection, Row as _, SqliteConnection, TypeInfo, ValueRef};
+use sqlx::sqlite::SqliteRow;
+
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::response::{ResultBatch, Row, Value};
+use crate::workload::Workload;
+
+/// The default number of rows per `ResultBatch` when a result set is streamed to a client.
+pub const RESULT_BATCH_SIZE: usize = 1000;
+
+/// Converts a single value out of a result row into its wire representation.
+fn craft_value(row: &SqliteRow, i: usize) -> Result<Value> {
+    let mut value = Value::new();
+
+    // See the comment in `Worker::print_result` for why we have to try every output type in turn.
+    // NULL is the one case we can check for directly.
+    if row.try_get_raw(i)?.is_null() {
+        value.set_null(true);
+    } else if let Ok(v) = row.try_get::<i64, _>(i) {
+        value.set_integer(v);
+    } else if let Ok(v) = row.try_get::<f64, _>(i) {
+        value.set_real(v);
+    } else if let Ok(v) = row.try_get::<String, _>(i) {
+        // Dates and times are stored as text (see `Table::dump`), so the only way to tell them
+        // apart from any other text is the declared type of the column they came out of. Note
+        // that only plain column references have one; e.g. `SELECT date(d)` comes back as text.
+        match row.columns()[i].type_info().name() {
+            "DATE" => value.set_date(v),
+            "DATETIME" => value.set_datetime(v),
+            _ => value.set_text(v),
+        }
+    } else if let Ok(v) = row.try_get::<Vec<u8>, _>(i) {
+        value.set_blob(v);
+    } else {
+        Err(WorkerError::new(
+            ErrKind::DatabaseError,
+            "Result set has output type not understood by the worker."
+        ))?
+    }
+    Ok(value)
+}
+
+/// Formats a value for display, e.g. by `Worker::print_result`.
+pub fn format_value(value: &Value) -> String {
+    if value.has_integer() {
+        value.get_integer().to_string()
+    } else if value.has_real() {
+        value.get_real().to_string()
+    } else if value.has_text() {
+        value.get_text().to_owned()
+    } else if value.has_date() {
+        value.get_date().to_owned()
+    } else if value.has_datetime() {
+        value.get_datetime().to_owned()
+    } else if value.has_blob() {
+        format!("<{} bytes>", value.get_blob().len())
+    } else {
+        "NULL".to_owned()
+    }
+}
+
+/// Reads the column names off of a result row.
+pub fn craft_columns(row: &SqliteRow) -> Vec<String> {
+    row.columns().iter().map(|column| column.name().to_owned()).collect()
+}
+
+/// Serializes a single result row.
+pub fn craft_row(row: &SqliteRow) -> Result<Row> {
+    let mut out_row = Row::new();
+    let values = (0..row.len()).map(|i| craft_value(row, i)).collect::<Result<Vec<_>>>()?;
+    out_row.set_values(RepeatedField::from_vec(values));
+    Ok(out_row)
+}
+
+/// Assembles already-serialized rows into a batch.
+pub fn craft_batch(job_id: u64, columns: &[String], rows: Vec<Row>, last: bool) -> ResultBatch {
+    let mut batch = ResultBatch::new();
+    batch.set_job_id(job_id);
+    batch.set_columns(RepeatedField::from_vec(columns.to_vec()));
+    batch.set_rows(RepeatedField::from_vec(rows));
+    batch.set_last(last);
+    batch
+}
+
+/// Serializes a job's (entire) result set.
+pub fn craft_result_batch(job_id: u64, rows: &[SqliteRow]) -> Result<ResultBatch> {
+    let columns = rows.first().map(craft_columns).unwrap_or_default();
+    let out_rows = rows.iter().map(craft_row).collect::<Result<Vec<_>>>()?;
+    let mut batch = craft_batch(job_id, &columns, out_rows, true);
+    batch.set_op_last(true);
+    Ok(batch)
+}
+
+/// Puts streamed result batches back together into whole result sets, keyed on the sequence
+/// number of the op each one came from.
+pub fn collect_result_sets(batches: Vec<ResultBatch>) -> BTreeMap<i32, ResultBatch> {
+    let mut result_sets: BTreeMap<i32, ResultBatch> = BTreeMap::new();
+    for mut batch in batches {
+        let result_set = result_sets.entry(batch.get_op_sequence_num()).or_insert_with(|| {
+            let mut result_set = ResultBatch::new();
+            result_set.set_job_id(batch.get_job_id());
+            result_set.set_op_sequence_num(batch.get_op_sequence_num());
+            result_set
+        });
+        if result_set.get_columns().is_empty() {
+            result_set.set_columns(batch.take_columns());
+        }
+        for row in batch.take_rows().into_vec() {
+            result_set.mut_rows().push(row);
+        }
+        // The flags are only ever set on the final batch, so they can just be OR-ed together.
+        result_set.set_last(result_set.get_last() || batch.get_last());
+        result_set.set_op_last(result_set.get_op_last() || batch.get_op_last());
+        result_set.set_truncated(result_set.get_truncated() || batch.get_truncated());
+        if batch.has_metrics() {
+            result_set.set_metrics(batch.take_metrics());
+        }
+    }
+    result_sets
+}
+
+/// Quotes a column or table name, e.g. one that came out of a result set, for use in SQL.
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace("\"", "\"\""))
+}
+
+/// Loads a result set into a table, e.g. so that the result sets of several jobs can be combined
+/// with SQL. The table is created if it doesn't exist yet, and appended to if it does. Its
+/// columns are declared without a type, so every value keeps the type it came over the wire
+/// with (dates and datetimes become text).
+pub async fn load_result_set(
+    conn: &mut SqliteConnection, table: &str, result_set: &ResultBatch
+) -> Result<()> {
+    let columns = result_set.get_columns();
+    if columns.is_empty() {
+        return Ok(());
+    }
+    let table = quote_identifier(table);
+    let create_query = format!(
+        "CREATE TABLE IF NOT EXISTS {} ({})",
+        table,
+        columns.iter().map(|column| quote_identifier(column)).collect::<Vec<_>>().join(", ")
+    );
+    sqlx::query(&create_query).execute(&mut *conn).await?;
+
+    let insert_query = format!(
+        "INSERT INTO {} VALUES ({})", table, vec!["?"; columns.len()].join(", ")
+    );
+    let mut tx = conn.begin().await?;
+    for row in result_set.get_rows() {
+        let mut query = sqlx::query(&insert_query);
+        for value in row.get_values() {
+            query = if value.has_integer() {
+                query.bind(value.get_integer())
+            } else if value.has_real() {
+                query.bind(value.get_real())
+            } else if value.has_text() {
+                query.bind(value.get_text().to_owned())
+            } else if value.has_date() {
+                query.bind(value.get_date().to_owned())
+            } else if value.has_datetime() {
+                query.bind(value.get_datetime().to_owned())
+            } else if value.has_blob() {
+                query.bind(value.get_blob().to_vec())
+            } else {
+                query.bind(None::<i64>)
+            };
+        }
+        query.execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Runs a query, returning its entire result set as a single batch.
+pub async fn query_result_set(conn: &mut SqliteConnection, sql: &str) -> Result<ResultBatch> {
+    let rows = sqlx::query(sql).fetch_all(&mut *conn).await?;
+    craft_result_batch(0, &rows)
+}
+
+/// Caps on the size of a job's result set, taken from its workload. Zero means no limit.
+///
+/// A query like `SELECT * FROM dataset_1` over a large table can produce far more rows than anyone
+/// actually wants to look at, and the worker holds onto result batches until they are fetched. So
+/// a workload can cap its result set, and choose between having it cut short (with the final
+/// batch marked `truncated`) or failing outright with a `ResultLimitError`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResultLimits {
+    pub max_rows: u64,
+    pub max_bytes: u64,
+    pub truncate: bool,
+}
+
+impl ResultLimits {
+    pub fn from_workload(workload: &Workload) -> ResultLimits {
+        ResultLimits {
+            max_rows: workload.get_max_result_rows(),
+            max_bytes: workload.get_max_result_bytes(),
+            truncate: workload.get_truncate_results(),
+        }
+    }
+
+    /// Checks whether a result set of `n_rows` rows, taking up `n_bytes` bytes, is within the
+    /// limits.
+    pub fn allows(&self, n_rows: u64, n_bytes: u64) -> bool {
+        (self.max_rows == 0 || n_rows <= self.max_rows) &&
+            (self.max_bytes == 0 || n_bytes <= self.max_bytes)
+    }
+
+    /// Describes why a result set outgrew the limits.
+    pub fn exceeded_error(&self) -> WorkerError {
+        WorkerError::new(
+            ErrKind::ResultLimitError,
+            &format!(
+                "Result set outgrew the workload's limits of {} rows and {} bytes (0 is no limit).",
+                self.max_rows, self.max_bytes
+            )
+        )
+    }
+}
+
+/// Splits a batch into pieces whose encoded size is at most `max_bytes`, e.g. so that they fit
+/// into a frame. Only the final piece keeps the original batch's `last` flag.
+///
+/// Batches are cut by row count, not by size, so on a table with very wide rows a batch can
+/// outgrow a frame; when that happens we just keep halving it until it fits.
+pub fn split_result_batch(batch: ResultBatch, max_bytes: usize) -> Result<Vec<ResultBatch>> {
+    if batch.compute_size() as usize <= max_bytes {
+        return Ok(vec![batch]);
+    }
+    let rows = batch.get_rows();
+    if rows.len() <= 1 {
+        Err(WorkerError::new(
+            ErrKind::ProtocolError,
+            &format!("Result row is too large to send (more than {} bytes).", max_bytes)
+        ))?
+    }
+
+    let mid = rows.len() / 2;
+    let mut head = batch.clone();
+    head.set_rows(rows[..mid].to_vec().into());
+    head.set_last(false);
+    head.set_op_last(false);
+    head.set_truncated(false);
+    head.clear_metrics();
+    let mut tail = batch.clone();
+    tail.set_rows(rows[mid..].to_vec().into());
+
+    let mut pieces = split_result_batch(head, max_bytes)?;
+    pieces.extend(split_result_batch(tail, max_bytes)?);
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use sqlx::{Connection, SqliteConnection};
+
+    use super::*;
+
+    #[test]
+    fn test_craft_result_batch() {
+        let mut conn = block_on(SqliteConnection::connect("sqlite::memory:")).unwrap();
+        let rows = block_on(
+            sqlx::query("SELECT 1 AS a, 'x' AS b, 1.5 AS c, NULL AS d").fetch_all(&mut conn)
+        ).unwrap();
+
+        let batch = craft_result_batch(7, &rows).unwrap();
+        assert_eq!(batch.get_job_id(), 7);
+        assert_eq!(batch.get_columns(), &["a", "b", "c", "d"]);
+        assert_eq!(batch.get_rows().len(), 1);
+
+        let values = batch.get_rows()[0].get_values();
+        assert_eq!(values[0].get_integer(), 1);
+        assert_eq!(values[1].get_text(), "x");
+        assert_eq!(values[2].get_real(), 1.5);
+        assert!(values[3].has_null());
+    }
+
+    #[test]
+    fn test_craft_date_values() {
+        let mut conn = block_on(SqliteConnection::connect("sqlite::memory:")).unwrap();
+        block_on(sqlx::query(
+            "CREATE TABLE foo (d date, t datetime); \
+            INSERT INTO foo VALUES ('2021-03-01', '2021-03-01 10:00:00');"
+        ).execute(&mut conn)).unwrap();
+        let rows = block_on(
+            sqlx::query("SELECT d, t, date(d) AS e FROM foo").fetch_all(&mut conn)
+        ).unwrap();
+
+        let batch = craft_result_batch(7, &rows).unwrap();
+        let values = batch.get_rows()[0].get_values();
+        assert_eq!(values[0].get_date(), "2021-03-01");
+        assert_eq!(values[1].get_datetime(), "2021-03-01 10:00:00");
+        assert_eq!(values[2].get_text(), "2021-03-01");
+        assert_eq!(format_value(&values[1]), "2021-03-01 10:00:00");
+    }
+
+    #[test]
+    fn test_collect_result_sets() {
+        let mut batches = vec![];
+        for (op_sequence_num, n_rows, op_last, last) in
+            vec![(1, 2, false, false), (1, 1, true, false), (2, 3, true, true)] {
+            let mut batch = craft_batch(7, &["a".to_owned()], vec![Row::new(); n_rows], last);
+            batch.set_op_sequence_num(op_sequence_num);
+            batch.set_op_last(op_last);
+            batches.push(batch);
+        }
+
+        let result_sets = collect_result_sets(batches);
+        assert_eq!(result_sets.len(), 2);
+        assert_eq!(result_sets[&1].get_rows().len(), 3);
+        assert!(result_sets[&1].get_op_last());
+        assert!(!result_sets[&1].get_last());
+        assert_eq!(result_sets[&2].get_rows().len(), 3);
+        assert!(result_sets[&2].get_last());
+    }
+
+    #[test]
+    fn test_load_result_set() {
+        let mut conn = block_on(SqliteConnection::connect("sqlite::memory:")).unwrap();
+        let craft_partial = |status: &str, n: i64| {
+            let mut row = Row::new();
+            let mut status_value = Value::new();
+            status_value.set_text(status.to_owned());
+            let mut n_value = Value::new();
+            n_value.set_integer(n);
+            row.set_values(RepeatedField::from_vec(vec![status_value, n_value]));
+            craft_batch(7, &["status".to_owned(), "n".to_owned()], vec![row], true)
+        };
+        for (status, n) in vec![("ok", 2), ("ok", 3), ("error", 1)] {
+            block_on(load_result_set(&mut conn, "partials", &craft_partial(status, n))).unwrap();
+        }
+
+        let merged = block_on(query_result_set(
+            &mut conn, "SELECT status, SUM(n) AS n FROM partials GROUP BY status ORDER BY status"
+        )).unwrap();
+        assert_eq!(merged.get_columns(), &["status", "n"]);
+        let rows = merged.get_rows();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get_values()[0].get_text(), "error");
+        assert_eq!(rows[1].get_values()[1].get_integer(), 5);
+    }
+
+    #[test]
+    fn test_result_limits() {
+        let unlimited = ResultLimits::default();
+        assert!(unlimited.allows(u64::MAX, u64::MAX));
+
+        let limits = ResultLimits { max_rows: 10, max_bytes: 100, truncate: false };
+        assert!(limits.allows(10, 100));
+        assert!(!limits.allows(11, 100));
+        assert!(!limits.allows(10, 101));
+    }
+
+    #[test]
+    fn test_split_result_batch() {
+        let mut row = Row::new();
+        let mut value = Value::new();
+        value.set_text("x".repeat(100));
+        row.set_values(RepeatedField::from_vec(vec![value]));
+        let mut batch = ResultBatch::new();
+        batch.set_rows(RepeatedField::from_vec(vec![row; 8]));
+        batch.set_last(true);
+
+        // Small enough already.
+        assert_eq!(split_result_batch(batch.clone(), 65535).unwrap().len(), 1);
+
+        let pieces = split_result_batch(batch.clone(), 300).unwrap();
+        assert!(pieces.len() > 1);
+        assert_eq!(pieces.iter().map(|piece| piece.get_rows().len()).sum::<usize>(), 8);
+        assert!(pieces.iter().all(|piece| piece.compute_size() <= 300));
+        assert!(pieces[..pieces.len() - 1].iter().all(|piece| !piece.get_last()));
+        assert!(pieces[pieces.len() - 1].get_last());
+
+        // A single row that can never fit.
+        assert!(split_result_batch(batch, 50).is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use std::error::Error;
+use std::fmt;
+
+use sqlparser::ast::Statement;
+use sqlparser::dialect::SQLiteDialect;
+use sqlparser::parser::Parser;
+use sqlparser::tokenizer::{Token, Tokenizer};
+
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::workload::Workload;
+use crate::dag::schedule;
+use crate::exchange::check_exchange;
+
+/// The classes of SQL statement an op may be restricted to.
+///
+/// Anything `sqlparser` cannot parse at all (which includes SQLite-specific statements like
+/// `ATTACH` and `PRAGMA`) never makes it this far, so it is always rejected in sandbox mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatementClass {
+    Select,
+    CreateTableAs,
+    CreateTable,
+    CreateIndex,
+    Insert,
+    Update,
+    Delete,
+    Drop,
+    Other,
+}
+
+/// The statement classes allowed in sandbox mode: read queries, and materializing the result of a
+/// read query into a new table.
+pub const SANDBOX_STATEMENTS: [StatementClass; 2] =
+    [StatementClass::Select, StatementClass::CreateTableAs];
+
+impl StatementClass {
+    /// Parses a statement class from its config name, e.g. `create_table_as`.
+    pub fn from_name(name: &str) -> Result<StatementClass> {
+        let class = match name.trim().to_lowercase().as_str() {
+            "select" => StatementClass::Select,
+            "create_table_as" => StatementClass::CreateTableAs,
+            "create_table" => StatementClass::CreateTable,
+            "create_index" => StatementClass::CreateIndex,
+            "insert" => StatementClass::Insert,
+            "update" => StatementClass::Update,
+            "delete" => StatementClass::Delete,
+            "drop" => StatementClass::Drop,
+            _ => Err(WorkerError::new(
+                ErrKind::ValidationError,
+                &format!("Unknown statement class {:?}.", name)
+            ))?
+        };
+        Ok(class)
+    }
+
+    /// The class's config name, i.e. the one `from_name` parses. `Other` can't be allowed by
+    /// name, but is called "other".
+    pub fn name(&self) -> &'static str {
+        match self {
+            StatementClass::Select => "select",
+            StatementClass::CreateTableAs => "create_table_as",
+            StatementClass::CreateTable => "create_table",
+            StatementClass::CreateIndex => "create_index",
+            StatementClass::Insert => "insert",
+            StatementClass::Update => "update",
+            StatementClass::Delete => "delete",
+            StatementClass::Drop => "drop",
+            StatementClass::Other => "other",
+        }
+    }
+
+    pub fn of(statement: &Statement) -> StatementClass {
+        match statement {
+            Statement::Query(_) => StatementClass::Select,
+            Statement::CreateTable { query: Some(_), .. } => StatementClass::CreateTableAs,
+            Statement::CreateTable { .. } => StatementClass::CreateTable,
+            Statement::CreateIndex { .. } => StatementClass::CreateIndex,
+            Statement::Insert { .. } => StatementClass::Insert,
+            Statement::Update { .. } => StatementClass::Update,
+            Statement::Delete { .. } => StatementClass::Delete,
+            Statement::Drop { .. } => StatementClass::Drop,
+            _ => StatementClass::Other,
+        }
+    }
+}
+
+/// A workload failed validation because of one of its ops.
+#[derive(Debug)]
+pub struct InvalidOp {
+    /// The position of the offending op in the workload, counting from 0.
+    pub op_index: usize,
+    pub message: String,
+}
+
+impl fmt::Display for InvalidOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ValidationError when checking op {}: {}", self.op_index, self.message)
+    }
+}
+
+impl Error for InvalidOp {}
+
+/// Parses `sql`, and if `allowed` is given, checks that every statement in it belongs to one of
+/// the `allowed` classes. Returns what is wrong with it, if anything.
+fn check_statement(
+    sql: &str, allowed: Option<&[StatementClass]>
+) -> std::result::Result<(), String> {
+    let statements = Parser::parse_sql(&SQLiteDialect {}, sql)
+        .map_err(|err| format!("Could not parse statement {:?}: {}", sql, err))?;
+    let allowed = match allowed {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    for statement in statements.iter() {
+        let class = StatementClass::of(statement);
+        if !allowed.contains(&class) {
+            return Err(
+                format!("Statement {:?} has class {:?}, which is not allowed.", sql, class)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Parses `sql` and checks that every statement in it belongs to one of the `allowed` classes.
+pub fn validate_statement(sql: &str, allowed: &[StatementClass]) -> Result<()> {
+    check_statement(sql, Some(allowed))
+        .map_err(|message| WorkerError::new(ErrKind::ValidationError, &message))?;
+    Ok(())
+}
+
+/// Checks a workload before it is run: every op's statement has to parse, and if `allowed` is
+/// given, only contain statements of the `allowed` classes. The first op that doesn't pass is
+/// reported as an `InvalidOp`.
+///
+/// This is much cheaper than finding out half-way through the job, after all of its files have
+/// been downloaded and loaded. Note that it does mean that statements have to be SQL that
+/// `sqlparser` understands, which doesn't cover every last SQLite-ism (e.g. `PRAGMA`).
+pub fn validate_workload(workload: &Workload, allowed: Option<&[StatementClass]>) -> Result<()> {
+    for (op_index, op) in workload.get_ops().iter().enumerate() {
+        check_statement(op.get_statement(), allowed)
+            .map_err(|message| InvalidOp { op_index, message })?;
+        if op.has_exchange() {
+            check_exchange(op.get_exchange())
+                .map_err(|err| InvalidOp { op_index, message: err.to_string() })?;
+        }
+    }
+    // The ops' dependencies have to make sense too.
+    schedule(workload.get_ops())?;
+    // Checkpoints are kept in the worker's database, under the workload's idempotency key.
+    if workload.get_checkpoint() && workload.get_idempotency_key().is_empty() {
+        Err(WorkerError::new(
+            ErrKind::ValidationError, "Checkpointed workloads need an idempotency key."
+        ))?
+    }
+    if workload.get_checkpoint() && workload.get_ephemeral() {
+        Err(WorkerError::new(
+            ErrKind::ValidationError, "Ephemeral workloads can't be checkpointed."
+        ))?
+    }
+    Ok(())
+}
+
+/// Renders a token back into SQL. Tokens print as they were written, except for string literals,
+/// which the tokenizer unescapes (`'it''s'` becomes `it's`), and so have to be escaped again.
+fn render_token(token: &Token) -> String {
+    match token {
+        Token::SingleQuotedString(s) => format!("'{}'", s.replace('\'', "''")),
+        Token::NationalStringLiteral(s) => format!("N'{}'", s.replace('\'', "''")),
+        _ => token.to_string(),
+    }
+}
+
+/// Splits an op's SQL into its statements, e.g. `CREATE INDEX ...; SELECT ...` into the `CREATE
+/// INDEX` and the `SELECT`. Only semicolons outside of string literals, quoted identifiers, and
+/// comments count, which is why this goes through `sqlparser`'s tokenizer instead of just
+/// splitting on `;`. Empty statements (e.g. after a trailing semicolon) are left out.
+pub fn split_statements(sql: &str) -> Result<Vec<String>> {
+    let tokens = Tokenizer::new(&SQLiteDialect {}, sql).tokenize().map_err(|err| {
+        WorkerError::new(
+            ErrKind::ValidationError,
+            &format!("Could not tokenize statement {:?}: {:?}", sql, err)
+        )
+    })?;
+    let mut statements = vec![];
+    let mut statement = String::new();
+    // Whether or not the statement so far has anything other than whitespace and comments in it.
+    let mut has_content = false;
+    for token in tokens.iter() {
+        match token {
+            Token::SemiColon => {
+                if has_content { statements.push(statement.trim().to_owned()); }
+                statement.clear();
+                has_content = false;
+            },
+            Token::Whitespace(_) => statement += &render_token(token),
+            _ => {
+                statement += &render_token(token);
+                has_content = true;
+            },
+        }
+    }
+    if has_content { statements.push(statement.trim().to_owned()); }
+    Ok(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_statements() {
+        assert_eq!(
+            split_statements("CREATE TABLE foo (a TEXT);\nSELECT 'a;b' FROM foo;").unwrap(),
+            vec!["CREATE TABLE foo (a TEXT)", "SELECT 'a;b' FROM foo"]
+        );
+        // Escaped quotes survive the round trip, and a trailing comment is not a statement.
+        assert_eq!(
+            split_statements("SELECT 'it''s'; -- done").unwrap(),
+            vec!["SELECT 'it''s'"]
+        );
+        assert_eq!(split_statements("SELECT \"a;b\" FROM foo").unwrap().len(), 1);
+        assert!(split_statements(" ; ").unwrap().is_empty());
+        assert!(split_statements("SELECT 'unterminated").is_err());
+    }
+
+    #[test]
+    fn test_validate_statement_sandbox() {
+        let ok = validate_statement("SELECT * FROM dataset_1", &SANDBOX_STATEMENTS);
+        assert!(ok.is_ok());
+
+        let ok = validate_statement(
+            "CREATE TABLE foo AS SELECT a FROM dataset_1", &SANDBOX_STATEMENTS
+        );
+        assert!(ok.is_ok());
+
+        let err = validate_statement("DROP TABLE dataset_1", &SANDBOX_STATEMENTS);
+        assert!(err.is_err());
+
+        let err = validate_statement("CREATE TABLE foo (a int)", &SANDBOX_STATEMENTS);
+        assert!(err.is_err());
+
+        // Unparseable SQLite-isms are rejected outright.
+        let err = validate_statement("ATTACH DATABASE '/etc/passwd' AS pw", &SANDBOX_STATEMENTS);
+        assert!(err.is_err());
+        let err = validate_statement("PRAGMA writable_schema = 1", &SANDBOX_STATEMENTS);
+        assert!(err.is_err());
+
+        // A forbidden statement smuggled in behind an allowed one.
+        let err = validate_statement("SELECT 1; DELETE FROM dataset_1", &SANDBOX_STATEMENTS);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_validate_workload() {
+        use protobuf::RepeatedField;
+        use crate::fixtures::*;
+
+        let craft_workload = |statements: &[&str]| {
+            let ops = statements.iter().enumerate().map(|(i, statement)| {
+                craft_op_message(None, Some((*statement).to_owned()), Some(i as i32 + 1))
+            }).collect::<Vec<_>>();
+            craft_workload_message(Some(RepeatedField::from_vec(ops)))
+        };
+
+        let workload = craft_workload(&["SELECT 1", "SELECT * FROM dataset_1"]);
+        assert!(validate_workload(&workload, None).is_ok());
+        assert!(validate_workload(&workload, Some(&SANDBOX_STATEMENTS)).is_ok());
+
+        // Syntax errors are caught whether or not statements are restricted.
+        let workload = craft_workload(&["SELECT 1", "SELEC * FROM dataset_1"]);
+        let err = validate_workload(&workload, None).unwrap_err();
+        assert_eq!(err.downcast_ref::<InvalidOp>().unwrap().op_index, 1);
+
+        let workload = craft_workload(&["DROP TABLE dataset_1", "SELECT 1"]);
+        assert!(validate_workload(&workload, None).is_ok());
+        let err = validate_workload(&workload, Some(&SANDBOX_STATEMENTS)).unwrap_err();
+        assert_eq!(err.downcast_ref::<InvalidOp>().unwrap().op_index, 0);
+    }
+
+    #[test]
+    fn test_statement_class_from_name() {
+        assert_eq!(
+            StatementClass::from_name(" Create_Table_As").unwrap(), StatementClass::CreateTableAs
+        );
+        assert!(StatementClass::from_name("vacuum").is_err());
+        for class in SANDBOX_STATEMENTS.iter() {
+            assert_eq!(StatementClass::from_name(class.name()).unwrap(), *class);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::err::Result;
+use crate::file::get_worker_dir;
+use crate::job::table_name;
+use crate::response::OpMetrics;
+use crate::workload::Op;
+
+/// Where slow ops are logged, by default: next to the worker's cache (see `get_worker_dir`).
+pub fn default_slow_op_log() -> PathBuf {
+    get_worker_dir().join("slow-ops.log")
+}
+
+// The slow-op log. The metrics on a job's final result batch already say how long each of its
+// ops took, but only to whoever submitted the job, and only for as long as they hang on to
+// them. So that slow queries can be looked into after the fact (and across jobs), an op that
+// takes longer than `WorkerConfig.slow_op_threshold` to run is also written to a log file of
+// its own, one line per op:
+//
+//     at=1618000000000 job=3 op=2 duration_micros=2500000 rows_returned=10 rows_affected=0 \
+//         tables=dataset_1,dataset_2 statement="SELECT ..."
+//
+// (`at` is in milliseconds since the epoch.) The tables are the ones the op targets. The worker
+// also counts the slow ops it has seen, which it reports in its status.
+
+/// Logs the ops that run for longer than a threshold.
+#[derive(Debug)]
+pub struct SlowOpLog {
+    pub threshold: Duration,
+    pub path: PathBuf,
+    count: AtomicU64,
+    // Held while a line is written, so that ops finishing at the same time don't interleave.
+    file: Mutex<()>,
+}
+
+impl SlowOpLog {
+    pub fn new(threshold: Duration, path: &Path) -> SlowOpLog {
+        SlowOpLog {
+            threshold,
+            path: path.to_owned(),
+            count: AtomicU64::new(0),
+            file: Mutex::new(()),
+        }
+    }
+
+    /// How many slow ops have been logged so far.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Logs an op that has just finished, if it was slow, returning whether or not it was.
+    pub fn record(&self, job_id: u64, op: &Op, metrics: &OpMetrics) -> Result<bool> {
+        if Duration::from_micros(metrics.get_duration_micros()) < self.threshold {
+            return Ok(false);
+        }
+        self.count.fetch_add(1, Ordering::SeqCst);
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis());
+        let tables = op.get_targets().iter().map(table_name).collect::<Vec<_>>().join(",");
+        let line = format!(
+            "at={} job={} op={} duration_micros={} rows_returned={} rows_affected={} tables={} \
+            statement={:?}\n",
+            at, job_id, op.get_op_sequence_num(), metrics.get_duration_micros(),
+            metrics.get_rows_returned(), metrics.get_rows_affected(), tables, op.get_statement()
+        );
+        let _guard = self.file.lock().unwrap();
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        OpenOptions::new().create(true).append(true).open(&self.path)?
+            .write_all(line.as_bytes())?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::RepeatedField;
+
+    use crate::fixtures::*;
+    use super::*;
+
+    #[test]
+    fn test_slow_op_log() {
+        let path = std::env::temp_dir().join("mini-cluster-test-slow-ops.log");
+        let _ = fs::remove_file(&path);
+        let log = SlowOpLog::new(Duration::from_millis(100), &path);
+        let file = craft_file_message(Some(2), Some("s3://foo/bar.csv".to_owned()));
+        let op = craft_op_message(
+            Some(RepeatedField::from_vec(vec![file])),
+            Some("SELECT *\nFROM dataset_2".to_owned()),
+            Some(1)
+        );
+        let mut metrics = OpMetrics::new();
+        metrics.set_rows_returned(5);
+
+        metrics.set_duration_micros(99_999);
+        assert!(!log.record(7, &op, &metrics).unwrap());
+        assert_eq!(log.count(), 0);
+        assert!(!path.exists());
+
+        metrics.set_duration_micros(250_000);
+        assert!(log.record(7, &op, &metrics).unwrap());
+        assert_eq!(log.count(), 1);
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        assert!(contents.contains(
+            "job=7 op=1 duration_micros=250000 rows_returned=5 rows_affected=0 \
+            tables=dataset_2 statement=\"SELECT *\\nFROM dataset_2\""
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
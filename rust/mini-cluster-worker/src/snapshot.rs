@@ -0,0 +1,189 @@
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use sqlx::SqliteConnection;
+use uuid::Uuid;
+
+use crate::db::Database;
+use crate::encryption::{self, open_unsealed};
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::file::{
+    download_object, download_url, get_cache_dir, parse_file_path, verify_file_etag, with_suffix,
+    ObjectHead, WorkerS3ClientAdapter, WorkerS3ClientTrait,
+};
+use crate::gc::{lease, user_tables};
+use crate::presigned::{is_presigned_url, without_query};
+use crate::response::Snapshot;
+
+// Database snapshots. Everything a worker loads ends up in its database, which is what lets a
+// job reuse the tables an earlier one loaded (see `TableFingerprint`). But a new worker starts
+// out with an empty one, so a fleet of new workers each download and ingest the same CSVs all
+// over again before they're any use.
+//
+// So a worker can be asked to snapshot its database to S3 (a SNAPSHOT frame; see `take`), and a
+// new worker can start out from a snapshot (`WorkerConfig.restore_snapshot`; see `restore`)
+// instead of from nothing. The snapshot carries the table registry along with the tables, so
+// the tables in it are reused by jobs that ask for the same version of the same files, just as
+// they would be on the worker they were loaded on.
+//
+// A snapshot is taken with `VACUUM INTO`, which copies the database as of one transaction
+// into a fresh file, compacting it on the way. Jobs carry on whilst it runs; a table a job is
+// still loading makes it into the snapshot without a fingerprint, and is loaded again by
+// whoever needs it (and collected as garbage otherwise).
+//
+// The snapshot of an encrypted database (see `encryption`) is encrypted with the same key, and
+// can only be restored by workers that have it.
+
+/// The first bytes of every SQLite database file.
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// Copies the database `conn` is connected to into a new database file at `fp`, replacing
+/// whatever is there.
+pub async fn export(conn: &mut SqliteConnection, fp: &Path) -> Result<()> {
+    // `VACUUM INTO` won't overwrite a file.
+    if fp.exists() {
+        fs::remove_file(fp)?;
+    }
+    let filename = fp.to_string_lossy().into_owned();
+    sqlx::query("VACUUM INTO ?").bind(filename).execute(&mut *conn).await?;
+    Ok(())
+}
+
+/// Whether or not the file at `fp` is a SQLite database.
+pub fn is_database_file(fp: impl AsRef<Path>) -> Result<bool> {
+    let mut header = vec![0; SQLITE_HEADER.len()];
+    let mut file = fs::File::open(fp)?;
+    Ok(file.read_exact(&mut header).is_ok() && header == SQLITE_HEADER)
+}
+
+/// Snapshots the worker's database to the given S3 path. The snapshot is written to the cache
+/// directory first, and removed from there once it is uploaded.
+pub async fn take<T: WorkerS3ClientTrait>(
+    client: &WorkerS3ClientAdapter<T>, path: &str
+) -> Result<Snapshot> {
+    // Checked before the export, which can take a while on a big database.
+    parse_file_path(path)?;
+    let fp = get_cache_dir().join(format!("snapshot-{}.sqlite", Uuid::new_v4()));
+    let mut conn = Database::connect().await?;
+    let exported = export(&mut conn, &fp).await;
+    drop(conn);
+    exported?;
+
+    let bytes = fs::metadata(&fp)?.len();
+    // The database isn't sealed, encrypted or not (see `encryption`), so it's uploaded as it is.
+    let (mut file, len) = open_unsealed(&fp)?;
+    let uploaded = client.put_reader(path, &mut file, len).await;
+    drop(file);
+    fs::remove_file(&fp)?;
+    let mut snapshot = Snapshot::new();
+    snapshot.set_path(path.to_owned());
+    snapshot.set_etag(uploaded?);
+    snapshot.set_bytes(bytes);
+    log!("Snapshotted the database to {} ({} bytes).", path, bytes);
+    Ok(snapshot)
+}
+
+/// Checks that a downloaded snapshot is all there, and is a SQLite database. An encrypted
+/// database looks like noise until it's opened, so if the cache is encrypted, the most that can
+/// be checked is that the snapshot isn't a database in the clear.
+fn check_snapshot(path: &str, fp: &Path, head: &ObjectHead) -> Result<()> {
+    verify_file_etag(path, open_unsealed(fp)?.0, head)?;
+    match (encryption::key().is_some(), is_database_file(fp)?) {
+        (false, false) => Err(WorkerError::new(
+            ErrKind::DatabaseError, &format!("{} is not a SQLite database.", path)
+        ))?,
+        (true, true) => Err(WorkerError::new(
+            ErrKind::DatabaseError,
+            &format!("{} is not encrypted, and the worker's database has to be.", path)
+        ))?,
+        _ => Ok(()),
+    }
+}
+
+/// Replaces the worker's database with the snapshot at the given S3 path (or pre-signed URL),
+/// returning how big it is, in bytes. This mustn't run whilst anything is connected to the
+/// database, so it only ever runs at startup.
+///
+/// The snapshot is downloaded next to the database, checked, and only then moved into place,
+/// so a download that fails partway through leaves the database as it was.
+pub async fn restore<T: WorkerS3ClientTrait>(
+    client: &WorkerS3ClientAdapter<T>, path: &str
+) -> Result<u64> {
+    let shown = without_query(path);
+    let head = client.head(path).await?;
+    let etag = head.etag.clone().ok_or_else(|| WorkerError::new(
+        ErrKind::AWSError, &format!("Object {} has no ETag.", shown)
+    ))?;
+    fs::create_dir_all(get_cache_dir())?;
+    let db_path = Database::get_db_path();
+    let fp = with_suffix(&db_path, ".restoring");
+    let mut file = fs::File::create(&fp)?;
+    let downloaded = if is_presigned_url(path) {
+        download_url(client, path, &head, None, &mut file, &|_| {}).await
+    } else {
+        let bucket_map = parse_file_path(path)?;
+        let bucket = bucket_map.get("bucket").unwrap().clone();
+        let object = bucket_map.get("object").unwrap().clone();
+        download_object(client, bucket, object, etag, None, &mut file, &|_| {}).await
+    };
+    drop(file);
+    if let Err(err) = downloaded.and_then(|()| check_snapshot(shown, &fp, &head)) {
+        fs::remove_file(&fp)?;
+        return Err(err);
+    }
+
+    // Whatever was left of the old database's write-ahead log belongs to the old database.
+    for suffix in ["-wal", "-shm"].iter() {
+        let leftover = with_suffix(&db_path, suffix);
+        if leftover.exists() {
+            fs::remove_file(&leftover)?;
+        }
+    }
+    fs::rename(&fp, &db_path)?;
+
+    // The tables' leases are as old as the snapshot, which would make them garbage straight
+    // away on a worker with a short `table_retention`. They count as used when they arrive.
+    let mut conn = Database::connect().await?;
+    let tables = user_tables(&mut conn).await?;
+    lease(&mut conn, &tables).await?;
+    drop(conn);
+    let bytes = fs::metadata(&db_path)?.len();
+    log!("Restored the database from the snapshot at {} ({} bytes).", shown, bytes);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use sqlx::Connection;
+
+    use super::*;
+
+    #[test]
+    fn test_export() {
+        block_on(async {
+            let database = Database::new_in_memory().await.unwrap();
+            let mut conn = database.connection().await.unwrap();
+            sqlx::query("CREATE TABLE foo (a INTEGER)").execute(&mut *conn).await.unwrap();
+            sqlx::query("INSERT INTO foo VALUES (1), (2)").execute(&mut *conn).await.unwrap();
+
+            // Exporting twice to the same file replaces it.
+            let fp = &std::env::temp_dir().join("mini-cluster-test-export.sqlite");
+            export(&mut conn, fp).await.unwrap();
+            export(&mut conn, fp).await.unwrap();
+            assert!(is_database_file(fp).unwrap());
+
+            let url = format!("sqlite://{}", fp.display());
+            let mut copy = SqliteConnection::connect(&url).await.unwrap();
+            let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM foo")
+                .fetch_one(&mut copy).await.unwrap();
+            assert_eq!(count, 2);
+            drop(copy);
+
+            fs::write(fp, "a_int\n1\n").unwrap();
+            assert!(!is_database_file(fp).unwrap());
+            fs::remove_file(fp).unwrap();
+        });
+    }
+}
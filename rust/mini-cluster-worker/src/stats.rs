@@ -0,0 +1,207 @@
+use protobuf::RepeatedField;
+use sqlx::{Row, SqliteConnection};
+
+use crate::db::Table;
+use crate::err::Result;
+use crate::response::{ColumnStats, TableStats};
+
+/// The table the worker keeps the statistics of its tables in.
+pub const TABLE_STATS: &str = "_mini_cluster_table_stats";
+/// The table the worker keeps the statistics of its tables' columns in.
+pub const COLUMN_STATS: &str = "_mini_cluster_column_stats";
+
+// Table statistics. The scheduler decides where a workload goes, and how to split it up, without
+// ever seeing the data; all it has to go by is the paths of the files. So whenever `Table::dump`
+// loads a file into a table, the worker `ANALYZE`s the table, for the sake of SQLite's query
+// planner, and records how many rows it has, how many bytes of CSV it was loaded from, and
+// the smallest and largest value in each of its columns.
+//
+// The statistics are kept in the database, right alongside the tables they describe, so they
+// are dropped along with them, and make it into snapshots (see `snapshot`). They are reported in
+// every `WorkerStatus`, and in the metrics of every job that loads (or reuses) the table.
+
+pub(crate) async fn create_stats_if_missing(conn: &mut SqliteConnection) -> Result<()> {
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (\n\
+            name TEXT PRIMARY KEY,\n\
+            n_rows INTEGER NOT NULL,\n\
+            bytes INTEGER NOT NULL\n\
+        );",
+        TABLE_STATS
+    )).execute(&mut *conn).await?;
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (\n\
+            name TEXT NOT NULL,\n\
+            position INTEGER NOT NULL,\n\
+            column_name TEXT NOT NULL,\n\
+            min TEXT,\n\
+            max TEXT,\n\
+            PRIMARY KEY (name, position)\n\
+        );",
+        COLUMN_STATS
+    )).execute(&mut *conn).await?;
+    Ok(())
+}
+
+/// `ANALYZE`s the given table and records its statistics, replacing any earlier ones. `bytes` is
+/// how many bytes of CSV it was loaded from.
+pub async fn collect(conn: &mut SqliteConnection, table: &str, bytes: u64) -> Result<TableStats> {
+    create_stats_if_missing(&mut *conn).await?;
+    sqlx::query(&format!("ANALYZE {}", table)).execute(&mut *conn).await?;
+    // `ANALYZE` only records what the query planner needs (in `sqlite_stat1`), which isn't much
+    // use to the scheduler, so the rest takes a scan of our own.
+    let names: Vec<(String,)> = sqlx::query_as(
+        "SELECT name FROM pragma_table_info(?) ORDER BY cid"
+    ).bind(table).fetch_all(&mut *conn).await?;
+    let mut aggregates = vec!["COUNT(*)".to_owned()];
+    aggregates.extend(names.iter().map(|(name,)| {
+        format!("CAST(MIN({0}) AS TEXT), CAST(MAX({0}) AS TEXT)", name)
+    }));
+    let row = sqlx::query(&format!("SELECT {} FROM {}", aggregates.join(", "), table))
+        .fetch_one(&mut *conn)
+        .await?;
+    let rows: i64 = row.try_get(0)?;
+    let mut columns = vec![];
+    for (i, (name,)) in names.into_iter().enumerate() {
+        let min: Option<String> = row.try_get(2 * i + 1)?;
+        let max: Option<String> = row.try_get(2 * i + 2)?;
+        let mut column = ColumnStats::new();
+        column.set_name(name);
+        column.set_all_null(min.is_none());
+        column.set_min(min.unwrap_or_default());
+        column.set_max(max.unwrap_or_default());
+        columns.push(column);
+    }
+
+    let mut stats = TableStats::new();
+    stats.set_name(table.to_owned());
+    stats.set_rows(rows as u64);
+    stats.set_bytes(bytes);
+    stats.set_columns(RepeatedField::from_vec(columns));
+    write(&mut *conn, &stats).await?;
+    Ok(stats)
+}
+
+async fn write(conn: &mut SqliteConnection, stats: &TableStats) -> Result<()> {
+    remove(&mut *conn, stats.get_name()).await?;
+    sqlx::query(&format!("INSERT INTO {} VALUES (?, ?, ?)", TABLE_STATS))
+        .bind(stats.get_name())
+        .bind(stats.get_rows() as i64)
+        .bind(stats.get_bytes() as i64)
+        .execute(&mut *conn)
+        .await?;
+    for (position, column) in stats.get_columns().iter().enumerate() {
+        let (min, max) = match column.get_all_null() {
+            true => (None, None),
+            false => (Some(column.get_min()), Some(column.get_max())),
+        };
+        sqlx::query(&format!("INSERT INTO {} VALUES (?, ?, ?, ?, ?)", COLUMN_STATS))
+            .bind(stats.get_name())
+            .bind(position as i64)
+            .bind(column.get_name())
+            .bind(min)
+            .bind(max)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Returns the statistics recorded for the given table, if there are any, along with where the
+/// table was loaded from, if it is registered.
+pub async fn read(conn: &mut SqliteConnection, table: &str) -> Result<Option<TableStats>> {
+    create_stats_if_missing(&mut *conn).await?;
+    let row: Option<(i64, i64)> = sqlx::query_as(
+        &format!("SELECT n_rows, bytes FROM {} WHERE name = ?", TABLE_STATS)
+    ).bind(table).fetch_optional(&mut *conn).await?;
+    let (rows, bytes) = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let columns: Vec<(String, Option<String>, Option<String>)> = sqlx::query_as(&format!(
+        "SELECT column_name, min, max FROM {} WHERE name = ? ORDER BY position", COLUMN_STATS
+    )).bind(table).fetch_all(&mut *conn).await?;
+
+    let mut stats = TableStats::new();
+    stats.set_name(table.to_owned());
+    stats.set_rows(rows as u64);
+    stats.set_bytes(bytes as u64);
+    stats.set_columns(columns.into_iter().map(|(name, min, max)| {
+        let mut column = ColumnStats::new();
+        column.set_name(name);
+        column.set_all_null(min.is_none());
+        column.set_min(min.unwrap_or_default());
+        column.set_max(max.unwrap_or_default());
+        column
+    }).collect());
+    if let Some(fingerprint) = Table::new(table, "").fingerprint(&mut *conn).await? {
+        stats.set_path(fingerprint.path);
+        stats.set_etag(fingerprint.etag);
+    }
+    Ok(Some(stats))
+}
+
+/// Returns the statistics of every table that has them, in order of name.
+pub async fn read_all(conn: &mut SqliteConnection) -> Result<Vec<TableStats>> {
+    create_stats_if_missing(&mut *conn).await?;
+    let names: Vec<(String,)> = sqlx::query_as(
+        &format!("SELECT name FROM {} ORDER BY name", TABLE_STATS)
+    ).fetch_all(&mut *conn).await?;
+    let mut all = vec![];
+    for (name,) in names {
+        all.extend(read(&mut *conn, &name).await?);
+    }
+    Ok(all)
+}
+
+/// Forgets the statistics of the given table, e.g. because it is being dropped.
+pub async fn remove(conn: &mut SqliteConnection, table: &str) -> Result<()> {
+    create_stats_if_missing(&mut *conn).await?;
+    for stats_table in [TABLE_STATS, COLUMN_STATS].iter() {
+        sqlx::query(&format!("DELETE FROM {} WHERE name = ?", stats_table))
+            .bind(table)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use crate::db::Database;
+    use super::*;
+
+    #[test]
+    fn test_collect() {
+        block_on(async {
+            let database = Database::new_in_memory().await.unwrap();
+            let mut conn = database.connection().await.unwrap();
+            sqlx::query("CREATE TABLE foo (a INTEGER, b TEXT)").execute(&mut *conn).await.unwrap();
+            sqlx::query("INSERT INTO foo VALUES (3, NULL), (1, NULL), (2, NULL)")
+                .execute(&mut *conn).await.unwrap();
+            assert!(read(&mut conn, "foo").await.unwrap().is_none());
+
+            let stats = collect(&mut conn, "foo", 42).await.unwrap();
+            assert_eq!(stats.get_rows(), 3);
+            assert_eq!(stats.get_bytes(), 42);
+            let columns = stats.get_columns();
+            assert_eq!(columns.len(), 2);
+            assert_eq!((columns[0].get_min(), columns[0].get_max()), ("1", "3"));
+            assert!(!columns[0].get_all_null());
+            assert!(columns[1].get_all_null());
+            assert_eq!(read(&mut conn, "foo").await.unwrap(), Some(stats.clone()));
+            assert_eq!(read_all(&mut conn).await.unwrap(), vec![stats]);
+
+            // Empty tables have no rows, and no values in any column.
+            sqlx::query("DELETE FROM foo").execute(&mut *conn).await.unwrap();
+            let stats = collect(&mut conn, "foo", 0).await.unwrap();
+            assert_eq!(stats.get_rows(), 0);
+            assert!(stats.get_columns().iter().all(|column| column.get_all_null()));
+
+            remove(&mut conn, "foo").await.unwrap();
+            assert!(read(&mut conn, "foo").await.unwrap().is_none());
+        });
+    }
+}
@@ -0,0 +1,246 @@
+use std::fs;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, NoClientAuth, PrivateKey,
+    RootCertStore, ServerConfig,
+};
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::err::{Result, WorkerError, ErrKind};
+use crate::transport::Address;
+
+// TLS. Out of the box, frames go over the wire in the clear, and the only thing standing between
+// a worker and anyone who can reach its port is the shared secret (see `auth`), which every
+// member of the cluster has to be given. A worker given a certificate and its key instead
+// (`WorkerConfig.tls`) serves every connection over TLS, so nobody can read or tamper with
+// what's sent over them.
+//
+// A worker given the cluster's CA certificate as well only talks to clients that present a
+// certificate the CA signed, i.e. it does mutual TLS. The scheduler (see `WorkerProxy`) then
+// presents a certificate of its own, and checks the worker's against the same CA, which gives
+// both ends an identity the other can check, without a secret to share between them.
+//
+// Certificates and keys are PEM files. Keys may be PKCS #8 or RSA keys.
+
+/// A certificate (chain), and the key that goes with it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Identity {
+    fn load(&self) -> Result<(Vec<Certificate>, PrivateKey)> {
+        Ok((read_certs(&self.cert)?, read_key(&self.key)?))
+    }
+}
+
+/// How a worker serves TLS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerTls {
+    /// The worker's own certificate.
+    pub identity: Identity,
+    /// The CA client certificates have to be signed by. Clients don't need one unless this is
+    /// set.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl ServerTls {
+    /// Loads the certificates and keys, failing if any of them can't be read.
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let verifier = match &self.client_ca {
+            Some(ca) => AllowAnyAuthenticatedClient::new(read_roots(ca)?),
+            None => NoClientAuth::new(),
+        };
+        let mut config = ServerConfig::new(verifier);
+        let (certs, key) = self.identity.load()?;
+        config.set_single_cert(certs, key).map_err(|err| invalid(&self.identity.cert, err))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// How a client connects to workers over TLS.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientTls {
+    /// The CA the workers' certificates have to be signed by.
+    pub ca: PathBuf,
+    /// The certificate to present to workers that ask for one.
+    pub identity: Option<Identity>,
+    /// The name the workers' certificates are checked against. By default, the host the worker
+    /// is reached at, which has to be a DNS name: certificates can't be checked against IP
+    /// addresses, so a cluster that reaches its workers by IP address has to give them a
+    /// certificate for a name, and set this to it.
+    pub server_name: Option<String>,
+}
+
+impl ClientTls {
+    /// Loads the certificates and keys, failing if any of them can't be read.
+    pub fn connector(&self) -> Result<TlsClient> {
+        let mut config = ClientConfig::new();
+        config.root_store = read_roots(&self.ca)?;
+        if let Some(identity) = &self.identity {
+            let (certs, key) = identity.load()?;
+            config.set_single_client_cert(certs, key)
+                .map_err(|err| invalid(&identity.cert, err))?;
+        }
+        Ok(TlsClient {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: self.server_name.clone(),
+        })
+    }
+}
+
+/// What connecting to workers over TLS takes (see `ClientTls::connector`).
+#[derive(Clone)]
+pub struct TlsClient {
+    pub connector: TlsConnector,
+    server_name: Option<String>,
+}
+
+impl TlsClient {
+    /// The name the certificate of the worker at `address` is checked against.
+    pub fn server_name<'a>(&'a self, address: &'a Address) -> Result<DNSNameRef<'a>> {
+        let name = match (&self.server_name, address) {
+            (Some(name), _) => name.as_str(),
+            (None, Address::Host(host, _)) => host.as_str(),
+            (None, Address::Tcp(_)) | (None, Address::Unix(_)) => "localhost",
+        };
+        DNSNameRef::try_from_ascii_str(name).map_err(|_| WorkerError::new(
+            ErrKind::NetworkError,
+            &format!(
+                "Can't check the certificate of the worker at {} against {:?}, which isn't a DNS \
+                name. Set the name its certificate is for instead.", address, name
+            )
+        ).into())
+    }
+}
+
+fn invalid(path: &Path, reason: impl std::fmt::Display) -> WorkerError {
+    WorkerError::new(
+        ErrKind::ValidationError, &format!("{} is no good for TLS: {}", path.display(), reason)
+    )
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = certs(&mut BufReader::new(fs::File::open(path)?))
+        .map_err(|()| invalid(path, "it isn't a PEM file"))?;
+    if certs.is_empty() {
+        Err(invalid(path, "it has no certificates in it"))?
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey> {
+    let pem = fs::read(path)?;
+    let keys = match pkcs8_private_keys(&mut &pem[..]) {
+        Ok(keys) if !keys.is_empty() => keys,
+        _ => rsa_private_keys(&mut &pem[..]).map_err(|()| invalid(path, "it isn't a PEM file"))?,
+    };
+    keys.into_iter().next().ok_or_else(|| invalid(path, "it has no private key in it").into())
+}
+
+fn read_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots.add(&cert).map_err(|err| invalid(path, format!("{:?}", err)))?;
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::fixtures::artifact_path;
+    use crate::transport::Stream;
+
+    /// One of the test certificates (see `tests/artifacts/tls/generate.sh`): `worker` and
+    /// `client`, which the CA signed, or `unsigned`, which it didn't.
+    fn identity(name: &str) -> Identity {
+        Identity {
+            cert: artifact_path(&format!("tls/{}.pem", name)),
+            key: artifact_path(&format!("tls/{}-key.pem", name)),
+        }
+    }
+
+    /// Has a client with the given certificate (if any) connect to a worker over an in-memory
+    /// pipe, and send it a few bytes. Returns whether or not the worker accepted the client,
+    /// and whether or not the bytes made it across.
+    async fn handshake(client_ca: bool, client_identity: Option<&str>) -> (bool, bool) {
+        let server = ServerTls {
+            identity: identity("worker"),
+            client_ca: Some(artifact_path("tls/ca.pem")).filter(|_| client_ca),
+        };
+        let client = ClientTls {
+            ca: artifact_path("tls/ca.pem"),
+            identity: client_identity.map(identity),
+            server_name: None,
+        };
+        let (acceptor, client) = (server.acceptor().unwrap(), client.connector().unwrap());
+        let (client_stream, server_stream) = Stream::pair();
+        let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let (client_stream, server_stream) = tokio::join!(
+            client.connector.connect(name, client_stream),
+            server_stream.accept_tls(&acceptor),
+        );
+        let (mut client_stream, mut server_stream) = match (client_stream, server_stream) {
+            (Ok(client_stream), Ok(server_stream)) => (client_stream, server_stream),
+            (_, server_stream) => return (server_stream.is_ok(), false),
+        };
+        let mut buf = [0 as u8; 5];
+        let written = async {
+            client_stream.write_all(b"hello").await?;
+            client_stream.flush().await
+        };
+        let (_, read) = tokio::join!(written, server_stream.read_exact(&mut buf));
+        (true, read.is_ok() && &buf == b"hello")
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        assert_eq!(handshake(false, None).await, (true, true));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_client_ca() {
+        assert_eq!(handshake(true, Some("client")).await, (true, true));
+        assert_eq!(handshake(true, None).await, (false, false));
+        assert_eq!(handshake(true, Some("unsigned")).await, (false, false));
+    }
+
+    /// The name `server_name` comes up with, if it comes up with one.
+    fn server_name(server_name: Option<&str>, address: &Address) -> Option<String> {
+        let client = TlsClient {
+            connector: TlsConnector::from(Arc::new(ClientConfig::new())),
+            server_name: server_name.map(str::to_owned),
+        };
+        let name: &str = client.server_name(address).ok()?.into();
+        Some(name.to_owned())
+    }
+
+    #[test]
+    fn test_server_name() {
+        let worker = Address::Host("worker-1.internal".to_owned(), 8080);
+        let by_ip = Address::Host("10.0.0.2".to_owned(), 8080);
+        assert_eq!(server_name(None, &worker).unwrap(), "worker-1.internal");
+        assert_eq!(server_name(None, &Address::Tcp(8080)).unwrap(), "localhost");
+        assert_eq!(server_name(None, &by_ip), None);
+        assert_eq!(server_name(Some("worker.cluster"), &by_ip).unwrap(), "worker.cluster");
+    }
+
+    #[test]
+    fn test_read_bad_pem() {
+        let fp = std::env::temp_dir().join("mini-cluster-tls-test.pem");
+        fs::write(&fp, "not a certificate").unwrap();
+        assert!(read_certs(&fp).is_err());
+        assert!(read_key(&fp).is_err());
+        let missing = std::env::temp_dir().join("mini-cluster-tls-test-missing.pem");
+        assert!(read_roots(&missing).is_err());
+    }
+}
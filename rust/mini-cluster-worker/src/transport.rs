@@ -0,0 +1,414 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::client::TlsStream as ClientTlsStream;
+use tokio_rustls::server::TlsStream as ServerTlsStream;
+
+#[cfg(feature = "chaos")]
+use crate::chaos::ChaosStream;
+use crate::err::Result;
+#[cfg(not(unix))]
+use crate::err::{WorkerError, ErrKind};
+use crate::tls::TlsClient;
+
+/// Where a worker listens, and where clients go to reach it.
+///
+/// Workers usually listen on a TCP port, but on a single host (e.g. in tests) a Unix domain socket
+/// saves us from having to hand out port numbers. A bare port is on the local machine; a cluster
+/// spread over several machines names the host too.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Tcp(u16),
+    /// A TCP port on the given host: a hostname (looked up through DNS when connecting), or an
+    /// IPv4 or IPv6 address (without the square brackets).
+    Host(String, u16),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Tcp(port) => write!(f, "port:{}", port),
+            // IPv6 addresses have colons of their own, so they go in brackets, as in URLs.
+            Address::Host(host, port) if host.contains(':') => write!(f, "[{}]:{}", host, port),
+            Address::Host(host, port) => write!(f, "{}:{}", host, port),
+            Address::Unix(path) => write!(f, "path:{}", path.display()),
+        }
+    }
+}
+
+impl Address {
+    /// Parses an address out of a string, e.g. out of an environment variable: a number is a TCP
+    /// port, `host:port` (or `[ipv6]:port`) is a TCP port on that host, and anything else is the
+    /// path to a Unix domain socket.
+    pub fn parse(address: &str) -> Address {
+        let address = address.trim();
+        if let Ok(port) = address.parse::<u16>() {
+            return Address::Tcp(port);
+        }
+        if let Ok(socket_address) = address.parse::<SocketAddr>() {
+            return Address::from(socket_address);
+        }
+        // Paths can have colons in them too, but hardly ever followed by nothing but a number.
+        if let Some(colon) = address.rfind(':') {
+            let (host, port) = (&address[..colon], &address[colon + 1..]);
+            if let Ok(port) = port.parse::<u16>() {
+                if !host.is_empty() && !host.contains('/') {
+                    return Address::Host(host.to_owned(), port);
+                }
+            }
+        }
+        Address::Unix(PathBuf::from(address))
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(address: SocketAddr) -> Address {
+        Address::Host(address.ip().to_string(), address.port())
+    }
+}
+
+impl From<u16> for Address {
+    fn from(port: u16) -> Address {
+        Address::Tcp(port)
+    }
+}
+
+impl From<PathBuf> for Address {
+    fn from(path: PathBuf) -> Address {
+        Address::Unix(path)
+    }
+}
+
+impl From<&str> for Address {
+    fn from(path: &str) -> Address {
+        Address::Unix(PathBuf::from(path))
+    }
+}
+
+/// A listener bound to an `Address`.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(address: &Address) -> Result<Listener> {
+        match address {
+            Address::Tcp(port) => {
+                let addr = format!("127.0.0.1:{port}", port=port.to_string());
+                Ok(Listener::Tcp(TcpListener::bind(addr).await?))
+            },
+            Address::Host(host, port) => {
+                Ok(Listener::Tcp(TcpListener::bind((host.as_str(), *port)).await?))
+            },
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                // Unlike a TCP port, a socket file outlives the process that bound it, so one
+                // left behind by a previous run (e.g. one that crashed) has to be cleaned up
+                // before we can bind to the same path again.
+                match std::fs::remove_file(path) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err)?,
+                    _ => {},
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            },
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unix_unsupported())?,
+        }
+    }
+
+    /// The address the listener was bound to, given the one it was bound with: the same
+    /// address, except that port 0 is swapped for the port the OS picked.
+    pub fn local_address(&self, bound: &Address) -> io::Result<Address> {
+        let port = match self {
+            Listener::Tcp(listener) => listener.local_addr()?.port(),
+            #[cfg(unix)]
+            Listener::Unix(_) => return Ok(bound.clone()),
+        };
+        Ok(match bound {
+            Address::Tcp(_) => Address::Tcp(port),
+            Address::Host(host, _) => Address::Host(host.clone(), port),
+            Address::Unix(_) => bound.clone(),
+        })
+    }
+
+    pub async fn accept(&self) -> io::Result<Stream> {
+        let stream = match self {
+            Listener::Tcp(listener) => Stream::Tcp(listener.accept().await?.0),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Stream::Unix(listener.accept().await?.0),
+        };
+        Ok(stream.with_faults())
+    }
+}
+
+/// A connection between a client and a worker, over either transport, and possibly over TLS
+/// on top of that (see `tls`). All of them implement tokio's `AsyncRead` and `AsyncWrite`, so
+/// this does too, by passing the calls straight through.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    TlsClient(Box<ClientTlsStream<Stream>>),
+    TlsServer(Box<ServerTlsStream<Stream>>),
+    /// One end of an in-memory pipe, for a client in the same process as the worker (see
+    /// `Stream::pair`).
+    Duplex(DuplexStream),
+    /// Any of the others, with faults injected into it (see `chaos`).
+    #[cfg(feature = "chaos")]
+    Chaos(Box<ChaosStream<Stream>>),
+}
+
+/// How many bytes an in-memory pipe (see `Stream::pair`) buffers in each direction before
+/// writes to it wait on reads from the other end.
+pub const DUPLEX_BUFFER_BYTES: usize = 64 * 1024;
+
+impl Stream {
+    pub async fn connect(address: &Address) -> Result<Stream> {
+        let stream = match address {
+            Address::Tcp(port) => {
+                Stream::Tcp(TcpStream::connect(format!("localhost:{}", port)).await?)
+            },
+            // Every address the host resolves to is tried in turn, IPv4 and IPv6 alike.
+            Address::Host(host, port) => {
+                Stream::Tcp(TcpStream::connect((host.as_str(), *port)).await?)
+            },
+            #[cfg(unix)]
+            Address::Unix(path) => Stream::Unix(UnixStream::connect(path).await?),
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unix_unsupported())?,
+        };
+        Ok(stream.with_faults())
+    }
+
+    /// Returns the two ends of an in-memory pipe: whatever is written to one can be read from
+    /// the other. This takes the network out of the picture, e.g. for tests.
+    pub fn pair() -> (Stream, Stream) {
+        let (client, server) = tokio::io::duplex(DUPLEX_BUFFER_BYTES);
+        (Stream::Duplex(client).with_faults(), Stream::Duplex(server).with_faults())
+    }
+
+    /// Has faults injected into the connection, if the worker was built with the `chaos`
+    /// feature. Otherwise, it is left as it is.
+    #[cfg(feature = "chaos")]
+    fn with_faults(self) -> Stream {
+        Stream::Chaos(Box::new(ChaosStream::new(self)))
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    fn with_faults(self) -> Stream {
+        self
+    }
+
+    /// Undoes `with_faults`, for a connection about to go over to TLS. Faults go on the TLS
+    /// stream instead, so that they come out as the same I/O errors as they do over plain
+    /// connections, and not as TLS errors.
+    #[cfg(feature = "chaos")]
+    fn without_faults(self) -> Stream {
+        match self {
+            Stream::Chaos(stream) => stream.into_inner(),
+            stream => stream,
+        }
+    }
+
+    #[cfg(not(feature = "chaos"))]
+    fn without_faults(self) -> Stream {
+        self
+    }
+
+    /// Like `connect`, but over TLS.
+    pub async fn connect_tls(address: &Address, tls: &TlsClient) -> Result<Stream> {
+        let server_name = tls.server_name(address)?;
+        let stream = Stream::connect(address).await?.without_faults();
+        let stream = tls.connector.connect(server_name, stream).await?;
+        Ok(Stream::TlsClient(Box::new(stream)).with_faults())
+    }
+
+    /// Has the client on the other end of a connection that was just accepted go over to TLS.
+    pub async fn accept_tls(self, acceptor: &TlsAcceptor) -> io::Result<Stream> {
+        let stream = acceptor.accept(self.without_faults()).await?;
+        Ok(Stream::TlsServer(Box::new(stream)).with_faults())
+    }
+
+    /// Who is on the other end: the client's IP address over TCP (without the port, which is
+    /// different for every connection), and `local` over a Unix socket or an in-memory pipe.
+    pub fn peer(&self) -> String {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_else(|_| "unknown".to_owned()),
+            #[cfg(unix)]
+            Stream::Unix(_) => "local".to_owned(),
+            Stream::TlsClient(stream) => stream.get_ref().0.peer(),
+            Stream::TlsServer(stream) => stream.get_ref().0.peer(),
+            Stream::Duplex(_) => "local".to_owned(),
+            #[cfg(feature = "chaos")]
+            Stream::Chaos(stream) => stream.get_ref().peer(),
+        }
+    }
+}
+
+/// Unix domain sockets are only there to be had on Unix. Elsewhere (e.g. on Windows), workers
+/// have to listen on a TCP port.
+#[cfg(not(unix))]
+fn unix_unsupported() -> WorkerError {
+    WorkerError::new(
+        ErrKind::NetworkError, "Unix domain sockets aren't supported on this platform."
+    )
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::TlsClient(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::TlsServer(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Duplex(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "chaos")]
+            Stream::Chaos(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::TlsClient(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::TlsServer(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Duplex(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "chaos")]
+            Stream::Chaos(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::TlsClient(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::TlsServer(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Duplex(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "chaos")]
+            Stream::Chaos(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::TlsClient(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::TlsServer(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Duplex(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "chaos")]
+            Stream::Chaos(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(Address::parse("5000"), Address::Tcp(5000));
+        assert_eq!(Address::parse("/tmp/scheduler.sock"), Address::from("/tmp/scheduler.sock"));
+        assert_eq!(
+            Address::parse("worker-1.internal:8080"),
+            Address::Host("worker-1.internal".to_owned(), 8080)
+        );
+        assert_eq!(Address::parse("10.0.0.2:8080"), Address::Host("10.0.0.2".to_owned(), 8080));
+        assert_eq!(Address::parse("[::1]:8080"), Address::Host("::1".to_owned(), 8080));
+    }
+
+    #[test]
+    fn test_display_address_round_trip() {
+        for address in vec![
+            Address::Host("worker-1.internal".to_owned(), 8080),
+            Address::Host("::1".to_owned(), 8080),
+        ] {
+            assert_eq!(Address::parse(&address.to_string()), address);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_host_stream_round_trip() {
+        let address = Address::Host("127.0.0.1".to_owned(), 5100);
+        let listener = Listener::bind(&address).await.unwrap();
+
+        let mut client = Stream::connect(&Address::Host("localhost".to_owned(), 5100))
+            .await
+            .unwrap();
+        let mut server = listener.accept().await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0 as u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_local_address() {
+        let listener = Listener::bind(&Address::Tcp(0)).await.unwrap();
+        let address = listener.local_address(&Address::Tcp(0)).unwrap();
+        assert_ne!(address, Address::Tcp(0));
+        let mut client = Stream::connect(&address).await.unwrap();
+        let mut server = listener.accept().await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0 as u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_pair_round_trip() {
+        let (mut client, mut server) = Stream::pair();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0 as u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        assert_eq!(server.peer(), "local");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_stream_round_trip() {
+        let address = Address::Unix(std::env::temp_dir().join("mini-cluster-transport-test.sock"));
+        let listener = Listener::bind(&address).await.unwrap();
+
+        let mut client = Stream::connect(&address).await.unwrap();
+        let mut server = listener.accept().await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0 as u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Binding a second time cleans up the stale socket file rather than failing.
+        drop(listener);
+        assert!(Listener::bind(&address).await.is_ok());
+    }
+}
@@ -0,0 +1,38 @@
+#!/bin/sh
+# Generates the certificates the TLS tests use (see `src/tls.rs`): a CA, a worker certificate
+# for `localhost` and a client certificate, both signed by the CA, and a client certificate
+# that signs itself, which workers that check client certificates should turn away. They are
+# good for a hundred years, so there should be no need to run this again.
+set -e
+cd "$(dirname "$0")"
+days=36500
+
+openssl req -x509 -newkey rsa:2048 -nodes -keyout ca-key.pem -out ca.pem -days $days \
+    -subj "/CN=mini-cluster test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign"
+
+sign() {
+    name=$1
+    extensions=$2
+    openssl req -newkey rsa:2048 -nodes -keyout "$name-key.pem" -out "$name.csr" \
+        -subj "/CN=mini-cluster test $name"
+    printf "%s\n" "$extensions" > "$name.ext"
+    openssl x509 -req -in "$name.csr" -CA ca.pem -CAkey ca-key.pem -CAcreateserial \
+        -out "$name.pem" -days $days -sha256 -extfile "$name.ext"
+    rm "$name.csr" "$name.ext"
+}
+
+sign worker "basicConstraints=CA:FALSE
+keyUsage=critical,digitalSignature,keyEncipherment
+extendedKeyUsage=serverAuth
+subjectAltName=DNS:localhost"
+sign client "basicConstraints=CA:FALSE
+keyUsage=critical,digitalSignature,keyEncipherment
+extendedKeyUsage=clientAuth"
+
+openssl req -x509 -newkey rsa:2048 -nodes -keyout unsigned-key.pem -out unsigned.pem \
+    -days $days -subj "/CN=mini-cluster test unsigned client" \
+    -addext "basicConstraints=CA:FALSE" -addext "extendedKeyUsage=clientAuth"
+
+# Nothing gets signed with the CA after this, so its key isn't kept.
+rm ca-key.pem ca.srl
@@ -1,5 +1,9 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use serial_test::serial;
-use protobuf::RepeatedField;
+use protobuf::{Message, RepeatedField};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
 // use tokio::{io::AsyncWriteExt, net::{TcpStream, TcpListener}};
 
 use mini_cluster_worker::file::{
@@ -7,8 +11,16 @@ use mini_cluster_worker::file::{
 };
 use mini_cluster_worker::job::Job;
 use mini_cluster_worker::fixtures::{
-    craft_file_message, craft_workload_message, craft_op_message
+    craft_file_message, craft_workload_message, craft_op_message, spawn_in_process_worker
+};
+use mini_cluster_worker::{health, Worker};
+use mini_cluster_worker::config::WorkerConfig;
+use mini_cluster_worker::protocol::{self, craft_frame, FrameHeader, HEADER_LENGTH};
+use mini_cluster_worker::transport::{Address, Stream};
+use mini_cluster_worker::response::{
+    Ack, ErrorResponse, ErrorResponse_Kind, ResultBatch, WorkerStatus
 };
+use mini_cluster_worker::workload::FetchResults;
 // use mini_cluster_worker::Worker;
 
 #[tokio::test]
@@ -81,29 +93,299 @@ async fn test_run_job() {
     let build_result = job.build(create_new_s3_client()).await;
     assert!(build_result.is_ok());
 
-    let run_result = job.run().await;
+    let mut batches = vec![];
+    let run_result = job.run(1, 1000, |batch| batches.push(batch)).await;
     assert!(run_result.is_ok());
-    let result = run_result.unwrap();
+    assert_eq!(run_result.unwrap(), 1);
+
+    assert_eq!(batches.len(), 1);
+    assert_eq!(batches[0].get_rows().len(), 1);
+    assert!(batches[0].get_last());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial]
+async fn test_plan_job() {
+    let f = craft_file_message(
+        Some(1), Some("s3://mini-cluster-tests/simple-csv.csv".to_owned())
+    );
+    let op = craft_op_message(
+        Some(RepeatedField::from_vec(vec![f])),
+        Some("SELECT * FROM dataset_1".to_owned()),
+        Some(1),
+    );
+    let mut workload = craft_workload_message(
+        Some(RepeatedField::from_vec(vec![op])),
+    );
+    workload.set_dry_run(true);
+
+    let job = Job::new(workload).await.unwrap();
+    let plan = job.plan(&create_new_s3_client()).await.unwrap();
+    assert_eq!(plan.get_files().len(), 1);
+    assert_eq!(plan.get_files()[0].get_table(), "dataset_1");
+    assert!(plan.get_files()[0].get_size() > 0);
+    assert!(!plan.get_files()[0].get_columns().is_empty());
+    assert_eq!(plan.get_ops()[0].get_tables(), &["dataset_1".to_owned()]);
+    assert!(plan.get_ops()[0].get_return_result());
+
+    let f = craft_file_message(
+        Some(2), Some("s3://mini-cluster-tests/no-such-file.csv".to_owned())
+    );
+    let op = craft_op_message(
+        Some(RepeatedField::from_vec(vec![f])), Some("SELECT 1".to_owned()), Some(1)
+    );
+    let job = Job::new(craft_workload_message(Some(RepeatedField::from_vec(vec![op]))))
+        .await.unwrap();
+    assert!(job.plan(&create_new_s3_client()).await.is_err());
+}
+
+async fn read_header<S: AsyncReadExt + Unpin>(stream: &mut S) -> FrameHeader {
+    let mut header: [u8; HEADER_LENGTH] = [0; HEADER_LENGTH];
+    stream.read_exact(&mut header).await.unwrap();
+    FrameHeader::from_bytes(header)
+}
+
+/// Reads a whole frame: its header, and its payload.
+async fn read_frame<S: AsyncReadExt + Unpin>(stream: &mut S) -> (FrameHeader, Vec<u8>) {
+    let header = read_header(stream).await;
+    let mut payload = vec![0; header.payload_size];
+    stream.read_exact(&mut payload).await.unwrap();
+    (header, payload)
+}
+
+/// Sends several frames over a single connection, checking that every one of them gets answered
+/// with the matching request ID.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial]
+async fn test_keep_alive_session() {
+    let worker = Worker::new(5002, WorkerConfig::default()).await.unwrap();
+    tokio::spawn(async move { Arc::new(worker).listen().await.unwrap(); });
+
+    let mut stream = TcpStream::connect("127.0.0.1:5002").await.unwrap();
+    for request_id in 1..4 {
+        let ping = craft_frame(protocol::PING, request_id, 0, &[]).unwrap();
+        stream.write_all(&ping).await.unwrap();
+
+        let header = read_header(&mut stream).await;
+        assert_eq!(header.signal, protocol::STATUS);
+        assert_eq!(header.request_id, request_id);
+        let mut payload = vec![0; header.payload_size];
+        stream.read_exact(&mut payload).await.unwrap();
+    }
+
+    // SHUTDOWN ends the session, so the worker hangs up on us.
+    let shutdown = craft_frame(protocol::SHUTDOWN, 4, 0, &[]).unwrap();
+    stream.write_all(&shutdown).await.unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+}
+
+/// A client that sends a partial header and then goes quiet gets hung up on once the idle timeout
+/// runs out.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial]
+async fn test_stale_connection_times_out() {
+    let mut config = WorkerConfig::default();
+    config.idle_timeout = std::time::Duration::from_millis(200);
+    let worker = Worker::new(5003, config).await.unwrap();
+    tokio::spawn(async move { Arc::new(worker).listen().await.unwrap(); });
+
+    let mut stale = TcpStream::connect("127.0.0.1:5003").await.unwrap();
+    stale.write_all(&[protocol::PING, 0]).await.unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(stale.read(&mut buf).await.unwrap(), 0);
+
+    let mut fresh = TcpStream::connect("127.0.0.1:5003").await.unwrap();
+    fresh.write_all(&craft_frame(protocol::PING, 1, 0, &[]).unwrap()).await.unwrap();
+    assert_eq!(read_header(&mut fresh).await.signal, protocol::STATUS);
+}
+
+/// With `reject_when_busy` set, connections beyond `max_connections` are sent a BUSY frame.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial]
+async fn test_max_connections_busy() {
+    let mut config = WorkerConfig::default();
+    config.max_connections = 1;
+    config.reject_when_busy = true;
+    let worker = Worker::new(5004, config).await.unwrap();
+    tokio::spawn(async move { Arc::new(worker).listen().await.unwrap(); });
+
+    // Make sure the first connection has been accepted and is being served before we open the
+    // second one.
+    let mut first = TcpStream::connect("127.0.0.1:5004").await.unwrap();
+    first.write_all(&craft_frame(protocol::PING, 1, 0, &[]).unwrap()).await.unwrap();
+    assert_eq!(read_header(&mut first).await.signal, protocol::STATUS);
+
+    let mut second = TcpStream::connect("127.0.0.1:5004").await.unwrap();
+    assert_eq!(read_header(&mut second).await.signal, protocol::BUSY);
+}
+
+/// Workers can listen on a Unix domain socket instead of a TCP port.
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial]
+async fn test_unix_socket_session() {
+    let address = Address::Unix(std::env::temp_dir().join("mini-cluster-worker-test.sock"));
+    let worker = Worker::new(address.clone(), WorkerConfig::default()).await.unwrap();
+    tokio::spawn(async move { Arc::new(worker).listen().await.unwrap(); });
+
+    let mut stream = Stream::connect(&address).await.unwrap();
+    stream.write_all(&craft_frame(protocol::PING, 1, 0, &[]).unwrap()).await.unwrap();
+    let header = read_header(&mut stream).await;
+    assert_eq!(header.signal, protocol::STATUS);
+    assert_eq!(header.request_id, 1);
+}
+
+/// VALIDATE checks a workload without queueing it, and points out the op at fault.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial]
+async fn test_validate_workload() {
+    let worker = Worker::new(5005, WorkerConfig::default()).await.unwrap();
+    tokio::spawn(async move { Arc::new(worker).listen().await.unwrap(); });
+    let mut stream = TcpStream::connect("127.0.0.1:5005").await.unwrap();
+
+    let craft_workload = |statements: &[&str]| {
+        let ops = statements.iter().enumerate().map(|(i, statement)| {
+            craft_op_message(None, Some((*statement).to_owned()), Some(i as i32 + 1))
+        }).collect::<Vec<_>>();
+        craft_workload_message(Some(RepeatedField::from_vec(ops)))
+    };
+
+    let valid = craft_workload(&["SELECT 1", "SELECT 2"]).write_to_bytes().unwrap();
+    stream.write_all(&craft_frame(protocol::VALIDATE, 1, 0, &valid).unwrap()).await.unwrap();
+    let header = read_header(&mut stream).await;
+    assert_eq!(header.signal, protocol::VALID);
+    assert_eq!(header.request_id, 1);
+
+    let invalid = craft_workload(&["SELECT 1", "SELEC 2"]).write_to_bytes().unwrap();
+    stream.write_all(&craft_frame(protocol::VALIDATE, 2, 0, &invalid).unwrap()).await.unwrap();
+    let header = read_header(&mut stream).await;
+    assert_eq!(header.signal, protocol::ERROR);
+    let mut payload = vec![0; header.payload_size];
+    stream.read_exact(&mut payload).await.unwrap();
+    let error = ErrorResponse::parse_from_bytes(&payload).unwrap();
+    assert_eq!(error.get_kind(), ErrorResponse_Kind::VALIDATION);
+    assert_eq!(error.get_op_index(), 1);
+
+    // Nothing was queued.
+    stream.write_all(&craft_frame(protocol::PING, 3, 0, &[]).unwrap()).await.unwrap();
+    let header = read_header(&mut stream).await;
+    let mut payload = vec![0; header.payload_size];
+    stream.read_exact(&mut payload).await.unwrap();
+    assert_eq!(WorkerStatus::parse_from_bytes(&payload).unwrap().get_queue_depth(), 0);
+}
 
-    assert!(result.len() == 1);
+/// A draining worker turns away new work, and says it is draining when pinged.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial]
+async fn test_drain() {
+    let worker = Worker::new(5006, WorkerConfig::default()).await.unwrap();
+    tokio::spawn(async move { Arc::new(worker).listen().await.unwrap(); });
+    let mut stream = TcpStream::connect("127.0.0.1:5006").await.unwrap();
+
+    stream.write_all(&craft_frame(protocol::DRAIN, 1, 0, &[]).unwrap()).await.unwrap();
+    let header = read_header(&mut stream).await;
+    assert_eq!(header.signal, protocol::DRAINING);
+    assert_eq!(header.request_id, 1);
+
+    let op = craft_op_message(None, Some("SELECT 1".to_owned()), Some(1));
+    let workload = craft_workload_message(Some(RepeatedField::from_vec(vec![op])));
+    let payload = workload.write_to_bytes().unwrap();
+    stream.write_all(&craft_frame(protocol::WORK, 2, 0, &payload).unwrap()).await.unwrap();
+    let header = read_header(&mut stream).await;
+    assert_eq!(header.signal, protocol::ERROR);
+    let mut payload = vec![0; header.payload_size];
+    stream.read_exact(&mut payload).await.unwrap();
+    let error = ErrorResponse::parse_from_bytes(&payload).unwrap();
+    assert_eq!(error.get_kind(), ErrorResponse_Kind::DRAINING);
+
+    stream.write_all(&craft_frame(protocol::PING, 3, 0, &[]).unwrap()).await.unwrap();
+    let header = read_header(&mut stream).await;
+    let mut payload = vec![0; header.payload_size];
+    stream.read_exact(&mut payload).await.unwrap();
+    let status = WorkerStatus::parse_from_bytes(&payload).unwrap();
+    assert!(status.get_draining());
+    assert_eq!(status.get_queue_depth(), 0);
+}
+
+/// Sends a bare-bones HTTP GET request to the given local port, returning the whole response.
+async fn http_get(port: u16, path: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+/// Health and readiness probes are answered over plain HTTP on the admin port.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial]
+async fn test_health_probes() {
+    let worker = Arc::new(Worker::new(5007, WorkerConfig::default()).await.unwrap());
+    let listening = Arc::clone(&worker);
+    tokio::spawn(async move { listening.listen().await.unwrap(); });
+    let probed = Arc::clone(&worker);
+    tokio::spawn(async move { health::serve(probed, 5107).await.unwrap(); });
+    // Give the admin server a moment to bind.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(http_get(5107, "/healthz").await.starts_with("HTTP/1.1 200"));
+    // Whether or not the readiness checks pass as a whole depends on there being S3 credentials
+    // about, but the rest of them should.
+    let response = http_get(5107, "/readyz").await;
+    assert!(response.contains("listening: ok"));
+    assert!(response.contains("accepting_work: ok"));
+    assert!(response.contains("database: ok"));
+    assert!(response.contains("cache_dir: ok"));
+
+    worker.drain();
+    let response = http_get(5107, "/readyz").await;
+    assert!(response.starts_with("HTTP/1.1 503"));
+    assert!(response.contains("accepting_work: failed"));
+    // A draining worker is still alive.
+    assert!(http_get(5107, "/healthz").await.starts_with("HTTP/1.1 200"));
+    assert!(http_get(5107, "/metrics").await.starts_with("HTTP/1.1 404"));
 }
 
-// TODO: integration test for the handle_connection in lib.rs.
-// #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
-// #[serial]
-// async fn test_handle_connection() {
-//     let worker = Worker::new(5001).await;
-//     assert!(worker.is_ok());
-//     let worker = worker.unwrap();
-
-//     let stream = TcpStream::connect("localhost:5000").await;
-//     assert!(stream.is_ok());
-//     let mut stream = stream.unwrap();
-    
-//     let stream_write = stream.write(b"\x00").await;
-//     assert!(stream_write.is_ok());
-//     let handle_connection_fut = worker.handle_connection(&mut stream);
-
-//     let result = handle_connection_fut.await;
-//     assert!(result.is_ok());
-// }
\ No newline at end of file
+/// Submits a workload over an in-process connection, and fetches its results, start to finish.
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[serial]
+async fn test_handle_connection() {
+    let (_worker, mut stream) = spawn_in_process_worker(WorkerConfig::default()).await;
+    stream.write_all(&craft_frame(protocol::PING, 1, 0, &[]).unwrap()).await.unwrap();
+    let (header, _) = read_frame(&mut stream).await;
+    assert_eq!(header.signal, protocol::STATUS);
+
+    let op = craft_op_message(Some(RepeatedField::new()), Some("SELECT 1 + 1".to_owned()), None);
+    let mut workload = craft_workload_message(Some(RepeatedField::from_vec(vec![op])));
+    workload.set_ephemeral(true);
+    let payload = workload.write_to_bytes().unwrap();
+    stream.write_all(&craft_frame(protocol::WORK, 2, 0, &payload).unwrap()).await.unwrap();
+    let (header, payload) = read_frame(&mut stream).await;
+    assert_eq!(header.signal, protocol::ACK);
+    assert_eq!(header.request_id, 2);
+    let job_id = Ack::parse_from_bytes(&payload).unwrap().get_job_id();
+
+    let mut fetch = FetchResults::new();
+    fetch.set_job_id(job_id);
+    let payload = fetch.write_to_bytes().unwrap();
+    stream.write_all(&craft_frame(protocol::FETCH, 3, 0, &payload).unwrap()).await.unwrap();
+    let mut batches = vec![];
+    loop {
+        let (header, payload) = read_frame(&mut stream).await;
+        assert_eq!(header.signal, protocol::RESULTS);
+        assert_eq!(header.request_id, 3);
+        batches.push(ResultBatch::parse_from_bytes(&payload).unwrap());
+        if batches.last().unwrap().get_last() {
+            break;
+        }
+    }
+    assert_eq!(batches[0].get_rows()[0].get_values()[0].get_integer(), 2);
+
+    stream.write_all(&craft_frame(protocol::SHUTDOWN, 4, 0, &[]).unwrap()).await.unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+}